@@ -0,0 +1,78 @@
+use ppu::{ScanlineOutput, SCREEN_WIDTH, SCREEN_HEIGHT};
+use std::sync::{Arc, Mutex};
+
+// Columns at the left and the right edge of the picture which light the
+// bars.
+const EDGE_COLUMNS: usize = 16;
+
+// Lights the bars around the picture with the average color of its edges,
+// like the lamps behind some TVs. It adds up the lines as the PPU draws
+// them, so the frontend only has to read the color of the last frame.
+pub struct Ambilight {
+	sums: [u32; 3],
+	color: Arc<Mutex<(u8, u8, u8)>>,
+}
+
+impl Ambilight {
+	pub fn new() -> Ambilight {
+		Ambilight {
+			sums: [0; 3],
+			color: Arc::new(Mutex::new((0, 0, 0))),
+		}
+	}
+
+	// The color of the bars, updated after each frame.
+	pub fn color(&self) -> Arc<Mutex<(u8, u8, u8)>> {
+		self.color.clone()
+	}
+}
+
+impl ScanlineOutput for Ambilight {
+	fn scanline(&mut self, y: usize, pixels: &[u8]) {
+		let edges = pixels[..EDGE_COLUMNS * 4].chunks(4).chain(pixels[(SCREEN_WIDTH - EDGE_COLUMNS) * 4..].chunks(4));
+		for pixel in edges {
+			for (sum, &value) in self.sums.iter_mut().zip(pixel) {
+				*sum += value as u32;
+			}
+		}
+		if y == SCREEN_HEIGHT - 1 {
+			let count = (SCREEN_HEIGHT * EDGE_COLUMNS * 2) as u32;
+			*self.color.lock().unwrap() = ((self.sums[0] / count) as u8, (self.sums[1] / count) as u8,
+				(self.sums[2] / count) as u8);
+			self.sums = [0; 3];
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn edge_color() {
+		let mut ambilight = Ambilight::new();
+		let color = ambilight.color();
+		// red on the left, blue on the right, green in between
+		let mut line = Vec::new();
+		for x in 0..SCREEN_WIDTH {
+			line.extend_from_slice(match x {
+				0..=15 => &[200, 0, 0, 255],
+				240..=255 => &[0, 0, 100, 255],
+				_ => &[0, 255, 0, 255],
+			});
+		}
+		for y in 0..SCREEN_HEIGHT - 1 {
+			ambilight.scanline(y, &line);
+		}
+		assert_eq!((0, 0, 0), *color.lock().unwrap());
+		ambilight.scanline(SCREEN_HEIGHT - 1, &line);
+		assert_eq!((100, 0, 50), *color.lock().unwrap());
+
+		// every frame starts over
+		let black = vec![0; SCREEN_WIDTH * 4];
+		for y in 0..SCREEN_HEIGHT {
+			ambilight.scanline(y, &black);
+		}
+		assert_eq!((0, 0, 0), *color.lock().unwrap());
+	}
+}
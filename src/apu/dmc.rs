@@ -0,0 +1,330 @@
+use region::Region;
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// Timer periods in CPU cycles, indexed by bits 0-3 of $4010.
+const NTSC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const PAL_RATES: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+// The delta modulation channel. It plays 1 bit delta encoded samples from
+// CPU memory, which the DMA unit fetches byte by byte, see take_request,
+// and each bit moves the 7 bit output level up or down by 2. The level can
+// also be set directly, which games use for PCM samples and to change the
+// volume of the triangle and noise channels.
+#[derive(Debug, Clone)]
+pub struct Dmc {
+	region: Region,
+	irq_enabled: bool,
+	irq: bool,
+	looping: bool,
+	// in CPU cycles, a bit is played every period
+	period: u16,
+	timer: u16,
+	level: u8,
+	// $4012 and $4013 as written
+	sample_address: u8,
+	sample_length: u8,
+	// the next byte to fetch and the bytes left of the sample
+	address: u16,
+	bytes_remaining: u16,
+	// the fetched byte not played yet
+	buffer: Option<u8>,
+	// A fetch was requested and not finished yet.
+	fetching: bool,
+	// the byte being played, and its bits left
+	shift_register: u8,
+	bits_remaining: u8,
+	// No byte was there when the last one ended, the level holds.
+	silent: bool,
+}
+
+impl Dmc {
+	pub fn new() -> Dmc {
+		Dmc {
+			region: Region::Ntsc,
+			irq_enabled: false,
+			irq: false,
+			looping: false,
+			period: NTSC_RATES[0],
+			timer: 0,
+			level: 0,
+			sample_address: 0,
+			sample_length: 0,
+			address: 0xC000,
+			bytes_remaining: 0,
+			buffer: None,
+			fetching: false,
+			shift_register: 0,
+			bits_remaining: 8,
+			silent: true,
+		}
+	}
+
+	pub fn set_region(&mut self, region: Region) {
+		self.region = region;
+	}
+
+	// A write to one of the 4 registers of the channel, by index.
+	pub fn write(&mut self, register: u16, value: u8) {
+		match register {
+			0 => {
+				self.irq_enabled = value & 0x80 != 0;
+				if !self.irq_enabled {
+					self.irq = false;
+				}
+				self.looping = value & 0x40 != 0;
+				let rates = if self.region == Region::Pal { PAL_RATES } else { NTSC_RATES };
+				self.period = rates[(value & 0x0F) as usize];
+			}
+			1 => self.level = value & 0x7F,
+			2 => self.sample_address = value,
+			_ => self.sample_length = value,
+		}
+	}
+
+	// Bit 4 of $4015. Enabling starts the sample unless it still plays,
+	// disabling stops it after the byte in the buffer. Both acknowledge the
+	// interrupt.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.irq = false;
+		if !enabled {
+			self.bytes_remaining = 0;
+		} else if self.bytes_remaining == 0 {
+			self.restart();
+		}
+	}
+
+	fn restart(&mut self) {
+		self.address = 0xC000 | (self.sample_address as u16) << 6;
+		self.bytes_remaining = (self.sample_length as u16) << 4 | 1;
+	}
+
+	// For the status of $4015.
+	pub fn active(&self) -> bool {
+		self.bytes_remaining > 0
+	}
+
+	pub fn irq_pending(&self) -> bool {
+		self.irq
+	}
+
+	// The address of the next byte of the sample when the buffer needs it,
+	// once per fetch. The byte is handed over with fetched.
+	pub fn take_request(&mut self) -> Option<u16> {
+		if self.buffer.is_none() && self.bytes_remaining > 0 && !self.fetching {
+			self.fetching = true;
+			Some(self.address)
+		} else {
+			None
+		}
+	}
+
+	// Number of timer clocks until the buffer runs empty, after which the
+	// channel asks for the next byte. 0 if it waits for one already, None
+	// if it needs none or a fetch is underway.
+	pub fn clocks_until_request(&self) -> Option<u32> {
+		if self.bytes_remaining == 0 || self.fetching {
+			None
+		} else if self.buffer.is_none() {
+			Some(0)
+		} else {
+			let first = (self.timer as u32).div_ceil(2).max(1);
+			Some(first + (self.bits_remaining as u32 - 1) * (self.period as u32).div_ceil(2))
+		}
+	}
+
+	pub fn fetched(&mut self, value: u8) {
+		self.fetching = false;
+		if self.bytes_remaining == 0 {
+			// the channel was disabled meanwhile
+			return;
+		}
+		self.buffer = Some(value);
+		// wraps to 0x8000 after 0xFFFF
+		self.address = self.address.wrapping_add(1) | 0x8000;
+		self.bytes_remaining -= 1;
+		if self.bytes_remaining == 0 {
+			if self.looping {
+				self.restart();
+			} else if self.irq_enabled {
+				self.irq = true;
+			}
+		}
+	}
+
+	// Clocked every APU cycle, every other CPU cycle. The periods are in
+	// CPU cycles, so the timer counts two of them at a time.
+	pub fn clock_timer(&mut self) {
+		if self.timer > 2 {
+			self.timer -= 2;
+			return;
+		}
+		self.timer = self.period;
+		if !self.silent {
+			if self.shift_register & 1 != 0 {
+				if self.level <= 125 {
+					self.level += 2;
+				}
+			} else if self.level >= 2 {
+				self.level -= 2;
+			}
+		}
+		self.shift_register >>= 1;
+		self.bits_remaining -= 1;
+		if self.bits_remaining == 0 {
+			self.bits_remaining = 8;
+			match self.buffer.take() {
+				Some(value) => {
+					self.shift_register = value;
+					self.silent = false;
+				}
+				None => self.silent = true,
+			}
+		}
+	}
+
+	// The 7 bit output level.
+	pub fn output(&self) -> u8 {
+		self.level
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bool(out, self.irq_enabled)?;
+		savestate::write_bool(out, self.irq)?;
+		savestate::write_bool(out, self.looping)?;
+		savestate::write_u16(out, self.period)?;
+		savestate::write_u16(out, self.timer)?;
+		savestate::write_u8(out, self.level)?;
+		savestate::write_u8(out, self.sample_address)?;
+		savestate::write_u8(out, self.sample_length)?;
+		savestate::write_u16(out, self.address)?;
+		savestate::write_u16(out, self.bytes_remaining)?;
+		savestate::write_bool(out, self.buffer.is_some())?;
+		savestate::write_u8(out, self.buffer.unwrap_or(0))?;
+		savestate::write_u8(out, self.shift_register)?;
+		savestate::write_u8(out, self.bits_remaining)?;
+		savestate::write_bool(out, self.silent)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.irq_enabled = savestate::read_bool(input)?;
+		self.irq = savestate::read_bool(input)?;
+		self.looping = savestate::read_bool(input)?;
+		self.period = savestate::read_u16(input)?;
+		self.timer = savestate::read_u16(input)?;
+		self.level = savestate::read_u8(input)? & 0x7F;
+		self.sample_address = savestate::read_u8(input)?;
+		self.sample_length = savestate::read_u8(input)?;
+		self.address = savestate::read_u16(input)? | 0x8000;
+		self.bytes_remaining = savestate::read_u16(input)? & 0xFFF;
+		let buffered = savestate::read_bool(input)?;
+		let buffer = savestate::read_u8(input)?;
+		self.buffer = if buffered { Some(buffer) } else { None };
+		// fetches finish before the console stops between instructions
+		self.fetching = false;
+		self.shift_register = savestate::read_u8(input)?;
+		self.bits_remaining = savestate::read_u8(input)?.max(1).min(8);
+		self.silent = savestate::read_bool(input)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// Plays a sample of the given bytes at the fastest rate and returns the
+	// levels after every bit.
+	fn play(dmc: &mut Dmc, bytes: &[u8]) -> Vec<u8> {
+		let mut levels = Vec::new();
+		for _ in 0..(bytes.len() + 2) * 8 * 27 {
+			if let Some(address) = dmc.take_request() {
+				dmc.fetched(bytes[(address - 0xC000) as usize % bytes.len()]);
+			}
+			dmc.clock_timer();
+			if dmc.timer == dmc.period {
+				levels.push(dmc.output());
+			}
+		}
+		levels
+	}
+
+	#[test]
+	fn sample() {
+		let mut dmc = Dmc::new();
+		dmc.write(0, 0x0F);
+		dmc.write(1, 0x40);
+		// 17 bytes at 0xC000
+		dmc.write(2, 0x00);
+		dmc.write(3, 0x01);
+		dmc.set_enabled(true);
+		assert!(dmc.active());
+		let levels = play(&mut dmc, &[0xFF; 17]);
+		// silent for the first byte, then up by 2 with every bit
+		assert_eq!(vec![0x40; 8], levels[..8].to_vec());
+		assert_eq!(0x42, levels[8]);
+		assert_eq!(0x7F & !1, *levels.last().unwrap());
+		assert!(!dmc.active());
+		assert!(!dmc.irq_pending());
+	}
+
+	#[test]
+	fn irq_and_loop() {
+		let mut dmc = Dmc::new();
+		dmc.write(0, 0x8F);
+		dmc.write(3, 0x00);
+		dmc.set_enabled(true);
+		assert_eq!(Some(0xC000), dmc.take_request());
+		assert_eq!(None, dmc.take_request());
+		dmc.fetched(0x00);
+		assert!(!dmc.active());
+		assert!(dmc.irq_pending());
+		dmc.set_enabled(false);
+		assert!(!dmc.irq_pending());
+
+		// looping never ends, nor raises the interrupt
+		dmc.write(0, 0xCF);
+		dmc.set_enabled(true);
+		for _ in 0..10 {
+			play(&mut dmc, &[0x55]);
+		}
+		assert!(dmc.active());
+		assert!(!dmc.irq_pending());
+
+		// the address wraps to 0x8000
+		let mut dmc = Dmc::new();
+		dmc.write(2, 0xFF);
+		dmc.write(3, 0x04);
+		dmc.set_enabled(true);
+		assert_eq!(Some(0xFFC0), dmc.take_request());
+		for _ in 0..0x40 {
+			dmc.fetched(0);
+			dmc.buffer = None;
+			dmc.take_request();
+		}
+		assert_eq!(0x8000, dmc.address);
+	}
+
+	#[test]
+	fn clocks_until_request() {
+		let mut dmc = Dmc::new();
+		assert_eq!(None, dmc.clocks_until_request());
+		dmc.write(0, 0x4E);
+		dmc.write(3, 0x01);
+		dmc.set_enabled(true);
+		assert_eq!(Some(0), dmc.clocks_until_request());
+		dmc.take_request();
+		assert_eq!(None, dmc.clocks_until_request());
+		for _ in 0..20 {
+			dmc.fetched(0x55);
+			let clocks = dmc.clocks_until_request().unwrap();
+			for _ in 0..clocks - 1 {
+				dmc.clock_timer();
+				assert_eq!(None, dmc.take_request());
+			}
+			dmc.clock_timer();
+			assert!(dmc.take_request().is_some());
+		}
+	}
+}
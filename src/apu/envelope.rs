@@ -0,0 +1,109 @@
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// Volume unit of the pulse and noise channels. It either outputs a
+// constant volume, or a decay level which falls from 15 to 0 and then
+// stays there or loops, one step per period + 1 quarter frames.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+	// bit 5 loop, bit 4 constant volume, bits 0-3 volume or period
+	control: u8,
+	// set by the length register write, restarts the decay
+	start: bool,
+	divider: u8,
+	decay: u8,
+}
+
+impl Envelope {
+	pub fn new() -> Envelope {
+		Envelope {
+			control: 0,
+			start: false,
+			divider: 0,
+			decay: 0,
+		}
+	}
+
+	// The first register of the channel, only bits 0-5 are used.
+	pub fn write(&mut self, value: u8) {
+		self.control = value & 0x3F;
+	}
+
+	pub fn restart(&mut self) {
+		self.start = true;
+	}
+
+	fn period(&self) -> u8 {
+		self.control & 0x0F
+	}
+
+	// Clocked every quarter frame.
+	pub fn clock(&mut self) {
+		if self.start {
+			self.start = false;
+			self.decay = 15;
+			self.divider = self.period();
+		} else if self.divider > 0 {
+			self.divider -= 1;
+		} else {
+			self.divider = self.period();
+			if self.decay > 0 {
+				self.decay -= 1;
+			} else if self.control & 0x20 != 0 {
+				self.decay = 15;
+			}
+		}
+	}
+
+	pub fn volume(&self) -> u8 {
+		if self.control & 0x10 != 0 { self.period() } else { self.decay }
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.control)?;
+		savestate::write_bool(out, self.start)?;
+		savestate::write_u8(out, self.divider)?;
+		savestate::write_u8(out, self.decay)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.control = savestate::read_u8(input)? & 0x3F;
+		self.start = savestate::read_bool(input)?;
+		self.divider = savestate::read_u8(input)? & 0x0F;
+		self.decay = savestate::read_u8(input)? & 0x0F;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn decay() {
+		let mut envelope = Envelope::new();
+		// period 1, so two quarter frames per step
+		envelope.write(0x01);
+		envelope.restart();
+		envelope.clock();
+		assert_eq!(15, envelope.volume());
+		for _ in 0..30 {
+			envelope.clock();
+		}
+		assert_eq!(0, envelope.volume());
+		envelope.clock();
+		envelope.clock();
+		assert_eq!(0, envelope.volume());
+
+		// looping
+		envelope.write(0x21);
+		envelope.clock();
+		envelope.clock();
+		assert_eq!(15, envelope.volume());
+
+		// constant volume
+		envelope.write(0x17);
+		assert_eq!(7, envelope.volume());
+	}
+}
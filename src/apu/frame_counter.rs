@@ -0,0 +1,193 @@
+use region::Region;
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// CPU cycles after a reset of the sequence at which its steps happen, for
+// the 4 step mode. The 5 step mode has its last step later instead, and
+// the sequence restarts one cycle after the last step.
+const NTSC_STEPS: [u32; 4] = [7457, 14913, 22371, 29829];
+const NTSC_LAST_STEP_5: u32 = 37281;
+const PAL_STEPS: [u32; 4] = [8313, 16627, 24939, 33253];
+const PAL_LAST_STEP_5: u32 = 41565;
+
+// What a CPU cycle of the frame counter clocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameClocks {
+	// envelopes and the linear counter
+	pub quarter: bool,
+	// length counters and sweeps
+	pub half: bool,
+}
+
+const NO_CLOCKS: FrameClocks = FrameClocks { quarter: false, half: false };
+const QUARTER: FrameClocks = FrameClocks { quarter: true, half: false };
+const HALF: FrameClocks = FrameClocks { quarter: true, half: true };
+
+// The sequencer of $4017, which clocks the envelopes, length counters and
+// sweeps about 240 times a second, and raises the frame interrupt at the
+// end of every 4 step sequence unless inhibited.
+#[derive(Debug, Clone)]
+pub struct FrameCounter {
+	region: Region,
+	five_step: bool,
+	irq_inhibit: bool,
+	irq: bool,
+	// CPU cycles since the sequence was reset
+	cycle: u32,
+}
+
+impl FrameCounter {
+	pub fn new() -> FrameCounter {
+		FrameCounter {
+			region: Region::Ntsc,
+			five_step: false,
+			irq_inhibit: false,
+			irq: false,
+			cycle: 0,
+		}
+	}
+
+	pub fn set_region(&mut self, region: Region) {
+		self.region = region;
+	}
+
+	// A write to $4017. The sequence restarts, and the 5 step mode clocks
+	// all units right away. Returns those clocks.
+	pub fn write(&mut self, value: u8) -> FrameClocks {
+		self.five_step = value & 0x80 != 0;
+		self.irq_inhibit = value & 0x40 != 0;
+		if self.irq_inhibit {
+			self.irq = false;
+		}
+		self.cycle = 0;
+		if self.five_step { HALF } else { NO_CLOCKS }
+	}
+
+	pub fn irq_pending(&self) -> bool {
+		self.irq
+	}
+
+	pub fn acknowledge_irq(&mut self) {
+		self.irq = false;
+	}
+
+	// The cycles of the first 3 steps of the sequence, and of its last
+	// step for the current mode.
+	fn steps(&self) -> ([u32; 3], u32) {
+		let (steps, last_step_5) = match self.region {
+			Region::Ntsc => (NTSC_STEPS, NTSC_LAST_STEP_5),
+			Region::Pal => (PAL_STEPS, PAL_LAST_STEP_5),
+		};
+		([steps[0], steps[1], steps[2]], if self.five_step { last_step_5 } else { steps[3] })
+	}
+
+	// Number of CPU cycles to run until the next step has happened.
+	pub fn cycles_until_step(&self) -> u32 {
+		let (steps, last_step) = self.steps();
+		match steps.iter().cloned().chain(Some(last_step)).find(|&step| step > self.cycle) {
+			Some(step) => step - self.cycle,
+			// the cycle after the last step restarts the sequence
+			None => (last_step + 1).saturating_sub(self.cycle).max(1) + steps[0],
+		}
+	}
+
+	// Runs for one CPU cycle.
+	pub fn clock(&mut self) -> FrameClocks {
+		self.cycle += 1;
+		let (steps, last_step) = self.steps();
+		if self.cycle == steps[0] || self.cycle == steps[2] {
+			QUARTER
+		} else if self.cycle == steps[1] {
+			HALF
+		} else if self.cycle == last_step {
+			if !self.five_step && !self.irq_inhibit {
+				self.irq = true;
+			}
+			HALF
+		} else {
+			if self.cycle > last_step {
+				self.cycle = 0;
+			}
+			NO_CLOCKS
+		}
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bool(out, self.five_step)?;
+		savestate::write_bool(out, self.irq_inhibit)?;
+		savestate::write_bool(out, self.irq)?;
+		savestate::write_u16(out, self.cycle as u16)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.five_step = savestate::read_bool(input)?;
+		self.irq_inhibit = savestate::read_bool(input)?;
+		self.irq = savestate::read_bool(input)?;
+		self.cycle = savestate::read_u16(input)? as u32;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn run(counter: &mut FrameCounter, cycles: u32) -> (u32, u32) {
+		let mut quarters = 0;
+		let mut halves = 0;
+		for _ in 0..cycles {
+			let clocks = counter.clock();
+			quarters += clocks.quarter as u32;
+			halves += clocks.half as u32;
+		}
+		(quarters, halves)
+	}
+
+	#[test]
+	fn four_step() {
+		let mut counter = FrameCounter::new();
+		assert_eq!((3, 1), run(&mut counter, 29828));
+		assert!(!counter.irq_pending());
+		assert_eq!((1, 1), run(&mut counter, 1));
+		assert!(counter.irq_pending());
+		counter.acknowledge_irq();
+		// the next sequence starts after another cycle
+		assert_eq!((4, 2), run(&mut counter, 29830));
+		assert!(counter.irq_pending());
+
+		assert_eq!(7458, counter.cycles_until_step());
+
+		assert_eq!(NO_CLOCKS, counter.write(0x40));
+		assert!(!counter.irq_pending());
+		run(&mut counter, 29830);
+		assert!(!counter.irq_pending());
+	}
+
+	#[test]
+	fn five_step() {
+		let mut counter = FrameCounter::new();
+		assert_eq!(HALF, counter.write(0x80));
+		assert_eq!((4, 2), run(&mut counter, 37282));
+		assert!(!counter.irq_pending());
+
+		counter.set_region(Region::Pal);
+		counter.write(0x00);
+		assert_eq!((3, 1), run(&mut counter, 33252));
+		assert_eq!((1, 1), run(&mut counter, 1));
+	}
+
+	#[test]
+	fn cycles_until_step() {
+		let mut counter = FrameCounter::new();
+		for &mode in [0x00, 0x80].iter() {
+			counter.write(mode);
+			for _ in 0..12 {
+				let cycles = counter.cycles_until_step();
+				let (quarters, _) = run(&mut counter, cycles - 1);
+				assert_eq!(0, quarters);
+				assert!(counter.clock().quarter);
+			}
+		}
+	}
+}
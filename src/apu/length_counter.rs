@@ -0,0 +1,100 @@
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// Values loaded into the length counter, indexed by bits 3-7 of the last
+// register of a channel.
+const LENGTHS: [u8; 32] = [
+	10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+	12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Silences a channel after a number of half frames, unless halted. A
+// channel disabled in $4015 has its counter held at 0.
+#[derive(Debug, Clone)]
+pub struct LengthCounter {
+	value: u8,
+	enabled: bool,
+	halted: bool,
+}
+
+impl LengthCounter {
+	pub fn new() -> LengthCounter {
+		LengthCounter {
+			value: 0,
+			enabled: false,
+			halted: false,
+		}
+	}
+
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+		if !enabled {
+			self.value = 0;
+		}
+	}
+
+	pub fn set_halted(&mut self, halted: bool) {
+		self.halted = halted;
+	}
+
+	// Loads the length of bits 3-7 of the last register of the channel.
+	pub fn load(&mut self, value: u8) {
+		if self.enabled {
+			self.value = LENGTHS[(value >> 3) as usize];
+		}
+	}
+
+	// Clocked every half frame.
+	pub fn clock(&mut self) {
+		if !self.halted && self.value > 0 {
+			self.value -= 1;
+		}
+	}
+
+	pub fn active(&self) -> bool {
+		self.value > 0
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.value)?;
+		savestate::write_bool(out, self.enabled)?;
+		savestate::write_bool(out, self.halted)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.value = savestate::read_u8(input)?;
+		self.enabled = savestate::read_bool(input)?;
+		self.halted = savestate::read_bool(input)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn length_counter() {
+		let mut length = LengthCounter::new();
+		length.load(0x08);
+		assert!(!length.active());
+		length.set_enabled(true);
+		// 254 half frames
+		length.load(0x08);
+		for _ in 0..253 {
+			length.clock();
+		}
+		assert!(length.active());
+		length.set_halted(true);
+		length.clock();
+		assert!(length.active());
+		length.set_halted(false);
+		length.clock();
+		assert!(!length.active());
+
+		length.load(0xF8);
+		length.set_enabled(false);
+		assert!(!length.active());
+	}
+}
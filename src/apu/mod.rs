@@ -0,0 +1,1060 @@
+mod dmc;
+mod envelope;
+mod length_counter;
+mod frame_counter;
+mod noise;
+mod pulse;
+mod triangle;
+
+use apu::dmc::Dmc;
+use apu::frame_counter::{FrameClocks, FrameCounter};
+use apu::noise::Noise;
+use apu::pulse::{Pulse, PulseId};
+use apu::triangle::Triangle;
+use region::Region;
+use savestate;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::Range;
+
+// Rate of the generated samples.
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Number of samples which cover the first cycles CPU cycles. The NTSC CPU
+// runs at 236.25 MHz / 132, about 1789772.7 Hz, and sample counts are
+// computed with this exact ratio, so they never drift. An NTSC frame of
+// 29780.5 cycles gets 733 or 734 samples, the fractions carry over to the
+// next frames.
+pub fn samples_for_cycles(cycles: u64, region: Region) -> u64 {
+	let (numerator, denominator) = region.cpu_clock();
+	cycles * SAMPLE_RATE as u64 * denominator / numerator
+}
+
+// Sound chips which cartridges, or the Disk System, mix into the console
+// output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpansionChip {
+	Fds,
+	Vrc6,
+	Vrc7,
+	Mmc5,
+	Namco163,
+	Sunsoft5b,
+}
+
+pub const EXPANSION_CHIPS: [ExpansionChip; 6] = [ExpansionChip::Fds, ExpansionChip::Vrc6,
+	ExpansionChip::Vrc7, ExpansionChip::Mmc5, ExpansionChip::Namco163, ExpansionChip::Sunsoft5b];
+
+impl ExpansionChip {
+	pub fn from_name(name: &str) -> Option<ExpansionChip> {
+		EXPANSION_CHIPS.iter().cloned().find(|chip| chip.name() == name)
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			ExpansionChip::Fds => "fds",
+			ExpansionChip::Vrc6 => "vrc6",
+			ExpansionChip::Vrc7 => "vrc7",
+			ExpansionChip::Mmc5 => "mmc5",
+			ExpansionChip::Namco163 => "n163",
+			ExpansionChip::Sunsoft5b => "5b",
+		}
+	}
+}
+
+// The sound channels. Each has an output of its own before they are mixed,
+// see Apu::set_stems.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+	Pulse1,
+	Pulse2,
+	Triangle,
+	Noise,
+	Dmc,
+	// The sound chip of the cartridge, if it has one.
+	Expansion,
+}
+
+pub const CHANNELS: [Channel; 6] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle,
+	Channel::Noise, Channel::Dmc, Channel::Expansion];
+
+impl Channel {
+	pub fn from_name(name: &str) -> Option<Channel> {
+		CHANNELS.iter().cloned().find(|channel| channel.name() == name)
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Channel::Pulse1 => "pulse1",
+			Channel::Pulse2 => "pulse2",
+			Channel::Triangle => "triangle",
+			Channel::Noise => "noise",
+			Channel::Dmc => "dmc",
+			Channel::Expansion => "expansion",
+		}
+	}
+
+	// The APU registers of the channel. Those of the expansion audio are on
+	// the cartridge, so it has none.
+	pub fn registers(&self) -> Range<u16> {
+		match *self {
+			Channel::Pulse1 => 0x4000..0x4004,
+			Channel::Pulse2 => 0x4004..0x4008,
+			Channel::Triangle => 0x4008..0x400C,
+			Channel::Noise => 0x400C..0x4010,
+			Channel::Dmc => 0x4010..0x4014,
+			Channel::Expansion => 0x4000..0x4000,
+		}
+	}
+}
+
+// The APU registers, 4000 - 4017. 4014 and 4016 belong to the OAM DMA and
+// the controllers instead and are never written to the APU.
+const REGISTERS_START: u16 = 0x4000;
+const REGISTER_COUNT: usize = 0x18;
+// Channel enables when written, length counters and interrupts when read.
+pub const STATUS: u16 = 0x4015;
+// Bit of a status read which nothing drives. The read does not reach the
+// external data bus either, so it keeps the value from before.
+pub const STATUS_OPEN_BUS_BITS: u8 = 0x20;
+// Mode and interrupt inhibit of the frame counter, write only. Reads go to
+// the second controller port.
+const FRAME_COUNTER: u16 = 0x4017;
+
+// The nonlinear DAC of the pulse channels, for the sum of their 4 bit
+// levels. Both at 15 give about 0.26.
+fn pulse_dac(sum: f32) -> f32 {
+	if sum == 0.0 { 0.0 } else { 95.88 / (8128.0 / sum + 100.0) }
+}
+
+// The nonlinear DAC shared by the triangle, noise and DMC channels, for
+// their 4, 4 and 7 bit levels. The triangle at 15 alone gives about 0.16.
+fn tnd_dac(triangle: f32, noise: f32, dmc: f32) -> f32 {
+	let weighted = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+	if weighted == 0.0 { 0.0 } else { 159.79 / (1.0 / weighted + 100.0) }
+}
+
+// Presets for the expansion audio levels of AudioSettings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpansionMix {
+	// The levels of the Famicom, which mixes the cartridge audio in as is.
+	Famicom,
+	// A front-loading NES with the usual expansion audio mod. Its resistor
+	// on the expansion port makes the chips about half as loud compared to
+	// the APU.
+	FrontLoader,
+}
+
+impl ExpansionMix {
+	pub fn from_name(name: &str) -> Option<ExpansionMix> {
+		match name {
+			"famicom" => Some(ExpansionMix::Famicom),
+			"nes" => Some(ExpansionMix::FrontLoader),
+			_ => None,
+		}
+	}
+}
+
+// How the channels and the expansion audio are mixed into the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSettings {
+	pub expansion_audio: bool,
+	// Factors for the levels of the chips, indexed by ExpansionChip.
+	pub expansion_levels: [f32; 6],
+	// The only channel in the output, all of them if None.
+	pub solo: Option<Channel>,
+}
+
+impl AudioSettings {
+	pub fn from_preset(mix: ExpansionMix) -> AudioSettings {
+		AudioSettings {
+			expansion_audio: true,
+			expansion_levels: [match mix {
+				ExpansionMix::Famicom => 1.0,
+				ExpansionMix::FrontLoader => 0.5,
+			}; 6],
+			solo: None,
+		}
+	}
+
+	pub fn expansion_level(&self, chip: ExpansionChip) -> f32 {
+		if self.expansion_audio { self.expansion_levels[chip as usize] } else { 0.0 }
+	}
+
+	pub fn set_expansion_level(&mut self, chip: ExpansionChip, level: f32) {
+		self.expansion_levels[chip as usize] = level;
+	}
+}
+
+// Receives the samples as the APU generates them, e.g. to play them. They
+// come in batches of OUTPUT_BATCH samples or when the console takes them,
+// see Apu::take_audio.
+pub trait AudioOutput {
+	fn samples(&mut self, sample_rate: u32, samples: &[i16]);
+}
+
+// Samples handed to the AudioOutput at once, about 6 ms.
+const OUTPUT_BATCH: usize = 256;
+
+// Samples over which the output ramps to a new level, 5 ms.
+const RAMP_SAMPLES: u32 = SAMPLE_RATE / 200;
+
+// Smooths jumps of the output which come from the emulator rather than the
+// game: switching the expansion audio on or off, loading a state, power
+// cycling, or resuming after a pause with the output silent. The output
+// then ramps from where it was to the mixed level instead of popping.
+#[derive(Debug, Clone)]
+struct Declicker {
+	output: f32,
+	from: f32,
+	samples_left: u32,
+}
+
+impl Declicker {
+	fn new() -> Declicker {
+		Declicker {
+			output: 0.0,
+			from: 0.0,
+			samples_left: 0,
+		}
+	}
+
+	fn ramp_from(&mut self, level: f32) {
+		self.from = level;
+		self.samples_left = RAMP_SAMPLES;
+	}
+
+	// Returns the output for the mixed level of the next sample.
+	fn apply(&mut self, level: f32) -> f32 {
+		if self.samples_left > 0 {
+			let progress = 1.0 - self.samples_left as f32 / RAMP_SAMPLES as f32;
+			self.output = self.from + (level - self.from) * progress;
+			self.samples_left -= 1;
+		} else {
+			self.output = level;
+		}
+		self.output
+	}
+}
+
+pub struct Apu {
+	region: Region,
+	// CPU cycles clocked so far.
+	cycles: u64,
+	// Samples not taken yet.
+	samples: Vec<i16>,
+	settings: AudioSettings,
+	// The expansion audio of the cartridge, see Cartridge::expansion_audio,
+	// before the mixing of the settings.
+	expansion: Option<(ExpansionChip, f32)>,
+	declicker: Declicker,
+	// The last values written to the registers, from REGISTERS_START on.
+	registers: [u8; REGISTER_COUNT],
+	frame_counter: FrameCounter,
+	pulse1: Pulse,
+	pulse2: Pulse,
+	triangle: Triangle,
+	noise: Noise,
+	dmc: Dmc,
+	// Sum of the output levels of the cycles since the last sample, which
+	// is their average.
+	level_sum: f32,
+	// The same for every channel, indexed by Channel. They are summed up
+	// even without stems, so a recording starts with a whole sample.
+	stem_sums: [f32; 6],
+	cycles_since_sample: u32,
+	// Samples of every channel not taken yet, indexed by Channel, while
+	// they are recorded.
+	stems: Option<Vec<Vec<i16>>>,
+	output: Option<Box<dyn AudioOutput + Send>>,
+	// Number of the samples not taken yet which were handed to the output.
+	forwarded: usize,
+	// CPU cycles to run yet, see defer, and the cycles until the next
+	// frame counter step or DMC fetch when they were last run.
+	deferred: u32,
+	horizon: u32,
+}
+
+impl Apu {
+	pub fn new() -> Apu {
+		Apu {
+			region: Region::Ntsc,
+			cycles: 0,
+			samples: Vec::new(),
+			settings: AudioSettings::from_preset(ExpansionMix::Famicom),
+			expansion: None,
+			declicker: Declicker::new(),
+			registers: [0; REGISTER_COUNT],
+			frame_counter: FrameCounter::new(),
+			pulse1: Pulse::new(PulseId::One),
+			pulse2: Pulse::new(PulseId::Two),
+			triangle: Triangle::new(),
+			noise: Noise::new(),
+			dmc: Dmc::new(),
+			level_sum: 0.0,
+			stem_sums: [0.0; 6],
+			cycles_since_sample: 0,
+			stems: None,
+			output: None,
+			forwarded: 0,
+			deferred: 0,
+			horizon: 0,
+		}
+	}
+
+	// Sets or removes the receiver of the samples.
+	pub fn set_output(&mut self, output: Option<Box<dyn AudioOutput + Send>>) {
+		self.catch_up();
+		self.output = output;
+		self.forwarded = self.samples.len();
+	}
+
+	pub fn take_output(&mut self) -> Option<Box<dyn AudioOutput + Send>> {
+		self.catch_up();
+		self.output.take()
+	}
+
+	// Hands the samples since the last call to the output.
+	fn forward_samples(&mut self) {
+		if let Some(ref mut output) = self.output {
+			if self.forwarded < self.samples.len() {
+				output.samples(SAMPLE_RATE, &self.samples[self.forwarded..]);
+			}
+		}
+		self.forwarded = self.samples.len();
+	}
+
+	pub fn set_region(&mut self, region: Region) {
+		self.catch_up();
+		self.region = region;
+		self.frame_counter.set_region(region);
+		self.noise.set_region(region);
+		self.dmc.set_region(region);
+		self.horizon = self.cycles_until_event();
+	}
+
+	pub fn set_settings(&mut self, settings: AudioSettings) {
+		self.catch_up();
+		if settings.expansion_audio != self.settings.expansion_audio || settings.solo != self.settings.solo {
+			self.declick();
+		}
+		self.settings = settings;
+	}
+
+	// Sets the expansion audio mixed into the following samples.
+	pub fn set_expansion_audio(&mut self, audio: Option<(ExpansionChip, f32)>) {
+		if audio != self.expansion {
+			self.catch_up();
+			self.expansion = audio;
+		}
+	}
+
+	// A write of the CPU to 4000 - 401F. The values are kept for tools as
+	// well, see channel_registers.
+	pub fn write(&mut self, address: u16, value: u8) {
+		self.catch_up();
+		if let Some(register) = self.registers.get_mut(address.wrapping_sub(REGISTERS_START) as usize) {
+			*register = value;
+		}
+		match address {
+			0x4000..=0x4003 => self.pulse1.write(address - 0x4000, value),
+			0x4004..=0x4007 => self.pulse2.write(address - 0x4004, value),
+			0x4008..=0x400B => self.triangle.write(address - 0x4008, value),
+			0x400C..=0x400F => self.noise.write(address - 0x400C, value),
+			0x4010..=0x4013 => self.dmc.write(address - 0x4010, value),
+			STATUS => {
+				self.pulse1.set_enabled(value & 0x01 != 0);
+				self.pulse2.set_enabled(value & 0x02 != 0);
+				self.triangle.set_enabled(value & 0x04 != 0);
+				self.noise.set_enabled(value & 0x08 != 0);
+				self.dmc.set_enabled(value & 0x10 != 0);
+			}
+			FRAME_COUNTER => {
+				let clocks = self.frame_counter.write(value);
+				self.clock_frame(clocks);
+			}
+			_ => {}
+		}
+		self.horizon = self.cycles_until_event();
+	}
+
+	// A read of $4015, which acknowledges the frame interrupt.
+	pub fn read_status(&mut self) -> u8 {
+		self.catch_up();
+		let status = self.peek_status();
+		self.frame_counter.acknowledge_irq();
+		status
+	}
+
+	// Bits 0-3 tell whether the length counters of the pulse, triangle and
+	// noise channels are above 0, bit 4 whether the DMC sample has bytes
+	// left, bits 6 and 7 are the frame and DMC interrupts.
+	pub fn peek_status(&self) -> u8 {
+		self.pulse1.active() as u8 | (self.pulse2.active() as u8) << 1 | (self.triangle.active() as u8) << 2
+			| (self.noise.active() as u8) << 3 | (self.dmc.active() as u8) << 4
+			| (self.frame_counter.irq_pending() as u8) << 6 | (self.dmc.irq_pending() as u8) << 7
+	}
+
+	// Whether the APU asserts the IRQ line.
+	pub fn irq_pending(&self) -> bool {
+		self.frame_counter.irq_pending() || self.dmc.irq_pending()
+	}
+
+	// The address of the next DMC sample byte, if the channel needs it. The
+	// DMA unit reads it from CPU memory and hands it to dmc_fetched, see
+	// Nes::run_dma.
+	pub fn take_dmc_request(&mut self) -> Option<u16> {
+		self.dmc.take_request()
+	}
+
+	pub fn dmc_fetched(&mut self, value: u8) {
+		self.catch_up();
+		self.dmc.fetched(value);
+		self.horizon = self.cycles_until_event();
+	}
+
+	// Number of CPU cycles to run until the next step of the frame counter
+	// has happened.
+	pub fn cycles_until_frame_step(&self) -> u32 {
+		self.frame_counter.cycles_until_step().saturating_sub(self.deferred)
+	}
+
+	// Number of CPU cycles to run until the DMC channel asks for its next
+	// sample byte, see take_dmc_request. None while it needs none.
+	pub fn cycles_until_dmc_fetch(&self) -> Option<u32> {
+		self.dmc_fetch_cycles().map(|cycles| cycles.saturating_sub(self.deferred))
+	}
+
+	// The same, from the cycles run so far. The DMC timer runs on odd
+	// cycles.
+	fn dmc_fetch_cycles(&self) -> Option<u32> {
+		self.dmc.clocks_until_request().map(|clocks| match clocks {
+			0 => 0,
+			_ => self.cycles.is_multiple_of(2) as u32 + 2 * clocks - 1,
+		})
+	}
+
+	// Cycles from the cycles run so far until the state changes on its own,
+	// rather than by the CPU.
+	fn cycles_until_event(&self) -> u32 {
+		let dmc = self.dmc_fetch_cycles().unwrap_or(u32::max_value());
+		self.frame_counter.cycles_until_step().min(dmc)
+	}
+
+	// The last values written to the registers of a channel.
+	pub fn channel_registers(&self, channel: Channel) -> &[u8] {
+		let range = channel.registers();
+		&self.registers[(range.start - REGISTERS_START) as usize..(range.end - REGISTERS_START) as usize]
+	}
+
+	// Runs for a number of CPU cycles, after the deferred ones. Afterwards
+	// exactly samples_for_cycles(total cycles) samples have been generated.
+	pub fn clock(&mut self, cycles: u32) {
+		let cycles = cycles + mem::replace(&mut self.deferred, 0);
+		for _ in 0..cycles {
+			self.clock_cycle();
+		}
+		self.horizon = self.cycles_until_event();
+		if self.output.is_some() && self.samples.len() - self.forwarded >= OUTPUT_BATCH {
+			self.forward_samples();
+		}
+	}
+
+	// Runs for a number of CPU cycles later, in one go with the following
+	// ones. Until the next frame counter step or DMC fetch only the CPU can
+	// change the state, so the cycles run when it accesses the registers,
+	// by that event, or when the samples are taken.
+	pub fn defer(&mut self, cycles: u32) {
+		self.deferred += cycles;
+		if self.deferred >= self.horizon {
+			self.clock(0);
+		}
+	}
+
+	// Runs the deferred cycles.
+	pub fn catch_up(&mut self) {
+		if self.deferred > 0 {
+			self.clock(0);
+		}
+	}
+
+	fn clock_cycle(&mut self) {
+		let clocks = self.frame_counter.clock();
+		self.clock_frame(clocks);
+		self.triangle.clock_timer();
+		// the other channel timers run at half the CPU clock
+		if self.cycles % 2 == 1 {
+			self.pulse1.clock_timer();
+			self.pulse2.clock_timer();
+			self.noise.clock_timer();
+			self.dmc.clock_timer();
+		}
+
+		let outputs = self.channel_outputs();
+		self.level_sum += self.mix(&outputs);
+		for &channel in CHANNELS.iter() {
+			self.stem_sums[channel as usize] += self.stem_level(channel, &outputs);
+		}
+		self.cycles_since_sample += 1;
+
+		let before = samples_for_cycles(self.cycles, self.region);
+		self.cycles += 1;
+		if samples_for_cycles(self.cycles, self.region) > before {
+			self.emit_sample();
+		}
+	}
+
+	fn clock_frame(&mut self, clocks: FrameClocks) {
+		if clocks.quarter {
+			self.pulse1.clock_quarter_frame();
+			self.pulse2.clock_quarter_frame();
+			self.triangle.clock_quarter_frame();
+			self.noise.clock_quarter_frame();
+		}
+		if clocks.half {
+			self.pulse1.clock_half_frame();
+			self.pulse2.clock_half_frame();
+			self.triangle.clock_half_frame();
+			self.noise.clock_half_frame();
+		}
+	}
+
+	// Appends the average of the levels since the last sample.
+	fn emit_sample(&mut self) {
+		let cycles = self.cycles_since_sample as f32;
+		let level = (self.level_sum / cycles).clamp(-1.0, 1.0);
+		let sample = (self.declicker.apply(level) * i16::MAX as f32) as i16;
+		self.samples.push(sample);
+		if let Some(ref mut stems) = self.stems {
+			for (stem, sum) in stems.iter_mut().zip(&self.stem_sums) {
+				stem.push(((sum / cycles).clamp(-1.0, 1.0) * i16::MAX as f32) as i16);
+			}
+		}
+		self.stem_sums = [0.0; 6];
+		self.level_sum = 0.0;
+		self.cycles_since_sample = 0;
+	}
+
+	// The outputs of the channels before their DACs, indexed by Channel:
+	// 4 bit levels for the APU channels, the level of the expansion audio.
+	fn channel_outputs(&self) -> [f32; 6] {
+		let mut outputs = [0.0; 6];
+		outputs[Channel::Pulse1 as usize] = self.pulse1.output() as f32;
+		outputs[Channel::Pulse2 as usize] = self.pulse2.output() as f32;
+		outputs[Channel::Triangle as usize] = self.triangle.output() as f32;
+		outputs[Channel::Noise as usize] = self.noise.output() as f32;
+		outputs[Channel::Dmc as usize] = self.dmc.output() as f32;
+		outputs[Channel::Expansion as usize] = self.expansion.map(|(_, level)| level).unwrap_or(0.0);
+		outputs
+	}
+
+	// The level of a channel on its own, as if the others were silent.
+	fn stem_level(&self, channel: Channel, outputs: &[f32; 6]) -> f32 {
+		match channel {
+			Channel::Pulse1 | Channel::Pulse2 => pulse_dac(outputs[channel as usize]),
+			Channel::Triangle => tnd_dac(outputs[channel as usize], 0.0, 0.0),
+			Channel::Noise => tnd_dac(0.0, outputs[channel as usize], 0.0),
+			Channel::Dmc => tnd_dac(0.0, 0.0, outputs[channel as usize]),
+			Channel::Expansion => outputs[channel as usize],
+		}
+	}
+
+	// The output level of the channel outputs, with the expansion audio
+	// scaled and the channels left out as the settings say.
+	fn mix(&self, outputs: &[f32; 6]) -> f32 {
+		let audible = |channel: Channel| {
+			if self.settings.solo.map(|solo| solo == channel).unwrap_or(true) { outputs[channel as usize] } else { 0.0 }
+		};
+		let expansion_factor = self.expansion.map(|(chip, _)| self.settings.expansion_level(chip)).unwrap_or(0.0);
+		pulse_dac(audible(Channel::Pulse1) + audible(Channel::Pulse2))
+			+ tnd_dac(audible(Channel::Triangle), audible(Channel::Noise), audible(Channel::Dmc))
+			+ audible(Channel::Expansion) * expansion_factor
+	}
+
+	// The level of the last sample, from -1 to 1. Deferred cycles are not
+	// part of it.
+	pub fn output(&self) -> f32 {
+		self.declicker.output
+	}
+
+	// Ramps from the level of the last sample to the following ones, e.g.
+	// after loading a state. See Declicker.
+	pub fn declick(&mut self) {
+		let output = self.declicker.output;
+		self.ramp_from(output);
+	}
+
+	// Ramps from the level to the following samples, e.g. from 0 when the
+	// output resumes after a pause.
+	pub fn ramp_from(&mut self, level: f32) {
+		self.catch_up();
+		self.declicker.ramp_from(level);
+	}
+
+	// Continues counting from a number of cycles without generating the
+	// samples before, e.g. after loading a state.
+	pub fn set_cycles(&mut self, cycles: u64) {
+		self.cycles = cycles;
+		self.horizon = self.cycles_until_event();
+	}
+
+	// Returns the samples generated since the last call.
+	pub fn take_audio(&mut self) -> Option<AudioChunk> {
+		self.catch_up();
+		self.forward_samples();
+		self.forwarded = 0;
+		if self.samples.is_empty() {
+			return None;
+		}
+		Some(AudioChunk {
+			sample_rate: SAMPLE_RATE,
+			samples: self.samples.split_off(0),
+		})
+	}
+
+	// Starts or stops recording the output of every channel on its own,
+	// e.g. to export stems. The recording starts with the next samples.
+	pub fn set_stems(&mut self, enabled: bool) {
+		self.catch_up();
+		self.stems = if enabled { Some(vec![Vec::new(); CHANNELS.len()]) } else { None };
+	}
+
+	pub fn stems_enabled(&self) -> bool {
+		self.stems.is_some()
+	}
+
+	// Returns the samples of every channel generated since the last call,
+	// as many as take_audio returns, in the order of CHANNELS. They are the
+	// levels before mixing, so the settings do not change them. None if
+	// stems are not recorded or there are no samples.
+	pub fn take_stems(&mut self) -> Option<Vec<(Channel, AudioChunk)>> {
+		self.catch_up();
+		let stems = match self.stems {
+			Some(ref mut stems) if !stems[0].is_empty() => stems,
+			_ => return None,
+		};
+		Some(CHANNELS.iter().zip(stems.iter_mut()).map(|(&channel, stem)| (channel, AudioChunk {
+			sample_rate: SAMPLE_RATE,
+			samples: stem.split_off(0),
+		})).collect())
+	}
+
+	// The register values, so music tools can resume playback exactly, and
+	// the state of the channels. The cycles and the declicker are restored
+	// by the console, which runs the deferred cycles before.
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		debug_assert_eq!(0, self.deferred);
+		savestate::write_bytes(out, &self.registers)?;
+		self.frame_counter.save_state(out)?;
+		self.pulse1.save_state(out)?;
+		self.pulse2.save_state(out)?;
+		self.triangle.save_state(out)?;
+		self.noise.save_state(out)?;
+		self.dmc.save_state(out)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		savestate::read_bytes(input, &mut self.registers)?;
+		self.frame_counter.load_state(input)?;
+		self.pulse1.load_state(input)?;
+		self.pulse2.load_state(input)?;
+		self.triangle.load_state(input)?;
+		self.noise.load_state(input)?;
+		self.dmc.load_state(input)?;
+		self.deferred = 0;
+		self.horizon = self.cycles_until_event();
+		Ok(())
+	}
+}
+
+// A batch of mono samples, handed from the APU to the frontend.
+#[derive(Clone)]
+pub struct AudioChunk {
+	pub sample_rate: u32,
+	pub samples: Vec<i16>,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+
+	#[test]
+	fn sample_count() {
+		// one second
+		assert_eq!(44099, samples_for_cycles(1_789_772, Region::Ntsc));
+		assert_eq!(44100, samples_for_cycles(1_789_773, Region::Ntsc));
+		assert_eq!(44100, samples_for_cycles(1_662_608, Region::Pal));
+
+		// frames of 29780 and 29781 cycles alternate
+		let mut apu = Apu::new();
+		let mut total = 0;
+		for frame in 0..60 {
+			apu.clock(29780 + frame % 2);
+			let samples = apu.take_audio().unwrap().samples.len();
+			assert!(samples == 733 || samples == 734);
+			total += samples as u64;
+		}
+		assert_eq!(samples_for_cycles(30 * 29780 + 30 * 29781, Region::Ntsc), total);
+		assert!(apu.take_audio().is_none());
+	}
+
+	#[test]
+	fn expansion_audio() {
+		let mut apu = Apu::new();
+		apu.set_expansion_audio(Some((ExpansionChip::Fds, 0.5)));
+		apu.clock(1000);
+		apu.set_expansion_audio(Some((ExpansionChip::Fds, 2.0)));
+		apu.clock(1000);
+		let samples = apu.take_audio().unwrap().samples;
+		assert_eq!(16383, samples[0]);
+		assert_eq!(i16::MAX, samples[samples.len() - 1]);
+
+		let mut settings = AudioSettings::from_preset(ExpansionMix::FrontLoader);
+		settings.set_expansion_level(ExpansionChip::Vrc6, 0.25);
+		apu.set_settings(settings.clone());
+		apu.set_expansion_audio(Some((ExpansionChip::Fds, 0.5)));
+		apu.clock(1000);
+		apu.set_expansion_audio(Some((ExpansionChip::Vrc6, 0.5)));
+		apu.clock(1000);
+		settings.expansion_audio = false;
+		apu.set_settings(settings);
+		apu.set_expansion_audio(Some((ExpansionChip::Fds, 0.5)));
+		apu.clock(1000);
+		let samples = apu.take_audio().unwrap().samples;
+		// the first sample averages in the cycles before
+		assert_eq!(8191, samples[1]);
+		assert_eq!(4095, samples[samples.len() / 2]);
+		// switching off ramps down, see Declicker
+		assert!(samples[samples.len() - 1] < 4095);
+		apu.clock(10000);
+		assert_eq!(Some(&0), apu.take_audio().unwrap().samples.last());
+		assert_eq!(Some(ExpansionChip::Namco163), ExpansionChip::from_name("n163"));
+	}
+
+	#[test]
+	fn expansion_levels() {
+		let output = |settings: &AudioSettings, chip: ExpansionChip| {
+			let mut apu = Apu::new();
+			apu.set_settings(settings.clone());
+			apu.set_expansion_audio(Some((chip, 0.5)));
+			apu.clock(10000);
+			apu.output()
+		};
+		let mut settings = AudioSettings::from_preset(ExpansionMix::Famicom);
+		for &chip in EXPANSION_CHIPS.iter() {
+			assert!((output(&settings, chip) - 0.5).abs() < 1e-6, "{}", chip.name());
+		}
+		// lowering the level of one chip leaves the others as they are
+		settings.set_expansion_level(ExpansionChip::Vrc7, 0.5);
+		for &chip in EXPANSION_CHIPS.iter() {
+			let expected = if chip == ExpansionChip::Vrc7 { 0.25 } else { 0.5 };
+			assert!((output(&settings, chip) - expected).abs() < 1e-6, "{}", chip.name());
+		}
+	}
+
+	#[test]
+	fn declick() {
+		// no steps larger than the ramp makes
+		let max_step = |samples: &[i16]| samples.windows(2).map(|pair| (pair[1] as i32 - pair[0] as i32).abs()).max().unwrap();
+		let ramp_step = i16::MAX as i32 / 2 / RAMP_SAMPLES as i32 + 2;
+
+		let mut apu = Apu::new();
+		apu.set_expansion_audio(Some((ExpansionChip::Fds, 0.5)));
+		apu.clock(10000);
+		apu.take_audio();
+		assert!((apu.output() - 0.5).abs() < 1e-6);
+
+		// switched off by the settings
+		let mut settings = AudioSettings::from_preset(ExpansionMix::Famicom);
+		settings.expansion_audio = false;
+		apu.set_settings(settings);
+		apu.set_expansion_audio(Some((ExpansionChip::Fds, 0.5)));
+		apu.clock(10000);
+		let samples = apu.take_audio().unwrap().samples;
+		assert_eq!(16383, samples[0]);
+		assert!(max_step(&samples) <= ramp_step);
+		assert_eq!(0, samples[samples.len() - 1]);
+
+		// switched on again
+		apu.set_settings(AudioSettings::from_preset(ExpansionMix::Famicom));
+		apu.set_expansion_audio(Some((ExpansionChip::Fds, 0.5)));
+		apu.clock(10000);
+		let samples = apu.take_audio().unwrap().samples;
+		assert_eq!(0, samples[0]);
+		assert!(max_step(&samples) <= ramp_step);
+		assert_eq!(16383, samples[samples.len() - 1]);
+
+		// resuming from silence
+		apu.ramp_from(0.0);
+		apu.clock(10000);
+		let samples = apu.take_audio().unwrap().samples;
+		assert_eq!(0, samples[0]);
+		assert!(max_step(&samples) <= ramp_step);
+	}
+
+	#[test]
+	fn stems_and_solo() {
+		let mut apu = Apu::new();
+		apu.set_expansion_audio(Some((ExpansionChip::Vrc6, 0.5)));
+		apu.clock(1000);
+		assert!(apu.take_stems().is_none());
+		apu.take_audio();
+
+		apu.set_stems(true);
+		let mut settings = AudioSettings::from_preset(ExpansionMix::FrontLoader);
+		settings.solo = Some(Channel::Expansion);
+		apu.set_settings(settings.clone());
+		apu.clock(10000);
+		let samples = apu.take_audio().unwrap().samples;
+		let stems = apu.take_stems().unwrap();
+		assert_eq!(CHANNELS.len(), stems.len());
+		assert_eq!(Channel::Pulse1, stems[0].0);
+		assert!(stems.iter().all(|&(_, ref stem)| stem.samples.len() == samples.len()));
+		assert!(stems[..5].iter().all(|&(_, ref stem)| stem.samples.iter().all(|&sample| sample == 0)));
+		// before the mixing, which halves the expansion audio
+		assert!(stems[5].1.samples.iter().all(|&sample| sample == 16383));
+		assert_eq!(Some(&8191), samples.last());
+		assert!(apu.take_stems().is_none());
+
+		// soloing another channel leaves the stems alone
+		settings.solo = Some(Channel::from_name("triangle").unwrap());
+		apu.set_settings(settings);
+		apu.clock(10000);
+		assert_eq!(Some(&0), apu.take_audio().unwrap().samples.last());
+		assert_eq!(Some(&16383), apu.take_stems().unwrap()[5].1.samples.last());
+		apu.set_stems(false);
+		apu.clock(1000);
+		assert!(apu.take_stems().is_none());
+	}
+
+	#[test]
+	fn registers() {
+		let mut apu = Apu::new();
+		apu.write(0x4002, 0xFD);
+		apu.write(0x400F, 0x08);
+		apu.write(0x4017, 0x40);
+		apu.write(0x401A, 0x12);
+		assert_eq!(&[0, 0, 0xFD, 0], apu.channel_registers(Channel::Pulse1));
+		assert_eq!(&[0, 0, 0, 0x08], apu.channel_registers(Channel::Noise));
+		assert!(apu.channel_registers(Channel::Expansion).is_empty());
+
+		let mut state = Vec::new();
+		apu.save_state(&mut state).unwrap();
+		let mut loaded = Apu::new();
+		loaded.load_state(&mut &state[..]).unwrap();
+		assert_eq!(&[0, 0, 0xFD, 0], loaded.channel_registers(Channel::Pulse1));
+		assert_eq!(&[0, 0, 0, 0x08], loaded.channel_registers(Channel::Noise));
+	}
+
+	#[test]
+	fn pulse_tone() {
+		let mut apu = Apu::new();
+		apu.set_stems(true);
+		apu.write(STATUS, 0x01);
+		// 50% duty, constant volume 15, about 440 Hz
+		apu.write(0x4000, 0xBF);
+		apu.write(0x4002, 0xFD);
+		apu.write(0x4003, 0x00);
+		assert_eq!(0x01, apu.peek_status());
+		apu.clock(1_789_773);
+		let samples = apu.take_audio().unwrap().samples;
+		let high = (pulse_dac(15.0) * i16::MAX as f32) as i16;
+		assert!(samples.iter().all(|&sample| sample >= 0 && sample <= high));
+		let rising = samples.windows(2).filter(|pair| pair[0] < high / 2 && pair[1] >= high / 2).count();
+		assert!((438..=442).contains(&rising), "{} periods", rising);
+		let stems = apu.take_stems().unwrap();
+		assert_eq!(samples, stems[Channel::Pulse1 as usize].1.samples);
+		assert!(stems[Channel::Pulse2 as usize].1.samples.iter().all(|&sample| sample == 0));
+
+		// the length counter is halted, the frame interrupt was raised
+		assert_eq!(0x41, apu.read_status());
+		assert_eq!(0x01, apu.peek_status());
+		// inhibited interrupts
+		apu.write(0x4017, 0x40);
+		apu.write(STATUS, 0x00);
+		apu.clock(1000);
+		assert_eq!(Some(&0), apu.take_audio().unwrap().samples.last());
+		assert_eq!(0x00, apu.peek_status());
+
+		// length 10 without halt
+		apu.write(0x4017, 0x40);
+		apu.write(STATUS, 0x03);
+		apu.write(0x4004, 0x9F);
+		apu.write(0x4006, 0xFD);
+		apu.write(0x4007, 0x00);
+		assert_eq!(0x02, apu.peek_status());
+		apu.clock(29830 * 4);
+		assert_eq!(0x02, apu.peek_status());
+		apu.clock(29830);
+		assert_eq!(0x00, apu.read_status());
+		assert!(!apu.irq_pending());
+	}
+
+	#[test]
+	fn triangle_tone() {
+		let mut apu = Apu::new();
+		apu.set_stems(true);
+		apu.write(STATUS, 0x04);
+		// the linear counter keeps reloading, about 220 Hz
+		apu.write(0x4008, 0x81);
+		apu.write(0x400A, 0xFD);
+		apu.write(0x400B, 0x00);
+		assert_eq!(0x04, apu.peek_status());
+		apu.clock(1_789_773);
+		let samples = apu.take_audio().unwrap().samples;
+		let high = (tnd_dac(15.0, 0.0, 0.0) * i16::MAX as f32) as i16;
+		assert!(samples.iter().all(|&sample| sample >= 0 && sample <= high));
+		let rising = samples.windows(2).filter(|pair| pair[0] < high / 2 && pair[1] >= high / 2).count();
+		assert!((218..=222).contains(&rising), "{} periods", rising);
+		let stems = apu.take_stems().unwrap();
+		assert_eq!(samples, stems[Channel::Triangle as usize].1.samples);
+
+		// soloing the pulse channels leaves it out
+		let mut settings = apu.settings.clone();
+		settings.solo = Some(Channel::Pulse1);
+		apu.set_settings(settings);
+		apu.clock(10000);
+		assert_eq!(Some(&0), apu.take_audio().unwrap().samples.last());
+	}
+
+	#[test]
+	fn noise() {
+		let mut apu = Apu::new();
+		apu.set_stems(true);
+		apu.write(FRAME_COUNTER, 0x40);
+		apu.write(STATUS, 0x08);
+		// constant volume 15, the length counter halted
+		apu.write(0x400C, 0x3F);
+		apu.write(0x400E, 0x03);
+		apu.write(0x400F, 0x00);
+		assert_eq!(0x08, apu.peek_status());
+		apu.clock(100_000);
+		let samples = apu.take_audio().unwrap().samples;
+		let high = (tnd_dac(0.0, 15.0, 0.0) * i16::MAX as f32) as i16;
+		assert!(samples.iter().all(|&sample| sample >= 0 && sample <= high));
+		// about half of the time high
+		let average = samples.iter().map(|&sample| sample as f32).sum::<f32>() / samples.len() as f32;
+		assert!(average > high as f32 * 0.4 && average < high as f32 * 0.6, "{} of {}", average, high);
+		let stems = apu.take_stems().unwrap();
+		assert_eq!(samples, stems[Channel::Noise as usize].1.samples);
+
+		apu.write(STATUS, 0x00);
+		assert_eq!(0x00, apu.peek_status());
+	}
+
+	#[test]
+	fn dmc() {
+		let mut apu = Apu::new();
+		apu.set_stems(true);
+		// the level written directly, as games play PCM samples
+		apu.write(0x4011, 0x7F);
+		apu.clock(1000);
+		let samples = apu.take_audio().unwrap().samples;
+		let level = (tnd_dac(0.0, 0.0, 127.0) * i16::MAX as f32) as i16;
+		assert_eq!(Some(&level), samples.last());
+		assert_eq!(samples, apu.take_stems().unwrap()[Channel::Dmc as usize].1.samples);
+
+		// a sample of 17 bytes at $C040 with its interrupt
+		apu.write(0x4010, 0x8F);
+		apu.write(0x4012, 0x01);
+		apu.write(0x4013, 0x01);
+		apu.write(STATUS, 0x10);
+		assert_eq!(0x10, apu.peek_status() & 0x10);
+		assert_eq!(Some(0xC040), apu.take_dmc_request());
+		assert_eq!(None, apu.take_dmc_request());
+		apu.dmc_fetched(0x00);
+		for i in 1..17 {
+			// the next one when the byte starts playing
+			let mut request = None;
+			while request.is_none() {
+				apu.clock(54);
+				request = apu.take_dmc_request();
+			}
+			assert_eq!(Some(0xC040 + i), request);
+			apu.dmc_fetched(0x00);
+		}
+		assert_eq!(0x80, apu.peek_status() & 0x90);
+		assert!(apu.irq_pending());
+		// reading the status leaves it, writing acknowledges it
+		apu.read_status();
+		assert!(apu.irq_pending());
+		apu.write(STATUS, 0x00);
+		assert!(!apu.irq_pending());
+	}
+
+	#[test]
+	fn deferred_cycles() {
+		let mut eager = Apu::new();
+		let mut lazy = Apu::new();
+		for apu in [&mut eager, &mut lazy].iter_mut() {
+			apu.write(0x4000, 0xBF);
+			apu.write(0x4002, 0x80);
+			apu.write(0x4003, 0x08);
+			apu.write(0x4010, 0x8E);
+			apu.write(0x4013, 0x02);
+			apu.write(STATUS, 0x11);
+		}
+		let mut step = 0;
+		let mut longest = 0;
+		while eager.cycles < 100000 {
+			// instructions of 2 to 7 cycles
+			let cycles = 2 + step % 6;
+			step += 1;
+			eager.clock(cycles);
+			lazy.defer(cycles);
+			longest = longest.max(lazy.deferred);
+			assert_eq!(eager.irq_pending(), lazy.irq_pending());
+			assert_eq!(eager.peek_status(), lazy.peek_status());
+			let request = eager.take_dmc_request();
+			assert_eq!(request, lazy.take_dmc_request());
+			if request.is_some() {
+				eager.dmc_fetched(0x5A);
+				lazy.dmc_fetched(0x5A);
+			}
+			if eager.peek_status() & 0x80 != 0 {
+				// restarts the sample
+				eager.write(STATUS, 0x11);
+				lazy.write(STATUS, 0x11);
+			}
+		}
+		// the cycles ran in batches between the DMC fetches
+		assert!(longest > 100);
+		assert_eq!(eager.take_audio().unwrap().samples, lazy.take_audio().unwrap().samples);
+	}
+
+	struct SampleRecorder {
+		samples: Arc<Mutex<Vec<i16>>>,
+	}
+
+	impl AudioOutput for SampleRecorder {
+		fn samples(&mut self, sample_rate: u32, samples: &[i16]) {
+			assert_eq!(SAMPLE_RATE, sample_rate);
+			self.samples.lock().unwrap().extend_from_slice(samples);
+		}
+	}
+
+	#[test]
+	fn audio_output() {
+		let played = Arc::new(Mutex::new(Vec::new()));
+		let mut apu = Apu::new();
+		apu.set_expansion_audio(Some((ExpansionChip::Fds, 0.5)));
+		apu.set_output(Some(Box::new(SampleRecorder { samples: played.clone() })));
+		// batches of OUTPUT_BATCH samples
+		apu.clock(10000);
+		assert!(played.lock().unwrap().is_empty());
+		apu.clock(1000);
+		let batch = played.lock().unwrap().len();
+		assert!(batch >= OUTPUT_BATCH);
+		apu.clock(100);
+		assert_eq!(batch, played.lock().unwrap().len());
+		// and the rest when the samples are taken
+		let samples = apu.take_audio().unwrap().samples;
+		assert!(samples.len() > batch);
+		assert_eq!(samples, *played.lock().unwrap());
+		apu.clock(1000);
+		apu.take_audio();
+		assert_eq!(samples_for_cycles(12100, Region::Ntsc) as usize, played.lock().unwrap().len());
+		assert!(apu.take_output().is_some());
+	}
+}
@@ -0,0 +1,175 @@
+use apu::envelope::Envelope;
+use apu::length_counter::LengthCounter;
+use region::Region;
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// Timer periods in CPU cycles, indexed by bits 0-3 of $400E.
+const NTSC_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const PAL_PERIODS: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+// The noise channel: pseudo-random bits from a 15 bit linear feedback
+// shift register, with envelope and length counter. In the long mode the
+// feedback comes from bit 1 and the sequence repeats after 32767 bits, in
+// the short mode from bit 6, which gives a metallic tone of 93 or 31 bits.
+#[derive(Debug, Clone)]
+pub struct Noise {
+	region: Region,
+	// bit 7 of $400E
+	short_mode: bool,
+	// in CPU cycles, the register shifts every period
+	period: u16,
+	timer: u16,
+	shift_register: u16,
+	envelope: Envelope,
+	length: LengthCounter,
+}
+
+impl Noise {
+	pub fn new() -> Noise {
+		Noise {
+			region: Region::Ntsc,
+			short_mode: false,
+			period: NTSC_PERIODS[0],
+			timer: 0,
+			// 1 at power on, it never becomes 0
+			shift_register: 1,
+			envelope: Envelope::new(),
+			length: LengthCounter::new(),
+		}
+	}
+
+	pub fn set_region(&mut self, region: Region) {
+		self.region = region;
+	}
+
+	// A write to one of the 4 registers of the channel, by index. The
+	// second one is not used.
+	pub fn write(&mut self, register: u16, value: u8) {
+		match register {
+			0 => {
+				self.length.set_halted(value & 0x20 != 0);
+				self.envelope.write(value);
+			}
+			1 => {}
+			2 => {
+				self.short_mode = value & 0x80 != 0;
+				let periods = if self.region == Region::Pal { PAL_PERIODS } else { NTSC_PERIODS };
+				self.period = periods[(value & 0x0F) as usize];
+			}
+			_ => {
+				self.length.load(value);
+				self.envelope.restart();
+			}
+		}
+	}
+
+	// Bit 3 of $4015.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.length.set_enabled(enabled);
+	}
+
+	// For the status of $4015.
+	pub fn active(&self) -> bool {
+		self.length.active()
+	}
+
+	// Clocked every APU cycle, every other CPU cycle. The periods are in
+	// CPU cycles, so the timer counts two of them at a time.
+	pub fn clock_timer(&mut self) {
+		if self.timer <= 2 {
+			self.timer = self.period;
+			self.shift();
+		} else {
+			self.timer -= 2;
+		}
+	}
+
+	fn shift(&mut self) {
+		let tap = if self.short_mode { 6 } else { 1 };
+		let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+		self.shift_register = (self.shift_register >> 1) | feedback << 14;
+	}
+
+	pub fn clock_quarter_frame(&mut self) {
+		self.envelope.clock();
+	}
+
+	pub fn clock_half_frame(&mut self) {
+		self.length.clock();
+	}
+
+	// The 4 bit output level, silent while bit 0 of the register is set.
+	pub fn output(&self) -> u8 {
+		if !self.length.active() || self.shift_register & 1 != 0 {
+			0
+		} else {
+			self.envelope.volume()
+		}
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bool(out, self.short_mode)?;
+		savestate::write_u16(out, self.period)?;
+		savestate::write_u16(out, self.timer)?;
+		savestate::write_u16(out, self.shift_register)?;
+		self.envelope.save_state(out)?;
+		self.length.save_state(out)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.short_mode = savestate::read_bool(input)?;
+		self.period = savestate::read_u16(input)?;
+		self.timer = savestate::read_u16(input)?;
+		// a register of 0 would stay 0
+		self.shift_register = (savestate::read_u16(input)? & 0x7FFF).max(1);
+		self.envelope.load_state(input)?;
+		self.length.load_state(input)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// The number of shifts until the register repeats.
+	fn sequence_length(short_mode: bool) -> usize {
+		let mut noise = Noise::new();
+		noise.write(2, if short_mode { 0x80 } else { 0x00 });
+		let start = noise.shift_register;
+		(1..40000).find(|_| {
+			noise.shift();
+			noise.shift_register == start
+		}).unwrap()
+	}
+
+	#[test]
+	fn sequences() {
+		assert_eq!(32767, sequence_length(false));
+		assert_eq!(93, sequence_length(true));
+	}
+
+	#[test]
+	fn output() {
+		let mut noise = Noise::new();
+		noise.set_enabled(true);
+		// constant volume 12, period 4 CPU cycles, so a shift every other APU
+		// cycle
+		noise.write(0, 0x1C);
+		noise.write(2, 0x00);
+		noise.write(3, 0x08);
+		// a whole sequence
+		let levels: Vec<u8> = (0..65534).map(|_| {
+			noise.clock_timer();
+			noise.output()
+		}).collect();
+		let high = levels.iter().filter(|&&level| level == 12).count();
+		assert_eq!(65534, high + levels.iter().filter(|&&level| level == 0).count());
+		assert!(high > 32000 && high < 33500, "{} high", high);
+
+		noise.set_enabled(false);
+		assert!(!noise.active());
+		assert_eq!(0, noise.output());
+	}
+}
@@ -0,0 +1,235 @@
+use apu::envelope::Envelope;
+use apu::length_counter::LengthCounter;
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// The waveforms of the 4 duty cycles, 12.5%, 25%, 50% and 25% negated.
+// The sequencer steps through them backwards.
+const DUTIES: [[u8; 8]; 4] = [
+	[0, 0, 0, 0, 0, 0, 0, 1],
+	[0, 0, 0, 0, 0, 0, 1, 1],
+	[0, 0, 0, 0, 1, 1, 1, 1],
+	[1, 1, 1, 1, 1, 1, 0, 0],
+];
+
+// Which of the two pulse channels, they differ in how the sweep subtracts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PulseId {
+	// $4000-$4003, subtracts the ones' complement of the change
+	One,
+	// $4004-$4007, subtracts the two's complement
+	Two,
+}
+
+// A pulse channel: a square wave of one of 4 duty cycles, with envelope,
+// length counter and a sweep unit which bends the period up or down.
+#[derive(Debug, Clone)]
+pub struct Pulse {
+	id: PulseId,
+	duty: u8,
+	// position in the duty cycle
+	step: u8,
+	// 11 bits, the wave steps every period + 1 APU cycles
+	period: u16,
+	timer: u16,
+	envelope: Envelope,
+	length: LengthCounter,
+	// $4001 as written
+	sweep: u8,
+	sweep_divider: u8,
+	sweep_reload: bool,
+}
+
+impl Pulse {
+	pub fn new(id: PulseId) -> Pulse {
+		Pulse {
+			id,
+			duty: 0,
+			step: 0,
+			period: 0,
+			timer: 0,
+			envelope: Envelope::new(),
+			length: LengthCounter::new(),
+			sweep: 0,
+			sweep_divider: 0,
+			sweep_reload: false,
+		}
+	}
+
+	// A write to one of the 4 registers of the channel, by index.
+	pub fn write(&mut self, register: u16, value: u8) {
+		match register {
+			0 => {
+				self.duty = value >> 6;
+				self.length.set_halted(value & 0x20 != 0);
+				self.envelope.write(value);
+			}
+			1 => {
+				self.sweep = value;
+				self.sweep_reload = true;
+			}
+			2 => self.period = (self.period & 0x700) | value as u16,
+			_ => {
+				self.period = (self.period & 0xFF) | (value as u16 & 0x07) << 8;
+				self.length.load(value);
+				self.step = 0;
+				self.envelope.restart();
+			}
+		}
+	}
+
+	// Bit 0 or 1 of $4015.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.length.set_enabled(enabled);
+	}
+
+	// For the status of $4015.
+	pub fn active(&self) -> bool {
+		self.length.active()
+	}
+
+	// Clocked every APU cycle, every other CPU cycle.
+	pub fn clock_timer(&mut self) {
+		if self.timer == 0 {
+			self.timer = self.period;
+			self.step = (self.step + 7) & 7;
+		} else {
+			self.timer -= 1;
+		}
+	}
+
+	pub fn clock_quarter_frame(&mut self) {
+		self.envelope.clock();
+	}
+
+	pub fn clock_half_frame(&mut self) {
+		self.length.clock();
+		let shift = self.sweep & 0x07;
+		if self.sweep_divider == 0 && self.sweep & 0x80 != 0 && shift > 0 && !self.muted() {
+			self.period = self.target_period();
+		}
+		if self.sweep_divider == 0 || self.sweep_reload {
+			self.sweep_divider = (self.sweep >> 4) & 0x07;
+			self.sweep_reload = false;
+		} else {
+			self.sweep_divider -= 1;
+		}
+	}
+
+	// The period the sweep moves to next. It is computed all the time, even
+	// with the sweep disabled, and mutes the channel if it overflows.
+	fn target_period(&self) -> u16 {
+		let change = self.period >> (self.sweep & 0x07);
+		if self.sweep & 0x08 == 0 {
+			self.period + change
+		} else if self.id == PulseId::One {
+			self.period.saturating_sub(change + 1)
+		} else {
+			self.period - change
+		}
+	}
+
+	// Periods below 8 would be ultrasonic and are silenced, as are targets
+	// past 11 bits.
+	fn muted(&self) -> bool {
+		self.period < 8 || self.target_period() > 0x7FF
+	}
+
+	// The 4 bit output level.
+	pub fn output(&self) -> u8 {
+		if !self.length.active() || self.muted() || DUTIES[self.duty as usize][self.step as usize] == 0 {
+			0
+		} else {
+			self.envelope.volume()
+		}
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.duty)?;
+		savestate::write_u8(out, self.step)?;
+		savestate::write_u16(out, self.period)?;
+		savestate::write_u16(out, self.timer)?;
+		self.envelope.save_state(out)?;
+		self.length.save_state(out)?;
+		savestate::write_u8(out, self.sweep)?;
+		savestate::write_u8(out, self.sweep_divider)?;
+		savestate::write_bool(out, self.sweep_reload)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.duty = savestate::read_u8(input)? & 0x03;
+		self.step = savestate::read_u8(input)? & 0x07;
+		self.period = savestate::read_u16(input)? & 0x7FF;
+		self.timer = savestate::read_u16(input)? & 0x7FF;
+		self.envelope.load_state(input)?;
+		self.length.load_state(input)?;
+		self.sweep = savestate::read_u8(input)?;
+		self.sweep_divider = savestate::read_u8(input)? & 0x07;
+		self.sweep_reload = savestate::read_bool(input)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// The outputs of the next APU cycles.
+	fn wave(pulse: &mut Pulse, cycles: usize) -> Vec<u8> {
+		(0..cycles).map(|_| {
+			pulse.clock_timer();
+			pulse.output()
+		}).collect()
+	}
+
+	#[test]
+	fn duty_and_period() {
+		let mut pulse = Pulse::new(PulseId::One);
+		pulse.set_enabled(true);
+		// 25% duty, constant volume 9, period 9, so 10 cycles per step
+		pulse.write(0, 0x59);
+		pulse.write(2, 0x09);
+		pulse.write(3, 0x08);
+		let levels = wave(&mut pulse, 160);
+		assert_eq!(40, levels.iter().filter(|&&level| level == 9).count());
+		assert_eq!(120, levels.iter().filter(|&&level| level == 0).count());
+		assert_eq!(&[9; 10], &levels[..10]);
+
+		// below a period of 8 it is muted
+		pulse.write(2, 0x07);
+		assert_eq!(0, wave(&mut pulse, 160).iter().map(|&level| level as u32).sum::<u32>());
+
+		pulse.set_enabled(false);
+		assert!(!pulse.active());
+	}
+
+	#[test]
+	fn sweep() {
+		let sweep = |id: PulseId, sweep: u8, period: u16| {
+			let mut pulse = Pulse::new(id);
+			pulse.set_enabled(true);
+			pulse.write(0, 0x3F);
+			pulse.write(1, sweep);
+			pulse.write(2, period as u8);
+			pulse.write(3, (period >> 8) as u8);
+			pulse.clock_half_frame();
+			pulse.period
+		};
+		// enabled, divider period 0, shift 1
+		assert_eq!(0x300, sweep(PulseId::One, 0x81, 0x200));
+		// negated, the channels differ by one
+		assert_eq!(0x0FF, sweep(PulseId::One, 0x89, 0x200));
+		assert_eq!(0x100, sweep(PulseId::Two, 0x89, 0x200));
+		// the target overflows, which mutes the channel even with the
+		// sweep disabled
+		assert_eq!(0x600, sweep(PulseId::One, 0x01, 0x600));
+		let mut pulse = Pulse::new(PulseId::One);
+		pulse.set_enabled(true);
+		pulse.write(0, 0x3F);
+		pulse.write(1, 0x01);
+		pulse.write(2, 0x00);
+		pulse.write(3, 0x06);
+		assert!(pulse.muted());
+	}
+}
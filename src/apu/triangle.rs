@@ -0,0 +1,192 @@
+use apu::length_counter::LengthCounter;
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// The 32 steps of the triangle wave, down and up again.
+const SEQUENCE: [u8; 32] = [
+	15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+	0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// The triangle channel: a triangle wave without volume control, silenced
+// by the length counter or the linear counter, whichever runs out first.
+// When silenced, the wave stops where it is instead of falling to 0.
+#[derive(Debug, Clone)]
+pub struct Triangle {
+	// position in the sequence
+	step: u8,
+	// 11 bits, the wave steps every period + 1 CPU cycles
+	period: u16,
+	timer: u16,
+	length: LengthCounter,
+	// $4008 as written: bit 7 halts the length counter and keeps the
+	// linear counter reloading, bits 0-6 are the reload value
+	linear_control: u8,
+	linear_counter: u8,
+	linear_reload: bool,
+}
+
+// The step at power on. The wave stops at whatever level the game leaves
+// it, which the console filters out as it does not change. As there is no
+// such filter here, the wave starts at level 0 so that an unused channel
+// is silent.
+const POWER_ON_STEP: u8 = 16;
+
+impl Triangle {
+	pub fn new() -> Triangle {
+		Triangle {
+			step: POWER_ON_STEP,
+			period: 0,
+			timer: 0,
+			length: LengthCounter::new(),
+			linear_control: 0,
+			linear_counter: 0,
+			linear_reload: false,
+		}
+	}
+
+	// A write to one of the 4 registers of the channel, by index. The
+	// second one is not used.
+	pub fn write(&mut self, register: u16, value: u8) {
+		match register {
+			0 => {
+				self.linear_control = value;
+				self.length.set_halted(value & 0x80 != 0);
+			}
+			1 => {}
+			2 => self.period = (self.period & 0x700) | value as u16,
+			_ => {
+				self.period = (self.period & 0xFF) | (value as u16 & 0x07) << 8;
+				self.length.load(value);
+				self.linear_reload = true;
+			}
+		}
+	}
+
+	// Bit 2 of $4015.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.length.set_enabled(enabled);
+	}
+
+	// For the status of $4015.
+	pub fn active(&self) -> bool {
+		self.length.active()
+	}
+
+	// Clocked every CPU cycle, unlike the other channels.
+	pub fn clock_timer(&mut self) {
+		if self.timer == 0 {
+			self.timer = self.period;
+			if self.length.active() && self.linear_counter > 0 {
+				self.step = (self.step + 1) & 31;
+			}
+		} else {
+			self.timer -= 1;
+		}
+	}
+
+	pub fn clock_quarter_frame(&mut self) {
+		if self.linear_reload {
+			self.linear_counter = self.linear_control & 0x7F;
+		} else if self.linear_counter > 0 {
+			self.linear_counter -= 1;
+		}
+		if self.linear_control & 0x80 == 0 {
+			self.linear_reload = false;
+		}
+	}
+
+	pub fn clock_half_frame(&mut self) {
+		self.length.clock();
+	}
+
+	// The 4 bit output level.
+	pub fn output(&self) -> u8 {
+		SEQUENCE[self.step as usize]
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.step)?;
+		savestate::write_u16(out, self.period)?;
+		savestate::write_u16(out, self.timer)?;
+		self.length.save_state(out)?;
+		savestate::write_u8(out, self.linear_control)?;
+		savestate::write_u8(out, self.linear_counter)?;
+		savestate::write_bool(out, self.linear_reload)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.step = savestate::read_u8(input)? & 31;
+		self.period = savestate::read_u16(input)? & 0x7FF;
+		self.timer = savestate::read_u16(input)? & 0x7FF;
+		self.length.load_state(input)?;
+		self.linear_control = savestate::read_u8(input)?;
+		self.linear_counter = savestate::read_u8(input)? & 0x7F;
+		self.linear_reload = savestate::read_bool(input)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// The outputs of the next CPU cycles.
+	fn wave(triangle: &mut Triangle, cycles: usize) -> Vec<u8> {
+		(0..cycles).map(|_| {
+			triangle.clock_timer();
+			triangle.output()
+		}).collect()
+	}
+
+	#[test]
+	fn wave_and_period() {
+		let mut triangle = Triangle::new();
+		triangle.set_enabled(true);
+		// linear counter 10, period 1, so 2 cycles per step
+		triangle.write(0, 0x0A);
+		triangle.write(2, 0x01);
+		triangle.write(3, 0x08);
+		// silent until the linear counter is loaded
+		assert_eq!(vec![0; 8], wave(&mut triangle, 8));
+		triangle.clock_quarter_frame();
+		let levels = wave(&mut triangle, 64);
+		assert_eq!(&[1, 1, 2, 2], &levels[..4]);
+		assert_eq!(15 * 16 * 2, levels.iter().map(|&level| level as u32).sum::<u32>());
+	}
+
+	#[test]
+	fn linear_counter() {
+		let mut triangle = Triangle::new();
+		triangle.set_enabled(true);
+		triangle.write(0, 0x02);
+		triangle.write(3, 0x08);
+		triangle.clock_quarter_frame();
+		assert_eq!(2, triangle.linear_counter);
+		triangle.clock_quarter_frame();
+		triangle.clock_quarter_frame();
+		triangle.clock_quarter_frame();
+		assert_eq!(0, triangle.linear_counter);
+		// the wave stops where it is
+		wave(&mut triangle, 3);
+		let level = triangle.output();
+		assert_eq!(vec![level; 16], wave(&mut triangle, 16));
+
+		// with the control bit set it keeps reloading
+		triangle.write(0, 0x82);
+		triangle.write(3, 0x08);
+		for _ in 0..10 {
+			triangle.clock_quarter_frame();
+		}
+		assert_eq!(2, triangle.linear_counter);
+		// and the length counter is halted
+		for _ in 0..300 {
+			triangle.clock_half_frame();
+		}
+		assert!(triangle.active());
+
+		triangle.set_enabled(false);
+		assert!(!triangle.active());
+	}
+}
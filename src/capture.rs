@@ -0,0 +1,96 @@
+use cartridge::load_rom;
+use input_stream::{InputStream, StreamFormat};
+use nes::Nes;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+// Headless batch mode:
+//   capture <rom> [--frames N] [--every N] [--out DIR] [--input-stream text|binary]
+//
+// Runs the ROM for the given number of frames without opening a window and
+// writes every n-th frame to DIR as PPM image, named after its frame number.
+// With --input-stream the buttons are read from stdin, see InputStream, and
+// every frame waits for them, so a piped script plays the same every time.
+pub fn run(args: &[String]) {
+	let mut rom_path = None;
+	let mut frames = 600;
+	let mut every = 60;
+	let mut out_dir = String::from("capture");
+	let mut input_stream = None;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_ref() {
+			"--frames" | "--every" => {
+				let value = match args.next().and_then(|value| value.parse::<u64>().ok()) {
+					Some(value) if value > 0 => value,
+					_ => {
+						println!("{} expects a positive number.", arg);
+						return;
+					}
+				};
+				if arg == "--frames" { frames = value } else { every = value }
+			}
+			"--out" => {
+				out_dir = match args.next() {
+					Some(dir) => dir.clone(),
+					None => {
+						println!("--out expects a directory.");
+						return;
+					}
+				};
+			}
+			"--input-stream" => {
+				input_stream = match args.next().and_then(|name| StreamFormat::from_name(name)) {
+					Some(format) => Some(InputStream::stdin(format)),
+					None => {
+						println!("--input-stream expects text or binary.");
+						return;
+					}
+				};
+			}
+			_ => rom_path = Some(arg.clone()),
+		}
+	}
+	let rom_path = match rom_path {
+		Some(path) => path,
+		None => {
+			println!("Usage: capture <rom> [--frames N] [--every N] [--out DIR] [--input-stream text|binary]");
+			return;
+		}
+	};
+
+	let cartridge = match load_rom(&rom_path) {
+		Ok(rom) => rom,
+		Err(err) => {
+			println!("Could not load ROM: {}", err);
+			return;
+		}
+	};
+	if let Err(err) = fs::create_dir_all(&out_dir) {
+		println!("Could not create {}: {}", out_dir, err);
+		return;
+	}
+
+	let mut nes = Nes::new(cartridge);
+	let mut written = 0;
+	for _ in 0..frames {
+		if let Some(ref mut stream) = input_stream {
+			let buttons = stream.buttons(nes.ppu().frame_number(), true);
+			nes.set_buttons(0, buttons[0]);
+			nes.set_buttons(1, buttons[1]);
+		}
+		let frame = nes.run_frame();
+		if !(frame.number + 1).is_multiple_of(every) {
+			continue;
+		}
+		let path = Path::new(&out_dir).join(format!("frame_{:06}.ppm", frame.number + 1));
+		let result = File::create(&path).and_then(|file| frame.write_ppm(&mut BufWriter::new(file)));
+		if let Err(err) = result {
+			println!("Could not write {}: {}", path.display(), err);
+			return;
+		}
+		written += 1;
+	}
+	println!("Wrote {} screenshots to {}.", written, out_dir);
+}
@@ -0,0 +1,240 @@
+use cartridge::{Cartridge, MirrorMode, HAS_CHR_RAM};
+use cpu::memory_map;
+use std::fmt;
+use std::io::{self, Read, Write};
+use savestate;
+
+// Camerica/Codemasters BF909x boards with 8 KiB CHR RAM.
+// iNES mapper 071 (BF9093, BF9097)
+//   8000-BFFF  PRG ROM (switchable 16 KiB bank)
+//   C000-FFFF  PRG ROM (fixed to last bank)
+//   9000-9FFF  write: single screen mirroring in bit 4 (Fire Hawk only)
+//   C000-FFFF  write: PRG bank
+// iNES mapper 232 (BF9096, Quattro multicarts)
+//   8000-BFFF  PRG ROM (switchable 16 KiB bank within the 64 KiB block)
+//   C000-FFFF  PRG ROM (fixed to last bank of the 64 KiB block)
+//   8000-BFFF  write: 64 KiB block in bits 3-4
+//   C000-FFFF  write: PRG bank within the block
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_071
+// and http://wiki.nesdev.com/w/index.php/INES_Mapper_232
+#[derive(Clone)]
+pub struct Camerica {
+	prg_rom: Vec<u8>,
+	chr_ram: [u8; 8192],
+	quattro: bool,
+	prg_bank: u8,
+	block: u8,
+	ppu_ram: [u8; 4096],
+	mirror_mode: MirrorMode,
+}
+
+impl Camerica {
+	// Mapper 71.
+	pub fn new(prg_rom: Vec<u8>, mirror_mode: MirrorMode) -> Camerica {
+		Camerica::with_board(prg_rom, mirror_mode, false)
+	}
+
+	// Mapper 232.
+	pub fn new_quattro(prg_rom: Vec<u8>, mirror_mode: MirrorMode) -> Camerica {
+		Camerica::with_board(prg_rom, mirror_mode, true)
+	}
+
+	fn with_board(prg_rom: Vec<u8>, mirror_mode: MirrorMode, quattro: bool) -> Camerica {
+		assert!(prg_rom.len().is_multiple_of(16 * 1024) && !prg_rom.is_empty());
+		Camerica {
+			prg_rom,
+			chr_ram: [0; 8192],
+			quattro,
+			prg_bank: 0,
+			block: 0,
+			ppu_ram: [0; 4096],
+			mirror_mode,
+		}
+	}
+
+	// Returns the 16 KiB banks mapped to 8000 and C000.
+	fn banks(&self) -> (usize, usize) {
+		if self.quattro {
+			let first = (self.block as usize) * 4;
+			(first + (self.prg_bank as usize & 0b11), first + 3)
+		} else {
+			(self.prg_bank as usize & 0x0F, self.prg_rom.len() / (16 * 1024) - 1)
+		}
+	}
+}
+
+impl fmt::Debug for Camerica {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Camerica")
+			.field("prg_rom_size", &self.prg_rom.len())
+			.field("quattro", &self.quattro)
+			.field("prg_bank", &self.prg_bank)
+			.field("block", &self.block)
+			.field("mirror_mode", &self.mirror_mode)
+			.finish()
+	}
+}
+
+impl Cartridge for Camerica {
+	fn name(&self) -> &'static str {
+		if self.quattro { "Camerica Quattro" } else { "Camerica/Codemasters" }
+	}
+
+	fn capabilities(&self) -> u8 {
+		HAS_CHR_RAM
+	}
+
+	fn read_cpu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr < 0x8000 {
+			// not mapped
+			0
+		} else {
+			let (low, high) = self.banks();
+			let bank = if addr < 0xC000 { low } else { high };
+			let index = bank * 16 * 1024 + (addr as usize & 0x3FFF);
+			self.prg_rom[index % self.prg_rom.len()]
+		}
+	}
+
+	fn write_cpu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr < 0x8000 {
+			// not mapped
+		} else if addr < 0xC000 {
+			if self.quattro {
+				self.block = (value >> 3) & 0b11;
+			} else if addr & 0xF000 == 0x9000 {
+				// Only Fire Hawk writes here, other games keep the
+				// mirroring from the header.
+				self.mirror_mode =
+					if value & 0b10000 == 0 { MirrorMode::SingleScreenLower }
+					else { MirrorMode::SingleScreenUpper };
+			}
+		} else {
+			self.prg_bank = value;
+		}
+	}
+
+	fn read_ppu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_ram[addr as usize]
+		} else {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)]
+		}
+	}
+
+	fn write_ppu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_ram[addr as usize] = value;
+		} else {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)] = value;
+		}
+	}
+
+	fn mirror_mode(&self) -> MirrorMode {
+		self.mirror_mode.clone()
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bytes(out, &self.chr_ram)?;
+		savestate::write_u8(out, self.prg_bank)?;
+		savestate::write_u8(out, self.block)?;
+		savestate::write_u8(out, match self.mirror_mode {
+			MirrorMode::HorizontalMirroring => 0,
+			MirrorMode::VerticalMirroring => 1,
+			MirrorMode::SingleScreenLower => 2,
+			MirrorMode::SingleScreenUpper => 3,
+			MirrorMode::FourScreen => 4,
+		})?;
+		savestate::write_bytes(out, &self.ppu_ram)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		savestate::read_bytes(input, &mut self.chr_ram)?;
+		self.prg_bank = savestate::read_u8(input)?;
+		self.block = savestate::read_u8(input)?;
+		self.mirror_mode = match savestate::read_u8(input)? {
+			0 => MirrorMode::HorizontalMirroring,
+			1 => MirrorMode::VerticalMirroring,
+			2 => MirrorMode::SingleScreenLower,
+			3 => MirrorMode::SingleScreenUpper,
+			4 => MirrorMode::FourScreen,
+			_ => return savestate::invalid_state("Invalid mirror mode."),
+		};
+		savestate::read_bytes(input, &mut self.ppu_ram)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::{Cartridge, MirrorMode};
+	use cartridge::conformance::{check, numbered_banks, nametable_steps};
+	use cartridge::conformance::Step::*;
+
+	// 16 KiB banks starting with their number
+	fn rom(banks: usize) -> Vec<u8> {
+		numbered_banks(banks, 16 * 1024, 0, 0)
+	}
+
+	#[test]
+	fn prg_71() {
+		let mut a = Camerica::new(rom(8), MirrorMode::VerticalMirroring);
+		for bank in 0..8 {
+			check(&mut a, &[Write(0xC000, bank), Cpu(0x8000, bank), Cpu(0xC000, 7)]);
+		}
+		check(&mut a, &[Write(0x6000, 1), Cpu(0x6000, 0)]);
+	}
+
+	#[test]
+	fn mirroring_71() {
+		let mut a = Camerica::new(rom(8), MirrorMode::VerticalMirroring);
+		// bank writes to 8000 do not change the mirroring
+		check(&mut a, &[Write(0x8000, 0b10000)]);
+		check(&mut a, &nametable_steps(MirrorMode::VerticalMirroring));
+		check(&mut a, &[Write(0x9000, 0b10000)]);
+		check(&mut a, &nametable_steps(MirrorMode::SingleScreenUpper));
+		check(&mut a, &[WritePpu(0x2000, 1), Ppu(0x2C00, 1), Write(0x9FFF, 0), Ppu(0x2000, 0)]);
+		check(&mut a, &nametable_steps(MirrorMode::SingleScreenLower));
+	}
+
+	#[test]
+	fn prg_232() {
+		let mut a = Camerica::new_quattro(rom(16), MirrorMode::HorizontalMirroring);
+		for block in 0..4 {
+			check(&mut a, &[Write(0x8000, block << 3)]);
+			for page in 0..4 {
+				check(&mut a, &[Write(0xC000, page), Cpu(0x8000, block * 4 + page), Cpu(0xC000, block * 4 + 3)]);
+			}
+		}
+		check(&mut a, &[Write(0x9000, 0b10000), Mirroring(MirrorMode::HorizontalMirroring)]);
+	}
+
+	#[test]
+	fn chr_ram() {
+		let mut a = Camerica::new(rom(2), MirrorMode::VerticalMirroring);
+		a.write_ppu(0x0001, 1);
+		a.write_ppu(0x1FFF, 2);
+		assert_eq!(1, a.read_ppu(0x0001));
+		assert_eq!(2, a.read_ppu(0x1FFF));
+	}
+
+	#[test]
+	fn state() {
+		let mut a = Camerica::new(rom(8), MirrorMode::VerticalMirroring);
+		a.write_cpu(0xC000, 5);
+		a.write_cpu(0x9000, 0b10000);
+		a.write_ppu(0x0010, 3);
+		let mut state = Vec::new();
+		a.save_state(&mut state).unwrap();
+
+		let mut b = Camerica::new(rom(8), MirrorMode::VerticalMirroring);
+		b.load_state(&mut &state[..]).unwrap();
+		assert_eq!(5, b.read_cpu(0x8000));
+		assert_eq!(MirrorMode::SingleScreenUpper, b.mirror_mode());
+		assert_eq!(3, b.read_ppu(0x0010));
+	}
+}
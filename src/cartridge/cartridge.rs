@@ -1,30 +1,245 @@
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Write, Seek, SeekFrom};
 use std::io;
+use std::fmt;
 use std::borrow::Borrow;
-use cartridge::mmc1::Mmc1;
+use std::path::Path;
+use cartridge::mmc1::{Mmc1, Mmc1Board};
+use cartridge::mmc3::{Mmc3, Mmc3Irq};
 use cartridge::nrom::NRom;
+use logging::{Level, Category};
+use cartridge::camerica::Camerica;
+use cartridge::protected_cnrom::ProtectedCnRom;
+use cartridge::nanjing::Nanjing;
+use cartridge::datach::Datach;
+use cartridge::fds::{Fds, parse_disk_image};
+use cartridge::rom_info::RomInfo;
+use region::Region;
+use apu::ExpansionChip;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MirrorMode {
 	HorizontalMirroring,
 	VerticalMirroring,
+	SingleScreenLower,
+	SingleScreenUpper,
 	FourScreen,
 }
 
-pub trait Cartridge {
+impl MirrorMode {
+	// Maps a PPU address in 2000-3EFF to an index into the nametable RAM.
+	// The RAM has to be 4 KiB for FourScreen and 2 KiB otherwise.
+	pub fn nametable_index(&self, addr: u16) -> usize {
+		debug_assert!((0x2000..=0x3EFF).contains(&addr));
+		let addr = (addr as usize - 0x2000) & 0xFFF;
+		let table = addr / 0x400;
+		let offset = addr & 0x3FF;
+		match *self {
+			MirrorMode::HorizontalMirroring => (table / 2) * 0x400 + offset,
+			MirrorMode::VerticalMirroring => (table % 2) * 0x400 + offset,
+			MirrorMode::SingleScreenLower => offset,
+			MirrorMode::SingleScreenUpper => 0x400 + offset,
+			MirrorMode::FourScreen => addr,
+		}
+	}
+}
+
+// Deep copies of boxed cartridges, e.g. for rewind or run-ahead. Every
+// cartridge which is Clone gets it.
+pub trait CartridgeClone {
+	fn box_clone(&self) -> Box<dyn Cartridge>;
+}
+
+impl<T: Cartridge + Clone + 'static> CartridgeClone for T {
+	fn box_clone(&self) -> Box<dyn Cartridge> {
+		Box::new(self.clone())
+	}
+}
+
+impl Clone for Box<dyn Cartridge> {
+	fn clone(&self) -> Box<dyn Cartridge> {
+		self.box_clone()
+	}
+}
+
+// What a cartridge has besides its ROM, or'ed together by
+// Cartridge::capabilities, e.g. for the frontend to show and to know what
+// to persist.
+pub const HAS_PRG_RAM: u8 = 0x01;
+// The PRG RAM is battery backed and keeps its contents when switched off.
+pub const HAS_BATTERY: u8 = 0x02;
+pub const HAS_IRQ: u8 = 0x04;
+pub const HAS_EXPANSION_AUDIO: u8 = 0x08;
+pub const HAS_CHR_RAM: u8 = 0x10;
+
+const CAPABILITY_NAMES: [(u8, &'static str); 5] = [
+	(HAS_PRG_RAM, "PRG RAM"),
+	(HAS_BATTERY, "battery"),
+	(HAS_IRQ, "IRQ"),
+	(HAS_EXPANSION_AUDIO, "expansion audio"),
+	(HAS_CHR_RAM, "CHR RAM"),
+];
+
+// The capabilities for the user, e.g. ["PRG RAM", "battery", "IRQ"].
+pub fn capability_names(capabilities: u8) -> Vec<&'static str> {
+	CAPABILITY_NAMES.iter()
+		.filter(|&&(capability, _)| capabilities & capability != 0)
+		.map(|&(_, name)| name)
+		.collect()
+}
+
+// Debug output shows the mapper registers and the sizes of the memories,
+// not their contents.
+pub trait Cartridge: CartridgeClone + fmt::Debug {
+	// The name of the mapper as in mapper_info, e.g. "MMC3".
+	fn name(&self) -> &'static str;
+	// The HAS_ bits which apply to this cartridge.
+	fn capabilities(&self) -> u8;
+
 	fn read_cpu(&mut self, addr: u16) -> u8;
 	fn write_cpu(&mut self, addr: u16, value: u8);
+	// Like read_cpu, but must not change any state. Used by debuggers and
+	// tracers. Mappers with side effects on reads have to override this.
+	fn peek_cpu(&mut self, addr: u16) -> u8 {
+		self.read_cpu(addr)
+	}
 
 	// Attention: These have to handle reads and writes from 0x0000-0x3EFF,
 	// although - strictly speaking - some of these memory areas would be
 	// managed by the PPU itself.
 	fn read_ppu(&mut self, addr: u16) -> u8;
 	fn write_ppu(&mut self, addr: u16, value: u8);
+	// Like read_ppu, but must not change any state, see peek_cpu. Mappers
+	// which watch the PPU fetches have to override this.
+	fn peek_ppu(&mut self, addr: u16) -> u8 {
+		self.read_ppu(addr)
+	}
+	// The nametable mirroring currently in effect. Has to be consistent with
+	// the nametable accesses through read_ppu and write_ppu.
 	fn mirror_mode(&self) -> MirrorMode;
+
+	// Called from the main loop after CPU cycles (M2 clocks) have passed,
+	// for mappers with timers counting CPU cycles. Does nothing by default.
+	fn cpu_clock(&mut self, _cycles: u32) {
+	}
+
+	// The sound chip on the cartridge and its output level, mixed into the
+	// APU output, where 1.0 is full scale at the Famicom levels. Sampled
+	// after cpu_clock. None for cartridges without expansion audio.
+	fn expansion_audio(&self) -> Option<(ExpansionChip, f32)> {
+		None
+	}
+
+	// Called by the PPU for every filtered rising edge of the address line
+	// A12, as used by MMC3 style scanline counters. Does nothing by default.
+	fn ppu_a12_rise(&mut self) {
+	}
+
+	// The level of the IRQ line of the cartridge, polled by the CPU before
+	// every instruction. It is level triggered: the line stays asserted
+	// until the game acknowledges the IRQ through the registers of the
+	// mapper, taking the IRQ does not clear it. The CPU enters the handler
+	// whenever the line is asserted and the I flag is clear, so a handler
+	// which does not acknowledge runs again right after RTI. False for
+	// cartridges without IRQs.
+	fn irq_pending(&self) -> bool {
+		false
+	}
+
+	// Number of CPU cycles until a timer of the mapper raises the IRQ, if
+	// it counts CPU cycles, so the console can schedule it, see
+	// Nes::run_until_event. Asked again after every write of the CPU to the
+	// cartridge. None by default, and for counters of PPU fetches.
+	fn cycles_until_irq(&self) -> Option<u32> {
+		None
+	}
+
+	// Swipes a barcode through the reader of the cartridge, for the few
+	// which have one. Fails with a message for the user otherwise.
+	fn insert_barcode(&mut self, _digits: &str) -> Result<(), String> {
+		Err(String::from("The cartridge has no barcode reader."))
+	}
+
+	// Ejects the disk from the drive of the Famicom Disk System and inserts
+	// its next side a moment later, the first one after the last. Returns
+	// the number of the side, from 1. Fails with a message for the user
+	// without a drive.
+	fn change_disk_side(&mut self) -> Result<usize, String> {
+		Err(String::from("There is no disk drive."))
+	}
+
+	// Marks the PRG RAM as battery backed, as the header tells. Ignored by
+	// cartridges without PRG RAM.
+	fn set_battery(&mut self, _battery: bool) {
+	}
+
+	// Writes the mutable state (RAM and mapper registers) for a save state.
+	// The ROM contents are not part of the state.
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()>;
+	// Restores the state written by save_state of the same cartridge.
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()>;
+}
+
+// Information about a well-known iNES mapper.
+pub struct MapperInfo {
+	pub number: u8,
+	pub name: &'static str,
+	// Approximate number of released games using this mapper.
+	pub games: usize,
+}
+
+// The iNES mapper number set aside for the Famicom Disk System, which has
+// disk images instead, see load_fds.
+const FDS_MAPPER: u8 = 20;
+const FDS_BIOS_NAME: &str = "disksys.rom";
+
+// iNES mappers which can be loaded by load_rom.
+const SUPPORTED_MAPPERS: [u8; 8] = [0, 1, 4, 71, 157, 163, 185, 232];
+
+// The most common mappers, ordered by number.
+const KNOWN_MAPPERS: [MapperInfo; 18] = [
+	MapperInfo { number: 0,   name: "NROM",                 games: 248 },
+	MapperInfo { number: 1,   name: "MMC1",                 games: 680 },
+	MapperInfo { number: 2,   name: "UxROM",                games: 269 },
+	MapperInfo { number: 3,   name: "CNROM",                games: 155 },
+	MapperInfo { number: 4,   name: "MMC3",                 games: 599 },
+	MapperInfo { number: 5,   name: "MMC5",                 games: 24  },
+	MapperInfo { number: 7,   name: "AxROM",                games: 76  },
+	MapperInfo { number: 9,   name: "MMC2",                 games: 2   },
+	MapperInfo { number: 10,  name: "MMC4",                 games: 3   },
+	MapperInfo { number: 11,  name: "Color Dreams",         games: 32  },
+	MapperInfo { number: 66,  name: "GxROM",                games: 17  },
+	MapperInfo { number: 69,  name: "Sunsoft FME-7",        games: 15  },
+	MapperInfo { number: 71,  name: "Camerica/Codemasters", games: 15  },
+	MapperInfo { number: 157, name: "Bandai Datach",        games: 6   },
+	MapperInfo { number: 163, name: "Nanjing",              games: 40  },
+	MapperInfo { number: 185, name: "CNROM (protected)",    games: 9   },
+	MapperInfo { number: 206, name: "DxROM/Namco 108",      games: 33  },
+	MapperInfo { number: 232, name: "Camerica Quattro",     games: 4   },
+];
+
+// Returns the iNES mapper numbers supported by load_rom.
+pub fn supported_mappers() -> &'static [u8] {
+	&SUPPORTED_MAPPERS
 }
 
-pub fn load_rom(path: &str) -> Result<Box<Cartridge>, &'static str> {
+// Returns name and popularity of a mapper, if it is a well-known one.
+pub fn mapper_info(mapper: u8) -> Option<&'static MapperInfo> {
+	KNOWN_MAPPERS.iter().find(|info| info.number == mapper)
+}
+
+pub fn load_rom(path: &str) -> Result<Box<dyn Cartridge>, &'static str> {
+	load_rom_with_info(path).map(|(cartridge, _)| cartridge)
+}
+
+// Like load_rom, additionally returns the header information and checksums.
+pub fn load_rom_with_info(path: &str) -> Result<(Box<dyn Cartridge>, RomInfo), &'static str> {
+	load_rom_with_submapper(path, None)
+}
+
+// Like load_rom_with_info, but with the submapper from a database instead
+// of the one in the header, for ROMs with iNES headers which can not tell.
+pub fn load_rom_with_submapper(path: &str, submapper: Option<u8>) -> Result<(Box<dyn Cartridge>, RomInfo), &'static str> {
 	let mut file = match File::open(path) {
 		Ok(file) => file,
 		Err(_) => return Result::Err("Could not open file."),
@@ -35,28 +250,46 @@ pub fn load_rom(path: &str) -> Result<Box<Cartridge>, &'static str> {
 		Err(_) => return Result::Err("Could not read file."),
 	}
 	if header == [0x4E, 0x45, 0x53, 0x1A] {
-		println!("Loading iNES file.");
-		match load_ines(&mut file) {
+		log!(Level::Info, Category::Loader, "Loading iNES file.");
+		match load_ines(&mut file, submapper) {
 			Ok(rom) => Result::Ok(rom),
 			Err(_) => Result::Err("Could not read file."),
 		}
+	} else if header == [0x46, 0x44, 0x53, 0x1A] || header == [0x01, 0x2A, 0x4E, 0x49] {
+		log!(Level::Info, Category::Loader, "Loading FDS disk image.");
+		load_fds(path, &mut file)
 	} else {
 		Result::Err("Unknown file format.")
 	}
 }
 
-fn load_ines(file: &mut File) -> io::Result<Box<Cartridge>> {
-	let mut header = [0; 16];
-	try!(file.seek(SeekFrom::Start(0)));
-	try!(file.read_exact(&mut header));
-	for byte in header.iter() {
-		print!("{:02X} ", byte);
+// Disk images need the BIOS of the RAM adapter, in the same directory.
+fn load_fds(path: &str, file: &mut File) -> Result<(Box<dyn Cartridge>, RomInfo), &'static str> {
+	let mut data = Vec::new();
+	if file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_end(&mut data)).is_err() {
+		return Result::Err("Could not read file.");
 	}
-	println!("");
+	let sides = parse_disk_image(&data)?;
+	let mut bios = Vec::new();
+	let bios_path = Path::new(path).with_file_name(FDS_BIOS_NAME);
+	match File::open(&bios_path).and_then(|mut file| file.read_to_end(&mut bios)) {
+		Ok(8192) => (),
+		_ => return Result::Err("The FDS BIOS disksys.rom (8 KiB) must be next to the disk image."),
+	}
+	log!(Level::Info, Category::Loader, "Disk sides: {}", sides.len());
 
-	let prg_size = (header[4] as usize) * 16 * 1024;
+	let data = if data.starts_with(b"FDS\x1A") { &data[16..] } else { &data[..] };
+	let info = RomInfo::new(FDS_MAPPER, data, &[], MirrorMode::HorizontalMirroring);
+	log!(Level::Info, Category::Loader, "CRC32: {:08X}  SHA-1: {}", info.crc32, info.sha1_hex());
+	Ok((Box::new(Fds::new(bios, sides)), info))
+}
 
-	let chr_size = (header[5] as usize) * 8 * 1024;
+fn load_ines(file: &mut File, submapper_override: Option<u8>) -> io::Result<(Box<dyn Cartridge>, RomInfo)> {
+	let mut header = [0; 16];
+	file.seek(SeekFrom::Start(0))?;
+	file.read_exact(&mut header)?;
+	let header_bytes: Vec<String> = header.iter().map(|byte| format!("{:02X}", byte)).collect();
+	log!(Level::Debug, Category::Loader, "Header: {}", header_bytes.join(" "));
 
 	let flags6 = header[6];
 	let mirror_mode =
@@ -73,48 +306,339 @@ fn load_ines(file: &mut File) -> io::Result<Box<Cartridge>> {
 	let flags7 = header[7];
 	mapper |= flags7 & 0xF0;
 	let vs_unisystem = flags7 & 1 != 0;
+	// also console type 2 in NES 2.0
+	let playchoice = flags7 & 0b11 == 0b10;
 	let file_format = (flags7 & 0b1100) >> 2;
 	if vs_unisystem {
 		return parse_error("VS Unisystem ROMs not supported.");
 	}
-	if file_format != 0 {
+	let nes2 = file_format == 2;
+	if file_format != 0 && !nes2 {
 		return parse_error(format!("Unsupported iNES file format: {}", file_format).borrow());
 	}
 
-	let ram_size =
-		if header[8] == 0 { 8 * 1024 }
-		else { (header[8] as usize) * 8 * 1024 }; 
+	let prg_size;
+	let chr_size;
+	let ram_size;
+	let mut submapper = 0;
+	if nes2 {
+		// NES 2.0: byte 8 has the upper mapper bits and the submapper, byte 9
+		// the upper ROM size bits and byte 10 the RAM sizes as shift counts.
+		if header[8] & 0x0F != 0 {
+			return parse_error("Unsupported ROM: Mapper number above 255.");
+		}
+		submapper = header[8] >> 4;
+		if header[9] & 0x0F == 0x0F || header[9] >> 4 == 0x0F {
+			return parse_error("Unsupported ROM: Exponent ROM sizes.");
+		}
+		prg_size = ((header[9] as usize & 0x0F) << 8 | header[4] as usize) * 16 * 1024;
+		chr_size = ((header[9] as usize >> 4) << 8 | header[5] as usize) * 8 * 1024;
+		let shift_size = |shift: u8| if shift == 0 { 0 } else { 64 << shift as usize };
+		let nes2_ram_size = shift_size(header[10] & 0x0F) + shift_size(header[10] >> 4);
+		ram_size = if nes2_ram_size == 0 { 8 * 1024 } else { nes2_ram_size };
+	} else {
+		prg_size = (header[4] as usize) * 16 * 1024;
+		chr_size = (header[5] as usize) * 8 * 1024;
+		ram_size =
+			if header[8] == 0 { 8 * 1024 }
+			else { (header[8] as usize) * 8 * 1024 };
 
-	if header[9] != 1 && header[9] != 0 {
-		return parse_error("Header byte 9 invalid.");
-	}
+		if header[9] != 1 && header[9] != 0 {
+			return parse_error("Header byte 9 invalid.");
+		}
 
-	// ignore flag 10
+		// ignore flag 10
 
-	for i in 11..16 {
-		if header[i] != 0 {
-			return parse_error(format!("Unsupported ROM: Byte {} is not zero.", i).borrow());
+		for i in 11..16 {
+			if header[i] != 0 {
+				return parse_error(format!("Unsupported ROM: Byte {} is not zero.", i).borrow());
+			}
 		}
 	}
 
+	if let Some(override_submapper) = submapper_override {
+		submapper = override_submapper;
+	}
+
 	let mut prg_rom = vec![0; prg_size];
-	try!(file.read_exact(&mut prg_rom[..]));
+	file.read_exact(&mut prg_rom[..])?;
 	let mut chr_rom = vec![0; chr_size];
-	try!(file.read_exact(&mut chr_rom[..]));
+	file.read_exact(&mut chr_rom[..])?;
+
+	// PlayChoice-10 dumps have the 8 KiB INST-ROM with the game description
+	// and the 32 bytes of the PROM after CHR ROM, NES 2.0 files can have
+	// miscellaneous ROMs of other hardware at the end. Neither is needed to
+	// run the game, and they are often missing or cut, so only their sizes
+	// are taken.
+	let mut remaining = file.metadata()?.len().saturating_sub(file.stream_position()?) as usize;
+	let mut playchoice_size = 0;
+	if playchoice {
+		playchoice_size = match remaining {
+			0..=8191 => {
+				log!(Level::Warn, Category::Loader, "PlayChoice-10 ROM without INST-ROM.");
+				0
+			}
+			8192..=8223 => 8192,
+			_ => 8192 + 32,
+		};
+		remaining -= playchoice_size;
+	}
+	let misc_rom_size = if nes2 && header[14] & 0b11 != 0 { remaining } else { 0 };
+	if remaining > misc_rom_size {
+		log!(Level::Warn, Category::Loader, "Ignoring {} bytes after the ROM data.", remaining);
+	}
 
-	println!("Mapper: {:03}  PRG ROM: {} KiB  PRG RAM: {} KiB  CHR: {} KiB", 
-		mapper, prg_size / 1024, ram_size / 1024, chr_size / 1024);
-	println!("Mirror: {:?}  Persistent: {}  Trainer: {}",
+	log!(Level::Info, Category::Loader, "Mapper: {:03}.{}  PRG ROM: {} KiB  PRG RAM: {} KiB  CHR: {} KiB",
+		mapper, submapper, prg_size / 1024, ram_size / 1024, chr_size / 1024);
+	log!(Level::Info, Category::Loader, "Mirror: {:?}  Persistent: {}  Trainer: {}",
 		mirror_mode, persistent, trainer);
+	if playchoice_size > 0 || misc_rom_size > 0 {
+		log!(Level::Info, Category::Loader, "PlayChoice-10 INST-ROM and PROM: {} bytes  Miscellaneous ROMs: {} bytes",
+			playchoice_size, misc_rom_size);
+	}
 
-	match mapper {
-		000 => Result::Ok(Box::new(NRom::new(prg_rom, chr_rom, ram_size, mirror_mode))),
-		001 => Result::Ok(Box::new(Mmc1::new(prg_rom, chr_rom, ram_size))),
-		_   => parse_error(format!("Unsupported ROM mapper {:03}.", mapper).borrow()),
+	let mut info = RomInfo::new(mapper, &prg_rom, &chr_rom, mirror_mode.clone());
+	info.submapper = submapper;
+	info.playchoice_size = playchoice_size;
+	info.misc_rom_size = misc_rom_size;
+	// Byte 12 of NES 2.0 has the timing, 2 is for both systems and 3 for
+	// the Dendy clones. iNES has a PAL flag in byte 9 which is rarely set.
+	info.region = match (nes2, header[12] & 0b11, header[9] & 1) {
+		(true, 0, _) => Some(Region::Ntsc),
+		(true, 1, _) => Some(Region::Pal),
+		(false, _, 1) => Some(Region::Pal),
+		_ => None,
+	};
+	log!(Level::Info, Category::Loader, "CRC32: {:08X}  SHA-1: {}", info.crc32, info.sha1_hex());
+
+	if let Err(error) = check_sizes(mapper, prg_rom.len(), chr_rom.len(), ram_size) {
+		return parse_error(&error);
+	}
+	let mut cartridge: Box<dyn Cartridge> = match mapper {
+		000 => Box::new(NRom::new(prg_rom, chr_rom, ram_size, mirror_mode)),
+		// submapper 1 is the deprecated way to mark SUROM
+		001 if submapper == 1 => Box::new(Mmc1::with_board(prg_rom, chr_rom, ram_size, Mmc1Board::SuRom)),
+		001 => Box::new(Mmc1::new(prg_rom, chr_rom, ram_size)),
+		004 => Box::new(Mmc3::new(prg_rom, chr_rom, ram_size, mirror_mode, Mmc3Irq::from_submapper(submapper))),
+		71  => Box::new(Camerica::new(prg_rom, mirror_mode)),
+		157 => Box::new(Datach::new(prg_rom)),
+		163 => Box::new(Nanjing::new(prg_rom, mirror_mode)),
+		185 => Box::new(ProtectedCnRom::new(prg_rom, chr_rom, mirror_mode)),
+		232 => Box::new(Camerica::new_quattro(prg_rom, mirror_mode)),
+		_   => return parse_error(unsupported_mapper_message(mapper).borrow()),
+	};
+	cartridge.set_battery(persistent);
+	Ok((cartridge, info))
+}
+
+// Checks the sizes from the header against what the boards of the mapper
+// have, which their constructors assert.
+fn check_sizes(mapper: u8, prg_size: usize, chr_size: usize, ram_size: usize) -> Result<(), String> {
+	let supported = match mapper {
+		000 => (prg_size == 16 * 1024 || prg_size == 32 * 1024) && chr_size == 8 * 1024 &&
+			ram_size.is_multiple_of(0x400) && ram_size <= 0x2000,
+		// SOROM and SXROM with more PRG RAM are not supported yet
+		001 => prg_size.is_power_of_two() && (16 * 1024..=512 * 1024).contains(&prg_size) &&
+			(chr_size == 0 || (chr_size.is_power_of_two() && (8 * 1024..=128 * 1024).contains(&chr_size))) &&
+			ram_size == 8 * 1024,
+		004 => prg_size != 0 && prg_size.is_multiple_of(8 * 1024) && chr_size.is_multiple_of(0x400),
+		71 | 232 => prg_size != 0 && prg_size.is_multiple_of(16 * 1024),
+		157 => prg_size != 0 && prg_size.is_multiple_of(16 * 1024),
+		163 => prg_size != 0 && prg_size.is_multiple_of(32 * 1024),
+		185 => (prg_size == 16 * 1024 || prg_size == 32 * 1024) && chr_size == 8 * 1024,
+		_ => true,
+	};
+	if supported {
+		Ok(())
+	} else {
+		let name = mapper_info(mapper).map(|info| info.name).unwrap_or("Mapper");
+		Err(format!("Unsupported ROM: {} with {} KiB PRG ROM, {} KiB CHR ROM and {} bytes PRG RAM.",
+			name, prg_size / 1024, chr_size / 1024, ram_size))
+	}
+}
+
+fn unsupported_mapper_message(mapper: u8) -> String {
+	match mapper_info(mapper) {
+		Some(info) => format!(
+			"Unsupported ROM mapper {:03} ({}), used by about {} games. \
+			 Consider opening an issue to track support for it.",
+			mapper, info.name, info.games),
+		None => format!("Unsupported ROM mapper {:03}.", mapper),
 	}
 }
 
 fn parse_error<T>(error: &str) -> io::Result<T> {
-	println!("{}", error);
+	log!(Level::Error, Category::Loader, "{}", error);
 	Result::Err(io::Error::new(io::ErrorKind::Other, ""))
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::nrom::NRom;
+	use cartridge::mmc1::Mmc1;
+	use std::env;
+	use std::fs;
+
+	// Checks that the cartridge's nametable accesses behave like its reported mirror mode.
+	fn assert_mirroring(cartridge: &mut dyn Cartridge, expected: MirrorMode) {
+		assert_eq!(expected, cartridge.mirror_mode());
+		let mut ram = vec![0; 4096];
+		for (i, addr) in (0x2000..0x3F00).step_by(0x155).enumerate() {
+			cartridge.write_ppu(addr, i as u8);
+			ram[expected.nametable_index(addr)] = i as u8;
+		}
+		for addr in (0x2000..0x3F00).step_by(0x155) {
+			assert_eq!(ram[expected.nametable_index(addr)], cartridge.read_ppu(addr));
+		}
+	}
+
+	fn write_mmc1(cartridge: &mut Mmc1, addr: u16, value: u8) {
+		for i in 0..5 {
+			cartridge.write_cpu(addr, value >> i);
+		}
+	}
+
+	#[test]
+	fn nametable_index() {
+		assert_eq!(0x000, MirrorMode::HorizontalMirroring.nametable_index(0x2400));
+		assert_eq!(0x401, MirrorMode::HorizontalMirroring.nametable_index(0x2801));
+		assert_eq!(0x400, MirrorMode::VerticalMirroring.nametable_index(0x2400));
+		assert_eq!(0x001, MirrorMode::VerticalMirroring.nametable_index(0x2801));
+		assert_eq!(0x3FF, MirrorMode::SingleScreenLower.nametable_index(0x2FFF));
+		assert_eq!(0x400, MirrorMode::SingleScreenUpper.nametable_index(0x2000));
+		assert_eq!(0xC05, MirrorMode::FourScreen.nametable_index(0x3C05));
+	}
+
+	#[test]
+	fn mirroring_nrom() {
+		for mode in [MirrorMode::HorizontalMirroring, MirrorMode::VerticalMirroring, MirrorMode::FourScreen].iter() {
+			let mut a = NRom::new(vec![0; 16 * 1024], vec![0; 8 * 1024], 0, mode.clone());
+			assert_mirroring(&mut a, mode.clone());
+		}
+	}
+
+	#[test]
+	fn mirroring_mmc1() {
+		let mut a = Mmc1::new(vec![0; 256 * 1024], vec![0; 128 * 1024], 0x2000);
+		assert_mirroring(&mut a, MirrorMode::SingleScreenLower);
+		write_mmc1(&mut a, 0x8000, 0b01101);
+		assert_mirroring(&mut a, MirrorMode::SingleScreenUpper);
+		write_mmc1(&mut a, 0x8000, 0b01110);
+		assert_mirroring(&mut a, MirrorMode::VerticalMirroring);
+		write_mmc1(&mut a, 0x8000, 0b01111);
+		assert_mirroring(&mut a, MirrorMode::HorizontalMirroring);
+	}
+
+	#[test]
+	fn box_clone() {
+		let mut mmc1 = Mmc1::new(vec![0; 256 * 1024], vec![0; 128 * 1024], 0x2000);
+		mmc1.write_cpu(0x6000, 1);
+		write_mmc1(&mut mmc1, 0xE000, 0b00011);
+		let mut a: Box<dyn Cartridge> = Box::new(mmc1);
+		let mut b = a.clone();
+		b.write_cpu(0x6000, 2);
+		assert_eq!(1, a.read_cpu(0x6000));
+		assert_eq!(2, b.read_cpu(0x6000));
+
+		// registers are shown, the memories only with their sizes
+		let debug = format!("{:?}", b);
+		assert!(debug.starts_with("Mmc1 { board: Standard, prg_rom_size: 262144,"), "{}", debug);
+		assert!(debug.contains("prg_bank: 3"), "{}", debug);
+	}
+
+	#[test]
+	fn mappers() {
+		for &mapper in supported_mappers() {
+			assert!(mapper_info(mapper).is_some());
+		}
+		assert_eq!("MMC3", mapper_info(4).unwrap().name);
+		assert!(mapper_info(255).is_none());
+		assert!(unsupported_mapper_message(4).contains("MMC3"));
+	}
+
+	fn try_load_file(name: &str, header: &[u8], size: usize) -> Result<(Box<dyn Cartridge>, RomInfo), &'static str> {
+		let path = env::temp_dir().join(format!("nes-{}-{}.nes", name, ::std::process::id()));
+		let mut data = header.to_vec();
+		data.extend_from_slice(&vec![0x55; size]);
+		File::create(&path).and_then(|mut file| file.write_all(&data)).unwrap();
+		let result = load_rom_with_info(path.to_str().unwrap());
+		fs::remove_file(&path).unwrap();
+		result
+	}
+
+	fn load_file_cartridge(name: &str, header: &[u8], extra: usize) -> (Box<dyn Cartridge>, RomInfo) {
+		try_load_file(name, header, 16 * 1024 + 8 * 1024 + extra).unwrap()
+	}
+
+	fn load_file(name: &str, header: &[u8], extra: usize) -> RomInfo {
+		load_file_cartridge(name, header, extra).1
+	}
+
+	#[test]
+	fn unsupported_sizes() {
+		// NES 2.0 SOROM with 8 KiB volatile and 8 KiB battery backed RAM
+		let sorom = [0x4E, 0x45, 0x53, 0x1A, 16, 0, 0x12, 0x08, 0, 0, 0x77, 0, 0, 0, 0, 0];
+		assert!(try_load_file("sorom", &sorom, 256 * 1024).is_err());
+		let mut four_kib = sorom;
+		four_kib[10] = 0x06;
+		assert!(try_load_file("mmc1-4k", &four_kib, 256 * 1024).is_err());
+		four_kib[10] = 0x07;
+		assert!(try_load_file("mmc1-8k", &four_kib, 256 * 1024).is_ok());
+		// MMC1 with 48 KiB PRG ROM, NROM with 16 KiB CHR ROM
+		let mut odd_prg = four_kib;
+		odd_prg[4] = 3;
+		assert!(try_load_file("mmc1-48k", &odd_prg, 48 * 1024).is_err());
+		let nrom = [0x4E, 0x45, 0x53, 0x1A, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+		assert!(try_load_file("nrom-chr", &nrom, 32 * 1024).is_err());
+
+		// without PRG ROM, and Nanjing with 16 KiB
+		for &mapper in [4, 71, 157, 163, 232].iter() {
+			let header = [0x4E, 0x45, 0x53, 0x1A, 0, 0, mapper << 4, mapper & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+			assert!(try_load_file("no-prg", &header, 0).is_err(), "mapper {}", mapper);
+		}
+		let nanjing = [0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x30, 0xA0, 0, 0, 0, 0, 0, 0, 0, 0];
+		assert!(try_load_file("nanjing-16k", &nanjing, 16 * 1024).is_err());
+		// protected CNROM with 16 KiB CHR ROM
+		let cnrom = [0x4E, 0x45, 0x53, 0x1A, 2, 2, 0x90, 0xB0, 0, 0, 0, 0, 0, 0, 0, 0];
+		assert!(try_load_file("cnrom-chr", &cnrom, 48 * 1024).is_err());
+	}
+
+	#[test]
+	fn names_and_capabilities() {
+		// NROM with battery backed RAM, from the header
+		let header = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0b10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+		let (cartridge, _) = load_file_cartridge("battery", &header, 0);
+		assert_eq!("NROM", cartridge.name());
+		assert_eq!(HAS_PRG_RAM | HAS_BATTERY, cartridge.capabilities());
+		assert_eq!(vec!["PRG RAM", "battery"], capability_names(cartridge.capabilities()));
+
+		// the battery needs RAM to back
+		let mut nrom = NRom::new(vec![0; 16 * 1024], vec![0; 8 * 1024], 0, MirrorMode::HorizontalMirroring);
+		nrom.set_battery(true);
+		assert_eq!(0, nrom.capabilities());
+		assert!(capability_names(0).is_empty());
+
+		let mmc1 = Mmc1::new(vec![0; 256 * 1024], vec![], 0x2000);
+		assert_eq!(mapper_info(1).unwrap().name, mmc1.name());
+		assert_eq!(HAS_PRG_RAM | HAS_CHR_RAM, mmc1.capabilities());
+		assert_eq!(vec!["PRG RAM", "IRQ", "expansion audio", "CHR RAM"],
+			capability_names(HAS_PRG_RAM | HAS_IRQ | HAS_EXPANSION_AUDIO | HAS_CHR_RAM));
+	}
+
+	#[test]
+	fn trailing_data() {
+		// PlayChoice-10 with INST-ROM and PROM, with INST-ROM only, without
+		let playchoice = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0b10, 0, 0, 0, 0, 0, 0, 0, 0];
+		let info = load_file("pc10", &playchoice, 8192 + 32);
+		assert_eq!((16 * 1024, 8 * 1024, 8192 + 32, 0), (info.prg_size, info.chr_size, info.playchoice_size, info.misc_rom_size));
+		assert_eq!(8192, load_file("pc10-inst", &playchoice, 8192).playchoice_size);
+		assert_eq!(0, load_file("pc10-cut", &playchoice, 100).playchoice_size);
+
+		// NES 2.0 with one miscellaneous ROM, the checksums do not cover it
+		let nes2 = [0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0b1000, 0, 0, 0, 0, 0, 0, 1, 0];
+		let info = load_file("misc", &nes2, 300);
+		assert_eq!((0, 300), (info.playchoice_size, info.misc_rom_size));
+		assert_eq!(load_file("plain", &nes2, 0).sha1, info.sha1);
+	}
+}
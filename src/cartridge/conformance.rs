@@ -0,0 +1,98 @@
+use cartridge::{Cartridge, MirrorMode};
+
+// Declarative mapper tests: a table of register writes and the memory
+// contents expected after them, run against a cartridge by check. Every
+// mapper has such a table in its tests, with ROMs from numbered_banks so
+// that a read tells the bank mapped there.
+#[derive(Debug, Clone)]
+pub enum Step {
+	// CPU write
+	Write(u16, u8),
+	// Write of a 5 bit value to a serial port like the MMC1 one, bit 0 first.
+	Serial(u16, u8),
+	// PPU write
+	WritePpu(u16, u8),
+	// Value read by the CPU
+	Cpu(u16, u8),
+	// Value read by the PPU
+	Ppu(u16, u8),
+	Mirroring(MirrorMode),
+	// CPU cycles passing, see Cartridge::cpu_clock
+	CpuClock(u32),
+	// Rising edge of PPU A12, see Cartridge::ppu_a12_rise
+	A12Rise,
+	// Level of the IRQ line
+	Irq(bool),
+}
+
+// Runs the steps in order and panics at the first expectation which is
+// not met, naming the step.
+pub fn check(cartridge: &mut dyn Cartridge, steps: &[Step]) {
+	for (i, step) in steps.iter().enumerate() {
+		match *step {
+			Step::Write(addr, value) => cartridge.write_cpu(addr, value),
+			Step::Serial(addr, value) => {
+				for bit in 0..5 {
+					cartridge.write_cpu(addr, value >> bit);
+				}
+			}
+			Step::WritePpu(addr, value) => cartridge.write_ppu(addr, value),
+			Step::Cpu(addr, value) => {
+				assert_eq!(value, cartridge.read_cpu(addr), "step {}: {:?}", i, step);
+			}
+			Step::Ppu(addr, value) => {
+				assert_eq!(value, cartridge.read_ppu(addr), "step {}: {:?}", i, step);
+			}
+			Step::Mirroring(ref mode) => {
+				assert_eq!(*mode, cartridge.mirror_mode(), "step {}: {:?}", i, step);
+			}
+			Step::CpuClock(cycles) => cartridge.cpu_clock(cycles),
+			Step::A12Rise => cartridge.ppu_a12_rise(),
+			Step::Irq(level) => {
+				assert_eq!(level, cartridge.irq_pending(), "step {}: {:?}", i, step);
+			}
+		}
+	}
+}
+
+// A ROM of banks of bank_size bytes, each with its number at offset and
+// fill everywhere else.
+pub fn numbered_banks(banks: usize, bank_size: usize, offset: usize, fill: u8) -> Vec<u8> {
+	let mut rom = vec![fill; banks * bank_size];
+	for i in 0..banks {
+		rom[i * bank_size + offset] = i as u8;
+	}
+	rom
+}
+
+// Steps which check the nametable layout of a mirroring mode through the
+// PPU, 2000-2FFF and its mirror 3000-3EFF. The nametables must be zero.
+pub fn nametable_steps(mode: MirrorMode) -> Vec<Step> {
+	// the internal nametable each of the four maps to
+	let tables = match mode {
+		MirrorMode::HorizontalMirroring => [0, 0, 1, 1],
+		MirrorMode::VerticalMirroring => [0, 1, 0, 1],
+		MirrorMode::SingleScreenLower => [0, 0, 0, 0],
+		MirrorMode::SingleScreenUpper => [1, 1, 1, 1],
+		MirrorMode::FourScreen => [0, 1, 2, 3],
+	};
+	let first = |table: usize| tables.iter().position(|&t| t == table).map(|i| 0x2000 + i as u16 * 0x400);
+	let mut steps = vec![Step::Mirroring(mode.clone())];
+	// a byte in one of the four shows up in all of them which map to the
+	// same internal nametable
+	for table in 0..4 {
+		let value = table as u8 + 2;
+		let start = match first(table) {
+			Some(start) => start,
+			None => continue,
+		};
+		steps.push(Step::WritePpu(start + 0x102, value));
+		for (i, &t) in tables.iter().enumerate() {
+			let expected = if t == table { value } else { 0 };
+			steps.push(Step::Ppu(0x2102 + i as u16 * 0x400, expected));
+			steps.push(Step::Ppu(0x3102 + i as u16 * 0x400, expected));
+		}
+		steps.push(Step::WritePpu(start + 0x102, 0));
+	}
+	steps
+}
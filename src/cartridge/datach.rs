@@ -0,0 +1,432 @@
+use cartridge::{Cartridge, MirrorMode, HAS_IRQ, HAS_CHR_RAM};
+use cpu::memory_map;
+use std::fmt;
+use std::io::{self, Read, Write};
+use savestate;
+
+// Bandai Datach Joint ROM System: A Bandai FCG (LZ93D50) board in a base
+// unit with a barcode reader, into which the game cartridges are plugged.
+// iNES mapper 157
+//   6000-7FFF  read: barcode reader in bit 3, EEPROM data in bit 4
+//   8000-BFFF  PRG ROM (switchable 16 KiB bank)
+//   C000-FFFF  PRG ROM (fixed to last bank)
+//   8000-FFFF  write: registers selected by the lowest 4 address bits
+//     8        PRG bank
+//     9        mirroring: vertical, horizontal, single screen lower/upper
+//     A        IRQ enable in bit 0, copies the latch to the counter
+//     B, C     IRQ latch low and high byte
+//     0-7, D   EEPROM lines, the EEPROMs are not emulated yet
+// The IRQ counter decrements with every CPU cycle and fires at 0.
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_157
+#[derive(Clone)]
+pub struct Datach {
+	prg_rom: Vec<u8>,
+	chr_ram: [u8; 8192],
+	prg_bank: u8,
+	irq_enabled: bool,
+	irq_pending: bool,
+	irq_latch: u16,
+	irq_counter: u16,
+	ppu_ram: [u8; 4096],
+	mirror_mode: MirrorMode,
+	barcode: BarcodeReader,
+}
+
+impl Datach {
+	pub fn new(prg_rom: Vec<u8>) -> Datach {
+		assert!(!prg_rom.is_empty() && prg_rom.len() & 0x3FFF == 0);
+		Datach {
+			prg_rom,
+			chr_ram: [0; 8192],
+			prg_bank: 0,
+			irq_enabled: false,
+			irq_pending: false,
+			irq_latch: 0,
+			irq_counter: 0,
+			ppu_ram: [0; 4096],
+			mirror_mode: MirrorMode::VerticalMirroring,
+			barcode: BarcodeReader::new(),
+		}
+	}
+}
+
+impl fmt::Debug for Datach {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Datach")
+			.field("prg_rom_size", &self.prg_rom.len())
+			.field("prg_bank", &self.prg_bank)
+			.field("irq_enabled", &self.irq_enabled)
+			.field("irq_pending", &self.irq_pending)
+			.field("irq_latch", &self.irq_latch)
+			.field("irq_counter", &self.irq_counter)
+			.field("mirror_mode", &self.mirror_mode)
+			.field("barcode", &self.barcode)
+			.finish()
+	}
+}
+
+impl Cartridge for Datach {
+	fn name(&self) -> &'static str {
+		"Bandai Datach"
+	}
+
+	fn capabilities(&self) -> u8 {
+		HAS_IRQ | HAS_CHR_RAM
+	}
+
+	fn read_cpu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr < 0x6000 {
+			// not mapped
+			0
+		} else if addr < 0x8000 {
+			self.barcode.output()
+		} else {
+			let bank =
+				if addr < 0xC000 { self.prg_bank as usize & 0x0F }
+				else { self.prg_rom.len() / (16 * 1024) - 1 };
+			let index = bank * 16 * 1024 + (addr as usize & 0x3FFF);
+			self.prg_rom[index % self.prg_rom.len()]
+		}
+	}
+
+	fn write_cpu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr < 0x8000 {
+			return;
+		}
+		match addr & 0x0F {
+			0x8 => self.prg_bank = value,
+			0x9 => self.mirror_mode = match value & 0b11 {
+				0 => MirrorMode::VerticalMirroring,
+				1 => MirrorMode::HorizontalMirroring,
+				2 => MirrorMode::SingleScreenLower,
+				_ => MirrorMode::SingleScreenUpper,
+			},
+			0xA => {
+				self.irq_enabled = value & 1 != 0;
+				self.irq_counter = self.irq_latch;
+				self.irq_pending = false;
+			}
+			0xB => self.irq_latch = (self.irq_latch & 0xFF00) | value as u16,
+			0xC => self.irq_latch = (self.irq_latch & 0x00FF) | (value as u16) << 8,
+			// EEPROM lines
+			_ => {}
+		}
+	}
+
+	fn read_ppu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_ram[addr as usize]
+		} else {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)]
+		}
+	}
+
+	fn write_ppu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_ram[addr as usize] = value;
+		} else {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)] = value;
+		}
+	}
+
+	fn mirror_mode(&self) -> MirrorMode {
+		self.mirror_mode.clone()
+	}
+
+	fn cpu_clock(&mut self, cycles: u32) {
+		self.barcode.clock(cycles);
+		if self.irq_enabled {
+			self.irq_counter = self.irq_counter.saturating_sub(cycles.min(0xFFFF) as u16);
+			if self.irq_counter == 0 {
+				self.irq_pending = true;
+				self.irq_enabled = false;
+			}
+		}
+	}
+
+	fn irq_pending(&self) -> bool {
+		self.irq_pending
+	}
+
+	fn cycles_until_irq(&self) -> Option<u32> {
+		if self.irq_enabled { Some((self.irq_counter as u32).max(1)) } else { None }
+	}
+
+	fn insert_barcode(&mut self, digits: &str) -> Result<(), String> {
+		self.barcode.scan(digits)
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bytes(out, &self.chr_ram)?;
+		savestate::write_u8(out, self.prg_bank)?;
+		savestate::write_bool(out, self.irq_enabled)?;
+		savestate::write_bool(out, self.irq_pending)?;
+		savestate::write_u16(out, self.irq_latch)?;
+		savestate::write_u16(out, self.irq_counter)?;
+		savestate::write_u8(out, match self.mirror_mode {
+			MirrorMode::VerticalMirroring => 0,
+			MirrorMode::HorizontalMirroring => 1,
+			MirrorMode::SingleScreenLower => 2,
+			_ => 3,
+		})?;
+		savestate::write_bytes(out, &self.ppu_ram)?;
+		self.barcode.save_state(out)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		savestate::read_bytes(input, &mut self.chr_ram)?;
+		self.prg_bank = savestate::read_u8(input)?;
+		self.irq_enabled = savestate::read_bool(input)?;
+		self.irq_pending = savestate::read_bool(input)?;
+		self.irq_latch = savestate::read_u16(input)?;
+		self.irq_counter = savestate::read_u16(input)?;
+		self.mirror_mode = match savestate::read_u8(input)? {
+			0 => MirrorMode::VerticalMirroring,
+			1 => MirrorMode::HorizontalMirroring,
+			2 => MirrorMode::SingleScreenLower,
+			3 => MirrorMode::SingleScreenUpper,
+			_ => return savestate::invalid_state("Invalid mirror mode."),
+		};
+		savestate::read_bytes(input, &mut self.ppu_ram)?;
+		self.barcode.load_state(input)
+	}
+}
+
+// CPU cycles per module (narrowest bar or space) of a scanned barcode.
+const CYCLES_PER_MODULE: u32 = 1000;
+// Spaces before and after the code.
+const QUIET_ZONE: usize = 32;
+
+// EAN digit encodings, one bit per module with 1 for a bar. The first
+// digit of EAN-13 is encoded in which of the next six digits use the odd
+// or the even encoding.
+const LEFT_ODD: [u8; 10] = [0x0D, 0x19, 0x13, 0x3D, 0x23, 0x31, 0x2F, 0x3B, 0x37, 0x0B];
+const LEFT_EVEN: [u8; 10] = [0x27, 0x33, 0x1B, 0x21, 0x1D, 0x39, 0x05, 0x11, 0x09, 0x17];
+const RIGHT: [u8; 10] = [0x72, 0x66, 0x6C, 0x42, 0x5C, 0x4E, 0x50, 0x44, 0x48, 0x74];
+const EVEN_DIGITS: [u8; 10] = [0x00, 0x0B, 0x0D, 0x0E, 0x13, 0x19, 0x1C, 0x15, 0x16, 0x1A];
+
+// Plays an EAN-13 or EAN-8 barcode to the game as if swiped through the
+// reader, one module every CYCLES_PER_MODULE CPU cycles. The output is 0
+// when no code is being scanned.
+#[derive(Debug, Clone)]
+pub struct BarcodeReader {
+	modules: Vec<bool>,
+	position: usize,
+	cycles: u32,
+}
+
+impl BarcodeReader {
+	pub fn new() -> BarcodeReader {
+		BarcodeReader {
+			modules: Vec::new(),
+			position: 0,
+			cycles: 0,
+		}
+	}
+
+	// Accepts 7, 8, 12 or 13 digits. The check digit is added to 7 and 12
+	// digit codes.
+	pub fn scan(&mut self, digits: &str) -> Result<(), String> {
+		let mut digits: Vec<u8> = match digits.chars().map(|c| c.to_digit(10).map(|d| d as u8)).collect() {
+			Some(digits) => digits,
+			None => return Err(String::from("A barcode consists of digits only.")),
+		};
+		if digits.len() == 7 || digits.len() == 12 {
+			let check = check_digit(&digits);
+			digits.push(check);
+		}
+		if digits.len() != 8 && digits.len() != 13 {
+			return Err(String::from("A barcode has 8 or 13 digits (EAN-8, EAN-13)."));
+		}
+
+		let mut modules = vec![false; QUIET_ZONE];
+		{
+			let mut push = |bits: u8, count: usize| {
+				for i in (0..count).rev() {
+					modules.push(bits & (1 << i) != 0);
+				}
+			};
+			push(0b101, 3);
+			let half = digits.len() / 2;
+			let (left, right) = if digits.len() == 13 {
+				let even = EVEN_DIGITS[digits[0] as usize];
+				for (i, &digit) in digits[1..7].iter().enumerate() {
+					let encodings = if even & (0x20 >> i) != 0 { &LEFT_EVEN } else { &LEFT_ODD };
+					push(encodings[digit as usize], 7);
+				}
+				(&digits[1..7], &digits[7..])
+			} else {
+				for &digit in digits[..half].iter() {
+					push(LEFT_ODD[digit as usize], 7);
+				}
+				(&digits[..half], &digits[half..])
+			};
+			debug_assert_eq!(left.len(), right.len());
+			push(0b01010, 5);
+			for &digit in right.iter() {
+				push(RIGHT[digit as usize], 7);
+			}
+			push(0b101, 3);
+		}
+		modules.extend_from_slice(&[false; QUIET_ZONE]);
+
+		self.modules = modules;
+		self.position = 0;
+		self.cycles = 0;
+		Ok(())
+	}
+
+	// Bit 3 is set while the reader sees a bar.
+	pub fn output(&self) -> u8 {
+		match self.modules.get(self.position) {
+			Some(&true) => 0x08,
+			_ => 0,
+		}
+	}
+
+	pub fn clock(&mut self, cycles: u32) {
+		if self.position >= self.modules.len() {
+			return;
+		}
+		self.cycles += cycles;
+		self.position += (self.cycles / CYCLES_PER_MODULE) as usize;
+		self.cycles %= CYCLES_PER_MODULE;
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u16(out, self.modules.len() as u16)?;
+		for &bar in self.modules.iter() {
+			savestate::write_bool(out, bar)?;
+		}
+		savestate::write_u16(out, self.position.min(self.modules.len()) as u16)?;
+		savestate::write_u16(out, self.cycles as u16)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		let len = savestate::read_u16(input)? as usize;
+		self.modules.clear();
+		for _ in 0..len {
+			self.modules.push(savestate::read_bool(input)?);
+		}
+		self.position = savestate::read_u16(input)? as usize;
+		self.cycles = savestate::read_u16(input)? as u32;
+		Ok(())
+	}
+}
+
+// Digits are weighted 3 and 1 from the right, the sum with the check digit
+// is a multiple of 10.
+fn check_digit(digits: &[u8]) -> u8 {
+	let sum: u32 = digits.iter().rev().enumerate()
+		.map(|(i, &digit)| digit as u32 * if i % 2 == 0 { 3 } else { 1 })
+		.sum();
+	((10 - sum % 10) % 10) as u8
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::{Cartridge, MirrorMode};
+	use cartridge::conformance::{check, numbered_banks};
+	use cartridge::conformance::Step::*;
+
+	// 16 KiB banks starting with their number
+	fn rom(banks: usize) -> Vec<u8> {
+		numbered_banks(banks, 16 * 1024, 0, 0)
+	}
+
+	// Samples the reader output of the cartridge once per module.
+	fn scan(a: &mut Datach) -> String {
+		let mut bars = String::new();
+		for _ in 0..200 {
+			bars.push(if a.read_cpu(0x6000) & 0x08 != 0 { '1' } else { '0' });
+			a.cpu_clock(CYCLES_PER_MODULE);
+		}
+		String::from(bars.trim_matches('0'))
+	}
+
+	#[test]
+	fn registers() {
+		let mut a = Datach::new(rom(8));
+		check(&mut a, &[
+			Write(0x8008, 3),
+			Cpu(0x8000, 3),
+			Cpu(0xC000, 7),
+			// registers are mirrored every 16 bytes
+			Write(0xFFF8, 5),
+			Cpu(0x8000, 5),
+			Write(0x8009, 1),
+			Mirroring(MirrorMode::HorizontalMirroring),
+			Write(0x8019, 3),
+			Mirroring(MirrorMode::SingleScreenUpper),
+			WritePpu(0x0123, 9),
+			Ppu(0x0123, 9),
+		]);
+	}
+
+	#[test]
+	fn irq() {
+		let mut a = Datach::new(rom(2));
+		check(&mut a, &[
+			Write(0x800B, 0x34),
+			Write(0x800C, 0x12),
+			CpuClock(0x2000),
+			Irq(false),
+			Write(0x800A, 1),
+			CpuClock(0x1233),
+			Irq(false),
+			CpuClock(1),
+			Irq(true),
+			// the line stays asserted until acknowledged by writing the
+			// control register
+			CpuClock(100),
+			Irq(true),
+			Write(0x800A, 0),
+			Irq(false),
+			CpuClock(0x10000),
+			Irq(false),
+		]);
+		assert_eq!(None, a.cycles_until_irq());
+		a.write_cpu(0x800A, 1);
+		assert_eq!(Some(0x1234), a.cycles_until_irq());
+		a.cpu_clock(0x1234);
+		assert!(a.irq_pending());
+		assert_eq!(None, a.cycles_until_irq());
+	}
+
+	#[test]
+	fn barcode() {
+		assert_eq!(1, check_digit(&[4, 0, 0, 6, 3, 8, 1, 3, 3, 3, 9, 3]));
+		assert_eq!(4, check_digit(&[9, 6, 3, 8, 5, 0, 7]));
+
+		let mut a = Datach::new(rom(2));
+		assert_eq!("", scan(&mut a));
+		assert!(a.insert_barcode("12345").is_err());
+		assert!(a.insert_barcode("1234567x").is_err());
+
+		// EAN-8 96385074
+		a.insert_barcode("9638507").unwrap();
+		assert_eq!(concat!(
+			"101", "0001011", "0101111", "0111101", "0110111",
+			"01010", "1001110", "1110010", "1000100", "1011100", "101"), scan(&mut a));
+
+		// EAN-13 4006381333931, the first digit selects the encodings
+		a.insert_barcode("400638133393").unwrap();
+		assert_eq!(concat!(
+			"101", "0001101", "0100111", "0101111", "0111101", "0001001", "0110011",
+			"01010", "1000010", "1000010", "1000010", "1110100", "1000010", "1100110", "101"), scan(&mut a));
+
+		let mut state = Vec::new();
+		a.insert_barcode("96385074").unwrap();
+		a.cpu_clock(40 * CYCLES_PER_MODULE);
+		a.save_state(&mut state).unwrap();
+		let mut b = Datach::new(rom(2));
+		b.load_state(&mut &state[..]).unwrap();
+		assert_eq!(a.read_cpu(0x6000), b.read_cpu(0x6000));
+		assert_eq!(scan(&mut a), scan(&mut b));
+	}
+}
@@ -0,0 +1,745 @@
+use cartridge::{Cartridge, MirrorMode, HAS_PRG_RAM, HAS_IRQ, HAS_EXPANSION_AUDIO, HAS_CHR_RAM};
+use cartridge::fds_audio::FdsAudio;
+use apu::ExpansionChip;
+use cpu::memory_map;
+use std::fmt;
+use std::io::{self, Read, Write};
+use savestate;
+
+// Size of a disk side in .fds images, without gaps and checksums.
+pub const SIDE_SIZE: usize = 65500;
+// Bytes passing the head in one run of the drive, a side with its gaps
+// and the unused rest of the disk.
+const SIDE_CAPACITY: usize = 0x12000;
+// Zero bytes before the first block and after every block.
+const LEAD_IN: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// The disk has its blocks with a checksum each, which the drive does not
+// check here, so the images get one which is the same for all.
+const BLOCK_CRC: [u8; 2] = [0x4D, 0x62];
+
+// CPU cycles between two bytes passing the head, and from starting the
+// motor until the head reaches the disk.
+const BYTE_CYCLES: u32 = 149;
+const HEAD_CYCLES: u32 = 50000;
+// CPU cycles without a disk when changing the side, long enough for the
+// BIOS to notice.
+const CHANGE_CYCLES: u32 = 1_000_000;
+
+// FDS full volume is about 2.4 times the level of a pulse channel of the
+// APU at full volume.
+const AUDIO_LEVEL: f32 = 0.36;
+
+// $4025 bits
+const MOTOR_ON: u8 = 0x01;
+const TRANSFER_RESET: u8 = 0x02;
+const READ_MODE: u8 = 0x04;
+const HORIZONTAL_MIRRORING: u8 = 0x08;
+const CRC_CONTROL: u8 = 0x10;
+const DISK_READY: u8 = 0x40;
+const DISK_IRQ_ENABLED: u8 = 0x80;
+
+// Splits a .fds image into its sides and adds the gaps and checksums the
+// drive reads between the blocks. The image may start with a 16 byte
+// header.
+pub fn parse_disk_image(data: &[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+	let data = if data.starts_with(b"FDS\x1A") { &data[16.min(data.len())..] } else { data };
+	if data.is_empty() || data.len() % SIDE_SIZE != 0 {
+		return Err("The disk image does not consist of whole disk sides.");
+	}
+	data.chunks(SIDE_SIZE).map(add_gaps).collect()
+}
+
+fn add_gaps(side: &[u8]) -> Result<Vec<u8>, &'static str> {
+	if !side.starts_with(b"\x01*NINTENDO-HVC*") {
+		return Err("The disk side does not start with the disk header.");
+	}
+	let mut disk = vec![0; LEAD_IN];
+	let mut position = 0;
+	while position < side.len() {
+		let length = match side[position] {
+			1 => 56,
+			2 => 2,
+			3 => 16,
+			// the file header before has the size at 13
+			4 if position >= 16 && side[position - 16] == 3 =>
+				1 + (side[position - 3] as usize | (side[position - 2] as usize) << 8),
+			// the rest of the side is unused
+			_ => break,
+		};
+		if position + length > side.len() {
+			return Err("The disk side has a block which does not fit on it.");
+		}
+		disk.push(0x80);
+		disk.extend_from_slice(&side[position..position + length]);
+		disk.extend_from_slice(&BLOCK_CRC);
+		disk.extend_from_slice(&[0; BLOCK_GAP]);
+		position += length;
+	}
+	let capacity = disk.len().max(SIDE_CAPACITY);
+	disk.resize(capacity, 0);
+	Ok(disk)
+}
+
+// The Famicom Disk System: the RAM adapter in the cartridge slot with its
+// BIOS, the sound unit and the drive with the disk.
+//   4020/4021  write: timer reload value, low and high byte
+//   4022       write: timer control, bit 0 repeat, bit 1 enable
+//   4023       write: bit 0 enables the disk registers, bit 1 the sound
+//   4024       write: byte to write to the disk
+//   4025       write: drive control, see the bits above
+//   4030       read: bit 0 timer IRQ, bit 1 byte transferred, bit 6 end
+//              of the disk; acknowledges both IRQs
+//   4031       read: byte read from the disk, acknowledges the disk IRQ
+//   4032       read: bit 0 no disk, bit 1 not ready, bit 2 write protected
+//   4033       read: bit 7 battery good
+//   4040-4097  sound unit, see FdsAudio
+//   6000-DFFF  PRG RAM (32 KiB)
+//   E000-FFFF  BIOS ROM (8 KiB)
+// The PPU sees 8 KiB CHR RAM. The drive reads the disk as a stream of
+// bytes with gaps of zeros before the blocks, each block starts with the
+// byte 80.
+// See http://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+#[derive(Clone)]
+pub struct Fds {
+	bios: Vec<u8>,
+	prg_ram: Vec<u8>,
+	chr_ram: [u8; 8192],
+	ppu_ram: [u8; 2048],
+	// the sides as the drive reads them, see parse_disk_image
+	sides: Vec<Vec<u8>>,
+	// None while the disk is out of the drive
+	side: Option<usize>,
+	// the side inserted when the CPU cycles have passed, see
+	// change_disk_side
+	next_side: Option<(usize, u32)>,
+	audio: FdsAudio,
+	disk_registers: bool,
+	sound_registers: bool,
+	timer_reload: u16,
+	timer_counter: u16,
+	timer_repeat: bool,
+	timer_enabled: bool,
+	timer_irq: bool,
+	control: u8,
+	write_data: u8,
+	read_data: u8,
+	transfer_complete: bool,
+	disk_irq: bool,
+	// the byte of the side under the head
+	position: usize,
+	// CPU cycles until the next byte
+	delay: u32,
+	// the head is at the start of the disk and has to move in first
+	end_of_head: bool,
+	scanning: bool,
+	// a block started since the disk became ready
+	gap_ended: bool,
+}
+
+impl Fds {
+	pub fn new(bios: Vec<u8>, sides: Vec<Vec<u8>>) -> Fds {
+		assert!(bios.len() == 8 * 1024 && !sides.is_empty());
+		Fds {
+			bios,
+			prg_ram: vec![0; 32 * 1024],
+			chr_ram: [0; 8192],
+			ppu_ram: [0; 2048],
+			sides,
+			side: Some(0),
+			next_side: None,
+			audio: FdsAudio::new(),
+			disk_registers: false,
+			sound_registers: false,
+			timer_reload: 0,
+			timer_counter: 0,
+			timer_repeat: false,
+			timer_enabled: false,
+			timer_irq: false,
+			control: 0,
+			write_data: 0,
+			read_data: 0,
+			transfer_complete: false,
+			disk_irq: false,
+			position: 0,
+			delay: 0,
+			end_of_head: true,
+			scanning: false,
+			gap_ended: false,
+		}
+	}
+
+	fn clock_timer(&mut self) {
+		if !self.timer_enabled {
+			return;
+		}
+		if self.timer_counter == 0 {
+			self.timer_irq = true;
+			if self.timer_repeat {
+				self.timer_counter = self.timer_reload;
+			} else {
+				self.timer_enabled = false;
+			}
+		} else {
+			self.timer_counter -= 1;
+		}
+	}
+
+	fn clock_disk(&mut self) {
+		if let Some((side, cycles)) = self.next_side {
+			self.next_side = if cycles > 1 { Some((side, cycles - 1)) } else { None };
+			if self.next_side.is_none() {
+				self.side = Some(side);
+			}
+		}
+		let side = match self.side {
+			Some(side) if self.control & MOTOR_ON != 0 => side,
+			_ => {
+				self.end_of_head = true;
+				self.scanning = false;
+				return;
+			}
+		};
+		if self.control & TRANSFER_RESET != 0 && !self.scanning {
+			return;
+		}
+		if self.end_of_head {
+			self.end_of_head = false;
+			self.delay = HEAD_CYCLES;
+			self.position = 0;
+			self.gap_ended = false;
+			return;
+		}
+		if self.delay > 0 {
+			self.delay -= 1;
+			return;
+		}
+
+		self.scanning = true;
+		let mut irq = self.control & DISK_IRQ_ENABLED != 0;
+		if self.control & READ_MODE != 0 {
+			let byte = self.sides[side][self.position];
+			if self.control & DISK_READY == 0 {
+				self.gap_ended = false;
+			} else if byte != 0 && !self.gap_ended {
+				// the 80 which ends the gap raises no IRQ
+				self.gap_ended = true;
+				irq = false;
+			}
+			if self.gap_ended {
+				self.transfer_complete = true;
+				self.read_data = byte;
+				self.disk_irq |= irq;
+			}
+		} else {
+			if self.control & CRC_CONTROL == 0 {
+				self.transfer_complete = true;
+				self.disk_irq |= irq;
+			}
+			self.sides[side][self.position] =
+				if self.control & DISK_READY == 0 { 0 }
+				// the checksum is not computed, see BLOCK_CRC
+				else if self.control & CRC_CONTROL != 0 { BLOCK_CRC[0] }
+				else { self.write_data };
+			self.gap_ended = false;
+		}
+
+		self.position += 1;
+		if self.position >= self.sides[side].len() {
+			self.control &= !MOTOR_ON;
+		} else {
+			self.delay = BYTE_CYCLES;
+		}
+	}
+}
+
+impl fmt::Debug for Fds {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Fds")
+			.field("sides", &self.sides.len())
+			.field("side", &self.side)
+			.field("next_side", &self.next_side)
+			.field("audio", &self.audio)
+			.field("disk_registers", &self.disk_registers)
+			.field("sound_registers", &self.sound_registers)
+			.field("timer_reload", &self.timer_reload)
+			.field("timer_counter", &self.timer_counter)
+			.field("timer_repeat", &self.timer_repeat)
+			.field("timer_enabled", &self.timer_enabled)
+			.field("timer_irq", &self.timer_irq)
+			.field("control", &self.control)
+			.field("transfer_complete", &self.transfer_complete)
+			.field("disk_irq", &self.disk_irq)
+			.field("position", &self.position)
+			.field("delay", &self.delay)
+			.finish()
+	}
+}
+
+impl Cartridge for Fds {
+	fn name(&self) -> &'static str {
+		"FDS"
+	}
+
+	fn capabilities(&self) -> u8 {
+		HAS_PRG_RAM | HAS_IRQ | HAS_EXPANSION_AUDIO | HAS_CHR_RAM
+	}
+
+	fn read_cpu(&mut self, addr: u16) -> u8 {
+		let value = self.peek_cpu(addr);
+		match addr {
+			// acknowledge the IRQs
+			0x4030 if self.disk_registers => {
+				self.timer_irq = false;
+				self.disk_irq = false;
+				self.transfer_complete = false;
+			}
+			0x4031 if self.disk_registers => {
+				self.disk_irq = false;
+				self.transfer_complete = false;
+			}
+			_ => {}
+		}
+		value
+	}
+
+	fn peek_cpu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		// open bus, the high byte of the address was on the bus last
+		let open_bus = (addr >> 8) as u8;
+		let no_disk = self.side.is_none();
+		match addr {
+			0x4030 if self.disk_registers => open_bus & 0x2C | self.timer_irq as u8
+				| (self.transfer_complete as u8) << 1 | (self.end_of_head as u8) << 6,
+			0x4031 if self.disk_registers => self.read_data,
+			0x4032 if self.disk_registers =>
+				open_bus & 0xF8 | no_disk as u8 | ((no_disk || !self.scanning) as u8) << 1 | (no_disk as u8) << 2,
+			0x4033 if self.disk_registers => 0x80,
+			0x4040..=0x4097 if self.sound_registers => self.audio.read(addr, open_bus).unwrap_or(open_bus),
+			0x6000..=0xDFFF => self.prg_ram[addr as usize - 0x6000],
+			0xE000..=0xFFFF => self.bios[addr as usize - 0xE000],
+			_ => open_bus,
+		}
+	}
+
+	fn write_cpu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		match addr {
+			0x4023 => {
+				self.disk_registers = value & 0x01 != 0;
+				self.sound_registers = value & 0x02 != 0;
+				if !self.disk_registers {
+					self.timer_enabled = false;
+					self.timer_irq = false;
+					self.disk_irq = false;
+				}
+			}
+			0x4020 if self.disk_registers => self.timer_reload = self.timer_reload & 0xFF00 | value as u16,
+			0x4021 if self.disk_registers => self.timer_reload = self.timer_reload & 0x00FF | (value as u16) << 8,
+			0x4022 if self.disk_registers => {
+				self.timer_repeat = value & 0x01 != 0;
+				self.timer_enabled = value & 0x02 != 0;
+				if self.timer_enabled {
+					self.timer_counter = self.timer_reload;
+				} else {
+					self.timer_irq = false;
+				}
+			}
+			0x4024 if self.disk_registers => {
+				self.write_data = value;
+				self.transfer_complete = false;
+				self.disk_irq = false;
+			}
+			0x4025 if self.disk_registers => {
+				self.control = value;
+				self.disk_irq = false;
+			}
+			0x4040..=0x4097 if self.sound_registers => self.audio.write(addr, value),
+			0x6000..=0xDFFF => self.prg_ram[addr as usize - 0x6000] = value,
+			_ => {}
+		}
+	}
+
+	fn read_ppu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_ram[addr as usize]
+		} else {
+			self.ppu_ram[self.mirror_mode().nametable_index(addr)]
+		}
+	}
+
+	fn write_ppu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_ram[addr as usize] = value;
+		} else {
+			self.ppu_ram[self.mirror_mode().nametable_index(addr)] = value;
+		}
+	}
+
+	fn mirror_mode(&self) -> MirrorMode {
+		if self.control & HORIZONTAL_MIRRORING != 0 {
+			MirrorMode::HorizontalMirroring
+		} else {
+			MirrorMode::VerticalMirroring
+		}
+	}
+
+	fn cpu_clock(&mut self, cycles: u32) {
+		for _ in 0..cycles {
+			self.clock_timer();
+			self.clock_disk();
+		}
+		self.audio.clock(cycles);
+	}
+
+	fn expansion_audio(&self) -> Option<(ExpansionChip, f32)> {
+		Some((ExpansionChip::Fds, self.audio.output() * AUDIO_LEVEL))
+	}
+
+	fn irq_pending(&self) -> bool {
+		self.timer_irq || self.disk_irq
+	}
+
+	// The disk IRQ may come with any byte once the motor runs, the
+	// console asks again then.
+	fn cycles_until_irq(&self) -> Option<u32> {
+		let timer = if self.timer_enabled { Some(self.timer_counter as u32 + 1) } else { None };
+		let disk = if self.control & (MOTOR_ON | DISK_IRQ_ENABLED) == MOTOR_ON | DISK_IRQ_ENABLED {
+			Some(self.delay + 1)
+		} else {
+			None
+		};
+		match (timer, disk) {
+			(Some(timer), Some(disk)) => Some(timer.min(disk)),
+			(timer, disk) => timer.or(disk),
+		}
+	}
+
+	fn change_disk_side(&mut self) -> Result<usize, String> {
+		let current = self.side.or(self.next_side.map(|(side, _)| side)).unwrap_or(0);
+		let side = (current + 1) % self.sides.len();
+		self.side = None;
+		self.next_side = Some((side, CHANGE_CYCLES));
+		Ok(side + 1)
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.side.map(|side| side as u8 + 1).unwrap_or(0))?;
+		savestate::write_u8(out, self.next_side.map(|(side, _)| side as u8 + 1).unwrap_or(0))?;
+		savestate::write_u64(out, self.next_side.map(|(_, cycles)| cycles as u64).unwrap_or(0))?;
+		self.audio.save_state(out)?;
+		savestate::write_bool(out, self.disk_registers)?;
+		savestate::write_bool(out, self.sound_registers)?;
+		savestate::write_u16(out, self.timer_reload)?;
+		savestate::write_u16(out, self.timer_counter)?;
+		savestate::write_bool(out, self.timer_repeat)?;
+		savestate::write_bool(out, self.timer_enabled)?;
+		savestate::write_bool(out, self.timer_irq)?;
+		savestate::write_u8(out, self.control)?;
+		savestate::write_u8(out, self.write_data)?;
+		savestate::write_u8(out, self.read_data)?;
+		savestate::write_bool(out, self.transfer_complete)?;
+		savestate::write_bool(out, self.disk_irq)?;
+		savestate::write_u64(out, self.position as u64)?;
+		savestate::write_u64(out, self.delay as u64)?;
+		savestate::write_bool(out, self.end_of_head)?;
+		savestate::write_bool(out, self.scanning)?;
+		savestate::write_bool(out, self.gap_ended)?;
+		savestate::write_bytes(out, &self.prg_ram)?;
+		savestate::write_bytes(out, &self.chr_ram)?;
+		savestate::write_bytes(out, &self.ppu_ram)?;
+		// the games save to the disk
+		for side in self.sides.iter() {
+			savestate::write_bytes(out, side)?;
+		}
+		Ok(())
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		let side_count = self.sides.len();
+		let side = |number: u8| match number as usize {
+			0 => Ok(None),
+			number if number <= side_count => Ok(Some(number - 1)),
+			_ => savestate::invalid_state("Invalid disk side."),
+		};
+		self.side = side(savestate::read_u8(input)?)?;
+		let next_side = side(savestate::read_u8(input)?)?;
+		let cycles = savestate::read_u64(input)? as u32;
+		self.next_side = next_side.map(|side| (side, cycles));
+		self.audio.load_state(input)?;
+		self.disk_registers = savestate::read_bool(input)?;
+		self.sound_registers = savestate::read_bool(input)?;
+		self.timer_reload = savestate::read_u16(input)?;
+		self.timer_counter = savestate::read_u16(input)?;
+		self.timer_repeat = savestate::read_bool(input)?;
+		self.timer_enabled = savestate::read_bool(input)?;
+		self.timer_irq = savestate::read_bool(input)?;
+		self.control = savestate::read_u8(input)?;
+		self.write_data = savestate::read_u8(input)?;
+		self.read_data = savestate::read_u8(input)?;
+		self.transfer_complete = savestate::read_bool(input)?;
+		self.disk_irq = savestate::read_bool(input)?;
+		self.position = savestate::read_u64(input)? as usize;
+		self.delay = savestate::read_u64(input)? as u32;
+		self.end_of_head = savestate::read_bool(input)?;
+		self.scanning = savestate::read_bool(input)?;
+		self.gap_ended = savestate::read_bool(input)?;
+		savestate::read_bytes(input, &mut self.prg_ram)?;
+		savestate::read_bytes(input, &mut self.chr_ram)?;
+		savestate::read_bytes(input, &mut self.ppu_ram)?;
+		for side in self.sides.iter_mut() {
+			savestate::read_bytes(input, side)?;
+		}
+		if self.side.map(|side| self.position >= self.sides[side].len()).unwrap_or(false) {
+			return savestate::invalid_state("Invalid disk position.");
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::{Cartridge, MirrorMode, load_rom_with_info};
+	use cartridge::conformance::check;
+	use cartridge::nrom::NRom;
+	use cartridge::conformance::Step::*;
+	use std::env;
+	use std::fs::{self, File};
+	use std::io::Write;
+
+	// A side with the disk header, the file count and one file of 3 bytes.
+	fn side(number: u8) -> Vec<u8> {
+		let mut side = b"\x01*NINTENDO-HVC*".to_vec();
+		side.resize(56, number);
+		side.extend_from_slice(&[0x02, 1]);
+		side.extend_from_slice(&[0x03, 0, 0, b'F', b'I', b'L', b'E', b' ', b' ', b' ', b' ', 0x00, 0x60, 3, 0, 0]);
+		side.extend_from_slice(&[0x04, 0xAA, 0xBB, number]);
+		side.resize(SIDE_SIZE, 0);
+		side
+	}
+
+	fn bios() -> Vec<u8> {
+		let mut bios = vec![0xEA; 8192];
+		bios[0x1FFC] = 0x34;
+		bios
+	}
+
+	fn fds(sides: usize) -> Fds {
+		let image: Vec<u8> = (0..sides).flat_map(|number| side(number as u8)).collect();
+		Fds::new(bios(), parse_disk_image(&image).unwrap())
+	}
+
+	// Clocks until the drive raises the IRQ for the next byte and reads it.
+	fn next_byte(a: &mut Fds) -> u8 {
+		for _ in 0..SIDE_CAPACITY as u32 * BYTE_CYCLES {
+			if a.irq_pending() {
+				return a.read_cpu(0x4031);
+			}
+			a.cpu_clock(1);
+		}
+		panic!("no IRQ");
+	}
+
+	#[test]
+	fn disk_image() {
+		let sides = parse_disk_image(&side(0)).unwrap();
+		assert_eq!(1, sides.len());
+		let disk = &sides[0];
+		assert_eq!(SIDE_CAPACITY, disk.len());
+		assert!(disk[..LEAD_IN].iter().all(|&byte| byte == 0));
+		// gap end mark, block, checksum, gap
+		assert_eq!(0x80, disk[LEAD_IN]);
+		assert_eq!(&side(0)[..56], &disk[LEAD_IN + 1..LEAD_IN + 57]);
+		assert_eq!(&BLOCK_CRC, &disk[LEAD_IN + 57..LEAD_IN + 59]);
+		let file_count = LEAD_IN + 59 + BLOCK_GAP;
+		assert_eq!(&[0x80, 0x02, 1], &disk[file_count..file_count + 3]);
+		let file = file_count + 5 + BLOCK_GAP + 19 + BLOCK_GAP;
+		assert_eq!(&[0x80, 0x04, 0xAA, 0xBB, 0, 0x4D], &disk[file..file + 6]);
+
+		let mut header = b"FDS\x1A\x02".to_vec();
+		header.resize(16, 0);
+		let image: Vec<u8> = header.into_iter().chain(side(0)).chain(side(1)).collect();
+		let sides = parse_disk_image(&image).unwrap();
+		assert_eq!(2, sides.len());
+		assert_eq!(1, sides[1][LEAD_IN + 20]);
+
+		assert!(parse_disk_image(&side(0)[..1000]).is_err());
+		assert!(parse_disk_image(&vec![0; SIDE_SIZE]).is_err());
+		// a file larger than the rest of the side
+		let mut cut = side(0);
+		cut[56 + 2 + 13] = 0xFF;
+		cut[56 + 2 + 14] = 0xFF;
+		assert!(parse_disk_image(&cut).is_err());
+	}
+
+	#[test]
+	fn memory() {
+		let mut a = fds(1);
+		check(&mut a, &[
+			Write(0x6000, 1),
+			Write(0xDFFF, 2),
+			Cpu(0x6000, 1),
+			Cpu(0xDFFF, 2),
+			Cpu(0xFFFC, 0x34),
+			// the BIOS is ROM
+			Write(0xE000, 3),
+			Cpu(0xE000, 0xEA),
+			WritePpu(0x1FFF, 4),
+			Ppu(0x1FFF, 4),
+			// the registers need $4023
+			Write(0x4025, 0x08),
+			Mirroring(MirrorMode::VerticalMirroring),
+			Cpu(0x4033, 0x40),
+			Write(0x4023, 0x01),
+			Write(0x4025, 0x08),
+			Mirroring(MirrorMode::HorizontalMirroring),
+			Cpu(0x4033, 0x80),
+			Write(0x4025, 0x00),
+			Mirroring(MirrorMode::VerticalMirroring),
+		]);
+	}
+
+	#[test]
+	fn timer() {
+		let mut a = fds(1);
+		check(&mut a, &[
+			Write(0x4023, 0x01),
+			Write(0x4020, 0x34),
+			Write(0x4021, 0x12),
+			Write(0x4022, 0x03),
+			CpuClock(0x1234),
+			Irq(false),
+			CpuClock(1),
+			Irq(true),
+			// repeats until acknowledged through $4030
+			CpuClock(0x1235),
+			Irq(true),
+		]);
+		assert_eq!(0x41, a.peek_cpu(0x4030));
+		assert!(a.irq_pending());
+		assert_eq!(0x41, a.read_cpu(0x4030));
+		assert!(!a.irq_pending());
+		assert_eq!(Some(0x1235), a.cycles_until_irq());
+
+		// once, and not without the disk registers
+		a.write_cpu(0x4022, 0x02);
+		a.cpu_clock(0x1235);
+		assert!(a.irq_pending());
+		a.read_cpu(0x4030);
+		assert_eq!(None, a.cycles_until_irq());
+		a.write_cpu(0x4022, 0x03);
+		a.write_cpu(0x4023, 0x00);
+		a.cpu_clock(0x10000);
+		assert!(!a.irq_pending());
+	}
+
+	#[test]
+	fn read_disk() {
+		let mut a = fds(1);
+		a.write_cpu(0x4023, 0x01);
+		// no IRQs while the disk is not ready, the data of the gaps are
+		// not transferred
+		a.write_cpu(0x4025, DISK_IRQ_ENABLED | READ_MODE | MOTOR_ON);
+		a.cpu_clock(HEAD_CYCLES + BYTE_CYCLES * 100);
+		assert!(!a.irq_pending());
+		assert_eq!(0x40, a.read_cpu(0x4032));
+		a.write_cpu(0x4025, DISK_IRQ_ENABLED | DISK_READY | READ_MODE | MOTOR_ON);
+		let header: Vec<u8> = (0..15).map(|_| next_byte(&mut a)).collect();
+		assert_eq!(b"\x01*NINTENDO-HVC*".to_vec(), header);
+		assert_eq!(Some(BYTE_CYCLES + 1), a.cycles_until_irq());
+
+		// stopping the motor moves the head back
+		a.write_cpu(0x4025, 0);
+		a.cpu_clock(1);
+		assert_eq!(0x42, a.read_cpu(0x4032));
+		assert_eq!(0x40, a.read_cpu(0x4030) & 0x40);
+		a.write_cpu(0x4025, DISK_IRQ_ENABLED | DISK_READY | READ_MODE | MOTOR_ON);
+		assert_eq!(0x01, next_byte(&mut a));
+	}
+
+	#[test]
+	fn write_disk() {
+		let mut a = fds(1);
+		a.write_cpu(0x4023, 0x01);
+		a.write_cpu(0x4024, 0x5A);
+		a.write_cpu(0x4025, DISK_IRQ_ENABLED | DISK_READY | MOTOR_ON);
+		a.cpu_clock(HEAD_CYCLES + 2);
+		assert!(a.irq_pending());
+		a.write_cpu(0x4024, 0xA5);
+		assert!(!a.irq_pending());
+		a.cpu_clock(BYTE_CYCLES + 1);
+		assert!(a.irq_pending());
+		assert_eq!(&[0x5A, 0xA5], &a.sides[0][..2]);
+
+		// the disk keeps what was written in save states
+		let mut state = Vec::new();
+		a.save_state(&mut state).unwrap();
+		let mut b = fds(1);
+		b.load_state(&mut &state[..]).unwrap();
+		assert_eq!(format!("{:?}", a), format!("{:?}", b));
+		assert_eq!(a.sides, b.sides);
+	}
+
+	#[test]
+	fn change_disk_side() {
+		let mut a = fds(2);
+		a.write_cpu(0x4023, 0x01);
+		assert_eq!(0x00, a.read_cpu(0x4032) & 0x01);
+		assert_eq!(Ok(2), a.change_disk_side());
+		assert_eq!(0x45, a.read_cpu(0x4032) & 0x45);
+		a.cpu_clock(CHANGE_CYCLES - 1);
+		assert_eq!(0x01, a.read_cpu(0x4032) & 0x01);
+		a.cpu_clock(1);
+		assert_eq!(0x00, a.read_cpu(0x4032) & 0x01);
+		a.write_cpu(0x4025, DISK_IRQ_ENABLED | DISK_READY | READ_MODE | MOTOR_ON);
+		for _ in 0..20 {
+			next_byte(&mut a);
+		}
+		assert_eq!(1, next_byte(&mut a));
+
+		// back to the first side, also while changing
+		assert_eq!(Ok(1), a.change_disk_side());
+		assert_eq!(Ok(2), a.change_disk_side());
+		let mut nrom = NRom::new(vec![0; 16 * 1024], vec![0; 8 * 1024], 0, MirrorMode::HorizontalMirroring);
+		assert!(nrom.change_disk_side().is_err());
+	}
+
+	#[test]
+	fn audio() {
+		let mut a = fds(1);
+		assert_eq!(Some((ExpansionChip::Fds, 0.0)), a.expansion_audio());
+		// a wave at full volume, writable only with the sound registers
+		a.write_cpu(0x4089, 0x80);
+		a.write_cpu(0x4040, 63);
+		assert_eq!(0x40, a.read_cpu(0x4040));
+		a.write_cpu(0x4023, 0x02);
+		a.write_cpu(0x4089, 0x80);
+		a.write_cpu(0x4040, 63);
+		assert_eq!(0x7F, a.read_cpu(0x4040));
+		a.write_cpu(0x4089, 0x00);
+		a.write_cpu(0x4080, 0x80 | 32);
+		a.cpu_clock(1);
+		assert_eq!(Some((ExpansionChip::Fds, AUDIO_LEVEL)), a.expansion_audio());
+	}
+
+	#[test]
+	fn load() {
+		let dir = env::temp_dir().join(format!("nes-fds-{}", ::std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let image = dir.join("game.fds");
+		File::create(&image).and_then(|mut file| file.write_all(&side(0))).unwrap();
+		let path = image.to_str().unwrap();
+		assert!(load_rom_with_info(path).is_err());
+		File::create(dir.join("disksys.rom")).and_then(|mut file| file.write_all(&bios())).unwrap();
+		let result = load_rom_with_info(path);
+		fs::remove_dir_all(&dir).unwrap();
+
+		let (mut cartridge, info) = result.unwrap();
+		assert_eq!("FDS", cartridge.name());
+		assert_eq!(0x34, cartridge.read_cpu(0xFFFC));
+		assert_eq!(SIDE_SIZE, info.prg_size);
+	}
+}
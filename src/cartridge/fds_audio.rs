@@ -0,0 +1,405 @@
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// Master volume factors of $4089 bits 0-1, 2/2, 2/3, 2/4 and 2/5, in
+// 30ths.
+const MASTER_VOLUMES: [u32; 4] = [30, 20, 15, 12];
+// Changes of the modulation counter by the 3 bit entries of the mod table,
+// None resets it to 0.
+const MOD_STEPS: [Option<i8>; 8] = [Some(0), Some(1), Some(2), Some(4), None, Some(-4), Some(-2), Some(-1)];
+// The output is computed with gains up to 32, higher ones are clamped.
+const MAX_GAIN: u8 = 32;
+const MAX_OUTPUT: u32 = 63 * MAX_GAIN as u32 * 30;
+
+// Volume or modulation envelope, $4080 and $4084.
+#[derive(Debug, Clone)]
+struct Envelope {
+	// bit 7: disabled, gain is set to the speed bits; bit 6: increase
+	control: u8,
+	gain: u8,
+	// CPU cycles until the next change
+	counter: u32,
+}
+
+impl Envelope {
+	fn new() -> Envelope {
+		Envelope {
+			control: 0x80,
+			gain: 0,
+			counter: 0,
+		}
+	}
+
+	fn write(&mut self, value: u8, master_speed: u8) {
+		self.control = value;
+		if value & 0x80 != 0 {
+			self.gain = value & 0x3F;
+		}
+		self.counter = self.period(master_speed);
+	}
+
+	fn period(&self, master_speed: u8) -> u32 {
+		8 * (master_speed as u32 + 1) * ((self.control as u32 & 0x3F) + 1)
+	}
+
+	fn clock(&mut self, master_speed: u8) {
+		if self.control & 0x80 != 0 {
+			return;
+		}
+		if self.counter > 1 {
+			self.counter -= 1;
+			return;
+		}
+		self.counter = self.period(master_speed);
+		if self.control & 0x40 != 0 {
+			if self.gain < MAX_GAIN {
+				self.gain += 1;
+			}
+		} else if self.gain > 0 {
+			self.gain -= 1;
+		}
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.control)?;
+		savestate::write_u8(out, self.gain)?;
+		savestate::write_u64(out, self.counter as u64)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.control = savestate::read_u8(input)?;
+		self.gain = savestate::read_u8(input)?;
+		self.counter = savestate::read_u64(input)? as u32;
+		Ok(())
+	}
+}
+
+// The sound unit of the Famicom Disk System RAM adapter, registers
+// $4040-$4097: one channel playing a 64 step wavetable of 6 bit samples,
+// whose pitch is bent by a modulation unit stepping through a table of
+// 3 bit deltas, with envelopes for volume and modulation depth.
+#[derive(Debug, Clone)]
+pub struct FdsAudio {
+	wave: [u8; 64],
+	// $4089 bit 7, the wave RAM can be written and the output holds
+	wave_write: bool,
+	master_volume: u8,
+	// $4082/$4083 bits 0-3
+	frequency: u16,
+	// $4083 bit 7, the wave stops at its first step
+	wave_halt: bool,
+	// $4083 bit 6, both envelopes stop
+	envelopes_halt: bool,
+	// 6 bits of wave step above 16 bits of fraction
+	wave_accumulator: u32,
+	volume: Envelope,
+	// $408A, scales the periods of both envelopes, 0 stops them
+	envelope_speed: u8,
+	// 64 steps, every entry is written twice
+	mod_table: [u8; 64],
+	mod_position: u8,
+	// 7 bit signed, $4085
+	mod_counter: i8,
+	mod_frequency: u16,
+	// $4087 bit 7, the mod unit stops and the table can be written
+	mod_halt: bool,
+	mod_accumulator: u32,
+	modulation: Envelope,
+	// The wave step currently played, updated except while writes to the
+	// wave RAM are enabled.
+	output_step: u8,
+}
+
+impl FdsAudio {
+	pub fn new() -> FdsAudio {
+		FdsAudio {
+			wave: [0; 64],
+			wave_write: false,
+			master_volume: 0,
+			frequency: 0,
+			wave_halt: true,
+			envelopes_halt: false,
+			wave_accumulator: 0,
+			volume: Envelope::new(),
+			envelope_speed: 0xE8,
+			mod_table: [0; 64],
+			mod_position: 0,
+			mod_counter: 0,
+			mod_frequency: 0,
+			mod_halt: true,
+			mod_accumulator: 0,
+			modulation: Envelope::new(),
+			output_step: 0,
+		}
+	}
+
+	// Reads of $4040-$4097 which are answered, the wave RAM and the current
+	// gains, with open bus in the upper bits. None for the other addresses.
+	pub fn read(&self, addr: u16, open_bus: u8) -> Option<u8> {
+		match addr {
+			0x4040..=0x407F => Some(open_bus & 0xC0 | self.wave[addr as usize - 0x4040]),
+			0x4090 => Some(open_bus & 0xC0 | self.volume.gain),
+			0x4092 => Some(open_bus & 0xC0 | self.modulation.gain),
+			_ => None,
+		}
+	}
+
+	pub fn write(&mut self, addr: u16, value: u8) {
+		match addr {
+			0x4040..=0x407F if self.wave_write => self.wave[addr as usize - 0x4040] = value & 0x3F,
+			0x4080 => self.volume.write(value, self.envelope_speed),
+			0x4082 => self.frequency = self.frequency & 0xF00 | value as u16,
+			0x4083 => {
+				self.frequency = self.frequency & 0xFF | (value as u16 & 0x0F) << 8;
+				self.wave_halt = value & 0x80 != 0;
+				self.envelopes_halt = value & 0x40 != 0;
+				if self.wave_halt {
+					self.wave_accumulator = 0;
+				}
+			}
+			0x4084 => self.modulation.write(value, self.envelope_speed),
+			0x4085 => self.mod_counter = sign_extend_7(value),
+			0x4086 => self.mod_frequency = self.mod_frequency & 0xF00 | value as u16,
+			0x4087 => {
+				self.mod_frequency = self.mod_frequency & 0xFF | (value as u16 & 0x0F) << 8;
+				self.mod_halt = value & 0x80 != 0;
+				if self.mod_halt {
+					self.mod_accumulator = 0;
+				}
+			}
+			0x4088 if self.mod_halt => {
+				let position = self.mod_position as usize & 0x3E;
+				self.mod_table[position] = value & 0x07;
+				self.mod_table[position + 1] = value & 0x07;
+				self.mod_position = (self.mod_position + 2) & 0x3F;
+			}
+			0x4089 => {
+				self.wave_write = value & 0x80 != 0;
+				self.master_volume = value & 0x03;
+			}
+			0x408A => self.envelope_speed = value,
+			_ => {}
+		}
+	}
+
+	// Runs for a number of CPU cycles.
+	pub fn clock(&mut self, cycles: u32) {
+		for _ in 0..cycles {
+			self.clock_cycle();
+		}
+	}
+
+	fn clock_cycle(&mut self) {
+		if !self.envelopes_halt && !self.wave_halt && self.envelope_speed != 0 {
+			self.volume.clock(self.envelope_speed);
+			self.modulation.clock(self.envelope_speed);
+		}
+
+		if !self.mod_halt && self.mod_frequency != 0 {
+			self.mod_accumulator += self.mod_frequency as u32;
+			if self.mod_accumulator >= 0x10000 {
+				self.mod_accumulator &= 0xFFFF;
+				self.mod_counter = match MOD_STEPS[self.mod_table[self.mod_position as usize] as usize] {
+					Some(delta) => sign_extend_7(self.mod_counter.wrapping_add(delta) as u8),
+					None => 0,
+				};
+				self.mod_position = (self.mod_position + 1) & 0x3F;
+			}
+		}
+
+		if !self.wave_halt {
+			self.wave_accumulator = (self.wave_accumulator + self.pitch()) & 0x3FFFFF;
+		}
+		if !self.wave_write {
+			self.output_step = (self.wave_accumulator >> 16) as u8;
+		}
+	}
+
+	// The frequency bent by the modulation unit, as computed by the
+	// hardware including its odd rounding.
+	fn pitch(&self) -> u32 {
+		let frequency = self.frequency as i32;
+		if self.mod_halt {
+			return frequency as u32;
+		}
+		let counter = self.mod_counter as i32;
+		let product = counter * self.modulation.gain as i32;
+		let mut temp = product >> 4;
+		if product & 0x0F != 0 && temp & 0x80 == 0 {
+			temp += if counter < 0 { -1 } else { 2 };
+		}
+		if temp >= 192 {
+			temp -= 256;
+		} else if temp < -64 {
+			temp += 256;
+		}
+		let product = frequency * temp;
+		let mut offset = product >> 6;
+		if product & 0x3F >= 32 {
+			offset += 1;
+		}
+		(frequency + offset).max(0) as u32
+	}
+
+	// The output level between 0.0 and 1.0, at full volume of the wave and
+	// the master volume.
+	pub fn output(&self) -> f32 {
+		let gain = self.volume.gain.min(MAX_GAIN) as u32;
+		let level = self.wave[self.output_step as usize] as u32 * gain * MASTER_VOLUMES[self.master_volume as usize];
+		level as f32 / MAX_OUTPUT as f32
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bytes(out, &self.wave)?;
+		savestate::write_bool(out, self.wave_write)?;
+		savestate::write_u8(out, self.master_volume)?;
+		savestate::write_u16(out, self.frequency)?;
+		savestate::write_bool(out, self.wave_halt)?;
+		savestate::write_bool(out, self.envelopes_halt)?;
+		savestate::write_u64(out, self.wave_accumulator as u64)?;
+		self.volume.save_state(out)?;
+		savestate::write_u8(out, self.envelope_speed)?;
+		savestate::write_bytes(out, &self.mod_table)?;
+		savestate::write_u8(out, self.mod_position)?;
+		savestate::write_u8(out, self.mod_counter as u8)?;
+		savestate::write_u16(out, self.mod_frequency)?;
+		savestate::write_bool(out, self.mod_halt)?;
+		savestate::write_u64(out, self.mod_accumulator as u64)?;
+		self.modulation.save_state(out)?;
+		savestate::write_u8(out, self.output_step)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		savestate::read_bytes(input, &mut self.wave)?;
+		self.wave_write = savestate::read_bool(input)?;
+		self.master_volume = savestate::read_u8(input)? & 0x03;
+		self.frequency = savestate::read_u16(input)? & 0xFFF;
+		self.wave_halt = savestate::read_bool(input)?;
+		self.envelopes_halt = savestate::read_bool(input)?;
+		self.wave_accumulator = savestate::read_u64(input)? as u32 & 0x3FFFFF;
+		self.volume.load_state(input)?;
+		self.envelope_speed = savestate::read_u8(input)?;
+		savestate::read_bytes(input, &mut self.mod_table)?;
+		self.mod_position = savestate::read_u8(input)? & 0x3F;
+		self.mod_counter = sign_extend_7(savestate::read_u8(input)?);
+		self.mod_frequency = savestate::read_u16(input)? & 0xFFF;
+		self.mod_halt = savestate::read_bool(input)?;
+		self.mod_accumulator = savestate::read_u64(input)? as u32 & 0xFFFF;
+		self.modulation.load_state(input)?;
+		self.output_step = savestate::read_u8(input)? & 0x3F;
+		for entry in self.mod_table.iter_mut() {
+			*entry &= 0x07;
+		}
+		Ok(())
+	}
+}
+
+fn sign_extend_7(value: u8) -> i8 {
+	((value << 1) as i8) >> 1
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// A square wave: 32 steps of 63, then 32 steps of 0.
+	fn square(fds: &mut FdsAudio) {
+		fds.write(0x4089, 0x80);
+		for i in 0..64 {
+			fds.write(0x4040 + i, if i < 32 { 63 } else { 0 });
+		}
+		fds.write(0x4089, 0x00);
+		// full volume, envelope off
+		fds.write(0x4080, 0x80 | 32);
+	}
+
+	// Cycles until the output changes.
+	fn cycles_to_edge(fds: &mut FdsAudio) -> u32 {
+		let start = fds.output();
+		let mut cycles = 0;
+		while fds.output() == start {
+			fds.clock(1);
+			cycles += 1;
+			assert!(cycles < 1_000_000);
+		}
+		cycles
+	}
+
+	#[test]
+	fn wave() {
+		let mut fds = FdsAudio::new();
+		square(&mut fds);
+		// halted waves stay at the first step
+		fds.clock(1000);
+		assert_eq!(1.0, fds.output());
+
+		// 32 steps at 0x400 / 0x10000 steps per cycle
+		fds.write(0x4082, 0x00);
+		fds.write(0x4083, 0x04);
+		cycles_to_edge(&mut fds);
+		assert_eq!(0.0, fds.output());
+		assert_eq!(2048, cycles_to_edge(&mut fds));
+		assert_eq!(1.0, fds.output());
+
+		fds.write(0x4089, 0x03);
+		assert_eq!(0.4, fds.output());
+		fds.write(0x4080, 0x80 | 16);
+		assert_eq!(0.2, fds.output());
+		assert_eq!(Some(0x40 | 16), fds.read(0x4090, 0x40));
+		assert_eq!(Some(63), fds.read(0x4040, 0));
+		assert_eq!(None, fds.read(0x4091, 0));
+		// the wave RAM is read-only while playing
+		fds.write(0x4041, 0);
+		assert_eq!(Some(63), fds.read(0x4041, 0));
+	}
+
+	#[test]
+	fn modulation() {
+		let mut fds = FdsAudio::new();
+		square(&mut fds);
+		fds.write(0x4082, 0x00);
+		fds.write(0x4083, 0x04);
+		// a constant counter of 16 at gain 32 bends the pitch up by half
+		fds.write(0x4084, 0x80 | 32);
+		fds.write(0x4085, 16);
+		fds.write(0x4087, 0x00);
+		cycles_to_edge(&mut fds);
+		let period = cycles_to_edge(&mut fds);
+		assert!(period == 1365 || period == 1366, "{}", period);
+
+		// counting down by 1 per mod step from 0 ends at -64 and wraps
+		fds.write(0x4087, 0x80);
+		for _ in 0..32 {
+			fds.write(0x4088, 7);
+		}
+		fds.write(0x4085, 0);
+		fds.write(0x4086, 0x00);
+		fds.write(0x4087, 0x01);
+		fds.clock(0x100 * 64);
+		assert_eq!(-64, fds.mod_counter);
+		fds.clock(0x100);
+		assert_eq!(63, fds.mod_counter);
+	}
+
+	#[test]
+	fn volume_envelope() {
+		let mut fds = FdsAudio::new();
+		square(&mut fds);
+		fds.write(0x4083, 0x00);
+		// increase every 8 * (1 + 1) * (0 + 1) cycles
+		fds.write(0x408A, 1);
+		fds.write(0x4080, 0x80);
+		fds.write(0x4080, 0x40);
+		fds.clock(16 * 10);
+		assert_eq!(Some(10), fds.read(0x4090, 0));
+		fds.clock(16 * 100);
+		assert_eq!(Some(32), fds.read(0x4090, 0));
+
+		let mut other = FdsAudio::new();
+		let mut state = Vec::new();
+		fds.save_state(&mut state).unwrap();
+		other.load_state(&mut &state[..]).unwrap();
+		assert_eq!(format!("{:?}", fds), format!("{:?}", other));
+	}
+}
@@ -1,16 +1,51 @@
-use cartridge::{Cartridge, MirrorMode};
+use cartridge::{Cartridge, MirrorMode, HAS_PRG_RAM, HAS_BATTERY, HAS_CHR_RAM};
 use cpu::memory_map;
+use std::fmt;
+use std::io::{self, Read, Write};
+use savestate;
+
+// Boards which use the upper CHR bank bits for something else.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mmc1Board {
+	// CHR bank bits select CHR ROM only (SKROM, SLROM, ...).
+	Standard,
+	// 8 KiB CHR RAM, bit 4 of CHR bank 0 disables the PRG RAM.
+	SnRom,
+	// 8 KiB CHR RAM, bit 4 of CHR bank 0 selects the 256 KiB PRG half.
+	SuRom,
+}
+
+impl Mmc1Board {
+	// Guesses the board from the ROM sizes, for headers without submapper.
+	pub fn detect(prg_size: usize, chr_size: usize) -> Mmc1Board {
+		if prg_size > 256 * 1024 {
+			Mmc1Board::SuRom
+		} else if chr_size == 0 {
+			Mmc1Board::SnRom
+		} else {
+			Mmc1Board::Standard
+		}
+	}
+}
 
 // Nintendo MMC1
 // CPU:
 //   6000-7FFF  PRG RAM (8 KiB)
 //   8000-BFFF  PRG ROM (switchable/fixed to first)
 //   C000-FFFF  PRG ROM (fixed to last/switchable)
+// PPU:
+//   0000-1FFF  CHR ROM or 8 KiB CHR RAM (one 8 KiB or two 4 KiB banks)
+// The "last" bank is the last one of the selected 256 KiB half on SUROM.
+// SNROM and SUROM look at CHR bank 0 only, which is what the games use.
 // See http://wiki.nesdev.com/w/index.php/MMC1
+#[derive(Clone)]
 pub struct Mmc1 {
+	board: Mmc1Board,
 	prg_rom: Vec<u8>,
 	chr_rom: Vec<u8>,
+	chr_ram: bool,
 	ram: Vec<u8>,
+	battery: bool,
 	control: u8,
 	chr_bank0: u8,
 	chr_bank1: u8,
@@ -20,15 +55,25 @@ pub struct Mmc1 {
 }
 
 impl Mmc1 {
-	// TODO validate input!!! (ram size ...)
+	// Empty chr_rom means 8 KiB CHR RAM. The board is guessed from the sizes.
 	pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, ram_size: usize) -> Mmc1 {
-		assert!(prg_rom.len() == 16 * 16 * 1024);
-		assert!(chr_rom.len() == 128 * 1024);
+		let board = Mmc1Board::detect(prg_rom.len(), chr_rom.len());
+		Mmc1::with_board(prg_rom, chr_rom, ram_size, board)
+	}
+
+	// TODO validate input!!! (ram size ...)
+	pub fn with_board(prg_rom: Vec<u8>, chr_rom: Vec<u8>, ram_size: usize, board: Mmc1Board) -> Mmc1 {
+		assert!(prg_rom.len().is_power_of_two() && 16 * 1024 <= prg_rom.len() && prg_rom.len() <= 512 * 1024);
+		assert!(chr_rom.is_empty() || (chr_rom.len().is_power_of_two() && 8 * 1024 <= chr_rom.len() && chr_rom.len() <= 128 * 1024));
 		assert!(ram_size == 8 * 1024);
+		let chr_ram = chr_rom.is_empty();
 		Mmc1 {
-			prg_rom: prg_rom,
-			chr_rom: chr_rom,
+			board,
+			prg_rom,
+			chr_rom: if chr_ram { vec![0; 8 * 1024] } else { chr_rom },
+			chr_ram,
 			ram: vec![0; ram_size],
+			battery: false,
 			control: 0x0C,
 			chr_bank0: 0,
 			chr_bank1: 0,
@@ -37,9 +82,75 @@ impl Mmc1 {
 			ppu_ram: [0; 2048],
 		}
 	}
+
+	fn ram_enabled(&self) -> bool {
+		let disabled_by_chr = self.board == Mmc1Board::SnRom && self.chr_bank0 & 0b10000 != 0;
+		self.prg_bank & 0b10000 == 0 && !disabled_by_chr
+	}
+
+	// Index into prg_rom for 8000-FFFF. Smaller ROMs are mirrored.
+	fn prg_index(&self, addr: u16) -> usize {
+		let outer =
+			if self.board == Mmc1Board::SuRom { ((self.chr_bank0 >> 4) & 1) as usize * 16 }
+			else { 0 };
+		let bank = (self.prg_bank & 0b1111) as usize;
+		let bank = match (self.control >> 2) & 0b11 {
+			0 | 1 => (bank & !1) | ((addr as usize >> 14) & 1),
+			2 => if addr < 0xC000 { 0 } else { bank },
+			3 => if addr < 0xC000 { bank } else { 15 },
+			_ => { unreachable!() }
+		};
+		((outer | bank) * 0x4000 + (addr as usize & 0x3FFF)) % self.prg_rom.len()
+	}
+
+	// Index into chr_rom for 0000-1FFF. Smaller ROMs are mirrored.
+	fn chr_index(&self, addr: u16) -> usize {
+		let bank =
+			if self.control & 0b10000 == 0 {
+				// 8 KiB mode
+				(self.chr_bank0 & !1) as usize | (addr as usize >> 12)
+			} else if addr <= 0x0FFF {
+				// 4 KiB mode
+				self.chr_bank0 as usize
+			} else {
+				self.chr_bank1 as usize
+			};
+		(bank * 0x1000 + (addr as usize & 0x0FFF)) % self.chr_rom.len()
+	}
+}
+
+impl fmt::Debug for Mmc1 {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Mmc1")
+			.field("board", &self.board)
+			.field("prg_rom_size", &self.prg_rom.len())
+			.field("chr_rom_size", &self.chr_rom.len())
+			.field("chr_ram", &self.chr_ram)
+			.field("ram_size", &self.ram.len())
+			.field("control", &self.control)
+			.field("chr_bank0", &self.chr_bank0)
+			.field("chr_bank1", &self.chr_bank1)
+			.field("prg_bank", &self.prg_bank)
+			.field("shifter", &self.shifter)
+			.finish()
+	}
 }
 
 impl Cartridge for Mmc1 {
+	fn name(&self) -> &'static str {
+		"MMC1"
+	}
+
+	fn capabilities(&self) -> u8 {
+		HAS_PRG_RAM
+			| if self.battery { HAS_BATTERY } else { 0 }
+			| if self.chr_ram { HAS_CHR_RAM } else { 0 }
+	}
+
+	fn set_battery(&mut self, battery: bool) {
+		self.battery = battery;
+	}
+
 	fn read_cpu(&mut self, addr: u16) -> u8 {
 		debug_assert!(addr >= memory_map::CARTRIDGE_START);
 		if addr < 0x6000 {
@@ -47,36 +158,14 @@ impl Cartridge for Mmc1 {
 			0
 		} else if addr < 0x8000 {
 			// ram
-			if self.prg_bank & 0b10000 == 0 {
+			if self.ram_enabled() {
 				self.ram[addr as usize - 0x6000]
 			} else {
 				0
 			}
 		} else {
 			// program rom
-			match (self.control >> 2) & 0b11 {
-				0 | 1 => {
-					let bank = ((self.prg_bank >> 1) & 0b111) as usize;
-					self.prg_rom[0x8000 * bank + addr as usize - 0x8000]
-				},
-				2 => {
-					if addr < 0xC000 {
-						self.prg_rom[addr as usize - 0x8000]
-					} else {
-						let bank = (self.prg_bank & 0b1111) as usize;
-						self.prg_rom[0x4000 * bank + addr as usize - 0xC000]
-					}
-				},
-				3 => {
-					if addr < 0xC000 {
-						let bank = (self.prg_bank & 0b1111) as usize;
-						self.prg_rom[0x4000 * bank + addr as usize - 0x8000]
-					} else {
-						self.prg_rom[0x4000 * 15 + addr as usize - 0xC000]
-					}
-				},
-				_ => { unreachable!() }
-			}
+			self.prg_rom[self.prg_index(addr)]
 		}
 	}
 
@@ -87,7 +176,7 @@ impl Cartridge for Mmc1 {
 			// not mapped
 		} else if addr < 0x8000 {
 			// ram
-			if self.prg_bank & 0b10000 == 0 {
+			if self.ram_enabled() {
 				self.ram[addr as usize - 0x6000] = value;
 			}
 		} else {
@@ -122,37 +211,56 @@ impl Cartridge for Mmc1 {
 	fn read_ppu(&mut self, addr: u16) -> u8 {
 		debug_assert!(addr <= 0x3EFF);
 		if addr <= 0x1FFF {
-			if self.control & 0b10000 == 0 {
-				// 8 KiB mode
-				let bank = (self.chr_bank0 >> 1) as usize;
-				self.chr_rom[bank * 8 * 1024 + addr as usize]
-			} else {
-				// 4 KiB mode
-				if addr <= 0x0FFF {
-					self.chr_rom[(self.chr_bank0 as usize) * 4 * 1024 + addr as usize]
-				} else {
-					self.chr_rom[(self.chr_bank1 as usize) * 4 * 1024 + addr as usize - 0x1000]
-				}
-			}
-		} else if addr <= 0x2FFF {
-			self.ppu_ram[(addr as usize - 0x1000) & 0x7FF]
+			self.chr_rom[self.chr_index(addr)]
 		} else {
-			self.ppu_ram[(addr as usize - 0x2000) & 0x7FF]
+			self.ppu_ram[self.mirror_mode().nametable_index(addr)]
 		}
 	}
 
 	fn write_ppu(&mut self, addr: u16, value: u8) {
 		debug_assert!(addr <= 0x3EFF);
-		if addr <= 0x1FFF {
-		} else if addr <= 0x2FFF {
-			self.ppu_ram[(addr as usize - 0x1000) & 0x7FF] = value;
-		} else {
-			self.ppu_ram[(addr as usize - 0x2000) & 0x7FF] = value;
+		if addr > 0x1FFF {
+			self.ppu_ram[self.mirror_mode().nametable_index(addr)] = value;
+		} else if self.chr_ram {
+			let index = self.chr_index(addr);
+			self.chr_rom[index] = value;
 		}
 	}
 
 	fn mirror_mode(&self) -> MirrorMode {
-		unimplemented!()
+		match self.control & 0b11 {
+			0 => MirrorMode::SingleScreenLower,
+			1 => MirrorMode::SingleScreenUpper,
+			2 => MirrorMode::VerticalMirroring,
+			3 => MirrorMode::HorizontalMirroring,
+			_ => { unreachable!() }
+		}
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bytes(out, &self.ram)?;
+		savestate::write_u8(out, self.control)?;
+		savestate::write_u8(out, self.chr_bank0)?;
+		savestate::write_u8(out, self.chr_bank1)?;
+		savestate::write_u8(out, self.prg_bank)?;
+		savestate::write_u8(out, self.shifter)?;
+		if self.chr_ram {
+			savestate::write_bytes(out, &self.chr_rom)?;
+		}
+		savestate::write_bytes(out, &self.ppu_ram)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		savestate::read_bytes(input, &mut self.ram)?;
+		self.control = savestate::read_u8(input)?;
+		self.chr_bank0 = savestate::read_u8(input)?;
+		self.chr_bank1 = savestate::read_u8(input)?;
+		self.prg_bank = savestate::read_u8(input)?;
+		self.shifter = savestate::read_u8(input)?;
+		if self.chr_ram {
+			savestate::read_bytes(input, &mut self.chr_rom)?;
+		}
+		savestate::read_bytes(input, &mut self.ppu_ram)
 	}
 }
 
@@ -160,173 +268,183 @@ impl Cartridge for Mmc1 {
 mod test {
 	use super::*;
 	use cartridge::Cartridge;
+	use cartridge::conformance::{check, numbered_banks, nametable_steps, Step};
+	use cartridge::conformance::Step::*;
 
 	#[test]
 	fn unmapped() {
 		let mut a = Mmc1::new(vec![0; 256 * 1024], vec![0; 128 * 1024], 0x2000);
-		a.write_cpu(0x5000, 123);
-		assert_eq!(0, a.read_cpu(0x5000));
+		check(&mut a, &[Write(0x5000, 123), Cpu(0x5000, 0)]);
 	}
 
 	#[test]
 	fn ram() {
 		let mut a = Mmc1::new(vec![0; 256 * 1024], vec![0; 128 * 1024], 0x2000);
-		a.write_cpu(0x6001, 123);
-		assert_eq!(123, a.read_cpu(0x6001));
-
-		// disable RAM
-		a.write_cpu(0x8000, 0);
-		a.write_cpu(0x8000, 0);
-		a.write_cpu(0x8000, 0);
-		a.write_cpu(0x8000, 0);
-		a.write_cpu(0xE000, 1);
-		assert_eq!(0, a.read_cpu(0x6001));
-		a.write_cpu(0x6001, 111);
-
-		// enable RAM
-		a.write_cpu(0x8000, 0);
-		a.write_cpu(0x8000, 0);
-		a.write_cpu(0x8000, 0);
-		a.write_cpu(0x8000, 0);
-		a.write_cpu(0xE000, 0);
-		assert_eq!(123, a.read_cpu(0x6001));
+		check(&mut a, &[
+			Write(0x6001, 123),
+			Cpu(0x6001, 123),
+			// disable RAM
+			Serial(0xE000, 0x10),
+			Cpu(0x6001, 0),
+			Write(0x6001, 111),
+			// enable RAM
+			Serial(0xE000, 0x00),
+			Cpu(0x6001, 123),
+		]);
 	}
 
 	#[test]
 	fn rom() {
-		let mut rom = vec![255; 256 * 1024];
-		for i in 0..16 {
-			rom[i * 16 * 1024 + 1] = i as u8;
-		}
-		let mut a = Mmc1::new(rom, vec![0; 128 * 1024], 0x2000);
-
-		// 32 switch mode
-		for i in 0..2 {
-			a.write_cpu(0x8001, 0);
-			a.write_cpu(0x8001, 0);
-			a.write_cpu(0x8001, i);
-			a.write_cpu(0x8001, 0);
-			a.write_cpu(0x8001, 0);
-			for j in 0..16 {
-				a.write_cpu(0xE000, j);
-				a.write_cpu(0xE000, j >> 1);
-				a.write_cpu(0xE000, j >> 2);
-				a.write_cpu(0xE000, j >> 3);
-				a.write_cpu(0xE000, 0);
-				assert_eq!((j / 2) * 2, a.read_cpu(0x8001));
-				assert_eq!((j / 2) * 2 + 1, a.read_cpu(0xC001));
+		let mut a = Mmc1::new(numbered_banks(16, 16 * 1024, 1, 255), vec![0; 128 * 1024], 0x2000);
+		// 32 KiB switch mode, in both of its encodings: bank numbers
+		// select pairs
+		for &mode in [0x00, 0x04].iter() {
+			let mut steps = vec![Serial(0x8001, mode)];
+			for bank in 0..16 {
+				steps.extend_from_slice(&[Serial(0xE000, bank), Cpu(0x8001, bank / 2 * 2), Cpu(0xC001, bank / 2 * 2 + 1)]);
 			}
+			check(&mut a, &steps);
 		}
 
-		// fix first, 16 switch
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 1);
-		a.write_cpu(0x8001, 0);
-		for i in 0..16 {
-			a.write_cpu(0xE000, i);
-			a.write_cpu(0xE000, i >> 1);
-			a.write_cpu(0xE000, i >> 2);
-			a.write_cpu(0xE000, i >> 3);
-			a.write_cpu(0xE000, 0);
-			assert_eq!(0, a.read_cpu(0x8001));
-			assert_eq!(i, a.read_cpu(0xC001));
+		// fix first, 16 KiB switch
+		let mut steps = vec![Serial(0x8001, 0x08)];
+		for bank in 0..16 {
+			steps.extend_from_slice(&[Serial(0xE000, bank), Cpu(0x8001, 0), Cpu(0xC001, bank)]);
 		}
+		check(&mut a, &steps);
 
-		// fix last, 16 switch
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 1);
-		a.write_cpu(0x8001, 1);
-		a.write_cpu(0x8001, 0);
-		for i in 0..16 {
-			a.write_cpu(0xE000, i);
-			a.write_cpu(0xE000, i >> 1);
-			a.write_cpu(0xE000, i >> 2);
-			a.write_cpu(0xE000, i >> 3);
-			a.write_cpu(0xE000, 0);
-			assert_eq!(i, a.read_cpu(0x8001));
-			assert_eq!(15, a.read_cpu(0xC001));
+		// fix last, 16 KiB switch
+		let mut steps = vec![Serial(0x8001, 0x0C)];
+		for bank in 0..16 {
+			steps.extend_from_slice(&[Serial(0xE000, bank), Cpu(0x8001, bank), Cpu(0xC001, 15)]);
 		}
+		check(&mut a, &steps);
 	}
 
 	#[test]
 	fn ppu_ram() {
 		let mut a = Mmc1::new(vec![123; 256 * 1024], vec![0; 128 * 1024], 0x2000);
-		a.write_ppu(0x2002, 2);
-		a.write_ppu(0x3403, 3);
-		assert_eq!(2, a.read_ppu(0x2002));
-		assert_eq!(0, a.read_ppu(0x2402));
-		assert_eq!(2, a.read_ppu(0x2802));
-		assert_eq!(0, a.read_ppu(0x2C02));
-		assert_eq!(0, a.read_ppu(0x2003));
-		assert_eq!(3, a.read_ppu(0x2403));
-		assert_eq!(0, a.read_ppu(0x2803));
-		assert_eq!(3, a.read_ppu(0x2C03));
-		assert_eq!(2, a.read_ppu(0x3002));
-		assert_eq!(0, a.read_ppu(0x3402));
-		assert_eq!(2, a.read_ppu(0x3802));
-		assert_eq!(0, a.read_ppu(0x3C02));
-		assert_eq!(0, a.read_ppu(0x3003));
-		assert_eq!(3, a.read_ppu(0x3403));
-		assert_eq!(0, a.read_ppu(0x3803));
-		assert_eq!(3, a.read_ppu(0x3C03));
+		let steps: Vec<Step> = [Serial(0x8000, 0x02), Serial(0x8000, 0x03), Serial(0x8000, 0x00), Serial(0x8000, 0x01)]
+			.iter()
+			.zip([MirrorMode::VerticalMirroring, MirrorMode::HorizontalMirroring,
+				MirrorMode::SingleScreenLower, MirrorMode::SingleScreenUpper].iter())
+			.flat_map(|(write, mode)| {
+				let mut steps = vec![write.clone()];
+				steps.extend(nametable_steps(mode.clone()));
+				steps
+			})
+			.collect();
+		check(&mut a, &steps);
 	}
 
 	#[test]
 	fn ppu_rom() {
-		let mut rom = vec![123; 128 * 1024];
-		for i in 0..32 {
-			rom[i * 4 * 1024 + 2] = i as u8;
-		}
+		let mut a = Mmc1::new(vec![123; 256 * 1024], numbered_banks(32, 4 * 1024, 2, 123), 0x2000);
 
-		let mut a = Mmc1::new(vec![123; 256 * 1024], rom, 0x2000);
-		
-		// 8 switch mode
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
+		// 8 KiB switch mode: bank 0 selects a pair, bank 1 is ignored
+		let mut steps = vec![Serial(0x8001, 0x00)];
 		for i in 0..32 {
-			a.write_cpu(0x8001, i >> 0);
-			a.write_cpu(0x8001, i >> 1);
-			a.write_cpu(0x8001, i >> 2);
-			a.write_cpu(0x8001, i >> 3);
-			a.write_cpu(0xA001, i >> 4);
+			steps.push(Serial(0xA001, i));
 			for j in 0..32 {
-				a.write_cpu(0x8001, j >> 0);
-				a.write_cpu(0x8001, j >> 1);
-				a.write_cpu(0x8001, j >> 2);
-				a.write_cpu(0x8001, j >> 3);
-				a.write_cpu(0xC001, j >> 4);
-				assert_eq!(i / 2 * 2, a.read_ppu(0x0002));
-				assert_eq!(i / 2 * 2 + 1, a.read_ppu(0x1002));
+				steps.extend_from_slice(&[Serial(0xC001, j), Ppu(0x0002, i / 2 * 2), Ppu(0x1002, i / 2 * 2 + 1)]);
 			}
 		}
+		check(&mut a, &steps);
 
-		// 4 switch mode
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 0);
-		a.write_cpu(0x8001, 1);
+		// 4 KiB switch mode
+		let mut steps = vec![Serial(0x8001, 0x10)];
 		for i in 0..32 {
-			a.write_cpu(0x8001, i >> 0);
-			a.write_cpu(0x8001, i >> 1);
-			a.write_cpu(0x8001, i >> 2);
-			a.write_cpu(0x8001, i >> 3);
-			a.write_cpu(0xA001, i >> 4);
+			steps.push(Serial(0xA001, i));
 			for j in 0..32 {
-				a.write_cpu(0x8001, j >> 0);
-				a.write_cpu(0x8001, j >> 1);
-				a.write_cpu(0x8001, j >> 2);
-				a.write_cpu(0x8001, j >> 3);
-				a.write_cpu(0xC001, j >> 4);
-				assert_eq!(i, a.read_ppu(0x0002));
-				assert_eq!(j, a.read_ppu(0x1002));
+				steps.extend_from_slice(&[Serial(0xC001, j), Ppu(0x0002, i), Ppu(0x1002, j)]);
 			}
 		}
+		check(&mut a, &steps);
+	}
+
+	#[test]
+	fn small_rom() {
+		let mut a = Mmc1::new(numbered_banks(8, 16 * 1024, 0, 0), numbered_banks(8, 4 * 1024, 0, 0), 0x2000);
+		check(&mut a, &[
+			Cpu(0xC000, 7),
+			// bank numbers wrap around
+			Serial(0xE000, 9),
+			Cpu(0x8000, 1),
+			Serial(0x8000, 0b10000),
+			Serial(0xA000, 6),
+			Serial(0xC000, 13),
+			Ppu(0x0000, 6),
+			Ppu(0x1000, 5),
+			// CHR ROM is read only
+			WritePpu(0x0000, 100),
+			Ppu(0x0000, 6),
+		]);
+	}
+
+	#[test]
+	fn snrom() {
+		let mut a = Mmc1::new(vec![0; 128 * 1024], vec![], 0x2000);
+		assert_eq!(Mmc1Board::SnRom, a.board);
+		check(&mut a, &[
+			WritePpu(0x1234, 42),
+			Ppu(0x1234, 42),
+			Write(0x6000, 1),
+			// disable RAM with the CHR bank
+			Serial(0xA000, 0b10000),
+			Cpu(0x6000, 0),
+			Write(0x6000, 2),
+			Ppu(0x1234, 42),
+			Serial(0xA000, 0),
+			Cpu(0x6000, 1),
+		]);
+
+		let mut state = Vec::new();
+		a.save_state(&mut state).unwrap();
+		let mut b = Mmc1::new(vec![0; 128 * 1024], vec![], 0x2000);
+		b.load_state(&mut &state[..]).unwrap();
+		assert_eq!(42, b.read_ppu(0x1234));
+	}
+
+	#[test]
+	fn surom() {
+		let mut a = Mmc1::new(numbered_banks(32, 16 * 1024, 0, 0), vec![], 0x2000);
+		assert_eq!(Mmc1Board::SuRom, a.board);
+		check(&mut a, &[
+			Serial(0xE000, 3),
+			Cpu(0x8000, 3),
+			Cpu(0xC000, 15),
+			// second half, RAM stays enabled
+			Serial(0xA000, 0b10000),
+			Cpu(0x8000, 19),
+			Cpu(0xC000, 31),
+			Write(0x6000, 5),
+			Cpu(0x6000, 5),
+		]);
+	}
+
+	#[test]
+	fn state() {
+		let rom = numbered_banks(16, 16 * 1024, 1, 255);
+		let mut a = Mmc1::new(rom.clone(), vec![0; 128 * 1024], 0x2000);
+		check(&mut a, &[
+			Write(0x6001, 123),
+			Serial(0xE000, 5),
+			// half written register
+			Write(0xE000, 1),
+		]);
+		let mut state = Vec::new();
+		a.save_state(&mut state).unwrap();
+
+		let mut b = Mmc1::new(rom, vec![0; 128 * 1024], 0x2000);
+		b.load_state(&mut &state[..]).unwrap();
+		check(&mut b, &[
+			Cpu(0x6001, 123),
+			Cpu(0x8001, 5),
+			Write(0xE000, 1),
+			Write(0xE000, 0),
+			Write(0xE000, 0),
+			Write(0xE000, 0),
+			Cpu(0x8001, 3),
+		]);
 	}
 }
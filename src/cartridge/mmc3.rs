@@ -0,0 +1,455 @@
+use cartridge::{Cartridge, MirrorMode, HAS_PRG_RAM, HAS_BATTERY, HAS_IRQ, HAS_CHR_RAM};
+use cpu::memory_map;
+use std::fmt;
+use std::io::{self, Read, Write};
+use savestate;
+
+// The two behaviors of the scanline counter when it reaches 0. Games were
+// tested on the revision they shipped with, and a few have a shaking
+// status bar on the other one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mmc3Irq {
+	// MMC3B and MMC3C (Sharp): the IRQ fires whenever the counter is 0
+	// after a clock, also when it is reloaded with a latch of 0.
+	New,
+	// MMC3A (NEC): the IRQ only fires when the counter decrements to 0 or
+	// is reloaded with 0 after a write of $C001. NES 2.0 submapper 4.
+	Old,
+}
+
+impl Mmc3Irq {
+	pub fn from_submapper(submapper: u8) -> Mmc3Irq {
+		if submapper == 4 { Mmc3Irq::Old } else { Mmc3Irq::New }
+	}
+}
+
+// Nintendo MMC3
+// CPU:
+//   6000-7FFF  PRG RAM (8 KiB)
+//   8000-9FFF  PRG ROM (switchable 8 KiB bank or fixed to second last)
+//   A000-BFFF  PRG ROM (switchable 8 KiB bank)
+//   C000-DFFF  PRG ROM (fixed to second last or switchable)
+//   E000-FFFF  PRG ROM (fixed to last)
+//   8000-FFFF  write: pairs of registers selected by address bit 0
+//     8000/8001  bank select/bank data
+//     A000/A001  mirroring/PRG RAM protect
+//     C000/C001  IRQ latch/IRQ reload
+//     E000/E001  IRQ disable and acknowledge/IRQ enable
+// PPU:
+//   0000-1FFF  CHR ROM or 8 KiB CHR RAM (two 2 KiB and four 1 KiB banks)
+// The scanline counter is clocked by the filtered rising edges of PPU A12.
+// See http://wiki.nesdev.com/w/index.php/MMC3
+#[derive(Clone)]
+pub struct Mmc3 {
+	irq_style: Mmc3Irq,
+	prg_rom: Vec<u8>,
+	chr_rom: Vec<u8>,
+	chr_ram: bool,
+	ram: Vec<u8>,
+	battery: bool,
+	// bits 0-2: bank register, 6: PRG mode, 7: CHR inversion
+	bank_select: u8,
+	banks: [u8; 8],
+	// bit 0: horizontal, ignored for four screen boards
+	mirroring: u8,
+	// bit 7: enabled, 6: write protected
+	ram_protect: u8,
+	irq_latch: u8,
+	irq_counter: u8,
+	irq_reload: bool,
+	irq_enabled: bool,
+	irq_pending: bool,
+	four_screen: bool,
+	ppu_ram: [u8; 4096],
+}
+
+impl Mmc3 {
+	// Empty chr_rom means 8 KiB CHR RAM. FourScreen mirroring is fixed by
+	// the board, with the other modes the mirroring register decides.
+	pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, ram_size: usize, mirror_mode: MirrorMode, irq_style: Mmc3Irq) -> Mmc3 {
+		assert!(!prg_rom.is_empty() && prg_rom.len() & 0x1FFF == 0);
+		assert!(chr_rom.len() & 0x03FF == 0);
+		let chr_ram = chr_rom.is_empty();
+		Mmc3 {
+			irq_style,
+			prg_rom,
+			chr_rom: if chr_ram { vec![0; 8 * 1024] } else { chr_rom },
+			chr_ram,
+			ram: vec![0; ram_size],
+			battery: false,
+			bank_select: 0,
+			banks: [0, 2, 4, 5, 6, 7, 0, 1],
+			mirroring: 0,
+			ram_protect: 0x80,
+			irq_latch: 0,
+			irq_counter: 0,
+			irq_reload: false,
+			irq_enabled: false,
+			irq_pending: false,
+			four_screen: mirror_mode == MirrorMode::FourScreen,
+			ppu_ram: [0; 4096],
+		}
+	}
+
+	// Index into prg_rom for 8000-FFFF. Smaller ROMs are mirrored.
+	fn prg_index(&self, addr: u16) -> usize {
+		let last = self.prg_rom.len() / 0x2000 - 1;
+		let second_last = last.saturating_sub(1);
+		let swap = self.bank_select & 0x40 != 0;
+		let bank = match (addr >> 13) & 3 {
+			0 => if swap { second_last } else { self.banks[6] as usize },
+			1 => self.banks[7] as usize,
+			2 => if swap { self.banks[6] as usize } else { second_last },
+			3 => last,
+			_ => unreachable!(),
+		};
+		(bank * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_rom.len()
+	}
+
+	// Index into chr_rom for 0000-1FFF. Smaller ROMs are mirrored.
+	fn chr_index(&self, addr: u16) -> usize {
+		// the inversion swaps the pattern tables
+		let addr = if self.bank_select & 0x80 != 0 { addr ^ 0x1000 } else { addr };
+		let slot = (addr >> 10) as usize;
+		let bank = match slot {
+			0 | 1 => (self.banks[0] & !1) as usize | slot,
+			2 | 3 => (self.banks[1] & !1) as usize | (slot & 1),
+			_ => self.banks[slot - 2] as usize,
+		};
+		(bank * 0x400 + (addr as usize & 0x3FF)) % self.chr_rom.len()
+	}
+
+	fn ram_readable(&self) -> bool {
+		!self.ram.is_empty() && self.ram_protect & 0x80 != 0
+	}
+}
+
+impl fmt::Debug for Mmc3 {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Mmc3")
+			.field("irq_style", &self.irq_style)
+			.field("prg_rom_size", &self.prg_rom.len())
+			.field("chr_rom_size", &self.chr_rom.len())
+			.field("chr_ram", &self.chr_ram)
+			.field("ram_size", &self.ram.len())
+			.field("bank_select", &self.bank_select)
+			.field("banks", &self.banks)
+			.field("mirroring", &self.mirroring)
+			.field("ram_protect", &self.ram_protect)
+			.field("irq_latch", &self.irq_latch)
+			.field("irq_counter", &self.irq_counter)
+			.field("irq_reload", &self.irq_reload)
+			.field("irq_enabled", &self.irq_enabled)
+			.field("irq_pending", &self.irq_pending)
+			.finish()
+	}
+}
+
+impl Cartridge for Mmc3 {
+	fn name(&self) -> &'static str {
+		"MMC3"
+	}
+
+	fn capabilities(&self) -> u8 {
+		let ram = if self.ram.is_empty() { 0 }
+			else if self.battery { HAS_PRG_RAM | HAS_BATTERY }
+			else { HAS_PRG_RAM };
+		ram | HAS_IRQ | if self.chr_ram { HAS_CHR_RAM } else { 0 }
+	}
+
+	fn set_battery(&mut self, battery: bool) {
+		self.battery = battery;
+	}
+
+	fn read_cpu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr < 0x6000 {
+			// not mapped
+			0
+		} else if addr < 0x8000 {
+			if self.ram_readable() {
+				let index = (addr as usize - 0x6000) % self.ram.len();
+				self.ram[index]
+			} else {
+				0
+			}
+		} else {
+			self.prg_rom[self.prg_index(addr)]
+		}
+	}
+
+	fn write_cpu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr < 0x6000 {
+			// not mapped
+		} else if addr < 0x8000 {
+			if self.ram_readable() && self.ram_protect & 0x40 == 0 {
+				let index = (addr as usize - 0x6000) % self.ram.len();
+				self.ram[index] = value;
+			}
+		} else {
+			match (addr & 0xE000, addr & 1) {
+				(0x8000, 0) => self.bank_select = value,
+				(0x8000, _) => self.banks[(self.bank_select & 7) as usize] = value,
+				(0xA000, 0) => self.mirroring = value & 1,
+				(0xA000, _) => self.ram_protect = value,
+				(0xC000, 0) => self.irq_latch = value,
+				(0xC000, _) => {
+					self.irq_counter = 0;
+					self.irq_reload = true;
+				}
+				(0xE000, 0) => {
+					self.irq_enabled = false;
+					self.irq_pending = false;
+				}
+				_ => self.irq_enabled = true,
+			}
+		}
+	}
+
+	fn read_ppu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_rom[self.chr_index(addr)]
+		} else {
+			self.ppu_ram[self.mirror_mode().nametable_index(addr)]
+		}
+	}
+
+	fn write_ppu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr <= 0x3EFF);
+		if addr > 0x1FFF {
+			self.ppu_ram[self.mirror_mode().nametable_index(addr)] = value;
+		} else if self.chr_ram {
+			let index = self.chr_index(addr);
+			self.chr_rom[index] = value;
+		}
+	}
+
+	fn mirror_mode(&self) -> MirrorMode {
+		if self.four_screen {
+			MirrorMode::FourScreen
+		} else if self.mirroring == 0 {
+			MirrorMode::VerticalMirroring
+		} else {
+			MirrorMode::HorizontalMirroring
+		}
+	}
+
+	fn ppu_a12_rise(&mut self) {
+		let reload = self.irq_reload;
+		let decremented = self.irq_counter != 0 && !reload;
+		if self.irq_counter == 0 || reload {
+			self.irq_counter = self.irq_latch;
+			self.irq_reload = false;
+		} else {
+			self.irq_counter -= 1;
+		}
+		let fires = self.irq_counter == 0 && match self.irq_style {
+			Mmc3Irq::New => true,
+			Mmc3Irq::Old => decremented || reload,
+		};
+		if fires && self.irq_enabled {
+			self.irq_pending = true;
+		}
+	}
+
+	fn irq_pending(&self) -> bool {
+		self.irq_pending
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bytes(out, &self.ram)?;
+		savestate::write_u8(out, self.bank_select)?;
+		savestate::write_bytes(out, &self.banks)?;
+		savestate::write_u8(out, self.mirroring)?;
+		savestate::write_u8(out, self.ram_protect)?;
+		savestate::write_u8(out, self.irq_latch)?;
+		savestate::write_u8(out, self.irq_counter)?;
+		savestate::write_bool(out, self.irq_reload)?;
+		savestate::write_bool(out, self.irq_enabled)?;
+		savestate::write_bool(out, self.irq_pending)?;
+		if self.chr_ram {
+			savestate::write_bytes(out, &self.chr_rom)?;
+		}
+		savestate::write_bytes(out, &self.ppu_ram)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		savestate::read_bytes(input, &mut self.ram)?;
+		self.bank_select = savestate::read_u8(input)?;
+		savestate::read_bytes(input, &mut self.banks)?;
+		self.mirroring = savestate::read_u8(input)? & 1;
+		self.ram_protect = savestate::read_u8(input)?;
+		self.irq_latch = savestate::read_u8(input)?;
+		self.irq_counter = savestate::read_u8(input)?;
+		self.irq_reload = savestate::read_bool(input)?;
+		self.irq_enabled = savestate::read_bool(input)?;
+		self.irq_pending = savestate::read_bool(input)?;
+		if self.chr_ram {
+			savestate::read_bytes(input, &mut self.chr_rom)?;
+		}
+		savestate::read_bytes(input, &mut self.ppu_ram)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::Cartridge;
+	use cartridge::conformance::{check, numbered_banks, nametable_steps};
+	use cartridge::conformance::Step::*;
+
+	// 8 KiB PRG banks and 1 KiB CHR banks starting with their number
+	fn mmc3(irq_style: Mmc3Irq) -> Mmc3 {
+		Mmc3::new(numbered_banks(16, 0x2000, 0, 0), numbered_banks(64, 0x400, 0, 0), 0x2000,
+			MirrorMode::VerticalMirroring, irq_style)
+	}
+
+	// Clocks the counter like the scanlines of a frame and returns the
+	// clocks after which the IRQ was pending, acknowledging them.
+	fn irq_clocks(a: &mut Mmc3, clocks: usize) -> Vec<usize> {
+		let mut fired = Vec::new();
+		for i in 1..clocks + 1 {
+			a.ppu_a12_rise();
+			if a.irq_pending() {
+				fired.push(i);
+				a.write_cpu(0xE000, 0);
+				a.write_cpu(0xE001, 0);
+			}
+		}
+		fired
+	}
+
+	#[test]
+	fn banks() {
+		let mut a = mmc3(Mmc3Irq::New);
+		check(&mut a, &[
+			Write(0x8000, 6),
+			Write(0x8001, 3),
+			Write(0x8000, 7),
+			Write(0x8001, 5),
+			Cpu(0x8000, 3),
+			Cpu(0xA000, 5),
+			Cpu(0xC000, 14),
+			Cpu(0xE000, 15),
+			// PRG mode 1 swaps 8000 and C000
+			Write(0x8000, 0x46),
+			Cpu(0x8000, 14),
+			Cpu(0xC000, 3),
+			// 2 KiB banks ignore bit 0
+			Write(0x8000, 0),
+			Write(0x8001, 9),
+			Write(0x8000, 5),
+			Write(0x8001, 33),
+			Ppu(0x0000, 8),
+			Ppu(0x0400, 9),
+			Ppu(0x1C00, 33),
+			// CHR inversion swaps the pattern tables
+			Write(0x8000, 0x80),
+			Ppu(0x1000, 8),
+			Ppu(0x0C00, 33),
+			// registers are mirrored over each 8 KiB
+			Write(0x9FFE, 0x07),
+			Write(0x9FFF, 1),
+			Cpu(0xA000, 1),
+		]);
+	}
+
+	#[test]
+	fn ram_and_mirroring() {
+		let mut a = mmc3(Mmc3Irq::New);
+		check(&mut a, &[
+			Write(0x6001, 123),
+			Cpu(0x6001, 123),
+			// write protected
+			Write(0xA001, 0xC0),
+			Write(0x6001, 111),
+			Cpu(0x6001, 123),
+			// disabled
+			Write(0xA001, 0x00),
+			Cpu(0x6001, 0),
+			Write(0xA001, 0x80),
+			Cpu(0x6001, 123),
+			Write(0xA000, 1),
+		]);
+		check(&mut a, &nametable_steps(MirrorMode::HorizontalMirroring));
+		a.write_cpu(0xA000, 0);
+		check(&mut a, &nametable_steps(MirrorMode::VerticalMirroring));
+
+		let mut b = Mmc3::new(vec![0; 0x8000], vec![], 0x2000, MirrorMode::FourScreen, Mmc3Irq::New);
+		b.write_cpu(0xA000, 1);
+		check(&mut b, &nametable_steps(MirrorMode::FourScreen));
+	}
+
+	#[test]
+	fn irq() {
+		for &style in [Mmc3Irq::New, Mmc3Irq::Old].iter() {
+			let mut a = mmc3(style);
+			a.write_cpu(0xC000, 3);
+			a.write_cpu(0xC001, 0);
+			a.write_cpu(0xE001, 0);
+			// reloaded with 3 by the first clock, then 2, 1, 0
+			assert_eq!(vec![4, 8], irq_clocks(&mut a, 10));
+			// disabled
+			a.write_cpu(0xE000, 0);
+			assert_eq!(Vec::<usize>::new(), irq_clocks(&mut a, 10));
+		}
+
+		// the line stays asserted until acknowledged by $E000
+		let mut b = mmc3(Mmc3Irq::New);
+		check(&mut b, &[
+			Write(0xC000, 1),
+			Write(0xC001, 0),
+			Write(0xE001, 0),
+			A12Rise,
+			Irq(false),
+			A12Rise,
+			Irq(true),
+			A12Rise,
+			A12Rise,
+			Irq(true),
+			Write(0xE000, 0),
+			Irq(false),
+		]);
+	}
+
+	#[test]
+	fn irq_latch_zero() {
+		// A latch of 0 fires on every clock with the new behavior, but only
+		// after the reload by $C001 with the old one.
+		let mut a = mmc3(Mmc3Irq::New);
+		a.write_cpu(0xC000, 0);
+		a.write_cpu(0xC001, 0);
+		a.write_cpu(0xE001, 0);
+		assert_eq!(vec![1, 2, 3], irq_clocks(&mut a, 3));
+
+		let mut b = mmc3(Mmc3Irq::Old);
+		b.write_cpu(0xC000, 0);
+		b.write_cpu(0xC001, 0);
+		b.write_cpu(0xE001, 0);
+		assert_eq!(vec![1], irq_clocks(&mut b, 3));
+
+		// the reload by a write of $C001 in the middle of a count
+		b.write_cpu(0xC000, 5);
+		b.write_cpu(0xC001, 0);
+		assert_eq!(vec![6], irq_clocks(&mut b, 7));
+	}
+
+	#[test]
+	fn state() {
+		let mut a = mmc3(Mmc3Irq::Old);
+		a.write_cpu(0x8000, 2);
+		a.write_cpu(0x8001, 7);
+		a.write_cpu(0x6000, 42);
+		a.write_cpu(0xC000, 9);
+		a.ppu_a12_rise();
+		let mut state = Vec::new();
+		a.save_state(&mut state).unwrap();
+		let mut b = mmc3(Mmc3Irq::Old);
+		b.load_state(&mut &state[..]).unwrap();
+		assert_eq!(format!("{:?}", a), format!("{:?}", b));
+		assert_eq!(7, b.read_ppu(0x1000));
+		assert_eq!(42, b.read_cpu(0x6000));
+	}
+}
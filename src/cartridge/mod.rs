@@ -1,5 +1,19 @@
-mod nrom;
+pub mod nrom;
 mod mmc1;
+mod mmc3;
+mod camerica;
+mod protected_cnrom;
+mod nanjing;
+mod datach;
+mod fds;
+mod fds_audio;
+mod rom_info;
+#[cfg(test)]
+pub mod test_cartridge;
+#[cfg(test)]
+mod conformance;
 pub mod cartridge;  // TODO REMOVE RUST BUG!!!!
 
-pub use cartridge::cartridge::{Cartridge, MirrorMode, load_rom};
+pub use cartridge::cartridge::{Cartridge, MirrorMode, load_rom, load_rom_with_info, load_rom_with_submapper, supported_mappers, capability_names};
+pub use cartridge::rom_info::{RomInfo, RomDatabase};
+pub use cartridge::cartridge::{HAS_PRG_RAM, HAS_BATTERY, HAS_IRQ, HAS_EXPANSION_AUDIO, HAS_CHR_RAM};
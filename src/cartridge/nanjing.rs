@@ -0,0 +1,357 @@
+use cartridge::{Cartridge, MirrorMode, HAS_PRG_RAM, HAS_BATTERY, HAS_CHR_RAM};
+use cpu::memory_map;
+use std::fmt;
+use std::io::{self, Read, Write};
+use savestate;
+
+// The protection part of the Nanjing chip: registers the games write
+// values to and read back combined, and a flip-flop toggled through 5101.
+// The games check the values at start and in between and stop working
+// when they are wrong.
+#[derive(Debug, Clone, PartialEq)]
+struct Protection {
+	// written to 5100 and 5300
+	reg_5100: u8,
+	reg_5300: u8,
+	// Toggles when 5101 is written 0 after a value other than 0.
+	feedback: bool,
+	last_strobe: u8,
+}
+
+impl Protection {
+	fn new() -> Protection {
+		Protection {
+			reg_5100: 0,
+			reg_5300: 0,
+			feedback: false,
+			last_strobe: 0,
+		}
+	}
+
+	fn strobe(&mut self, value: u8) {
+		if self.last_strobe != 0 && value == 0 {
+			self.feedback = !self.feedback;
+		}
+		self.last_strobe = value;
+	}
+
+	// The read value of 5100 and 5500, mixed with the PRG bank registers,
+	// None for the other addresses.
+	fn read(&self, addr: u16, prg_low: u8, prg_high: u8) -> Option<u8> {
+		match addr & 0x7700 {
+			0x5100 => Some(self.reg_5300 | prg_high | prg_low | (self.reg_5100 ^ 0xFF)),
+			0x5500 if self.feedback => Some(self.reg_5300 | prg_low),
+			0x5500 => Some(0),
+			_ => None,
+		}
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.reg_5100)?;
+		savestate::write_u8(out, self.reg_5300)?;
+		savestate::write_bool(out, self.feedback)?;
+		savestate::write_u8(out, self.last_strobe)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.reg_5100 = savestate::read_u8(input)?;
+		self.reg_5300 = savestate::read_u8(input)?;
+		self.feedback = savestate::read_bool(input)?;
+		self.last_strobe = savestate::read_u8(input)?;
+		Ok(())
+	}
+}
+
+// Nanjing boards of many unlicensed Chinese games, with a chip which
+// besides the banking has a protection and switches the CHR RAM halves in
+// the middle of the screen by itself.
+// iNES mapper 163
+//   6000-7FFF  PRG RAM (8 KiB)
+//   8000-FFFF  PRG ROM (switchable 32 KiB bank)
+//   5000       write: PRG bank bits 0-3, bit 7 enables the CHR switching
+//   5200       write: PRG bank bits 4-5
+//   5100/5300  write: protection registers, 6 to 5100 maps PRG bank 3
+//   5101       write: protection flip-flop
+//   5100/5500  read: protection values
+// The registers are decoded with the mask 7300. With the CHR switching
+// enabled, both pattern tables show the lower 4 KiB of CHR RAM in the top
+// half of the screen and the upper 4 KiB in the bottom half: the chip
+// latches A9 of the nametable fetches, which tells the half of the row.
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_163
+#[derive(Clone)]
+pub struct Nanjing {
+	prg_rom: Vec<u8>,
+	prg_ram: [u8; 8192],
+	battery: bool,
+	chr_ram: [u8; 8192],
+	prg_low: u8,
+	prg_high: u8,
+	// 32 KiB bank mapped until the next bank register write, see write_cpu
+	forced_bank: Option<usize>,
+	protection: Protection,
+	// the CHR RAM half selected by the last nametable fetch
+	chr_half: usize,
+	ppu_ram: [u8; 4096],
+	mirror_mode: MirrorMode,
+}
+
+impl Nanjing {
+	pub fn new(prg_rom: Vec<u8>, mirror_mode: MirrorMode) -> Nanjing {
+		assert!(prg_rom.len().is_multiple_of(32 * 1024) && !prg_rom.is_empty());
+		Nanjing {
+			prg_rom,
+			prg_ram: [0; 8192],
+			battery: false,
+			chr_ram: [0; 8192],
+			prg_low: 0xFF,
+			prg_high: 0,
+			forced_bank: None,
+			protection: Protection::new(),
+			chr_half: 0,
+			ppu_ram: [0; 4096],
+			mirror_mode,
+		}
+	}
+
+	fn prg_bank(&self) -> usize {
+		let bank = self.forced_bank.unwrap_or(((self.prg_high as usize & 0b11) << 4) | (self.prg_low as usize & 0x0F));
+		bank % (self.prg_rom.len() / (32 * 1024))
+	}
+
+	fn chr_switching(&self) -> bool {
+		self.prg_low & 0x80 != 0
+	}
+
+	fn chr_index(&self, addr: u16) -> usize {
+		if self.chr_switching() {
+			(self.chr_half << 12) | (addr as usize & 0x0FFF)
+		} else {
+			addr as usize
+		}
+	}
+}
+
+impl fmt::Debug for Nanjing {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Nanjing")
+			.field("prg_rom_size", &self.prg_rom.len())
+			.field("prg_low", &self.prg_low)
+			.field("prg_high", &self.prg_high)
+			.field("forced_bank", &self.forced_bank)
+			.field("protection", &self.protection)
+			.field("chr_half", &self.chr_half)
+			.field("mirror_mode", &self.mirror_mode)
+			.finish()
+	}
+}
+
+impl Cartridge for Nanjing {
+	fn name(&self) -> &'static str {
+		"Nanjing"
+	}
+
+	fn capabilities(&self) -> u8 {
+		HAS_PRG_RAM | HAS_CHR_RAM | if self.battery { HAS_BATTERY } else { 0 }
+	}
+
+	fn set_battery(&mut self, battery: bool) {
+		self.battery = battery;
+	}
+
+	fn read_cpu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr < 0x5000 {
+			// not mapped
+			0
+		} else if addr < 0x6000 {
+			// the unused registers read as 4, which some games expect
+			self.protection.read(addr, self.prg_low, self.prg_high).unwrap_or(4)
+		} else if addr < 0x8000 {
+			self.prg_ram[addr as usize - 0x6000]
+		} else {
+			self.prg_rom[self.prg_bank() * 32 * 1024 + (addr as usize - 0x8000)]
+		}
+	}
+
+	fn write_cpu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if (0x6000..0x8000).contains(&addr) {
+			self.prg_ram[addr as usize - 0x6000] = value;
+			return;
+		}
+		if addr == 0x5101 {
+			self.protection.strobe(value);
+			return;
+		}
+		if addr == 0x5100 && value == 6 {
+			// the protection check of some games expects this bank
+			self.forced_bank = Some(3);
+			return;
+		}
+		match addr & 0x7300 {
+			0x5000 => {
+				self.prg_low = value;
+				self.forced_bank = None;
+			}
+			0x5100 => {
+				self.protection.reg_5100 = value;
+				self.forced_bank = None;
+			}
+			0x5200 => {
+				self.prg_high = value;
+				self.forced_bank = None;
+			}
+			0x5300 => self.protection.reg_5300 = value,
+			_ => {}
+		}
+	}
+
+	fn read_ppu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_ram[self.chr_index(addr)]
+		} else {
+			// nametable, not attribute table fetches
+			if addr & 0x3FF < 0x3C0 {
+				self.chr_half = (addr as usize >> 9) & 1;
+			}
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)]
+		}
+	}
+
+	// Without latching the CHR RAM half.
+	fn peek_ppu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			self.chr_ram[self.chr_index(addr)]
+		} else {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)]
+		}
+	}
+
+	fn write_ppu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			let index = self.chr_index(addr);
+			self.chr_ram[index] = value;
+		} else {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)] = value;
+		}
+	}
+
+	fn mirror_mode(&self) -> MirrorMode {
+		self.mirror_mode.clone()
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.prg_low)?;
+		savestate::write_u8(out, self.prg_high)?;
+		savestate::write_u8(out, self.forced_bank.map(|bank| bank as u8 + 1).unwrap_or(0))?;
+		self.protection.save_state(out)?;
+		savestate::write_u8(out, self.chr_half as u8)?;
+		savestate::write_bytes(out, &self.prg_ram)?;
+		savestate::write_bytes(out, &self.chr_ram)?;
+		savestate::write_bytes(out, &self.ppu_ram)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.prg_low = savestate::read_u8(input)?;
+		self.prg_high = savestate::read_u8(input)?;
+		self.forced_bank = match savestate::read_u8(input)? {
+			0 => None,
+			bank => Some(bank as usize - 1),
+		};
+		self.protection.load_state(input)?;
+		self.chr_half = savestate::read_u8(input)? as usize & 1;
+		savestate::read_bytes(input, &mut self.prg_ram)?;
+		savestate::read_bytes(input, &mut self.chr_ram)?;
+		savestate::read_bytes(input, &mut self.ppu_ram)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::{Cartridge, MirrorMode};
+	use cartridge::conformance::{check, nametable_steps, numbered_banks};
+	use cartridge::conformance::Step::*;
+
+	fn nanjing() -> Nanjing {
+		Nanjing::new(numbered_banks(32, 32 * 1024, 0, 0xEE), MirrorMode::VerticalMirroring)
+	}
+
+	#[test]
+	fn prg_banks() {
+		let mut a = nanjing();
+		check(&mut a, &[
+			// bank 15 after power on
+			Cpu(0x8000, 15),
+			Write(0x5000, 0x03), Cpu(0x8000, 3), Cpu(0xFFFF, 0xEE),
+			Write(0x5200, 0x01), Cpu(0x8000, 19),
+			// mirrors of the registers
+			Write(0x50FF, 0x05), Cpu(0x8000, 21),
+			Write(0x5C00, 0x02), Cpu(0x8000, 18),
+			// 6 to 5100 maps bank 3 until the next bank write
+			Write(0x5100, 0x06), Cpu(0x8000, 3),
+			Write(0x5200, 0x00), Cpu(0x8000, 2),
+			Write(0x6123, 0x42), Cpu(0x6123, 0x42),
+		]);
+		check(&mut a, &nametable_steps(MirrorMode::VerticalMirroring));
+	}
+
+	#[test]
+	fn protection() {
+		let mut a = nanjing();
+		check(&mut a, &[
+			Write(0x5000, 0x01), Write(0x5200, 0x02), Write(0x5300, 0x10), Write(0x5100, 0xF0),
+			Cpu(0x5100, 0x10 | 0x02 | 0x01 | 0x0F),
+			Cpu(0x5000, 4), Cpu(0x5200, 4),
+			// the flip-flop toggles on 0 after another value
+			Cpu(0x5500, 0),
+			Write(0x5101, 0x00), Cpu(0x5500, 0),
+			Write(0x5101, 0x01), Cpu(0x5500, 0),
+			Write(0x5101, 0x00), Cpu(0x5500, 0x11),
+			Write(0x5101, 0x00), Cpu(0x5500, 0x11),
+			Write(0x5101, 0x01), Write(0x5101, 0x00), Cpu(0x5500, 0),
+		]);
+	}
+
+	#[test]
+	fn chr_switching() {
+		let mut a = nanjing();
+		a.write_cpu(0x5000, 0x00);
+		a.write_ppu(0x0010, 1);
+		a.write_ppu(0x1010, 2);
+		check(&mut a, &[
+			// the top half of the screen shows the lower 4 KiB, the bottom
+			// half the upper 4 KiB
+			Write(0x5000, 0x80),
+			Ppu(0x2000 + 15 * 32, 0), Ppu(0x0010, 1), Ppu(0x1010, 1),
+			Ppu(0x2000 + 16 * 32, 0), Ppu(0x0010, 2), Ppu(0x1010, 2),
+			// not by attribute fetches
+			Ppu(0x23C0, 0), Ppu(0x0010, 2),
+			Ppu(0x2400, 0), Ppu(0x1010, 1),
+			Write(0x5000, 0x00), Ppu(0x0010, 1), Ppu(0x1010, 2),
+		]);
+
+		// peeking a nametable leaves the half alone
+		a.write_cpu(0x5000, 0x80);
+		assert_eq!(1, a.read_ppu(0x0010));
+		assert_eq!(0, a.peek_ppu(0x2000 + 16 * 32));
+		assert_eq!(1, a.peek_ppu(0x0010));
+		assert_eq!(0, a.read_ppu(0x2000 + 16 * 32));
+		assert_eq!(2, a.peek_ppu(0x0010));
+	}
+
+	#[test]
+	fn save_state() {
+		let mut a = nanjing();
+		check(&mut a, &[Write(0x5000, 0x81), Write(0x5100, 6), Write(0x5101, 1), Write(0x5101, 0), Ppu(0x2200, 0)]);
+		let mut state = Vec::new();
+		a.save_state(&mut state).unwrap();
+		let mut b = nanjing();
+		b.load_state(&mut &state[..]).unwrap();
+		assert_eq!(format!("{:?}", a), format!("{:?}", b));
+		check(&mut b, &[Cpu(0x8000, 3), Cpu(0x5500, 0x81), Ppu(0x0010, 0)]);
+	}
+}
@@ -1,16 +1,20 @@
-use cartridge::{Cartridge, MirrorMode};
+use cartridge::{Cartridge, MirrorMode, HAS_PRG_RAM, HAS_BATTERY};
 use cpu::memory_map;
-use std::clone::Clone;
+use std::fmt;
+use std::io::{self, Read, Write};
+use savestate;
 
 // Simple non-banking ROM with some RAM.
 // iNES mapper 000
+#[derive(Clone)]
 pub struct NRom {
 	prg_rom: Vec<u8>,
 	prg_mask: usize,
 	chr_rom: Vec<u8>,
 	ram: Vec<u8>,
 	ram_mask: usize,
-	ppu_ram: [u8; 2048],
+	battery: bool,
+	ppu_ram: [u8; 4096],
 	mirror_mode: MirrorMode,
 }
 
@@ -18,22 +22,50 @@ impl NRom {
 	// TODO validate input!!! (ram size ...)
 	pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, ram_size: usize, mirror_mode: MirrorMode) -> NRom {
 		assert!(prg_rom.len() == 16 * 1024 || prg_rom.len() == 32 * 1024);
-		assert!(ram_size % 0x400 == 0 && ram_size <= 0x2000);
+		assert!(ram_size.is_multiple_of(0x400) && ram_size <= 0x2000);
 		assert!(chr_rom.len() == 8 * 1024);
 		let prg_mask = prg_rom.len() - 1;
 		NRom {
-			prg_rom: prg_rom,
-			prg_mask: prg_mask,
-			chr_rom: chr_rom,
+			prg_rom,
+			prg_mask,
+			chr_rom,
 			ram: vec![0; ram_size],
 			ram_mask: if ram_size == 0 { 0 } else { ram_size as usize - 1 },
-			ppu_ram: [0; 2048],
-			mirror_mode: mirror_mode,
+			battery: false,
+			ppu_ram: [0; 4096],
+			mirror_mode,
 		}
 	}
 }
 
+impl fmt::Debug for NRom {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("NRom")
+			.field("prg_rom_size", &self.prg_rom.len())
+			.field("chr_rom_size", &self.chr_rom.len())
+			.field("ram_size", &self.ram.len())
+			.field("mirror_mode", &self.mirror_mode)
+			.finish()
+	}
+}
+
 impl Cartridge for NRom {
+	fn name(&self) -> &'static str {
+		"NROM"
+	}
+
+	fn capabilities(&self) -> u8 {
+		match (self.ram.is_empty(), self.battery) {
+			(true, _) => 0,
+			(false, false) => HAS_PRG_RAM,
+			(false, true) => HAS_PRG_RAM | HAS_BATTERY,
+		}
+	}
+
+	fn set_battery(&mut self, battery: bool) {
+		self.battery = battery;
+	}
+
 	fn read_cpu(&mut self, addr: u16) -> u8 {
 		debug_assert!(addr >= memory_map::CARTRIDGE_START);
 		if addr < 0x6000 {
@@ -63,32 +95,39 @@ impl Cartridge for NRom {
 		debug_assert!(addr <= 0x3EFF);
 		if addr <= 0x1FFF {
 			self.chr_rom[addr as usize]
-		} else if addr <= 0x2FFF {
-			self.ppu_ram[(addr as usize - 0x1000) & 0x7FF]
 		} else {
-			self.ppu_ram[(addr as usize - 0x2000) & 0x7FF]
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)]
 		}
 	}
 
 	fn write_ppu(&mut self, addr: u16, value: u8) {
 		debug_assert!(addr <= 0x3EFF);
-		if addr <= 0x1FFF {
-		} else if addr <= 0x2FFF {
-			self.ppu_ram[(addr as usize - 0x1000) & 0x7FF] = value;
-		} else {
-			self.ppu_ram[(addr as usize - 0x2000) & 0x7FF] = value;
+		if addr > 0x1FFF {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)] = value;
 		}
 	}
 
 	fn mirror_mode(&self) -> MirrorMode {
 		self.mirror_mode.clone()
 	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bytes(out, &self.ram)?;
+		savestate::write_bytes(out, &self.ppu_ram)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		savestate::read_bytes(input, &mut self.ram)?;
+		savestate::read_bytes(input, &mut self.ppu_ram)
+	}
 }
 
 #[cfg(test)]
 mod test {
 	use super::*;
 	use cartridge::{Cartridge, MirrorMode};
+	use cartridge::conformance::{check, nametable_steps};
+	use cartridge::conformance::Step::*;
 
 	#[test]
 	fn unmapped() {
@@ -130,48 +169,25 @@ mod test {
 		let mut rom = vec![123; 16 * 1024];
 		rom[1] = 0;
 		let mut a = NRom::new(rom, vec![0; 8 * 1024], 0, MirrorMode::HorizontalMirroring);
-		a.write_cpu(0x8001, 111);
-		assert_eq!(0, a.read_cpu(0x8001));
-		assert_eq!(123, a.read_cpu(0x8002));
-		assert_eq!(0, a.read_cpu(0xC001));
-		assert_eq!(123, a.read_cpu(0xC002));
+		check(&mut a, &[Write(0x8001, 111), Cpu(0x8001, 0), Cpu(0x8002, 123), Cpu(0xC001, 0), Cpu(0xC002, 123)]);
 
 		rom = vec![123; 32 * 1024];
 		rom[1] = 0;
 		a = NRom::new(rom, vec![0; 8 * 1024], 0, MirrorMode::HorizontalMirroring);
-		a.write_cpu(0x8001, 111);
-		assert_eq!(0, a.read_cpu(0x8001));
-		assert_eq!(123, a.read_cpu(0x8002));
-		assert_eq!(123, a.read_cpu(0xC001));
-		assert_eq!(123, a.read_cpu(0xC002));
+		check(&mut a, &[Write(0x8001, 111), Cpu(0x8001, 0), Cpu(0x8002, 123), Cpu(0xC001, 123), Cpu(0xC002, 123)]);
 	}
 
 	#[test]
 	fn ppu() {
 		let mut chr = vec![0; 8 * 1024];
 		chr[2] = 123;
-		let mut a = NRom::new(vec![123; 16 * 1024], chr, 0, MirrorMode::HorizontalMirroring);
-
-		a.write_ppu(0x0002, 42);
-		assert_eq!(123, a.read_ppu(0x0002));
-
-		a.write_ppu(0x2002, 2);
-		a.write_ppu(0x3403, 3);
-		assert_eq!(2, a.read_ppu(0x2002));
-		assert_eq!(0, a.read_ppu(0x2402));
-		assert_eq!(2, a.read_ppu(0x2802));
-		assert_eq!(0, a.read_ppu(0x2C02));
-		assert_eq!(0, a.read_ppu(0x2003));
-		assert_eq!(3, a.read_ppu(0x2403));
-		assert_eq!(0, a.read_ppu(0x2803));
-		assert_eq!(3, a.read_ppu(0x2C03));
-		assert_eq!(2, a.read_ppu(0x3002));
-		assert_eq!(0, a.read_ppu(0x3402));
-		assert_eq!(2, a.read_ppu(0x3802));
-		assert_eq!(0, a.read_ppu(0x3C02));
-		assert_eq!(0, a.read_ppu(0x3003));
-		assert_eq!(3, a.read_ppu(0x3403));
-		assert_eq!(0, a.read_ppu(0x3803));
-		assert_eq!(3, a.read_ppu(0x3C03));
+		let mut a = NRom::new(vec![123; 16 * 1024], chr, 0, MirrorMode::VerticalMirroring);
+
+		// CHR ROM is read only
+		check(&mut a, &[WritePpu(0x0002, 42), Ppu(0x0002, 123)]);
+		check(&mut a, &nametable_steps(MirrorMode::VerticalMirroring));
+
+		let mut a = NRom::new(vec![123; 16 * 1024], vec![0; 8 * 1024], 0, MirrorMode::HorizontalMirroring);
+		check(&mut a, &nametable_steps(MirrorMode::HorizontalMirroring));
 	}
 }
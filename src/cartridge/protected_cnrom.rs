@@ -0,0 +1,160 @@
+use cartridge::{Cartridge, MirrorMode};
+use cpu::memory_map;
+use std::fmt;
+use std::io::{self, Read, Write};
+use savestate;
+
+// CNROM with copy protection: Instead of switching CHR banks, the latch
+// connects or disconnects the single 8 KiB CHR ROM. Games check at start
+// that pattern reads fail while it is disconnected.
+// iNES mapper 185
+//   8000-FFFF  PRG ROM (16 or 32 KiB)
+//   8000-FFFF  write: CHR enable latch
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_185
+#[derive(Clone)]
+pub struct ProtectedCnRom {
+	prg_rom: Vec<u8>,
+	prg_mask: usize,
+	chr_rom: Vec<u8>,
+	latch: u8,
+	ppu_ram: [u8; 4096],
+	mirror_mode: MirrorMode,
+}
+
+impl ProtectedCnRom {
+	pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirror_mode: MirrorMode) -> ProtectedCnRom {
+		assert!(prg_rom.len() == 16 * 1024 || prg_rom.len() == 32 * 1024);
+		assert!(chr_rom.len() == 8 * 1024);
+		let prg_mask = prg_rom.len() - 1;
+		ProtectedCnRom {
+			prg_rom,
+			prg_mask,
+			chr_rom,
+			latch: 0,
+			ppu_ram: [0; 4096],
+			mirror_mode,
+		}
+	}
+
+	// The boards differ in which latch value enables the CHR ROM. Without
+	// an NES 2.0 submapper this is the common heuristic: any value with one
+	// of the low bits set, except 13 which Seicross writes to disable it.
+	fn chr_enabled(&self) -> bool {
+		self.latch & 0b11 != 0 && self.latch != 0x13
+	}
+}
+
+impl fmt::Debug for ProtectedCnRom {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("ProtectedCnRom")
+			.field("prg_rom_size", &self.prg_rom.len())
+			.field("chr_rom_size", &self.chr_rom.len())
+			.field("latch", &self.latch)
+			.field("mirror_mode", &self.mirror_mode)
+			.finish()
+	}
+}
+
+impl Cartridge for ProtectedCnRom {
+	fn name(&self) -> &'static str {
+		"CNROM (protected)"
+	}
+
+	fn capabilities(&self) -> u8 {
+		0
+	}
+
+	fn read_cpu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr < 0x8000 {
+			// not mapped
+			0
+		} else {
+			self.prg_rom[(addr as usize - 0x8000) & self.prg_mask]
+		}
+	}
+
+	fn write_cpu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr >= memory_map::CARTRIDGE_START);
+		if addr >= 0x8000 {
+			self.latch = value;
+		}
+	}
+
+	fn read_ppu(&mut self, addr: u16) -> u8 {
+		debug_assert!(addr <= 0x3EFF);
+		if addr <= 0x1FFF {
+			if self.chr_enabled() {
+				self.chr_rom[addr as usize]
+			} else {
+				// Open bus: The PPU multiplexes the low address byte on the
+				// data lines, so this is what it reads back.
+				addr as u8
+			}
+		} else {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)]
+		}
+	}
+
+	fn write_ppu(&mut self, addr: u16, value: u8) {
+		debug_assert!(addr <= 0x3EFF);
+		if addr > 0x1FFF {
+			self.ppu_ram[self.mirror_mode.nametable_index(addr)] = value;
+		}
+	}
+
+	fn mirror_mode(&self) -> MirrorMode {
+		self.mirror_mode.clone()
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.latch)?;
+		savestate::write_bytes(out, &self.ppu_ram)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.latch = savestate::read_u8(input)?;
+		savestate::read_bytes(input, &mut self.ppu_ram)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::{Cartridge, MirrorMode};
+	use cartridge::conformance::{check, nametable_steps};
+	use cartridge::conformance::Step::*;
+
+	#[test]
+	fn prg_rom() {
+		let mut rom = vec![0; 16 * 1024];
+		rom[1] = 1;
+		let mut a = ProtectedCnRom::new(rom, vec![0; 8 * 1024], MirrorMode::VerticalMirroring);
+		assert_eq!(1, a.read_cpu(0x8001));
+		assert_eq!(1, a.read_cpu(0xC001));
+		assert_eq!(0, a.read_cpu(0x6001));
+	}
+
+	#[test]
+	fn chr_protection() {
+		let mut a = ProtectedCnRom::new(vec![0; 32 * 1024], vec![0xAA; 8 * 1024], MirrorMode::VerticalMirroring);
+		// disabled after power on
+		assert_eq!(0x34, a.read_ppu(0x1234));
+
+		// disabled CHR reads return the low address byte
+		check(&mut a, &[
+			Write(0x8000, 0x00), Ppu(0x0056, 0x56),
+			Write(0x8000, 0x01), Ppu(0x0056, 0xAA),
+			Write(0x8000, 0x02), Ppu(0x0056, 0xAA),
+			Write(0x8000, 0x03), Ppu(0x0056, 0xAA),
+			Write(0x8000, 0x10), Ppu(0x0056, 0x56),
+			Write(0x8000, 0x11), Ppu(0x0056, 0xAA),
+			Write(0x8000, 0x13), Ppu(0x0056, 0x56),
+			Write(0x8000, 0x20), Ppu(0x0056, 0x56),
+		]);
+
+		// nametables are always connected
+		check(&mut a, &[Write(0x8000, 0)]);
+		check(&mut a, &nametable_steps(MirrorMode::VerticalMirroring));
+	}
+}
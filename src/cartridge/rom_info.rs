@@ -0,0 +1,192 @@
+use cartridge::MirrorMode;
+use checksum::{crc32, sha1, to_hex};
+use region::Region;
+use std::fs::File;
+use std::io::{self, Read};
+
+// Facts about a loaded ROM. The checksums cover PRG and CHR ROM without
+// the header, like in the No-Intro databases.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+	pub mapper: u8,
+	// From NES 2.0 headers or the database, 0 otherwise.
+	pub submapper: u8,
+	pub prg_size: usize,
+	pub chr_size: usize,
+	pub mirror_mode: MirrorMode,
+	pub crc32: u32,
+	pub sha1: [u8; 20],
+	// The TV system from the header, if it tells.
+	pub region: Option<Region>,
+	// Data after CHR ROM which the emulation does not use: the INST-ROM and
+	// PROM of PlayChoice-10 dumps, and NES 2.0 miscellaneous ROMs.
+	pub playchoice_size: usize,
+	pub misc_rom_size: usize,
+}
+
+impl RomInfo {
+	pub fn new(mapper: u8, prg_rom: &[u8], chr_rom: &[u8], mirror_mode: MirrorMode) -> RomInfo {
+		let mut data = Vec::with_capacity(prg_rom.len() + chr_rom.len());
+		data.extend_from_slice(prg_rom);
+		data.extend_from_slice(chr_rom);
+		RomInfo {
+			mapper,
+			submapper: 0,
+			prg_size: prg_rom.len(),
+			chr_size: chr_rom.len(),
+			mirror_mode,
+			crc32: crc32(&data),
+			sha1: sha1(&data),
+			region: None,
+			playchoice_size: 0,
+			misc_rom_size: 0,
+		}
+	}
+
+	// Stable identifier of the game, e.g. for save files.
+	pub fn sha1_hex(&self) -> String {
+		to_hex(&self.sha1)
+	}
+}
+
+struct DatabaseEntry {
+	title: String,
+	crc32: Option<u32>,
+	sha1: Option<String>,
+	submapper: Option<u8>,
+}
+
+// Game titles from a No-Intro style DAT file (clrmamepro XML), e.g.
+//   <game name="Title (USA)"><rom name="..." crc="1234ABCD" sha1="..."/></game>
+// A game can have a submapper attribute, like in the pcb tags of the NES 2.0
+// database, for the boards which an iNES header can not tell apart, e.g.
+//   <game name="..."><rom .../><pcb mapper="4" submapper="4"/></game>
+pub struct RomDatabase {
+	entries: Vec<DatabaseEntry>,
+}
+
+impl RomDatabase {
+	pub fn load(path: &str) -> io::Result<RomDatabase> {
+		let mut text = String::new();
+		File::open(path)?.read_to_string(&mut text)?;
+		Ok(RomDatabase::parse(&text))
+	}
+
+	// Reads all games with a name and at least one checksum, ignoring
+	// everything else.
+	pub fn parse(text: &str) -> RomDatabase {
+		let mut entries = Vec::new();
+		// each part starts with the attributes of a game tag
+		for game in text.split("<game").skip(1) {
+			let end = game.find("</game>").unwrap_or(game.len());
+			let game = &game[..end];
+			let title = match attribute(game, "name") {
+				Some(title) => unescape(title),
+				None => continue,
+			};
+			let crc32 = attribute(game, "crc").and_then(|crc| u32::from_str_radix(crc, 16).ok());
+			let sha1 = attribute(game, "sha1").map(|sha1| sha1.to_lowercase());
+			let submapper = attribute(game, "submapper").and_then(|submapper| submapper.parse().ok());
+			if crc32.is_some() || sha1.is_some() {
+				entries.push(DatabaseEntry { title, crc32, sha1, submapper });
+			}
+		}
+		RomDatabase { entries }
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.entries.is_empty()
+	}
+
+	// Returns the title of the ROM. SHA-1 is preferred over CRC32 if the
+	// database has it.
+	pub fn find(&self, info: &RomInfo) -> Option<&str> {
+		self.entry(info).map(|entry| entry.title.as_ref())
+	}
+
+	// Returns the submapper of the ROM, if the database has one.
+	pub fn submapper(&self, info: &RomInfo) -> Option<u8> {
+		self.entry(info).and_then(|entry| entry.submapper)
+	}
+
+	fn entry(&self, info: &RomInfo) -> Option<&DatabaseEntry> {
+		let sha1 = info.sha1_hex();
+		self.entries.iter().find(|entry| match entry.sha1 {
+			Some(ref entry_sha1) => *entry_sha1 == sha1,
+			None => entry.crc32 == Some(info.crc32),
+		})
+	}
+}
+
+// Value of the first attribute with this name.
+fn attribute<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+	let pattern = format!(" {}=\"", name);
+	let start = match text.find(&pattern) {
+		Some(i) => i + pattern.len(),
+		None => return None,
+	};
+	text[start..].find('"').map(|end| &text[start..start + end])
+}
+
+fn unescape(text: &str) -> String {
+	text.replace("&quot;", "\"").replace("&apos;", "'")
+		.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::MirrorMode;
+
+	const DAT: &str = r#"<?xml version="1.0"?>
+<datafile>
+	<header><name>Nintendo - NES</name></header>
+	<game name="Zero (USA)">
+		<description>Zero (USA)</description>
+		<rom name="Zero (USA).nes" size="16" crc="ECBB4B55" sha1="0000000000000000000000000000000000000000"/>
+	</game>
+	<game name="Tom &amp; Jerry (USA)">
+		<rom name="Tom &amp; Jerry (USA).nes" size="32" crc="190A55AD"/>
+	</game>
+	<game name="Mapper Four (USA)">
+		<rom name="Mapper Four (USA).nes" size="8" crc="6522DF69"/>
+		<pcb mapper="4" submapper="4"/>
+	</game>
+	<game name="Broken"></game>
+</datafile>
+"#;
+
+	#[test]
+	fn info() {
+		let info = RomInfo::new(0, b"abc", b"", MirrorMode::VerticalMirroring);
+		assert_eq!(3, info.prg_size);
+		assert_eq!(0x352441C2, info.crc32);
+		assert_eq!("a9993e364706816aba3e25717850c26c9cd0d89d", info.sha1_hex());
+	}
+
+	#[test]
+	fn database() {
+		let database = RomDatabase::parse(DAT);
+		assert_eq!(3, database.len());
+		assert!(!database.is_empty());
+		assert!(RomDatabase::parse("<datafile></datafile>").is_empty());
+
+		// CRC32 matches, but SHA-1 does not
+		let short = RomInfo::new(0, &[0; 16], &[], MirrorMode::VerticalMirroring);
+		assert_eq!(0xECBB4B55, short.crc32);
+		assert_eq!(None, database.find(&short));
+
+		let long = RomInfo::new(0, &[0; 16], &[0; 16], MirrorMode::VerticalMirroring);
+		assert_eq!(0x190A55AD, long.crc32);
+		assert_eq!(Some("Tom & Jerry (USA)"), database.find(&long));
+		assert_eq!(None, database.submapper(&long));
+
+		let mmc3 = RomInfo::new(4, &[0; 8], &[], MirrorMode::VerticalMirroring);
+		assert_eq!(0x6522DF69, mmc3.crc32);
+		assert_eq!(Some(4), database.submapper(&mmc3));
+	}
+}
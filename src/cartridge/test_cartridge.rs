@@ -0,0 +1,102 @@
+use cartridge::MirrorMode;
+use cartridge::nrom::NRom;
+
+// Builds NROM cartridges from memory, so tests do not depend on ROM files.
+//
+// By default there is 32 KiB PRG ROM filled with NOPs, empty CHR ROM,
+// 8 KiB PRG RAM, horizontal mirroring and all vectors pointing to 8000.
+pub struct TestCartridge;
+
+impl TestCartridge {
+	pub fn builder() -> TestCartridgeBuilder {
+		TestCartridgeBuilder {
+			prg_rom: vec![0xEA; 32 * 1024],
+			chr_rom: vec![0; 8 * 1024],
+			ram_size: 8 * 1024,
+			mirror_mode: MirrorMode::HorizontalMirroring,
+		}
+		.nmi_vector(0x8000)
+		.reset_vector(0x8000)
+		.irq_vector(0x8000)
+	}
+}
+
+pub struct TestCartridgeBuilder {
+	prg_rom: Vec<u8>,
+	chr_rom: Vec<u8>,
+	ram_size: usize,
+	mirror_mode: MirrorMode,
+}
+
+impl TestCartridgeBuilder {
+	// Places data at the given CPU address (8000-FFFF).
+	pub fn prg(mut self, addr: u16, data: &[u8]) -> TestCartridgeBuilder {
+		assert!(addr >= 0x8000 && addr as usize + data.len() <= 0x10000);
+		let start = addr as usize - 0x8000;
+		self.prg_rom[start..start + data.len()].copy_from_slice(data);
+		self
+	}
+
+	// Places data at the given PPU address (0000-1FFF).
+	pub fn chr(mut self, addr: u16, data: &[u8]) -> TestCartridgeBuilder {
+		assert!(addr as usize + data.len() <= 0x2000);
+		let start = addr as usize;
+		self.chr_rom[start..start + data.len()].copy_from_slice(data);
+		self
+	}
+
+	pub fn nmi_vector(self, addr: u16) -> TestCartridgeBuilder {
+		self.prg(0xFFFA, &[addr as u8, (addr >> 8) as u8])
+	}
+
+	pub fn reset_vector(self, addr: u16) -> TestCartridgeBuilder {
+		self.prg(0xFFFC, &[addr as u8, (addr >> 8) as u8])
+	}
+
+	pub fn irq_vector(self, addr: u16) -> TestCartridgeBuilder {
+		self.prg(0xFFFE, &[addr as u8, (addr >> 8) as u8])
+	}
+
+	pub fn ram_size(mut self, ram_size: usize) -> TestCartridgeBuilder {
+		self.ram_size = ram_size;
+		self
+	}
+
+	pub fn mirror_mode(mut self, mirror_mode: MirrorMode) -> TestCartridgeBuilder {
+		self.mirror_mode = mirror_mode;
+		self
+	}
+
+	pub fn build(self) -> NRom {
+		NRom::new(self.prg_rom, self.chr_rom, self.ram_size, self.mirror_mode)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::{Cartridge, MirrorMode};
+
+	#[test]
+	fn builder() {
+		let mut a = TestCartridge::builder()
+			.prg(0x8000, &[1, 2])
+			.prg(0xC000, &[3])
+			.chr(0x1000, &[4])
+			.reset_vector(0xC000)
+			.mirror_mode(MirrorMode::VerticalMirroring)
+			.ram_size(0x1000)
+			.build();
+		assert_eq!(1, a.read_cpu(0x8000));
+		assert_eq!(2, a.read_cpu(0x8001));
+		assert_eq!(0xEA, a.read_cpu(0x8002));
+		assert_eq!(3, a.read_cpu(0xC000));
+		assert_eq!(4, a.read_ppu(0x1000));
+		assert_eq!(0x00, a.read_cpu(0xFFFC));
+		assert_eq!(0xC0, a.read_cpu(0xFFFD));
+		assert_eq!(0x80, a.read_cpu(0xFFFB));
+		assert_eq!(MirrorMode::VerticalMirroring, a.mirror_mode());
+		a.write_cpu(0x6001, 5);
+		assert_eq!(5, a.read_cpu(0x7001));
+	}
+}
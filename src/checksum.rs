@@ -0,0 +1,91 @@
+// Checksums to identify ROMs.
+
+// CRC-32 as used by zip and the ROM databases.
+pub fn crc32(data: &[u8]) -> u32 {
+	let mut crc = !0u32;
+	for &byte in data {
+		crc ^= byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+		}
+	}
+	!crc
+}
+
+// SHA-1, see FIPS 180-4.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+	let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+	let mut message = data.to_vec();
+	message.push(0x80);
+	while message.len() % 64 != 56 {
+		message.push(0);
+	}
+	let bits = (data.len() as u64).wrapping_mul(8);
+	for i in 0..8 {
+		message.push((bits >> (56 - i * 8)) as u8);
+	}
+
+	for chunk in message.chunks(64) {
+		let mut w = [0u32; 80];
+		for i in 0..16 {
+			w[i] = ((chunk[i * 4] as u32) << 24) | ((chunk[i * 4 + 1] as u32) << 16) |
+			       ((chunk[i * 4 + 2] as u32) << 8) | (chunk[i * 4 + 3] as u32);
+		}
+		for i in 16..80 {
+			w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+		}
+
+		let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+		for i in 0..80 {
+			let (f, k) =
+				if i < 20      { ((b & c) | (!b & d), 0x5A827999) }
+				else if i < 40 { (b ^ c ^ d, 0x6ED9EBA1) }
+				else if i < 60 { ((b & c) | (b & d) | (c & d), 0x8F1BBCDC) }
+				else           { (b ^ c ^ d, 0xCA62C1D6) };
+			let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w[i]);
+			e = d;
+			d = c;
+			c = b.rotate_left(30);
+			b = a;
+			a = temp;
+		}
+		h[0] = h[0].wrapping_add(a);
+		h[1] = h[1].wrapping_add(b);
+		h[2] = h[2].wrapping_add(c);
+		h[3] = h[3].wrapping_add(d);
+		h[4] = h[4].wrapping_add(e);
+	}
+
+	let mut digest = [0; 20];
+	for i in 0..5 {
+		for j in 0..4 {
+			digest[i * 4 + j] = (h[i] >> (24 - j * 8)) as u8;
+		}
+	}
+	digest
+}
+
+// Lower case hexadecimal representation, as used in the databases.
+pub fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn crc() {
+		assert_eq!(0, crc32(b""));
+		assert_eq!(0xCBF43926, crc32(b"123456789"));
+	}
+
+	#[test]
+	fn sha() {
+		assert_eq!("da39a3ee5e6b4b0d3255bfef95601890afd80709", to_hex(&sha1(b"")));
+		assert_eq!("a9993e364706816aba3e25717850c26c9cd0d89d", to_hex(&sha1(b"abc")));
+		assert_eq!("84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+			to_hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")));
+	}
+}
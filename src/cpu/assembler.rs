@@ -0,0 +1,171 @@
+use cpu::instructions::{OPCODE_INFO, AddressingMode};
+
+// A tiny 6502 assembler for tests and patching.
+//
+// Takes instructions separated by newlines or ';' and returns the machine
+// code, assuming it is placed at origin. Supported operand syntax:
+//   (none), A, #$12, $12, $1234, $12,X, $1234,Y, ($1234), ($12,X), ($12),Y
+// Numbers are hexadecimal with '$' and decimal otherwise. Branches take
+// the absolute target address. Labels are not supported.
+pub fn assemble(origin: u16, source: &str) -> Result<Vec<u8>, String> {
+	let mut code = Vec::new();
+	for line in source.split([';', '\n']) {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		let address = origin.wrapping_add(code.len() as u16);
+		let bytes = assemble_instruction(address, line)?;
+		code.extend(bytes);
+	}
+	Ok(code)
+}
+
+fn assemble_instruction(address: u16, line: &str) -> Result<Vec<u8>, String> {
+	let (mnemonic, operand) = match line.find(char::is_whitespace) {
+		Some(i) => (&line[..i], line[i..].trim()),
+		None => (line, ""),
+	};
+	let mnemonic = mnemonic.to_uppercase();
+	let operand = operand.to_uppercase().replace(" ", "");
+
+	let (modes, value) = parse_operand(&operand)?;
+	for mode in modes {
+		if let Some(opcode) = find_opcode(&mnemonic, mode) {
+			let mut bytes = vec![opcode];
+			match mode {
+				AddressingMode::Implied | AddressingMode::Accumulator => {}
+				AddressingMode::Relative => {
+					let offset = value as i32 - (address as i32 + 2);
+					if !(-128..=127).contains(&offset) {
+						return Err(format!("Branch target out of range: {}", line));
+					}
+					bytes.push(offset as u8);
+				}
+				_ => {
+					let size = OPCODE_INFO[opcode as usize].size;
+					if size == 2 && value > 0xFF {
+						return Err(format!("Operand out of range: {}", line));
+					}
+					bytes.push(value as u8);
+					if size == 3 {
+						bytes.push((value >> 8) as u8);
+					}
+				}
+			}
+			return Ok(bytes);
+		}
+	}
+	Err(format!("Invalid instruction: {}", line))
+}
+
+// Returns the candidate addressing modes in order of preference and the operand value.
+fn parse_operand(operand: &str) -> Result<(Vec<AddressingMode>, u16), String> {
+	if operand.is_empty() {
+		return Ok((vec![AddressingMode::Implied, AddressingMode::Accumulator], 0));
+	}
+	if operand == "A" {
+		return Ok((vec![AddressingMode::Accumulator], 0));
+	}
+	if operand.starts_with('#') {
+		let value = parse_number(&operand[1..])?;
+		return Ok((vec![AddressingMode::Immediate], value));
+	}
+	if operand.starts_with('(') {
+		if operand.ends_with(",X)") {
+			let value = parse_number(&operand[1..operand.len() - 3])?;
+			return Ok((vec![AddressingMode::IndirectX], value));
+		} else if operand.ends_with("),Y") {
+			let value = parse_number(&operand[1..operand.len() - 3])?;
+			return Ok((vec![AddressingMode::IndirectY], value));
+		} else if operand.ends_with(')') {
+			let value = parse_number(&operand[1..operand.len() - 1])?;
+			return Ok((vec![AddressingMode::Indirect], value));
+		}
+		return Err(format!("Invalid operand: {}", operand));
+	}
+
+	let (number, zero_page, absolute) =
+		if operand.ends_with(",X") {
+			(&operand[..operand.len() - 2], AddressingMode::ZeroPageX, AddressingMode::AbsoluteX)
+		} else if operand.ends_with(",Y") {
+			(&operand[..operand.len() - 2], AddressingMode::ZeroPageY, AddressingMode::AbsoluteY)
+		} else {
+			(operand, AddressingMode::ZeroPage, AddressingMode::Absolute)
+		};
+	let value = parse_number(number)?;
+	let short = value <= 0xFF && !(number.starts_with('$') && number.len() > 3);
+	if short {
+		Ok((vec![zero_page, absolute, AddressingMode::Relative], value))
+	} else {
+		Ok((vec![absolute, AddressingMode::Relative], value))
+	}
+}
+
+fn parse_number(number: &str) -> Result<u16, String> {
+	let result =
+		if number.starts_with('$') {
+			u16::from_str_radix(&number[1..], 16)
+		} else {
+			number.parse::<u16>()
+		};
+	result.map_err(|_| format!("Invalid number: {}", number))
+}
+
+// Finds the opcode for the given instruction, preferring official ones.
+fn find_opcode(mnemonic: &str, mode: AddressingMode) -> Option<u8> {
+	let mut result = None;
+	for (opcode, info) in OPCODE_INFO.iter().enumerate() {
+		if info.mnemonic == mnemonic && info.mode == mode {
+			if info.official {
+				return Some(opcode as u8);
+			} else if result.is_none() {
+				result = Some(opcode as u8);
+			}
+		}
+	}
+	result
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn modes() {
+		assert_eq!(Ok(vec![0xEA]), assemble(0, "NOP"));
+		assert_eq!(Ok(vec![0x0A, 0x0A]), assemble(0, "ASL; asl a"));
+		assert_eq!(Ok(vec![0xA9, 0x01]), assemble(0, "LDA #$01"));
+		assert_eq!(Ok(vec![0xA9, 10]), assemble(0, "LDA #10"));
+		assert_eq!(Ok(vec![0xA5, 0x12]), assemble(0, "LDA $12"));
+		assert_eq!(Ok(vec![0xAD, 0x12, 0x00]), assemble(0, "LDA $0012"));
+		assert_eq!(Ok(vec![0x8D, 0x00, 0x20]), assemble(0, "STA $2000"));
+		assert_eq!(Ok(vec![0xB5, 0x12]), assemble(0, "LDA $12,X"));
+		assert_eq!(Ok(vec![0xB6, 0x12]), assemble(0, "LDX $12,Y"));
+		assert_eq!(Ok(vec![0xBD, 0x34, 0x12]), assemble(0, "LDA $1234,X"));
+		assert_eq!(Ok(vec![0x99, 0x12, 0x00]), assemble(0, "STA $12,Y"));
+		assert_eq!(Ok(vec![0x6C, 0x34, 0x12]), assemble(0, "JMP ($1234)"));
+		assert_eq!(Ok(vec![0xA1, 0x12]), assemble(0, "LDA ($12,X)"));
+		assert_eq!(Ok(vec![0xB1, 0x12]), assemble(0, "LDA ($12),Y"));
+		assert_eq!(Ok(vec![0xE9, 0x01]), assemble(0, "SBC #$01"));
+	}
+
+	#[test]
+	fn branches() {
+		assert_eq!(Ok(vec![0xD0, 0xFE]), assemble(0x8000, "BNE $8000"));
+		assert_eq!(Ok(vec![0xEA, 0xF0, 0x10]), assemble(0x8000, "NOP\nBEQ $8013"));
+		assert!(assemble(0x8000, "BNE $9000").is_err());
+	}
+
+	#[test]
+	fn errors() {
+		assert!(assemble(0, "FOO").is_err());
+		assert!(assemble(0, "LDA #$XY").is_err());
+		assert!(assemble(0, "JMP #$01").is_err());
+		// one byte operands
+		assert_eq!(Err(String::from("Operand out of range: LDA #$1234")), assemble(0, "LDA #$1234"));
+		assert!(assemble(0, "LDA ($1234,X)").is_err());
+		assert!(assemble(0, "LDA ($0100),Y").is_err());
+		assert!(assemble(0, "LDA #256").is_err());
+	}
+}
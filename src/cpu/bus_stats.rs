@@ -0,0 +1,79 @@
+use cpu::memory_map;
+use cpu::watch::AccessSource;
+
+// Parts of the CPU address space, by the hardware which answers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusRegion {
+	// 0000-1FFF
+	Ram,
+	// 2000-3FFF
+	PpuRegisters,
+	// 4000-401F
+	ApuIo,
+	// 4020-FFFF
+	Cartridge,
+}
+
+pub const BUS_REGIONS: [BusRegion; 4] =
+	[BusRegion::Ram, BusRegion::PpuRegisters, BusRegion::ApuIo, BusRegion::Cartridge];
+
+impl BusRegion {
+	pub fn of(addr: u16) -> BusRegion {
+		if addr < memory_map::PPU_START {
+			BusRegion::Ram
+		} else if addr < memory_map::APU_IO_START {
+			BusRegion::PpuRegisters
+		} else if addr < memory_map::CARTRIDGE_START {
+			BusRegion::ApuIo
+		} else {
+			BusRegion::Cartridge
+		}
+	}
+}
+
+// Numbers of CPU bus accesses, reads and writes, by source and region. The
+// accesses to the PPU registers and the cartridge are the ones which need
+// the other components to be caught up to the CPU.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BusStats {
+	counts: [[u64; 4]; 5],
+}
+
+impl BusStats {
+	pub fn new() -> BusStats {
+		BusStats {
+			counts: [[0; 4]; 5],
+		}
+	}
+
+	pub fn record(&mut self, source: AccessSource, addr: u16) {
+		self.counts[source as usize][BusRegion::of(addr) as usize] += 1;
+	}
+
+	pub fn count(&self, source: AccessSource, region: BusRegion) -> u64 {
+		self.counts[source as usize][region as usize]
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn counts() {
+		let mut stats = BusStats::new();
+		stats.record(AccessSource::Fetch, 0x8000);
+		stats.record(AccessSource::Fetch, 0xC123);
+		stats.record(AccessSource::Data, 0x2002);
+		stats.record(AccessSource::Data, 0x0801);
+		stats.record(AccessSource::Stack, 0x01FD);
+		stats.record(AccessSource::Data, 0x4016);
+		stats.record(AccessSource::Data, 0x4020);
+		assert_eq!(2, stats.count(AccessSource::Fetch, BusRegion::Cartridge));
+		assert_eq!(1, stats.count(AccessSource::Data, BusRegion::Cartridge));
+		assert_eq!(1, stats.count(AccessSource::Data, BusRegion::Ram));
+		assert_eq!(1, stats.count(AccessSource::Stack, BusRegion::Ram));
+		assert_eq!(1, stats.count(AccessSource::Data, BusRegion::PpuRegisters));
+		assert_eq!(1, stats.count(AccessSource::Data, BusRegion::ApuIo));
+	}
+}
@@ -1,21 +1,40 @@
 use cpu::memory_map;
+use cpu::watch::{AccessSource, Watchpoint, WatchHit};
+use cpu::bus_stats::BusStats;
 use cartridge::Cartridge;
-use cpu::instructions::{INSTRUCTION_SIZES, INSTRUCTIONS};
-use std::io::Write;
+use cpu::instructions::{OPCODE_INFO, INSTRUCTIONS, OpcodeInfo, AddressingMode};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::collections::HashMap;
 use ppu::Ppu;
-use apu::Apu;
+use apu::{self, Apu};
+use input::{self, Input};
+use savestate;
+use io_registers;
+use dma;
+use prng::Prng;
 
 // Tuple to pass the whole hardware to the CPU.
 pub struct Hardware<'a> {
 	pub apu: &'a mut Apu,
+	pub input: &'a mut Input,
 	pub ppu: &'a mut Ppu,
-	pub cartridge: &'a mut Cartridge
+	pub cartridge: &'a mut dyn Cartridge
 }
 
+// Native code which runs instead of the 6502 code at a trap address, for
+// high-level emulation of BIOS routines and player shims, and for hooks of
+// test harnesses. It gets the CPU with the registers as the 6502 code would
+// have them and returns the number of cycles it took. PC still points to
+// the trap address, so the handler has to move it on, e.g. with
+// Cpu::return_from_subroutine for a routine called by JSR.
+pub type TrapHandler = Box<dyn FnMut(&mut Cpu, &mut Hardware) -> u32 + Send>;
+
 // Start of the stack
 pub const STACK_START: u16 = 0x0100;
 
 // Status register
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Status {
 	pub carry: bool,
 	pub zero: bool,
@@ -57,7 +76,26 @@ impl Status {
 	}
 }
 
+// The flags from the byte pushed by PHP or an interrupt, bits 4 and 5 are
+// ignored.
+impl From<u8> for Status {
+	fn from(value: u8) -> Status {
+		let mut p = Status::new();
+		p.set_value(value);
+		p
+	}
+}
+
+// The byte as read by PLA after PHP without the break flag, i.e. bit 5 set
+// and bit 4 clear.
+impl From<Status> for u8 {
+	fn from(p: Status) -> u8 {
+		p.value(false)
+	}
+}
+
 // Register file of the CPU.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Registers {
 	pub a: u8,
 	pub x: u8,
@@ -81,11 +119,20 @@ impl Registers {
 	}
 }
 
+// Snapshot of everything a program can observe of the CPU itself, to
+// inspect or replace it at once, e.g. in tests and debuggers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpuState {
+	pub registers: Registers,
+	// see Cpu::jam
+	pub jammed: bool,
+}
+
 // CPU of the NES.
 //
 // The memory map is as follows:
 // * 0000 - 07FF is RAM
-// * 0800 - 1FFF mirrors RAM
+// * 0800 - 1FFF mirrors RAM, famiclones can have more RAM there
 // * 2000 - 2007 are PPU registers
 // * 2008 - 3FFF mirrors PPU registers
 // * 4000 - 401F are APU and IO registers
@@ -94,41 +141,187 @@ pub struct Cpu {
 	registers: Registers,
 	opcode8: u8,
 	opcode16: u16,
-	ram: [u8; memory_map::RAM_SIZE as usize],
+	// memory_map::RAM_SIZE bytes, or more on a famiclone
+	ram: Vec<u8>,
+	// Explain PPU, APU and IO register accesses in the instruction log.
+	annotate_io: bool,
+	// A KIL instruction stopped the CPU until the next reset.
+	jammed: bool,
+	// Accesses to the PPU registers and controller ports, for the watchdog.
+	io_accesses: u64,
+	// What the current bus accesses are for, and the instruction doing them.
+	access_source: AccessSource,
+	instruction_pc: u16,
+	watchpoints: Vec<Watchpoint>,
+	watch_hit: Option<WatchHit>,
+	bus_stats: BusStats,
+	// The last value read or written, which reads of bits nothing drives
+	// return, see input::OPEN_BUS_BITS.
+	data_bus: u8,
+	// The I flag from before the last instruction if it was CLI, SEI or
+	// PLP, as the interrupt polling sees it, see irq_enabled.
+	delayed_interrupt_flag: Option<bool>,
+	// The page of the last write to dma::OAM_DMA, see take_oam_dma.
+	oam_dma_page: Option<u8>,
+	// The APU or the cartridge were written to, see take_device_write.
+	device_write: bool,
+	// See set_dummy_reads.
+	dummy_reads: bool,
+	traps: HashMap<u16, TrapHandler>,
 }
 
 impl Cpu {
 	pub fn new() -> Cpu {
+		Cpu::with_ram_size(memory_map::RAM_SIZE)
+	}
+
+	// A CPU with more or less internal RAM, a power of two up to
+	// memory_map::RAM_AREA_SIZE bytes.
+	pub fn with_ram_size(size: u16) -> Cpu {
+		assert!(size.is_power_of_two() && size <= memory_map::RAM_AREA_SIZE);
 		Cpu {
 			registers: Registers::new(),
 			opcode8: 0,
 			opcode16: 0,
-			ram: [0; memory_map::RAM_SIZE as usize],
+			ram: vec![0; size as usize],
+			annotate_io: false,
+			jammed: false,
+			io_accesses: 0,
+			access_source: AccessSource::Fetch,
+			instruction_pc: 0,
+			watchpoints: Vec::new(),
+			watch_hit: None,
+			bus_stats: BusStats::new(),
+			data_bus: 0,
+			delayed_interrupt_flag: None,
+			oam_dma_page: None,
+			device_write: false,
+			dummy_reads: true,
+			traps: HashMap::new(),
 		}
 	}
 
 	pub fn jump_to_start(&mut self, hw: &mut Hardware) {
+		self.access_source = AccessSource::Vector;
 		let addr_lo = self.read_memory(hw, 0xFFFC) as u16;
 		let addr_hi = self.read_memory(hw, 0xFFFD) as u16;
 		self.registers.pc = (addr_hi << 8) | addr_lo;
 	}
 
+	// Writes the registers and the RAM for a save state.
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u8(out, self.registers.a)?;
+		savestate::write_u8(out, self.registers.x)?;
+		savestate::write_u8(out, self.registers.y)?;
+		savestate::write_u16(out, self.registers.pc)?;
+		savestate::write_u8(out, self.registers.s)?;
+		savestate::write_u8(out, u8::from(self.registers.p))?;
+		savestate::write_u8(out, self.opcode8)?;
+		savestate::write_u16(out, self.opcode16)?;
+		savestate::write_u16(out, self.ram.len() as u16)?;
+		savestate::write_bytes(out, &self.ram)
+	}
+
+	// Restores the state written by save_state.
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.registers.a = savestate::read_u8(input)?;
+		self.registers.x = savestate::read_u8(input)?;
+		self.registers.y = savestate::read_u8(input)?;
+		self.registers.pc = savestate::read_u16(input)?;
+		self.registers.s = savestate::read_u8(input)?;
+		self.registers.p = Status::from(savestate::read_u8(input)?);
+		self.opcode8 = savestate::read_u8(input)?;
+		self.opcode16 = savestate::read_u16(input)?;
+		if savestate::read_u16(input)? as usize != self.ram.len() {
+			return savestate::invalid_state("The state is of a machine with another RAM size.");
+		}
+		savestate::read_bytes(input, &mut self.ram)
+	}
+
+	// Reset button: Like an interrupt without the stack writes.
+	pub fn reset(&mut self, hw: &mut Hardware) {
+		self.jammed = false;
+		self.registers.s = self.registers.s.wrapping_sub(3);
+		self.registers.p.interrupt = true;
+		self.jump_to_start(hw);
+	}
+
 	pub fn jump_to_interrupt(&mut self, hw: &mut Hardware, break_flag: bool) {
+		self.interrupt(hw, 0xFFFE, break_flag);
+	}
+
+	// Non-maskable interrupt, as raised by the PPU at the start of vblank.
+	// Returns the number of cycles taken.
+	pub fn nmi(&mut self, hw: &mut Hardware) -> u32 {
+		self.instruction_pc = self.registers.pc;
+		self.interrupt(hw, 0xFFFA, false);
+		7
+	}
+
+	// Maskable interrupt, as raised by the IRQ line of the cartridge, see
+	// irq_enabled. Returns the number of cycles taken.
+	pub fn irq(&mut self, hw: &mut Hardware) -> u32 {
+		self.instruction_pc = self.registers.pc;
+		self.interrupt(hw, 0xFFFE, false);
+		7
+	}
+
+	// Whether an asserted IRQ line is taken before the next instruction.
+	// The polling happens before CLI, SEI and PLP change the I flag, so an
+	// IRQ is taken only after the instruction following CLI, and still
+	// right after SEI. RTI changes it at once.
+	pub fn irq_enabled(&self) -> bool {
+		!self.delayed_interrupt_flag.unwrap_or(self.registers.p.interrupt)
+	}
+
+	// Pushes PC and P and continues at the address stored at vector.
+	fn interrupt(&mut self, hw: &mut Hardware, vector: u16, break_flag: bool) {
 		let mut sp = self.registers.s;
 		let old_pc = self.registers.pc;
 		let old_p = self.registers.p.value(break_flag);
-		self.write_memory(hw, STACK_START + sp as u16, (old_pc >> 8) as u8);
+		self.write_stack(hw, sp, (old_pc >> 8) as u8);
 		sp = sp.wrapping_sub(1);
-		self.write_memory(hw, STACK_START + sp as u16, old_pc as u8);
+		self.write_stack(hw, sp, old_pc as u8);
 		sp = sp.wrapping_sub(1);
-		self.write_memory(hw, STACK_START + sp as u16, old_p);
+		self.write_stack(hw, sp, old_p);
 		sp = sp.wrapping_sub(1);
 
-		let addr_lo = self.read_memory(hw, 0xFFFE) as u16;
-		let addr_hi = self.read_memory(hw, 0xFFFF) as u16;
+		self.access_source = AccessSource::Vector;
+		let addr_lo = self.read_memory(hw, vector) as u16;
+		let addr_hi = self.read_memory(hw, vector + 1) as u16;
 		self.registers.pc = (addr_hi << 8) | addr_lo;
 		self.registers.p.interrupt = true;
 		self.registers.s = sp;
+		self.delayed_interrupt_flag = None;
+	}
+
+	// Stops the CPU, see OpKIL.
+	pub fn jam(&mut self) {
+		self.jammed = true;
+	}
+
+	// Makes the extra bus accesses of indexed addressing and of
+	// read-modify-write instructions, which games rarely notice but which
+	// have side effects on registers like $2007 and on some mappers.
+	// Without them the instructions only access their operand. Not part of
+	// save states.
+	pub fn set_dummy_reads(&mut self, enabled: bool) {
+		self.dummy_reads = enabled;
+	}
+
+	pub fn dummy_reads(&self) -> bool {
+		self.dummy_reads
+	}
+
+	pub fn jammed(&self) -> bool {
+		self.jammed
+	}
+
+	// Number of reads and writes of the PPU registers and controller ports
+	// so far. Every game does them regularly, the watchdog takes their
+	// absence as a sign of a crash.
+	pub fn io_accesses(&self) -> u64 {
+		self.io_accesses
 	}
 
 	pub fn registers_mut(&mut self) -> &mut Registers {
@@ -139,32 +332,234 @@ impl Cpu {
 		&self.registers
 	}
 
+	pub fn state(&self) -> CpuState {
+		CpuState {
+			registers: self.registers,
+			jammed: self.jammed,
+		}
+	}
+
+	pub fn set_state(&mut self, state: CpuState) {
+		self.registers = state.registers;
+		self.jammed = state.jammed;
+	}
+
+	// Stops at the first access which matches one of the watchpoints, see
+	// take_watch_hit.
+	pub fn set_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) {
+		self.watchpoints = watchpoints;
+	}
+
+	pub fn watchpoints(&self) -> &[Watchpoint] {
+		&self.watchpoints
+	}
+
+	// Runs the handler instead of the instruction whenever PC reaches the
+	// address, see TrapHandler. Replaces a trap at the same address.
+	pub fn set_trap(&mut self, address: u16, handler: TrapHandler) {
+		self.traps.insert(address, handler);
+	}
+
+	pub fn remove_trap(&mut self, address: u16) {
+		self.traps.remove(&address);
+	}
+
+	// All traps, e.g. to keep them over a power cycle.
+	pub fn take_traps(&mut self) -> HashMap<u16, TrapHandler> {
+		std::mem::take(&mut self.traps)
+	}
+
+	pub fn set_traps(&mut self, traps: HashMap<u16, TrapHandler>) {
+		self.traps = traps;
+	}
+
+	// Does what RTS does, for trap handlers of subroutines.
+	pub fn return_from_subroutine(&mut self, hw: &mut Hardware) {
+		INSTRUCTIONS[0x60].execute(self, hw);
+	}
+
+	// Returns the access which matched a watchpoint since the last call.
+	pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+		self.watch_hit.take()
+	}
+
+	// The page the last instruction started OAM DMA of. The console runs
+	// the transfer, see Dma.
+	pub fn take_oam_dma(&mut self) -> Option<u8> {
+		self.oam_dma_page.take()
+	}
+
+	// Whether the APU or the cartridge were written to since the last
+	// call, which may move their next events, see Nes::run_until_event.
+	pub fn take_device_write(&mut self) -> bool {
+		mem::replace(&mut self.device_write, false)
+	}
+
+	// Accesses of the DMA unit, which uses the bus of the CPU.
+	pub fn dma_read(&mut self, hw: &mut Hardware, address: u16) -> u8 {
+		let source = mem::replace(&mut self.access_source, AccessSource::Dma);
+		let value = self.read_memory(hw, address);
+		self.access_source = source;
+		value
+	}
+
+	pub fn dma_write(&mut self, hw: &mut Hardware, address: u16, value: u8) {
+		let source = mem::replace(&mut self.access_source, AccessSource::Dma);
+		self.write_memory(hw, address, value);
+		self.access_source = source;
+	}
+
+	fn check_watchpoints(&mut self, address: u16, value: u8, write: bool) {
+		let source = self.access_source;
+		if self.watch_hit.is_none() && self.watchpoints.iter().any(|watchpoint| watchpoint.matches(address, write, source)) {
+			self.watch_hit = Some(WatchHit {
+				addr: address,
+				value,
+				write,
+				source,
+				pc: self.instruction_pc,
+			});
+		}
+	}
+
+	// Returns the bus accesses since the last call.
+	pub fn take_bus_stats(&mut self) -> BusStats {
+		mem::replace(&mut self.bus_stats, BusStats::new())
+	}
+
+	// Pushes and pulls at the stack pointer sp, tagged as stack accesses.
+	pub fn write_stack(&mut self, hw: &mut Hardware, sp: u8, value: u8) {
+		let source = mem::replace(&mut self.access_source, AccessSource::Stack);
+		self.write_memory(hw, STACK_START + sp as u16, value);
+		self.access_source = source;
+	}
+
+	pub fn read_stack(&mut self, hw: &mut Hardware, sp: u8) -> u8 {
+		let source = mem::replace(&mut self.access_source, AccessSource::Stack);
+		let value = self.read_memory(hw, STACK_START + sp as u16);
+		self.access_source = source;
+		value
+	}
+
 	pub fn write_memory(&mut self, hw: &mut Hardware, address: u16, value: u8) {
+		self.bus_stats.record(self.access_source, address);
+		self.data_bus = value;
+		if !self.watchpoints.is_empty() {
+			self.check_watchpoints(address, value, true);
+		}
 		if address < memory_map::PPU_START {
-			self.ram[(address & (memory_map::RAM_SIZE - 1)) as usize] = value;
+			let index = self.ram_index(address);
+			self.ram[index] = value;
 		} else if address < memory_map::APU_IO_START {
+			self.io_accesses += 1;
 			hw.ppu.write(hw.cartridge, address, value);
 		} else if address < memory_map::CARTRIDGE_START {
-			// TODO
+			match address {
+				input::PORT_1 => {
+					self.io_accesses += 1;
+					hw.input.write(value);
+				}
+				dma::OAM_DMA => self.oam_dma_page = Some(value),
+				_ => {
+					self.device_write = true;
+					hw.apu.write(address, value);
+				}
+			}
 		} else {
+			self.device_write = true;
 			hw.cartridge.write_cpu(address, value);
 		}
 	}
 
-	pub fn read_memory(&self, hw: &mut Hardware, address: u16) -> u8 {
+	pub fn read_memory(&mut self, hw: &mut Hardware, address: u16) -> u8 {
+		self.bus_stats.record(self.access_source, address);
+		let value = self.read_bus(hw, address);
+		// $4015 is inside the CPU, its reads leave the data bus alone
+		if address != apu::STATUS {
+			self.data_bus = value;
+		}
+		if !self.watchpoints.is_empty() {
+			self.check_watchpoints(address, value, false);
+		}
+		value
+	}
+
+	fn read_bus(&mut self, hw: &mut Hardware, address: u16) -> u8 {
 		if address < memory_map::PPU_START {
-			self.ram[(address & (memory_map::RAM_SIZE - 1)) as usize]
+			self.ram[self.ram_index(address)]
 		} else if address < memory_map::APU_IO_START {
+			self.io_accesses += 1;
 			hw.ppu.read(hw.cartridge, address)
 		} else if address < memory_map::CARTRIDGE_START {
-			// TODO
-			//hw.apu.read(address)
-			0
+			match address {
+				input::PORT_1 | input::PORT_2 => {
+					self.io_accesses += 1;
+					hw.input.sense_light(hw.ppu);
+					(self.data_bus & input::OPEN_BUS_BITS) | hw.input.read(address)
+				}
+				apu::STATUS => {
+					self.io_accesses += 1;
+					(self.data_bus & apu::STATUS_OPEN_BUS_BITS) | hw.apu.read_status()
+				}
+				// The other APU registers, including the unused $4009 and
+				// $400D, are write only, and $4018-$401F are disabled test
+				// registers, so nothing drives the bus.
+				_ => self.data_bus,
+			}
 		} else {
 			hw.cartridge.read_cpu(address)
 		}
 	}
 
+	// Returns what read_memory would, without side effects on the hardware.
+	pub fn peek_memory(&self, hw: &mut Hardware, address: u16) -> u8 {
+		if address < memory_map::PPU_START {
+			self.ram[self.ram_index(address)]
+		} else if address < memory_map::APU_IO_START {
+			hw.ppu.peek(hw.cartridge, address)
+		} else if address < memory_map::CARTRIDGE_START {
+			match address {
+				input::PORT_1 | input::PORT_2 => {
+					// as if read by an absolute load, which leaves the
+					// high byte of the address on the bus
+					hw.input.sense_light(hw.ppu);
+					(address >> 8) as u8 & input::OPEN_BUS_BITS | hw.input.peek(address)
+				}
+				// the high byte of the address is $40, so bit 5 is clear
+				apu::STATUS => hw.apu.peek_status(),
+				_ => (address >> 8) as u8,
+			}
+		} else {
+			hw.cartridge.peek_cpu(address)
+		}
+	}
+
+	// Fills the internal RAM with random values, like the uninitialized
+	// RAM of a console after power on.
+	pub fn randomize_ram(&mut self, prng: &mut Prng) {
+		prng.fill(&mut self.ram);
+	}
+
+	// Reads from the internal RAM, addr is mirrored like on the bus.
+	pub fn peek_ram(&self, addr: u16) -> u8 {
+		self.ram[self.ram_index(addr)]
+	}
+
+	pub fn ram_size(&self) -> u16 {
+		self.ram.len() as u16
+	}
+
+	fn ram_index(&self, address: u16) -> usize {
+		address as usize & (self.ram.len() - 1)
+	}
+
+	// Appends e.g. "; write $2001 PPUMASK: BG on, sprites on" to logged
+	// instructions which access a register. Off by default, the log then
+	// matches the nestest log.
+	pub fn set_annotate_io(&mut self, annotate: bool) {
+		self.annotate_io = annotate;
+	}
+
 	// Returns the value of the last 2 byte opcode.
 	pub fn opcode8(&self) -> u8 {
 		self.opcode8
@@ -175,16 +570,180 @@ impl Cpu {
 		self.opcode16
 	}
 
-	// One CPU tick.
-	pub fn tick(&mut self, hw: &mut Hardware, instr_log: &mut Option<&mut Write>) {
+	// Effective address and memory contents for the operand of the decoded
+	// instruction, formatted like in the nestest log, e.g. " @ 0301 = 89".
+	// Read with peek_memory before executing, so stores show the old value.
+	fn annotation(&self, hw: &mut Hardware, info: &OpcodeInfo) -> String {
+		let x = self.registers.x;
+		let y = self.registers.y;
+		let zero_page_word = |cpu: &Cpu, hw: &mut Hardware, addr: u8| {
+			let lo = cpu.peek_memory(hw, addr as u16) as u16;
+			let hi = cpu.peek_memory(hw, addr.wrapping_add(1) as u16) as u16;
+			(hi << 8) | lo
+		};
+		match info.mode {
+			AddressingMode::ZeroPage => {
+				format!(" = {:02X}", self.peek_memory(hw, self.opcode8 as u16))
+			}
+			AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+				let addr = self.opcode8.wrapping_add(if info.mode == AddressingMode::ZeroPageX { x } else { y });
+				format!(" @ {:02X} = {:02X}", addr, self.peek_memory(hw, addr as u16))
+			}
+			AddressingMode::Absolute if info.mnemonic != "JMP" && info.mnemonic != "JSR" => {
+				format!(" = {:02X}", self.peek_memory(hw, self.opcode16))
+			}
+			AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+				let offset = if info.mode == AddressingMode::AbsoluteX { x } else { y };
+				let addr = self.opcode16.wrapping_add(offset as u16);
+				format!(" @ {:04X} = {:02X}", addr, self.peek_memory(hw, addr))
+			}
+			AddressingMode::Indirect => {
+				// the pointer does not cross pages
+				let lo = self.peek_memory(hw, self.opcode16) as u16;
+				let hi_addr = (self.opcode16 & 0xFF00) | (self.opcode16.wrapping_add(1) & 0x00FF);
+				let hi = self.peek_memory(hw, hi_addr) as u16;
+				format!(" = {:04X}", (hi << 8) | lo)
+			}
+			AddressingMode::IndirectX => {
+				let pointer = self.opcode8.wrapping_add(x);
+				let addr = zero_page_word(self, hw, pointer);
+				format!(" @ {:02X} = {:04X} = {:02X}", pointer, addr, self.peek_memory(hw, addr))
+			}
+			AddressingMode::IndirectY => {
+				let base = zero_page_word(self, hw, self.opcode8);
+				let addr = base.wrapping_add(y as u16);
+				format!(" = {:04X} @ {:04X} = {:02X}", base, addr, self.peek_memory(hw, addr))
+			}
+			_ => String::new(),
+		}
+	}
+
+	// Describes the access of the decoded instruction to a PPU, APU or IO
+	// register, e.g. "  ; write $2001 PPUMASK: BG on, sprites on". Empty if
+	// there is none. Reads show the value peek_memory returns.
+	fn io_annotation(&self, hw: &mut Hardware, info: &OpcodeInfo) -> String {
+		let addr = match info.mode {
+			AddressingMode::Absolute if info.mnemonic != "JMP" && info.mnemonic != "JSR" => self.opcode16,
+			AddressingMode::AbsoluteX => self.opcode16.wrapping_add(self.registers.x as u16),
+			AddressingMode::AbsoluteY => self.opcode16.wrapping_add(self.registers.y as u16),
+			AddressingMode::IndirectX => {
+				let pointer = self.opcode8.wrapping_add(self.registers.x);
+				let lo = self.peek_ram(pointer as u16) as u16;
+				let hi = self.peek_ram(pointer.wrapping_add(1) as u16) as u16;
+				(hi << 8) | lo
+			}
+			AddressingMode::IndirectY => {
+				let lo = self.peek_ram(self.opcode8 as u16) as u16;
+				let hi = self.peek_ram(self.opcode8.wrapping_add(1) as u16) as u16;
+				((hi << 8) | lo).wrapping_add(self.registers.y as u16)
+			}
+			_ => return String::new(),
+		};
+		let register = match io_registers::describe_register(addr) {
+			Some(register) => register,
+			None => return String::new(),
+		};
+		let stored = match info.mnemonic {
+			"STA" => Some(self.registers.a),
+			"STX" => Some(self.registers.x),
+			"STY" => Some(self.registers.y),
+			"SAX" => Some(self.registers.a & self.registers.x),
+			_ => None,
+		};
+		match stored {
+			Some(value) => format!("  ; write ${:04X} {}", addr, register.describe_write(value)),
+			None => format!("  ; read ${:04X} {}", addr, register.describe_read(self.peek_memory(hw, addr))),
+		}
+	}
+
+	// Returns the number of cycles the next instruction takes, without
+	// executing it.
+	pub fn next_instruction_cycles(&self, hw: &mut Hardware) -> u32 {
+		// a trap handler accesses the bus right away
+		if self.traps.contains_key(&self.registers.pc) {
+			return 1;
+		}
+		let pc = self.registers.pc;
+		let opcode = self.peek_memory(hw, pc);
+		let info = &OPCODE_INFO[opcode as usize];
+		let operand = match info.size {
+			2 => self.peek_memory(hw, pc.wrapping_add(1)) as u16,
+			3 => (self.peek_memory(hw, pc.wrapping_add(2)) as u16) << 8 | self.peek_memory(hw, pc.wrapping_add(1)) as u16,
+			_ => 0,
+		};
+		info.cycles as u32 + self.penalty_cycles(opcode, operand, pc.wrapping_add(info.size as u16))
+	}
+
+	// The cycles on top of OPCODE_INFO with the registers before the
+	// instruction: one for indexed reads crossing a page, one for taken
+	// branches and another one if the branch crosses a page. Stores and
+	// read-modify-write instructions always take the extra cycle of the
+	// indexed modes, it is in their base cycles, which tells them apart.
+	fn penalty_cycles(&self, opcode: u8, operand: u16, next_pc: u16) -> u32 {
+		let info = &OPCODE_INFO[opcode as usize];
+		let crosses = |base: u16, offset: u16| base & 0xFF00 != base.wrapping_add(offset) & 0xFF00;
+		let crossed = match info.mode {
+			AddressingMode::AbsoluteX if info.cycles == 4 => crosses(operand, self.registers.x as u16),
+			AddressingMode::AbsoluteY if info.cycles == 4 => crosses(operand, self.registers.y as u16),
+			AddressingMode::IndirectY if info.cycles == 5 => {
+				let lo = self.peek_ram(operand & 0xFF) as u16;
+				let hi = self.peek_ram((operand as u8).wrapping_add(1) as u16) as u16;
+				crosses(hi << 8 | lo, self.registers.y as u16)
+			}
+			AddressingMode::Relative if self.branch_taken(opcode) => {
+				return 1 + crosses(next_pc, operand as u8 as i8 as u16) as u32;
+			}
+			_ => false,
+		};
+		crossed as u32
+	}
+
+	// Branches are xxy10000, xx selects N, V, C or Z and y the value the
+	// flag needs for the branch.
+	fn branch_taken(&self, opcode: u8) -> bool {
+		let p = &self.registers.p;
+		let flag = match opcode >> 6 {
+			0 => p.negative,
+			1 => p.overflow,
+			2 => p.carry,
+			_ => p.zero,
+		};
+		flag == (opcode & 0x20 != 0)
+	}
+
+	// Runs the handler of the trap at PC instead of fetching an instruction.
+	// It is out of the map while it runs, as it gets the whole CPU.
+	fn run_trap(&mut self, hw: &mut Hardware, instr_log: &mut Option<&mut dyn Write>, mut handler: TrapHandler) -> u32 {
+		let pc = self.registers.pc;
+		if let &mut Some(ref mut fp) = instr_log {
+			let _ = writeln!(fp, "{:04X}  trap", pc);
+		}
+		self.access_source = AccessSource::Data;
+		let cycles = handler(self, hw);
+		// unless the handler set another one at its address
+		self.traps.entry(pc).or_insert(handler);
+		self.delayed_interrupt_flag = None;
+		cycles
+	}
+
+	// Executes one instruction and returns the number of cycles it took.
+	pub fn tick(&mut self, hw: &mut Hardware, instr_log: &mut Option<&mut dyn Write>) -> u32 {
 		// fetch PC
 		let mut pc = self.registers.pc;
+		self.instruction_pc = pc;
+		// no hashing for every instruction without traps
+		let trap = if self.traps.is_empty() { None } else { self.traps.remove(&pc) };
+		if let Some(handler) = trap {
+			return self.run_trap(hw, instr_log, handler);
+		}
+		self.access_source = AccessSource::Fetch;
 
 		// decode
 		let mut opcode = [0, 0, 0];
 		opcode[0] = self.read_memory(hw, pc);
 		pc = pc.wrapping_add(1);
-		let opcode_size = INSTRUCTION_SIZES[opcode[0] as usize];
+		let info = &OPCODE_INFO[opcode[0] as usize];
+		let opcode_size = info.size;
 		match opcode_size {
 			1 => {}
 			2 => {
@@ -205,10 +764,10 @@ impl Cpu {
 
 		// log
 		if let &mut Some(ref mut fp) = instr_log {
-			let asm_str = instruction.asm_str(self);
+			let asm_str = instruction.asm_str(self) + &self.annotation(hw, info);
 			let _ = writeln!(
 				fp,
-				"{:04X}  {:-8}  {:-30}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+				"{:04X}  {:-8} {}{:-30}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}{}",
 				self.registers.pc,
 				match opcode_size {
 					1 => { format!("{:02X}", opcode[0]) }
@@ -216,16 +775,277 @@ impl Cpu {
 					3 => { format!("{:02X} {:02X} {:02X}", opcode[0], opcode[1], opcode[2]) }
 					_ => { unreachable!() }
 				},
+				if info.official { ' ' } else { '*' },
 				asm_str,
 				self.registers.a,
 				self.registers.x,
 				self.registers.y,
 				self.registers.p.value(false),
-				self.registers.s);
+				self.registers.s,
+				if self.annotate_io { self.io_annotation(hw, info) } else { String::new() });
 		}
 
+		let operand = if opcode_size == 3 { self.opcode16 } else { opcode[1] as u16 };
+		let penalty = self.penalty_cycles(opcode[0], operand, pc);
+
 		// execute
 		self.registers.pc = pc;
+		self.access_source = AccessSource::Data;
+		let interrupt = self.registers.p.interrupt;
 		instruction.execute(self, hw);
+		// CLI, SEI, PLP
+		self.delayed_interrupt_flag = match opcode[0] {
+			0x58 | 0x78 | 0x28 => Some(interrupt),
+			_ => None,
+		};
+		info.cycles as u32 + penalty
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::test_cartridge::{TestCartridge, TestCartridgeBuilder};
+	use cpu::assembler::assemble;
+
+	#[test]
+	fn status_byte() {
+		let p = Status::from(0xFF);
+		assert!(p.carry && p.zero && p.interrupt && p.decimal && p.overflow && p.negative);
+		assert_eq!(0xEF, u8::from(p));
+		assert_eq!(0x24, u8::from(Status::from(0x04)));
+		assert_eq!(0xFF, p.value(true));
+		assert_eq!(Status::from(0xC3), Status::from(u8::from(Status::from(0xC3))));
+	}
+
+	#[test]
+	fn state() {
+		let mut cpu = Cpu::new();
+		let mut state = cpu.state();
+		state.registers.a = 0x42;
+		state.registers.pc = 0xC000;
+		state.registers.p = Status::from(0x81);
+		state.jammed = true;
+		cpu.set_state(state);
+		assert_eq!(state, cpu.state());
+		assert_eq!(0xC000, cpu.registers().pc);
+		assert!(cpu.jammed());
+	}
+
+	#[test]
+	fn controller_open_bus() {
+		let mut cartridge = TestCartridge::builder().build();
+		let mut input = Input::new();
+		input.set_buttons(0, input::BUTTON_A);
+		let mut hw = Hardware {
+			ppu: &mut Ppu::new(),
+			apu: &mut Apu::new(),
+			input: &mut input,
+			cartridge: &mut cartridge,
+		};
+		let mut cpu = Cpu::new();
+		cpu.write_memory(&mut hw, input::PORT_1, 1);
+		// the upper bits keep what was last on the data bus
+		cpu.write_memory(&mut hw, 0x0000, 0xA5);
+		cpu.read_memory(&mut hw, 0x0000);
+		assert_eq!(0xA1, cpu.read_memory(&mut hw, input::PORT_1));
+		// the value read is on the bus now, so the bits stay
+		assert_eq!(0xA1, cpu.read_memory(&mut hw, input::PORT_1));
+		// writes drive the bus as well
+		cpu.write_memory(&mut hw, 0x0000, 0x00);
+		assert_eq!(0x01, cpu.read_memory(&mut hw, input::PORT_1));
+		assert_eq!(0x00, cpu.read_memory(&mut hw, input::PORT_2));
+		// a peek assumes an absolute load, which leaves $40 on the bus
+		assert_eq!(0x41, cpu.peek_memory(&mut hw, input::PORT_1));
+	}
+
+	#[test]
+	fn dummy_accesses() {
+		// the index crosses from $4016 to $4116, and INC writes OAMDATA twice
+		let code = "LDA #$01; STA $4016; LDA #$00; STA $4016; \
+			LDX #$26; LDA $40F0,X; LDA $4016; INC $2004";
+		for &dummy_reads in &[true, false] {
+			let mut cartridge = TestCartridge::builder().prg(0x8000, &assemble(0x8000, code).unwrap()).build();
+			let mut ppu = Ppu::new();
+			ppu.poke_oam(0, 5);
+			ppu.poke_oam(1, 0x77);
+			let mut input = Input::new();
+			input.set_buttons(0, input::BUTTON_A);
+			let mut cpu = Cpu::new();
+			cpu.set_dummy_reads(dummy_reads);
+			{
+				let mut hw = Hardware {
+					ppu: &mut ppu,
+					apu: &mut Apu::new(),
+					input: &mut input,
+					cartridge: &mut cartridge,
+				};
+				cpu.reset(&mut hw);
+				for _ in 0..8 {
+					cpu.tick(&mut hw, &mut None);
+				}
+			}
+			if dummy_reads {
+				// the dummy read took A, the load gets B
+				assert_eq!(0x40, cpu.registers().a);
+				assert_eq!((5, 6), (ppu.peek_oam(0), ppu.peek_oam(1)));
+			} else {
+				assert_eq!(0x41, cpu.registers().a);
+				assert_eq!((6, 0x77), (ppu.peek_oam(0), ppu.peek_oam(1)));
+			}
+		}
+	}
+
+	// The cycles of the next instructions of the code, as predicted and as
+	// taken. The code starts at $8000 and may have parts elsewhere.
+	fn instruction_cycles(parts: &[(u16, &str)], instructions: usize) -> Vec<(u32, u32)> {
+		let mut cartridge = code(parts).build();
+		let mut hw = Hardware {
+			ppu: &mut Ppu::new(),
+			apu: &mut Apu::new(),
+			input: &mut Input::new(),
+			cartridge: &mut cartridge,
+		};
+		let mut cpu = Cpu::new();
+		cpu.reset(&mut hw);
+		(0..instructions).map(|_| {
+			let predicted = cpu.next_instruction_cycles(&mut hw);
+			(predicted, cpu.tick(&mut hw, &mut None))
+		}).collect()
+	}
+
+	fn assert_cycles(expected: &[u32], cycles: &[(u32, u32)]) {
+		let taken: Vec<u32> = cycles.iter().map(|&(_, taken)| taken).collect();
+		assert_eq!(expected.to_vec(), taken);
+		let predicted: Vec<u32> = cycles.iter().map(|&(predicted, _)| predicted).collect();
+		assert_eq!(expected.to_vec(), predicted);
+	}
+
+	#[test]
+	fn page_crossing_cycles() {
+		// reads within and across pages, the pointer at $00 is $02F0, and a
+		// store which always takes 5
+		let code = "LDX #$10; LDY #$20; LDA $0200,X; LDA $02F8,X; LDA $02F0,Y; \
+			LDA #$F0; STA $00; LDA #$02; STA $01; LDA ($00),Y; LDA $0200,Y; STA $02F8,X";
+		let cycles = instruction_cycles(&[(0x8000, code)], 12);
+		assert_cycles(&[2, 2, 4, 5, 5, 2, 3, 2, 3, 6, 4, 5], &cycles);
+	}
+
+	#[test]
+	fn branch_cycles() {
+		// not taken, taken within the page, and taken from $80FA to the
+		// next page
+		let cycles = instruction_cycles(&[
+			(0x8000, "LDA #$00; BNE $8000; BEQ $8006; JMP $80FA"),
+			(0x80FA, "BEQ $8110"),
+			(0x8110, "BNE $8100"),
+		], 6);
+		assert_cycles(&[2, 2, 3, 3, 4, 2], &cycles);
+	}
+
+	// The instruction groups of blargg's instruction test ROMs, see main.rs,
+	// which need only a few instructions also run from a test cartridge.
+	// The code starts at $8000 and may have parts elsewhere.
+	fn code(parts: &[(u16, &str)]) -> TestCartridgeBuilder {
+		let mut builder = TestCartridge::builder();
+		for &(addr, source) in parts {
+			builder = builder.prg(addr, &assemble(addr, source).unwrap());
+		}
+		builder
+	}
+
+	// Runs the instructions from the reset vector and returns the CPU and the
+	// zero page, where the code leaves its results.
+	fn run_code(builder: TestCartridgeBuilder, instructions: usize) -> (Cpu, Vec<u8>) {
+		let mut cartridge = builder.build();
+		let mut hardware = Hardware {
+			ppu: &mut Ppu::new(),
+			apu: &mut Apu::new(),
+			input: &mut Input::new(),
+			cartridge: &mut cartridge,
+		};
+		let mut cpu = Cpu::new();
+		cpu.reset(&mut hardware);
+		for _ in 0..instructions {
+			cpu.tick(&mut hardware, &mut None);
+		}
+		let zero_page = (0..0x100).map(|addr| cpu.peek_memory(&mut hardware, addr)).collect();
+		(cpu, zero_page)
+	}
+
+	#[test]
+	fn immediate() {
+		// the status pushed after an overflowing ADC and a borrowing SBC
+		let (cpu, ram) = run_code(code(&[(0x8000, "\
+			LDA #$7F; CLC; ADC #$01; STA $00; PHP; PLA; STA $01; \
+			LDA #$00; SEC; SBC #$01; STA $02; PHP; PLA; STA $03; \
+			LDA #$F0; AND #$3C; EOR #$FF; ORA #$01; STA $04")]), 19);
+		assert_eq!(&[0x80, 0xF4, 0xFF, 0xB4, 0xCF], &ram[..5]);
+		assert!(cpu.registers().p.negative);
+	}
+
+	#[test]
+	fn zero_page_indexed() {
+		// the index wraps within the zero page
+		let (_, ram) = run_code(code(&[(0x8000, "\
+			LDA #$11; STA $05; LDX #$06; LDA $FF,X; STA $10; \
+			LDA #$22; STA $FF,X; LDY #$10; LDX $F5,Y; STX $11")]), 10);
+		assert_eq!(0x22, ram[0x05]);
+		assert_eq!(&[0x11, 0x22], &ram[0x10..0x12]);
+	}
+
+	#[test]
+	fn absolute_indexed() {
+		// the index carries into the high byte, and wraps past $FFFF
+		let (_, ram) = run_code(code(&[(0x8000, "\
+			LDA #$33; STA $0305; LDX #$F6; LDA $020F,X; STA $00; \
+			LDY #$10; LDA #$44; STA $02F5,Y; LDA $0305; STA $01; \
+			LDA #$55; STA $E6; LDA $FFF0,X; STA $02")]), 14);
+		assert_eq!(&[0x33, 0x44, 0x55], &ram[..3]);
+	}
+
+	#[test]
+	fn indirect() {
+		// the pointer at $FF takes its high byte from $00
+		let (_, ram) = run_code(code(&[(0x8000, "\
+			LDA #$34; STA $FF; LDA #$03; STA $00; \
+			LDA #$66; STA $0334; LDA #$77; STA $0344; \
+			LDX #$00; LDA ($FF,X); STA $10; LDX #$80; LDA ($7F,X); STA $11; \
+			LDY #$10; LDA ($FF),Y; STA $12")]), 17);
+		assert_eq!(&[0x66, 0x66, 0x77], &ram[0x10..0x13]);
+	}
+
+	#[test]
+	fn stack() {
+		// the stack pointer wraps within page 1
+		let (_, ram) = run_code(code(&[(0x8000, "\
+			LDX #$00; TXS; LDA #$42; PHA; TSX; STX $00; \
+			LDA $0100; STA $01; PLA; PLA; TSX; STX $02")]), 12);
+		assert_eq!(&[0xFF, 0x42, 0x01], &ram[..3]);
+	}
+
+	#[test]
+	fn jmp_jsr_rts() {
+		// JSR pushes the address of its last byte, and JMP ($02FF) takes
+		// the high byte of the target from $0200
+		let (_, ram) = run_code(code(&[
+			(0x8000, "\
+				LDA #$00; STA $02FF; LDA #$A0; STA $0200; LDA #$B0; STA $0300; \
+				JSR $9000; STA $01; JMP ($02FF)"),
+			(0x9000, "TSX; STX $00; LDA $01F9; STA $02; LDA #$5A; RTS"),
+			(0xA000, "LDA #$01; STA $03"),
+		]), 17);
+		assert_eq!(&[0xF8, 0x5A, 0x11, 0x01], &ram[..4]);
+	}
+
+	#[test]
+	fn brk_rti() {
+		// BRK pushes the status with B set and skips the byte after it,
+		// RTI restores both
+		let (_, ram) = run_code(code(&[
+			(0x8000, "SEC; BRK; INX; STX $01; PHP; PLA; STA $03"),
+			(0x9000, "TSX; STX $00; PLA; PHA; STA $02; LDX #$07; RTI"),
+		]).irq_vector(0x9000), 13);
+		assert_eq!(&[0xF7, 0x07, 0x35, 0x35], &ram[..4]);
 	}
 }
@@ -0,0 +1,54 @@
+use cpu::instructions::{OPCODE_INFO, AddressingMode};
+
+// Disassembles the instruction at address, given its first bytes, in the
+// syntax of the assembler. Returns the text and the size of the
+// instruction. Undocumented opcodes are marked with a '*' like in the trace.
+pub fn disassemble(address: u16, bytes: [u8; 3]) -> (String, u8) {
+	let info = &OPCODE_INFO[bytes[0] as usize];
+	let byte = bytes[1];
+	let word = (bytes[2] as u16) << 8 | bytes[1] as u16;
+	let operand = match info.mode {
+		AddressingMode::Implied => String::new(),
+		AddressingMode::Accumulator => String::from("A"),
+		AddressingMode::Immediate => format!("#${:02X}", byte),
+		AddressingMode::ZeroPage => format!("${:02X}", byte),
+		AddressingMode::ZeroPageX => format!("${:02X},X", byte),
+		AddressingMode::ZeroPageY => format!("${:02X},Y", byte),
+		AddressingMode::Absolute => format!("${:04X}", word),
+		AddressingMode::AbsoluteX => format!("${:04X},X", word),
+		AddressingMode::AbsoluteY => format!("${:04X},Y", word),
+		AddressingMode::Indirect => format!("(${:04X})", word),
+		AddressingMode::IndirectX => format!("(${:02X},X)", byte),
+		AddressingMode::IndirectY => format!("(${:02X}),Y", byte),
+		AddressingMode::Relative => {
+			let target = address.wrapping_add(2).wrapping_add(byte as i8 as u16);
+			format!("${:04X}", target)
+		}
+	};
+	let mark = if info.official { "" } else { "*" };
+	let text = if operand.is_empty() {
+		format!("{}{}", mark, info.mnemonic)
+	} else {
+		format!("{}{} {}", mark, info.mnemonic, operand)
+	};
+	(text, info.size)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+
+	#[test]
+	fn round_trip() {
+		let source = ["LDA #$01", "STA $2000", "LDA $12,X", "LDX $12,Y", "LDA $1234,Y",
+			"JMP ($1234)", "LDA ($12,X)", "LDA ($12),Y", "ASL A", "BNE $8000", "RTS"];
+		for line in source.iter() {
+			let code = assemble(0x8010, line).unwrap();
+			let mut bytes = [0; 3];
+			bytes[..code.len()].copy_from_slice(&code);
+			assert_eq!((String::from(*line), code.len() as u8), disassemble(0x8010, bytes));
+		}
+		assert_eq!((String::from("*NOP"), 1), disassemble(0, [0x1A, 0, 0]));
+	}
+}
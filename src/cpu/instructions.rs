@@ -1,4 +1,4 @@
-use cpu::cpu::{Cpu, Hardware, STACK_START};
+use cpu::cpu::{Cpu, Hardware};
 use std::marker::PhantomData;
 use std::io::Write;
 
@@ -7,6 +7,36 @@ trait AddrMode {
 	fn read(&self, cpu: &mut Cpu, hw: &mut Hardware) -> u8;
 	fn write(&self, cpu: &mut Cpu, hw: &mut Hardware, value: u8);
 	fn asm_str(cpu: &Cpu) -> String;
+	// The read of a read-modify-write instruction.
+	fn read_to_modify(&self, cpu: &mut Cpu, hw: &mut Hardware) -> u8 {
+		self.read(cpu, hw)
+	}
+	// The write of a read-modify-write instruction, which writes the value
+	// it read once more before the result. Mappers and registers see both.
+	fn write_modified(&self, cpu: &mut Cpu, hw: &mut Hardware, original: u8, value: u8) {
+		if cpu.dummy_reads() {
+			self.write(cpu, hw, original);
+		}
+		self.write(cpu, hw, value);
+	}
+}
+
+// The read an indexed access makes while it adds the carry to the high
+// byte, from the address without it. Reads make it only if the index
+// crosses a page, stores and read-modify-writes always.
+fn dummy_read(cpu: &mut Cpu, hw: &mut Hardware, uncarried: u16, addr: u16, always: bool) {
+	if cpu.dummy_reads() && (always || uncarried != addr) {
+		cpu.read_memory(hw, uncarried);
+	}
+}
+
+// AddrMode::write_modified for the indexed modes, whose dummy read was
+// already made by read_to_modify.
+fn write_modified_at(cpu: &mut Cpu, hw: &mut Hardware, addr: u16, original: u8, value: u8) {
+	if cpu.dummy_reads() {
+		cpu.write_memory(hw, addr, original);
+	}
+	cpu.write_memory(hw, addr, value);
 }
 
 // Access A.
@@ -21,6 +51,9 @@ impl AddrMode for AddrAccumulator {
 	fn write(&self, cpu: &mut Cpu, _: &mut Hardware, value: u8) {
 		cpu.registers_mut().a = value;
 	}
+	fn write_modified(&self, cpu: &mut Cpu, hw: &mut Hardware, _: u8, value: u8) {
+		self.write(cpu, hw, value);
+	}
 	fn asm_str(_: &Cpu) -> String {
 		String::from("A")
 	}
@@ -124,18 +157,29 @@ impl AddrMode for AddrAbsolute {
 // Access absolute memory address + X.
 struct AddrAbsoluteX {
 	addr: u16,
+	uncarried: u16,
 }
 impl AddrMode for AddrAbsoluteX {
 	fn decode(cpu: &mut Cpu, _: &mut Hardware) -> AddrAbsoluteX {
-		let offset = cpu.registers().x as u16;
-		AddrAbsoluteX { addr: cpu.opcode16().wrapping_add(offset) }
+		let base = cpu.opcode16();
+		let addr = base.wrapping_add(cpu.registers().x as u16);
+		AddrAbsoluteX { addr, uncarried: (base & 0xFF00) | (addr & 0x00FF) }
 	}
 	fn read(&self, cpu: &mut Cpu, hw: &mut Hardware) -> u8 {
+		dummy_read(cpu, hw, self.uncarried, self.addr, false);
 		cpu.read_memory(hw, self.addr)
 	}
 	fn write(&self, cpu: &mut Cpu, hw: &mut Hardware, value: u8) {
+		dummy_read(cpu, hw, self.uncarried, self.addr, true);
 		cpu.write_memory(hw, self.addr, value);
 	}
+	fn read_to_modify(&self, cpu: &mut Cpu, hw: &mut Hardware) -> u8 {
+		dummy_read(cpu, hw, self.uncarried, self.addr, true);
+		cpu.read_memory(hw, self.addr)
+	}
+	fn write_modified(&self, cpu: &mut Cpu, hw: &mut Hardware, original: u8, value: u8) {
+		write_modified_at(cpu, hw, self.addr, original, value);
+	}
 	fn asm_str(cpu: &Cpu) -> String {
 		format!("${:04X},X", cpu.opcode16())
 	}
@@ -144,18 +188,29 @@ impl AddrMode for AddrAbsoluteX {
 // Access absolute memory address + Y.
 struct AddrAbsoluteY {
 	addr: u16,
+	uncarried: u16,
 }
 impl AddrMode for AddrAbsoluteY {
 	fn decode(cpu: &mut Cpu, _: &mut Hardware) -> AddrAbsoluteY {
-		let offset = cpu.registers().y as u16;
-		AddrAbsoluteY { addr: cpu.opcode16().wrapping_add(offset) }
+		let base = cpu.opcode16();
+		let addr = base.wrapping_add(cpu.registers().y as u16);
+		AddrAbsoluteY { addr, uncarried: (base & 0xFF00) | (addr & 0x00FF) }
 	}
 	fn read(&self, cpu: &mut Cpu, hw: &mut Hardware) -> u8 {
+		dummy_read(cpu, hw, self.uncarried, self.addr, false);
 		cpu.read_memory(hw, self.addr)
 	}
 	fn write(&self, cpu: &mut Cpu, hw: &mut Hardware, value: u8) {
+		dummy_read(cpu, hw, self.uncarried, self.addr, true);
 		cpu.write_memory(hw, self.addr, value);
 	}
+	fn read_to_modify(&self, cpu: &mut Cpu, hw: &mut Hardware) -> u8 {
+		dummy_read(cpu, hw, self.uncarried, self.addr, true);
+		cpu.read_memory(hw, self.addr)
+	}
+	fn write_modified(&self, cpu: &mut Cpu, hw: &mut Hardware, original: u8, value: u8) {
+		write_modified_at(cpu, hw, self.addr, original, value);
+	}
 	fn asm_str(cpu: &Cpu) -> String {
 		format!("${:04X},Y", cpu.opcode16())
 	}
@@ -186,6 +241,7 @@ impl AddrMode for AddrIndirectX {
 // Access memory address + Y at given zero parge memory address.
 struct AddrIndirectY {
 	addr: u16,
+	uncarried: u16,
 }
 impl AddrMode for AddrIndirectY {
 	fn decode(cpu: &mut Cpu, hw: &mut Hardware) -> AddrIndirectY {
@@ -193,14 +249,24 @@ impl AddrMode for AddrIndirectY {
 		let addr_lo = cpu.read_memory(hw, iaddr as u16) as u16;
 		let addr_hi = cpu.read_memory(hw, iaddr.wrapping_add(1) as u16) as u16;
 		let offset = cpu.registers().y as u16;
-		AddrIndirectY { addr: ((addr_hi << 8) | addr_lo).wrapping_add(offset) }
+		let addr = ((addr_hi << 8) | addr_lo).wrapping_add(offset);
+		AddrIndirectY { addr, uncarried: (addr_hi << 8) | (addr & 0x00FF) }
 	}
 	fn read(&self, cpu: &mut Cpu, hw: &mut Hardware) -> u8 {
+		dummy_read(cpu, hw, self.uncarried, self.addr, false);
 		cpu.read_memory(hw, self.addr)
 	}
 	fn write(&self, cpu: &mut Cpu, hw: &mut Hardware, value: u8) {
+		dummy_read(cpu, hw, self.uncarried, self.addr, true);
 		cpu.write_memory(hw, self.addr, value);
 	}
+	fn read_to_modify(&self, cpu: &mut Cpu, hw: &mut Hardware) -> u8 {
+		dummy_read(cpu, hw, self.uncarried, self.addr, true);
+		cpu.read_memory(hw, self.addr)
+	}
+	fn write_modified(&self, cpu: &mut Cpu, hw: &mut Hardware, original: u8, value: u8) {
+		write_modified_at(cpu, hw, self.addr, original, value);
+	}
 	fn asm_str(cpu: &Cpu) -> String {
 		format!("(${:02X}),Y", cpu.opcode8())
 	}
@@ -305,9 +371,9 @@ struct OpASL<A: AddrMode> {
 impl<A: AddrMode> Instruction for OpASL<A> {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let access = A::decode(cpu, hw);
-		let src = access.read(cpu, hw);
+		let src = access.read_to_modify(cpu, hw);
 		let result = src << 1;
-		access.write(cpu, hw, result);
+		access.write_modified(cpu, hw, src, result);
 		cpu.registers_mut().p.carry = src & 0x80 != 0;
 		cpu.registers_mut().p.zero = result == 0;
 		cpu.registers_mut().p.negative = result & 0x80 != 0;
@@ -588,8 +654,9 @@ struct OpDEC<A: AddrMode> {
 impl<A: AddrMode> Instruction for OpDEC<A> {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let access = A::decode(cpu, hw);
-		let result = access.read(cpu, hw).wrapping_sub(1);
-		access.write(cpu, hw, result);
+		let src = access.read_to_modify(cpu, hw);
+		let result = src.wrapping_sub(1);
+		access.write_modified(cpu, hw, src, result);
 		cpu.registers_mut().p.zero = result == 0;
 		cpu.registers_mut().p.negative = result & 0x80 != 0;
 	}
@@ -649,8 +716,9 @@ struct OpINC<A: AddrMode> {
 impl<A: AddrMode> Instruction for OpINC<A> {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let access = A::decode(cpu, hw);
-		let result = access.read(cpu, hw).wrapping_add(1);
-		access.write(cpu, hw, result);
+		let src = access.read_to_modify(cpu, hw);
+		let result = src.wrapping_add(1);
+		access.write_modified(cpu, hw, src, result);
 		cpu.registers_mut().p.zero = result == 0;
 		cpu.registers_mut().p.negative = result & 0x80 != 0;
 	}
@@ -733,9 +801,9 @@ impl Instruction for OpJSR {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let mut sp = cpu.registers().s;
 		let pc = cpu.registers().pc.wrapping_sub(1);
-		cpu.write_memory(hw, STACK_START + sp as u16, (pc >> 8) as u8);
+		cpu.write_stack(hw, sp, (pc >> 8) as u8);
 		sp = sp.wrapping_sub(1);
-		cpu.write_memory(hw, STACK_START + sp as u16, pc as u8);
+		cpu.write_stack(hw, sp, pc as u8);
 		sp = sp.wrapping_sub(1);
 
 		let addr = cpu.opcode16();
@@ -842,9 +910,9 @@ struct OpLSR<A: AddrMode> {
 impl<A: AddrMode> Instruction for OpLSR<A> {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let access = A::decode(cpu, hw);
-		let src = access.read(cpu, hw);
+		let src = access.read_to_modify(cpu, hw);
 		let result = src >> 1;
-		access.write(cpu, hw, result);
+		access.write_modified(cpu, hw, src, result);
 		cpu.registers_mut().p.carry = src & 1 != 0;
 		cpu.registers_mut().p.zero = result == 0;
 		cpu.registers_mut().p.negative = result & 0x80 != 0;
@@ -876,7 +944,7 @@ impl Instruction for OpPHA {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let sp = cpu.registers().s;
 		let value = cpu.registers().a;
-		cpu.write_memory(hw, STACK_START + sp as u16, value);
+		cpu.write_stack(hw, sp, value);
 		cpu.registers_mut().s = sp.wrapping_sub(1);
 	}
 	fn asm_str(&self, _: &Cpu) -> String {
@@ -890,7 +958,7 @@ impl Instruction for OpPHP {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let sp = cpu.registers().s;
 		let value = cpu.registers().p.value(true);
-		cpu.write_memory(hw, STACK_START + sp as u16, value);
+		cpu.write_stack(hw, sp, value);
 		cpu.registers_mut().s = sp.wrapping_sub(1);
 	}
 	fn asm_str(&self, _: &Cpu) -> String {
@@ -903,7 +971,7 @@ struct OpPLA;
 impl Instruction for OpPLA {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let sp = cpu.registers().s.wrapping_add(1);
-		let value = cpu.read_memory(hw, STACK_START + sp as u16);
+		let value = cpu.read_stack(hw, sp);
 		cpu.registers_mut().a = value;
 		cpu.registers_mut().s = sp;
 		cpu.registers_mut().p.zero = value == 0;
@@ -919,7 +987,7 @@ struct OpPLP;
 impl Instruction for OpPLP {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let sp = cpu.registers().s.wrapping_add(1);
-		let value = cpu.read_memory(hw, STACK_START + sp as u16);
+		let value = cpu.read_stack(hw, sp);
 		cpu.registers_mut().p.set_value(value);
 		cpu.registers_mut().s = sp;
 	}
@@ -949,9 +1017,9 @@ struct OpROL<A: AddrMode> {
 impl<A: AddrMode> Instruction for OpROL<A> {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let access = A::decode(cpu, hw);
-		let src = access.read(cpu, hw);
+		let src = access.read_to_modify(cpu, hw);
 		let result = (src << 1) | cpu.registers().p.carry as u8;
-		access.write(cpu, hw, result);
+		access.write_modified(cpu, hw, src, result);
 		cpu.registers_mut().p.carry = src & 0x80 != 0;
 		cpu.registers_mut().p.zero = result == 0;
 		cpu.registers_mut().p.negative = result & 0x80 != 0;
@@ -968,9 +1036,9 @@ struct OpROR<A: AddrMode> {
 impl<A: AddrMode> Instruction for OpROR<A> {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let access = A::decode(cpu, hw);
-		let src = access.read(cpu, hw);
+		let src = access.read_to_modify(cpu, hw);
 		let result = (src >> 1) | ((cpu.registers().p.carry as u8) << 7);
-		access.write(cpu, hw, result);
+		access.write_modified(cpu, hw, src, result);
 		cpu.registers_mut().p.carry = src & 1 != 0;
 		cpu.registers_mut().p.zero = result == 0;
 		cpu.registers_mut().p.negative = result & 0x80 != 0;
@@ -1000,11 +1068,11 @@ impl Instruction for OpRTI {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let mut sp = cpu.registers().s;
 		sp = sp.wrapping_add(1);
-		let p = cpu.read_memory(hw, STACK_START + sp as u16);
+		let p = cpu.read_stack(hw, sp);
 		sp = sp.wrapping_add(1);
-		let addr_lo = cpu.read_memory(hw, STACK_START + sp as u16) as u16;
+		let addr_lo = cpu.read_stack(hw, sp) as u16;
 		sp = sp.wrapping_add(1);
-		let addr_hi = cpu.read_memory(hw, STACK_START + sp as u16) as u16;
+		let addr_hi = cpu.read_stack(hw, sp) as u16;
 		let addr = (addr_hi << 8) | addr_lo;
 		cpu.registers_mut().s = sp;
 		cpu.registers_mut().pc = addr;
@@ -1021,9 +1089,9 @@ impl Instruction for OpRTS {
 	fn execute(&self, cpu: &mut Cpu, hw: &mut Hardware) {
 		let mut sp = cpu.registers().s;
 		sp = sp.wrapping_add(1);
-		let addr_lo = cpu.read_memory(hw, STACK_START + sp as u16) as u16;
+		let addr_lo = cpu.read_stack(hw, sp) as u16;
 		sp = sp.wrapping_add(1);
-		let addr_hi = cpu.read_memory(hw, STACK_START + sp as u16) as u16;
+		let addr_hi = cpu.read_stack(hw, sp) as u16;
 		let addr = ((addr_hi << 8) | addr_lo).wrapping_add(1);
 		cpu.registers_mut().s = sp;
 		cpu.registers_mut().pc = addr;
@@ -1253,6 +1321,20 @@ impl Instruction for OpTYA {
 	}
 }
 
+// Jams the CPU: it keeps executing this instruction and ignores NMIs,
+// only a reset recovers.
+struct OpKIL;
+impl Instruction for OpKIL {
+	fn execute(&self, cpu: &mut Cpu, _: &mut Hardware) {
+		let pc = cpu.registers().pc.wrapping_sub(1);
+		cpu.registers_mut().pc = pc;
+		cpu.jam();
+	}
+	fn asm_str(&self, _: &Cpu) -> String {
+		String::from("KIL")
+	}
+}
+
 // TODO Inofficial Instructions
 struct OpTODO;
 impl Instruction for OpTODO {
@@ -1264,31 +1346,334 @@ impl Instruction for OpTODO {
 	}
 }
 
-pub const INSTRUCTION_SIZES: [usize; 256] = [
-	//         0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
-	/* 0x00 */ 2, 2, 1, 2, 2, 2, 2, 2, 1, 2, 1, 2, 3, 3, 3, 3,
-	/* 0x10 */ 2, 2, 1, 2, 2, 2, 2, 2, 1, 3, 1, 3, 3, 3, 3, 3,
-	/* 0x20 */ 3, 2, 1, 2, 2, 2, 2, 2, 1, 2, 1, 2, 3, 3, 3, 3,
-	/* 0x30 */ 2, 2, 1, 2, 2, 2, 2, 2, 1, 3, 1, 3, 3, 3, 3, 3,
-	/* 0x40 */ 1, 2, 1, 2, 2, 2, 2, 2, 1, 2, 1, 2, 3, 3, 3, 3,
-	/* 0x50 */ 2, 2, 1, 2, 2, 2, 2, 2, 1, 3, 1, 3, 3, 3, 3, 3,
-	/* 0x60 */ 1, 2, 1, 2, 2, 2, 2, 2, 1, 2, 1, 2, 3, 3, 3, 3,
-	/* 0x70 */ 2, 2, 1, 2, 2, 2, 2, 2, 1, 3, 1, 3, 3, 3, 3, 3,
-	/* 0x80 */ 2, 2, 2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 3, 3, 3, 3,
-	/* 0x90 */ 2, 2, 1, 1, 2, 2, 2, 2, 1, 3, 1, 1, 1, 3, 1, 1,
-	/* 0xA0 */ 2, 2, 2, 2, 2, 2, 2, 2, 1, 2, 1, 2, 3, 3, 3, 3,
-	/* 0xB0 */ 2, 2, 1, 2, 2, 2, 2, 2, 1, 3, 1, 1, 3, 3, 3, 3,
-	/* 0xC0 */ 2, 2, 2, 2, 2, 2, 2, 2, 1, 2, 1, 2, 3, 3, 3, 3,
-	/* 0xD0 */ 2, 2, 1, 2, 2, 2, 2, 2, 1, 3, 1, 3, 3, 3, 3, 3,
-	/* 0xE0 */ 2, 2, 2, 2, 2, 2, 2, 2, 1, 2, 1, 2, 3, 3, 3, 3,
-	/* 0xF0 */ 2, 2, 1, 2, 2, 2, 2, 2, 1, 3, 1, 3, 3, 3, 3, 3,
+// Addressing modes as listed in the usual opcode tables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressingMode {
+	Implied,
+	Accumulator,
+	Immediate,
+	ZeroPage,
+	ZeroPageX,
+	ZeroPageY,
+	Absolute,
+	AbsoluteX,
+	AbsoluteY,
+	Indirect,
+	IndirectX,
+	IndirectY,
+	Relative,
+}
+
+// Static information about an opcode.
+#[derive(Debug, Clone, Copy)]
+pub struct OpcodeInfo {
+	pub mnemonic: &'static str,
+	pub mode: AddressingMode,
+	// Size in bytes including the opcode.
+	pub size: u8,
+	// Cycles without page crossing and branch penalties.
+	pub cycles: u8,
+	// False for undocumented opcodes.
+	pub official: bool,
+}
+
+// Metadata of all opcodes, in the same order as INSTRUCTIONS.
+// BRK has a size of 2 because it skips the following padding byte.
+pub const OPCODE_INFO: [OpcodeInfo; 256] = [
+	// 0x00
+	/* 0 */ OpcodeInfo { mnemonic: "BRK", mode: AddressingMode::Implied, size: 2, cycles: 7, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "ORA", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "SLO", mode: AddressingMode::IndirectX, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "ORA", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "ASL", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "SLO", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "PHP", mode: AddressingMode::Implied, size: 1, cycles: 3, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "ORA", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* A */ OpcodeInfo { mnemonic: "ASL", mode: AddressingMode::Accumulator, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "ANC", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* C */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: false },
+	/* D */ OpcodeInfo { mnemonic: "ORA", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "ASL", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: true },
+	/* F */ OpcodeInfo { mnemonic: "SLO", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: false },
+	
+	// 0x10
+	/* 0 */ OpcodeInfo { mnemonic: "BPL", mode: AddressingMode::Relative, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "ORA", mode: AddressingMode::IndirectY, size: 2, cycles: 5, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "SLO", mode: AddressingMode::IndirectY, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "ORA", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "ASL", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "SLO", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "CLC", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "ORA", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: true },
+	/* A */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* B */ OpcodeInfo { mnemonic: "SLO", mode: AddressingMode::AbsoluteY, size: 3, cycles: 7, official: false },
+	/* C */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: false },
+	/* D */ OpcodeInfo { mnemonic: "ORA", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "ASL", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: true },
+	/* F */ OpcodeInfo { mnemonic: "SLO", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: false },
+	
+	// 0x20
+	/* 0 */ OpcodeInfo { mnemonic: "JSR", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "AND", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "RLA", mode: AddressingMode::IndirectX, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "BIT", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 5 */ OpcodeInfo { mnemonic: "AND", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "ROL", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "RLA", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "PLP", mode: AddressingMode::Implied, size: 1, cycles: 4, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "AND", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* A */ OpcodeInfo { mnemonic: "ROL", mode: AddressingMode::Accumulator, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "ANC", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* C */ OpcodeInfo { mnemonic: "BIT", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* D */ OpcodeInfo { mnemonic: "AND", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "ROL", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: true },
+	/* F */ OpcodeInfo { mnemonic: "RLA", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: false },
+	
+	// 0x30
+	/* 0 */ OpcodeInfo { mnemonic: "BMI", mode: AddressingMode::Relative, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "AND", mode: AddressingMode::IndirectY, size: 2, cycles: 5, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "RLA", mode: AddressingMode::IndirectY, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "AND", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "ROL", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "RLA", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "SEC", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "AND", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: true },
+	/* A */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* B */ OpcodeInfo { mnemonic: "RLA", mode: AddressingMode::AbsoluteY, size: 3, cycles: 7, official: false },
+	/* C */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: false },
+	/* D */ OpcodeInfo { mnemonic: "AND", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "ROL", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: true },
+	/* F */ OpcodeInfo { mnemonic: "RLA", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: false },
+	
+	// 0x40
+	/* 0 */ OpcodeInfo { mnemonic: "RTI", mode: AddressingMode::Implied, size: 1, cycles: 6, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "EOR", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "SRE", mode: AddressingMode::IndirectX, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "EOR", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "LSR", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "SRE", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "PHA", mode: AddressingMode::Implied, size: 1, cycles: 3, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "EOR", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* A */ OpcodeInfo { mnemonic: "LSR", mode: AddressingMode::Accumulator, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "ALR", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* C */ OpcodeInfo { mnemonic: "JMP", mode: AddressingMode::Absolute, size: 3, cycles: 3, official: true },
+	/* D */ OpcodeInfo { mnemonic: "EOR", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "LSR", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: true },
+	/* F */ OpcodeInfo { mnemonic: "SRE", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: false },
+	
+	// 0x50
+	/* 0 */ OpcodeInfo { mnemonic: "BVC", mode: AddressingMode::Relative, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "EOR", mode: AddressingMode::IndirectY, size: 2, cycles: 5, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "SRE", mode: AddressingMode::IndirectY, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "EOR", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "LSR", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "SRE", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "CLI", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "EOR", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: true },
+	/* A */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* B */ OpcodeInfo { mnemonic: "SRE", mode: AddressingMode::AbsoluteY, size: 3, cycles: 7, official: false },
+	/* C */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: false },
+	/* D */ OpcodeInfo { mnemonic: "EOR", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "LSR", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: true },
+	/* F */ OpcodeInfo { mnemonic: "SRE", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: false },
+	
+	// 0x60
+	/* 0 */ OpcodeInfo { mnemonic: "RTS", mode: AddressingMode::Implied, size: 1, cycles: 6, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "ADC", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "RRA", mode: AddressingMode::IndirectX, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "ADC", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "ROR", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "RRA", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "PLA", mode: AddressingMode::Implied, size: 1, cycles: 4, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "ADC", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* A */ OpcodeInfo { mnemonic: "ROR", mode: AddressingMode::Accumulator, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "ARR", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* C */ OpcodeInfo { mnemonic: "JMP", mode: AddressingMode::Indirect, size: 3, cycles: 5, official: true },
+	/* D */ OpcodeInfo { mnemonic: "ADC", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "ROR", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: true },
+	/* F */ OpcodeInfo { mnemonic: "RRA", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: false },
+	
+	// 0x70
+	/* 0 */ OpcodeInfo { mnemonic: "BVS", mode: AddressingMode::Relative, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "ADC", mode: AddressingMode::IndirectY, size: 2, cycles: 5, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "RRA", mode: AddressingMode::IndirectY, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "ADC", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "ROR", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "RRA", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "SEI", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "ADC", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: true },
+	/* A */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* B */ OpcodeInfo { mnemonic: "RRA", mode: AddressingMode::AbsoluteY, size: 3, cycles: 7, official: false },
+	/* C */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: false },
+	/* D */ OpcodeInfo { mnemonic: "ADC", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "ROR", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: true },
+	/* F */ OpcodeInfo { mnemonic: "RRA", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: false },
+	
+	// 0x80
+	/* 0 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* 1 */ OpcodeInfo { mnemonic: "STA", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "SAX", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "STY", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 5 */ OpcodeInfo { mnemonic: "STA", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "STX", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "SAX", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "DEY", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* A */ OpcodeInfo { mnemonic: "TXA", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "XAA", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* C */ OpcodeInfo { mnemonic: "STY", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* D */ OpcodeInfo { mnemonic: "STA", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "STX", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* F */ OpcodeInfo { mnemonic: "SAX", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: false },
+	
+	// 0x90
+	/* 0 */ OpcodeInfo { mnemonic: "BCC", mode: AddressingMode::Relative, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "STA", mode: AddressingMode::IndirectY, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "AHX", mode: AddressingMode::IndirectY, size: 2, cycles: 6, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "STY", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 5 */ OpcodeInfo { mnemonic: "STA", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "STX", mode: AddressingMode::ZeroPageY, size: 2, cycles: 4, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "SAX", mode: AddressingMode::ZeroPageY, size: 2, cycles: 4, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "TYA", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "STA", mode: AddressingMode::AbsoluteY, size: 3, cycles: 5, official: true },
+	/* A */ OpcodeInfo { mnemonic: "TXS", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "TAS", mode: AddressingMode::AbsoluteY, size: 3, cycles: 5, official: false },
+	/* C */ OpcodeInfo { mnemonic: "SHY", mode: AddressingMode::AbsoluteX, size: 3, cycles: 5, official: false },
+	/* D */ OpcodeInfo { mnemonic: "STA", mode: AddressingMode::AbsoluteX, size: 3, cycles: 5, official: true },
+	/* E */ OpcodeInfo { mnemonic: "SHX", mode: AddressingMode::AbsoluteY, size: 3, cycles: 5, official: false },
+	/* F */ OpcodeInfo { mnemonic: "AHX", mode: AddressingMode::AbsoluteY, size: 3, cycles: 5, official: false },
+	
+	// 0xA0
+	/* 0 */ OpcodeInfo { mnemonic: "LDY", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "LDA", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "LDX", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* 3 */ OpcodeInfo { mnemonic: "LAX", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "LDY", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 5 */ OpcodeInfo { mnemonic: "LDA", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "LDX", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "LAX", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "TAY", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "LDA", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* A */ OpcodeInfo { mnemonic: "TAX", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "LAX", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* C */ OpcodeInfo { mnemonic: "LDY", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* D */ OpcodeInfo { mnemonic: "LDA", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "LDX", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* F */ OpcodeInfo { mnemonic: "LAX", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: false },
+	
+	// 0xB0
+	/* 0 */ OpcodeInfo { mnemonic: "BCS", mode: AddressingMode::Relative, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "LDA", mode: AddressingMode::IndirectY, size: 2, cycles: 5, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "LAX", mode: AddressingMode::IndirectY, size: 2, cycles: 5, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "LDY", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 5 */ OpcodeInfo { mnemonic: "LDA", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "LDX", mode: AddressingMode::ZeroPageY, size: 2, cycles: 4, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "LAX", mode: AddressingMode::ZeroPageY, size: 2, cycles: 4, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "CLV", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "LDA", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: true },
+	/* A */ OpcodeInfo { mnemonic: "TSX", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "LAS", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: false },
+	/* C */ OpcodeInfo { mnemonic: "LDY", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: true },
+	/* D */ OpcodeInfo { mnemonic: "LDA", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "LDX", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: true },
+	/* F */ OpcodeInfo { mnemonic: "LAX", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: false },
+	
+	// 0xC0
+	/* 0 */ OpcodeInfo { mnemonic: "CPY", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "CMP", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "DCP", mode: AddressingMode::IndirectX, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "CPY", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 5 */ OpcodeInfo { mnemonic: "CMP", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "DEC", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "DCP", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "INY", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "CMP", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* A */ OpcodeInfo { mnemonic: "DEX", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "AXS", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* C */ OpcodeInfo { mnemonic: "CPY", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* D */ OpcodeInfo { mnemonic: "CMP", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "DEC", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: true },
+	/* F */ OpcodeInfo { mnemonic: "DCP", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: false },
+	
+	// 0xD0
+	/* 0 */ OpcodeInfo { mnemonic: "BNE", mode: AddressingMode::Relative, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "CMP", mode: AddressingMode::IndirectY, size: 2, cycles: 5, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "DCP", mode: AddressingMode::IndirectY, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "CMP", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "DEC", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "DCP", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "CLD", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "CMP", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: true },
+	/* A */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* B */ OpcodeInfo { mnemonic: "DCP", mode: AddressingMode::AbsoluteY, size: 3, cycles: 7, official: false },
+	/* C */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: false },
+	/* D */ OpcodeInfo { mnemonic: "CMP", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "DEC", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: true },
+	/* F */ OpcodeInfo { mnemonic: "DCP", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: false },
+	
+	// 0xE0
+	/* 0 */ OpcodeInfo { mnemonic: "CPX", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::IndirectX, size: 2, cycles: 6, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "ISB", mode: AddressingMode::IndirectX, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "CPX", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 5 */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::ZeroPage, size: 2, cycles: 3, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "INC", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "ISB", mode: AddressingMode::ZeroPage, size: 2, cycles: 5, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "INX", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: true },
+	/* A */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* B */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::Immediate, size: 2, cycles: 2, official: false },
+	/* C */ OpcodeInfo { mnemonic: "CPX", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* D */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::Absolute, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "INC", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: true },
+	/* F */ OpcodeInfo { mnemonic: "ISB", mode: AddressingMode::Absolute, size: 3, cycles: 6, official: false },
+	
+	// 0xF0
+	/* 0 */ OpcodeInfo { mnemonic: "BEQ", mode: AddressingMode::Relative, size: 2, cycles: 2, official: true },
+	/* 1 */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::IndirectY, size: 2, cycles: 5, official: true },
+	/* 2 */ OpcodeInfo { mnemonic: "KIL", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* 3 */ OpcodeInfo { mnemonic: "ISB", mode: AddressingMode::IndirectY, size: 2, cycles: 8, official: false },
+	/* 4 */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: false },
+	/* 5 */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::ZeroPageX, size: 2, cycles: 4, official: true },
+	/* 6 */ OpcodeInfo { mnemonic: "INC", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: true },
+	/* 7 */ OpcodeInfo { mnemonic: "ISB", mode: AddressingMode::ZeroPageX, size: 2, cycles: 6, official: false },
+	/* 8 */ OpcodeInfo { mnemonic: "SED", mode: AddressingMode::Implied, size: 1, cycles: 2, official: true },
+	/* 9 */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::AbsoluteY, size: 3, cycles: 4, official: true },
+	/* A */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::Implied, size: 1, cycles: 2, official: false },
+	/* B */ OpcodeInfo { mnemonic: "ISB", mode: AddressingMode::AbsoluteY, size: 3, cycles: 7, official: false },
+	/* C */ OpcodeInfo { mnemonic: "NOP", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: false },
+	/* D */ OpcodeInfo { mnemonic: "SBC", mode: AddressingMode::AbsoluteX, size: 3, cycles: 4, official: true },
+	/* E */ OpcodeInfo { mnemonic: "INC", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: true },
+	/* F */ OpcodeInfo { mnemonic: "ISB", mode: AddressingMode::AbsoluteX, size: 3, cycles: 7, official: false },
 ];
 
-pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
+pub const INSTRUCTIONS: [&'static (dyn Instruction + Sync); 256] = [
 	// 0x00
 	/* 0 */ &OpBRK,
 	/* 1 */ &OpORA::<AddrIndirectX>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpSLO::<AddrIndirectX>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPage>{ phantom: PhantomData },
 	/* 5 */ &OpORA::<AddrZeroPage>{ phantom: PhantomData },
@@ -1306,7 +1691,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0x10
 	/* 0 */ &OpBPL,
 	/* 1 */ &OpORA::<AddrIndirectY>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpSLO::<AddrIndirectY>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPageX>{ phantom: PhantomData },
 	/* 5 */ &OpORA::<AddrZeroPageX>{ phantom: PhantomData },
@@ -1324,7 +1709,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0x20
 	/* 0 */ &OpJSR,
 	/* 1 */ &OpAND::<AddrIndirectX>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpRLA::<AddrIndirectX>{ phantom: PhantomData },
 	/* 4 */ &OpBIT::<AddrZeroPage>{ phantom: PhantomData },
 	/* 5 */ &OpAND::<AddrZeroPage>{ phantom: PhantomData },
@@ -1342,7 +1727,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0x30
 	/* 0 */ &OpBMI,
 	/* 1 */ &OpAND::<AddrIndirectY>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpRLA::<AddrIndirectY>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPageX>{ phantom: PhantomData },
 	/* 5 */ &OpAND::<AddrZeroPageX>{ phantom: PhantomData },
@@ -1360,7 +1745,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0x40
 	/* 0 */ &OpRTI,
 	/* 1 */ &OpEOR::<AddrIndirectX>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpSRE::<AddrIndirectX>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPage>{ phantom: PhantomData },
 	/* 5 */ &OpEOR::<AddrZeroPage>{ phantom: PhantomData },
@@ -1378,7 +1763,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0x50
 	/* 0 */ &OpBVC,
 	/* 1 */ &OpEOR::<AddrIndirectY>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpSRE::<AddrIndirectY>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPageX>{ phantom: PhantomData },
 	/* 5 */ &OpEOR::<AddrZeroPageX>{ phantom: PhantomData },
@@ -1396,7 +1781,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0x60
 	/* 0 */ &OpRTS,
 	/* 1 */ &OpADC::<AddrIndirectX>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpRRA::<AddrIndirectX>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPage>{ phantom: PhantomData },
 	/* 5 */ &OpADC::<AddrZeroPage>{ phantom: PhantomData },
@@ -1414,7 +1799,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0x70
 	/* 0 */ &OpBVS,
 	/* 1 */ &OpADC::<AddrIndirectY>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpRRA::<AddrIndirectY>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPageX>{ phantom: PhantomData },
 	/* 5 */ &OpADC::<AddrZeroPageX>{ phantom: PhantomData },
@@ -1450,7 +1835,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0x90
 	/* 0 */ &OpBCC,
 	/* 1 */ &OpSTA::<AddrIndirectY>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpTODO,
 	/* 4 */ &OpSTY::<AddrZeroPageX>{ phantom: PhantomData },
 	/* 5 */ &OpSTA::<AddrZeroPageX>{ phantom: PhantomData },
@@ -1486,7 +1871,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0xB0
 	/* 0 */ &OpBCS,
 	/* 1 */ &OpLDA::<AddrIndirectY>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpLAX::<AddrIndirectY>{ phantom: PhantomData },
 	/* 4 */ &OpLDY::<AddrZeroPageX>{ phantom: PhantomData },
 	/* 5 */ &OpLDA::<AddrZeroPageX>{ phantom: PhantomData },
@@ -1522,7 +1907,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0xD0
 	/* 0 */ &OpBNE,
 	/* 1 */ &OpCMP::<AddrIndirectY>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpDCP::<AddrIndirectY>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPageX>{ phantom: PhantomData },
 	/* 5 */ &OpCMP::<AddrZeroPageX>{ phantom: PhantomData },
@@ -1558,7 +1943,7 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	// 0xF0
 	/* 0 */ &OpBEQ,
 	/* 1 */ &OpSBC::<AddrIndirectY>{ phantom: PhantomData },
-	/* 2 */ &OpTODO,
+	/* 2 */ &OpKIL,
 	/* 3 */ &OpISB::<AddrIndirectY>{ phantom: PhantomData },
 	/* 4 */ &OpNOPMulti::<AddrZeroPageX>{ phantom: PhantomData },
 	/* 5 */ &OpSBC::<AddrZeroPageX>{ phantom: PhantomData },
@@ -1574,3 +1959,20 @@ pub const INSTRUCTIONS: [&'static (Instruction + Sync); 256] = [
 	/* F */ &OpISB::<AddrAbsoluteX>{ phantom: PhantomData },
 ];
 
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn opcode_info() {
+		let cpu = Cpu::new();
+		for (i, instruction) in INSTRUCTIONS.iter().enumerate() {
+			let asm = instruction.asm_str(&cpu);
+			if asm != "???" {
+				assert_eq!(OPCODE_INFO[i].mnemonic, &asm[..3], "opcode {:02X}", i);
+			}
+		}
+		assert_eq!(151, OPCODE_INFO.iter().filter(|info| info.official).count());
+	}
+}
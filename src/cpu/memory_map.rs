@@ -1,5 +1,8 @@
-// Size of the internal RAM.
+// Size of the internal RAM of the Nintendo consoles. Famiclones can have
+// more, see MachineConfig.
 pub const RAM_SIZE: u16 = 2048;
+// Size of the area the internal RAM is mirrored over.
+pub const RAM_AREA_SIZE: u16 = 8192;
 // Number of PPU registers à 1 byte.
 pub const PPU_SIZE: u16 = 8;
 // Number of APU and IO registers à 1 byte.
@@ -10,7 +13,7 @@ pub const CARTRIDGE_SIZE: u16 = 49120;
 // Start address of RAM.
 pub const RAM_START: u16 = 0;
 // Start address of PPU registers.
-pub const PPU_START: u16 = RAM_START + 4 * RAM_SIZE;
+pub const PPU_START: u16 = RAM_START + RAM_AREA_SIZE;
 // Start of API and IO registers.
 pub const APU_IO_START: u16 = PPU_START + 1024 * PPU_SIZE;
 // Start of cartridge space.
@@ -24,6 +27,7 @@ mod test {
 	fn constants() {
 		assert_eq!(0, RAM_START);
 		assert!(RAM_START < PPU_START);
+		assert_eq!(0, RAM_AREA_SIZE % RAM_SIZE);
 		assert!(PPU_START < APU_IO_START);
 		assert!(APU_IO_START < CARTRIDGE_START);
 		assert_eq!(0, CARTRIDGE_START.wrapping_add(CARTRIDGE_SIZE));
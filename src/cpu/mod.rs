@@ -1,5 +1,13 @@
 mod cpu;
 mod instructions;
+mod assembler;
+mod disassembler;
+mod watch;
+mod bus_stats;
 
 pub mod memory_map;
-pub use cpu::cpu::{Cpu, Hardware};
+pub use cpu::cpu::{Cpu, CpuState, Hardware, Status, TrapHandler};
+pub use cpu::assembler::assemble;
+pub use cpu::disassembler::disassemble;
+pub use cpu::bus_stats::{BusStats, BUS_REGIONS};
+pub use cpu::watch::{AccessSource, ACCESS_SOURCES, Watchpoint, WatchHit};
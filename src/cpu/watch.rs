@@ -0,0 +1,88 @@
+// What made the CPU access the bus, for watchpoints which only stop on
+// some kinds of accesses, e.g. on the writes of the program to a variable
+// and not on pushes to the same address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessSource {
+	// opcode and operand bytes of an instruction
+	Fetch,
+	// reads and writes of an instruction at its effective address
+	Data,
+	// pushes and pulls, also of JSR, RTS, RTI and interrupts
+	Stack,
+	// reads of the interrupt and reset vectors
+	Vector,
+	// reads and writes of OAM DMA and DMC fetches, see Dma
+	Dma,
+}
+
+pub const ACCESS_SOURCES: [AccessSource; 5] =
+	[AccessSource::Fetch, AccessSource::Data, AccessSource::Stack, AccessSource::Vector, AccessSource::Dma];
+
+impl AccessSource {
+	pub fn from_name(name: &str) -> Option<AccessSource> {
+		ACCESS_SOURCES.iter().cloned().find(|source| source.name() == name)
+	}
+
+	pub fn name(&self) -> &'static str {
+		match *self {
+			AccessSource::Fetch => "fetch",
+			AccessSource::Data => "data",
+			AccessSource::Stack => "stack",
+			AccessSource::Vector => "vector",
+			AccessSource::Dma => "dma",
+		}
+	}
+}
+
+// Stops on reads and/or writes of an address range by some sources.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watchpoint {
+	pub start: u16,
+	// inclusive
+	pub end: u16,
+	pub read: bool,
+	pub write: bool,
+	pub sources: Vec<AccessSource>,
+}
+
+impl Watchpoint {
+	pub fn matches(&self, addr: u16, write: bool, source: AccessSource) -> bool {
+		self.start <= addr && addr <= self.end &&
+			(if write { self.write } else { self.read }) &&
+			self.sources.contains(&source)
+	}
+}
+
+// The first access which matched a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+	pub addr: u16,
+	pub value: u8,
+	pub write: bool,
+	pub source: AccessSource,
+	// address of the instruction which accessed the bus
+	pub pc: u16,
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn matches() {
+		let watchpoint = Watchpoint {
+			start: 0x0100,
+			end: 0x01FF,
+			read: false,
+			write: true,
+			sources: vec![AccessSource::Data],
+		};
+		assert!(watchpoint.matches(0x01FD, true, AccessSource::Data));
+		assert!(!watchpoint.matches(0x01FD, true, AccessSource::Stack));
+		assert!(!watchpoint.matches(0x01FD, false, AccessSource::Data));
+		assert!(!watchpoint.matches(0x0200, true, AccessSource::Data));
+		assert_eq!(Some(AccessSource::Stack), AccessSource::from_name("stack"));
+		assert_eq!(Some(AccessSource::Dma), AccessSource::from_name("dma"));
+		assert_eq!(None, AccessSource::from_name("refresh"));
+	}
+}
@@ -0,0 +1,82 @@
+use nes::Nes;
+use ppu;
+use repl;
+use trace::Tracer;
+use std::fs::{self, File};
+use std::io::{self, Write, BufWriter};
+use std::panic;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Message and location of the last panic, kept by the hook for the report.
+static LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
+
+// Installs a panic hook which remembers the panic for write_report, and
+// prints it as before. Mapper faults caught by the PPU are left to its log.
+pub fn install_hook() {
+	let default_hook = panic::take_hook();
+	panic::set_hook(Box::new(move |info| {
+		if ppu::catching_mapper_fault() {
+			return;
+		}
+		if let Ok(mut last) = LAST_PANIC.lock() {
+			*last = Some(info.to_string());
+		}
+		default_hook(info);
+	}));
+}
+
+// Writes what is known about a crash of the emulation into the directory:
+//
+//   report.txt      the panic, the ROM and the CPU and PPU registers
+//   trace.log       the last traced instructions, if tracing was on
+//   screenshot.ppm  the frame being drawn
+pub fn write_report(dir: &str, nes: &Nes, tracer: &Tracer, rom_path: &str) -> io::Result<()> {
+	let dir = Path::new(dir);
+	fs::create_dir_all(dir)?;
+	let panic = LAST_PANIC.lock().ok().and_then(|last| last.clone());
+	let mut report = File::create(dir.join("report.txt"))?;
+	report.write_all(self::report(panic.as_ref().map(|panic| panic.as_ref()), nes, rom_path).as_bytes())?;
+	let mut trace = BufWriter::new(File::create(dir.join("trace.log"))?);
+	tracer.dump(&mut trace)?;
+	trace.flush()?;
+	let mut screenshot = BufWriter::new(File::create(dir.join("screenshot.ppm"))?);
+	nes.ppu().frame_in_progress().write_ppm(&mut screenshot)?;
+	screenshot.flush()
+}
+
+fn report(panic: Option<&str>, nes: &Nes, rom_path: &str) -> String {
+	format!("{}\n\nROM: {}\nCPU: {}\nPPU: {}\nCPU cycles: {}\n",
+		panic.unwrap_or("Unknown panic."), rom_path, repl::registers(nes), nes.ppu().registers(), nes.cpu_cycles())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+	use cartridge::test_cartridge::TestCartridge;
+	use std::env;
+	use std::io::Read;
+
+	#[test]
+	fn report_files() {
+		let code = assemble(0x8000, "LDA #$1E; STA $2001; JMP $8005").unwrap();
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+		let mut instr_log: Option<&mut dyn Write> = None;
+		for _ in 0..3 {
+			nes.step(&mut instr_log);
+		}
+		let text = report(Some("panicked at src/ppu.rs:1:1:\nboom"), &nes, "game.nes");
+		assert!(text.starts_with("panicked at src/ppu.rs:1:1:\nboom\n\nROM: game.nes\nCPU: PC:8005 A:1E"), "{}", text);
+		assert!(text.contains("PPU: CTRL:00 MASK:1E STATUS:00"), "{}", text);
+
+		let dir = env::temp_dir().join(format!("nes-crash-{}", ::std::process::id()));
+		write_report(dir.to_str().unwrap(), &nes, &Tracer::new(10), "game.nes").unwrap();
+		let mut screenshot = Vec::new();
+		File::open(dir.join("screenshot.ppm")).and_then(|mut file| file.read_to_end(&mut screenshot)).unwrap();
+		assert!(screenshot.starts_with(b"P6\n256 240\n255\n"));
+		assert!(dir.join("report.txt").exists());
+		assert!(dir.join("trace.log").exists());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
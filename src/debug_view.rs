@@ -0,0 +1,149 @@
+use nes::Nes;
+use ppu::RGB_PALETTE;
+
+// Contents of the debug windows, drawn without SDL so they can be tested.
+// Each view renders into an RGBA image of a fixed size, which the frontend
+// shows in a window of its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugView {
+	// Both pattern tables side by side, colored with background palette 0,
+	// and the 32 palette entries below them.
+	PpuViewer,
+	// The 64 colors of the master palette in 4 rows, see PaletteEditor.
+	PaletteEditor,
+}
+
+pub struct DebugImage {
+	pub width: usize,
+	pub height: usize,
+	// RGBA, row by row
+	pub pixels: Vec<u8>,
+	// RGB of the colors drawn by set_pixel
+	pub palette: [u8; 64 * 3],
+}
+
+impl DebugImage {
+	pub fn new(width: usize, height: usize) -> DebugImage {
+		DebugImage {
+			width,
+			height,
+			pixels: vec![0; width * height * 4],
+			palette: RGB_PALETTE,
+		}
+	}
+
+	pub fn set_pixel(&mut self, x: usize, y: usize, color: u8) {
+		let index = (y * self.width + x) * 4;
+		let color = (color & 0x3F) as usize * 3;
+		self.pixels[index..index + 3].copy_from_slice(&self.palette[color..color + 3]);
+		self.pixels[index + 3] = 0xFF;
+	}
+
+	// For the tests of the views, the frontend only uploads the pixels.
+	#[cfg(test)]
+	pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+		let index = (y * self.width + x) * 4;
+		(self.pixels[index], self.pixels[index + 1], self.pixels[index + 2])
+	}
+}
+
+// Height of one palette entry in the PPU viewer.
+const PALETTE_ROW: usize = 16;
+
+// Size of a color in the palette editor.
+pub const PALETTE_EDITOR_CELL: usize = 16;
+
+impl DebugView {
+	pub fn title(&self) -> &'static str {
+		match *self {
+			DebugView::PpuViewer => "PPU Viewer",
+			DebugView::PaletteEditor => "Palette Editor",
+		}
+	}
+
+	// Size of the image in pixels.
+	pub fn size(&self) -> (usize, usize) {
+		match *self {
+			DebugView::PpuViewer => (256, 128 + PALETTE_ROW),
+			DebugView::PaletteEditor => (16 * PALETTE_EDITOR_CELL, 4 * PALETTE_EDITOR_CELL),
+		}
+	}
+
+	pub fn draw(&self, nes: &mut Nes) -> DebugImage {
+		let (width, height) = self.size();
+		let mut image = DebugImage::new(width, height);
+		image.palette = *nes.master_palette();
+		match *self {
+			DebugView::PpuViewer => draw_ppu_viewer(nes, &mut image),
+			DebugView::PaletteEditor => draw_palette(&mut image),
+		}
+		image
+	}
+}
+
+fn draw_ppu_viewer(nes: &mut Nes, image: &mut DebugImage) {
+	let colors = nes.palette_ram().colors(0);
+	for table in 0..2 {
+		for tile in 0..256 {
+			let addr = (table * 0x1000 + tile * 16) as u16;
+			let x0 = table * 128 + (tile % 16) * 8;
+			let y0 = (tile / 16) * 8;
+			for row in 0..8 {
+				let low = nes.peek_vram(addr + row as u16);
+				let high = nes.peek_vram(addr + row as u16 + 8);
+				for column in 0..8 {
+					let bit = 7 - column;
+					let value = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+					image.set_pixel(x0 + column, y0 + row, colors[value as usize]);
+				}
+			}
+		}
+	}
+	for (entry, &color) in nes.palette_ram().entries().iter().enumerate() {
+		for y in 128..128 + PALETTE_ROW {
+			for x in entry * 8..entry * 8 + 8 {
+				image.set_pixel(x, y, color);
+			}
+		}
+	}
+}
+
+fn draw_palette(image: &mut DebugImage) {
+	for y in 0..image.height {
+		for x in 0..image.width {
+			let color = (y / PALETTE_EDITOR_CELL) * 16 + x / PALETTE_EDITOR_CELL;
+			image.set_pixel(x, y, color as u8);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::test_cartridge::TestCartridge;
+
+	fn rgb(color: usize) -> (u8, u8, u8) {
+		(RGB_PALETTE[color * 3], RGB_PALETTE[color * 3 + 1], RGB_PALETTE[color * 3 + 2])
+	}
+
+	#[test]
+	fn ppu_viewer() {
+		// tile 1 of the left table: top row color 1, second row color 3
+		let cartridge = TestCartridge::builder().chr(0x0010, &[0xFF, 0x00, 0, 0, 0, 0, 0, 0, 0x00, 0xFF]).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		nes.poke_palette(0, 0x0F);
+		nes.poke_palette(1, 0x16);
+		nes.poke_palette(2, 0x2A);
+		nes.poke_palette(3, 0x30);
+		nes.poke_palette(0x1F, 0x12);
+
+		let view = DebugView::PpuViewer;
+		let image = view.draw(&mut nes);
+		assert_eq!(view.size(), (image.width, image.height));
+		assert_eq!(rgb(0x16), image.pixel(8, 0));
+		assert_eq!(rgb(0x2A), image.pixel(15, 1));
+		assert_eq!(rgb(0x0F), image.pixel(8, 2));
+		assert_eq!(rgb(0x0F), image.pixel(128, 0));
+		assert_eq!(rgb(0x12), image.pixel(255, 130));
+	}
+}
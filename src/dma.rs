@@ -0,0 +1,218 @@
+// Writing the page number here copies the page to the sprite memory.
+pub const OAM_DMA: u16 = 0x4014;
+// OAMDATA, which OAM DMA writes the bytes to.
+const OAM_DATA: u16 = 0x2004;
+// Cycles a DMC fetch waits after its request, the halt and a dummy cycle.
+const DMC_WAIT_CYCLES: u8 = 2;
+
+// What the DMA unit does on the bus in a cycle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DmaAccess {
+	// halt, dummy and alignment cycles
+	None,
+	// the value goes to Dma::read_done
+	Read(u16),
+	Write(u16, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reader {
+	Oam,
+	Dmc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OamTransfer {
+	// of the next byte to read
+	address: u16,
+	// the byte read and not written yet
+	latch: Option<u8>,
+	written: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DmcFetch {
+	address: u16,
+	wait: u8,
+}
+
+// The DMA unit, which takes the bus over from the CPU for
+// * OAM DMA, which copies a page to the sprite memory after a write to
+//   OAM_DMA,
+// * DMC DMA, which fetches the next sample byte of the DMC channel.
+//
+// The bus alternates between get cycles, in which DMA can read, and put
+// cycles, in which it can write. A transfer starts with a cycle in which the
+// CPU halts, and waits with an alignment cycle until a read falls on a get
+// cycle, so OAM DMA takes 513 or 514 cycles. A DMC fetch during OAM DMA
+// hides its halt and dummy cycle in the transfer and takes one of its get
+// cycles, after which OAM DMA needs an alignment cycle to read again. That
+// costs 2 cycles, or 1 or 3 when the fetch falls on the end of the transfer.
+// The console runs the other components cycle by cycle meanwhile, see
+// Nes::step.
+pub struct Dma {
+	oam: Option<OamTransfer>,
+	dmc: Option<DmcFetch>,
+	dmc_byte: Option<u8>,
+	// The CPU is halted for the running transfers.
+	halted: bool,
+	reading: Reader,
+}
+
+impl Dma {
+	pub fn new() -> Dma {
+		Dma {
+			oam: None,
+			dmc: None,
+			dmc_byte: None,
+			halted: false,
+			reading: Reader::Oam,
+		}
+	}
+
+	// Whether the CPU stays halted for a transfer.
+	pub fn active(&self) -> bool {
+		self.oam.is_some() || self.dmc.is_some()
+	}
+
+	pub fn start_oam(&mut self, page: u8) {
+		if !self.active() {
+			self.halted = false;
+		}
+		self.oam = Some(OamTransfer {
+			address: (page as u16) << 8,
+			latch: None,
+			written: 0,
+		});
+	}
+
+	// The DMC channel needs the byte at the address, see take_dmc_byte.
+	pub fn request_dmc(&mut self, address: u16) {
+		if !self.active() {
+			self.halted = false;
+		}
+		self.dmc = Some(DmcFetch {
+			address,
+			wait: DMC_WAIT_CYCLES,
+		});
+	}
+
+	// The byte of the last finished DMC fetch.
+	pub fn take_dmc_byte(&mut self) -> Option<u8> {
+		self.dmc_byte.take()
+	}
+
+	// Decides the access of the next cycle, which is a get cycle if get is
+	// true and a put cycle otherwise.
+	pub fn cycle(&mut self, get: bool) -> DmaAccess {
+		let access = if !self.halted {
+			self.halted = true;
+			DmaAccess::None
+		} else if get && self.dmc.map(|dmc| dmc.wait == 0).unwrap_or(false) {
+			self.reading = Reader::Dmc;
+			DmaAccess::Read(self.dmc.unwrap().address)
+		} else if let Some(ref mut oam) = self.oam {
+			match oam.latch {
+				Some(value) if !get => {
+					oam.latch = None;
+					oam.written += 1;
+					DmaAccess::Write(OAM_DATA, value)
+				}
+				None if get => {
+					oam.address = oam.address.wrapping_add(1);
+					self.reading = Reader::Oam;
+					DmaAccess::Read(oam.address.wrapping_sub(1))
+				}
+				_ => DmaAccess::None,
+			}
+		} else {
+			DmaAccess::None
+		};
+		if self.oam.map(|oam| oam.written == 256).unwrap_or(false) {
+			self.oam = None;
+		}
+		if let Some(ref mut dmc) = self.dmc {
+			dmc.wait = dmc.wait.saturating_sub(1);
+		}
+		access
+	}
+
+	// The value of the read the last cycle did.
+	pub fn read_done(&mut self, value: u8) {
+		match self.reading {
+			Reader::Oam => {
+				if let Some(ref mut oam) = self.oam {
+					oam.latch = Some(value);
+				}
+			}
+			Reader::Dmc => {
+				self.dmc = None;
+				self.dmc_byte = Some(value);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// Runs the transfers from a cycle on, with a DMC request at the cycle
+	// dmc_at. Returns the cycles taken and the bytes written to OAMDATA.
+	fn run(dma: &mut Dma, start: u64, dmc_at: Option<u64>) -> (u64, Vec<u8>) {
+		let mut written = Vec::new();
+		let mut cycle = start;
+		while dma.active() || dmc_at.map(|at| cycle <= at).unwrap_or(false) {
+			if dmc_at == Some(cycle) {
+				dma.request_dmc(0xC000);
+			}
+			match dma.cycle(cycle.is_multiple_of(2)) {
+				DmaAccess::Read(address) => dma.read_done(address as u8),
+				DmaAccess::Write(address, value) => {
+					assert_eq!(OAM_DATA, address);
+					written.push(value);
+				}
+				DmaAccess::None => {}
+			}
+			cycle += 1;
+		}
+		(cycle - start, written)
+	}
+
+	#[test]
+	fn oam_dma_alignment() {
+		let mut dma = Dma::new();
+		dma.start_oam(0x02);
+		let (cycles, written) = run(&mut dma, 1, None);
+		assert_eq!(513, cycles);
+		assert_eq!((0..256).map(|i| i as u8).collect::<Vec<u8>>(), written);
+		dma.start_oam(0x02);
+		assert_eq!(514, run(&mut dma, 0, None).0);
+	}
+
+	#[test]
+	fn dmc_dma() {
+		// alone: halt, dummy, alignment if needed and the fetch
+		let mut dma = Dma::new();
+		assert_eq!(3, run(&mut dma, 0, Some(0)).0);
+		assert_eq!(Some(0x00), dma.take_dmc_byte());
+		assert_eq!(4, run(&mut dma, 1, Some(1)).0);
+
+		// in the middle of OAM DMA
+		dma.start_oam(0x02);
+		let (cycles, written) = run(&mut dma, 1, Some(101));
+		assert_eq!(515, cycles);
+		assert_eq!(256, written.len());
+		assert_eq!(Some(0x00), dma.take_dmc_byte());
+
+		// at the end: the last put cycle is the 513th
+		let end = |dmc_at: u64| {
+			let mut dma = Dma::new();
+			dma.start_oam(0x02);
+			run(&mut dma, 1, Some(dmc_at)).0
+		};
+		assert_eq!(514, end(511));
+		assert_eq!(516, end(513));
+		assert_eq!(515, end(509));
+	}
+}
@@ -0,0 +1,280 @@
+use cartridge::load_rom;
+use nes::Nes;
+use ppu::{Frame, SCREEN_WIDTH, SCREEN_HEIGHT};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process;
+
+// Visual regression testing: the frames of two runs, e.g. before and after
+// a PPU change, are compared pixel by pixel. Each pair of frames gives a
+// metric of how much they differ and an image of the differences, the
+// pixels which differ in red over a dimmed gray version of the first frame.
+
+// The differences of two frames.
+pub struct FrameDiff {
+	// of the first frame
+	pub number: u64,
+	pub differing_pixels: usize,
+	// Mean of the absolute differences of all color channels, 0 to 255.
+	pub mean_error: f64,
+	pub image: Frame,
+}
+
+impl FrameDiff {
+	pub fn is_identical(&self) -> bool {
+		self.differing_pixels == 0
+	}
+
+	// Share of the pixels which differ, 0 to 1.
+	pub fn share(&self) -> f64 {
+		self.differing_pixels as f64 / (SCREEN_WIDTH * SCREEN_HEIGHT) as f64
+	}
+}
+
+pub fn diff_frames(before: &Frame, after: &Frame) -> FrameDiff {
+	let mut image = Frame::new(before.number);
+	let mut differing_pixels = 0;
+	let mut error = 0u64;
+	for y in 0..SCREEN_HEIGHT {
+		for x in 0..SCREEN_WIDTH {
+			let (r0, g0, b0) = before.pixel(x, y);
+			let (r1, g1, b1) = after.pixel(x, y);
+			let delta = [(r0, r1), (g0, g1), (b0, b1)].iter()
+				.map(|&(a, b)| (a as i32 - b as i32).unsigned_abs() as u64)
+				.sum::<u64>();
+			if delta > 0 {
+				differing_pixels += 1;
+				error += delta;
+				image.set_pixel(x, y, 0xFF, 0x00, 0x00);
+			} else {
+				let gray = ((r0 as u32 + g0 as u32 + b0 as u32) / 9) as u8;
+				image.set_pixel(x, y, gray, gray, gray);
+			}
+		}
+	}
+	FrameDiff {
+		number: before.number,
+		differing_pixels,
+		mean_error: error as f64 / (SCREEN_WIDTH * SCREEN_HEIGHT * 3) as f64,
+		image,
+	}
+}
+
+// Writes the image of every differing frame to dir as PPM, named after its
+// frame number, and a summary.txt with a line per differing frame, e.g.
+//   frame 120: 1532 pixels (2.67%), mean error 1.204
+// Returns the number of differing frames.
+pub fn write_report(dir: &str, diffs: &[FrameDiff]) -> io::Result<usize> {
+	fs::create_dir_all(dir)?;
+	let mut summary = BufWriter::new(File::create(Path::new(dir).join("summary.txt"))?);
+	let mut differing = 0;
+	for diff in diffs.iter().filter(|diff| !diff.is_identical()) {
+		let path = Path::new(dir).join(format!("diff_{:06}.ppm", diff.number + 1));
+		File::create(&path).and_then(|file| diff.image.write_ppm(&mut BufWriter::new(file)))?;
+		writeln!(summary, "frame {}: {} pixels ({:.2}%), mean error {:.3}",
+			diff.number + 1, diff.differing_pixels, diff.share() * 100.0, diff.mean_error)?;
+		differing += 1;
+	}
+	if differing == 0 {
+		writeln!(summary, "{} frames, no differences", diffs.len())?;
+	}
+	summary.flush().map(|_| differing)
+}
+
+// Path of the baseline of a frame in dir, named like the screenshots of
+// capture, which records baselines.
+fn baseline_path(dir: &str, number: u64) -> String {
+	Path::new(dir).join(format!("frame_{:06}.ppm", number + 1)).to_string_lossy().into_owned()
+}
+
+// Runs the given number of frames and compares each with its baseline in
+// dir, until the first one which differs. Frames without a baseline are
+// skipped, so baselines of every 60th frame give a quicker, coarser check.
+// Returns the differing frame, if any, and the number of frames compared.
+pub fn first_difference(nes: &mut Nes, frames: u64, dir: &str) -> io::Result<(Option<FrameDiff>, u64)> {
+	let mut compared = 0;
+	for _ in 0..frames {
+		let frame = nes.run_frame();
+		let path = baseline_path(dir, frame.number);
+		let baseline = match File::open(&path) {
+			Ok(file) => Frame::read_ppm(&mut BufReader::new(file), frame.number)?,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+			Err(err) => return Err(err),
+		};
+		compared += 1;
+		let diff = diff_frames(&baseline, &frame);
+		if !diff.is_identical() {
+			return Ok((Some(diff), compared));
+		}
+	}
+	Ok((None, compared))
+}
+
+// Headless regression check: compare <rom> --against DIR [--frames N] [--out DIR]
+//
+// Runs the ROM like capture and reports the first frame which differs from
+// the baselines in the --against directory, recorded with e.g.
+//   capture <rom> --frames 600 --every 1 --out baseline
+// --out writes the diff image of that frame. The exit status is meant for
+// git bisect run: 0 if all frames match, 1 if one differs and 125, skip
+// this commit, if the ROM or the baselines cannot be used.
+pub fn run(args: &[String]) {
+	let mut rom_path = None;
+	let mut frames = 600;
+	let mut baseline_dir = None;
+	let mut out_dir = None;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_ref() {
+			"--frames" => {
+				frames = match args.next().and_then(|value| value.parse::<u64>().ok()) {
+					Some(value) if value > 0 => value,
+					_ => {
+						println!("--frames expects a positive number.");
+						process::exit(125);
+					}
+				};
+			}
+			"--against" | "--out" => {
+				let dir = match args.next() {
+					Some(dir) => dir.clone(),
+					None => {
+						println!("{} expects a directory.", arg);
+						process::exit(125);
+					}
+				};
+				if arg == "--against" { baseline_dir = Some(dir) } else { out_dir = Some(dir) }
+			}
+			_ => rom_path = Some(arg.clone()),
+		}
+	}
+	let (rom_path, baseline_dir) = match (rom_path, baseline_dir) {
+		(Some(rom_path), Some(baseline_dir)) => (rom_path, baseline_dir),
+		_ => {
+			println!("Usage: compare <rom> --against DIR [--frames N] [--out DIR]");
+			process::exit(125);
+		}
+	};
+
+	let cartridge = match load_rom(&rom_path) {
+		Ok(rom) => rom,
+		Err(err) => {
+			println!("Could not load ROM: {}", err);
+			process::exit(125);
+		}
+	};
+	let mut nes = Nes::new(cartridge);
+	let diff = match first_difference(&mut nes, frames, &baseline_dir) {
+		Ok((_, 0)) => {
+			println!("No baselines for the first {} frames in {}.", frames, baseline_dir);
+			process::exit(125);
+		}
+		Ok((None, compared)) => {
+			println!("All {} frames with a baseline match.", compared);
+			return;
+		}
+		Ok((Some(diff), _)) => diff,
+		Err(err) => {
+			println!("Could not compare with the baselines: {}", err);
+			process::exit(125);
+		}
+	};
+	println!("Frame {} differs: {} pixels ({:.2}%), mean error {:.3}",
+		diff.number + 1, diff.differing_pixels, diff.share() * 100.0, diff.mean_error);
+	if let Some(dir) = out_dir {
+		if let Err(err) = write_report(&dir, &[diff]) {
+			println!("Could not write the diff image to {}: {}", dir, err);
+		}
+	}
+	process::exit(1);
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::test_cartridge::TestCartridge;
+	use cpu::assemble;
+	use std::env;
+	use std::io::Read;
+
+	fn record_frames(nes: &mut Nes, count: usize) -> Vec<Frame> {
+		(0..count).map(|_| nes.run_frame()).collect()
+	}
+
+	// A longer run has its extra frames left out.
+	fn diff_sequences(before: &[Frame], after: &[Frame]) -> Vec<FrameDiff> {
+		before.iter().zip(after).map(|(before, after)| diff_frames(before, after)).collect()
+	}
+
+	// The backdrop is gray, after a while it changes to the color.
+	fn backdrop_change(color: u8) -> Nes {
+		let code = assemble(0x8000, &format!("
+			LDA #$3F; STA $2006; LDA #$00; STA $2006
+			LDA #$10; STA $2007
+			LDA #$00; STA $2006; STA $2006
+			LDX #$00; LDY #$00
+			DEY; BNE $801B; DEX; BNE $801B
+			LDA #$3F; STA $2006; LDA #$00; STA $2006
+			LDA #${:02X}; STA $2007
+			LDA #$00; STA $2006; STA $2006
+			JMP $8038", color)).unwrap();
+		Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()))
+	}
+
+	#[test]
+	fn frame_diffs() {
+		let run = |color: u8| record_frames(&mut backdrop_change(color), 30);
+		let before = run(0x10);
+		let after = run(0x16);
+		let diffs = diff_sequences(&before, &after[..20]);
+		assert_eq!(20, diffs.len());
+		assert!(diffs[0].is_identical());
+		assert_eq!(0.0, diffs[0].mean_error);
+		let last = &diffs[19];
+		assert_eq!(SCREEN_WIDTH * SCREEN_HEIGHT, last.differing_pixels);
+		assert_eq!(1.0, last.share());
+		assert!(last.mean_error > 10.0);
+		assert_eq!((0xFF, 0x00, 0x00), last.image.pixel(100, 100));
+		let (r, g, b) = before[0].pixel(0, 0);
+		let gray = ((r as u32 + g as u32 + b as u32) / 9) as u8;
+		assert!(gray > 0);
+		assert_eq!((gray, gray, gray), diffs[0].image.pixel(0, 0));
+
+		let dir = env::temp_dir().join(format!("nes-frame-diff-{}", ::std::process::id()));
+		let dir = dir.to_str().unwrap();
+		let differing = write_report(dir, &diffs).unwrap();
+		assert_eq!(diffs.iter().filter(|diff| !diff.is_identical()).count(), differing);
+		let mut summary = String::new();
+		File::open(Path::new(dir).join("summary.txt")).and_then(|mut file| file.read_to_string(&mut summary)).unwrap();
+		assert_eq!(differing, summary.lines().count());
+		assert!(summary.ends_with(&format!("frame 20: 61440 pixels (100.00%), mean error {:.3}\n", last.mean_error)));
+		assert!(Path::new(dir).join("diff_000020.ppm").exists());
+		fs::remove_dir_all(dir).unwrap();
+	}
+
+	#[test]
+	fn first_differing_frame() {
+		let dir = env::temp_dir().join(format!("nes-frame-baseline-{}", ::std::process::id()));
+		let dir = dir.to_str().unwrap();
+		fs::create_dir_all(dir).unwrap();
+		// baselines of every other frame
+		for frame in record_frames(&mut backdrop_change(0x10), 30).iter().filter(|frame| frame.number % 2 == 1) {
+			let mut file = File::create(baseline_path(dir, frame.number)).unwrap();
+			frame.write_ppm(&mut file).unwrap();
+		}
+
+		let (diff, compared) = first_difference(&mut backdrop_change(0x10), 30, dir).unwrap();
+		assert!(diff.is_none());
+		assert_eq!(15, compared);
+		let (diff, compared) = first_difference(&mut backdrop_change(0x16), 30, dir).unwrap();
+		let diff = diff.unwrap();
+		assert_eq!(1, diff.number % 2);
+		assert_eq!(diff.number / 2 + 1, compared);
+		// the frames in between may differ already
+		let diffs = diff_sequences(&record_frames(&mut backdrop_change(0x10), 30), &record_frames(&mut backdrop_change(0x16), 30));
+		assert!(diffs[..diff.number as usize].iter().filter(|diff| diff.number % 2 == 1).all(|diff| diff.is_identical()));
+		assert_eq!(diffs[diff.number as usize].differing_pixels, diff.differing_pixels);
+		fs::remove_dir_all(dir).unwrap();
+	}
+}
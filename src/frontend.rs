@@ -0,0 +1,1771 @@
+use cartridge::{load_rom_with_info, load_rom_with_submapper, supported_mappers, capability_names, Cartridge, RomInfo, RomDatabase};
+use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use nes::{Nes, ConsoleEvent, AccuracyPreset, EmulationSettings};
+use apu::{AudioSettings, ExpansionChip, ExpansionMix};
+use trace::{Tracer, TraceTrigger};
+use watchdog::Watchdog;
+use logging::{self, Level, WriteLogger};
+use game_settings::GameSettings;
+use machine::{MachineConfig, MachinePreset};
+use kiosk::{Kiosk, KioskEntry};
+use metrics::Metrics;
+use movie::{Movie, MovieSession, SavedState};
+use input_stream::{InputStream, StreamFormat};
+use region::Region;
+use repl::{self, Repl, RunControl};
+use wav::AudioRecorder;
+use sdl_audio::SdlAudio;
+use perf::PerfHud;
+use turbo_file::{self, TurboFile};
+use zapper::Zapper;
+use ambilight::Ambilight;
+use debug_view::DebugView;
+use palette::{self, PaletteEdit, PaletteEditor};
+use input;
+use crash;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Write, BufWriter};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::borrow::Borrow;
+use sdl2::VideoSubsystem;
+use sdl2::video::WindowBuilder;
+use sdl2::event::{Event, WindowEventId};
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::Mouse;
+use sdl2::render::{Renderer, RendererBuilder, Texture};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+
+// How often the window events and the keyboard are checked.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(4);
+
+// What the user sees of the emulator outside of the picture, kept apart from
+// the SDL code of run so the window title can be derived from it.
+pub struct FrontendState {
+	pub game_name: String,
+	// See Cartridge::name, shown after the game name.
+	pub mapper: Option<String>,
+	pub paused: bool,
+	pub fast_forward: bool,
+	// There is no on-screen display yet, so the FPS go to the title.
+	pub show_fps: bool,
+	// Shown first in the title, e.g. by the watchdog.
+	pub warning: Option<String>,
+	// Layer toggles of the picture, see Ppu::set_layers.
+	pub show_background: bool,
+	pub show_sprites: bool,
+	// Forced PPU rendering, see Ppu::set_render_enabled.
+	pub render_override: Option<bool>,
+	fps: f64,
+	frames: u32,
+	elapsed: Duration,
+}
+
+impl FrontendState {
+	// Uses the database title if known, the ROM file name otherwise.
+	pub fn new(title: Option<&str>, rom_path: &str) -> FrontendState {
+		let game_name = match title {
+			Some(title) => String::from(title),
+			None => Path::new(rom_path).file_stem()
+				.map(|stem| stem.to_string_lossy().into_owned())
+				.unwrap_or_else(|| String::from(rom_path)),
+		};
+		FrontendState {
+			game_name,
+			mapper: None,
+			paused: false,
+			fast_forward: false,
+			show_fps: true,
+			warning: None,
+			show_background: true,
+			show_sprites: true,
+			render_override: None,
+			fps: 0.0,
+			frames: 0,
+			elapsed: Duration::from_secs(0),
+		}
+	}
+
+	// Counts the frames shown in this much time. Returns true once per
+	// second when the FPS have been updated.
+	pub fn count_frames(&mut self, frames: u32, time: Duration) -> bool {
+		self.frames += frames;
+		self.elapsed += time;
+		if self.elapsed < Duration::from_secs(1) {
+			return false;
+		}
+		let seconds = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+		self.fps = self.frames as f64 / seconds;
+		self.frames = 0;
+		self.elapsed = Duration::from_secs(0);
+		true
+	}
+
+	// e.g. "Zelda (MMC1) — Kaini's NES Emulator [Fast forward | 240 FPS]"
+	pub fn window_title(&self) -> String {
+		let mut status = Vec::new();
+		if let Some(ref warning) = self.warning {
+			status.push(warning.clone());
+		}
+		if !self.show_background {
+			status.push(String::from("No background"));
+		}
+		if !self.show_sprites {
+			status.push(String::from("No sprites"));
+		}
+		match self.render_override {
+			Some(true) => status.push(String::from("Rendering forced on")),
+			Some(false) => status.push(String::from("Rendering forced off")),
+			None => {}
+		}
+		if self.paused {
+			status.push(String::from("Paused"));
+		} else if self.fast_forward {
+			status.push(String::from("Fast forward"));
+		}
+		if self.show_fps && !self.paused {
+			status.push(format!("{:.0} FPS", self.fps));
+		}
+		let mut title = match self.mapper {
+			Some(ref mapper) => format!("{} ({}) — Kaini's NES Emulator", self.game_name, mapper),
+			None => format!("{} — Kaini's NES Emulator", self.game_name),
+		};
+		if !status.is_empty() {
+			title.push_str(&format!(" [{}]", status.join(" | ")));
+		}
+		title
+	}
+}
+
+// Pixels cut from the edges of the picture, e.g. the leftmost column of
+// games which show scroll garbage there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+	pub left: u32,
+	pub top: u32,
+	pub right: u32,
+	pub bottom: u32,
+}
+
+// How the picture is fit into the window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoSettings {
+	pub crop: Crop,
+	// Fill the whole window. Otherwise the picture keeps its proportions,
+	// centered between black bars.
+	pub stretch: bool,
+}
+
+impl VideoSettings {
+	// The whole picture, stretched over the window.
+	pub fn new() -> VideoSettings {
+		VideoSettings {
+			crop: Crop { left: 0, top: 0, right: 0, bottom: 0 },
+			stretch: true,
+		}
+	}
+
+	// The part of the picture which is shown, as x, y, width and height.
+	pub fn source(&self) -> (u32, u32, u32, u32) {
+		let width = (SCREEN_WIDTH as u32).saturating_sub(self.crop.left + self.crop.right).max(1);
+		let height = (SCREEN_HEIGHT as u32).saturating_sub(self.crop.top + self.crop.bottom).max(1);
+		(self.crop.left.min(SCREEN_WIDTH as u32 - width), self.crop.top.min(SCREEN_HEIGHT as u32 - height), width, height)
+	}
+
+	// Where the shown part goes in a window of this size.
+	pub fn destination(&self, (window_width, window_height): (u32, u32)) -> (i32, i32, u32, u32) {
+		if self.stretch {
+			return (0, 0, window_width, window_height);
+		}
+		let (_, _, width, height) = self.source();
+		// the largest size with the proportions of the picture
+		let (scaled_width, scaled_height) =
+			if window_width as u64 * height as u64 <= window_height as u64 * width as u64 {
+				(window_width, (window_width as u64 * height as u64 / width as u64) as u32)
+			} else {
+				((window_height as u64 * width as u64 / height as u64) as u32, window_height)
+			};
+		(((window_width - scaled_width) / 2) as i32, ((window_height - scaled_height) / 2) as i32,
+			scaled_width, scaled_height)
+	}
+
+	// The pixel of the picture shown at a position in the window, None on
+	// the bars.
+	pub fn picture_position(&self, window: (u32, u32), x: i32, y: i32) -> Option<(usize, usize)> {
+		let (source_x, source_y, width, height) = self.source();
+		let (dest_x, dest_y, dest_width, dest_height) = self.destination(window);
+		let (x, y) = (x - dest_x, y - dest_y);
+		if x < 0 || y < 0 || x as u32 >= dest_width || y as u32 >= dest_height {
+			return None;
+		}
+		Some(((source_x + x as u32 * width / dest_width) as usize,
+			(source_y + y as u32 * height / dest_height) as usize))
+	}
+}
+
+// Keeps the frames at the speed of the console: the next frame is due one
+// frame time after the previous one was due, so sleeping too long once does
+// not slow down the game.
+pub struct FramePacer {
+	frame_time: Duration,
+	next_frame: Instant,
+}
+
+impl FramePacer {
+	pub fn new(frame_time: Duration, now: Instant) -> FramePacer {
+		FramePacer {
+			frame_time,
+			next_frame: now,
+		}
+	}
+
+	// Time until the next frame is due, zero if it is.
+	pub fn wait_time(&self, now: Instant) -> Duration {
+		self.next_frame.saturating_duration_since(now)
+	}
+
+	// Schedules the next frame after one was shown. If the frames fell
+	// behind by more than one, e.g. after fast forward or a pause, the pace
+	// starts over instead of catching up at full speed.
+	pub fn frame_done(&mut self, now: Instant) {
+		self.next_frame += self.frame_time;
+		if self.next_frame + self.frame_time < now {
+			self.next_frame = now;
+		}
+	}
+}
+
+// The emulator in a window, see the usage printed without a ROM.
+pub fn run(args: &[String]) {
+	let mut rom_path = String::new();
+	let mut preset = AccuracyPreset::Accuracy;
+	let mut autosave = false;
+	let mut watch = false;
+	let mut watch_keep_state = false;
+	let mut trace_path = None;
+	let mut trace_start = None;
+	let mut trace_stop = None;
+	let mut trace_ring = 10000;
+	let mut log_target = None;
+	let mut log_level = None;
+	let mut database_path = None;
+	let mut record_audio_path = None;
+	let mut sample_rate = 44100;
+	let mut play_audio = true;
+	let mut perf_hud = false;
+	let mut repl_enabled = false;
+	let mut input_stream_format = None;
+	let mut turbo_file_path = None;
+	let mut entry = None;
+	let mut pc_log_path = None;
+	let mut watchdog_cycles = 5_000_000;
+	let mut region = None;
+	let mut machine_preset = None;
+	let mut ram_kib = None;
+	let mut zapper_radius = None;
+	let mut ambilight_enabled = false;
+	let mut audio_settings = AudioSettings::from_preset(ExpansionMix::Famicom);
+	let mut expansion_levels = Vec::new();
+	let mut random_ram = false;
+	let mut tolerate_mapper_faults = false;
+	let mut overclock_lines = 0;
+	let mut palette_path = None;
+	let mut overclock_prerender_lines = 0;
+	let mut seed = None;
+	let mut record_movie_path = None;
+	let mut play_movie_path = None;
+	let mut kiosk_path = None;
+	let mut kiosk_minutes = 3;
+	let mut metrics_path = None;
+	let mut metrics_interval = 10;
+	let mut args = args.iter().cloned();
+	while let Some(arg) = args.next() {
+		match arg.as_ref() {
+			"--preset" => {
+				preset = match args.next().and_then(|name| AccuracyPreset::from_name(&name)) {
+					Some(preset) => preset,
+					None => {
+						println!("--preset expects one of: accuracy, balanced, speed.");
+						return;
+					}
+				};
+			}
+			// overrides the region from the header and the database
+			"--region" => {
+				region = match args.next().and_then(|name| Region::from_name(&name)) {
+					Some(region) => Some(region),
+					None => {
+						println!("--region expects ntsc or pal.");
+						return;
+					}
+				};
+			}
+			// overrides the machine of the game settings
+			"--machine" => {
+				machine_preset = match args.next().and_then(|name| MachinePreset::from_name(&name)) {
+					Some(preset) => Some(preset),
+					None => {
+						println!("--machine expects one of: famicom, nes, famiclone.");
+						return;
+					}
+				};
+			}
+			// applied after the machine, whatever the order
+			"--ram-kib" => {
+				ram_kib = args.next().and_then(|kib| kib.parse::<u16>().ok());
+				if ram_kib.is_none() {
+					println!("--ram-kib expects a number of KiB.");
+					return;
+				}
+			}
+			"--expansion-mix" => {
+				audio_settings = match args.next().and_then(|name| ExpansionMix::from_name(&name)) {
+					Some(mix) => AudioSettings::from_preset(mix),
+					None => {
+						println!("--expansion-mix expects famicom or nes.");
+						return;
+					}
+				};
+			}
+			"--no-expansion-audio" => audio_settings.expansion_audio = false,
+			// applied after the preset, whatever the order
+			"--expansion-level" => {
+				match args.next().and_then(|text| parse_expansion_level(&text)) {
+					Some(level) => expansion_levels.push(level),
+					None => {
+						println!("--expansion-level expects CHIP=LEVEL like fds=0.8, the chips are: fds, vrc6, vrc7, mmc5, n163, 5b.");
+						return;
+					}
+				}
+			}
+			"--random-ram" => random_ram = true,
+			"--tolerate-mapper-faults" => tolerate_mapper_faults = true,
+			// extra scanlines before vblank, and before pre-render
+			"--overclock" | "--overclock-prerender" => {
+				let lines = match args.next().and_then(|lines| lines.parse().ok()) {
+					Some(lines) => lines,
+					None => {
+						println!("{} expects a number of extra scanlines.", arg);
+						return;
+					}
+				};
+				if arg == "--overclock" { overclock_lines = lines } else { overclock_prerender_lines = lines }
+			}
+			// for the random features, from the time by default
+			"--seed" => {
+				seed = args.next().and_then(|seed| seed.parse::<u64>().ok());
+				if seed.is_none() {
+					println!("--seed expects a number.");
+					return;
+				}
+			}
+			"--autosave" => autosave = true,
+			"--perf-hud" => perf_hud = true,
+			"--repl" => repl_enabled = true,
+			// buttons piped to stdin, see InputStream
+			"--input-stream" => {
+				input_stream_format = args.next().and_then(|name| StreamFormat::from_name(&name));
+				if input_stream_format.is_none() {
+					println!("--input-stream expects text or binary.");
+					return;
+				}
+			}
+			"--zapper" => zapper_radius = zapper_radius.or(Some(DEFAULT_ZAPPER_RADIUS)),
+			"--ambilight" => ambilight_enabled = true,
+			"--zapper-radius" => {
+				zapper_radius = args.next().and_then(|pixels| pixels.parse().ok());
+				if zapper_radius.is_none() {
+					println!("--zapper-radius expects a number of pixels.");
+					return;
+				}
+			}
+			"--watch" => watch = true,
+			"--watch-keep-state" => {
+				watch = true;
+				watch_keep_state = true;
+			}
+			"--trace" => {
+				trace_path = args.next();
+				if trace_path.is_none() {
+					println!("--trace expects a file name.");
+					return;
+				}
+			}
+			"--trace-start" | "--trace-stop" => {
+				let trigger = match args.next().and_then(|text| TraceTrigger::parse(&text)) {
+					Some(trigger) => trigger,
+					None => {
+						println!("{} expects a PC like C000 or a RAM condition like 0010=05.", arg);
+						return;
+					}
+				};
+				if arg == "--trace-start" { trace_start = Some(trigger) } else { trace_stop = Some(trigger) }
+			}
+			"--log" => {
+				log_target = args.next();
+				if log_target.is_none() {
+					println!("--log expects stdout, stderr or a file name.");
+					return;
+				}
+			}
+			"--database" => {
+				database_path = args.next();
+				if database_path.is_none() {
+					println!("--database expects a No-Intro DAT file.");
+					return;
+				}
+			}
+			"--record-audio" => {
+				record_audio_path = args.next();
+				if record_audio_path.is_none() {
+					println!("--record-audio expects a WAV file name.");
+					return;
+				}
+			}
+			// the rate asked of the audio device, the APU is resampled to it
+			"--sample-rate" => {
+				match args.next().and_then(|rate| rate.parse::<u32>().ok()) {
+					Some(rate) if (8000..=192000).contains(&rate) => sample_rate = rate,
+					_ => {
+						println!("--sample-rate expects a rate from 8000 to 192000 Hz.");
+						return;
+					}
+				}
+			}
+			"--mute" => play_audio = false,
+			"--log-level" => {
+				log_level = args.next().and_then(|name| Level::from_name(&name));
+				if log_level.is_none() {
+					println!("--log-level expects one of: error, warn, info, debug.");
+					return;
+				}
+			}
+			"--record-movie" | "--play-movie" => {
+				let path = args.next();
+				if path.is_none() {
+					println!("{} expects a file name.", arg);
+					return;
+				}
+				if arg == "--record-movie" { record_movie_path = path } else { play_movie_path = path }
+			}
+			"--kiosk" => {
+				kiosk_path = args.next();
+				if kiosk_path.is_none() {
+					println!("--kiosk expects a playlist file.");
+					return;
+				}
+			}
+			"--kiosk-minutes" => {
+				kiosk_minutes = match args.next().and_then(|minutes| minutes.parse().ok()) {
+					Some(minutes) if minutes > 0 => minutes,
+					_ => {
+						println!("--kiosk-minutes expects a number of minutes.");
+						return;
+					}
+				};
+			}
+			"--metrics" => {
+				metrics_path = args.next();
+				if metrics_path.is_none() {
+					println!("--metrics expects a file name, .json for JSON, Prometheus text otherwise.");
+					return;
+				}
+			}
+			"--metrics-interval" => {
+				metrics_interval = match args.next().and_then(|seconds| seconds.parse().ok()) {
+					Some(seconds) if seconds > 0 => seconds,
+					_ => {
+						println!("--metrics-interval expects a number of seconds.");
+						return;
+					}
+				};
+			}
+			"--palette" => {
+				palette_path = args.next();
+				if palette_path.is_none() {
+					println!("--palette expects a .pal file.");
+					return;
+				}
+			}
+			"--turbo-file" => {
+				turbo_file_path = args.next();
+				if turbo_file_path.is_none() {
+					println!("--turbo-file expects a file name.");
+					return;
+				}
+			}
+			"--entry" => {
+				entry = args.next().and_then(|text| parse_address(&text));
+				if entry.is_none() {
+					println!("--entry expects an address like 0xC000.");
+					return;
+				}
+			}
+			"--pc-log" => {
+				pc_log_path = args.next();
+				if pc_log_path.is_none() {
+					println!("--pc-log expects a file name.");
+					return;
+				}
+			}
+			"--watchdog" => {
+				watchdog_cycles = match args.next().and_then(|millions: String| millions.parse::<f64>().ok()) {
+					Some(millions) if millions >= 0.0 => (millions * 1e6) as u64,
+					_ => {
+						println!("--watchdog expects millions of CPU cycles, 0 turns it off.");
+						return;
+					}
+				};
+			}
+			"--trace-ring" => {
+				trace_ring = match args.next().and_then(|lines| lines.parse().ok()) {
+					Some(lines) => lines,
+					None => {
+						println!("--trace-ring expects a number of lines.");
+						return;
+					}
+				};
+			}
+			_ => rom_path = arg,
+		}
+	}
+	// With --kiosk the games of a playlist take turns, starting with the
+	// first one, see Kiosk.
+	let mut kiosk = None;
+	if let Some(ref path) = kiosk_path {
+		if record_movie_path.is_some() || play_movie_path.is_some() || !rom_path.is_empty() {
+			println!("--kiosk takes the ROMs and movies from the playlist.");
+			return;
+		}
+		match Kiosk::load(path, Duration::from_secs(kiosk_minutes * 60)) {
+			Ok(playlist) => {
+				rom_path = playlist.current().rom.clone();
+				play_movie_path = playlist.current().movie.clone();
+				kiosk = Some(playlist);
+			}
+			Err(err) => {
+				println!("Could not load playlist: {}", err);
+				return;
+			}
+		}
+	}
+	if rom_path.is_empty() {
+		println!("Missing first argument: Path to ROM file.");
+		return;
+	}
+	for &(chip, level) in expansion_levels.iter() {
+		audio_settings.set_expansion_level(chip, level);
+	}
+	if trace_path.is_some() && pc_log_path.is_some() {
+		println!("--trace and --pc-log cannot be combined.");
+		return;
+	}
+
+	// Diagnostics of the core go to stdout by default.
+	if log_target.is_some() || log_level.is_some() {
+		let out: Box<dyn Write + Send> = match log_target.as_ref().map(|target| target.as_ref()) {
+			None | Some("stdout") => Box::new(io::stdout()),
+			Some("stderr") => Box::new(io::stderr()),
+			Some(path) => match File::create(path) {
+				Ok(file) => Box::new(file),
+				Err(err) => {
+					println!("Could not create log file: {}", err);
+					return;
+				}
+			},
+		};
+		logging::set_logger(Some(Box::new(WriteLogger::new(out, log_level.unwrap_or(Level::Info)))));
+	}
+
+	println!("Loading ROM {}.", rom_path);
+	let (mut cartridge, mut rom_info) = match load_rom_with_info(rom_path.borrow()) {
+		Ok(rom) => rom,
+		Err(err) => {
+			println!("Could not load ROM: {}", err);
+			let mappers: Vec<String> = supported_mappers().iter().map(|m| format!("{:03}", m)).collect();
+			println!("Supported mappers: {}", mappers.join(", "));
+			return;
+		}
+	};
+
+	// Identify the game with the optional database.
+	let mut title = None;
+	if let Some(ref path) = database_path {
+		match RomDatabase::load(path) {
+			Ok(database) => {
+				if database.is_empty() {
+					println!("The database has no games, expected a No-Intro DAT file.");
+				} else {
+					println!("Loaded {} games from the database.", database.len());
+				}
+				title = database.find(&rom_info).map(String::from);
+				match title {
+					Some(ref title) => println!("Identified as {}.", title),
+					None => println!("ROM not found in the database."),
+				}
+				// The database knows the board revision of some games, e.g.
+				// the MMC3 with the old IRQ behavior.
+				match database.submapper(&rom_info) {
+					Some(submapper) if submapper != rom_info.submapper => {
+						println!("Using submapper {} from the database.", submapper);
+						match load_rom_with_submapper(rom_path.borrow(), Some(submapper)) {
+							Ok(rom) => {
+								cartridge = rom.0;
+								rom_info = rom.1;
+							}
+							Err(err) => println!("Could not reload ROM: {}", err),
+						}
+					}
+					_ => {}
+				}
+			}
+			Err(err) => println!("Could not load database: {}", err),
+		}
+	}
+
+	println!("{}", describe_mapper(&*cartridge));
+
+	// Per-game overrides, e.g. of the crop.
+	let mut game_settings = match GameSettings::load(&rom_info) {
+		Ok(settings) => settings,
+		Err(err) => {
+			println!("Could not load game settings: {}", err);
+			GameSettings::new()
+		}
+	};
+
+	let mut machine = machine_preset.map(MachineConfig::from_preset)
+		.or_else(|| game_settings.machine.clone())
+		.unwrap_or_else(MachineConfig::new);
+	if let Some(kib) = ram_kib {
+		if let Err(err) = machine.set_ram_kib(kib) {
+			println!("{}", err);
+			return;
+		}
+	}
+
+	// Most iNES headers do not tell the region, so games only released in
+	// PAL countries are recognized by their title. NTSC is the default.
+	let region = match (region, rom_info.region, title.as_ref().and_then(|title| Region::from_title(title))) {
+		(Some(region), _, _) => region,
+		(None, Some(region), _) => region,
+		(None, None, Some(region)) => {
+			if region == Region::Pal {
+				println!("Using PAL timing for a game released in PAL countries only, --region ntsc overrides.");
+			}
+			region
+		}
+		(None, None, None) => Region::Ntsc,
+	};
+
+	// A movie records the input of every frame from power on, with
+	// checkpoints which tell where a replay diverged, see movie.rs.
+	if record_movie_path.is_some() && play_movie_path.is_some() {
+		println!("--record-movie and --play-movie cannot be combined.");
+		return;
+	}
+	let mut movie_session = None;
+	if let Some(ref path) = play_movie_path {
+		match Movie::load(path) {
+			Ok(movie) => {
+				if movie.rom_sha1 != rom_info.sha1_hex() {
+					println!("The movie was recorded with another ROM, it will likely diverge.");
+				}
+				movie_session = Some(MovieSession::play(movie));
+			}
+			Err(err) => {
+				println!("Could not load movie: {}", err);
+				return;
+			}
+		}
+	} else if record_movie_path.is_some() {
+		movie_session = Some(MovieSession::record(Movie::new(&rom_info.sha1_hex(), region)));
+	}
+	let region = movie_session.as_ref().map(|session| session.movie().region).unwrap_or(region);
+
+	// The tracer keeps the last instructions to dump them on a crash. It is
+	// switched by F3, the triggers, or on from the start with --trace alone.
+	// --pc-log is a trace without register annotations, in the format of
+	// the nestest log, to compare with the logs of other emulators.
+	let mut tracer = Tracer::new(trace_ring);
+	tracer.set_triggers(trace_start, trace_stop);
+	if let Some(ref path) = trace_path.as_ref().or(pc_log_path.as_ref()) {
+		match File::create(path) {
+			Ok(file) => tracer.set_file(Some(Box::new(BufWriter::new(file)))),
+			Err(err) => {
+				println!("Could not create trace file: {}", err);
+				return;
+			}
+		}
+		tracer.set_active(trace_start.is_none());
+	}
+
+	let mut nes = Nes::new(cartridge);
+	let mut settings = EmulationSettings::from_preset(preset);
+	settings.random_ram = random_ram;
+	settings.tolerate_mapper_faults = tolerate_mapper_faults;
+	settings.overclock_lines = overclock_lines;
+	settings.overclock_prerender_lines = overclock_prerender_lines;
+	if overclock_lines + overclock_prerender_lines > 0 {
+		println!("Overclocking by {} scanlines per frame. This is inaccurate and can break games.",
+			overclock_lines + overclock_prerender_lines);
+	}
+	nes.set_settings(settings);
+	nes.set_audio_settings(audio_settings);
+	// The seed is shown to repeat a run with the same random numbers.
+	let seed = seed.unwrap_or_else(unix_time);
+	if random_ram {
+		println!("Random seed {}, --seed repeats it.", seed);
+	}
+	nes.set_seed(seed);
+	nes.set_machine(machine);
+	nes.set_region(region);
+	println!("{} KiB of RAM.", nes.ram_size() / 1024);
+	nes.set_annotate_io(pc_log_path.is_none());
+	if let Some(ref path) = palette_path {
+		match palette::load_pal(path) {
+			Ok(palette) => nes.set_master_palette(palette),
+			Err(err) => {
+				println!("Could not load palette: {}", err);
+				return;
+			}
+		}
+	}
+	// Test ROMs like nestest can be automated from an entry point other
+	// than the reset vector.
+	if let Some(addr) = entry {
+		nes.set_pc(addr);
+	}
+
+	// With --zapper the mouse aims the Zapper in port 2, see zapper_event.
+	if let Some(radius) = zapper_radius {
+		nes.set_zapper(Some(Zapper::new(radius)));
+	}
+
+	// With --ambilight the bars around the picture take the colors of its
+	// edges, see Ambilight.
+	let ambilight = if ambilight_enabled {
+		let light = Ambilight::new();
+		let color = light.color();
+		nes.set_scanline_output(Some(Box::new(light)));
+		Some(color)
+	} else {
+		None
+	};
+
+	// The Turbo File keeps its contents in a file of its own, like a battery.
+	if let Some(ref path) = turbo_file_path {
+		match TurboFile::load(path) {
+			Ok(device) => nes.set_expansion_device(Some(Box::new(device))),
+			Err(err) => {
+				println!("Could not load Turbo File: {}", err);
+				return;
+			}
+		}
+	}
+
+	// With --autosave the console state is saved on quit and can be resumed
+	// on the next launch of the same ROM.
+	// The boot macro of the game presses buttons after power on, until the
+	// live input takes over. Not when resuming, and not with a movie.
+	let mut boot_macro = if movie_session.is_none() { game_settings.boot_macro.clone() } else { None };
+	let mut state_path = if autosave { Some(autosave_path(&rom_info)) } else { None };
+	if let (Some(ref path), None) = (state_path.as_ref(), movie_session.as_ref()) {
+		if Path::new(path).exists() && confirm("Resume from the state saved on last exit?") {
+			match File::open(path).and_then(|mut file| nes.load_state(&mut file)) {
+				Ok(()) => {
+					println!("Resumed from {}.", path);
+					boot_macro = None;
+				}
+				// the console stays powered on as it was
+				Err(err) => println!("Could not load state: {}", err),
+			}
+		}
+	}
+
+	let sdl = sdl2::init().unwrap();
+	let sdl_video = sdl.video().unwrap();
+	let mut sdl_event_pump = sdl.event_pump().unwrap();
+	// Without an audio device the game runs silently.
+	let sdl_audio = if play_audio {
+		match SdlAudio::open(&sdl, sample_rate) {
+			Ok(audio) => Some(audio),
+			Err(err) => {
+				println!("Could not open the audio device, running without sound: {}", err);
+				None
+			}
+		}
+	} else {
+		None
+	};
+	if let Some(ref audio) = sdl_audio {
+		nes.set_audio_output(Some(audio.output()));
+	}
+	let mut frontend = FrontendState::new(title.as_ref().map(|title| title.as_ref()), &rom_path);
+	frontend.mapper = Some(String::from(nes.cartridge().name()));
+	let win = WindowBuilder::new(&sdl_video, &frontend.window_title(), 256 * 4, 240 * 4).build().unwrap();
+	let mut renderer = RendererBuilder::new(win).build().unwrap();
+	// ABGR8888 is RGBA in memory on little endian machines
+	let mut texture = renderer.create_texture_streaming(PixelFormatEnum::ABGR8888, 256, 240).unwrap();
+	let main_window_id = renderer.window().map(|window| window.id()).unwrap_or(0);
+
+	// Warns when the game stops accessing the hardware, which usually means
+	// it crashed. 5 million cycles are almost 3 seconds.
+	let mut watchdog = if watchdog_cycles > 0 { Some(Watchdog::new(watchdog_cycles)) } else { None };
+
+	// Buttons of controller 1 held on the keyboard. While a movie records
+	// or plays, they are only applied at the start of a frame, and after
+	// the boot macro.
+	let mut buttons = 0;
+	if let Some(ref mut session) = movie_session {
+		session.start_frame(&mut nes, [buttons, 0]);
+	}
+	if let Some(ref boot) = boot_macro {
+		nes.set_buttons(0, boot.buttons(nes.ppu().frame_number()).unwrap_or(0));
+	}
+
+	// Debug views open in windows of their own, F7 toggles the PPU viewer.
+	let mut debug_windows: Vec<DebugWindow> = Vec::new();
+
+	// With --watch the ROM is reloaded when the file changes, e.g. after
+	// assembling a new version.
+	let mut rom_modified = modified_time(&rom_path);
+	let mut last_watch_check = Instant::now();
+
+	// Audio is recorded from the start with --record-audio, F5 starts and
+	// stops further recordings.
+	let mut audio_recorder = record_audio_path.map(AudioRecorder::new);
+
+	// With --input-stream the buttons of both controllers come from stdin
+	// instead of the keyboard, after the boot macro and unless a movie
+	// plays. The emulation does not wait for them, records which arrive
+	// late apply from the next frame on.
+	if repl_enabled && input_stream_format.is_some() {
+		println!("--repl and --input-stream cannot be combined, both read stdin.");
+		return;
+	}
+	let mut input_stream = input_stream_format.map(InputStream::stdin);
+
+	// With --repl, debugger commands are read from stdin on a thread of
+	// their own and run between two polls of the events.
+	let mut repl = None;
+	let mut repl_lines = None;
+	if repl_enabled {
+		let (sender, receiver) = mpsc::channel();
+		thread::spawn(move || {
+			let stdin = io::stdin();
+			for line in stdin.lock().lines() {
+				if line.map(|line| sender.send(line)).is_err() {
+					break;
+				}
+			}
+		});
+		println!("Debugger ready, type help for the commands.");
+		repl = Some(Repl::new());
+		repl_lines = Some(receiver);
+	}
+
+	// A crash of the emulation is reported with the state of the console,
+	// see the end of the emulation below.
+	crash::install_hook();
+
+	// Frame times for the performance HUD, toggled by F4.
+	let mut hud = PerfHud::new();
+	let mut emulation_time = Duration::from_secs(0);
+	let mut render_time = Duration::from_secs(0);
+
+	// With --metrics the runtime metrics are written to a file every few
+	// seconds, see Metrics.
+	let mut metrics = Metrics::new(Instant::now());
+	let mut last_metrics_write = Instant::now();
+
+	// A frame is presented once it is complete, and only then, at the speed
+	// of the console unless fast forwarding. The events are polled every few
+	// milliseconds, however long a frame takes to emulate.
+	let mut pacer = FramePacer::new(nes.region().frame_time(), Instant::now());
+	let mut last_poll = Instant::now();
+	let mut last_loop = Instant::now();
+	let mut quit = false;
+	let mut next_game = false;
+	let mut was_paused = false;
+	// F10 saves a state in memory and F11 loads it. While a movie records,
+	// loading re-records from the frame of the state, F12 undoes the load.
+	let mut quick_state: Option<SavedState> = None;
+	while !quit {
+		// The kiosk moves on to the next game when its time is over or the
+		// coin key was pressed. Games which fail to load are skipped.
+		if let Some(ref mut playlist) = kiosk {
+			if next_game || playlist.due(Instant::now()) {
+				next_game = false;
+				for _ in 0..playlist.len() {
+					let entry = playlist.advance(Instant::now()).clone();
+					if let Some((settings, session)) = start_kiosk_entry(&mut nes, &entry) {
+						game_settings = settings;
+						movie_session = session;
+						boot_macro = if movie_session.is_none() { game_settings.boot_macro.clone() } else { None };
+						if let Some(ref boot) = boot_macro {
+							nes.set_buttons(0, boot.buttons(nes.ppu().frame_number()).unwrap_or(0));
+						}
+						if let Some(radius) = zapper_radius {
+							nes.set_zapper(Some(Zapper::new(radius)));
+						}
+						if let Some(ref repl) = repl {
+							repl.apply_to(&mut nes);
+						}
+						rom_path = entry.rom;
+						rom_modified = modified_time(&rom_path);
+						frontend.game_name = FrontendState::new(None, &rom_path).game_name;
+						frontend.mapper = Some(String::from(nes.cartridge().name()));
+						frontend.warning = None;
+						update_title(&mut renderer, &frontend);
+						pacer = FramePacer::new(nes.region().frame_time(), Instant::now());
+						break;
+					}
+				}
+			}
+		}
+
+		if watch && last_watch_check.elapsed() >= Duration::from_millis(500) {
+			last_watch_check = Instant::now();
+			let modified = modified_time(&rom_path);
+			if modified != rom_modified {
+				rom_modified = modified;
+				println!("ROM file changed, reloading {}.", rom_path);
+				if let Some(info) = reload_rom(&mut nes, &rom_path, watch_keep_state) {
+					if autosave {
+						state_path = Some(autosave_path(&info));
+					}
+					if let Some(radius) = zapper_radius {
+						nes.set_zapper(Some(Zapper::new(radius)));
+					}
+					if let Some(ref repl) = repl {
+						repl.apply_to(&mut nes);
+					}
+					frontend.mapper = Some(String::from(nes.cartridge().name()));
+					update_title(&mut renderer, &frontend);
+				}
+			}
+		}
+
+		// no samples were taken while paused, so the audio fades in again
+		if was_paused && !frontend.paused {
+			nes.resume_audio();
+		}
+		if was_paused != frontend.paused {
+			if let Some(ref audio) = sdl_audio {
+				audio.set_paused(frontend.paused);
+			}
+		}
+		was_paused = frontend.paused;
+		let running = !frontend.paused && (frontend.fast_forward || pacer.wait_time(Instant::now()) == Duration::from_secs(0));
+		let mut completed_frame = None;
+		let mut breakpoint_hit = false;
+		let mut watch_hit = None;
+		if running {
+			let emulation_start = Instant::now();
+			let result = panic::catch_unwind(AssertUnwindSafe(|| {
+				while last_poll.elapsed() < EVENT_POLL_INTERVAL {
+					for _ in 0..100 {
+						if repl.as_mut().map(|repl: &mut Repl| repl.check_breakpoint(nes.pc())).unwrap_or(false) {
+							breakpoint_hit = true;
+							return None;
+						}
+						tracer.update(&nes);
+						let mut instr_log: Option<&mut dyn Write> = if tracer.is_active() { Some(&mut tracer) } else { None };
+						nes.step(&mut instr_log);
+						watch_hit = nes.take_watch_hit();
+						if watch_hit.is_some() {
+							return None;
+						}
+						if let Some(frame) = nes.take_frame() {
+							return Some(frame);
+						}
+					}
+				}
+				None
+			}));
+			completed_frame = match result {
+				Ok(frame) => frame,
+				Err(err) => {
+					let dir = format!("crashes/crash_{}", unix_time());
+					match crash::write_report(&dir, &nes, &tracer, &rom_path) {
+						Ok(()) => println!("Wrote a crash report with the last {} traced instructions to {}.", tracer.lines().len(), dir),
+						Err(dump_err) => println!("Could not write crash report: {}", dump_err),
+					}
+					panic::resume_unwind(err);
+				}
+			};
+			emulation_time += emulation_start.elapsed();
+			if breakpoint_hit {
+				println!("Breakpoint at ${:04X}.\n{}", nes.pc(), repl::registers(&nes));
+			}
+			if let Some(ref hit) = watch_hit {
+				println!("{}\n{}", repl::describe_watch_hit(hit), repl::registers(&nes));
+			}
+			if breakpoint_hit || watch_hit.is_some() {
+				frontend.paused = true;
+				update_title(&mut renderer, &frontend);
+			}
+
+			if let Some(chunk) = nes.take_audio() {
+				let result = audio_recorder.as_mut().map(|recorder| recorder.record(&chunk)).unwrap_or(Ok(()));
+				if let Err(err) = result {
+					println!("Could not record audio: {}", err);
+					audio_recorder = None;
+				}
+			}
+		}
+
+		let mut frames = 0;
+		if completed_frame.is_some() {
+			let movie_ended = match movie_session {
+				Some(ref mut session) => {
+					let result = session.end_frame(&nes);
+					if let Err(ref err) = result {
+						println!("Movie playback stopped: {}", err);
+						metrics.record_desync();
+					}
+					result.is_err() || !session.start_frame(&mut nes, [buttons, 0])
+				}
+				None => false,
+			};
+			if movie_ended {
+				let session = movie_session.take().unwrap();
+				println!("The movie ended after {} frames.", session.frame());
+				nes.set_buttons(0, buttons);
+				nes.set_buttons(1, 0);
+			}
+			if let Some(boot) = boot_macro.take() {
+				match boot.buttons(nes.ppu().frame_number()) {
+					Some(held) => {
+						nes.set_buttons(0, held);
+						boot_macro = Some(boot);
+					}
+					None => nes.set_buttons(0, buttons),
+				}
+			}
+			let stream_finished = match input_stream {
+				Some(ref mut stream) => {
+					let streamed = stream.buttons(nes.ppu().frame_number(), false);
+					if movie_session.is_none() && boot_macro.is_none() {
+						nes.set_buttons(0, streamed[0]);
+						nes.set_buttons(1, streamed[1]);
+					}
+					stream.finished()
+				}
+				None => false,
+			};
+			// the keyboard takes over after the last record
+			if stream_finished {
+				input_stream = None;
+				println!("The input stream ended.");
+			}
+		}
+		if let Some(mut frame) = completed_frame {
+			let render_start = Instant::now();
+			pacer.frame_done(render_start);
+			hud.record(emulation_time, render_time, sdl_audio.as_ref().map(|audio| audio.fill()));
+			hud.record_bus(nes.take_bus_stats());
+			let frame_emulation_time = emulation_time;
+			emulation_time = Duration::from_secs(0);
+			if perf_hud {
+				hud.draw(&mut frame);
+			}
+			texture.update(None, &frame.pixels, SCREEN_WIDTH * 4).unwrap();
+			present(&mut renderer, &texture, &game_settings.video, bar_color(&ambilight));
+			for window in debug_windows.iter_mut() {
+				window.update(&mut nes);
+			}
+			render_time = render_start.elapsed();
+			let underrun = sdl_audio.as_ref().map(|audio| audio.take_underrun()).unwrap_or(false);
+			metrics.record_frame(frame_emulation_time + render_time, underrun, Instant::now());
+			frames = 1;
+		}
+		if let Some(ref path) = metrics_path {
+			if last_metrics_write.elapsed() >= Duration::from_secs(metrics_interval) {
+				last_metrics_write = Instant::now();
+				if let Err(err) = metrics.write_file(path, &frontend.game_name, Instant::now()) {
+					println!("Could not write metrics: {}", err);
+					metrics_path = None;
+				}
+			}
+		}
+
+		let mut title_changed = frontend.count_frames(frames, last_loop.elapsed());
+		let hung = watchdog.as_mut().map(|watchdog| frames > 0 && watchdog.check(&nes)).unwrap_or(false);
+		if frames > 0 && hung != frontend.warning.is_some() {
+			frontend.warning = if hung {
+				println!("The game stopped responding at PC {:04X}, F1 resets the console.", nes.pc());
+				Some(String::from("Not responding, F1 resets"))
+			} else {
+				None
+			};
+			title_changed = true;
+		}
+		if title_changed {
+			update_title(&mut renderer, &frontend);
+		}
+		last_loop = Instant::now();
+
+		if last_poll.elapsed() < EVENT_POLL_INTERVAL {
+			if running {
+				continue;
+			}
+			// waiting for the next frame or paused
+			let wait = if frontend.paused { EVENT_POLL_INTERVAL } else { pacer.wait_time(Instant::now()) };
+			thread::sleep(wait.min(EVENT_POLL_INTERVAL.saturating_sub(last_poll.elapsed())));
+			if last_poll.elapsed() < EVENT_POLL_INTERVAL {
+				continue;
+			}
+		}
+		last_poll = Instant::now();
+
+		if let (Some(repl), Some(lines)) = (repl.as_mut(), repl_lines.as_ref()) {
+			while let Ok(line) = lines.try_recv() {
+				let (text, control) = repl.execute(&mut nes, &line);
+				if !text.is_empty() {
+					println!("{}", text);
+				}
+				match control {
+					Some(RunControl::Pause) => frontend.paused = true,
+					Some(RunControl::Continue) => frontend.paused = false,
+					Some(RunControl::Quit) => quit = true,
+					None => {}
+				}
+				update_title(&mut renderer, &frontend);
+			}
+		}
+		for event in sdl_event_pump.poll_iter() {
+			// Events of the debug windows go to them, not to the game.
+			match event_window_id(&event) {
+				Some(id) if id != main_window_id => {
+					let closed = matches!(event,
+						Event::Window{win_event_id: WindowEventId::Close, ..} |
+						Event::KeyDown{keycode: Some(Keycode::Escape), ..});
+					if closed {
+						debug_windows.retain(|window| window.id() != id);
+					} else if let Some(window) = debug_windows.iter_mut().find(|window| window.id() == id) {
+						window.handle_event(&event, &mut nes);
+					}
+					continue;
+				}
+				_ => {}
+			}
+			match event {
+				Event::Quit{..} => { quit = true; }
+				// the picture is only presented with new frames, e.g. not while paused
+				Event::Window{win_event_id: WindowEventId::Exposed, ..} => {
+					present(&mut renderer, &texture, &game_settings.video, bar_color(&ambilight));
+				}
+				// with debug windows open, closing the game window does not quit by itself
+				Event::Window{win_event_id: WindowEventId::Close, ..} => { quit = true; }
+				Event::KeyDown{keycode: Some(Keycode::F1), ..} => {
+					match movie_session {
+						// recorded at the start of the next frame
+						Some(ref mut session) => {
+							if let Err(err) = session.queue_event(ConsoleEvent::SoftReset) {
+								println!("{}", err);
+							}
+						}
+						None => nes.handle_event(ConsoleEvent::SoftReset),
+					}
+				}
+				Event::KeyDown{keycode: Some(Keycode::F2), ..} => {
+					match movie_session {
+						Some(ref mut session) => {
+							if let Err(err) = session.queue_event(ConsoleEvent::PowerCycle) {
+								println!("{}", err);
+							}
+						}
+						None => {
+							nes.handle_event(ConsoleEvent::PowerCycle);
+							boot_macro = game_settings.boot_macro.clone();
+						}
+					}
+				}
+				Event::KeyDown{keycode: Some(Keycode::F3), ..} => {
+					let active = !tracer.is_active();
+					tracer.set_active(active);
+					println!("Tracing {}.", if active { "started" } else { "stopped" });
+				}
+				Event::KeyDown{keycode: Some(Keycode::F4), repeat: false, ..} => { perf_hud = !perf_hud; }
+				Event::KeyDown{keycode: Some(Keycode::F5), repeat: false, ..} => {
+					match audio_recorder.take() {
+						Some(recorder) => finish_audio_recording(recorder),
+						None => {
+							let path = format!("recordings/audio_{}.wav", unix_time());
+							match fs::create_dir_all("recordings") {
+								Ok(()) => {
+									println!("Recording audio to {}.", path);
+									audio_recorder = Some(AudioRecorder::new(path));
+								}
+								Err(err) => println!("Could not create recordings directory: {}", err),
+							}
+						}
+					}
+				}
+				Event::KeyDown{keycode: Some(Keycode::F6), repeat: false, ..} => {
+					toggle_debug_window(&mut debug_windows, &sdl_video, DebugView::PaletteEditor, &mut nes);
+				}
+				Event::KeyDown{keycode: Some(Keycode::F7), repeat: false, ..} => {
+					toggle_debug_window(&mut debug_windows, &sdl_video, DebugView::PpuViewer, &mut nes);
+				}
+				// F8 and F9 hide and show the background and sprite layers
+				Event::KeyDown{keycode: Some(Keycode::F8), repeat: false, ..} => {
+					frontend.show_background = !frontend.show_background;
+					nes.set_layers(frontend.show_background, frontend.show_sprites);
+					update_title(&mut renderer, &frontend);
+				}
+				Event::KeyDown{keycode: Some(Keycode::F9), repeat: false, ..} => {
+					frontend.show_sprites = !frontend.show_sprites;
+					nes.set_layers(frontend.show_background, frontend.show_sprites);
+					update_title(&mut renderer, &frontend);
+				}
+				// R cycles the PPU rendering between the game's, forced off and forced on
+				Event::KeyDown{keycode: Some(Keycode::R), repeat: false, ..} => {
+					frontend.render_override = match frontend.render_override {
+						None => Some(false),
+						Some(false) => Some(true),
+						Some(true) => None,
+					};
+					nes.set_render_enabled(frontend.render_override);
+					update_title(&mut renderer, &frontend);
+				}
+				Event::KeyDown{keycode: Some(Keycode::F10), repeat: false, ..} => {
+					match SavedState::save(&nes, movie_session.as_ref()) {
+						Ok(state) => {
+							println!("Saved state at frame {}.", nes.ppu().frame_number());
+							quick_state = Some(state);
+						}
+						Err(err) => println!("Could not save state: {}", err),
+					}
+				}
+				Event::KeyDown{keycode: Some(Keycode::F11), repeat: false, ..} => {
+					let result = match (quick_state.as_ref(), movie_session.as_mut()) {
+						(None, _) => Err(String::from("There is no saved state, F10 saves one.")),
+						(Some(state), Some(session)) => session.load_state(&mut nes, state)
+							.map(|_| println!("Recording again from frame {}, F12 undoes.", session.frame())),
+						(Some(state), None) => state.load(&mut nes),
+					};
+					if let Err(err) = result {
+						println!("{}", err);
+					}
+				}
+				Event::KeyDown{keycode: Some(Keycode::F12), repeat: false, ..} => {
+					if let Some(ref mut session) = movie_session {
+						match session.undo_load(&mut nes) {
+							Ok(true) => println!("Back to frame {}, {} more loads to undo.", session.frame(), session.branches()),
+							Ok(false) => println!("There is no load to undo."),
+							Err(err) => println!("{}", err),
+						}
+					}
+				}
+				// 5 inserts a coin, as on arcade cabinets: the kiosk skips to the next game
+				Event::KeyDown{keycode: Some(Keycode::Num5), repeat: false, ..} if kiosk.is_some() => { next_game = true; }
+				Event::KeyDown{keycode: Some(Keycode::P), repeat: false, ..} => {
+					frontend.paused = !frontend.paused;
+					update_title(&mut renderer, &frontend);
+				}
+				Event::KeyDown{keycode: Some(Keycode::Tab), repeat: false, ..} => {
+					frontend.fast_forward = true;
+					update_title(&mut renderer, &frontend);
+				}
+				Event::KeyUp{keycode: Some(Keycode::Tab), ..} => {
+					frontend.fast_forward = false;
+					update_title(&mut renderer, &frontend);
+				}
+				// B asks on the terminal for a barcode to swipe (Datach)
+				Event::KeyDown{keycode: Some(Keycode::B), repeat: false, ..} => {
+					if let Some(digits) = ask("Barcode digits:") {
+						match nes.insert_barcode(&digits) {
+							Ok(()) => println!("Scanning barcode {}.", digits),
+							Err(err) => println!("{}", err),
+						}
+					}
+				}
+				// F turns the disk over or takes the next one (Famicom Disk System)
+				Event::KeyDown{keycode: Some(Keycode::F), repeat: false, ..} => {
+					match nes.change_disk_side() {
+						Ok(side) => println!("Inserting disk side {}.", side),
+						Err(err) => println!("{}", err),
+					}
+				}
+				// M held: shout into the microphone of controller 2
+				Event::KeyDown{keycode: Some(Keycode::M), repeat: false, ..} => { nes.set_microphone(true); }
+				Event::KeyUp{keycode: Some(Keycode::M), ..} => { nes.set_microphone(false); }
+				Event::MouseMotion{..} | Event::MouseButtonDown{..} | Event::MouseButtonUp{..} |
+				Event::Window{win_event_id: WindowEventId::Leave, ..} => {
+					let window_size = renderer.window().map(|window| window.size()).unwrap_or((256, 240));
+					if let Some(zapper) = nes.zapper_mut() {
+						zapper_event(zapper, &event, window_size, &game_settings.video);
+					}
+				}
+				Event::KeyDown{keycode: Some(key), ..} if button_for_key(key).is_some() => {
+					buttons |= button_for_key(key).unwrap();
+					if movie_session.is_none() && boot_macro.is_none() && input_stream.is_none() {
+						nes.set_buttons(0, buttons);
+					}
+				}
+				Event::KeyUp{keycode: Some(key), ..} if button_for_key(key).is_some() => {
+					buttons &= !button_for_key(key).unwrap();
+					if movie_session.is_none() && boot_macro.is_none() && input_stream.is_none() {
+						nes.set_buttons(0, buttons);
+					}
+				}
+				_ => {}
+			}
+		}
+	}
+
+	if let Some(recorder) = audio_recorder {
+		finish_audio_recording(recorder);
+	}
+
+	if let Some(ref path) = metrics_path {
+		if let Err(err) = metrics.write_file(path, &frontend.game_name, Instant::now()) {
+			println!("Could not write metrics: {}", err);
+		}
+	}
+
+	if let (Some(path), Some(session)) = (record_movie_path, movie_session) {
+		if session.is_recording() {
+			match session.movie().save(&path) {
+				Ok(()) => println!("Saved movie of {} frames to {}.", session.frame(), path),
+				Err(err) => println!("Could not save movie: {}", err),
+			}
+		}
+	}
+
+	if let Some(ref path) = turbo_file_path {
+		if let Some(data) = nes.expansion_device().and_then(|device| device.battery_data()) {
+			match turbo_file::save(path, data) {
+				Ok(()) => println!("Saved Turbo File to {}.", path),
+				Err(err) => println!("Could not save Turbo File: {}", err),
+			}
+		}
+	}
+
+	if let Some(ref path) = state_path {
+		let result = fs::create_dir_all("states")
+			.and_then(|_| File::create(path))
+			.and_then(|mut file| nes.save_state(&mut file));
+		match result {
+			Ok(()) => println!("Saved state to {}.", path),
+			Err(err) => println!("Could not save state: {}", err),
+		}
+	}
+}
+// Keyboard layout of controller 1: arrows, X = A, Z = B, Return = Start,
+// right Shift = Select.
+fn button_for_key(key: Keycode) -> Option<u8> {
+	match key {
+		Keycode::X => Some(input::BUTTON_A),
+		Keycode::Z => Some(input::BUTTON_B),
+		Keycode::RShift => Some(input::BUTTON_SELECT),
+		Keycode::Return => Some(input::BUTTON_START),
+		Keycode::Up => Some(input::BUTTON_UP),
+		Keycode::Down => Some(input::BUTTON_DOWN),
+		Keycode::Left => Some(input::BUTTON_LEFT),
+		Keycode::Right => Some(input::BUTTON_RIGHT),
+		_ => None,
+	}
+}
+
+// Draws the picture into the window with the crop and scaling of the video
+// settings.
+fn present(renderer: &mut Renderer, texture: &Texture, video: &VideoSettings, bars: Color) {
+	let window_size = renderer.window().map(|window| window.size()).unwrap_or((256, 240));
+	let (x, y, width, height) = video.source();
+	let (dest_x, dest_y, dest_width, dest_height) = video.destination(window_size);
+	renderer.set_draw_color(bars);
+	renderer.clear();
+	renderer.copy(texture, Some(Rect::new(x as i32, y as i32, width, height)),
+		Some(Rect::new(dest_x, dest_y, dest_width, dest_height)));
+	renderer.present();
+}
+
+// The color of the bars around the picture, black without --ambilight.
+fn bar_color(ambilight: &Option<Arc<Mutex<(u8, u8, u8)>>>) -> Color {
+	let (r, g, b) = ambilight.as_ref().map(|color| *color.lock().unwrap()).unwrap_or((0, 0, 0));
+	Color::RGB(r, g, b)
+}
+
+// Pixels around the aim which the Zapper sees by default.
+const DEFAULT_ZAPPER_RADIUS: usize = 2;
+
+// The mouse aims the Zapper in the game window, the left button pulls the
+// trigger. The right button pulls it while pointing away from the screen,
+// which reloads in some games.
+fn zapper_event(zapper: &mut Zapper, event: &Event, window_size: (u32, u32), video: &VideoSettings) {
+	let aim = |x: i32, y: i32| video.picture_position(window_size, x, y);
+	match *event {
+		Event::MouseMotion{x, y, ..} => zapper.set_aim(aim(x, y)),
+		Event::MouseButtonDown{mouse_btn: Mouse::Left, x, y, ..} => {
+			zapper.set_aim(aim(x, y));
+			zapper.set_trigger(true);
+		}
+		Event::MouseButtonDown{mouse_btn: Mouse::Right, ..} => {
+			zapper.set_aim(None);
+			zapper.set_trigger(true);
+		}
+		Event::MouseButtonUp{mouse_btn: Mouse::Left, ..} | Event::MouseButtonUp{mouse_btn: Mouse::Right, ..} => {
+			zapper.set_trigger(false);
+		}
+		Event::Window{win_event_id: WindowEventId::Leave, ..} => zapper.set_aim(None),
+		_ => {}
+	}
+}
+
+// A window showing one of the debug views, updated with every frame.
+struct DebugWindow {
+	view: DebugView,
+	renderer: Renderer<'static>,
+	texture: Texture,
+	// the selection of DebugView::PaletteEditor
+	palette_editor: Option<PaletteEditor>,
+}
+
+impl DebugWindow {
+	fn open(video: &VideoSubsystem, view: DebugView) -> Result<DebugWindow, String> {
+		let (width, height) = view.size();
+		let window = (WindowBuilder::new(video, view.title(), width as u32 * 2, height as u32 * 2)
+			.build().map_err(|err| format!("{:?}", err)))?;
+		let renderer = RendererBuilder::new(window).build().map_err(|err| format!("{:?}", err))?;
+		let texture = (renderer.create_texture_streaming(PixelFormatEnum::ABGR8888, width as u32, height as u32)
+			.map_err(|err| format!("{:?}", err)))?;
+		Ok(DebugWindow {
+			view,
+			renderer,
+			texture,
+			palette_editor: if view == DebugView::PaletteEditor { Some(PaletteEditor::new()) } else { None },
+		})
+	}
+
+	fn id(&self) -> u32 {
+		self.renderer.window().map(|window| window.id()).unwrap_or(0)
+	}
+
+	fn update(&mut self, nes: &mut Nes) {
+		let mut image = self.view.draw(nes);
+		if let Some(ref editor) = self.palette_editor {
+			editor.draw_selection(&mut image);
+			if let Some(window) = self.renderer.window_mut() {
+				window.set_title(&editor.title(nes.master_palette())).unwrap();
+			}
+		}
+		self.texture.update(None, &image.pixels, image.width * 4).unwrap();
+		self.renderer.copy(&self.texture, None, None);
+		self.renderer.present();
+	}
+
+	// Keys in the palette editor: the arrows select a color, Q/A, W/S and
+	// E/D raise and lower its red, green and blue, R reverts it and Return
+	// asks on the terminal where to save the palette.
+	fn handle_event(&mut self, event: &Event, nes: &mut Nes) {
+		let key = match (self.palette_editor.as_ref(), event) {
+			(Some(_), &Event::KeyDown{keycode: Some(key), ..}) => key,
+			_ => return,
+		};
+		if key == Keycode::Return {
+			if let Some(path) = ask("Save palette as:") {
+				match palette::save_pal(&path, nes.master_palette()) {
+					Ok(()) => println!("Saved palette to {}.", path),
+					Err(err) => println!("Could not save palette: {}", err),
+				}
+			}
+			return;
+		}
+		let edit = match key {
+			Keycode::Left => PaletteEdit::Select(-1, 0),
+			Keycode::Right => PaletteEdit::Select(1, 0),
+			Keycode::Up => PaletteEdit::Select(0, -1),
+			Keycode::Down => PaletteEdit::Select(0, 1),
+			Keycode::Q => PaletteEdit::Adjust(0, 4),
+			Keycode::A => PaletteEdit::Adjust(0, -4),
+			Keycode::W => PaletteEdit::Adjust(1, 4),
+			Keycode::S => PaletteEdit::Adjust(1, -4),
+			Keycode::E => PaletteEdit::Adjust(2, 4),
+			Keycode::D => PaletteEdit::Adjust(2, -4),
+			Keycode::R => PaletteEdit::Revert,
+			_ => return,
+		};
+		let palette = self.palette_editor.as_mut().unwrap().edit(edit, nes.master_palette());
+		nes.set_master_palette(palette);
+		self.update(nes);
+	}
+}
+
+// Closes the window of the view if it is open, opens it otherwise.
+fn toggle_debug_window(windows: &mut Vec<DebugWindow>, video: &VideoSubsystem, view: DebugView, nes: &mut Nes) {
+	if let Some(index) = windows.iter().position(|window| window.view == view) {
+		windows.remove(index);
+		return;
+	}
+	match DebugWindow::open(video, view) {
+		Ok(mut window) => {
+			window.update(nes);
+			windows.push(window);
+		}
+		Err(err) => println!("Could not open {}: {}", view.title(), err),
+	}
+}
+
+// The window an input or window event belongs to.
+fn event_window_id(event: &Event) -> Option<u32> {
+	match *event {
+		Event::Window{window_id, ..} |
+		Event::KeyDown{window_id, ..} |
+		Event::KeyUp{window_id, ..} |
+		Event::MouseMotion{window_id, ..} |
+		Event::MouseButtonDown{window_id, ..} |
+		Event::MouseButtonUp{window_id, ..} |
+		Event::MouseWheel{window_id, ..} => Some(window_id),
+		_ => None,
+	}
+}
+
+// Replaces the cartridge by a fresh load of the ROM file. The console is
+// power cycled, unless keep_state is set and the old state can be loaded.
+// Keeps the old cartridge and returns None if the file can not be loaded.
+fn reload_rom(nes: &mut Nes, rom_path: &str, keep_state: bool) -> Option<RomInfo> {
+	let (cartridge, info) = match load_rom_with_info(rom_path) {
+		Ok(rom) => rom,
+		Err(err) => {
+			println!("Could not reload ROM: {}", err);
+			return None;
+		}
+	};
+	let mut state = Vec::new();
+	if keep_state {
+		nes.save_state(&mut state).unwrap();
+	}
+	let region = nes.region();
+	let settings = nes.settings().clone();
+	let audio_settings = nes.audio_settings().clone();
+	let seed = nes.seed();
+	let machine = nes.machine().clone();
+	let audio_output = nes.take_audio_output();
+	let scanline_output = nes.take_scanline_output();
+	let render_enabled = nes.render_enabled();
+	// e.g. the Turbo File, saved on quit
+	let expansion_device = nes.take_expansion_device();
+	*nes = Nes::new(cartridge);
+	nes.set_expansion_device(expansion_device);
+	nes.set_render_enabled(render_enabled);
+	nes.set_audio_output(audio_output);
+	nes.set_scanline_output(scanline_output);
+	nes.set_settings(settings);
+	nes.set_audio_settings(audio_settings);
+	nes.set_seed(seed);
+	nes.set_machine(machine);
+	nes.set_region(region);
+	nes.set_annotate_io(true);
+	if keep_state {
+		if let Err(err) = nes.load_state(&mut &state[..]) {
+			println!("Could not keep state: {}", err);
+			nes.handle_event(ConsoleEvent::PowerCycle);
+		}
+	}
+	Some(info)
+}
+
+// Loads the game of a playlist entry in place of the running one, with the
+// game settings and the playback of its demo movie, if it has one. The
+// region is the one of the movie or the ROM header.
+fn start_kiosk_entry(nes: &mut Nes, entry: &KioskEntry) -> Option<(GameSettings, Option<MovieSession>)> {
+	println!("Kiosk: loading {}.", entry.rom);
+	reload_rom(nes, &entry.rom, false).map(|info| {
+		let settings = GameSettings::load(&info).unwrap_or_else(|err| {
+			println!("Could not load game settings: {}", err);
+			GameSettings::new()
+		});
+		let movie = match entry.movie {
+			Some(ref path) => match Movie::load(path) {
+				Ok(movie) => Some(movie),
+				Err(err) => {
+					println!("Could not load movie: {}", err);
+					None
+				}
+			},
+			None => None,
+		};
+		nes.set_machine(settings.machine.clone().unwrap_or_else(MachineConfig::new));
+		nes.set_region(movie.as_ref().map(|movie| movie.region).or(info.region).unwrap_or(Region::Ntsc));
+		let session = movie.map(|movie| {
+			let mut session = MovieSession::play(movie);
+			session.start_frame(nes, [0, 0]);
+			session
+		});
+		(settings, session)
+	})
+}
+
+fn finish_audio_recording(recorder: AudioRecorder) {
+	let path = String::from(recorder.path());
+	match recorder.finish() {
+		Ok(samples) => println!("Recorded {} audio samples to {}.", samples, path),
+		Err(err) => println!("Could not finish audio recording: {}", err),
+	}
+}
+
+fn unix_time() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+// e.g. "Mapper: MMC1 with PRG RAM, battery."
+fn describe_mapper(cartridge: &dyn Cartridge) -> String {
+	let capabilities = capability_names(cartridge.capabilities());
+	if capabilities.is_empty() {
+		format!("Mapper: {}.", cartridge.name())
+	} else {
+		format!("Mapper: {} with {}.", cartridge.name(), capabilities.join(", "))
+	}
+}
+
+fn update_title(renderer: &mut Renderer, frontend: &FrontendState) {
+	if let Some(window) = renderer.window_mut() {
+		window.set_title(&frontend.window_title()).unwrap();
+	}
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+	fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Returns where the autosave state for the ROM is stored.
+fn autosave_path(info: &RomInfo) -> String {
+	format!("states/{}.state", info.sha1_hex())
+}
+
+// Parses an address given as 0xC000, $C000 or C000.
+fn parse_address(text: &str) -> Option<u16> {
+	let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')).unwrap_or(text);
+	u16::from_str_radix(digits, 16).ok()
+}
+
+// Parses a level of an expansion chip like fds=0.8, levels are factors of
+// the Famicom level.
+fn parse_expansion_level(text: &str) -> Option<(ExpansionChip, f32)> {
+	let mut parts = text.splitn(2, '=');
+	let chip = parts.next().and_then(ExpansionChip::from_name);
+	let level = parts.next().and_then(|level| level.parse::<f32>().ok()).filter(|&level| level >= 0.0);
+	match (chip, level) {
+		(Some(chip), Some(level)) => Some((chip, level)),
+		_ => None,
+	}
+}
+
+// Asks a yes/no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> bool {
+	print!("{} [y/N] ", question);
+	io::stdout().flush().unwrap();
+	let mut answer = String::new();
+	match io::stdin().read_line(&mut answer) {
+		Ok(_) => answer.trim().to_lowercase().starts_with('y'),
+		Err(_) => false,
+	}
+}
+
+// Asks for a line of text on the terminal, None if it is empty.
+fn ask(question: &str) -> Option<String> {
+	print!("{} ", question);
+	io::stdout().flush().unwrap();
+	let mut answer = String::new();
+	match io::stdin().read_line(&mut answer) {
+		Ok(_) if !answer.trim().is_empty() => Some(String::from(answer.trim())),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::time::Duration;
+	use apu::ExpansionChip;
+
+	#[test]
+	fn window_title() {
+		let mut state = FrontendState::new(None, "roms/Some Game.nes");
+		state.show_fps = false;
+		assert_eq!("Some Game — Kaini's NES Emulator", state.window_title());
+		state.paused = true;
+		assert_eq!("Some Game — Kaini's NES Emulator [Paused]", state.window_title());
+
+		let mut state = FrontendState::new(Some("Title (USA)"), "roms/game.nes");
+		state.fast_forward = true;
+		assert!(!state.count_frames(90, Duration::from_millis(600)));
+		assert!(state.count_frames(150, Duration::from_millis(400)));
+		assert_eq!("Title (USA) — Kaini's NES Emulator [Fast forward | 240 FPS]", state.window_title());
+		state.warning = Some(String::from("Hung"));
+		assert_eq!("Title (USA) — Kaini's NES Emulator [Hung | Fast forward | 240 FPS]", state.window_title());
+		state.warning = None;
+		state.show_sprites = false;
+		assert_eq!("Title (USA) — Kaini's NES Emulator [No sprites | Fast forward | 240 FPS]", state.window_title());
+		state.mapper = Some(String::from("MMC1"));
+		assert_eq!("Title (USA) (MMC1) — Kaini's NES Emulator [No sprites | Fast forward | 240 FPS]", state.window_title());
+		state.render_override = Some(false);
+		assert_eq!("Title (USA) (MMC1) — Kaini's NES Emulator [No sprites | Rendering forced off | Fast forward | 240 FPS]",
+			state.window_title());
+	}
+
+	#[test]
+	fn video_geometry() {
+		let mut video = VideoSettings::new();
+		assert_eq!((0, 0, 256, 240), video.source());
+		assert_eq!((0, 0, 1000, 500), video.destination((1000, 500)));
+		assert_eq!(Some((128, 120)), video.picture_position((512, 480), 256, 240));
+
+		// without the leftmost column, fit into a wide window
+		video.crop.left = 8;
+		video.stretch = false;
+		assert_eq!((8, 0, 248, 240), video.source());
+		assert_eq!((242, 0, 496, 480), video.destination((980, 480)));
+		assert_eq!(None, video.picture_position((980, 480), 100, 100));
+		assert_eq!(Some((8, 0)), video.picture_position((980, 480), 242, 0));
+		assert_eq!(Some((255, 239)), video.picture_position((980, 480), 737, 479));
+
+		// cropping everything leaves a pixel
+		video.crop = Crop { left: 200, top: 0, right: 200, bottom: 0 };
+		assert_eq!((200, 0, 1, 240), video.source());
+	}
+
+	#[test]
+	fn frame_pacer() {
+		let ms = Duration::from_millis;
+		let start = Instant::now();
+		let mut pacer = FramePacer::new(ms(16), start);
+		assert_eq!(ms(0), pacer.wait_time(start));
+		pacer.frame_done(start + ms(2));
+		assert_eq!(ms(14), pacer.wait_time(start + ms(2)));
+		// a late frame shortens the wait for the next one
+		pacer.frame_done(start + ms(20));
+		assert_eq!(ms(12), pacer.wait_time(start + ms(20)));
+		// far behind, e.g. after a pause
+		pacer.frame_done(start + ms(1000));
+		assert_eq!(ms(0), pacer.wait_time(start + ms(1000)));
+		pacer.frame_done(start + ms(1001));
+		assert_eq!(ms(15), pacer.wait_time(start + ms(1001)));
+	}
+
+	#[test]
+	fn entry_address() {
+		assert_eq!(Some(0xC000), parse_address("0xC000"));
+		assert_eq!(Some(0xC000), parse_address("$c000"));
+		assert_eq!(Some(0x8000), parse_address("8000"));
+		assert_eq!(None, parse_address("0x10000"));
+		assert_eq!(None, parse_address("start"));
+	}
+
+	#[test]
+	fn expansion_level() {
+		assert_eq!(Some((ExpansionChip::Fds, 0.8)), parse_expansion_level("fds=0.8"));
+		assert_eq!(Some((ExpansionChip::Sunsoft5b, 2.0)), parse_expansion_level("5b=2"));
+		assert_eq!(None, parse_expansion_level("fds"));
+		assert_eq!(None, parse_expansion_level("fds=-1"));
+		assert_eq!(None, parse_expansion_level("sid=1"));
+	}
+}
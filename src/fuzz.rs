@@ -0,0 +1,113 @@
+use cartridge::MirrorMode;
+use cartridge::nrom::NRom;
+use ppu::Ppu;
+use std::fs::File;
+use std::io::Read;
+use std::panic;
+
+// Fuzz harness for the PPU registers: fuzz-ppu <input files>
+//
+// ppu_registers interprets arbitrary bytes as a sequence of register
+// operations and checks the PPU state after each of them. Any panic is a
+// bug. The subcommand replays inputs, e.g. crashes found by a coverage
+// guided fuzzer calling ppu_registers, and reports which of them panic.
+//
+// Each operation starts with a byte, the top two bits select the kind:
+//   00rrrxxx vv  write vv to PPU register 2000 + r
+//   01rrrxxx     read PPU register 2000 + r
+//   10xxxxxx pp  OAM DMA from page pp, as 256 writes to 2004
+//   11nnnnnn     run the PPU for n * 97 + 1 dots
+pub fn ppu_registers(data: &[u8]) {
+	let mut cartridge = NRom::new(vec![0; 16 * 1024], chr_pattern(), 0, MirrorMode::VerticalMirroring);
+	let mut ppu = Ppu::new();
+	let mut bytes = data.iter().cloned();
+	while let Some(op) = bytes.next() {
+		let register = 0x2000 | (op as u16 >> 3 & 7);
+		match op >> 6 {
+			0 => {
+				let value = bytes.next().unwrap_or(0);
+				ppu.write(&mut cartridge, register, value);
+			}
+			1 => {
+				ppu.read(&mut cartridge, register);
+			}
+			2 => {
+				let page = bytes.next().unwrap_or(0);
+				for i in 0..256 {
+					ppu.write(&mut cartridge, 0x2004, page.wrapping_add(i as u8).rotate_left(3));
+				}
+			}
+			_ => {
+				for _ in 0..(op & 0x3F) as usize * 97 + 1 {
+					ppu.tick(&mut cartridge);
+				}
+			}
+		}
+		ppu.check_invariants();
+		// frames have to be taken or the PPU keeps the old one
+		ppu.take_frame();
+	}
+}
+
+// CHR with a different value in every byte, so fetches are visible.
+fn chr_pattern() -> Vec<u8> {
+	(0..8 * 1024).map(|i: usize| (i ^ (i >> 8)) as u8).collect()
+}
+
+pub fn run(args: &[String]) {
+	if args.is_empty() {
+		println!("fuzz-ppu expects input files.");
+		return;
+	}
+	let mut failed = 0;
+	for path in args.iter() {
+		let mut data = Vec::new();
+		if let Err(err) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+			println!("Could not read {}: {}", path, err);
+			continue;
+		}
+		if panic::catch_unwind(|| ppu_registers(&data)).is_err() {
+			failed += 1;
+			println!("PANIC {}", path);
+		}
+	}
+	println!("{} of {} inputs panicked.", failed, args.len());
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn ppu_registers_random() {
+		// xorshift, so every run checks the same inputs
+		let mut state: u32 = 0x1234_5678;
+		let mut next = || {
+			state ^= state << 13;
+			state ^= state >> 17;
+			state ^= state << 5;
+			state
+		};
+		for _ in 0..200 {
+			let len = (next() % 300) as usize;
+			let data: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+			ppu_registers(&data);
+		}
+	}
+
+	#[test]
+	fn ppu_registers_edges() {
+		ppu_registers(&[]);
+		// truncated operations
+		ppu_registers(&[0x00]);
+		ppu_registers(&[0x80]);
+		// palette writes with all bits set, then render a frame with them
+		let mut data = vec![0x30, 0x3F, 0x30, 0x00];
+		for _ in 0..64 {
+			data.extend_from_slice(&[0x38, 0xFF]);
+		}
+		data.extend_from_slice(&[0x08, 0x1E]);
+		data.extend(vec![0xFF; 20]);
+		ppu_registers(&data);
+	}
+}
@@ -0,0 +1,139 @@
+use cartridge::RomInfo;
+use frontend::VideoSettings;
+use input::BootMacro;
+use machine::{MachineConfig, MachinePreset};
+use std::fs::File;
+use std::io::{self, Read};
+
+// Settings for one game, which override the defaults when it is loaded.
+// They are kept in games/<SHA-1 of the ROM>.toml, in the TOML subset of the
+// test ROM manifest:
+//
+//   crop_left = 8        # pixels hidden at the edges, 0 by default
+//   crop_top = 0
+//   crop_right = 0
+//   crop_bottom = 8
+//   stretch = false      # keep the proportions, true by default
+//   boot_macro = "120:start 126:"   # buttons after power on, see BootMacro
+//   machine = "famiclone"  # the console model, see MachinePreset
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameSettings {
+	pub video: VideoSettings,
+	pub boot_macro: Option<BootMacro>,
+	// The console the game needs, the one of the command line if None.
+	pub machine: Option<MachineConfig>,
+}
+
+impl GameSettings {
+	pub fn new() -> GameSettings {
+		GameSettings {
+			video: VideoSettings::new(),
+			boot_macro: None,
+			machine: None,
+		}
+	}
+
+	pub fn path(info: &RomInfo) -> String {
+		format!("games/{}.toml", info.sha1_hex())
+	}
+
+	// The settings of the game, the defaults if it has no file.
+	pub fn load(info: &RomInfo) -> Result<GameSettings, String> {
+		let path = GameSettings::path(info);
+		let mut text = String::new();
+		match File::open(&path).and_then(|mut file| file.read_to_string(&mut text)) {
+			Ok(_) => GameSettings::parse(&text).map_err(|err| format!("{}: {}", path, err)),
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(GameSettings::new()),
+			Err(err) => Err(format!("{}: {}", path, err)),
+		}
+	}
+
+	pub fn parse(text: &str) -> Result<GameSettings, String> {
+		let mut settings = GameSettings::new();
+		for (i, line) in text.lines().enumerate() {
+			let error = |message: &str| Err(format!("line {}: {}", i + 1, message));
+			let line = match line.find('#') {
+				Some(pos) => &line[..pos],
+				None => line,
+			}.trim();
+			if line.is_empty() {
+				continue;
+			}
+			let (key, value) = match line.find('=') {
+				Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+				None => return error("Expected key = value."),
+			};
+			let crop = &mut settings.video.crop;
+			let edge = match key {
+				"crop_left" => &mut crop.left,
+				"crop_top" => &mut crop.top,
+				"crop_right" => &mut crop.right,
+				"crop_bottom" => &mut crop.bottom,
+				"boot_macro" => {
+					if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+						return error("boot_macro must be a quoted string.");
+					}
+					settings.boot_macro = match BootMacro::parse(&value[1..value.len() - 1]) {
+						Ok(boot_macro) => Some(boot_macro),
+						Err(err) => return error(&err),
+					};
+					continue;
+				}
+				"machine" => {
+					let name = value.trim_matches('"');
+					settings.machine = match MachinePreset::from_name(name) {
+						Some(preset) if value.len() == name.len() + 2 => Some(MachineConfig::from_preset(preset)),
+						_ => return error("machine must be \"famicom\", \"nes\" or \"famiclone\"."),
+					};
+					continue;
+				}
+				"stretch" => {
+					settings.video.stretch = match value {
+						"true" => true,
+						"false" => false,
+						_ => return error("stretch must be true or false."),
+					};
+					continue;
+				}
+				_ => return error(&format!("Unknown key {}.", key)),
+			};
+			*edge = match value.parse() {
+				Ok(pixels) if pixels < 128 => pixels,
+				_ => return error(&format!("{} must be a number of pixels below 128.", key)),
+			};
+		}
+		Ok(settings)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use frontend::Crop;
+
+	#[test]
+	fn parse() {
+		let settings = GameSettings::parse("
+			# scroll garbage on the left
+			crop_left = 8
+			crop_bottom = 8  # and the bottom
+			stretch = false
+			boot_macro = \"60:start 62:\"
+			machine = \"famiclone\"
+		").unwrap();
+		assert_eq!(Crop { left: 8, top: 0, right: 0, bottom: 8 }, settings.video.crop);
+		assert!(!settings.video.stretch);
+		assert_eq!(BootMacro::parse("60:start 62:").ok(), settings.boot_macro);
+		assert_eq!(Some(MachineConfig::from_preset(MachinePreset::Famiclone)), settings.machine);
+		assert_eq!(GameSettings::new(), GameSettings::parse("").unwrap());
+
+		assert_eq!(Err(String::from("line 2: Unknown key crop.")), GameSettings::parse("\ncrop = 8"));
+		assert!(GameSettings::parse("crop_top = 200").is_err());
+		assert!(GameSettings::parse("stretch = yes").is_err());
+		assert!(GameSettings::parse("stretch").is_err());
+		assert!(GameSettings::parse("boot_macro = 60:start").is_err());
+		assert!(GameSettings::parse("boot_macro = \"60:stop\"").is_err());
+		assert!(GameSettings::parse("machine = famiclone").is_err());
+		assert!(GameSettings::parse("machine = \"dendy\"").is_err());
+	}
+}
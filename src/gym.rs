@@ -0,0 +1,344 @@
+use cartridge::load_rom;
+use nes::Nes;
+use ppu::Frame;
+
+// Reinforcement learning environment in the style of OpenAI Gym. An
+// episode starts from the console state at the creation of the
+// environment. Every step holds the buttons of the action on controller 1
+// for a few frames and returns the last frame with the reward and whether
+// the episode is over. Reward and end of episode are expressions over the
+// RAM, e.g. for a score in BCD and a lives counter:
+//   reward: $07DD * 10 + $07DE
+//   done:   $075A == 0
+pub struct Environment {
+	nes: Nes,
+	initial_state: Vec<u8>,
+	reward: Expression,
+	done: Expression,
+	frames_per_step: u32,
+}
+
+impl Environment {
+	pub fn new(nes: Nes, reward: &str, done: &str) -> Result<Environment, String> {
+		let mut initial_state = Vec::new();
+		nes.save_state(&mut initial_state).map_err(|err| err.to_string())?;
+		Ok(Environment {
+			nes,
+			initial_state,
+			reward: Expression::parse(reward).map_err(|err| format!("reward: {}", err))?,
+			done: Expression::parse(done).map_err(|err| format!("done: {}", err))?,
+			frames_per_step: 4,
+		})
+	}
+
+	// Number of frames an action is held, 4 by default.
+	pub fn set_frames_per_step(&mut self, frames: u32) {
+		assert!(frames > 0);
+		self.frames_per_step = frames;
+	}
+
+	// Starts a new episode and returns its first frame.
+	pub fn reset(&mut self) -> Frame {
+		self.nes.load_state(&mut &self.initial_state[..]).unwrap();
+		self.nes.set_buttons(0, 0);
+		self.nes.run_frame()
+	}
+
+	// action is a combination of input::BUTTON_A etc.
+	pub fn step(&mut self, action: u8) -> (Frame, i64, bool) {
+		self.nes.set_buttons(0, action);
+		let mut frame = self.nes.run_frame();
+		for _ in 1..self.frames_per_step {
+			frame = self.nes.run_frame();
+		}
+		let reward = self.reward.eval(&self.nes);
+		let done = self.done.eval(&self.nes) != 0;
+		(frame, reward, done)
+	}
+
+	pub fn nes(&self) -> &Nes {
+		&self.nes
+	}
+}
+
+// Integer expression over the RAM: numbers (decimal or 0x hex), RAM bytes
+// ($hex), parentheses, unary minus, * / + - and the comparisons
+// == != < <= > >=, which give 1 or 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+	Number(i64),
+	Ram(u16),
+	Negate(Box<Expression>),
+	Binary(char, Box<Expression>, Box<Expression>),
+	Compare(&'static str, Box<Expression>, Box<Expression>),
+}
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+impl Expression {
+	pub fn parse(text: &str) -> Result<Expression, String> {
+		let mut parser = Parser { text, pos: 0 };
+		let expression = parser.comparison()?;
+		parser.skip_spaces();
+		if parser.pos < text.len() {
+			return Err(format!("Unexpected '{}' at {}.", &text[parser.pos..], parser.pos));
+		}
+		Ok(expression)
+	}
+
+	pub fn eval(&self, nes: &Nes) -> i64 {
+		match *self {
+			Expression::Number(value) => value,
+			Expression::Ram(addr) => nes.peek_ram(addr) as i64,
+			Expression::Negate(ref a) => -a.eval(nes),
+			Expression::Binary(op, ref a, ref b) => {
+				let (a, b) = (a.eval(nes), b.eval(nes));
+				match op {
+					'+' => a.wrapping_add(b),
+					'-' => a.wrapping_sub(b),
+					'*' => a.wrapping_mul(b),
+					_ => if b == 0 { 0 } else { a.wrapping_div(b) },
+				}
+			}
+			Expression::Compare(op, ref a, ref b) => {
+				let (a, b) = (a.eval(nes), b.eval(nes));
+				let result = match op {
+					"==" => a == b,
+					"!=" => a != b,
+					"<=" => a <= b,
+					">=" => a >= b,
+					"<" => a < b,
+					_ => a > b,
+				};
+				result as i64
+			}
+		}
+	}
+}
+
+struct Parser<'a> {
+	text: &'a str,
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn skip_spaces(&mut self) {
+		while self.rest().starts_with(' ') {
+			self.pos += 1;
+		}
+	}
+
+	fn rest(&self) -> &'a str {
+		&self.text[self.pos..]
+	}
+
+	// Consumes the token if it comes next.
+	fn accept(&mut self, token: &str) -> bool {
+		self.skip_spaces();
+		if self.rest().starts_with(token) {
+			self.pos += token.len();
+			true
+		} else {
+			false
+		}
+	}
+
+	fn comparison(&mut self) -> Result<Expression, String> {
+		let left = self.sum()?;
+		for op in COMPARISONS.iter() {
+			if self.accept(op) {
+				let right = self.sum()?;
+				return Ok(Expression::Compare(op, Box::new(left), Box::new(right)));
+			}
+		}
+		Ok(left)
+	}
+
+	fn sum(&mut self) -> Result<Expression, String> {
+		let mut left = self.product()?;
+		loop {
+			let op = if self.accept("+") { '+' } else if self.accept("-") { '-' } else { return Ok(left) };
+			let right = self.product()?;
+			left = Expression::Binary(op, Box::new(left), Box::new(right));
+		}
+	}
+
+	fn product(&mut self) -> Result<Expression, String> {
+		let mut left = self.atom()?;
+		loop {
+			let op = if self.accept("*") { '*' } else if self.accept("/") { '/' } else { return Ok(left) };
+			let right = self.atom()?;
+			left = Expression::Binary(op, Box::new(left), Box::new(right));
+		}
+	}
+
+	fn atom(&mut self) -> Result<Expression, String> {
+		if self.accept("(") {
+			let inner = self.comparison()?;
+			if !self.accept(")") {
+				return Err(format!("Missing ')' at {}.", self.pos));
+			}
+			return Ok(inner);
+		}
+		if self.accept("-") {
+			return Ok(Expression::Negate(Box::new(self.atom()?)));
+		}
+		let ram = self.accept("$");
+		let hex = ram || self.accept("0x");
+		let digits: String = self.rest().chars()
+			.take_while(|c| if hex { c.is_ascii_hexdigit() } else { c.is_ascii_digit() })
+			.collect();
+		if digits.is_empty() {
+			return Err(format!("Expected a number at {}.", self.pos));
+		}
+		self.pos += digits.len();
+		let value = (i64::from_str_radix(&digits, if hex { 16 } else { 10 })
+			.map_err(|err| format!("{}: {}", digits, err)))?;
+		if ram {
+			if value > 0x7FF {
+				return Err(format!("${} is not in the 2 KiB of RAM.", digits));
+			}
+			Ok(Expression::Ram(value as u16))
+		} else {
+			Ok(Expression::Number(value))
+		}
+	}
+}
+
+// Random agent: gym <rom> --reward EXPR --done EXPR [--episodes N]
+//
+// Plays episodes with random buttons and prints their total rewards, as a
+// baseline and to check the expressions. Episodes end after at most 10000
+// steps.
+pub fn run(args: &[String]) {
+	let mut rom_path = None;
+	let mut reward = None;
+	let mut done = None;
+	let mut episodes = 1;
+	let mut frames_per_step = None;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_ref() {
+			"--reward" => reward = args.next().cloned(),
+			"--done" => done = args.next().cloned(),
+			"--episodes" => {
+				episodes = match args.next().and_then(|value| value.parse().ok()) {
+					Some(value) => value,
+					None => {
+						println!("--episodes expects a number.");
+						return;
+					}
+				};
+			}
+			"--frames-per-step" => {
+				frames_per_step = match args.next().and_then(|value| value.parse().ok()) {
+					Some(value) if value > 0 => Some(value),
+					_ => {
+						println!("--frames-per-step expects a positive number.");
+						return;
+					}
+				};
+			}
+			_ => rom_path = Some(arg.clone()),
+		}
+	}
+	let (rom_path, reward, done) = match (rom_path, reward, done) {
+		(Some(rom_path), Some(reward), Some(done)) => (rom_path, reward, done),
+		_ => {
+			println!("Usage: gym <rom> --reward EXPR --done EXPR [--episodes N] [--frames-per-step N]");
+			return;
+		}
+	};
+	let nes = match load_rom(&rom_path) {
+		Ok(cartridge) => Nes::new(cartridge),
+		Err(err) => {
+			println!("Could not load ROM: {}", err);
+			return;
+		}
+	};
+	let mut environment = match Environment::new(nes, &reward, &done) {
+		Ok(environment) => environment,
+		Err(err) => {
+			println!("Invalid expression, {}", err);
+			return;
+		}
+	};
+	if let Some(frames) = frames_per_step {
+		environment.set_frames_per_step(frames);
+	}
+
+	// xorshift, the buttons do not need to be any more random
+	let mut random: u32 = 0x2545_F491;
+	for episode in 0..episodes {
+		environment.reset();
+		let mut total = 0;
+		let mut steps = 0;
+		while steps < 10000 {
+			random ^= random << 13;
+			random ^= random >> 17;
+			random ^= random << 5;
+			let (_, reward, done) = environment.step(random as u8);
+			total += reward;
+			steps += 1;
+			if done {
+				break;
+			}
+		}
+		println!("Episode {}: {} steps, total reward {}, ended in frame {}.",
+			episode + 1, steps, total, environment.nes().ppu().frame_number());
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+	use cartridge::test_cartridge::TestCartridge;
+	use input::{BUTTON_A, BUTTON_START};
+
+	#[test]
+	fn expressions() {
+		let cartridge = TestCartridge::builder().prg(0x8000, &assemble(0x8000, "JMP $8000").unwrap()).build();
+		let nes = Nes::new(Box::new(cartridge));
+		let eval = |text: &str| Expression::parse(text).unwrap().eval(&nes);
+		assert_eq!(7, eval("1 + 2 * 3"));
+		assert_eq!(9, eval("(1 + 2) * 3"));
+		assert_eq!(-5, eval("-5"));
+		assert_eq!(2, eval("0x10 / 8 - 7 / 0"));
+		assert_eq!(1, eval("$10 == 0"));
+		assert_eq!(0, eval("3 < 2 + 1"));
+		assert_eq!(1, eval("3 <= 2 + 1"));
+		assert!(Expression::parse("1 +").is_err());
+		assert!(Expression::parse("(1").is_err());
+		assert!(Expression::parse("$800").is_err());
+		assert!(Expression::parse("1 2").is_err());
+	}
+
+	#[test]
+	fn environment() {
+		// the NMI handler counts the frames with A held in $10, $11 becomes 1
+		// on start
+		let code = assemble(0x8000, "LDA #$80; STA $2000; JMP $8005").unwrap();
+		let handler = assemble(0x9000, "\
+			LDA #$01; STA $4016; LDA #$00; STA $4016; \
+			LDA $4016; AND #$01; CLC; ADC $10; STA $10; \
+			LDA $4016; LDA $4016; LDA $4016; AND #$01; STA $11; RTI").unwrap();
+		let cartridge = TestCartridge::builder()
+			.prg(0x8000, &code)
+			.prg(0x9000, &handler)
+			.nmi_vector(0x9000)
+			.build();
+		let mut environment = Environment::new(Nes::new(Box::new(cartridge)), "$10", "$11 == 1").unwrap();
+		environment.set_frames_per_step(2);
+		environment.reset();
+		let (_, reward, done) = environment.step(0);
+		assert_eq!((0, false), (reward, done));
+		let (_, reward, done) = environment.step(BUTTON_A);
+		assert_eq!((2, false), (reward, done));
+		let (_, reward, done) = environment.step(BUTTON_A | BUTTON_START);
+		assert_eq!((4, true), (reward, done));
+
+		environment.reset();
+		assert_eq!(0, environment.nes().peek_ram(0x10));
+	}
+}
@@ -0,0 +1,318 @@
+use ppu::Ppu;
+use zapper::Zapper;
+
+// A device plugged into the Famicom expansion port. It sees all writes to
+// $4016, and what it returns is merged into reads of both ports.
+pub trait ExpansionDevice {
+	fn write(&mut self, value: u8);
+	// Reads of the ports must not change the device, so this is also the peek.
+	fn read(&self, addr: u16) -> u8;
+	// Battery backed contents which should outlive the session.
+	fn battery_data(&self) -> Option<&[u8]> {
+		None
+	}
+}
+
+// Buttons of the standard controller, in the order they are read.
+pub const BUTTON_A: u8 = 0x01;
+pub const BUTTON_B: u8 = 0x02;
+pub const BUTTON_SELECT: u8 = 0x04;
+pub const BUTTON_START: u8 = 0x08;
+pub const BUTTON_UP: u8 = 0x10;
+pub const BUTTON_DOWN: u8 = 0x20;
+pub const BUTTON_LEFT: u8 = 0x40;
+pub const BUTTON_RIGHT: u8 = 0x80;
+
+// The controller ports at $4016 and $4017 with a standard controller in
+// each, the microphone of the Famicom's second controller and the
+// expansion port.
+// Writing bit 0 of $4016 is the strobe: While it is set, the controllers
+// reload their buttons continuously and reads return the current state of
+// A. Once it is cleared, every read returns the next button in bit 0, and
+// after the 8 buttons 1s, which the official controllers shift in.
+// Only the low bits are driven, the others are open bus, see OPEN_BUS_BITS.
+// The microphone shows up in bit 2 of $4016, which a few games poll, e.g.
+// to defeat Pols Voice in Zelda.
+// A Zapper replaces the controller in port 2.
+pub struct Input {
+	buttons: [u8; 2],
+	shift: [u8; 2],
+	strobe: bool,
+	microphone: bool,
+	zapper: Option<Zapper>,
+	expansion: Option<Box<dyn ExpansionDevice>>,
+}
+
+pub const PORT_1: u16 = 0x4016;
+pub const PORT_2: u16 = 0x4017;
+
+const MICROPHONE_BIT: u8 = 0x04;
+// Bits of reads of the ports which nothing drives, so they keep the last
+// value on the CPU data bus, usually $40 from the high byte of the
+// address. Some games check them to detect peripherals.
+pub const OPEN_BUS_BITS: u8 = 0xE0;
+
+impl Input {
+	pub fn new() -> Input {
+		Input {
+			buttons: [0; 2],
+			shift: [0; 2],
+			strobe: false,
+			microphone: false,
+			zapper: None,
+			expansion: None,
+		}
+	}
+
+	// Buttons held on the controller in port 0 or 1, see BUTTON_A etc.
+	pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+		self.buttons[port] = buttons;
+	}
+
+	// Whether something is loud enough to trigger the microphone.
+	pub fn set_microphone(&mut self, active: bool) {
+		self.microphone = active;
+	}
+
+	pub fn set_zapper(&mut self, zapper: Option<Zapper>) {
+		self.zapper = zapper;
+	}
+
+	pub fn zapper_mut(&mut self) -> Option<&mut Zapper> {
+		self.zapper.as_mut()
+	}
+
+	// Lets the Zapper look at the picture, to be called before reads of the
+	// ports.
+	pub fn sense_light(&mut self, ppu: &Ppu) {
+		if let Some(ref mut zapper) = self.zapper {
+			zapper.sense(ppu);
+		}
+	}
+
+	pub fn set_expansion_device(&mut self, device: Option<Box<dyn ExpansionDevice>>) {
+		self.expansion = device;
+	}
+
+	pub fn expansion_device(&self) -> Option<&dyn ExpansionDevice> {
+		self.expansion.as_deref()
+	}
+
+	pub fn take_expansion_device(&mut self) -> Option<Box<dyn ExpansionDevice>> {
+		self.expansion.take()
+	}
+
+	// Writes to $4016.
+	pub fn write(&mut self, value: u8) {
+		if self.strobe || value & 1 != 0 {
+			self.shift = self.buttons;
+		}
+		self.strobe = value & 1 != 0;
+		if let Some(ref mut device) = self.expansion {
+			device.write(value);
+		}
+	}
+
+	pub fn read(&mut self, addr: u16) -> u8 {
+		let value = self.peek(addr);
+		let port = (addr - PORT_1) as usize;
+		if self.strobe {
+			self.shift[port] = self.buttons[port];
+		} else {
+			self.shift[port] = (self.shift[port] >> 1) | 0x80;
+		}
+		value
+	}
+
+	// Returns what read would, without moving on to the next button. The
+	// open bus bits are 0, the CPU fills them in.
+	pub fn peek(&self, addr: u16) -> u8 {
+		let port = (addr - PORT_1) as usize;
+		let button = if self.strobe { self.buttons[port] } else { self.shift[port] } & 1;
+		let mut value = match (addr, self.zapper.as_ref()) {
+			(PORT_1, _) if self.microphone => button | MICROPHONE_BIT,
+			(PORT_2, Some(zapper)) => zapper.read(),
+			_ => button,
+		};
+		if let Some(ref device) = self.expansion {
+			value |= device.read(addr);
+		}
+		value
+	}
+}
+
+// The buttons in a text like "a+start", None for unknown names. An empty
+// text is no button.
+pub fn parse_buttons(text: &str) -> Option<u8> {
+	let mut buttons = 0;
+	for name in text.split('+').filter(|name| !name.is_empty()) {
+		buttons |= match name {
+			"a" => BUTTON_A,
+			"b" => BUTTON_B,
+			"select" => BUTTON_SELECT,
+			"start" => BUTTON_START,
+			"up" => BUTTON_UP,
+			"down" => BUTTON_DOWN,
+			"left" => BUTTON_LEFT,
+			"right" => BUTTON_RIGHT,
+			_ => return None,
+		};
+	}
+	Some(buttons)
+}
+
+// Buttons of controller 1 pressed after power on, e.g. to skip an intro.
+// Each step holds buttons from a frame on, e.g.
+//   120:start 126: 300:a+b 304:
+// holds Start in frames 120-125, A and B in frames 300-303. After the
+// frame of the last step the live input takes over.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootMacro {
+	steps: Vec<(u64, u8)>,
+}
+
+impl BootMacro {
+	pub fn parse(text: &str) -> Result<BootMacro, String> {
+		let mut steps: Vec<(u64, u8)> = Vec::new();
+		for step in text.split_whitespace() {
+			let (frame, buttons) = match step.find(':') {
+				Some(pos) => (&step[..pos], &step[pos + 1..]),
+				None => return Err(format!("Expected FRAME:BUTTONS instead of {}.", step)),
+			};
+			let frame = match frame.parse::<u64>() {
+				Ok(frame) if steps.last().map(|&(last, _)| frame > last).unwrap_or(true) => frame,
+				_ => return Err(format!("{} is not a frame after the previous step.", frame)),
+			};
+			match parse_buttons(buttons) {
+				Some(buttons) => steps.push((frame, buttons)),
+				None => return Err(format!("Unknown buttons {}, expected names like a+start.", buttons)),
+			}
+		}
+		if steps.is_empty() {
+			return Err(String::from("The macro has no steps."));
+		}
+		Ok(BootMacro { steps })
+	}
+
+	// The buttons held in a frame counted from power on, None once the
+	// macro is over.
+	pub fn buttons(&self, frame: u64) -> Option<u8> {
+		if frame > self.steps[self.steps.len() - 1].0 {
+			return None;
+		}
+		let held = self.steps.iter().take_while(|&&(start, _)| start <= frame).last();
+		Some(held.map(|&(_, buttons)| buttons).unwrap_or(0))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn microphone() {
+		let mut input = Input::new();
+		assert_eq!(0, input.read(PORT_1));
+		input.set_microphone(true);
+		assert_eq!(0x04, input.read(PORT_1));
+		assert_eq!(0, input.read(PORT_2));
+	}
+
+	#[test]
+	fn controller() {
+		let mut input = Input::new();
+		input.set_buttons(0, BUTTON_A | BUTTON_START | BUTTON_RIGHT);
+		input.set_buttons(1, BUTTON_B);
+		// strobe high: always A
+		input.write(1);
+		assert_eq!(1, input.read(PORT_1));
+		assert_eq!(1, input.read(PORT_1));
+		input.write(0);
+		input.set_buttons(0, 0);
+		let bits: Vec<u8> = (0..10).map(|_| input.read(PORT_1)).collect();
+		assert_eq!(vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1], bits);
+		assert_eq!(0, input.peek(PORT_2));
+		assert_eq!(0, input.read(PORT_2));
+		assert_eq!(1, input.peek(PORT_2));
+		assert_eq!(1, input.peek(PORT_2));
+
+		// strobe high: follows the buttons, and the falling edge latches them
+		input.write(1);
+		input.set_buttons(0, BUTTON_B);
+		assert_eq!(0, input.read(PORT_1));
+		input.set_buttons(0, BUTTON_A | BUTTON_SELECT);
+		assert_eq!(1, input.read(PORT_1));
+		input.write(0);
+		input.set_buttons(0, 0);
+		let bits: Vec<u8> = (0..3).map(|_| input.read(PORT_1)).collect();
+		assert_eq!(vec![1, 0, 1], bits);
+	}
+
+	#[test]
+	fn reads_after_buttons() {
+		let mut input = Input::new();
+		input.set_buttons(0, BUTTON_A | BUTTON_RIGHT);
+		input.set_buttons(1, BUTTON_SELECT);
+		input.write(1);
+		input.write(0);
+		// the 8 buttons, then 1s for as long as the strobe stays low
+		let bits: Vec<u8> = (0..16).map(|_| input.read(PORT_1)).collect();
+		assert_eq!(vec![1, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1], bits);
+		// the ports shift on their own
+		let bits: Vec<u8> = (0..12).map(|_| input.read(PORT_2)).collect();
+		assert_eq!(vec![0, 0, 1, 0, 0, 0, 0, 0, 1, 1, 1, 1], bits);
+		// another strobe starts over
+		input.write(1);
+		input.write(0);
+		assert_eq!(1, input.read(PORT_1));
+		assert_eq!(0, input.read(PORT_1));
+	}
+
+	#[test]
+	fn reads_while_strobe_high() {
+		let mut input = Input::new();
+		input.set_buttons(0, BUTTON_A | BUTTON_B);
+		input.write(1);
+		// reads do not shift, every one is A as held now
+		for _ in 0..10 {
+			assert_eq!(1, input.read(PORT_1));
+		}
+		input.set_buttons(0, BUTTON_B);
+		assert_eq!(0, input.read(PORT_1));
+		assert_eq!(0, input.peek(PORT_1));
+		// B follows A once the strobe is low, with the buttons of the
+		// falling edge
+		input.write(0);
+		input.set_buttons(0, 0);
+		assert_eq!(0, input.read(PORT_1));
+		assert_eq!(1, input.read(PORT_1));
+	}
+
+	#[test]
+	fn zapper() {
+		let mut input = Input::new();
+		input.set_buttons(1, BUTTON_A);
+		input.set_zapper(Some(Zapper::new(2)));
+		input.write(1);
+		assert_eq!(::zapper::LIGHT_BIT, input.read(PORT_2));
+		input.zapper_mut().unwrap().set_trigger(true);
+		assert_eq!(::zapper::LIGHT_BIT | ::zapper::TRIGGER_BIT, input.read(PORT_2));
+		assert_eq!(0, input.read(PORT_1));
+	}
+
+	#[test]
+	fn boot_macro() {
+		let boot = BootMacro::parse("120:start 126: 300:a+b").unwrap();
+		assert_eq!(Some(0), boot.buttons(0));
+		assert_eq!(Some(BUTTON_START), boot.buttons(120));
+		assert_eq!(Some(BUTTON_START), boot.buttons(125));
+		assert_eq!(Some(0), boot.buttons(126));
+		assert_eq!(Some(BUTTON_A | BUTTON_B), boot.buttons(300));
+		assert_eq!(None, boot.buttons(301));
+
+		assert!(BootMacro::parse("").is_err());
+		assert!(BootMacro::parse("120").is_err());
+		assert!(BootMacro::parse("120:start 100:").is_err());
+		assert_eq!(Err(String::from("Unknown buttons turbo, expected names like a+start.")), BootMacro::parse("1:turbo"));
+	}
+}
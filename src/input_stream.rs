@@ -0,0 +1,210 @@
+use input::parse_buttons;
+use logging::{Level, Category};
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// Buttons piped in by another program, e.g. a script or a fuzzer, without
+// recording a movie. Each record holds buttons on both controllers from a
+// frame counted from power on, until the record of a later frame. The
+// stream comes as text, a record per line:
+//   120 start        # controller 1 only
+//   126 -
+//   300 a+b right    # controller 2 as well
+//   304 0x00 0x00
+// or binary, 6 bytes per record: the frame as 32 bit little endian number
+// and the buttons of both controllers as bits, see input::BUTTON_A.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamFormat {
+	Text,
+	Binary,
+}
+
+impl StreamFormat {
+	pub fn from_name(name: &str) -> Option<StreamFormat> {
+		match name {
+			"text" => Some(StreamFormat::Text),
+			"binary" => Some(StreamFormat::Binary),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputRecord {
+	pub frame: u64,
+	pub buttons: [u8; 2],
+}
+
+// Buttons of the text format: names like a+start, - for none, or a number
+// of the button bits like 0x09.
+fn parse_stream_buttons(text: &str) -> Option<u8> {
+	if text == "-" {
+		Some(0)
+	} else if let Some(hex) = text.strip_prefix("0x") {
+		u8::from_str_radix(hex, 16).ok()
+	} else {
+		parse_buttons(text)
+	}
+}
+
+// A line of the text format, None for empty lines and comments.
+pub fn parse_line(line: &str) -> Result<Option<InputRecord>, String> {
+	let line = line.split('#').next().unwrap_or("");
+	let mut fields = line.split_whitespace();
+	let frame = match fields.next() {
+		Some(frame) => frame.parse::<u64>().map_err(|_| format!("{} is not a frame number.", frame))?,
+		None => return Ok(None),
+	};
+	let mut buttons = [0; 2];
+	for (port, text) in fields.enumerate() {
+		if port >= 2 {
+			return Err(String::from("Expected the buttons of at most two controllers."));
+		}
+		buttons[port] = (parse_stream_buttons(text)
+			.ok_or_else(|| format!("Unknown buttons {}, expected names like a+start, - or 0x09.", text)))?;
+	}
+	Ok(Some(InputRecord { frame, buttons }))
+}
+
+// Hands the records of a stream to handle until it returns false or the
+// stream ends. Malformed lines are handed over as errors.
+pub fn read_records<R: Read, F: FnMut(Result<InputRecord, String>) -> bool>(input: R, format: StreamFormat, mut handle: F) {
+	match format {
+		StreamFormat::Text => {
+			for (number, line) in BufReader::new(input).lines().enumerate() {
+				let result = match line {
+					Ok(line) => parse_line(&line),
+					Err(_) => return,
+				};
+				let keep_reading = match result {
+					Ok(Some(record)) => handle(Ok(record)),
+					Ok(None) => true,
+					Err(err) => handle(Err(format!("line {}: {}", number + 1, err))),
+				};
+				if !keep_reading {
+					return;
+				}
+			}
+		}
+		StreamFormat::Binary => {
+			let mut input = BufReader::new(input);
+			let mut record = [0; 6];
+			while input.read_exact(&mut record).is_ok() {
+				let frame = record[0] as u64 | (record[1] as u64) << 8 | (record[2] as u64) << 16 | (record[3] as u64) << 24;
+				if !handle(Ok(InputRecord { frame, buttons: [record[4], record[5]] })) {
+					return;
+				}
+			}
+		}
+	}
+}
+
+// The records of a stream, read on a thread of their own so the emulation
+// does not wait for them unless it asks to.
+pub struct InputStream {
+	records: Receiver<Result<InputRecord, String>>,
+	// The next record, not due yet.
+	next: Option<InputRecord>,
+	held: [u8; 2],
+	// The frame of the last record, later ones must not go back.
+	last_frame: Option<u64>,
+	ended: bool,
+}
+
+impl InputStream {
+	pub fn new<R: Read + Send + 'static>(input: R, format: StreamFormat) -> InputStream {
+		let (sender, receiver) = mpsc::channel();
+		thread::spawn(move || read_records(input, format, |record| sender.send(record).is_ok()));
+		InputStream {
+			records: receiver,
+			next: None,
+			held: [0, 0],
+			last_frame: None,
+			ended: false,
+		}
+	}
+
+	pub fn stdin(format: StreamFormat) -> InputStream {
+		InputStream::new(io::stdin(), format)
+	}
+
+	// The buttons of both controllers in a frame. With wait the stream is
+	// read until a record of a later frame or its end, so the input is the
+	// same on every run. Without, records which did not arrive yet apply in
+	// a later frame, which keeps a window responsive. Records for earlier
+	// frames apply right away.
+	pub fn buttons(&mut self, frame: u64, wait: bool) -> [u8; 2] {
+		loop {
+			if let Some(record) = self.next {
+				if record.frame > frame {
+					break;
+				}
+				self.held = record.buttons;
+				self.next = None;
+			}
+			if self.ended {
+				break;
+			}
+			let received = if wait {
+				self.records.recv().map_err(|_| TryRecvError::Disconnected)
+			} else {
+				self.records.try_recv()
+			};
+			match received {
+				Ok(Ok(record)) => {
+					if self.last_frame.map(|last| record.frame < last).unwrap_or(false) {
+						log!(Level::Warn, Category::Input, "Input stream: frame {} comes after frame {}, skipped.",
+							record.frame, self.last_frame.unwrap());
+						continue;
+					}
+					self.last_frame = Some(record.frame);
+					self.next = Some(record);
+				}
+				Ok(Err(err)) => log!(Level::Warn, Category::Input, "Input stream: {}", err),
+				Err(TryRecvError::Empty) => break,
+				Err(TryRecvError::Disconnected) => self.ended = true,
+			}
+		}
+		self.held
+	}
+
+	// Whether the stream ended and all of its records were applied.
+	pub fn finished(&self) -> bool {
+		self.ended && self.next.is_none()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use input::{BUTTON_A, BUTTON_B, BUTTON_START, BUTTON_RIGHT};
+	use std::io::Cursor;
+
+	#[test]
+	fn text_records() {
+		assert_eq!(Ok(None), parse_line("  # nothing"));
+		assert_eq!(Ok(Some(InputRecord { frame: 120, buttons: [BUTTON_START, 0] })), parse_line("120 start"));
+		assert_eq!(Ok(Some(InputRecord { frame: 3, buttons: [BUTTON_A | BUTTON_B, 0x81] })), parse_line("3 a+b 0x81 # both"));
+		assert_eq!(Ok(Some(InputRecord { frame: 7, buttons: [0, BUTTON_RIGHT] })), parse_line("7 - right"));
+		assert!(parse_line("start 120").is_err());
+		assert!(parse_line("120 jump").is_err());
+		assert!(parse_line("120 a b c").is_err());
+	}
+
+	#[test]
+	fn stream() {
+		let text = "2 start\n4 -\nbogus\n3 a\n6 a+b right\n";
+		let mut stream = InputStream::new(Cursor::new(text), StreamFormat::Text);
+		let buttons: Vec<[u8; 2]> = (0..8).map(|frame| stream.buttons(frame, true)).collect();
+		assert_eq!(vec![[0, 0], [0, 0], [BUTTON_START, 0], [BUTTON_START, 0], [0, 0], [0, 0],
+			[BUTTON_A | BUTTON_B, BUTTON_RIGHT], [BUTTON_A | BUTTON_B, BUTTON_RIGHT]], buttons);
+		assert!(stream.finished());
+
+		let binary = vec![1, 0, 0, 0, BUTTON_A, 0, 0, 1, 0, 0, 0, BUTTON_B, 0xFF];
+		let mut stream = InputStream::new(Cursor::new(binary), StreamFormat::Binary);
+		assert_eq!([BUTTON_A, 0], stream.buttons(255, true));
+		assert_eq!([0, BUTTON_B], stream.buttons(256, true));
+		assert!(stream.finished());
+	}
+}
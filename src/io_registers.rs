@@ -0,0 +1,346 @@
+// Documentation of the memory mapped PPU, APU and IO registers, so
+// debuggers and traces can explain accesses, e.g.
+// "write $2001 PPUMASK: BG on, sprites on".
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+	Read,
+	Write,
+	ReadWrite,
+}
+
+// Part of a register value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+	// A single bit, described by the text for set or clear. Empty texts
+	// are left out.
+	Flag(u8, &'static str, &'static str),
+	// A number in the masked bits, shown as "name value".
+	Value(u8, &'static str),
+}
+
+#[derive(Debug)]
+pub struct RegisterInfo {
+	pub addr: u16,
+	pub name: &'static str,
+	pub access: Access,
+	pub description: &'static str,
+	// Meaning of written and read values. Registers without fields are
+	// shown as a hexadecimal byte.
+	pub write_fields: &'static [Field],
+	pub read_fields: &'static [Field],
+}
+
+impl RegisterInfo {
+	// Describes a value written to the register.
+	pub fn describe_write(&self, value: u8) -> String {
+		format!("{}: {}", self.name, describe_fields(self.write_fields, value))
+	}
+
+	// Describes a value read from the register.
+	pub fn describe_read(&self, value: u8) -> String {
+		format!("{}: {}", self.name, describe_fields(self.read_fields, value))
+	}
+
+	// e.g. "$2002 PPUSTATUS, read only. PPU status, reading clears vblank and the address latch"
+	pub fn summary(&self) -> String {
+		let access = match self.access {
+			Access::Read => "read only",
+			Access::Write => "write only",
+			Access::ReadWrite => "read and write",
+		};
+		format!("${:04X} {}, {}. {}", self.addr, self.name, access, self.description)
+	}
+}
+
+fn describe_fields(fields: &[Field], value: u8) -> String {
+	if fields.is_empty() {
+		return format!("{:02X}", value);
+	}
+	let parts: Vec<String> = fields.iter().filter_map(|field| match *field {
+		Field::Flag(mask, set, clear) => {
+			let text = if value & mask != 0 { set } else { clear };
+			if text.is_empty() { None } else { Some(String::from(text)) }
+		}
+		Field::Value(mask, name) => Some(format!("{} {}", name, (value & mask) >> mask.trailing_zeros())),
+	}).collect();
+	if parts.is_empty() {
+		String::from("-")
+	} else {
+		parts.join(", ")
+	}
+}
+
+// The register at the address, PPU registers are mirrored up to 3FFF.
+pub fn describe_register(addr: u16) -> Option<&'static RegisterInfo> {
+	let addr = if (0x2000..0x4000).contains(&addr) { addr & 0x2007 } else { addr };
+	REGISTERS.iter().find(|register| register.addr == addr)
+}
+
+const ENVELOPE: &[Field] = &[
+	Field::Value(0xC0, "duty"),
+	Field::Flag(0x20, "loop", ""),
+	Field::Flag(0x10, "constant volume", ""),
+	Field::Value(0x0F, "volume"),
+];
+
+const SWEEP: &[Field] = &[
+	Field::Flag(0x80, "sweep on", "sweep off"),
+	Field::Value(0x70, "period"),
+	Field::Flag(0x08, "negate", ""),
+	Field::Value(0x07, "shift"),
+];
+
+const LENGTH_TIMER_HIGH: &[Field] = &[
+	Field::Value(0xF8, "length index"),
+	Field::Value(0x07, "timer high"),
+];
+
+const CONTROLLER_READ: &[Field] = &[
+	Field::Flag(0x01, "data 1", "data 0"),
+];
+
+pub const REGISTERS: &[RegisterInfo] = &[
+	RegisterInfo {
+		addr: 0x2000, name: "PPUCTRL", access: Access::Write,
+		description: "PPU control: nametable, increment, pattern tables, sprite size, NMI",
+		write_fields: &[
+			Field::Value(0x03, "nametable"),
+			Field::Flag(0x04, "increment 32", "increment 1"),
+			Field::Flag(0x08, "sprites at 1000", "sprites at 0000"),
+			Field::Flag(0x10, "BG at 1000", "BG at 0000"),
+			Field::Flag(0x20, "sprites 8x16", "sprites 8x8"),
+			Field::Flag(0x40, "EXT out", ""),
+			Field::Flag(0x80, "NMI on", "NMI off"),
+		],
+		read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x2001, name: "PPUMASK", access: Access::Write,
+		description: "PPU mask: rendering, left column, grayscale, color emphasis",
+		write_fields: &[
+			Field::Flag(0x01, "grayscale", ""),
+			Field::Flag(0x02, "BG left", ""),
+			Field::Flag(0x04, "sprites left", ""),
+			Field::Flag(0x08, "BG on", "BG off"),
+			Field::Flag(0x10, "sprites on", "sprites off"),
+			Field::Flag(0x20, "emphasize red", ""),
+			Field::Flag(0x40, "emphasize green", ""),
+			Field::Flag(0x80, "emphasize blue", ""),
+		],
+		read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x2002, name: "PPUSTATUS", access: Access::Read,
+		description: "PPU status, reading clears vblank and the address latch",
+		write_fields: &[],
+		read_fields: &[
+			Field::Flag(0x20, "sprite overflow", ""),
+			Field::Flag(0x40, "sprite 0 hit", ""),
+			Field::Flag(0x80, "vblank", ""),
+		],
+	},
+	RegisterInfo {
+		addr: 0x2003, name: "OAMADDR", access: Access::Write,
+		description: "OAM address for OAMDATA",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x2004, name: "OAMDATA", access: Access::ReadWrite,
+		description: "OAM data, writes increment OAMADDR",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x2005, name: "PPUSCROLL", access: Access::Write,
+		description: "Scroll position, X on the first and Y on the second write",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x2006, name: "PPUADDR", access: Access::Write,
+		description: "VRAM address, high byte on the first and low byte on the second write",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x2007, name: "PPUDATA", access: Access::ReadWrite,
+		description: "VRAM data, reads below 3F00 are buffered",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4000, name: "SQ1_VOL", access: Access::Write,
+		description: "Pulse 1 duty and envelope",
+		write_fields: ENVELOPE, read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4001, name: "SQ1_SWEEP", access: Access::Write,
+		description: "Pulse 1 sweep unit",
+		write_fields: SWEEP, read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4002, name: "SQ1_LO", access: Access::Write,
+		description: "Pulse 1 timer low byte",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4003, name: "SQ1_HI", access: Access::Write,
+		description: "Pulse 1 length counter and timer high bits",
+		write_fields: LENGTH_TIMER_HIGH, read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4004, name: "SQ2_VOL", access: Access::Write,
+		description: "Pulse 2 duty and envelope",
+		write_fields: ENVELOPE, read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4005, name: "SQ2_SWEEP", access: Access::Write,
+		description: "Pulse 2 sweep unit",
+		write_fields: SWEEP, read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4006, name: "SQ2_LO", access: Access::Write,
+		description: "Pulse 2 timer low byte",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4007, name: "SQ2_HI", access: Access::Write,
+		description: "Pulse 2 length counter and timer high bits",
+		write_fields: LENGTH_TIMER_HIGH, read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4008, name: "TRI_LINEAR", access: Access::Write,
+		description: "Triangle linear counter",
+		write_fields: &[
+			Field::Flag(0x80, "control", ""),
+			Field::Value(0x7F, "reload"),
+		],
+		read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x400A, name: "TRI_LO", access: Access::Write,
+		description: "Triangle timer low byte",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x400B, name: "TRI_HI", access: Access::Write,
+		description: "Triangle length counter and timer high bits",
+		write_fields: LENGTH_TIMER_HIGH, read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x400C, name: "NOISE_VOL", access: Access::Write,
+		description: "Noise envelope",
+		write_fields: &[
+			Field::Flag(0x20, "loop", ""),
+			Field::Flag(0x10, "constant volume", ""),
+			Field::Value(0x0F, "volume"),
+		],
+		read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x400E, name: "NOISE_LO", access: Access::Write,
+		description: "Noise mode and period",
+		write_fields: &[
+			Field::Flag(0x80, "short mode", ""),
+			Field::Value(0x0F, "period"),
+		],
+		read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x400F, name: "NOISE_HI", access: Access::Write,
+		description: "Noise length counter",
+		write_fields: &[Field::Value(0xF8, "length index")],
+		read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4010, name: "DMC_FREQ", access: Access::Write,
+		description: "DMC IRQ, loop and rate",
+		write_fields: &[
+			Field::Flag(0x80, "IRQ on", ""),
+			Field::Flag(0x40, "loop", ""),
+			Field::Value(0x0F, "rate"),
+		],
+		read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4011, name: "DMC_RAW", access: Access::Write,
+		description: "DMC output level",
+		write_fields: &[Field::Value(0x7F, "level")],
+		read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4012, name: "DMC_START", access: Access::Write,
+		description: "DMC sample address, C000 + value * 64",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4013, name: "DMC_LEN", access: Access::Write,
+		description: "DMC sample length, value * 16 + 1 bytes",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4014, name: "OAMDMA", access: Access::Write,
+		description: "Copies the page value * 100 to OAM",
+		write_fields: &[], read_fields: &[],
+	},
+	RegisterInfo {
+		addr: 0x4015, name: "SND_CHN", access: Access::ReadWrite,
+		description: "Channel enables, reads the length counter and IRQ status",
+		write_fields: &[
+			Field::Flag(0x01, "pulse 1", ""),
+			Field::Flag(0x02, "pulse 2", ""),
+			Field::Flag(0x04, "triangle", ""),
+			Field::Flag(0x08, "noise", ""),
+			Field::Flag(0x10, "DMC", ""),
+		],
+		read_fields: &[
+			Field::Flag(0x01, "pulse 1", ""),
+			Field::Flag(0x02, "pulse 2", ""),
+			Field::Flag(0x04, "triangle", ""),
+			Field::Flag(0x08, "noise", ""),
+			Field::Flag(0x10, "DMC", ""),
+			Field::Flag(0x40, "frame IRQ", ""),
+			Field::Flag(0x80, "DMC IRQ", ""),
+		],
+	},
+	RegisterInfo {
+		addr: 0x4016, name: "JOY1", access: Access::ReadWrite,
+		description: "Controller strobe, reads controller 1 serially",
+		write_fields: &[Field::Flag(0x01, "strobe on", "strobe off")],
+		read_fields: CONTROLLER_READ,
+	},
+	RegisterInfo {
+		addr: 0x4017, name: "JOY2", access: Access::ReadWrite,
+		description: "APU frame counter, reads controller 2 serially",
+		write_fields: &[
+			Field::Flag(0x80, "5-step", "4-step"),
+			Field::Flag(0x40, "IRQ inhibit", ""),
+		],
+		read_fields: CONTROLLER_READ,
+	},
+];
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn describe() {
+		let ppumask = describe_register(0x2001).unwrap();
+		assert_eq!("PPUMASK: BG on, sprites on", ppumask.describe_write(0x18));
+		assert_eq!("PPUMASK: BG off, sprites off", ppumask.describe_write(0x00));
+		assert_eq!("PPUCTRL", describe_register(0x3FF8).unwrap().name);
+		assert_eq!("PPUCTRL: nametable 2, increment 1, sprites at 0000, BG at 1000, sprites 8x8, NMI on",
+			describe_register(0x2000).unwrap().describe_write(0x92));
+		assert_eq!("PPUSTATUS: vblank", describe_register(0x2002).unwrap().describe_read(0x80));
+		assert_eq!("PPUADDR: 3F", describe_register(0x2006).unwrap().describe_write(0x3F));
+		assert_eq!("SND_CHN: -", describe_register(0x4015).unwrap().describe_read(0x00));
+		assert!(describe_register(0x4009).is_none());
+		assert!(describe_register(0x0002).is_none());
+		assert_eq!("$2000 PPUCTRL, write only. PPU control: nametable, increment, pattern tables, sprite size, NMI",
+			describe_register(0x2000).unwrap().summary());
+
+		let mut last = 0;
+		for register in REGISTERS.iter() {
+			assert!(register.addr > last);
+			last = register.addr;
+		}
+	}
+}
@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+// A game of the playlist, with the demo movie which plays it. Without a
+// movie the game runs its own attract mode, or its boot macro.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KioskEntry {
+	pub rom: String,
+	pub movie: Option<String>,
+}
+
+// Demo station: plays the games of a playlist one after another, each for
+// a while or until the coin key is pressed. The playlist has a game per
+// line, the ROM and optionally a movie, separated by white space:
+//
+//   # comments start with #
+//   games/smb.nes demos/smb.nesmovie
+//   games/tetris.nes
+pub struct Kiosk {
+	entries: Vec<KioskEntry>,
+	current: usize,
+	duration: Duration,
+	started: Instant,
+}
+
+impl Kiosk {
+	pub fn new(entries: Vec<KioskEntry>, duration: Duration, now: Instant) -> Kiosk {
+		Kiosk {
+			entries,
+			current: 0,
+			duration,
+			started: now,
+		}
+	}
+
+	pub fn load(path: &str, duration: Duration) -> Result<Kiosk, String> {
+		let mut text = String::new();
+		if let Err(err) = File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+			return Err(format!("{}: {}", path, err));
+		}
+		let entries = Kiosk::parse(&text).map_err(|err| format!("{}: {}", path, err))?;
+		Ok(Kiosk::new(entries, duration, Instant::now()))
+	}
+
+	pub fn parse(text: &str) -> Result<Vec<KioskEntry>, String> {
+		let mut entries = Vec::new();
+		for (i, line) in text.lines().enumerate() {
+			let line = match line.find('#') {
+				Some(pos) => &line[..pos],
+				None => line,
+			};
+			let words: Vec<&str> = line.split_whitespace().collect();
+			match words.len() {
+				0 => {}
+				1 | 2 => entries.push(KioskEntry {
+					rom: String::from(words[0]),
+					movie: words.get(1).map(|&movie| String::from(movie)),
+				}),
+				_ => return Err(format!("line {}: Expected a ROM and optionally a movie.", i + 1)),
+			}
+		}
+		if entries.is_empty() {
+			return Err(String::from("The playlist has no games."));
+		}
+		Ok(entries)
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub fn current(&self) -> &KioskEntry {
+		&self.entries[self.current]
+	}
+
+	// Whether the time of the current game is over.
+	pub fn due(&self, now: Instant) -> bool {
+		now.duration_since(self.started) >= self.duration
+	}
+
+	// Moves on to the next game, after the last one to the first again.
+	pub fn advance(&mut self, now: Instant) -> &KioskEntry {
+		self.current = (self.current + 1) % self.entries.len();
+		self.started = now;
+		&self.entries[self.current]
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn playlist() {
+		let entries = Kiosk::parse("
+			# demo station
+			games/smb.nes demos/smb.nesmovie
+			games/tetris.nes  # attract mode
+		").unwrap();
+		assert_eq!(vec![
+			KioskEntry { rom: String::from("games/smb.nes"), movie: Some(String::from("demos/smb.nesmovie")) },
+			KioskEntry { rom: String::from("games/tetris.nes"), movie: None },
+		], entries);
+		assert!(Kiosk::parse("# nothing").is_err());
+		assert_eq!(Err(String::from("line 2: Expected a ROM and optionally a movie.")), Kiosk::parse("\na b c"));
+
+		let start = Instant::now();
+		let mut kiosk = Kiosk::new(entries, Duration::from_secs(60), start);
+		assert!(!kiosk.due(start + Duration::from_secs(59)));
+		assert!(kiosk.due(start + Duration::from_secs(60)));
+		assert_eq!("games/tetris.nes", kiosk.advance(start + Duration::from_secs(60)).rom);
+		assert!(!kiosk.due(start + Duration::from_secs(61)));
+		assert_eq!("games/smb.nes", kiosk.advance(start).rom);
+		assert_eq!("games/smb.nes", kiosk.current().rom);
+	}
+}
@@ -0,0 +1,134 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::Mutex;
+
+// Diagnostics of the emulator core go through the log! macro instead of
+// being printed, so frontends and library users can filter, redirect or
+// silence them. Without a logger installed, Info and above go to stdout.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+	Error,
+	Warn,
+	Info,
+	Debug,
+}
+
+impl Level {
+	pub fn from_name(name: &str) -> Option<Level> {
+		match name {
+			"error" => Some(Level::Error),
+			"warn" => Some(Level::Warn),
+			"info" => Some(Level::Info),
+			"debug" => Some(Level::Debug),
+			_ => None,
+		}
+	}
+}
+
+// The part of the emulator a message comes from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+	Loader,
+	Mapper,
+	Input,
+}
+
+impl fmt::Display for Category {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match *self {
+			Category::Loader => "loader",
+			Category::Mapper => "mapper",
+			Category::Input => "input",
+		};
+		write!(f, "{}", name)
+	}
+}
+
+// Receives all log messages.
+pub trait Logger: Send {
+	fn log(&mut self, level: Level, category: Category, message: &str);
+}
+
+// Writes messages up to a level, one per line.
+pub struct WriteLogger {
+	out: Box<dyn Write + Send>,
+	max_level: Level,
+}
+
+impl WriteLogger {
+	pub fn new(out: Box<dyn Write + Send>, max_level: Level) -> WriteLogger {
+		WriteLogger {
+			out,
+			max_level,
+		}
+	}
+}
+
+impl Logger for WriteLogger {
+	fn log(&mut self, level: Level, category: Category, message: &str) {
+		if level <= self.max_level {
+			let _ = writeln!(self.out, "[{:?}] {}: {}", level, category, message);
+			let _ = self.out.flush();
+		}
+	}
+}
+
+static LOGGER: Mutex<Option<Box<dyn Logger>>> = Mutex::new(None);
+
+// Installs the logger for all threads. None restores the default.
+pub fn set_logger(logger: Option<Box<dyn Logger>>) {
+	*LOGGER.lock().unwrap() = logger;
+}
+
+pub fn log(level: Level, category: Category, message: &str) {
+	match *LOGGER.lock().unwrap() {
+		Some(ref mut logger) => logger.log(level, category, message),
+		None => {
+			if level <= Level::Info {
+				println!("{}", message);
+			}
+		}
+	}
+}
+
+// log!(Level::Info, Category::Loader, "format {}", args)
+macro_rules! log {
+	($level:expr, $category:expr, $($arg:tt)*) => {
+		::logging::log($level, $category, &format!($($arg)*))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+	use std::thread::{self, ThreadId};
+
+	// Records the messages of one thread, as the other tests log from
+	// theirs meanwhile, e.g. the mapper faults of the PPU tests.
+	struct Recorder {
+		thread: ThreadId,
+		messages: Arc<Mutex<Vec<(Level, String)>>>,
+	}
+
+	impl Logger for Recorder {
+		fn log(&mut self, level: Level, category: Category, message: &str) {
+			if category == Category::Mapper && thread::current().id() == self.thread {
+				self.messages.lock().unwrap().push((level, message.to_string()));
+			}
+		}
+	}
+
+	#[test]
+	fn logger() {
+		let messages = Arc::new(Mutex::new(Vec::new()));
+		set_logger(Some(Box::new(Recorder { thread: thread::current().id(), messages: messages.clone() })));
+		log!(Level::Warn, Category::Mapper, "bank {}", 3);
+		set_logger(None);
+		log!(Level::Debug, Category::Mapper, "not recorded");
+		assert_eq!(vec![(Level::Warn, String::from("bank 3"))], *messages.lock().unwrap());
+		assert_eq!(Some(Level::Debug), Level::from_name("debug"));
+		assert!(Level::Error < Level::Info);
+	}
+}
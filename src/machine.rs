@@ -0,0 +1,92 @@
+// The console model, for the famiclones which differ from the consoles of
+// Nintendo in ways games can notice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachinePreset {
+	// 2 KiB of RAM mirrored four times, cartridge audio mixed in.
+	Famicom,
+	// The cartridge audio of the NES only reaches the expansion port, which
+	// nothing is plugged into.
+	Nes,
+	// Clones with 8 KiB of RAM filling 0000 - 1FFF unmirrored, and no
+	// cartridge audio pins.
+	Famiclone,
+}
+
+impl MachinePreset {
+	pub fn from_name(name: &str) -> Option<MachinePreset> {
+		match name {
+			"famicom" => Some(MachinePreset::Famicom),
+			"nes" => Some(MachinePreset::Nes),
+			"famiclone" => Some(MachinePreset::Famiclone),
+			_ => None,
+		}
+	}
+}
+
+// The hardware of the console around the CPU and the PPU. It is chosen per
+// game or on the command line, and takes effect on the next power cycle,
+// see Nes::set_machine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineConfig {
+	// Size of the internal RAM in bytes, a power of two which fits into
+	// 0000 - 1FFF. Smaller RAM is mirrored over the whole area.
+	pub ram_size: u16,
+	// Whether the audio of the cartridge reaches the output, see
+	// Cartridge::expansion_audio.
+	pub expansion_audio: bool,
+}
+
+impl MachineConfig {
+	pub fn new() -> MachineConfig {
+		MachineConfig::from_preset(MachinePreset::Famicom)
+	}
+
+	pub fn from_preset(preset: MachinePreset) -> MachineConfig {
+		match preset {
+			MachinePreset::Famicom => MachineConfig {
+				ram_size: 0x800,
+				expansion_audio: true,
+			},
+			MachinePreset::Nes => MachineConfig {
+				ram_size: 0x800,
+				expansion_audio: false,
+			},
+			MachinePreset::Famiclone => MachineConfig {
+				ram_size: 0x2000,
+				expansion_audio: false,
+			},
+		}
+	}
+
+	// The RAM size in KiB, 2, 4 or 8, or an error message.
+	pub fn set_ram_kib(&mut self, kib: u16) -> Result<(), String> {
+		match kib {
+			2 | 4 | 8 => {
+				self.ram_size = kib * 1024;
+				Ok(())
+			}
+			_ => Err(String::from("The RAM size must be 2, 4 or 8 KiB.")),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn presets() {
+		assert_eq!(MachineConfig::from_preset(MachinePreset::Famicom), MachineConfig::new());
+		let clone = MachineConfig::from_preset(MachinePreset::from_name("famiclone").unwrap());
+		assert_eq!(8192, clone.ram_size);
+		assert!(!clone.expansion_audio);
+		assert_eq!(None, MachinePreset::from_name("dendy"));
+
+		let mut config = MachineConfig::new();
+		assert!(config.set_ram_kib(4).is_ok());
+		assert_eq!(4096, config.ram_size);
+		assert!(config.set_ram_kib(3).is_err());
+		assert!(config.set_ram_kib(16).is_err());
+		assert_eq!(4096, config.ram_size);
+	}
+}
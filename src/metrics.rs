@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// Number of frames the FPS and the frame time percentiles are taken over,
+// about 10 seconds.
+const WINDOW: usize = 600;
+
+#[derive(Debug, Clone, Copy)]
+struct FrameSample {
+	// time between this frame and the previous one
+	interval: f64,
+	// time spent emulating and rendering it
+	work: f64,
+}
+
+// Runtime metrics for long running deployments like the kiosk, and to
+// track the performance across versions, written periodically to a file
+// with --metrics:
+//   fps               frames shown per second, over the last frames
+//   frame time        percentiles of the time spent on a frame, without
+//                     the wait for the next one
+//   audio underruns   times the audio output ran out of samples
+//   desyncs           movie replays which diverged from the recording
+pub struct Metrics {
+	started: Instant,
+	last_frame: Option<Instant>,
+	samples: VecDeque<FrameSample>,
+	frames: u64,
+	audio_underruns: u64,
+	desyncs: u64,
+}
+
+impl Metrics {
+	pub fn new(now: Instant) -> Metrics {
+		Metrics {
+			started: now,
+			last_frame: None,
+			samples: VecDeque::with_capacity(WINDOW),
+			frames: 0,
+			audio_underruns: 0,
+			desyncs: 0,
+		}
+	}
+
+	// A frame was shown at now, after work time spent on it.
+	pub fn record_frame(&mut self, work: Duration, audio_underrun: bool, now: Instant) {
+		if let Some(last) = self.last_frame {
+			if self.samples.len() == WINDOW {
+				self.samples.pop_front();
+			}
+			self.samples.push_back(FrameSample {
+				interval: millis(now.duration_since(last)),
+				work: millis(work),
+			});
+		}
+		self.last_frame = Some(now);
+		self.frames += 1;
+		if audio_underrun {
+			self.audio_underruns += 1;
+		}
+	}
+
+	pub fn record_desync(&mut self) {
+		self.desyncs += 1;
+	}
+
+	pub fn fps(&self) -> f64 {
+		let total: f64 = self.samples.iter().map(|sample| sample.interval).sum();
+		if total > 0.0 { self.samples.len() as f64 * 1000.0 / total } else { 0.0 }
+	}
+
+	// The frame time in milliseconds which the given share of the recent
+	// frames took at most, e.g. 0.99 for the 99th percentile.
+	pub fn frame_time_percentile(&self, share: f64) -> f64 {
+		if self.samples.is_empty() {
+			return 0.0;
+		}
+		let mut times: Vec<f64> = self.samples.iter().map(|sample| sample.work).collect();
+		times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+		let rank = (share * times.len() as f64).ceil() as usize;
+		times[rank.clamp(1, times.len()) - 1]
+	}
+
+	// e.g. {"game":"smb","uptime_seconds":12.0,"frames":720,"fps":60.1,...}
+	pub fn to_json(&self, game: &str, now: Instant) -> String {
+		format!("{{\"game\":\"{}\",\"uptime_seconds\":{:.1},\"frames\":{},\"fps\":{:.2},\
+			\"frame_time_ms\":{{\"p50\":{:.3},\"p95\":{:.3},\"p99\":{:.3}}},\
+			\"audio_underruns\":{},\"desyncs\":{}}}\n",
+			json_escape(game), seconds(now.duration_since(self.started)), self.frames, self.fps(),
+			self.frame_time_percentile(0.5), self.frame_time_percentile(0.95), self.frame_time_percentile(0.99),
+			self.audio_underruns, self.desyncs)
+	}
+
+	// The text format of Prometheus, e.g. for the textfile collector of the
+	// node exporter.
+	pub fn to_prometheus(&self, game: &str, now: Instant) -> String {
+		let label = format!("{{game=\"{}\"}}", json_escape(game));
+		let mut text = String::new();
+		let mut metric = |name: &str, kind: &str, help: &str, values: Vec<(String, String)>| {
+			text.push_str(&format!("# HELP nes_{} {}\n# TYPE nes_{} {}\n", name, help, name, kind));
+			for (labels, value) in values {
+				text.push_str(&format!("nes_{}{} {}\n", name, labels, value));
+			}
+		};
+		metric("uptime_seconds", "gauge", "Time since the emulator started.",
+			vec![(label.clone(), format!("{:.1}", seconds(now.duration_since(self.started))))]);
+		metric("frames_total", "counter", "Frames shown.",
+			vec![(label.clone(), self.frames.to_string())]);
+		metric("fps", "gauge", "Frames shown per second over the last frames.",
+			vec![(label.clone(), format!("{:.2}", self.fps()))]);
+		metric("frame_time_milliseconds", "summary", "Time spent emulating and rendering a frame.",
+			[0.5, 0.95, 0.99].iter().map(|&share| {
+				(format!("{{game=\"{}\",quantile=\"{}\"}}", json_escape(game), share),
+					format!("{:.3}", self.frame_time_percentile(share)))
+			}).collect());
+		metric("audio_underruns_total", "counter", "Times the audio output ran out of samples.",
+			vec![(label.clone(), self.audio_underruns.to_string())]);
+		metric("desyncs_total", "counter", "Movie replays which diverged from the recording.",
+			vec![(label, self.desyncs.to_string())]);
+		text
+	}
+
+	// Writes the metrics in the format of the file extension, JSON for
+	// .json and Prometheus otherwise. The file is replaced at once, so a
+	// reader never sees half of it.
+	pub fn write_file(&self, path: &str, game: &str, now: Instant) -> io::Result<()> {
+		let text = if path.ends_with(".json") { self.to_json(game, now) } else { self.to_prometheus(game, now) };
+		let temp_path = format!("{}.tmp", path);
+		File::create(&temp_path).and_then(|mut file| file.write_all(text.as_bytes()))?;
+		fs::rename(&temp_path, path)
+	}
+}
+
+// Escapes a string for JSON, which also does for Prometheus labels.
+fn json_escape(text: &str) -> String {
+	let mut escaped = String::new();
+	for c in text.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+fn millis(duration: Duration) -> f64 {
+	seconds(duration) * 1000.0
+}
+
+fn seconds(duration: Duration) -> f64 {
+	duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::env;
+	use std::io::Read;
+
+	#[test]
+	fn metrics() {
+		let start = Instant::now();
+		let mut metrics = Metrics::new(start);
+		for i in 0..101 {
+			metrics.record_frame(Duration::from_millis(if i == 100 { 30 } else { 4 }), i == 50, start + Duration::from_millis(20 * i));
+		}
+		metrics.record_desync();
+		assert!((metrics.fps() - 50.0).abs() < 1e-6);
+		assert!((metrics.frame_time_percentile(0.5) - 4.0).abs() < 1e-6);
+		assert!((metrics.frame_time_percentile(1.0) - 30.0).abs() < 1e-6);
+
+		let now = start + Duration::from_secs(3);
+		assert_eq!("{\"game\":\"Say \\\"hi\\\"\",\"uptime_seconds\":3.0,\"frames\":101,\"fps\":50.00,\
+			\"frame_time_ms\":{\"p50\":4.000,\"p95\":4.000,\"p99\":4.000},\"audio_underruns\":1,\"desyncs\":1}\n",
+			metrics.to_json("Say \"hi\"", now));
+		let text = metrics.to_prometheus("smb", now);
+		assert!(text.contains("# TYPE nes_frames_total counter\nnes_frames_total{game=\"smb\"} 101\n"), "{}", text);
+		assert!(text.contains("nes_frame_time_milliseconds{game=\"smb\",quantile=\"0.99\"} 4.000\n"), "{}", text);
+		assert!(text.contains("nes_desyncs_total{game=\"smb\"} 1\n"), "{}", text);
+
+		let path = env::temp_dir().join(format!("nes-metrics-{}.json", ::std::process::id()));
+		let path = path.to_str().unwrap();
+		metrics.write_file(path, "smb", now).unwrap();
+		let mut written = String::new();
+		File::open(path).and_then(|mut file| file.read_to_string(&mut written)).unwrap();
+		assert_eq!(metrics.to_json("smb", now), written);
+		fs::remove_file(path).unwrap();
+	}
+}
@@ -0,0 +1,643 @@
+use cartridge::load_rom;
+use checksum;
+use nes::{Nes, ConsoleEvent};
+use ppu::Frame;
+use region::Region;
+use savestate::Compression;
+use std::fs::File;
+use std::io::{self, Read, Write, BufWriter};
+
+// Frames between two checkpoints of a recording, a second of NTSC.
+pub const CHECKPOINT_INTERVAL: u64 = 60;
+
+// Branches kept to undo loading states while recording. Each holds a save
+// state and the whole movie.
+const MAX_BRANCHES: usize = 16;
+
+// Recorded input of a run from power on, one line per frame:
+//
+//   nesmovie 1
+//   rom 0123...          SHA-1 of the ROM
+//   region ntsc
+//   frame 08 00          buttons of controller 1 and 2, hex
+//   check 60 1A2B3C4D 5E6F7081
+//   event 60 reset       or power
+//
+// A checkpoint follows the frame it was taken after, with the CRC32 of the
+// save state and of the input up to there. Playback compares them to find
+// the exact frame a replay went apart, instead of a desync much later. An
+// event precedes the frame it happened before.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movie {
+	pub rom_sha1: String,
+	pub region: Region,
+	pub inputs: Vec<[u8; 2]>,
+	pub checkpoints: Vec<Checkpoint>,
+	pub events: Vec<MovieEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checkpoint {
+	// frames run before it
+	pub frame: u64,
+	pub state_crc: u32,
+	pub input_crc: u32,
+}
+
+// The reset button or a power cycle, at the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MovieEvent {
+	// frames run before it
+	pub frame: u64,
+	pub event: ConsoleEvent,
+}
+
+fn event_name(event: ConsoleEvent) -> &'static str {
+	match event {
+		ConsoleEvent::SoftReset => "reset",
+		ConsoleEvent::PowerCycle => "power",
+	}
+}
+
+impl Movie {
+	pub fn new(rom_sha1: &str, region: Region) -> Movie {
+		Movie {
+			rom_sha1: String::from(rom_sha1),
+			region,
+			inputs: Vec::new(),
+			checkpoints: Vec::new(),
+			events: Vec::new(),
+		}
+	}
+
+	pub fn load(path: &str) -> Result<Movie, String> {
+		let mut text = String::new();
+		match File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+			Ok(_) => Movie::parse(&text).map_err(|err| format!("{}: {}", path, err)),
+			Err(err) => Err(format!("{}: {}", path, err)),
+		}
+	}
+
+	pub fn save(&self, path: &str) -> io::Result<()> {
+		let mut file = BufWriter::new(File::create(path)?);
+		self.write(&mut file)?;
+		file.flush()
+	}
+
+	pub fn parse(text: &str) -> Result<Movie, String> {
+		let mut lines = text.lines().enumerate();
+		if lines.next().map(|(_, line)| line.trim()) != Some("nesmovie 1") {
+			return Err(String::from("Not a movie of version 1."));
+		}
+		let mut movie = Movie::new("", Region::Ntsc);
+		for (i, line) in lines {
+			let error = |message: &str| Err(format!("line {}: {}", i + 1, message));
+			let words: Vec<&str> = line.split_whitespace().collect();
+			match (words.first().cloned(), words.len()) {
+				(None, _) => {}
+				(Some("rom"), 2) => movie.rom_sha1 = String::from(words[1]),
+				(Some("region"), 2) => {
+					movie.region = match Region::from_name(words[1]) {
+						Some(region) => region,
+						None => return error("Expected region ntsc or pal."),
+					};
+				}
+				(Some("frame"), 3) => {
+					match (u8::from_str_radix(words[1], 16), u8::from_str_radix(words[2], 16)) {
+						(Ok(port_1), Ok(port_2)) => movie.inputs.push([port_1, port_2]),
+						_ => return error("Expected the buttons of both controllers in hex."),
+					}
+				}
+				(Some("check"), 4) => {
+					let frame = words[1].parse::<u64>();
+					let state_crc = u32::from_str_radix(words[2], 16);
+					let input_crc = u32::from_str_radix(words[3], 16);
+					match (frame, state_crc, input_crc) {
+						(Ok(frame), Ok(state_crc), Ok(input_crc)) if frame == movie.inputs.len() as u64 => {
+							movie.checkpoints.push(Checkpoint {
+								frame,
+								state_crc,
+								input_crc,
+							});
+						}
+						(Ok(_), Ok(_), Ok(_)) => return error("The checkpoint does not follow its frame."),
+						_ => return error("Expected check <frame> <state CRC> <input CRC>."),
+					}
+				}
+				(Some("event"), 3) => {
+					let event = match words[2] {
+						"reset" => ConsoleEvent::SoftReset,
+						"power" => ConsoleEvent::PowerCycle,
+						_ => return error("Expected event <frame> reset or power."),
+					};
+					match words[1].parse::<u64>() {
+						Ok(frame) if frame == movie.inputs.len() as u64 => {
+							movie.events.push(MovieEvent {
+								frame,
+								event,
+							});
+						}
+						Ok(_) => return error("The event does not precede its frame."),
+						Err(_) => return error("Expected event <frame> reset or power."),
+					}
+				}
+				_ => return error(&format!("Unknown line {}.", line.trim())),
+			}
+		}
+		Ok(movie)
+	}
+
+	pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+		writeln!(out, "nesmovie 1")?;
+		writeln!(out, "rom {}", self.rom_sha1)?;
+		writeln!(out, "region {}", if self.region == Region::Pal { "pal" } else { "ntsc" })?;
+		let mut checkpoints = self.checkpoints.iter().peekable();
+		for (i, input) in self.inputs.iter().enumerate() {
+			for event in self.events.iter().filter(|event| event.frame == i as u64) {
+				writeln!(out, "event {} {}", event.frame, event_name(event.event))?;
+			}
+			writeln!(out, "frame {:02X} {:02X}", input[0], input[1])?;
+			while let Some(checkpoint) = checkpoints.peek().cloned().filter(|checkpoint| checkpoint.frame == i as u64 + 1) {
+				writeln!(out, "check {} {:08X} {:08X}", checkpoint.frame, checkpoint.state_crc, checkpoint.input_crc)?;
+				checkpoints.next();
+			}
+		}
+		for event in self.events.iter().filter(|event| event.frame >= self.inputs.len() as u64) {
+			writeln!(out, "event {} {}", event.frame, event_name(event.event))?;
+		}
+		Ok(())
+	}
+
+	// CRC32 of the input of the first frames, followed by the events
+	// before them.
+	pub fn input_crc(&self, frames: u64) -> u32 {
+		let mut bytes: Vec<u8> = self.inputs.iter().take(frames as usize)
+			.flat_map(|input| input.iter().cloned())
+			.collect();
+		for event in self.events.iter().filter(|event| event.frame < frames) {
+			bytes.extend((0..8).map(|i| (event.frame >> (i * 8)) as u8));
+			bytes.push(event.event as u8);
+		}
+		checksum::crc32(&bytes)
+	}
+
+	// The events at the start of a frame.
+	pub fn events_before(&self, frame: u64) -> Vec<ConsoleEvent> {
+		self.events.iter().filter(|event| event.frame == frame).map(|event| event.event).collect()
+	}
+}
+
+// Of the uncompressed state, so a recording checks the same in builds
+// with and without compression.
+pub fn state_crc(nes: &Nes) -> u32 {
+	let mut state = Vec::new();
+	nes.save_state_with(&mut state, Compression::None).unwrap();
+	checksum::crc32(&state)
+}
+
+// Runs one frame of a movie. The input of a frame is applied right after
+// the previous one is complete, at the same instruction in the window and
+// headless.
+pub fn run_frame(nes: &mut Nes) -> Frame {
+	let mut instr_log: Option<&mut dyn Write> = None;
+	loop {
+		nes.step(&mut instr_log);
+		if let Some(frame) = nes.take_frame() {
+			return frame;
+		}
+	}
+}
+
+// A save state taken while the console runs, e.g. on a key. While a movie
+// records, the input up to its frame is kept with it, so the recording can
+// go back there even after loading other states changed the input since.
+#[derive(Debug, Clone)]
+pub struct SavedState {
+	state: Vec<u8>,
+	// the movie up to and including the running frame, and its number
+	movie: Option<(Movie, u64)>,
+}
+
+impl SavedState {
+	pub fn save(nes: &Nes, session: Option<&MovieSession>) -> io::Result<SavedState> {
+		let mut state = Vec::new();
+		nes.save_state(&mut state)?;
+		let movie = session.filter(|session| session.is_recording()).map(|session| {
+			let mut movie = session.movie.clone();
+			movie.inputs.truncate(session.frame as usize + 1);
+			movie.checkpoints.retain(|checkpoint| checkpoint.frame <= session.frame);
+			movie.events.retain(|event| event.frame <= session.frame);
+			(movie, session.frame)
+		});
+		Ok(SavedState {
+			state,
+			movie,
+		})
+	}
+
+	// Loads the state without a movie.
+	pub fn load(&self, nes: &mut Nes) -> Result<(), String> {
+		nes.load_state(&mut &self.state[..]).map_err(|err| format!("Could not load state: {}", err))
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+	Record,
+	Play,
+}
+
+// Records or plays a movie, one frame at a time: start_frame before and
+// end_frame after each frame.
+pub struct MovieSession {
+	movie: Movie,
+	mode: Mode,
+	// frames complete
+	frame: u64,
+	// The movie and console as they were before each load of a state,
+	// the latest last.
+	branches: Vec<SavedState>,
+	// events to record at the start of the next frame
+	queued: Vec<ConsoleEvent>,
+}
+
+impl MovieSession {
+	pub fn record(movie: Movie) -> MovieSession {
+		MovieSession {
+			movie,
+			mode: Mode::Record,
+			frame: 0,
+			branches: Vec::new(),
+			queued: Vec::new(),
+		}
+	}
+
+	pub fn play(movie: Movie) -> MovieSession {
+		MovieSession {
+			movie,
+			mode: Mode::Play,
+			frame: 0,
+			branches: Vec::new(),
+			queued: Vec::new(),
+		}
+	}
+
+	pub fn movie(&self) -> &Movie {
+		&self.movie
+	}
+
+	pub fn frame(&self) -> u64 {
+		self.frame
+	}
+
+	pub fn is_recording(&self) -> bool {
+		self.mode == Mode::Record
+	}
+
+	// Re-recording: loads a state saved while recording. The movie goes
+	// back to the frame of the state and records from there, the input of
+	// later frames is dropped. The movie and console as they were become a
+	// branch which undo_load returns to. A movie which plays cannot load
+	// states, its input would not lead there.
+	pub fn load_state(&mut self, nes: &mut Nes, state: &SavedState) -> Result<(), String> {
+		if self.mode == Mode::Play {
+			return Err(String::from("States cannot be loaded while a movie plays."));
+		}
+		if state.movie.is_none() {
+			return Err(String::from("The state was not saved while recording the movie."));
+		}
+		let mut branch = SavedState::save(nes, None).map_err(|err| format!("Could not save state: {}", err))?;
+		branch.movie = Some((self.movie.clone(), self.frame));
+		self.restore(nes, state)?;
+		self.branches.push(branch);
+		if self.branches.len() > MAX_BRANCHES {
+			self.branches.remove(0);
+		}
+		Ok(())
+	}
+
+	// Returns to the movie and console as they were before the last load
+	// of a state, false if there is none.
+	pub fn undo_load(&mut self, nes: &mut Nes) -> Result<bool, String> {
+		match self.branches.pop() {
+			Some(branch) => self.restore(nes, &branch).map(|_| true),
+			None => Ok(false),
+		}
+	}
+
+	// Number of loads undo_load can go back.
+	pub fn branches(&self) -> usize {
+		self.branches.len()
+	}
+
+	// A state which fails to load leaves the console and the movie as they
+	// were.
+	fn restore(&mut self, nes: &mut Nes, state: &SavedState) -> Result<(), String> {
+		let (ref movie, frame) = *state.movie.as_ref().unwrap();
+		state.load(nes)?;
+		self.movie = movie.clone();
+		self.frame = frame;
+		self.queued.clear();
+		// the buttons are not part of the state
+		if let Some(&input) = self.movie.inputs.get(frame as usize) {
+			nes.set_buttons(0, input[0]);
+			nes.set_buttons(1, input[1]);
+		}
+		Ok(())
+	}
+
+	// Presses reset or power cycles while recording. It happens at the
+	// start of the next frame, where playback repeats it. A movie which
+	// plays has its own events.
+	pub fn queue_event(&mut self, event: ConsoleEvent) -> Result<(), String> {
+		if self.mode == Mode::Play {
+			return Err(String::from("The console cannot be reset while a movie plays."));
+		}
+		self.queued.push(event);
+		Ok(())
+	}
+
+	// Applies the events before the next frame and sets its buttons: the
+	// recorded ones when playing, which is false at the end of the movie,
+	// the given ones when recording.
+	pub fn start_frame(&mut self, nes: &mut Nes, buttons: [u8; 2]) -> bool {
+		let input = match self.mode {
+			Mode::Record => {
+				for event in self.queued.drain(..) {
+					self.movie.events.push(MovieEvent {
+						frame: self.frame,
+						event,
+					});
+					nes.handle_event(event);
+				}
+				self.movie.inputs.push(buttons);
+				buttons
+			}
+			Mode::Play => match self.movie.inputs.get(self.frame as usize) {
+				Some(&input) => {
+					for event in self.movie.events_before(self.frame) {
+						nes.handle_event(event);
+					}
+					input
+				}
+				None => return false,
+			},
+		};
+		nes.set_buttons(0, input[0]);
+		nes.set_buttons(1, input[1]);
+		true
+	}
+
+	// Takes the checkpoint after the frame when recording, or compares it
+	// when playing. The error tells the frame where the replay diverged.
+	pub fn end_frame(&mut self, nes: &Nes) -> Result<(), String> {
+		self.frame += 1;
+		match self.mode {
+			Mode::Record => {
+				if self.frame.is_multiple_of(CHECKPOINT_INTERVAL) {
+					let checkpoint = Checkpoint {
+						frame: self.frame,
+						state_crc: state_crc(nes),
+						input_crc: self.movie.input_crc(self.frame),
+					};
+					self.movie.checkpoints.push(checkpoint);
+				}
+				Ok(())
+			}
+			Mode::Play => {
+				let frame = self.frame;
+				let expected = match self.movie.checkpoints.iter().find(|checkpoint| checkpoint.frame == frame) {
+					Some(&checkpoint) => checkpoint,
+					None => return Ok(()),
+				};
+				let input_crc = self.movie.input_crc(frame);
+				if input_crc != expected.input_crc {
+					return Err(format!("The input up to frame {} was changed: CRC {:08X}, recorded {:08X}.",
+						frame, input_crc, expected.input_crc));
+				}
+				let state_crc = state_crc(nes);
+				if state_crc != expected.state_crc {
+					return Err(format!("Diverged at frame {}: state CRC {:08X}, recorded {:08X}.",
+						frame, state_crc, expected.state_crc));
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+// Headless movie check: verify-movie <rom> <movie>
+//
+// Replays the movie from power on and reports whether every checkpoint
+// matches, or the first frame where one does not.
+pub fn run(args: &[String]) {
+	if args.len() != 2 {
+		println!("Usage: verify-movie <rom> <movie>");
+		return;
+	}
+	let cartridge = match load_rom(&args[0]) {
+		Ok(cartridge) => cartridge,
+		Err(err) => {
+			println!("Could not load ROM: {}", err);
+			return;
+		}
+	};
+	let movie = match Movie::load(&args[1]) {
+		Ok(movie) => movie,
+		Err(err) => {
+			println!("Could not load movie: {}", err);
+			return;
+		}
+	};
+	let mut nes = Nes::new(cartridge);
+	nes.set_region(movie.region);
+	let checkpoints = movie.checkpoints.len();
+	let mut session = MovieSession::play(movie);
+	while session.start_frame(&mut nes, [0, 0]) {
+		run_frame(&mut nes);
+		if let Err(err) = session.end_frame(&nes) {
+			println!("FAIL: {}", err);
+			return;
+		}
+	}
+	println!("PASS: {} frames, {} checkpoints.", session.frame(), checkpoints);
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+	use cartridge::test_cartridge::TestCartridge;
+	use input::BUTTON_A;
+
+	fn nes() -> Nes {
+		// counts the frames with A held in $10
+		let code = assemble(0x8000, "\
+			LDA #$01; STA $4016; LDA #$00; STA $4016; \
+			LDA $4016; AND #$01; CLC; ADC $10; STA $10; \
+			LDA $2002; BPL $8014; JMP $8000").unwrap();
+		Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()))
+	}
+
+	fn peek(nes: &Nes) -> u8 {
+		nes.peek_ram(0x10)
+	}
+
+	fn record(inputs: &[u8]) -> Movie {
+		let mut nes = nes();
+		let mut session = MovieSession::record(Movie::new("00", Region::Ntsc));
+		for &buttons in inputs.iter() {
+			session.start_frame(&mut nes, [buttons, 0]);
+			run_frame(&mut nes);
+			session.end_frame(&nes).unwrap();
+		}
+		session.movie().clone()
+	}
+
+	fn play(movie: Movie) -> Result<u64, String> {
+		let mut nes = nes();
+		let mut session = MovieSession::play(movie);
+		while session.start_frame(&mut nes, [0, 0]) {
+			run_frame(&mut nes);
+			session.end_frame(&nes)?;
+		}
+		Ok(session.frame())
+	}
+
+	#[test]
+	fn checkpoints() {
+		let inputs: Vec<u8> = (0..150).map(|i| if i % 7 == 0 { BUTTON_A } else { 0 }).collect();
+		let movie = record(&inputs);
+		assert_eq!(150, movie.inputs.len());
+		assert_eq!(vec![60, 120], movie.checkpoints.iter().map(|checkpoint| checkpoint.frame).collect::<Vec<_>>());
+		assert_eq!(Ok(150), play(movie.clone()));
+
+		// the text keeps everything
+		let mut text = Vec::new();
+		movie.write(&mut text).unwrap();
+		assert_eq!(Ok(movie.clone()), Movie::parse(&String::from_utf8(text).unwrap()));
+
+		// a different state is found at the next checkpoint
+		let mut changed = movie.clone();
+		changed.inputs[70] = [0, 0];
+		changed.checkpoints[1].input_crc = changed.input_crc(120);
+		let err = play(changed).unwrap_err();
+		assert!(err.starts_with("Diverged at frame 120"), "{}", err);
+
+		// so is edited input
+		let mut edited = movie.clone();
+		edited.inputs[10] = [BUTTON_A, 0];
+		let err = play(edited).unwrap_err();
+		assert!(err.starts_with("The input up to frame 60"), "{}", err);
+	}
+
+	#[test]
+	fn parse_errors() {
+		assert!(Movie::parse("").is_err());
+		assert!(Movie::parse("nesmovie 2").is_err());
+		assert_eq!(Err(String::from("line 3: Expected the buttons of both controllers in hex.")),
+			Movie::parse("nesmovie 1\nrom 00\nframe 0G 00"));
+		assert!(Movie::parse("nesmovie 1\nframe 00 00\ncheck 2 0 0").is_err());
+		assert!(Movie::parse("nesmovie 1\nregion secam").is_err());
+		assert!(Movie::parse("nesmovie 1\nevent 0 eject").is_err());
+		assert!(Movie::parse("nesmovie 1\nframe 00 00\nevent 0 reset").is_err());
+	}
+
+	#[test]
+	fn events() {
+		// A held throughout, the power cycle before frame 50 clears the count
+		let mut nes = nes();
+		let mut session = MovieSession::record(Movie::new("00", Region::Ntsc));
+		for frame in 0..130 {
+			if frame == 50 {
+				session.queue_event(ConsoleEvent::PowerCycle).unwrap();
+			} else if frame == 90 {
+				session.queue_event(ConsoleEvent::SoftReset).unwrap();
+			}
+			session.start_frame(&mut nes, [BUTTON_A, 0]);
+			run_frame(&mut nes);
+			session.end_frame(&nes).unwrap();
+		}
+		let counted = peek(&nes);
+		assert!(counted < 80);
+		let movie = session.movie().clone();
+		assert_eq!(vec![MovieEvent { frame: 50, event: ConsoleEvent::PowerCycle },
+			MovieEvent { frame: 90, event: ConsoleEvent::SoftReset }], movie.events);
+
+		let mut text = Vec::new();
+		movie.write(&mut text).unwrap();
+		let text = String::from_utf8(text).unwrap();
+		assert!(text.contains("\nevent 50 power\nframe 01 00\n"), "{}", text);
+		assert_eq!(Ok(movie.clone()), Movie::parse(&text));
+
+		let mut nes = self::nes();
+		let mut playing = MovieSession::play(movie.clone());
+		assert!(playing.queue_event(ConsoleEvent::SoftReset).is_err());
+		while playing.start_frame(&mut nes, [0, 0]) {
+			run_frame(&mut nes);
+			playing.end_frame(&nes).unwrap();
+		}
+		assert_eq!(counted, peek(&nes));
+
+		// the events are part of the input the checkpoints cover
+		let mut moved = movie.clone();
+		moved.events[0].frame = 51;
+		let err = play(moved).unwrap_err();
+		assert!(err.starts_with("The input up to frame 60"), "{}", err);
+	}
+
+	#[test]
+	fn rerecord() {
+		let mut nes = nes();
+		let mut session = MovieSession::record(Movie::new("00", Region::Ntsc));
+		let mut saved = None;
+		for frame in 0..100 {
+			session.start_frame(&mut nes, [if frame < 50 { 0 } else { BUTTON_A }, 0]);
+			if frame == 40 {
+				saved = Some(SavedState::save(&nes, Some(&session)).unwrap());
+			}
+			run_frame(&mut nes);
+			session.end_frame(&nes).unwrap();
+		}
+		let first_take = session.movie().clone();
+		let counted = peek(&nes);
+		assert!(counted > 40);
+
+		// back to frame 40, which records again from there without A
+		session.load_state(&mut nes, saved.as_ref().unwrap()).unwrap();
+		assert_eq!(40, session.frame());
+		assert_eq!(41, session.movie().inputs.len());
+		assert_eq!(vec![] as Vec<u64>, session.movie().checkpoints.iter().map(|checkpoint| checkpoint.frame).collect::<Vec<_>>());
+		run_frame(&mut nes);
+		session.end_frame(&nes).unwrap();
+		for _ in 41..100 {
+			session.start_frame(&mut nes, [0, 0]);
+			run_frame(&mut nes);
+			session.end_frame(&nes).unwrap();
+		}
+		assert_eq!(0, peek(&nes));
+		assert_eq!(Ok(100), play(session.movie().clone()));
+
+		// undo goes back to the first take
+		assert_eq!(1, session.branches());
+		assert_eq!(Ok(true), session.undo_load(&mut nes));
+		assert_eq!(Ok(false), session.undo_load(&mut nes));
+		assert_eq!(first_take, *session.movie());
+		assert_eq!(counted, peek(&nes));
+
+		// a broken state changes nothing
+		let mut broken = saved.clone().unwrap();
+		let length = broken.state.len();
+		broken.state.truncate(length - 100);
+		let before = state_crc(&nes);
+		assert!(session.load_state(&mut nes, &broken).is_err());
+		assert_eq!((100, 0), (session.frame(), session.branches()));
+		assert_eq!(before, state_crc(&nes));
+
+		// a movie which plays cannot load states
+		let mut playing = MovieSession::play(first_take);
+		assert!(playing.load_state(&mut nes, saved.as_ref().unwrap()).is_err());
+		let plain = SavedState::save(&nes, None).unwrap();
+		assert!(session.load_state(&mut nes, &plain).is_err());
+	}
+}
@@ -0,0 +1,1512 @@
+use cartridge::Cartridge;
+use cpu::{Cpu, CpuState, Hardware, Watchpoint, WatchHit, BusStats, TrapHandler};
+use ppu::{Ppu, Frame, PaletteRam, ScanlineOutput, RGB_PALETTE};
+use apu::{Apu, AudioChunk, AudioOutput, AudioSettings, Channel, ExpansionMix};
+use input::{Input, ExpansionDevice};
+use zapper::Zapper;
+use scheduler::Scheduler;
+use dma::{Dma, DmaAccess};
+use region::Region;
+use machine::MachineConfig;
+use prng::Prng;
+use overclock::Overclock;
+use std::io::{self, Read, Write};
+use savestate::{self, Compression};
+
+// Events which change the state of the console from the outside.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleEvent {
+	// The reset button was pressed.
+	SoftReset,
+	// The console was turned off and on again.
+	PowerCycle,
+}
+
+// Timed events of the console, see Nes::run_until_event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NesEvent {
+	// The PPU entered vblank (scanline 241, dot 1).
+	Vblank,
+	// The APU frame counter clocked the envelopes, length counters and
+	// sweeps, and maybe raised the frame interrupt.
+	FrameStep,
+	// The DMC channel asked for its next sample byte.
+	DmcFetch,
+	// A timer of the mapper raised the IRQ, see Cartridge::cycles_until_irq.
+	MapperIrq,
+}
+
+const EVENTS: [NesEvent; 4] = [NesEvent::Vblank, NesEvent::FrameStep, NesEvent::DmcFetch, NesEvent::MapperIrq];
+
+// Presets for EmulationSettings, from most accurate to fastest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccuracyPreset {
+	Accuracy,
+	Balanced,
+	Speed,
+}
+
+impl AccuracyPreset {
+	pub fn from_name(name: &str) -> Option<AccuracyPreset> {
+		match name {
+			"accuracy" => Some(AccuracyPreset::Accuracy),
+			"balanced" => Some(AccuracyPreset::Balanced),
+			"speed" => Some(AccuracyPreset::Speed),
+			_ => None,
+		}
+	}
+}
+
+// Switches trading emulation accuracy for speed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmulationSettings {
+	// Model the PPU I/O latch returned by reads of write-only registers.
+	pub ppu_open_bus: bool,
+	// Model the OAM corruption caused by OAMADDR and $2004 writes while
+	// rendering.
+	pub oam_corruption: bool,
+	// Let the OAM contents decay while rendering is off for a long time,
+	// as the DRAM is not refreshed then.
+	pub oam_decay: bool,
+	// Draw each line at once instead of dot by dot, see
+	// Ppu::set_scanline_renderer.
+	pub scanline_renderer: bool,
+	// Draw at most 8 sprites per line, see Ppu::set_sprite_limit.
+	pub sprite_limit: bool,
+	// Make the extra reads and writes of indexed and read-modify-write
+	// instructions, see Cpu::set_dummy_reads.
+	pub dummy_reads: bool,
+	// Fill the RAM with random values at power on instead of zeros, which
+	// finds games and homebrew reading uninitialized RAM.
+	pub random_ram: bool,
+	// Extra scanlines of CPU time before vblank and before the pre-render
+	// line, see Overclock. Inaccurate, 0 turns them off.
+	pub overclock_lines: u32,
+	pub overclock_prerender_lines: u32,
+	// Catch panics of cartridge reads by the PPU and draw a pattern
+	// instead, see Ppu::set_tolerate_mapper_faults. For debugging mappers.
+	pub tolerate_mapper_faults: bool,
+}
+
+impl EmulationSettings {
+	pub fn from_preset(preset: AccuracyPreset) -> EmulationSettings {
+		match preset {
+			AccuracyPreset::Accuracy => EmulationSettings {
+				ppu_open_bus: true,
+				oam_corruption: true,
+				oam_decay: true,
+				scanline_renderer: false,
+				sprite_limit: true,
+				dummy_reads: true,
+				random_ram: false,
+				overclock_lines: 0,
+				overclock_prerender_lines: 0,
+				tolerate_mapper_faults: false,
+			},
+			// No game is known to depend on the decay, and few on the
+			// dummy reads.
+			AccuracyPreset::Balanced => EmulationSettings {
+				ppu_open_bus: true,
+				oam_corruption: true,
+				oam_decay: false,
+				scanline_renderer: false,
+				sprite_limit: true,
+				dummy_reads: false,
+				random_ram: false,
+				overclock_lines: 0,
+				overclock_prerender_lines: 0,
+				tolerate_mapper_faults: false,
+			},
+			// Games with mid-line effects and those hiding sprites behind
+			// others show glitches.
+			AccuracyPreset::Speed => EmulationSettings {
+				ppu_open_bus: false,
+				oam_corruption: false,
+				oam_decay: false,
+				scanline_renderer: true,
+				sprite_limit: false,
+				dummy_reads: false,
+				random_ram: false,
+				overclock_lines: 0,
+				overclock_prerender_lines: 0,
+				tolerate_mapper_faults: false,
+			},
+		}
+	}
+}
+
+// Enters the handler of an interrupt instead of running the next
+// instruction: the NMI raised by the PPU first, then the IRQ line of the
+// cartridge while the CPU lets it through. A jammed CPU does not respond.
+// Returns the cycles taken, None if there is no interrupt.
+fn poll_interrupts(cpu: &mut Cpu, hw: &mut Hardware) -> Option<u32> {
+	let nmi = hw.ppu.take_nmi();
+	if cpu.jammed() {
+		None
+	} else if nmi {
+		Some(cpu.nmi(hw))
+	} else if (hw.cartridge.irq_pending() || hw.apu.irq_pending()) && cpu.irq_enabled() {
+		Some(cpu.irq(hw))
+	} else {
+		None
+	}
+}
+
+// Number of PPU dots in the next CPU cycles, carrying the fifths of a dot
+// over to the next call.
+fn dots_for_cycles(region: Region, dot_fraction: &mut u64, cycles: u32) -> u32 {
+	let fifths = cycles as u64 * region.dots_per_5_cycles() + *dot_fraction;
+	*dot_fraction = fifths % 5;
+	(fifths / 5) as u32
+}
+
+// Identifies save states written by Nes::save_state.
+const STATE_MAGIC: &[u8; 4] = b"NESS";
+const STATE_VERSION: u8 = 19;
+
+// The whole console with an inserted cartridge.
+pub struct Nes {
+	cpu: Cpu,
+	ppu: Ppu,
+	apu: Apu,
+	input: Input,
+	cartridge: Box<dyn Cartridge>,
+	dma: Dma,
+	settings: EmulationSettings,
+	// Randomness for the features which need it, see Prng.
+	prng: Prng,
+	seed: u64,
+	overclock: Overclock,
+	audio_settings: AudioSettings,
+	master_palette: [u8; 64 * 3],
+	render_override: Option<bool>,
+	region: Region,
+	machine: MachineConfig,
+	// Master clock in PPU dots since the console was created.
+	clock: u64,
+	// Fifths of a dot not added to the clock yet, as a PAL CPU cycle takes
+	// 3.2 dots.
+	dot_fraction: u64,
+	scheduler: Scheduler<NesEvent>,
+}
+
+impl Nes {
+	pub fn new(cartridge: Box<dyn Cartridge>) -> Nes {
+		let mut nes = Nes {
+			cpu: Cpu::new(),
+			ppu: Ppu::new(),
+			apu: Apu::new(),
+			input: Input::new(),
+			cartridge,
+			dma: Dma::new(),
+			settings: EmulationSettings::from_preset(AccuracyPreset::Accuracy),
+			prng: Prng::new(0),
+			seed: 0,
+			overclock: Overclock::new(),
+			audio_settings: AudioSettings::from_preset(ExpansionMix::Famicom),
+			master_palette: RGB_PALETTE,
+			render_override: None,
+			region: Region::Ntsc,
+			machine: MachineConfig::new(),
+			clock: 0,
+			dot_fraction: 0,
+			scheduler: Scheduler::new(),
+		};
+		{
+			let mut hw = Hardware {
+				ppu: &mut nes.ppu,
+				apu: &mut nes.apu,
+				input: &mut nes.input,
+				cartridge: &mut *nes.cartridge,
+			};
+			nes.cpu.jump_to_start(&mut hw);
+		}
+		nes.reschedule();
+		nes
+	}
+
+	// Number of PPU dots emulated so far.
+	pub fn clock(&self) -> u64 {
+		self.clock
+	}
+
+	// Number of CPU cycles emulated so far.
+	pub fn cpu_cycles(&self) -> u64 {
+		(self.clock * 5 + self.dot_fraction) / self.region.dots_per_5_cycles()
+	}
+
+	pub fn region(&self) -> Region {
+		self.region
+	}
+
+	// Switches between NTSC and PAL timing, which power cycles the console.
+	pub fn set_region(&mut self, region: Region) {
+		self.region = region;
+		self.handle_event(ConsoleEvent::PowerCycle);
+	}
+
+	pub fn machine(&self) -> &MachineConfig {
+		&self.machine
+	}
+
+	// Size of the RAM in use, which follows the machine from the last
+	// power cycle on.
+	pub fn ram_size(&self) -> u16 {
+		self.cpu.ram_size()
+	}
+
+	// Switches to the RAM and audio of another console model, from the
+	// next power cycle on.
+	pub fn set_machine(&mut self, machine: MachineConfig) {
+		self.machine = machine;
+	}
+
+	// Registers the next event of every component. Needed whenever their
+	// state was replaced.
+	fn reschedule(&mut self) {
+		self.scheduler.clear();
+		for &event in EVENTS.iter() {
+			self.schedule(event);
+		}
+	}
+
+	// Replaces the pending occurrence of the event with the next one the
+	// component it comes from expects, if any.
+	fn schedule(&mut self, event: NesEvent) {
+		self.scheduler.cancel(event);
+		let cycles = match event {
+			NesEvent::Vblank => {
+				let time = self.clock + self.ppu.dots_until(241, 1);
+				self.scheduler.schedule(time, event);
+				return;
+			}
+			NesEvent::FrameStep => Some(self.apu.cycles_until_frame_step()),
+			NesEvent::DmcFetch => self.apu.cycles_until_dmc_fetch(),
+			NesEvent::MapperIrq => self.cartridge.cycles_until_irq(),
+		};
+		if let Some(cycles) = cycles {
+			let dots = (cycles as u64 * self.region.dots_per_5_cycles() + self.dot_fraction) / 5;
+			self.scheduler.schedule(self.clock + dots, event);
+		}
+	}
+
+	// Runs whole instructions until the next scheduled event is due and
+	// returns it. The clock may have passed the event by a few dots.
+	// Between events nothing is polled: the APU runs the cycles in one go
+	// when the CPU accesses it or at its next event, see Apu::defer, and
+	// only writes of the CPU to the APU or the cartridge, which may move
+	// their events, make the loop register them again.
+	pub fn run_until_event(&mut self, instr_log: &mut Option<&mut dyn Write>) -> NesEvent {
+		loop {
+			if let Some((_, event)) = self.scheduler.pop_due(self.clock) {
+				self.schedule(event);
+				self.apu.catch_up();
+				return event;
+			}
+			let next = self.scheduler.next_time().unwrap_or(u64::max_value());
+			while self.clock < next {
+				if self.run_instruction(instr_log) {
+					break;
+				}
+			}
+		}
+	}
+
+	pub fn settings(&self) -> &EmulationSettings {
+		&self.settings
+	}
+
+	pub fn set_settings(&mut self, settings: EmulationSettings) {
+		self.settings = settings;
+		self.apply_settings();
+	}
+
+	pub fn seed(&self) -> u64 {
+		self.seed
+	}
+
+	// Restarts the random numbers from a seed. They are used from the next
+	// power cycle on.
+	pub fn set_seed(&mut self, seed: u64) {
+		self.seed = seed;
+		self.prng.seed(seed);
+	}
+
+	pub fn audio_settings(&self) -> &AudioSettings {
+		&self.audio_settings
+	}
+
+	pub fn set_audio_settings(&mut self, settings: AudioSettings) {
+		self.audio_settings = settings;
+		self.apu.set_settings(self.audio_settings.clone());
+	}
+
+	fn apply_settings(&mut self) {
+		self.cpu.set_dummy_reads(self.settings.dummy_reads);
+		self.ppu.set_open_bus(self.settings.ppu_open_bus);
+		self.ppu.set_oam_corruption(self.settings.oam_corruption);
+		self.ppu.set_oam_decay(self.settings.oam_decay);
+		self.ppu.set_scanline_renderer(self.settings.scanline_renderer);
+		self.ppu.set_sprite_limit(self.settings.sprite_limit);
+		self.ppu.set_tolerate_mapper_faults(self.settings.tolerate_mapper_faults);
+		self.ppu.set_master_palette(self.master_palette);
+		self.ppu.set_render_enabled(self.render_override);
+		self.overclock.set_lines(self.region, self.settings.overclock_lines, self.settings.overclock_prerender_lines);
+	}
+
+	// Executes one CPU instruction, or enters the handler of an interrupt,
+	// see poll_interrupts, and lets the PPU and the APU catch up.
+	pub fn step(&mut self, instr_log: &mut Option<&mut dyn Write>) {
+		self.run_instruction(instr_log);
+		self.apu.catch_up();
+	}
+
+	// The same, while the APU may defer its cycles. Returns whether events
+	// were registered again, as the CPU wrote to the APU or the cartridge.
+	fn run_instruction(&mut self, instr_log: &mut Option<&mut dyn Write>) -> bool {
+		let expansion_audio = self.machine.expansion_audio;
+		let mut hw = Hardware {
+			ppu: &mut self.ppu,
+			apu: &mut self.apu,
+			input: &mut self.input,
+			cartridge: &mut *self.cartridge,
+		};
+		// only the CPU runs while overclocking stops the rest
+		if self.overclock.stopped() {
+			let cycles = match poll_interrupts(&mut self.cpu, &mut hw) {
+				Some(cycles) => cycles,
+				None => self.cpu.tick(&mut hw, instr_log),
+			};
+			let dots = self.overclock.spend(cycles);
+			self.clock += self.overclock.run_ppu(hw.ppu, hw.cartridge, dots) as u64;
+			self.run_dma();
+			return self.reschedule_devices();
+		}
+
+		if let Some(cycles) = poll_interrupts(&mut self.cpu, &mut hw) {
+			hw.cartridge.cpu_clock(cycles);
+			hw.apu.set_expansion_audio(hw.cartridge.expansion_audio().filter(|_| expansion_audio));
+			hw.apu.defer(cycles);
+			let dots = dots_for_cycles(self.region, &mut self.dot_fraction, cycles);
+			self.clock += self.overclock.run_ppu(hw.ppu, hw.cartridge, dots) as u64;
+			self.run_dma();
+			return self.reschedule_devices();
+		}
+
+		// Register accesses happen in the last cycle of an instruction, so
+		// the PPU runs up to there first. This makes reads of PPUSTATUS see
+		// the right dot.
+		let before = self.cpu.next_instruction_cycles(&mut hw) - 1;
+		let dots_before = dots_for_cycles(self.region, &mut self.dot_fraction, before);
+		let dots_before = self.overclock.run_ppu(hw.ppu, hw.cartridge, dots_before);
+		let cycles = self.cpu.tick(&mut hw, instr_log);
+		hw.cartridge.cpu_clock(cycles);
+		hw.apu.set_expansion_audio(hw.cartridge.expansion_audio().filter(|_| expansion_audio));
+		hw.apu.defer(cycles);
+		let dots_after = dots_for_cycles(self.region, &mut self.dot_fraction, cycles.saturating_sub(before));
+		let dots_after = self.overclock.run_ppu(hw.ppu, hw.cartridge, dots_after);
+		self.clock += (dots_before + dots_after) as u64;
+		self.run_dma();
+		self.reschedule_devices()
+	}
+
+	// Registers the events of the APU and the cartridge again if the CPU
+	// wrote to them. Returns whether it did.
+	fn reschedule_devices(&mut self) -> bool {
+		if !self.cpu.take_device_write() {
+			return false;
+		}
+		self.schedule(NesEvent::FrameStep);
+		self.schedule(NesEvent::DmcFetch);
+		self.schedule(NesEvent::MapperIrq);
+		true
+	}
+
+	// Runs the DMA transfers the last instruction started, and the sample
+	// fetches the DMC channel asked for meanwhile, while the CPU is halted.
+	// The rest of the console runs cycle by cycle meanwhile, so the PPU and
+	// the mapper see the accesses when they happen. Even CPU cycles are get
+	// cycles, so OAM DMA started by a write on an odd cycle takes the extra
+	// alignment cycle.
+	fn run_dma(&mut self) {
+		if let Some(page) = self.cpu.take_oam_dma() {
+			self.dma.start_oam(page);
+		}
+		if let Some(address) = self.apu.take_dmc_request() {
+			self.dma.request_dmc(address);
+		}
+		while self.dma.active() {
+			let get = self.cpu_cycles().is_multiple_of(2);
+			let mut hw = Hardware {
+				ppu: &mut self.ppu,
+				apu: &mut self.apu,
+				input: &mut self.input,
+				cartridge: &mut *self.cartridge,
+			};
+			match self.dma.cycle(get) {
+				DmaAccess::Read(address) => {
+					let value = self.cpu.dma_read(&mut hw, address);
+					self.dma.read_done(value);
+					if let Some(byte) = self.dma.take_dmc_byte() {
+						hw.apu.dmc_fetched(byte);
+					}
+				}
+				DmaAccess::Write(address, value) => self.cpu.dma_write(&mut hw, address, value),
+				DmaAccess::None => {}
+			}
+			let dots = if self.overclock.stopped() {
+				self.overclock.spend(1)
+			} else {
+				hw.cartridge.cpu_clock(1);
+				hw.apu.clock(1);
+				dots_for_cycles(self.region, &mut self.dot_fraction, 1)
+			};
+			self.clock += self.overclock.run_ppu(hw.ppu, hw.cartridge, dots) as u64;
+			if let Some(address) = hw.apu.take_dmc_request() {
+				self.dma.request_dmc(address);
+			}
+		}
+	}
+
+	// Runs until the next vblank and returns the frame completed before it.
+	// The audio of the frame can be taken afterwards, see take_audio.
+	pub fn run_frame(&mut self) -> Frame {
+		let mut instr_log: Option<&mut dyn Write> = None;
+		loop {
+			if self.run_until_event(&mut instr_log) == NesEvent::Vblank {
+				if let Some(frame) = self.take_frame() {
+					return frame;
+				}
+			}
+		}
+	}
+
+	// Sets or removes the receiver of completed lines. Kept on power
+	// cycles.
+	pub fn set_scanline_output(&mut self, output: Option<Box<dyn ScanlineOutput + Send>>) {
+		self.ppu.set_scanline_output(output);
+	}
+
+	pub fn take_scanline_output(&mut self) -> Option<Box<dyn ScanlineOutput + Send>> {
+		self.ppu.take_scanline_output()
+	}
+
+	// Returns the last completed frame, if there is a new one.
+	pub fn take_frame(&mut self) -> Option<Frame> {
+		self.ppu.take_frame()
+	}
+
+	// Returns the samples generated since the last call, None if there
+	// are none. The number of samples is exact: all chunks taken since the
+	// console was created hold apu::samples_for_cycles(cpu_cycles(), region())
+	// samples. An NTSC frame gets 733 or 734 samples at 44.1 kHz, so recordings
+	// and netplay can rely on the audio matching the frames.
+	pub fn take_audio(&mut self) -> Option<AudioChunk> {
+		self.apu.take_audio()
+	}
+
+	// Sets or removes the receiver of the samples as they are generated,
+	// e.g. to play them. They are returned by take_audio as well.
+	pub fn set_audio_output(&mut self, output: Option<Box<dyn AudioOutput + Send>>) {
+		self.apu.set_output(output);
+	}
+
+	pub fn take_audio_output(&mut self) -> Option<Box<dyn AudioOutput + Send>> {
+		self.apu.take_output()
+	}
+
+	// Records the output of every channel on its own besides the mixed
+	// audio, see take_audio_stems.
+	pub fn set_audio_stems(&mut self, enabled: bool) {
+		self.apu.set_stems(enabled);
+	}
+
+	// Returns the samples of every channel before mixing generated since
+	// the last call, as many per channel as take_audio returns.
+	pub fn take_audio_stems(&mut self) -> Option<Vec<(Channel, AudioChunk)>> {
+		self.apu.take_stems()
+	}
+
+	// The last values written to the APU registers of a channel.
+	pub fn channel_registers(&self, channel: Channel) -> &[u8] {
+		self.apu.channel_registers(channel)
+	}
+
+	// Ramps the audio up from silence, for when the output was silent for
+	// a while, e.g. paused.
+	pub fn resume_audio(&mut self) {
+		self.apu.ramp_from(0.0);
+	}
+
+	// Address of the next instruction.
+	pub fn pc(&self) -> u16 {
+		self.cpu.registers().pc
+	}
+
+	// See Cpu::io_accesses.
+	pub fn io_accesses(&self) -> u64 {
+		self.cpu.io_accesses()
+	}
+
+	// Whether a KIL instruction stopped the CPU, see Cpu::jam.
+	pub fn cpu_jammed(&self) -> bool {
+		self.cpu.jammed()
+	}
+
+	// Continues execution at addr, e.g. the automation entry point of a
+	// test ROM like C000 for nestest.
+	pub fn set_pc(&mut self, addr: u16) {
+		self.cpu.registers_mut().pc = addr;
+	}
+
+	// Explains register accesses in the instruction log, see
+	// Cpu::set_annotate_io.
+	pub fn set_annotate_io(&mut self, annotate: bool) {
+		self.cpu.set_annotate_io(annotate);
+	}
+
+	// Reads the CPU address space without side effects, e.g. for a memory
+	// viewer. See Cpu::peek_memory.
+	pub fn peek_memory(&mut self, addr: u16) -> u8 {
+		let mut hw = Hardware {
+			ppu: &mut self.ppu,
+			apu: &mut self.apu,
+			input: &mut self.input,
+			cartridge: &mut *self.cartridge,
+		};
+		self.cpu.peek_memory(&mut hw, addr)
+	}
+
+	// Writes to the CPU address space like the CPU would, including the
+	// side effects of writes to registers, e.g. for a debugger.
+	pub fn poke_memory(&mut self, addr: u16, value: u8) {
+		let mut hw = Hardware {
+			ppu: &mut self.ppu,
+			apu: &mut self.apu,
+			input: &mut self.input,
+			cartridge: &mut *self.cartridge,
+		};
+		self.cpu.write_memory(&mut hw, addr, value);
+	}
+
+	// The registers of the CPU and whether it is jammed.
+	pub fn cpu_state(&self) -> CpuState {
+		self.cpu.state()
+	}
+
+	// Replaces the CPU state at once, e.g. from a debugger.
+	pub fn set_cpu_state(&mut self, state: CpuState) {
+		self.cpu.set_state(state);
+	}
+
+	// See Cpu::set_watchpoints.
+	pub fn set_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) {
+		self.cpu.set_watchpoints(watchpoints);
+	}
+
+	// See Cpu::set_trap. The traps stay over power cycles.
+	pub fn set_trap(&mut self, addr: u16, handler: TrapHandler) {
+		self.cpu.set_trap(addr, handler);
+	}
+
+	pub fn remove_trap(&mut self, addr: u16) {
+		self.cpu.remove_trap(addr);
+	}
+
+	// The access which matched a watchpoint since the last call. The
+	// instruction which did it has completed.
+	pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+		self.cpu.take_watch_hit()
+	}
+
+	// CPU bus accesses since the last call, e.g. per frame.
+	pub fn take_bus_stats(&mut self) -> BusStats {
+		self.cpu.take_bus_stats()
+	}
+
+	// Reads CPU RAM (0000-1FFF with mirrors) without side effects.
+	pub fn peek_ram(&self, addr: u16) -> u8 {
+		self.cpu.peek_ram(addr)
+	}
+
+	// The PPU, for tools which show its state (position, rendering, ...).
+	pub fn ppu(&self) -> &Ppu {
+		&self.ppu
+	}
+
+	// See Ppu::set_render_enabled. Kept on power cycles.
+	pub fn set_render_enabled(&mut self, enabled: Option<bool>) {
+		self.render_override = enabled;
+		self.ppu.set_render_enabled(enabled);
+	}
+
+	pub fn render_enabled(&self) -> Option<bool> {
+		self.render_override
+	}
+
+	// See Ppu::set_master_palette. Kept on power cycles.
+	pub fn set_master_palette(&mut self, palette: [u8; 64 * 3]) {
+		self.master_palette = palette;
+		self.ppu.set_master_palette(palette);
+	}
+
+	pub fn master_palette(&self) -> &[u8; 64 * 3] {
+		&self.master_palette
+	}
+
+	// See Ppu::set_layers.
+	pub fn set_layers(&mut self, background: bool, sprites: bool) {
+		self.ppu.set_layers(background, sprites);
+	}
+
+	// Buttons held on the controller in port 0 or 1, see input::BUTTON_A etc.
+	pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+		self.input.set_buttons(port, buttons);
+	}
+
+	// Blows into the microphone of the second Famicom controller.
+	pub fn set_microphone(&mut self, active: bool) {
+		self.input.set_microphone(active);
+	}
+
+	// Plugs a Zapper into port 2 instead of the controller, or unplugs it.
+	pub fn set_zapper(&mut self, zapper: Option<Zapper>) {
+		self.input.set_zapper(zapper);
+	}
+
+	pub fn zapper_mut(&mut self) -> Option<&mut Zapper> {
+		self.input.zapper_mut()
+	}
+
+	// The inserted cartridge, e.g. for its name and capabilities.
+	pub fn cartridge(&self) -> &dyn Cartridge {
+		&*self.cartridge
+	}
+
+	// See Cartridge::insert_barcode.
+	pub fn insert_barcode(&mut self, digits: &str) -> Result<(), String> {
+		self.cartridge.insert_barcode(digits)
+	}
+
+	// See Cartridge::change_disk_side.
+	pub fn change_disk_side(&mut self) -> Result<usize, String> {
+		self.cartridge.change_disk_side()
+	}
+
+	// Plugs a device into the expansion port, or unplugs it with None.
+	pub fn set_expansion_device(&mut self, device: Option<Box<dyn ExpansionDevice>>) {
+		self.input.set_expansion_device(device);
+	}
+
+	pub fn expansion_device(&self) -> Option<&dyn ExpansionDevice> {
+		self.input.expansion_device()
+	}
+
+	pub fn take_expansion_device(&mut self) -> Option<Box<dyn ExpansionDevice>> {
+		self.input.take_expansion_device()
+	}
+
+	// Side-effect free access to the PPU memories, see Ppu::peek_vram.
+	pub fn peek_vram(&mut self, addr: u16) -> u8 {
+		self.ppu.peek_vram(&mut *self.cartridge, addr)
+	}
+
+	pub fn poke_vram(&mut self, addr: u16, value: u8) {
+		self.ppu.poke_vram(&mut *self.cartridge, addr, value);
+	}
+
+	pub fn peek_oam(&self, index: u8) -> u8 {
+		self.ppu.peek_oam(index)
+	}
+
+	pub fn poke_oam(&mut self, index: u8, value: u8) {
+		self.ppu.poke_oam(index, value);
+	}
+
+	pub fn peek_palette(&self, index: u8) -> u8 {
+		self.ppu.peek_palette(index)
+	}
+
+	pub fn poke_palette(&mut self, index: u8, value: u8) {
+		self.ppu.poke_palette(index, value);
+	}
+
+	pub fn palette_ram(&self) -> &PaletteRam {
+		self.ppu.palette_ram()
+	}
+
+	// Writes the state of the console, compressed if the build can. It can
+	// only be loaded again with the same ROM inserted.
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		self.save_state_with(out, Compression::preferred())
+	}
+
+	// The header is followed by the components, which take up to a few
+	// hundred KB with large PRG and CHR RAM, so they can be compressed.
+	pub fn save_state_with(&self, out: &mut dyn Write, compression: Compression) -> io::Result<()> {
+		savestate::write_bytes(out, STATE_MAGIC)?;
+		savestate::write_u8(out, STATE_VERSION)?;
+		savestate::write_u8(out, compression.id())?;
+		let body = self.save_body()?;
+		savestate::write_bytes(out, &savestate::compress(compression, body)?)
+	}
+
+	// The components after the header, uncompressed.
+	fn save_body(&self) -> io::Result<Vec<u8>> {
+		let sections = self.state_sections()?;
+		Ok(sections.into_iter().flat_map(|(_, data)| data).collect())
+	}
+
+	fn save_clock(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u64(out, self.clock)?;
+		savestate::write_bool(out, self.region == Region::Pal)?;
+		savestate::write_u8(out, self.dot_fraction as u8)
+	}
+
+	// The state of every component as written by save_state, in the order
+	// load_body reads them, for tools which compare states.
+	pub fn state_sections(&self) -> io::Result<Vec<(&'static str, Vec<u8>)>> {
+		let sections: [(&'static str, &dyn Fn(&mut dyn Write) -> io::Result<()>); 7] = [
+			("clock", &|out| self.save_clock(out)),
+			("prng", &|out| self.prng.save_state(out)),
+			("overclock", &|out| self.overclock.save_state(out)),
+			("cpu", &|out| self.cpu.save_state(out)),
+			("ppu", &|out| self.ppu.save_state(out)),
+			("apu", &|out| self.apu.save_state(out)),
+			("cartridge", &|out| self.cartridge.save_state(out)),
+		];
+		sections.iter().map(|&(name, save)| {
+			let mut data = Vec::new();
+			save(&mut data)?;
+			Ok((name, data))
+		}).collect()
+	}
+
+	// Restores a state written by save_state. If this fails, e.g. as the
+	// state is truncated or of another ROM, the console stays as it was.
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		let mut magic = [0; 4];
+		savestate::read_bytes(input, &mut magic)?;
+		if &magic != STATE_MAGIC {
+			return savestate::invalid_state("Not a save state.");
+		}
+		if savestate::read_u8(input)? != STATE_VERSION {
+			return savestate::invalid_state("Unsupported save state version.");
+		}
+		let compression = match Compression::from_id(savestate::read_u8(input)?) {
+			Some(compression) => compression,
+			None => return savestate::invalid_state("Unknown compression."),
+		};
+		let mut body = Vec::new();
+		input.read_to_end(&mut body)?;
+		let body = savestate::decompress(compression, body)?;
+		// the components are replaced one by one, so a failure half way
+		// goes back to the state from before
+		let backup = self.save_body()?;
+		if let Err(err) = self.load_body(&mut &body[..]) {
+			self.load_body(&mut &backup[..]).expect("Could not restore the state before loading");
+			return Err(err);
+		}
+		Ok(())
+	}
+
+	fn load_body(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.clock = savestate::read_u64(input)?;
+		self.region = if savestate::read_bool(input)? { Region::Pal } else { Region::Ntsc };
+		self.dot_fraction = savestate::read_u8(input)? as u64 % 5;
+		self.ppu.set_region(self.region);
+		self.apu.set_region(self.region);
+		self.apply_settings();
+		self.prng.load_state(input)?;
+		self.overclock.load_state(input)?;
+		self.cpu.load_state(input)?;
+		self.ppu.load_state(input)?;
+		self.apu.load_state(input)?;
+		self.cartridge.load_state(input)?;
+		let cycles = self.cpu_cycles();
+		self.apu.set_cycles(cycles);
+		self.apu.declick();
+		self.reschedule();
+		Ok(())
+	}
+
+	// Applies an external event at the current point in time.
+	pub fn handle_event(&mut self, event: ConsoleEvent) {
+		match event {
+			ConsoleEvent::SoftReset => {
+				self.ppu.reset();
+				let mut hw = Hardware {
+					ppu: &mut self.ppu,
+					apu: &mut self.apu,
+					input: &mut self.input,
+					cartridge: &mut *self.cartridge,
+				};
+				self.cpu.reset(&mut hw);
+			}
+			ConsoleEvent::PowerCycle => {
+				// the watchpoints and traps belong to the debugger and the
+				// high-level emulation, not to the console
+				let watchpoints = self.cpu.watchpoints().to_vec();
+				let traps = self.cpu.take_traps();
+				self.cpu = Cpu::with_ram_size(self.machine.ram_size);
+				self.cpu.set_watchpoints(watchpoints);
+				self.cpu.set_traps(traps);
+				if self.settings.random_ram {
+					self.cpu.randomize_ram(&mut self.prng);
+				}
+				let scanline_output = self.ppu.take_scanline_output();
+				self.ppu = Ppu::new();
+				self.ppu.set_region(self.region);
+				self.ppu.set_scanline_output(scanline_output);
+				self.dma = Dma::new();
+				self.overclock.reset();
+				self.apply_settings();
+				let output = self.apu.output();
+				let stems = self.apu.stems_enabled();
+				let audio_output = self.apu.take_output();
+				self.apu = Apu::new();
+				self.apu.set_stems(stems);
+				self.apu.set_output(audio_output);
+				self.apu.set_region(self.region);
+				self.apu.set_settings(self.audio_settings.clone());
+				self.apu.ramp_from(output);
+				let cycles = self.cpu_cycles();
+				self.apu.set_cycles(cycles);
+				let mut hw = Hardware {
+					ppu: &mut self.ppu,
+					apu: &mut self.apu,
+					input: &mut self.input,
+					cartridge: &mut *self.cartridge,
+				};
+				self.cpu.jump_to_start(&mut hw);
+			}
+		}
+		self.reschedule();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::{assemble, AccessSource, BUS_REGIONS};
+	use cartridge::test_cartridge::TestCartridge;
+	use cartridge::{MirrorMode, HAS_IRQ};
+	use cartridge::nrom::NRom;
+	use machine::MachinePreset;
+
+	fn peek(nes: &mut Nes, addr: u16) -> u8 {
+		nes.peek_memory(addr)
+	}
+
+	fn read(nes: &mut Nes, addr: u16) -> u8 {
+		let mut hw = Hardware {
+			ppu: &mut nes.ppu,
+			apu: &mut nes.apu,
+			input: &mut nes.input,
+			cartridge: &mut *nes.cartridge,
+		};
+		nes.cpu.read_memory(&mut hw, addr)
+	}
+
+	fn run(nes: &mut Nes, instructions: usize) {
+		let mut instr_log: Option<&mut dyn Write> = None;
+		for _ in 0..instructions {
+			nes.step(&mut instr_log);
+		}
+	}
+
+	#[test]
+	fn audio_sample_count() {
+		// rendering on, so every other frame is a dot short
+		let code = assemble(0x8000, "LDA #$08; STA $2001; JMP $8005").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		// the first frame starts at power on, not at a vblank
+		nes.run_frame();
+		let mut total = nes.take_audio().unwrap().samples.len() as u64;
+
+		// two frames take 29780 + 29781 cycles, measured from the dot which
+		// sets the vblank flag, as run_frame returns an instruction later
+		let vblank_start = |nes: &Nes| nes.clock - (nes.ppu.scanline() * 341 + nes.ppu.dot() - (241 * 341 + 2)) as u64;
+		let first = vblank_start(&nes);
+		let mut state = Vec::new();
+		nes.save_state(&mut state).unwrap();
+		nes.run_frame();
+		nes.run_frame();
+		assert_eq!(3 * (29780 + 29781), vblank_start(&nes) - first);
+		nes.load_state(&mut &state[..]).unwrap();
+		nes.take_audio();
+		for _ in 0..10 {
+			nes.run_frame();
+			let samples = nes.take_audio().unwrap().samples.len() as u64;
+			assert!((733..=735).contains(&samples), "{}", samples);
+			total += samples;
+			assert_eq!(::apu::samples_for_cycles(nes.cpu_cycles(), nes.region()), total);
+		}
+
+		// a loaded state continues the count from its clock
+		let mut state = Vec::new();
+		nes.save_state(&mut state).unwrap();
+		nes.run_frame();
+		nes.load_state(&mut &state[..]).unwrap();
+		nes.take_audio();
+		nes.run_frame();
+		total += nes.take_audio().unwrap().samples.len() as u64;
+		assert_eq!(::apu::samples_for_cycles(nes.cpu_cycles(), nes.region()), total);
+	}
+
+	#[test]
+	fn pal_timing() {
+		let code = assemble(0x8000, "JMP $8000").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		nes.set_region(Region::Pal);
+		nes.run_frame();
+		nes.take_audio();
+		let (clock, cycles) = (nes.clock(), nes.cpu_cycles());
+		nes.run_frame();
+		// 312 lines at 3.2 dots per CPU cycle, 50 frames per second
+		assert!((nes.clock() - clock).abs_diff(312 * 341) < 3 * 4);
+		assert!((nes.cpu_cycles() - cycles).abs_diff(33247) < 4);
+		let samples = nes.take_audio().unwrap().samples.len();
+		assert!((881..=883).contains(&samples), "{}", samples);
+
+		// the region is part of save states
+		let mut state = Vec::new();
+		nes.save_state(&mut state).unwrap();
+		nes.set_region(Region::Ntsc);
+		nes.load_state(&mut &state[..]).unwrap();
+		assert_eq!(Region::Pal, nes.region());
+		let clock = nes.clock();
+		nes.run_frame();
+		assert!((nes.clock() - clock).abs_diff(312 * 341) < 3 * 4);
+	}
+
+	#[test]
+	fn console_events() {
+		// counts how often the program was started
+		let code = assemble(0x8000, "INC $10; JMP $8002").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		run(&mut nes, 10);
+		assert_eq!(1, peek(&mut nes, 0x10));
+
+		nes.handle_event(ConsoleEvent::SoftReset);
+		run(&mut nes, 10);
+		assert_eq!(2, peek(&mut nes, 0x10));
+
+		nes.handle_event(ConsoleEvent::PowerCycle);
+		run(&mut nes, 10);
+		assert_eq!(1, peek(&mut nes, 0x10));
+	}
+
+	#[test]
+	fn random_ram() {
+		let ram = |nes: &Nes| (0..0x800).map(|addr| nes.peek_ram(addr)).collect::<Vec<u8>>();
+		let power_on = |seed: u64| {
+			let mut nes = Nes::new(Box::new(TestCartridge::builder().build()));
+			let mut settings = nes.settings().clone();
+			settings.random_ram = true;
+			nes.set_settings(settings);
+			nes.set_seed(seed);
+			nes.handle_event(ConsoleEvent::PowerCycle);
+			nes
+		};
+		let mut a = power_on(7);
+		assert_eq!(ram(&a), ram(&power_on(7)));
+		assert!(ram(&a) != ram(&power_on(8)));
+		assert!(ram(&a).iter().any(|&value| value != 0));
+
+		// the random numbers continue from a loaded state
+		let mut state = Vec::new();
+		a.save_state(&mut state).unwrap();
+		a.handle_event(ConsoleEvent::PowerCycle);
+		let next = ram(&a);
+		a.handle_event(ConsoleEvent::PowerCycle);
+		a.load_state(&mut &state[..]).unwrap();
+		a.handle_event(ConsoleEvent::PowerCycle);
+		assert_eq!(next, ram(&a));
+	}
+
+	#[test]
+	fn overclock() {
+		// counts loop iterations in $10/$11
+		let code = assemble(0x8000, "INC $10; BNE $8000; INC $11; JMP $8000").unwrap();
+		let frame = |post_render: u32, pre_render: u32| {
+			let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+			let mut settings = nes.settings().clone();
+			settings.overclock_lines = post_render;
+			settings.overclock_prerender_lines = pre_render;
+			nes.set_settings(settings);
+			let count = |nes: &Nes| nes.peek_ram(0x10) as u64 | (nes.peek_ram(0x11) as u64) << 8;
+			nes.run_frame();
+			let (clock, iterations) = (nes.clock(), count(&nes));
+			nes.run_frame();
+			(nes.clock() - clock, count(&nes) - iterations)
+		};
+		let (dots, iterations) = frame(0, 0);
+		// the console keeps its speed, the CPU gets the cycles of the lines
+		for &(post_render, pre_render) in [(20, 0), (10, 30)].iter() {
+			let (overclocked_dots, overclocked_iterations) = frame(post_render, pre_render);
+			assert!((dots as i64 - overclocked_dots as i64).abs() < 7 * 3, "{} {}", dots, overclocked_dots);
+			let expected = (iterations * Region::Ntsc.line_cycles(post_render + pre_render) / (dots / 3)) as i64;
+			let extra = (overclocked_iterations - iterations) as i64;
+			assert!((extra - expected).abs() < expected / 50, "{} {}", extra, expected);
+		}
+	}
+
+	#[test]
+	fn save_state() {
+		let code = assemble(0x8000, "INC $10; INC $6000; JMP $8000").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		run(&mut nes, 30);
+		let mut state = Vec::new();
+		nes.save_state(&mut state).unwrap();
+		run(&mut nes, 30);
+		assert_eq!(20, peek(&mut nes, 0x10));
+
+		let clock = nes.clock();
+		nes.load_state(&mut &state[..]).unwrap();
+		assert!(nes.clock() < clock);
+		assert_eq!(10, peek(&mut nes, 0x10));
+		assert_eq!(10, peek(&mut nes, 0x6000));
+		run(&mut nes, 3);
+		assert_eq!(11, peek(&mut nes, 0x10));
+		assert_eq!(11, peek(&mut nes, 0x6000));
+
+		assert!(nes.load_state(&mut &b"NESS"[..]).is_err());
+		assert!(nes.load_state(&mut &state[1..]).is_err());
+
+		// a state cut off in the cartridge leaves the console as it was
+		let mut plain = Vec::new();
+		nes.save_state_with(&mut plain, Compression::None).unwrap();
+		run(&mut nes, 30);
+		let mut before = Vec::new();
+		nes.save_state_with(&mut before, Compression::None).unwrap();
+		assert!(nes.load_state(&mut &plain[..plain.len() - 100]).is_err());
+		let mut after = Vec::new();
+		nes.save_state_with(&mut after, Compression::None).unwrap();
+		assert_eq!(before, after);
+		assert_eq!(21, peek(&mut nes, 0x10));
+	}
+
+	#[test]
+	fn compressed_state() {
+		let code = assemble(0x8000, "INC $10; INC $6000; JMP $8000").unwrap();
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+		run(&mut nes, 30);
+		let mut plain = Vec::new();
+		nes.save_state_with(&mut plain, Compression::None).unwrap();
+		assert_eq!(0, plain[5]);
+		let mut state = Vec::new();
+		let compressed = nes.save_state_with(&mut state, Compression::Lz4).is_ok();
+		assert_eq!(cfg!(feature = "lz4"), compressed);
+		if compressed {
+			assert_eq!(1, state[5]);
+			assert!(state.len() < plain.len() / 2);
+			run(&mut nes, 30);
+			nes.load_state(&mut &state[..]).unwrap();
+			let mut loaded = Vec::new();
+			nes.save_state_with(&mut loaded, Compression::None).unwrap();
+			assert_eq!(plain, loaded);
+		} else {
+			state = plain.clone();
+			state[5] = 1;
+			assert!(nes.load_state(&mut &state[..]).is_err());
+		}
+		plain[5] = 7;
+		assert!(nes.load_state(&mut &plain[..]).is_err());
+	}
+
+	#[test]
+	fn state_sections() {
+		let code = assemble(0x8000, "INC $10; JMP $8000").unwrap();
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+		run(&mut nes, 3);
+		let sections = nes.state_sections().unwrap();
+		let names: Vec<&str> = sections.iter().map(|&(name, _)| name).collect();
+		assert_eq!(vec!["clock", "prng", "overclock", "cpu", "ppu", "apu", "cartridge"], names);
+		// the body of an uncompressed state, after the magic, version and
+		// compression
+		let mut plain = Vec::new();
+		nes.save_state_with(&mut plain, Compression::None).unwrap();
+		let body: Vec<u8> = sections.into_iter().flat_map(|(_, data)| data).collect();
+		assert_eq!(&plain[6..], &body[..]);
+	}
+
+	#[test]
+	fn run_frame() {
+		let cartridge = TestCartridge::builder().build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		assert_eq!(0, nes.run_frame().number);
+		assert_eq!(1, nes.run_frame().number);
+		assert!(nes.take_frame().is_none());
+	}
+
+	#[test]
+	fn nmi() {
+		// counts NMIs while waiting in a loop
+		let code = assemble(0x8000, "LDA #$80; STA $2000; JMP $8005").unwrap();
+		let handler = assemble(0x9000, "INC $10; RTI").unwrap();
+		let cartridge = TestCartridge::builder()
+			.prg(0x8000, &code)
+			.prg(0x9000, &handler)
+			.nmi_vector(0x9000)
+			.build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		for _ in 0..3 {
+			nes.run_frame();
+		}
+		assert_eq!(2, peek(&mut nes, 0x10));
+	}
+
+	#[test]
+	fn status_race() {
+		// polls PPUSTATUS until vblank with NMI enabled, the handler counts
+		// NMIs and the loop counts detected vblanks
+		let code = assemble(0x8000, "LDA #$80; STA $2000; BIT $2002; BPL $8005; INC $11; JMP $8005").unwrap();
+		let handler = assemble(0x9000, "INC $10; RTI").unwrap();
+		let cartridge = TestCartridge::builder()
+			.prg(0x8000, &code)
+			.prg(0x9000, &handler)
+			.nmi_vector(0x9000)
+			.build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		for _ in 0..50 {
+			nes.run_frame();
+		}
+		// After every NMI the loop is back in the same phase. A frame is a
+		// third of a CPU cycle longer than a whole number of cycles, so
+		// every third frame the read lands on the dot before the flag is
+		// set, which suppresses both the flag and the NMI. Otherwise the
+		// loop sees the flag after the handler returns.
+		let nmis = peek(&mut nes, 0x10) as usize;
+		let vblanks = peek(&mut nes, 0x11) as usize;
+		assert_eq!((33, 33), (nmis, vblanks));
+	}
+
+	#[test]
+	fn peek_memory() {
+		let code = assemble(0x8000, "LDA #$05; STA $10; JMP $8004").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		run(&mut nes, 2);
+		assert_eq!(5, peek(&mut nes, 0x0810));
+		assert_eq!(0xA9, peek(&mut nes, 0x8000));
+
+		while peek(&mut nes, 0x2002) & 0x80 == 0 {
+			run(&mut nes, 1);
+		}
+		// peeking leaves the vblank flag alone, reading clears it
+		assert_eq!(0x80, peek(&mut nes, 0x200A) & 0x80);
+		assert_eq!(0x80, read(&mut nes, 0x2002) & 0x80);
+		assert_eq!(0, peek(&mut nes, 0x2002) & 0x80);
+	}
+
+	#[test]
+	fn microphone() {
+		// sets the strobe and stores $4016 to $10 forever
+		let code = assemble(0x8000, "LDA #$01; STA $4016; LDA $4016; STA $10; JMP $8005").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		run(&mut nes, 4);
+		assert_eq!(0x40, peek(&mut nes, 0x10));
+		nes.set_microphone(true);
+		run(&mut nes, 3);
+		// the upper bits are open bus, the high byte of the address
+		assert_eq!(0x44, peek(&mut nes, 0x10));
+		assert_eq!(0x44, peek(&mut nes, 0x4016));
+	}
+
+	#[test]
+	fn apu_open_bus() {
+		// the dummy read of the page crossing LDA $3FF5,X reads $2005,
+		// which returns the $20 last written to the PPU
+		let code = assemble(0x8000, "\
+			LDA $4009; STA $10; LDA $401F; STA $11; \
+			LDA #$20; STA $2005; LDX #$20; LDA $3FF5,X; STA $12; JMP $8016").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		run(&mut nes, 10);
+		// the high byte of the address is left on the bus
+		assert_eq!(0x40, peek(&mut nes, 0x10));
+		assert_eq!(0x40, peek(&mut nes, 0x11));
+		// bit 5 of $4015 is open bus
+		assert_eq!(0x20, peek(&mut nes, 0x12));
+		assert_eq!(0x40, peek(&mut nes, 0x400D));
+		assert_eq!(0x00, peek(&mut nes, 0x4015));
+	}
+
+	#[test]
+	fn run_until_event() {
+		let code = assemble(0x8000, "INC $10; JMP $8000").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		let mut instr_log: Option<&mut dyn Write> = None;
+		let mut last = None;
+		for _ in 0..3 {
+			while nes.run_until_event(&mut instr_log) != NesEvent::Vblank {
+			}
+			// due after dot 1 was processed, overshooting by one instruction at most
+			assert_eq!(241, nes.ppu().scanline());
+			assert!(2 <= nes.ppu().dot() && nes.ppu().dot() < 2 + 7 * 3);
+			if let Some(last) = last {
+				let frame = nes.clock() - last;
+				assert!(262 * 341 - 7 * 3 < frame && frame < 262 * 341 + 7 * 3);
+			}
+			last = Some(nes.clock());
+		}
+
+		// reloading a state moves the event along with the PPU
+		let mut state = Vec::new();
+		nes.run_frame();
+		nes.save_state(&mut state).unwrap();
+		run(&mut nes, 1000);
+		nes.load_state(&mut &state[..]).unwrap();
+		let before = nes.clock();
+		while nes.run_until_event(&mut instr_log) != NesEvent::Vblank {
+		}
+		assert!(nes.clock() - before > 261 * 341);
+	}
+
+	#[test]
+	fn apu_and_mapper_events() {
+		// plays a looping DMC sample, and starts the mapper timer
+		let code = assemble(0x8000, "LDA #$4F; STA $4010; LDA #$10; STA $4015; LDA #$40; STA $5001; JMP $800F").unwrap();
+		let nrom = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(IrqCartridge { nrom, line: false, timer: None }));
+		let mut instr_log: Option<&mut dyn Write> = None;
+		let mut counts = [0; 4];
+		let mut irq_cycles = 0;
+		for _ in 0..2 {
+			loop {
+				let event = nes.run_until_event(&mut instr_log);
+				counts[event as usize] += 1;
+				// due right after they happened
+				match event {
+					NesEvent::Vblank => break,
+					NesEvent::FrameStep => assert!(nes.apu.cycles_until_frame_step() > 7400),
+					NesEvent::DmcFetch => assert!(nes.apu.cycles_until_dmc_fetch().unwrap() > 8 * 54 - 8),
+					NesEvent::MapperIrq => {
+						assert!(nes.cartridge.irq_pending());
+						irq_cycles = nes.cpu_cycles();
+					}
+				}
+			}
+		}
+		assert_eq!(2, counts[NesEvent::Vblank as usize]);
+		assert!(counts[NesEvent::FrameStep as usize] >= 5);
+		assert!(counts[NesEvent::DmcFetch as usize] >= 60);
+		assert_eq!(1, counts[NesEvent::MapperIrq as usize]);
+		// started by the 6th instruction, which counts along, after a stall
+		// of up to 4 cycles by the first DMC fetch, and due by the end of
+		// the instruction where it runs out
+		assert!((0x40 * 64 + 14..0x40 * 64 + 14 + 4 + 7).contains(&irq_cycles));
+	}
+
+	// NROM with an IRQ line, asserted while the last write to $5000 was
+	// not 0, or after the value written to $5001 times 64 CPU cycles.
+	#[derive(Debug, Clone)]
+	struct IrqCartridge {
+		nrom: NRom,
+		line: bool,
+		timer: Option<u32>,
+	}
+
+	impl Cartridge for IrqCartridge {
+		fn name(&self) -> &'static str { "IrqCartridge" }
+		fn capabilities(&self) -> u8 { self.nrom.capabilities() | HAS_IRQ }
+		fn read_cpu(&mut self, addr: u16) -> u8 { self.nrom.read_cpu(addr) }
+		fn write_cpu(&mut self, addr: u16, value: u8) {
+			match addr {
+				0x5000 => self.line = value != 0,
+				0x5001 => self.timer = Some(value as u32 * 64),
+				_ => {}
+			}
+			self.nrom.write_cpu(addr, value)
+		}
+		fn read_ppu(&mut self, addr: u16) -> u8 { self.nrom.read_ppu(addr) }
+		fn write_ppu(&mut self, addr: u16, value: u8) { self.nrom.write_ppu(addr, value) }
+		fn mirror_mode(&self) -> MirrorMode { self.nrom.mirror_mode() }
+		fn cpu_clock(&mut self, cycles: u32) {
+			match self.timer {
+				Some(timer) if timer <= cycles => {
+					self.line = true;
+					self.timer = None;
+				}
+				Some(timer) => self.timer = Some(timer - cycles),
+				None => {}
+			}
+		}
+		fn irq_pending(&self) -> bool { self.line }
+		fn cycles_until_irq(&self) -> Option<u32> { self.timer }
+		fn save_state(&self, out: &mut dyn Write) -> io::Result<()> { self.nrom.save_state(out) }
+		fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> { self.nrom.load_state(input) }
+	}
+
+	#[test]
+	fn cartridge_irq() {
+		// asserts the line, then counts in $10
+		let main = assemble(0x8000, "LDX #$FF; TXS; LDA #1; STA $5000; CLI; INC $10; JMP $8009").unwrap();
+		// counts in $11 and acknowledges the third IRQ only
+		let handler = assemble(0x9000, "INC $11; LDA $11; CMP #3; BNE $900D; LDA #0; STA $5000; RTI").unwrap();
+		let nrom = TestCartridge::builder().prg(0x8000, &main).prg(0x9000, &handler).irq_vector(0x9000).build();
+		let mut nes = Nes::new(Box::new(IrqCartridge { nrom, line: false, timer: None }));
+		let mut instr_log: Option<&mut dyn Write> = None;
+		while nes.pc() != 0x9000 {
+			nes.step(&mut instr_log);
+		}
+		// taken after the instruction following CLI
+		assert_eq!((1, 0), (nes.peek_ram(0x10), nes.peek_ram(0x11)));
+		assert!(nes.cpu.registers().p.interrupt);
+
+		// the line stays asserted, so the handler runs again after RTI
+		while nes.peek_ram(0x11) < 3 {
+			nes.step(&mut instr_log);
+		}
+		assert_eq!(1, nes.peek_ram(0x10));
+		for _ in 0..20 {
+			nes.step(&mut instr_log);
+		}
+		assert_eq!(3, nes.peek_ram(0x11));
+		assert!(nes.peek_ram(0x10) > 5);
+	}
+
+	#[test]
+	fn traps() {
+		let code = assemble(0x8000, "LDX #$FF; TXS; JSR $9000; STA $11; INC $12; JMP $800A").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		// a native subroutine at 9000, where the ROM has no code
+		nes.set_trap(0x9000, Box::new(|cpu: &mut Cpu, hw: &mut Hardware| {
+			let count = cpu.read_memory(hw, 0x10);
+			cpu.write_memory(hw, 0x10, count + 1);
+			cpu.registers_mut().a = 0x42;
+			cpu.return_from_subroutine(hw);
+			6
+		}));
+		let mut instr_log: Option<&mut dyn Write> = None;
+		for _ in 0..3 {
+			nes.step(&mut instr_log);
+		}
+		assert_eq!(0x9000, nes.pc());
+		let start = nes.cpu_cycles();
+		nes.step(&mut instr_log);
+		assert_eq!(6, nes.cpu_cycles() - start);
+		assert_eq!(0x8006, nes.pc());
+		for _ in 0..3 {
+			nes.step(&mut instr_log);
+		}
+		assert_eq!((1, 0x42, 1), (nes.peek_ram(0x10), nes.peek_ram(0x11), nes.peek_ram(0x12)));
+
+		// the trap stays over a power cycle, until it is removed
+		nes.handle_event(ConsoleEvent::PowerCycle);
+		for _ in 0..7 {
+			nes.step(&mut instr_log);
+		}
+		assert_eq!(1, nes.peek_ram(0x10));
+		nes.remove_trap(0x9000);
+		nes.handle_event(ConsoleEvent::PowerCycle);
+		for _ in 0..4 {
+			nes.step(&mut instr_log);
+		}
+		assert_ne!(0x8006, nes.pc());
+	}
+
+	#[test]
+	fn famiclone_ram() {
+		let code = assemble(0x8000, "LDA #$42; STA $0800; LDA #$17; STA $1FFF; JMP $800A").unwrap();
+		let run = |machine: MachineConfig| {
+			let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+			nes.set_machine(machine);
+			nes.handle_event(ConsoleEvent::PowerCycle);
+			nes.run_frame();
+			nes
+		};
+		// mirrored on the Famicom, $0800 and $1FFF are $0000 and $07FF
+		let famicom = run(MachineConfig::new());
+		assert_eq!(2048, famicom.ram_size());
+		assert_eq!((0x42, 0x17), (famicom.peek_ram(0x0000), famicom.peek_ram(0x07FF)));
+		let famiclone = run(MachineConfig::from_preset(MachinePreset::Famiclone));
+		assert_eq!(8192, famiclone.ram_size());
+		assert_eq!((0x00, 0x42), (famiclone.peek_ram(0x0000), famiclone.peek_ram(0x0800)));
+		assert_eq!((0x00, 0x17), (famiclone.peek_ram(0x07FF), famiclone.peek_ram(0x1FFF)));
+
+		// states only load into a machine with the same RAM
+		let mut state = Vec::new();
+		famiclone.save_state(&mut state).unwrap();
+		let mut nes = run(MachineConfig::from_preset(MachinePreset::Famiclone));
+		nes.load_state(&mut &state[..]).unwrap();
+		assert!(run(MachineConfig::new()).load_state(&mut &state[..]).is_err());
+	}
+
+	#[test]
+	fn apu_registers_in_state() {
+		let code = assemble(0x8000, "LDA #$8F; STA $4008; LDA #$A9; STA $400A; JMP $800A").unwrap();
+		let cartridge = || Box::new(TestCartridge::builder().prg(0x8000, &code).build());
+		let mut nes = Nes::new(cartridge());
+		nes.run_frame();
+		assert_eq!(&[0x8F, 0, 0xA9, 0], nes.channel_registers(Channel::Triangle));
+		let mut state = Vec::new();
+		nes.save_state(&mut state).unwrap();
+		let mut loaded = Nes::new(cartridge());
+		loaded.load_state(&mut &state[..]).unwrap();
+		assert_eq!(&[0x8F, 0, 0xA9, 0], loaded.channel_registers(Channel::Triangle));
+	}
+
+	#[test]
+	fn oam_dma() {
+		let code = assemble(0x8000, "LDA #$02; STA $4014; STA $4014; NOP").unwrap();
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+		for i in 0..256 {
+			nes.poke_memory(0x0200 + i, 255 - i as u8);
+		}
+		let mut instr_log: Option<&mut dyn Write> = None;
+		nes.step(&mut instr_log);
+		// STA takes 4 cycles, DMA 513 and an alignment cycle if the write
+		// is on an odd cycle
+		for _ in 0..2 {
+			let start = nes.cpu_cycles();
+			nes.step(&mut instr_log);
+			let write_cycle = start + 3;
+			assert_eq!(4 + 513 + write_cycle % 2, nes.cpu_cycles() - start);
+		}
+		// without the unimplemented bits of the attributes
+		assert_eq!((0..256).map(|i| (255 - i as u8) & if i % 4 == 2 { 0xE3 } else { 0xFF }).collect::<Vec<u8>>(),
+			(0..256).map(|i| nes.peek_oam(i as u8)).collect::<Vec<u8>>());
+		let stats = nes.take_bus_stats();
+		assert_eq!(2 * 512, BUS_REGIONS.iter().map(|&region| stats.count(AccessSource::Dma, region)).sum::<u64>());
+	}
+
+	#[test]
+	fn dmc_dma() {
+		// a 1 byte sample at $C000 with its interrupt, which the handler
+		// counts in $10 and acknowledges
+		let code = assemble(0x8000, "\
+			LDA #$8F; STA $4010; LDA #$00; STA $4012; STA $4013; \
+			LDA #$10; STA $4015; CLI; JMP $8013").unwrap();
+		let handler = assemble(0x9000, "INC $10; LDA #$00; STA $4015; RTI").unwrap();
+		let cartridge = TestCartridge::builder()
+			.prg(0x8000, &code)
+			.prg(0x9000, &handler)
+			.prg(0xC000, &[0x55])
+			.irq_vector(0x9000)
+			.build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		run(&mut nes, 6);
+		nes.take_bus_stats();
+		// enabling the channel fetches the byte right away, which halts the
+		// CPU for 3 or 4 cycles
+		let start = nes.cpu_cycles();
+		run(&mut nes, 1);
+		let cycles = nes.cpu_cycles() - start;
+		assert!(cycles == 4 + 3 || cycles == 4 + 4, "{} cycles", cycles);
+		let stats = nes.take_bus_stats();
+		assert_eq!(1, BUS_REGIONS.iter().map(|&region| stats.count(AccessSource::Dma, region)).sum::<u64>());
+		assert_eq!(0x80, peek(&mut nes, 0x4015));
+		run(&mut nes, 10);
+		assert_eq!(1, peek(&mut nes, 0x10));
+		assert_eq!(0x00, peek(&mut nes, 0x4015));
+	}
+}
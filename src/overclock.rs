@@ -0,0 +1,207 @@
+use cartridge::Cartridge;
+use ppu::{Ppu, SCREEN_HEIGHT};
+use region::Region;
+use std::io::{self, Read, Write};
+use savestate;
+
+// Extra CPU time, which reduces the slowdown and flicker of games that do
+// not finish their work within a frame. Inaccurate: the real console has
+// no such thing, and games which count on their timing can break.
+//
+// The PPU stops for the CPU cycles of extra scanlines after the post-render
+// line, before vblank starts and raises the NMI, and optionally before the
+// pre-render line, at the end of vblank. With the former the time between
+// NMI and rendering stays the same, so games which time their vblank code
+// still work. The latter lengthens vblank instead, which more games mind.
+// The APU and the cartridge stop as well, so the audio and the clock
+// of the console keep their speed and only the CPU runs ahead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overclock {
+	// CPU cycles of the extra lines before vblank and before the
+	// pre-render line, 0 when off
+	post_render_cycles: u64,
+	pre_render_cycles: u64,
+	prerender_line: usize,
+	// CPU cycles left of the current stop
+	cycles_left: u64,
+	// PPU dots of the instruction which reached the stop, run after it
+	deferred_dots: u64,
+	// frame and line of the last stop, so each is only taken once
+	stopped_at: (u64, usize),
+}
+
+impl Overclock {
+	pub fn new() -> Overclock {
+		Overclock {
+			post_render_cycles: 0,
+			pre_render_cycles: 0,
+			prerender_line: Region::Ntsc.prerender_line(),
+			cycles_left: 0,
+			deferred_dots: 0,
+			stopped_at: (u64::MAX, 0),
+		}
+	}
+
+	// Sets the numbers of extra lines before vblank and before pre-render.
+	pub fn set_lines(&mut self, region: Region, post_render: u32, pre_render: u32) {
+		self.post_render_cycles = region.line_cycles(post_render);
+		self.pre_render_cycles = region.line_cycles(pre_render);
+		self.prerender_line = region.prerender_line();
+	}
+
+	// Forgets a stop in progress, e.g. on power cycle.
+	pub fn reset(&mut self) {
+		*self = Overclock {
+			post_render_cycles: self.post_render_cycles,
+			pre_render_cycles: self.pre_render_cycles,
+			prerender_line: self.prerender_line,
+			..Overclock::new()
+		};
+	}
+
+	// Whether the PPU stands still and only the CPU runs.
+	pub fn stopped(&self) -> bool {
+		self.cycles_left > 0
+	}
+
+	// Runs the PPU for the dots, unless it reaches a stop. The dots after
+	// the stop are run when it ends, see spend. Returns the dots run.
+	pub fn run_ppu(&mut self, ppu: &mut Ppu, cartridge: &mut dyn Cartridge, dots: u32) -> u32 {
+		if self.stopped() {
+			self.deferred_dots += dots as u64;
+			return 0;
+		}
+		if self.post_render_cycles == 0 && self.pre_render_cycles == 0 {
+			for _ in 0..dots {
+				ppu.tick(cartridge);
+			}
+			return dots;
+		}
+		for i in 0..dots {
+			let cycles = self.stop_cycles(ppu);
+			let position = (ppu.frame_number(), ppu.scanline());
+			if cycles > 0 && position != self.stopped_at {
+				self.stopped_at = position;
+				self.cycles_left = cycles;
+				self.deferred_dots = (dots - i) as u64;
+				return i;
+			}
+			ppu.tick(cartridge);
+		}
+		dots
+	}
+
+	// The extra CPU cycles at the position of the PPU: at the start of the
+	// line after the post-render line, whose dot 1 starts vblank, and at the
+	// start of the pre-render line.
+	fn stop_cycles(&self, ppu: &Ppu) -> u64 {
+		if ppu.dot() != 0 {
+			0
+		} else if ppu.scanline() == SCREEN_HEIGHT + 1 {
+			self.post_render_cycles
+		} else if ppu.scanline() == self.prerender_line {
+			self.pre_render_cycles
+		} else {
+			0
+		}
+	}
+
+	// Counts CPU cycles run during a stop. Returns the deferred dots to run
+	// once it ended.
+	pub fn spend(&mut self, cycles: u32) -> u32 {
+		self.cycles_left = self.cycles_left.saturating_sub(cycles as u64);
+		if self.stopped() {
+			return 0;
+		}
+		let dots = self.deferred_dots as u32;
+		self.deferred_dots = 0;
+		dots
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u64(out, self.cycles_left)?;
+		savestate::write_u64(out, self.deferred_dots)?;
+		savestate::write_u64(out, self.stopped_at.0)?;
+		savestate::write_u16(out, self.stopped_at.1 as u16)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.cycles_left = savestate::read_u64(input)?;
+		self.deferred_dots = savestate::read_u64(input)?;
+		let frame = savestate::read_u64(input)?;
+		self.stopped_at = (frame, savestate::read_u16(input)? as usize);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::test_cartridge::TestCartridge;
+
+	// The CPU cycles, at 3 dots each, at which the PPU reaches the
+	// post-render line, starts vblank with the NMI and ends it on the
+	// pre-render line, where rendering starts.
+	fn milestones(post_render: u32, pre_render: u32) -> [u64; 3] {
+		let mut overclock = Overclock::new();
+		overclock.set_lines(Region::Ntsc, post_render, pre_render);
+		let mut ppu = Ppu::new();
+		let mut cartridge = TestCartridge::builder().build();
+		let targets = [(SCREEN_HEIGHT, 0), (SCREEN_HEIGHT + 1, 1), (Region::Ntsc.prerender_line(), 1)];
+		let mut reached = [0; 3];
+		let mut next = 0;
+		let mut cycle = 0;
+		while next < targets.len() {
+			let dots = if overclock.stopped() { overclock.spend(1) } else { 3 };
+			overclock.run_ppu(&mut ppu, &mut cartridge, dots);
+			cycle += 1;
+			let (scanline, dot) = targets[next];
+			if ppu.scanline() == scanline && ppu.dot() >= dot {
+				reached[next] = cycle;
+				next += 1;
+			}
+		}
+		reached
+	}
+
+	#[test]
+	fn extra_lines() {
+		let normal = milestones(0, 0);
+		let before_nmi = |milestones: [u64; 3]| milestones[1] - milestones[0];
+		let nmi_to_rendering = |milestones: [u64; 3]| milestones[2] - milestones[1];
+		assert!(nmi_to_rendering(normal).abs_diff(20 * 341 / 3) <= 1);
+
+		// the CPU gets the lines before the NMI, vblank keeps its length
+		let post_render = milestones(20, 0);
+		assert!(before_nmi(post_render).abs_diff(before_nmi(normal) + Region::Ntsc.line_cycles(20)) <= 1);
+		assert_eq!(nmi_to_rendering(normal), nmi_to_rendering(post_render));
+
+		// the CPU gets the lines at the end of vblank
+		let pre_render = milestones(0, 30);
+		assert_eq!(before_nmi(normal), before_nmi(pre_render));
+		assert!(nmi_to_rendering(pre_render).abs_diff(nmi_to_rendering(normal) + Region::Ntsc.line_cycles(30)) <= 1);
+
+		let both = milestones(10, 30);
+		assert!(before_nmi(both).abs_diff(before_nmi(normal) + Region::Ntsc.line_cycles(10)) <= 1);
+		assert!(nmi_to_rendering(both).abs_diff(nmi_to_rendering(normal) + Region::Ntsc.line_cycles(30)) <= 1);
+	}
+
+	#[test]
+	fn stop_once_per_line() {
+		let mut overclock = Overclock::new();
+		overclock.set_lines(Region::Ntsc, 1, 0);
+		let mut ppu = Ppu::new();
+		let mut cartridge = TestCartridge::builder().build();
+		let dots = ppu.dots_until(SCREEN_HEIGHT, 340) as u32;
+		assert_eq!(dots, overclock.run_ppu(&mut ppu, &mut cartridge, dots + 5));
+		assert!(overclock.stopped());
+		// the PPU waits for the cycles of the line, then runs the dots left
+		assert_eq!(0, overclock.run_ppu(&mut ppu, &mut cartridge, 3));
+		assert_eq!(0, overclock.spend(100));
+		assert_eq!(8, overclock.spend(13));
+		assert!(!overclock.stopped());
+		// and carries on from the stop without taking it again
+		assert_eq!(8, overclock.run_ppu(&mut ppu, &mut cartridge, 8));
+		assert_eq!((SCREEN_HEIGHT + 1, 8), (ppu.scanline(), ppu.dot()));
+	}
+}
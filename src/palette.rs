@@ -0,0 +1,159 @@
+use debug_view::{DebugImage, PALETTE_EDITOR_CELL};
+use ppu::RGB_PALETTE;
+use std::fs::File;
+use std::io::{Read, Write};
+
+// .pal files hold the RGB bytes of the 64 colors. Some have 8 sets of
+// them, one for each combination of the emphasis bits; only the first set
+// is used, the emphasis is computed.
+pub fn load_pal(path: &str) -> Result<[u8; 64 * 3], String> {
+	let mut data = Vec::new();
+	if let Err(err) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+		return Err(format!("{}: {}", path, err));
+	}
+	if data.len() != 64 * 3 && data.len() != 8 * 64 * 3 {
+		return Err(format!("{}: A palette has 192 or 1536 bytes, not {}.", path, data.len()));
+	}
+	let mut palette = [0; 64 * 3];
+	palette.copy_from_slice(&data[..64 * 3]);
+	Ok(palette)
+}
+
+pub fn save_pal(path: &str, palette: &[u8; 64 * 3]) -> Result<(), String> {
+	File::create(path)
+		.and_then(|mut file| file.write_all(palette))
+		.map_err(|err| format!("{}: {}", path, err))
+}
+
+// Changes of the palette editor, from the keys in its window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaletteEdit {
+	// Moves the selection in the grid of 16 x 4 colors.
+	Select(i32, i32),
+	// Changes the red, green or blue channel of the selected color.
+	Adjust(usize, i32),
+	// Goes back to the built-in color of the selection.
+	Revert,
+}
+
+// The selection in the palette editor window. The palette itself is the
+// one of the console, so every change shows at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaletteEditor {
+	selected: usize,
+}
+
+impl PaletteEditor {
+	pub fn new() -> PaletteEditor {
+		PaletteEditor {
+			selected: 0,
+		}
+	}
+
+	// Applies the edit and returns the changed palette.
+	pub fn edit(&mut self, edit: PaletteEdit, palette: &[u8; 64 * 3]) -> [u8; 64 * 3] {
+		let mut palette = *palette;
+		let index = self.selected * 3;
+		match edit {
+			PaletteEdit::Select(dx, dy) => {
+				let x = (self.selected as i32 % 16 + dx + 16) % 16;
+				let y = (self.selected as i32 / 16 + dy + 4) % 4;
+				self.selected = (y * 16 + x) as usize;
+			}
+			PaletteEdit::Adjust(channel, delta) => {
+				let value = &mut palette[index + channel];
+				*value = (*value as i32 + delta).clamp(0, 255) as u8;
+			}
+			PaletteEdit::Revert => palette[index..index + 3].copy_from_slice(&RGB_PALETTE[index..index + 3]),
+		}
+		palette
+	}
+
+	// e.g. "Palette Editor: $16 B5 31 20"
+	pub fn title(&self, palette: &[u8; 64 * 3]) -> String {
+		let index = self.selected * 3;
+		format!("Palette Editor: ${:02X} {:02X} {:02X} {:02X}",
+			self.selected, palette[index], palette[index + 1], palette[index + 2])
+	}
+
+	// Frames the selected color in the image of DebugView::PaletteEditor,
+	// white on dark colors and black on light ones.
+	pub fn draw_selection(&self, image: &mut DebugImage) {
+		let x0 = self.selected % 16 * PALETTE_EDITOR_CELL;
+		let y0 = self.selected / 16 * PALETTE_EDITOR_CELL;
+		let index = self.selected * 3;
+		let brightness: u32 = image.palette[index..index + 3].iter().map(|&value| value as u32).sum();
+		let frame = if brightness < 3 * 128 { 0x30 } else { 0x0F };
+		for i in 0..PALETTE_EDITOR_CELL {
+			image.set_pixel(x0 + i, y0, frame);
+			image.set_pixel(x0 + i, y0 + PALETTE_EDITOR_CELL - 1, frame);
+			image.set_pixel(x0, y0 + i, frame);
+			image.set_pixel(x0 + PALETTE_EDITOR_CELL - 1, y0 + i, frame);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::test_cartridge::TestCartridge;
+	use debug_view::DebugView;
+	use nes::{Nes, ConsoleEvent};
+	use std::env;
+	use std::fs;
+
+	#[test]
+	fn editor() {
+		let mut editor = PaletteEditor::new();
+		let palette = editor.edit(PaletteEdit::Select(-1, 1), &RGB_PALETTE);
+		assert_eq!((0x1F, &RGB_PALETTE[..]), (editor.selected, &palette[..]));
+		editor.edit(PaletteEdit::Select(2, -3), &palette);
+		assert_eq!(0x21, editor.selected);
+
+		let palette = editor.edit(PaletteEdit::Adjust(0, 300), &palette);
+		let palette = editor.edit(PaletteEdit::Adjust(2, -300), &palette);
+		assert_eq!([0xFF, RGB_PALETTE[0x21 * 3 + 1], 0x00], palette[0x21 * 3..0x21 * 3 + 3]);
+		assert_eq!(&RGB_PALETTE[..0x21 * 3], &palette[..0x21 * 3]);
+		assert_eq!(format!("Palette Editor: $21 FF {:02X} 00", RGB_PALETTE[0x21 * 3 + 1]), editor.title(&palette));
+		let palette = editor.edit(PaletteEdit::Revert, &palette);
+		assert_eq!(&RGB_PALETTE[..], &palette[..]);
+
+		// the view shows the palette of the console
+		let rgb = |palette: &[u8], color: usize| (palette[color * 3], palette[color * 3 + 1], palette[color * 3 + 2]);
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().build()));
+		let palette = editor.edit(PaletteEdit::Adjust(1, 1), nes.master_palette());
+		nes.set_master_palette(palette);
+		nes.handle_event(ConsoleEvent::PowerCycle);
+		// the picture has the changed color, here the backdrop
+		nes.poke_palette(0, 0x21);
+		let frame = nes.run_frame();
+		assert_eq!(rgb(&palette, 0x21), frame.pixel(0, 0));
+		assert_ne!(rgb(&RGB_PALETTE, 0x21), frame.pixel(0, 0));
+		let mut image = DebugView::PaletteEditor.draw(&mut nes);
+		editor.draw_selection(&mut image);
+		let (x, y) = (PALETTE_EDITOR_CELL, 2 * PALETTE_EDITOR_CELL);
+		assert_eq!(rgb(&palette, 0x21), image.pixel(x + 5, y + 5));
+		assert_eq!(rgb(&palette, 0x0F), image.pixel(x, y + 5));
+		assert_eq!(rgb(&palette, 0x02), image.pixel(2 * PALETTE_EDITOR_CELL + 5, 5));
+	}
+
+	#[test]
+	fn pal_files() {
+		let path = env::temp_dir().join(format!("nes-palette-{}.pal", ::std::process::id()));
+		let path = path.to_str().unwrap();
+		let mut palette = RGB_PALETTE;
+		palette[5] = 0x77;
+		save_pal(path, &palette).unwrap();
+		assert_eq!(&palette[..], &load_pal(path).unwrap()[..]);
+
+		// with the emphasis sets
+		let mut data = palette.to_vec();
+		data.extend_from_slice(&[0; 7 * 64 * 3]);
+		File::create(path).and_then(|mut file| file.write_all(&data)).unwrap();
+		assert_eq!(&palette[..], &load_pal(path).unwrap()[..]);
+
+		File::create(path).and_then(|mut file| file.write_all(&data[..100])).unwrap();
+		assert!(load_pal(path).is_err());
+		fs::remove_file(path).unwrap();
+	}
+}
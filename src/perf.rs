@@ -0,0 +1,245 @@
+use ppu::{Frame, SCREEN_HEIGHT};
+use cpu::{BusStats, AccessSource, ACCESS_SOURCES, BUS_REGIONS};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Number of frames shown in the graph, one pixel column each.
+const HISTORY: usize = 120;
+const GRAPH_HEIGHT: usize = 40;
+// Vertical scale of the graph.
+const PIXELS_PER_MS: f64 = 2.0;
+// Time budget of an NTSC frame, drawn as a line.
+const FRAME_BUDGET_MS: f64 = 1000.0 / 60.0988;
+// Horizontal scale of the bus access bars, a frame has about 30000.
+const ACCESSES_PER_PIXEL: u64 = 256;
+// Height of the bus access bars, with one line between them.
+const BAR_HEIGHT: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+struct FrameTimes {
+	emulation: f64,
+	render: f64,
+	audio_fill: Option<f64>,
+}
+
+// Frame time statistics of the last frames, drawn over the picture as a
+// rolling graph with the averages above it:
+//   green  time spent emulating the frame
+//   blue   time spent rendering and presenting it
+//   yellow fill level of the audio buffer, if there is audio output
+//   red    the time budget of one frame
+// Above it, one bar per region of the CPU address space (RAM, PPU
+// registers, APU/IO, cartridge from top to bottom) shows the bus accesses
+// of the last frame, colored by source: fetch grey, data cyan, stack
+// magenta, vector orange.
+pub struct PerfHud {
+	history: VecDeque<FrameTimes>,
+	bus_stats: Option<BusStats>,
+}
+
+impl PerfHud {
+	pub fn new() -> PerfHud {
+		PerfHud {
+			history: VecDeque::with_capacity(HISTORY),
+			bus_stats: None,
+		}
+	}
+
+	// audio_fill is the fill level of the audio buffer from 0 to 1.
+	pub fn record(&mut self, emulation: Duration, render: Duration, audio_fill: Option<f64>) {
+		if self.history.len() == HISTORY {
+			self.history.pop_front();
+		}
+		self.history.push_back(FrameTimes {
+			emulation: millis(emulation),
+			render: millis(render),
+			audio_fill,
+		});
+	}
+
+	// The CPU bus accesses of the last frame.
+	pub fn record_bus(&mut self, stats: BusStats) {
+		self.bus_stats = Some(stats);
+	}
+
+	// Average emulation and render time in milliseconds.
+	pub fn averages(&self) -> (f64, f64) {
+		if self.history.is_empty() {
+			return (0.0, 0.0);
+		}
+		let count = self.history.len() as f64;
+		let emulation: f64 = self.history.iter().map(|times| times.emulation).sum();
+		let render: f64 = self.history.iter().map(|times| times.render).sum();
+		(emulation / count, render / count)
+	}
+
+	pub fn draw(&self, frame: &mut Frame) {
+		let top = SCREEN_HEIGHT - GRAPH_HEIGHT - 2;
+		let bars_top = top - 9 - BUS_REGIONS.len() * (BAR_HEIGHT + 1);
+		// darken the background so the graph is readable on any picture
+		for y in bars_top..SCREEN_HEIGHT - 2 {
+			for x in 2..HISTORY + 4 {
+				let (r, g, b) = frame.pixel(x, y);
+				frame.set_pixel(x, y, r / 4, g / 4, b / 4);
+			}
+		}
+
+		let height = |ms: f64| ((ms * PIXELS_PER_MS) as usize).min(GRAPH_HEIGHT);
+		let bottom = SCREEN_HEIGHT - 3;
+		for (i, times) in self.history.iter().enumerate() {
+			let x = 2 + i;
+			let emulation = height(times.emulation);
+			let total = height(times.emulation + times.render);
+			for y in 0..total {
+				if y < emulation {
+					frame.set_pixel(x, bottom - y, 0x20, 0xC0, 0x20);
+				} else {
+					frame.set_pixel(x, bottom - y, 0x40, 0x60, 0xFF);
+				}
+			}
+		}
+		if let Some(fill) = self.history.back().and_then(|times| times.audio_fill) {
+			let fill = (fill.clamp(0.0, 1.0) * GRAPH_HEIGHT as f64) as usize;
+			for y in 0..fill {
+				frame.set_pixel(HISTORY + 2, bottom - y, 0xFF, 0xE0, 0x20);
+				frame.set_pixel(HISTORY + 3, bottom - y, 0xFF, 0xE0, 0x20);
+			}
+		}
+		let budget = bottom - height(FRAME_BUDGET_MS);
+		for x in 2..HISTORY + 2 {
+			frame.set_pixel(x, budget, 0xFF, 0x30, 0x30);
+		}
+
+		let (emulation, render) = self.averages();
+		let text_y = top - 7;
+		let x = draw_number(frame, 2, text_y, emulation, (0x20, 0xC0, 0x20));
+		draw_number(frame, x + 4, text_y, render, (0x40, 0x60, 0xFF));
+
+		if let Some(ref stats) = self.bus_stats {
+			for (i, &region) in BUS_REGIONS.iter().enumerate() {
+				let y = bars_top + 1 + i * (BAR_HEIGHT + 1);
+				let mut x = 2;
+				for &source in ACCESS_SOURCES.iter() {
+					let width = (stats.count(source, region) / ACCESSES_PER_PIXEL) as usize;
+					let end = (x + width).min(HISTORY + 2);
+					let (r, g, b) = source_color(source);
+					for bar_x in x..end {
+						for bar_y in y..y + BAR_HEIGHT {
+							frame.set_pixel(bar_x, bar_y, r, g, b);
+						}
+					}
+					x = end;
+				}
+			}
+		}
+	}
+}
+
+fn source_color(source: AccessSource) -> (u8, u8, u8) {
+	match source {
+		AccessSource::Fetch => (0xA0, 0xA0, 0xA0),
+		AccessSource::Data => (0x20, 0xC0, 0xC0),
+		AccessSource::Stack => (0xC0, 0x40, 0xC0),
+		AccessSource::Vector => (0xFF, 0x90, 0x20),
+		AccessSource::Dma => (0x60, 0xE0, 0x40),
+	}
+}
+
+fn millis(duration: Duration) -> f64 {
+	duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1e6
+}
+
+// 3x5 pixel digits, one row per byte, the lowest three bits are the pixels.
+const DIGITS: [[u8; 5]; 10] = [
+	[0b111, 0b101, 0b101, 0b101, 0b111],
+	[0b010, 0b110, 0b010, 0b010, 0b111],
+	[0b111, 0b001, 0b111, 0b100, 0b111],
+	[0b111, 0b001, 0b111, 0b001, 0b111],
+	[0b101, 0b101, 0b111, 0b001, 0b001],
+	[0b111, 0b100, 0b111, 0b001, 0b111],
+	[0b111, 0b100, 0b111, 0b101, 0b111],
+	[0b111, 0b001, 0b010, 0b010, 0b010],
+	[0b111, 0b101, 0b111, 0b101, 0b111],
+	[0b111, 0b101, 0b111, 0b001, 0b111],
+];
+const POINT: [u8; 5] = [0b000, 0b000, 0b000, 0b000, 0b010];
+
+// Draws the number with one decimal and returns the x after it.
+fn draw_number(frame: &mut Frame, mut x: usize, y: usize, value: f64, color: (u8, u8, u8)) -> usize {
+	let text = format!("{:.1}", value.min(999.9));
+	for c in text.chars() {
+		let glyph = match c.to_digit(10) {
+			Some(digit) => &DIGITS[digit as usize],
+			None => &POINT,
+		};
+		for (row, bits) in glyph.iter().enumerate() {
+			for column in 0..3 {
+				if bits & (0b100 >> column) != 0 {
+					frame.set_pixel(x + column, y + row, color.0, color.1, color.2);
+				}
+			}
+		}
+		x += 4;
+	}
+	x
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ppu::{Frame, SCREEN_HEIGHT};
+	use cpu::{BusStats, AccessSource};
+	use std::time::Duration;
+
+	#[test]
+	fn perf_hud() {
+		let mut hud = PerfHud::new();
+		for _ in 0..HISTORY + 10 {
+			hud.record(Duration::from_millis(4), Duration::from_millis(1), None);
+		}
+		hud.record(Duration::from_millis(7), Duration::from_millis(0), Some(0.5));
+		assert_eq!(HISTORY, hud.history.len());
+		let (emulation, render) = hud.averages();
+		assert!((emulation - (4.0 * 119.0 + 7.0) / 120.0).abs() < 1e-9);
+		assert!((render - 119.0 / 120.0).abs() < 1e-9);
+
+		let mut frame = Frame::new(0);
+		hud.draw(&mut frame);
+		let bottom = SCREEN_HEIGHT - 3;
+		// 4 ms emulation and 1 ms render at 2 pixels per ms
+		assert_eq!((0x20, 0xC0, 0x20), frame.pixel(2, bottom - 7));
+		assert_eq!((0x40, 0x60, 0xFF), frame.pixel(2, bottom - 8));
+		assert_eq!((0, 0, 0), frame.pixel(2, bottom - 10));
+		// half full audio buffer
+		assert_eq!((0xFF, 0xE0, 0x20), frame.pixel(HISTORY + 2, bottom - 19));
+		assert_eq!((0, 0, 0), frame.pixel(HISTORY + 2, bottom - 20));
+	}
+
+	#[test]
+	fn bus_bars() {
+		let mut hud = PerfHud::new();
+		let mut stats = BusStats::new();
+		for _ in 0..512 {
+			stats.record(AccessSource::Fetch, 0x8000);
+			stats.record(AccessSource::Data, 0x2002);
+		}
+		for _ in 0..256 {
+			stats.record(AccessSource::Data, 0x8000);
+		}
+		hud.record_bus(stats);
+		let mut frame = Frame::new(0);
+		hud.draw(&mut frame);
+		let bars_top = SCREEN_HEIGHT - GRAPH_HEIGHT - 2 - 9 - 4 * (BAR_HEIGHT + 1);
+		// RAM: nothing
+		assert_eq!((0, 0, 0), frame.pixel(2, bars_top + 1));
+		// PPU registers: 2 pixels of data
+		let ppu_y = bars_top + 1 + (BAR_HEIGHT + 1);
+		assert_eq!((0x20, 0xC0, 0xC0), frame.pixel(3, ppu_y));
+		assert_eq!((0, 0, 0), frame.pixel(4, ppu_y));
+		// cartridge: 2 pixels of fetches, then 1 of data
+		let cartridge_y = bars_top + 1 + 3 * (BAR_HEIGHT + 1);
+		assert_eq!((0xA0, 0xA0, 0xA0), frame.pixel(3, cartridge_y + 2));
+		assert_eq!((0x20, 0xC0, 0xC0), frame.pixel(4, cartridge_y));
+		assert_eq!((0, 0, 0), frame.pixel(5, cartridge_y));
+	}
+}
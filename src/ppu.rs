@@ -1,8 +1,202 @@
 use cpu::memory_map;
 use cartridge::Cartridge;
+use std::mem;
+use std::any::Any;
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use savestate;
+use region::Region;
+use logging::{Level, Category};
 
-pub trait PpuOutput {
-	fn set_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8);
+thread_local! {
+	// Set while a cartridge read runs under catch_unwind, see
+	// set_tolerate_mapper_faults.
+	static CATCHING_FAULT: Cell<bool> = const { Cell::new(false) };
+}
+
+// Whether a panic would be caught as a mapper fault, so the panic hook can
+// keep quiet about it.
+pub fn catching_mapper_fault() -> bool {
+	CATCHING_FAULT.with(|catching| catching.get())
+}
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// Receives each line of the picture as soon as it is drawn completely.
+pub trait ScanlineOutput {
+	// pixels is the RGBA row y of the frame currently being drawn.
+	fn scanline(&mut self, y: usize, pixels: &[u8]);
+}
+
+// Number of dots A12 has to be low before a rise is reported, about three
+// CPU cycles.
+const A12_FILTER_DOTS: usize = 9;
+
+// Frames after which a bit of the PPU I/O latch decays to 0 if it was not
+// driven again, about 600 ms.
+const OPEN_BUS_DECAY_FRAMES: u64 = 36;
+
+// A rendered picture, handed from the PPU to the frontend.
+// Pixels are stored row by row with 4 bytes (RGBA) each.
+#[derive(Clone)]
+pub struct Frame {
+	pub number: u64,
+	pub pixels: Vec<u8>,
+}
+
+impl Frame {
+	pub fn new(number: u64) -> Frame {
+		Frame {
+			number,
+			pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+		}
+	}
+
+	pub fn set_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8) {
+		let i = (y * SCREEN_WIDTH + x) * 4;
+		self.pixels[i] = r;
+		self.pixels[i + 1] = g;
+		self.pixels[i + 2] = b;
+		self.pixels[i + 3] = 0xFF;
+	}
+
+	pub fn row(&self, y: usize) -> &[u8] {
+		&self.pixels[y * SCREEN_WIDTH * 4..(y + 1) * SCREEN_WIDTH * 4]
+	}
+
+	pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+		let i = (y * SCREEN_WIDTH + x) * 4;
+		(self.pixels[i], self.pixels[i + 1], self.pixels[i + 2])
+	}
+
+	// Writes the frame as binary PPM (P6) image.
+	pub fn write_ppm(&self, out: &mut dyn Write) -> io::Result<()> {
+		write!(out, "P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT)?;
+		let mut rgb = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+		for pixel in self.pixels.chunks(4) {
+			rgb.extend_from_slice(&pixel[..3]);
+		}
+		out.write_all(&rgb)
+	}
+
+	// Reads a binary PPM image of the screen size, like write_ppm writes.
+	pub fn read_ppm(input: &mut dyn Read, number: u64) -> io::Result<Frame> {
+		let mut data = Vec::new();
+		input.read_to_end(&mut data)?;
+		// magic, width, height and maximum value, separated by whitespace
+		let mut fields = Vec::new();
+		let mut pos = 0;
+		while fields.len() < 4 {
+			while pos < data.len() && data[pos].is_ascii_whitespace() {
+				pos += 1;
+			}
+			let start = pos;
+			while pos < data.len() && !data[pos].is_ascii_whitespace() {
+				pos += 1;
+			}
+			if start == pos {
+				return savestate::invalid_state("The PPM header is incomplete.");
+			}
+			fields.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+		}
+		let size = format!("{} {}", SCREEN_WIDTH, SCREEN_HEIGHT);
+		if fields[0] != "P6" || format!("{} {}", fields[1], fields[2]) != size || fields[3] != "255" {
+			return savestate::invalid_state(&format!("Expected a binary PPM of {} pixels with 8 bit colors.", size));
+		}
+		// a single whitespace character ends the header
+		let rgb = &data[(pos + 1).min(data.len())..];
+		if rgb.len() != SCREEN_WIDTH * SCREEN_HEIGHT * 3 {
+			return savestate::invalid_state("The PPM has not as many pixels as its header says.");
+		}
+		let mut frame = Frame::new(number);
+		for (i, pixel) in rgb.chunks(3).enumerate() {
+			frame.set_pixel(i % SCREEN_WIDTH, i / SCREEN_WIDTH, pixel[0], pixel[1], pixel[2]);
+		}
+		Ok(frame)
+	}
+}
+
+// Size of the palette RAM. It is mirrored through 3F00-3FFF.
+const PALETTE_SIZE: usize = 32;
+
+// The palette RAM with the colors of the 4 background palettes (00-0F) and
+// the 4 sprite palettes (10-1F), 6 bits each. The background color entries
+// of the sprite palettes (3F10/3F14/3F18/3F1C) are the same memory as the
+// ones of the background palettes. Those of background palettes 1-3
+// (3F04/3F08/3F0C) are memory of their own, which is only shown while
+// rendering is off; the picture uses 3F00 instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteRam {
+	entries: [u8; PALETTE_SIZE],
+}
+
+impl PaletteRam {
+	pub fn new() -> PaletteRam {
+		PaletteRam {
+			entries: [0; PALETTE_SIZE],
+		}
+	}
+
+	// The entry at an address in 3F00-3FFF, or at an index 00-1F.
+	pub fn get(&self, addr: u16) -> u8 {
+		self.entries[PaletteRam::index(addr)]
+	}
+
+	pub fn set(&mut self, addr: u16, value: u8) {
+		self.entries[PaletteRam::index(addr)] = value & 0b00111111;
+	}
+
+	// The color at 3F00, which shows where nothing else is drawn.
+	pub fn universal_background(&self) -> u8 {
+		self.entries[0]
+	}
+
+	// The colors of background palettes 0-3 and sprite palettes 4-7 as
+	// rendering uses them, with the universal background as color 0.
+	pub fn colors(&self, palette: usize) -> [u8; 4] {
+		let start = (palette & 7) * 4;
+		[self.universal_background(), self.entries[start + 1], self.entries[start + 2], self.entries[start + 3]]
+	}
+
+	// All 32 entries, with the mirrored ones repeated.
+	pub fn entries(&self) -> [u8; PALETTE_SIZE] {
+		let mut entries = [0; PALETTE_SIZE];
+		for (i, entry) in entries.iter_mut().enumerate() {
+			*entry = self.get(i as u16);
+		}
+		entries
+	}
+
+	fn index(addr: u16) -> usize {
+		let index = addr as usize & (PALETTE_SIZE - 1);
+		if index & 0x13 == 0x10 {
+			index - 0x10
+		} else {
+			index
+		}
+	}
+
+	fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bytes(out, &self.entries)
+	}
+
+	fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		savestate::read_bytes(input, &mut self.entries)?;
+		for entry in self.entries.iter_mut() {
+			*entry &= 0b00111111;
+		}
+		Ok(())
+	}
+}
+
+// The message of a caught panic, which is a &str or a String.
+fn panic_message(err: &Box<dyn Any + Send>) -> &str {
+	match err.downcast_ref::<&str>() {
+		Some(message) => message,
+		None => err.downcast_ref::<String>().map(|message| message.as_ref()).unwrap_or("unknown panic"),
+	}
 }
 
 // http://wiki.nesdev.com/w/index.php/PPU_registers et al.
@@ -30,6 +224,29 @@ pub struct Ppu {
 	sprite_0_hit: bool,
 	sprite_overflow: bool,
 	status_artifact: u8,
+	// frame number when each bit of status_artifact was last driven
+	status_artifact_refreshed: [u64; 8],
+	open_bus: bool,
+	// Accuracy settings for the OAM, see set_oam_corruption and
+	// set_oam_decay.
+	oam_corruption: bool,
+	oam_decay: bool,
+	// Whether panics of cartridge reads are caught, and the faulting reads
+	// of the current frame, see set_tolerate_mapper_faults.
+	tolerate_mapper_faults: bool,
+	mapper_faults: u64,
+	// Speed and accuracy trade-offs, see set_sprite_limit and
+	// set_scanline_renderer.
+	sprite_limit: bool,
+	scanline_renderer: bool,
+
+	// Debug override of the rendering enable bits in PPUMASK.
+	render_override: Option<bool>,
+	// Layers drawn into the frame, see set_layers.
+	show_background: bool,
+	show_sprites: bool,
+	// RGB of the 64 colors, see set_master_palette.
+	master_palette: [u8; 64 * 3],
 
 	// OAMADDR
 	oamaddr: u8,
@@ -42,15 +259,55 @@ pub struct Ppu {
 
 	// Internal RAM
 	oam: [u8; 256],
-	palette: [u8; 256],
+	// dot_count when each row of 8 OAM bytes was last accessed, which
+	// refreshes the DRAM
+	oam_row_refreshed: [u64; 32],
+	palette: PaletteRam,
 	
 	// Render state
+	// dots since power on, for the OAM decay
+	dot_count: u64,
+	region: Region,
+	// 261 for NTSC, 311 for PAL with its longer vblank
+	prerender_line: usize,
 	current_scanline: usize,
 	current_cycle: usize,
 	current_nametable_byte: u8,
 	current_attributetable_byte: u8,
 	current_tilebitmap_low: u8,
 	current_tilebitmap_high: u8,
+	// Background shift registers: the high bytes hold the tile being
+	// output, the low bytes the next one. The attribute registers hold
+	// the two palette bits, expanded to one byte per tile.
+	pattern_shift_low: u16,
+	pattern_shift_high: u16,
+	attribute_shift_low: u16,
+	attribute_shift_high: u16,
+	// Sprites of the next line, found by evaluate_sprites at the end of a
+	// line in OAM order, FF in unused slots. Their patterns are fetched
+	// after that, already flipped horizontally.
+	secondary_oam: [u8; 32],
+	sprite_count: usize,
+	sprite_zero_in_line: bool,
+	sprite_pattern_low: [u8; 8],
+	sprite_pattern_high: [u8; 8],
+	// Sprites after the eighth without the sprite limit, with their OAM
+	// bytes and patterns. Not part of save states.
+	extra_sprites: Vec<([u8; 4], u8, u8)>,
+
+	// NMI raised and not yet taken by the CPU
+	nmi_pending: bool,
+	// PPUSTATUS was read just before vblank starts in this frame
+	suppress_vblank: bool,
+
+	// Address line A12 as seen by the cartridge
+	a12_high: bool,
+	a12_low_dots: usize,
+
+	// Output
+	frame: Frame,
+	finished_frame: Option<Frame>,
+	scanline_output: Option<Box<dyn ScanlineOutput + Send>>,
 }
 
 impl Ppu {
@@ -74,60 +331,442 @@ impl Ppu {
 			sprite_0_hit: false,
 			sprite_overflow: false,
 			status_artifact: 0,
+			status_artifact_refreshed: [0; 8],
+			open_bus: true,
+			oam_corruption: true,
+			oam_decay: true,
+			tolerate_mapper_faults: false,
+			sprite_limit: true,
+			scanline_renderer: false,
+			mapper_faults: 0,
+			render_override: None,
+			show_background: true,
+			show_sprites: true,
+			master_palette: RGB_PALETTE,
 			oamaddr: 0,
 			current_vram_address: 0,
 			temp_vram_address: 0,
 			fine_x_scroll: 0,
 			write_toggle: false,
 			oam: [0; 256],
-			palette: [0; 256],
+			oam_row_refreshed: [0; 32],
+			palette: PaletteRam::new(),
+			dot_count: 0,
+			region: Region::Ntsc,
+			prerender_line: 261,
 			current_scanline: 261,
 			current_cycle: 0,
 			current_nametable_byte: 0,
 			current_attributetable_byte: 0,
 			current_tilebitmap_low: 0,
 			current_tilebitmap_high: 0,
+			pattern_shift_low: 0,
+			pattern_shift_high: 0,
+			attribute_shift_low: 0,
+			attribute_shift_high: 0,
+			secondary_oam: [0xFF; 32],
+			sprite_count: 0,
+			sprite_zero_in_line: false,
+			sprite_pattern_low: [0; 8],
+			extra_sprites: Vec::new(),
+			sprite_pattern_high: [0; 8],
+			nmi_pending: false,
+			suppress_vblank: false,
+			a12_high: false,
+			a12_low_dots: 0,
+			frame: Frame::new(0),
+			finished_frame: None,
+			scanline_output: None,
+		}
+	}
+
+	// Enables the emulation of the I/O latch returned when reading
+	// write-only registers. If disabled, these bits read as 0.
+	pub fn set_open_bus(&mut self, enabled: bool) {
+		self.open_bus = enabled;
+	}
+
+	// Whether background or sprite rendering is enabled, taking the debug
+	// override into account.
+	pub fn rendering_enabled(&self) -> bool {
+		self.render_override.unwrap_or(self.background_enable || self.sprite_enable)
+	}
+
+	// Switches to the timing of the region. Not part of save states.
+	pub fn set_region(&mut self, region: Region) {
+		if self.current_scanline == self.prerender_line {
+			self.current_scanline = region.prerender_line();
 		}
+		self.region = region;
+		self.prerender_line = region.prerender_line();
+	}
+
+	// Enables the OAM corruption of the 2C02G: While rendering, writes to
+	// $2004 only increment the upper 6 bits of OAMADDR and the sprite
+	// fetches reset OAMADDR to 0. If OAMADDR is 8 or more when rendering
+	// starts, the row of 8 bytes it points to is copied over the first row.
+	pub fn set_oam_corruption(&mut self, enabled: bool) {
+		self.oam_corruption = enabled;
+	}
+
+	// Enables the decay of OAM rows which are not accessed for a while,
+	// which happens while rendering is off. Decayed rows read as FF.
+	pub fn set_oam_decay(&mut self, enabled: bool) {
+		self.oam_decay = enabled;
+	}
+
+	// Catches panics of the cartridge when the PPU reads from it, to keep
+	// debugging a new mapper instead of aborting. Faulting pattern reads
+	// give a checkerboard of colors 2 and 3, so the affected tiles stand
+	// out, nametable reads tile 0. The first fault of a frame is logged
+	// with its message, the others are counted. Not part of save states.
+	pub fn set_tolerate_mapper_faults(&mut self, enabled: bool) {
+		self.tolerate_mapper_faults = enabled;
+	}
+
+	// Draws at most 8 sprites per line like the hardware, which makes
+	// sprites flicker in games which cycle their order. Without the limit
+	// the sprites after the eighth are drawn as well, which reveals those
+	// meant to be hidden behind a row of others. Sprite 0 hits and the
+	// overflow flag stay the same. Not part of save states.
+	pub fn set_sprite_limit(&mut self, enabled: bool) {
+		self.sprite_limit = enabled;
+	}
+
+	// Draws each visible line at once at its dot 256 instead of dot by
+	// dot, which is faster. Writes to the registers in the middle of a
+	// line only show from the next line on, sprite 0 hits come at the end
+	// of their line and the background fetches of a line happen at once,
+	// which shifts the IRQs of mappers counting A12 rises. Not part of save
+	// states.
+	pub fn set_scanline_renderer(&mut self, enabled: bool) {
+		self.scanline_renderer = enabled;
+	}
+
+	// Forces rendering on, or off with only the backdrop color drawn,
+	// regardless of PPUMASK. None follows PPUMASK again. Not part of save
+	// states.
+	pub fn set_render_enabled(&mut self, enabled: Option<bool>) {
+		self.render_override = enabled;
+	}
+
+	// Hides the background or the sprites in the frame, e.g. to capture
+	// clean sprite footage. Only the drawing changes, sprite 0 hits still
+	// happen. Not part of save states.
+	pub fn set_layers(&mut self, background: bool, sprites: bool) {
+		self.show_background = background;
+		self.show_sprites = sprites;
+	}
+
+	// Replaces the RGB colors of the 64 palette values, e.g. with a .pal
+	// file. Used from the next pixel on, not part of save states.
+	pub fn set_master_palette(&mut self, palette: [u8; 64 * 3]) {
+		self.master_palette = palette;
+	}
+
+	// The scanline (0-261, 261 is the pre-render line, up to 311 for PAL)
+	// and dot (0-340) which the next tick processes.
+	pub fn scanline(&self) -> usize {
+		self.current_scanline
+	}
+
+	pub fn dot(&self) -> usize {
+		self.current_cycle
 	}
 
-	pub fn read(&mut self, cartridge: &mut Cartridge, addr: u16) -> u8 {
-		debug_assert!(memory_map::PPU_START <= addr && addr < memory_map::APU_IO_START);
-		let result = match addr {
+	// Number of ticks until the given dot has been processed.
+	pub fn dots_until(&self, scanline: usize, dot: usize) -> u64 {
+		let frame_dots = (self.prerender_line + 1) * 341;
+		let now = self.current_scanline * 341 + self.current_cycle;
+		let target = scanline * 341 + dot;
+		let dots = ((target + frame_dots - now) % frame_dots) as u64 + 1;
+		// passing the end of the frame, which may be a dot short
+		if target < now && self.skips_last_dot() { dots - 1 } else { dots }
+	}
+
+	// Number of the frame being drawn.
+	pub fn frame_number(&self) -> u64 {
+		self.frame.number
+	}
+
+	// Whether the frame in progress has an odd number.
+	pub fn odd_frame(&self) -> bool {
+		self.frame.number % 2 == 1
+	}
+
+	// Whether the pre-render line of this frame ends a dot early: the NTSC
+	// PPU skips its last dot in odd frames while rendering, which makes
+	// its frames 29780.5 CPU cycles long on average.
+	fn skips_last_dot(&self) -> bool {
+		self.region.skips_odd_frame_dot() && self.odd_frame() && self.rendering_enabled()
+	}
+
+	// The registers as the CPU sees them and the internal ones, for crash
+	// reports and debuggers.
+	pub fn registers(&self) -> String {
+		let ctrl = (self.nmi_enable as u8) << 7 | (self.ppu_master as u8) << 6 | (self.sprite_height as u8) << 5 |
+			(self.background_tile_select as u8) << 4 | (self.sprite_tile_select as u8) << 3 |
+			(self.increment_mode as u8) << 2 | (self.temp_vram_address >> 10) as u8 & 0x03;
+		let mask = (self.color_emph_b as u8) << 7 | (self.color_emph_g as u8) << 6 | (self.color_emph_r as u8) << 5 |
+			(self.sprite_enable as u8) << 4 | (self.background_enable as u8) << 3 |
+			(self.sprite_left_column_enable as u8) << 2 | (self.background_left_column_enable as u8) << 1 |
+			self.greyscale as u8;
+		let status = (self.vblank as u8) << 7 | (self.sprite_0_hit as u8) << 6 | (self.sprite_overflow as u8) << 5;
+		format!("CTRL:{:02X} MASK:{:02X} STATUS:{:02X} OAMADDR:{:02X} v:{:04X} t:{:04X} x:{} w:{}",
+			ctrl, mask, status, self.oamaddr, self.current_vram_address, self.temp_vram_address,
+			self.fine_x_scroll, self.write_toggle as u8)
+	}
+
+	// Writes the registers, memories and render position for a save state.
+	// The frame in progress is not saved.
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_bool(out, self.nmi_enable)?;
+		savestate::write_bool(out, self.ppu_master)?;
+		savestate::write_bool(out, self.sprite_height)?;
+		savestate::write_bool(out, self.background_tile_select)?;
+		savestate::write_bool(out, self.sprite_tile_select)?;
+		savestate::write_bool(out, self.increment_mode)?;
+		savestate::write_bool(out, self.color_emph_b)?;
+		savestate::write_bool(out, self.color_emph_g)?;
+		savestate::write_bool(out, self.color_emph_r)?;
+		savestate::write_bool(out, self.sprite_enable)?;
+		savestate::write_bool(out, self.background_enable)?;
+		savestate::write_bool(out, self.sprite_left_column_enable)?;
+		savestate::write_bool(out, self.background_left_column_enable)?;
+		savestate::write_bool(out, self.greyscale)?;
+		savestate::write_bool(out, self.vblank)?;
+		savestate::write_bool(out, self.sprite_0_hit)?;
+		savestate::write_bool(out, self.sprite_overflow)?;
+		savestate::write_u8(out, self.status_artifact)?;
+		for &refreshed in self.status_artifact_refreshed.iter() {
+			savestate::write_u64(out, refreshed)?;
+		}
+		savestate::write_u8(out, self.oamaddr)?;
+		savestate::write_u16(out, self.current_vram_address)?;
+		savestate::write_u16(out, self.temp_vram_address)?;
+		savestate::write_u8(out, self.fine_x_scroll)?;
+		savestate::write_bool(out, self.write_toggle)?;
+		savestate::write_bytes(out, &self.oam)?;
+		for &refreshed in self.oam_row_refreshed.iter() {
+			savestate::write_u64(out, refreshed)?;
+		}
+		savestate::write_u64(out, self.dot_count)?;
+		self.palette.save_state(out)?;
+		savestate::write_u16(out, self.current_scanline as u16)?;
+		savestate::write_u16(out, self.current_cycle as u16)?;
+		savestate::write_u8(out, self.current_nametable_byte)?;
+		savestate::write_u8(out, self.current_attributetable_byte)?;
+		savestate::write_u8(out, self.current_tilebitmap_low)?;
+		savestate::write_u8(out, self.current_tilebitmap_high)?;
+		savestate::write_u16(out, self.pattern_shift_low)?;
+		savestate::write_u16(out, self.pattern_shift_high)?;
+		savestate::write_u16(out, self.attribute_shift_low)?;
+		savestate::write_u16(out, self.attribute_shift_high)?;
+		savestate::write_bytes(out, &self.secondary_oam)?;
+		savestate::write_u8(out, self.sprite_count as u8)?;
+		savestate::write_bool(out, self.sprite_zero_in_line)?;
+		savestate::write_bytes(out, &self.sprite_pattern_low)?;
+		savestate::write_bytes(out, &self.sprite_pattern_high)?;
+		savestate::write_bool(out, self.nmi_pending)?;
+		savestate::write_bool(out, self.suppress_vblank)?;
+		savestate::write_bool(out, self.a12_high)?;
+		savestate::write_u64(out, self.a12_low_dots as u64)?;
+		savestate::write_u64(out, self.frame.number)
+	}
+
+	// Restores the state written by save_state and starts a new frame.
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		self.nmi_enable = savestate::read_bool(input)?;
+		self.ppu_master = savestate::read_bool(input)?;
+		self.sprite_height = savestate::read_bool(input)?;
+		self.background_tile_select = savestate::read_bool(input)?;
+		self.sprite_tile_select = savestate::read_bool(input)?;
+		self.increment_mode = savestate::read_bool(input)?;
+		self.color_emph_b = savestate::read_bool(input)?;
+		self.color_emph_g = savestate::read_bool(input)?;
+		self.color_emph_r = savestate::read_bool(input)?;
+		self.sprite_enable = savestate::read_bool(input)?;
+		self.background_enable = savestate::read_bool(input)?;
+		self.sprite_left_column_enable = savestate::read_bool(input)?;
+		self.background_left_column_enable = savestate::read_bool(input)?;
+		self.greyscale = savestate::read_bool(input)?;
+		self.vblank = savestate::read_bool(input)?;
+		self.sprite_0_hit = savestate::read_bool(input)?;
+		self.sprite_overflow = savestate::read_bool(input)?;
+		self.status_artifact = savestate::read_u8(input)?;
+		for refreshed in self.status_artifact_refreshed.iter_mut() {
+			*refreshed = savestate::read_u64(input)?;
+		}
+		self.oamaddr = savestate::read_u8(input)?;
+		self.current_vram_address = savestate::read_u16(input)?;
+		self.temp_vram_address = savestate::read_u16(input)?;
+		self.fine_x_scroll = savestate::read_u8(input)?;
+		self.write_toggle = savestate::read_bool(input)?;
+		savestate::read_bytes(input, &mut self.oam)?;
+		for refreshed in self.oam_row_refreshed.iter_mut() {
+			*refreshed = savestate::read_u64(input)?;
+		}
+		self.dot_count = savestate::read_u64(input)?;
+		self.palette.load_state(input)?;
+		self.current_scanline = savestate::read_u16(input)? as usize;
+		self.current_cycle = savestate::read_u16(input)? as usize;
+		self.current_nametable_byte = savestate::read_u8(input)?;
+		self.current_attributetable_byte = savestate::read_u8(input)?;
+		self.current_tilebitmap_low = savestate::read_u8(input)?;
+		self.current_tilebitmap_high = savestate::read_u8(input)?;
+		self.pattern_shift_low = savestate::read_u16(input)?;
+		self.pattern_shift_high = savestate::read_u16(input)?;
+		self.attribute_shift_low = savestate::read_u16(input)?;
+		self.attribute_shift_high = savestate::read_u16(input)?;
+		savestate::read_bytes(input, &mut self.secondary_oam)?;
+		self.sprite_count = savestate::read_u8(input)?.min(8) as usize;
+		self.sprite_zero_in_line = savestate::read_bool(input)?;
+		savestate::read_bytes(input, &mut self.sprite_pattern_low)?;
+		savestate::read_bytes(input, &mut self.sprite_pattern_high)?;
+		self.nmi_pending = savestate::read_bool(input)?;
+		self.suppress_vblank = savestate::read_bool(input)?;
+		self.a12_high = savestate::read_bool(input)?;
+		self.a12_low_dots = savestate::read_u64(input)? as usize;
+		let number = savestate::read_u64(input)?;
+		self.frame = Frame::new(number);
+		self.finished_frame = None;
+		Ok(())
+	}
+
+	// Reset button: Clears PPUCTRL, PPUMASK, the scroll and the write toggle,
+	// everything else is unaffected.
+	pub fn reset(&mut self) {
+		self.nmi_enable = false;
+		self.ppu_master = false;
+		self.sprite_height = false;
+		self.background_tile_select = false;
+		self.sprite_tile_select = false;
+		self.increment_mode = false;
+		self.color_emph_b = false;
+		self.color_emph_g = false;
+		self.color_emph_r = false;
+		self.sprite_enable = false;
+		self.background_enable = false;
+		self.sprite_left_column_enable = false;
+		self.background_left_column_enable = false;
+		self.greyscale = false;
+		self.temp_vram_address = 0;
+		self.fine_x_scroll = 0;
+		self.write_toggle = false;
+	}
+
+	pub fn read(&mut self, cartridge: &mut dyn Cartridge, addr: u16) -> u8 {
+		debug_assert!((memory_map::PPU_START..memory_map::APU_IO_START).contains(&addr));
+		let artifact = if self.open_bus { self.decayed_status_artifact() } else { 0 };
+		// value and the bits actually driven by the PPU
+		let (result, driven) = match 0x2000 | (addr & 0b111) {
 			0x2002 => {
 				self.write_toggle = false;
-				(
-					(self.status_artifact   & 0b00011111)             |
+				if self.current_scanline == 241 {
+					// Race with the start of vblank at dot 1: Reading one dot
+					// earlier returns the flag clear and it is not set in this
+					// frame. Reading on that dot returns it set, but no NMI
+					// occurs. Reading later takes the normal path.
+					match self.current_cycle {
+						1 => { self.suppress_vblank = true; }
+						2 => { self.nmi_pending = false; }
+						_ => {}
+					}
+				}
+				let result =
+					(artifact               & 0b00011111)             |
 					if self.sprite_overflow { 0b00100000 } else { 0 } |
 					if self.sprite_0_hit    { 0b01000000 } else { 0 } |
-					if self.vblank          { 0b10000000 } else { 0 }
-				)
+					if self.vblank          { 0b10000000 } else { 0 };
+				self.vblank = false;
+				(result, 0b11100000)
 			}
 			0x2004 => {
 				// oam read
-				// TODO other oddities while rendering
-				self.oam[self.oamaddr as usize]
+				let row = self.oamaddr as usize / 8;
+				self.refresh_oam_row(row);
+				(self.oam_data(), 0xFF)
 			}
 			0x2007 => {
 				// ppu read
 				// TODO other oddities while rendering
-				let result = self.read_ppu(cartridge, self.current_vram_address);
-				self.current_vram_address += if self.increment_mode { 32 } else { 1 };
-				self.current_vram_address &= 0x3FFF;
-				result
+				let address = self.current_vram_address & 0x3FFF;
+				let result = self.read_ppu(cartridge, address);
+				self.increment_vram_address();
+				// palette entries have 6 bits, the upper 2 are open bus
+				if address >= 0x3F00 {
+					((result & 0x3F) | (artifact & 0xC0), 0x3F)
+				} else {
+					(result, 0xFF)
+				}
 			}
 			0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 => {
-				self.status_artifact
+				(artifact, 0)
 			}
 			_ => { unreachable!() }
 		};
-		self.status_artifact = result;
+		self.refresh_status_artifact(result, driven);
 		result
 	}
 
-	pub fn write(&mut self, cartridge: &mut Cartridge, addr: u16, value: u8) {
-		debug_assert!(memory_map::PPU_START <= addr && addr < memory_map::APU_IO_START);
-		match addr {
+	// Sets the driven bits of the I/O latch.
+	fn refresh_status_artifact(&mut self, value: u8, driven: u8) {
+		for bit in 0..8 {
+			if driven & (1 << bit) != 0 {
+				self.status_artifact_refreshed[bit] = self.frame.number;
+			}
+		}
+		self.status_artifact = (self.status_artifact & !driven) | (value & driven);
+	}
+
+	// Returns the I/O latch after letting bits decay which were not driven
+	// for a while.
+	fn decayed_status_artifact(&mut self) -> u8 {
+		self.status_artifact = self.peek_status_artifact();
+		self.status_artifact
+	}
+
+	// Like decayed_status_artifact, without storing the decayed value.
+	fn peek_status_artifact(&self) -> u8 {
+		let mut artifact = self.status_artifact;
+		for bit in 0..8 {
+			if self.frame.number - self.status_artifact_refreshed[bit] >= OPEN_BUS_DECAY_FRAMES {
+				artifact &= !(1 << bit);
+			}
+		}
+		artifact
+	}
+
+	// Returns what reading the register would, without any of the side
+	// effects (clearing vblank, incrementing the address, ...). For
+	// debuggers and tracers. Accepts the mirrors in 2008-3FFF too.
+	pub fn peek(&self, cartridge: &mut dyn Cartridge, addr: u16) -> u8 {
+		debug_assert!((memory_map::PPU_START..memory_map::APU_IO_START).contains(&addr));
+		let artifact = if self.open_bus { self.peek_status_artifact() } else { 0 };
+		match 0x2000 | (addr & 0b111) {
+			0x2002 => {
+				(artifact               & 0b00011111)             |
+				if self.sprite_overflow { 0b00100000 } else { 0 } |
+				if self.sprite_0_hit    { 0b01000000 } else { 0 } |
+				if self.vblank          { 0b10000000 } else { 0 }
+			}
+			0x2004 => self.oam[self.oamaddr as usize],
+			0x2007 if self.current_vram_address & 0x3FFF >= 0x3F00 => {
+				(self.peek_vram(cartridge, self.current_vram_address) & 0x3F) | (artifact & 0xC0)
+			}
+			0x2007 => self.peek_vram(cartridge, self.current_vram_address),
+			_ => artifact,
+		}
+	}
+
+	pub fn write(&mut self, cartridge: &mut dyn Cartridge, addr: u16, value: u8) {
+		debug_assert!((memory_map::PPU_START..memory_map::APU_IO_START).contains(&addr));
+		match 0x2000 | (addr & 0b111) {
 			0x2000 => {
+				// enabling NMI during vblank raises it immediately
+				if value & 0b10000000 != 0 && !self.nmi_enable && self.vblank {
+					self.nmi_pending = true;
+				}
 				self.nmi_enable             = value & 0b10000000 != 0;
 				self.ppu_master             = value & 0b01000000 != 0;
 				self.sprite_height          = value & 0b00100000 != 0;
@@ -153,10 +792,16 @@ impl Ppu {
 				self.oamaddr = value;
 			}
 			0x2004 => {
-				// TODO ignore writes during rendering and other oddities
-				// oam write
-				self.oam[self.oamaddr as usize] = value;
-				self.oamaddr = self.oamaddr.wrapping_add(1);
+				if self.oam_corruption && self.is_rendering() {
+					self.oamaddr = self.oamaddr.wrapping_add(4);
+				} else {
+					// oam write, the attribute bytes have no bits 2-4
+					let row = self.oamaddr as usize / 8;
+					self.refresh_oam_row(row);
+					let mask = if self.oamaddr & 3 == 2 { 0xE3 } else { 0xFF };
+					self.oam[self.oamaddr as usize] = value & mask;
+					self.oamaddr = self.oamaddr.wrapping_add(1);
+				}
 			}
 			0x2005 => {
 				if self.write_toggle {
@@ -185,66 +830,272 @@ impl Ppu {
 			}
 			0x2007 => {
 				// ppu write
-				// TODO special behavior if write is during lines 0-239.
-				let write_addr = self.current_vram_address;
+				let write_addr = self.current_vram_address & 0x3FFF;
 				self.write_ppu(cartridge, write_addr, value);
-				self.current_vram_address += if self.increment_mode { 32 } else { 1 };
-				self.current_vram_address &= 0x3FFF;
+				self.increment_vram_address();
 			}
 			_ => { unreachable!(); }
 		}
-		self.status_artifact = value;
+		self.refresh_status_artifact(value, 0xFF);
 	}
 
-	fn read_ppu(&self, cartridge: &mut Cartridge, addr: u16) -> u8 {
-		debug_assert!(addr <= 0x3FFF);
-		if addr <= 0x3EFF {
-			cartridge.read_ppu(addr)
+	// True if the PPU is currently fetching from VRAM, i.e. rendering is
+	// enabled and it is on a visible or the pre-render scanline.
+	fn is_rendering(&self) -> bool {
+		self.rendering_enabled() &&
+			(self.current_scanline <= 239 || self.current_scanline == self.prerender_line)
+	}
+
+	// The byte an OAMDATA read returns. While rendering it is the one the
+	// sprite circuits work on: the secondary OAM is cleared to FF at the
+	// start of visible lines, and its sprites are fetched from dot 257 on,
+	// the X byte four times. The evaluation in between reads at OAMADDR.
+	fn oam_data(&self) -> u8 {
+		if !self.is_rendering() {
+			return self.oam[self.oamaddr as usize];
+		}
+		match self.current_cycle {
+			1..=64 if self.current_scanline <= 239 => 0xFF,
+			257..=320 => {
+				let i = self.current_cycle - 257;
+				self.secondary_oam[i / 8 * 4 + (i % 8).min(3)]
+			}
+			0 | 321..=340 => self.secondary_oam[0],
+			_ => self.oam[self.oamaddr as usize],
+		}
+	}
+
+	// Advances v after a $2007 access.
+	fn increment_vram_address(&mut self) {
+		if self.is_rendering() {
+			// The access collides with the rendering fetches, which results
+			// in both a coarse X and a Y increment instead of the usual one.
+			self.increment_coarse_x();
+			self.increment_y();
+		} else {
+			self.current_vram_address += if self.increment_mode { 32 } else { 1 };
+			self.current_vram_address &= 0x3FFF;
+		}
+	}
+
+	// Increments coarse X of v, switching the horizontal nametable on wrap.
+	// See http://wiki.nesdev.com/w/index.php/PPU_scrolling
+	fn increment_coarse_x(&mut self) {
+		if self.current_vram_address & 0x001F == 31 {
+			self.current_vram_address &= !0x001F;
+			self.current_vram_address ^= 0x0400;
+		} else {
+			self.current_vram_address += 1;
+		}
+	}
+
+	// Increments fine Y of v, overflowing into coarse Y and switching the
+	// vertical nametable after row 29.
+	fn increment_y(&mut self) {
+		if self.current_vram_address & 0x7000 != 0x7000 {
+			self.current_vram_address += 0x1000;
 		} else {
-			match addr {
-				0x3F10 | 0x3F14 | 0x3F18 | 0x3F1C => { self.palette[(addr - 0x3F00 - 0x10) as usize] }
-				_ => { self.palette[(addr - 0x3F00) as usize] }
+			self.current_vram_address &= !0x7000;
+			let mut coarse_y = (self.current_vram_address & 0x03E0) >> 5;
+			if coarse_y == 29 {
+				coarse_y = 0;
+				self.current_vram_address ^= 0x0800;
+			} else if coarse_y == 31 {
+				coarse_y = 0;
+			} else {
+				coarse_y += 1;
 			}
+			self.current_vram_address = (self.current_vram_address & !0x03E0) | (coarse_y << 5);
+		}
+	}
+
+	// Tracks A12 of the PPU address bus and notifies the cartridge about
+	// rising edges. Like the MMC3, edges are filtered out if A12 was not low
+	// for long enough, so only the switch between pattern tables counts.
+	fn set_address_bus(&mut self, cartridge: &mut dyn Cartridge, addr: u16) {
+		let high = addr & 0x1000 != 0;
+		if high && !self.a12_high && self.a12_low_dots >= A12_FILTER_DOTS {
+			cartridge.ppu_a12_rise();
 		}
+		if !high && self.a12_high {
+			self.a12_low_dots = 0;
+		}
+		self.a12_high = high;
 	}
 
-	fn write_ppu(&mut self, cartridge: &mut Cartridge, addr: u16, value: u8) {
+	fn read_ppu(&mut self, cartridge: &mut dyn Cartridge, addr: u16) -> u8 {
 		debug_assert!(addr <= 0x3FFF);
+		self.set_address_bus(cartridge, addr);
 		if addr <= 0x3EFF {
-			cartridge.write_ppu(addr, value);
+			if self.tolerate_mapper_faults { self.read_cartridge_tolerant(cartridge, addr) } else { cartridge.read_ppu(addr) }
 		} else {
-			match addr {
-				0x3F10 | 0x3F14 | 0x3F18 | 0x3F1C => {
-					self.palette[(addr - 0x3F00 - 0x10) as usize] = value & 0b00111111;
+			self.palette.get(addr)
+		}
+	}
+
+	fn read_cartridge_tolerant(&mut self, cartridge: &mut dyn Cartridge, addr: u16) -> u8 {
+		CATCHING_FAULT.with(|catching| catching.set(true));
+		let result = panic::catch_unwind(AssertUnwindSafe(|| cartridge.read_ppu(addr)));
+		CATCHING_FAULT.with(|catching| catching.set(false));
+		match result {
+			Ok(value) => value,
+			Err(err) => {
+				if self.mapper_faults == 0 {
+					log!(Level::Error, Category::Mapper, "PPU read of ${:04X} failed on line {} dot {}: {}",
+						addr, self.current_scanline, self.current_cycle, panic_message(&err));
 				}
-				_ => {
-					self.palette[(addr - 0x3F00) as usize] = value & 0b00111111;
+				self.mapper_faults += 1;
+				if addr < 0x2000 {
+					// low plane 0101.../1010... by row, high plane set
+					if addr & 0x08 != 0 { 0xFF } else if addr & 1 == 0 { 0x55 } else { 0xAA }
+				} else {
+					0
 				}
 			}
 		}
 	}
 
-	pub fn tick(&mut self, cartridge: &mut Cartridge, output: &mut PpuOutput) {
-		if self.current_scanline == 261 {
-			self.tick_prerender_scanline();
+	fn write_ppu(&mut self, cartridge: &mut dyn Cartridge, addr: u16, value: u8) {
+		debug_assert!(addr <= 0x3FFF);
+		self.set_address_bus(cartridge, addr);
+		if addr <= 0x3EFF {
+			cartridge.write_ppu(addr, value);
+		} else {
+			self.palette.set(addr, value);
+		}
+	}
+
+	// The following functions access the PPU memories directly, for tools
+	// like viewers and editors. They have no side effects: The VRAM address,
+	// the write toggle, OAMADDR and the address bus seen by the cartridge
+	// are unaffected. Addresses are mirrored like the PPU would do.
+
+	// Reads from the PPU address space 0000-3FFF.
+	pub fn peek_vram(&self, cartridge: &mut dyn Cartridge, addr: u16) -> u8 {
+		let addr = addr & 0x3FFF;
+		if addr <= 0x3EFF {
+			cartridge.peek_ppu(addr)
+		} else {
+			self.palette.get(addr)
+		}
+	}
+
+	// Writes to the PPU address space 0000-3FFF. Writes to CHR ROM are
+	// ignored by the cartridge.
+	pub fn poke_vram(&mut self, cartridge: &mut dyn Cartridge, addr: u16, value: u8) {
+		let addr = addr & 0x3FFF;
+		if addr <= 0x3EFF {
+			cartridge.write_ppu(addr, value);
+		} else {
+			self.palette.set(addr, value);
+		}
+	}
+
+	pub fn peek_oam(&self, index: u8) -> u8 {
+		self.oam[index as usize]
+	}
+
+	pub fn poke_oam(&mut self, index: u8, value: u8) {
+		self.oam[index as usize] = value;
+	}
+
+	// Palette entries 00-1F, the same as 3F00-3F1F in PPU address space.
+	pub fn peek_palette(&self, index: u8) -> u8 {
+		self.palette.get(index as u16)
+	}
+
+	pub fn poke_palette(&mut self, index: u8, value: u8) {
+		self.palette.set(index as u16, value);
+	}
+
+	pub fn palette_ram(&self) -> &PaletteRam {
+		&self.palette
+	}
+
+	// Panics if the internal state is out of the range the hardware can
+	// represent. Used by the fuzzer after every operation.
+	pub fn check_invariants(&self) {
+		assert!(self.current_vram_address <= 0x7FFF, "v = {:04X}", self.current_vram_address);
+		assert!(self.temp_vram_address <= 0x7FFF, "t = {:04X}", self.temp_vram_address);
+		assert!(self.fine_x_scroll <= 7, "x = {}", self.fine_x_scroll);
+		assert!(self.current_scanline <= self.prerender_line, "scanline {}", self.current_scanline);
+		assert!(self.current_cycle <= 340, "dot {}", self.current_cycle);
+		assert!(self.palette.entries().iter().all(|&color| color <= 0x3F), "palette {:?}", self.palette);
+	}
+
+	// Returns whether an NMI was raised since the last call.
+	pub fn take_nmi(&mut self) -> bool {
+		mem::replace(&mut self.nmi_pending, false)
+	}
+
+	// Sets or removes the receiver of completed lines.
+	pub fn set_scanline_output(&mut self, output: Option<Box<dyn ScanlineOutput + Send>>) {
+		self.scanline_output = output;
+	}
+
+	pub fn take_scanline_output(&mut self) -> Option<Box<dyn ScanlineOutput + Send>> {
+		self.scanline_output.take()
+	}
+
+	// The frame being drawn, up to the pixel before the current dot. The
+	// rest still shows the last frame.
+	pub fn frame_in_progress(&self) -> &Frame {
+		&self.frame
+	}
+
+	// Returns the last completed frame, if there is a new one since the last call.
+	pub fn take_frame(&mut self) -> Option<Frame> {
+		self.finished_frame.take()
+	}
+
+	pub fn tick(&mut self, cartridge: &mut dyn Cartridge) {
+		self.dot_count += 1;
+		if !self.a12_high {
+			self.a12_low_dots += 1;
+		}
+
+		if self.current_scanline == self.prerender_line {
+			self.tick_prerender_scanline(cartridge);
 		} else if self.current_scanline <= 239 {
-			self.tick_visible_scanline(cartridge, output);
+			self.tick_visible_scanline(cartridge);
 		} else if self.current_scanline == 240 {
 			self.tick_postrender_scanline();
-		} else if self.current_scanline <= 260 {
+		} else if self.current_scanline < self.prerender_line {
 			self.tick_vblank_scanline();
 		} else {
 			unreachable!();
 		}
 	}
 
-	fn tick_prerender_scanline(&mut self) {
-		// TODO prefetching... simulated access...
+	fn tick_prerender_scanline(&mut self, cartridge: &mut dyn Cartridge) {
 		if self.current_cycle == 1 {
 			self.vblank = false;
+			self.sprite_0_hit = false;
+			self.sprite_overflow = false;
+			self.suppress_vblank = false;
+			if self.oam_corruption && self.is_rendering() && self.oamaddr >= 8 {
+				let row = self.oamaddr as usize & 0xF8;
+				for i in 0..8 {
+					self.oam[i] = self.oam[row + i];
+				}
+			}
+		}
+		if self.oam_corruption && self.is_rendering() && (257..=320).contains(&self.current_cycle) {
+			self.oamaddr = 0;
+		}
+		// no sprites on the first line
+		if self.current_cycle == 257 {
+			self.sprite_count = 0;
+			self.sprite_zero_in_line = false;
+			self.extra_sprites.clear();
+		}
+		// fetches like a visible line, so the first tiles are ready
+		self.tick_background(cartridge);
+		if self.is_rendering() && (280..=304).contains(&self.current_cycle) {
+			self.copy_vertical_scroll();
 		}
 
-		if self.current_cycle == 340 {
+		if self.current_cycle == 340 || (self.current_cycle == 339 && self.skips_last_dot()) {
 			self.current_scanline = 0;
 			self.current_cycle = 0;
 		} else {
@@ -252,77 +1103,262 @@ impl Ppu {
 		}
 	}
 
-	fn tick_visible_scanline(&mut self, cartridge: &mut Cartridge, output: &mut PpuOutput) {
-		// TODO each cycle one pixel (optimization potential!)
-		if self.current_cycle == 0 {
-			// do nothing
-		} else if self.current_cycle <= 256 {
-			// fetch tiles for this scanline
-			let y = self.current_scanline;
-			let tile_y = y / 8;
-			let in_tile_y = y % 8;
-			let tile_x = (self.current_cycle - 1) / 8;
-			debug_assert!(y < 240 + 1);
-			debug_assert!(tile_y < 30 + 1);
-			debug_assert!(tile_x < 32);
-
-			// TODO mirroring
-			match self.current_cycle % 8 {
-				1 => {
-					// draw
-					if !(y == 0 && tile_x == 0) {
-						if tile_x == 0 { self.draw_8x1(256 - 8       , y - 1, output) }
-						else           { self.draw_8x1(tile_x * 8 - 8, y    , output) };
-					}
+	fn tick_visible_scanline(&mut self, cartridge: &mut dyn Cartridge) {
+		let y = self.current_scanline;
+		let line_at_once = self.scanline_renderer && self.is_rendering();
+		if line_at_once && self.current_cycle == 256 {
+			self.render_line(cartridge, y);
+		}
+		self.tick_background(cartridge);
+		if !line_at_once && (1..=256).contains(&self.current_cycle) {
+			self.draw_pixel(self.current_cycle - 1, y);
+		}
+		if self.current_cycle == 256 {
+			self.finish_scanline(y);
+		}
+
+		if self.is_rendering() && (257..=320).contains(&self.current_cycle) {
+			if self.oam_corruption {
+				self.oamaddr = 0;
+			}
+			// sprites of the next line
+			if self.current_cycle == 257 {
+				self.evaluate_sprites();
+			}
+			let slot = (self.current_cycle - 257) / 8;
+			match (self.current_cycle - 257) % 8 {
+				5 => {
+					let addr = self.sprite_pattern_addr(slot);
+					let pattern = self.read_ppu(cartridge, addr);
+					self.sprite_pattern_low[slot] = self.flip_sprite_pattern(slot, pattern);
 				}
+				7 => {
+					let addr = self.sprite_pattern_addr(slot) + 8;
+					let pattern = self.read_ppu(cartridge, addr);
+					self.sprite_pattern_high[slot] = self.flip_sprite_pattern(slot, pattern);
+				}
+				_ => {}
+			}
+			if self.current_cycle == 320 {
+				self.fetch_extra_sprites(cartridge);
+			}
+		}
+
+		if self.current_cycle == 340 {
+			self.current_scanline += 1;
+			self.current_cycle = 0;
+		} else {
+			self.current_cycle += 1;
+		}
+	}
+
+	// Background fetches and shift registers of the visible and pre-render
+	// lines, see http://wiki.nesdev.com/w/index.php/PPU_rendering
+	//
+	// Dots 1-256 fetch the tiles 2-33 of the line, 321-336 the first two
+	// tiles of the next line. The registers shift once per dot and take the
+	// fetched tile every 8 dots, so the pixel output at dot x + 1 comes from
+	// the high bytes with fine X as offset.
+	fn tick_background(&mut self, cartridge: &mut dyn Cartridge) {
+		if !self.is_rendering() {
+			return;
+		}
+		let dot = self.current_cycle;
+		if self.scanline_renderer {
+			// render_line fetches the tiles of the line
+			match dot {
+				256 => self.increment_y(),
+				257 => self.copy_horizontal_scroll(),
+				_ => {}
+			}
+			return;
+		}
+		if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+			self.pattern_shift_low <<= 1;
+			self.pattern_shift_high <<= 1;
+			self.attribute_shift_low <<= 1;
+			self.attribute_shift_high <<= 1;
+			if dot % 8 == 1 {
+				self.reload_shift_registers();
+			}
+		}
+		if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+			let v = self.current_vram_address;
+			let fine_y = (v >> 12) & 0b111;
+			let base = if self.background_tile_select { 0x1000 } else { 0 };
+			match dot % 8 {
 				2 => {
-					self.current_nametable_byte =
-						self.read_ppu(cartridge, (0x2000 + tile_y * 32 + tile_x) as u16);
+					self.current_nametable_byte = self.read_ppu(cartridge, 0x2000 | (v & 0x0FFF));
 				}
-				3 => {}
 				4 => {
-					self.current_attributetable_byte =
-						self.read_ppu(cartridge, (0x23C0 + (tile_y * 32 + tile_x) / 4) as u16);
+					// one byte per 4x4 tiles, two bits per 2x2 tiles
+					let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+					let shift = ((v >> 4) & 0b100) | (v & 0b10);
+					self.current_attributetable_byte = (self.read_ppu(cartridge, addr) >> shift) & 0b11;
 				}
-				5 => {}
 				6 => {
-					// TODO when to use 0x1000+?
-					self.current_tilebitmap_low =
-						self.read_ppu(cartridge, (self.current_nametable_byte as usize * 16 + in_tile_y) as u16);
+					let addr = base + self.current_nametable_byte as u16 * 16 + fine_y;
+					self.current_tilebitmap_low = self.read_ppu(cartridge, addr);
 				}
-				7 => {}
 				0 => {
-					// TODO when to use 0x1000+?
-					self.current_tilebitmap_high =
-						self.read_ppu(cartridge, (self.current_nametable_byte as usize * 16 + in_tile_y + 8) as u16);
-					// TODO inc hori(v)
+					let addr = base + self.current_nametable_byte as u16 * 16 + fine_y + 8;
+					self.current_tilebitmap_high = self.read_ppu(cartridge, addr);
+					self.increment_coarse_x();
 				}
-				_ => { unreachable!(); }
-			}
-		} else if self.current_cycle == 257 {
-			// final draw cycle
-			self.draw_8x1(256 - 8, 239, output);
-			// TODO hori(v) = hori(t)
-		} else if self.current_cycle <= 320 {
-			// fetch sprites for next scanline
-			// TODO
-		} else if self.current_cycle <= 336 {
-			// fetch two tiles for next scanline
-			// TODO
-		} else if self.current_cycle <= 340 {
-			// unknown fetches
-			// TODO
+				_ => {}
+			}
+		}
+		if dot == 256 {
+			self.increment_y();
+		}
+		if dot == 257 {
+			self.copy_horizontal_scroll();
 		}
+	}
 
-		if self.current_cycle == 340 {
-			self.current_scanline += 1;
-			self.current_cycle = 0;
+	fn sprite_height_pixels(&self) -> usize {
+		if self.sprite_height { 16 } else { 8 }
+	}
+
+	// Copies the first 8 sprites which cover the next line to the secondary
+	// OAM. The hardware sets the overflow flag with a bug which misses or
+	// invents overflows, here it is set for a ninth sprite only.
+	fn evaluate_sprites(&mut self) {
+		// the sprite Y is one less than the first line it covers
+		let line = self.current_scanline;
+		let height = self.sprite_height_pixels();
+		// reading the OAM refreshes it
+		for row in 0..32 {
+			self.refresh_oam_row(row);
+		}
+		self.secondary_oam = [0xFF; 32];
+		self.sprite_count = 0;
+		self.sprite_zero_in_line = false;
+		self.extra_sprites.clear();
+		for sprite in 0..64 {
+			let y = self.oam[sprite * 4] as usize;
+			if line < y || line - y >= height {
+				continue;
+			}
+			if self.sprite_count == 8 {
+				self.sprite_overflow = true;
+				if self.sprite_limit {
+					break;
+				}
+				let mut bytes = [0; 4];
+				bytes.copy_from_slice(&self.oam[sprite * 4..sprite * 4 + 4]);
+				self.extra_sprites.push((bytes, 0, 0));
+				continue;
+			}
+			let slot = self.sprite_count * 4;
+			self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[sprite * 4..sprite * 4 + 4]);
+			self.sprite_zero_in_line |= sprite == 0;
+			self.sprite_count += 1;
+		}
+	}
+
+	// Called on every access of a row of 8 OAM bytes. Rows which were not
+	// accessed for about 3 ms, much longer than vblank, have decayed.
+	fn refresh_oam_row(&mut self, row: usize) {
+		const DECAY_DOTS: u64 = 16000;
+		if self.oam_decay && self.dot_count - self.oam_row_refreshed[row] > DECAY_DOTS {
+			for value in self.oam[row * 8..row * 8 + 8].iter_mut() {
+				*value = 0xFF;
+			}
+		}
+		self.oam_row_refreshed[row] = self.dot_count;
+	}
+
+	// Address of the low pattern byte of the sprite in the slot. Unused
+	// slots fetch tile FF like the hardware, which the mappers counting A12
+	// rises rely on.
+	fn sprite_pattern_addr(&self, slot: usize) -> u16 {
+		self.sprite_row_addr(&self.secondary_oam[slot * 4..slot * 4 + 4], slot < self.sprite_count)
+	}
+
+	// Address of the low pattern byte of the row of a sprite on the next
+	// line, given its OAM bytes, or of row 0 if it is not on the line.
+	fn sprite_row_addr(&self, sprite: &[u8], in_line: bool) -> u16 {
+		let height = self.sprite_height_pixels();
+		let tile = sprite[1] as u16;
+		let attributes = sprite[2];
+		// masked like the hardware if PPUCTRL changed since the evaluation
+		let mut row = if in_line {
+			self.current_scanline.wrapping_sub(sprite[0] as usize) & (height - 1)
 		} else {
-			self.current_cycle += 1;
+			0
+		};
+		if in_line && attributes & 0x80 != 0 {
+			row = height - 1 - row;
+		}
+		if self.sprite_height {
+			// 8x16 sprites choose the table with bit 0 of the tile number
+			let base = (tile & 1) * 0x1000;
+			let tile = (tile & 0xFE) + (row / 8) as u16;
+			base + tile * 16 + (row % 8) as u16
+		} else {
+			let base = if self.sprite_tile_select { 0x1000 } else { 0 };
+			base + tile * 16 + row as u16
+		}
+	}
+
+	// Mirrors the pattern of a horizontally flipped sprite, clears the one
+	// of an unused slot.
+	fn flip_sprite_pattern(&self, slot: usize, pattern: u8) -> u8 {
+		if slot >= self.sprite_count {
+			0
+		} else if self.secondary_oam[slot * 4 + 2] & 0x40 != 0 {
+			pattern.reverse_bits()
+		} else {
+			pattern
 		}
 	}
 
+	// Fetches the patterns of the sprites after the eighth, right after
+	// the others. The hardware has no time left for them.
+	fn fetch_extra_sprites(&mut self, cartridge: &mut dyn Cartridge) {
+		for i in 0..self.extra_sprites.len() {
+			let (sprite, _, _) = self.extra_sprites[i];
+			let addr = self.sprite_row_addr(&sprite, true);
+			let mut low = self.read_ppu(cartridge, addr);
+			let mut high = self.read_ppu(cartridge, addr + 8);
+			if sprite[2] & 0x40 != 0 {
+				low = low.reverse_bits();
+				high = high.reverse_bits();
+			}
+			self.extra_sprites[i] = (sprite, low, high);
+		}
+	}
+
+	fn reload_shift_registers(&mut self) {
+		self.pattern_shift_low = (self.pattern_shift_low & 0xFF00) | self.current_tilebitmap_low as u16;
+		self.pattern_shift_high = (self.pattern_shift_high & 0xFF00) | self.current_tilebitmap_high as u16;
+		let low = if self.current_attributetable_byte & 0b01 != 0 { 0xFF } else { 0 };
+		let high = if self.current_attributetable_byte & 0b10 != 0 { 0xFF } else { 0 };
+		self.attribute_shift_low = (self.attribute_shift_low & 0xFF00) | low;
+		self.attribute_shift_high = (self.attribute_shift_high & 0xFF00) | high;
+	}
+
+	// Coarse X and the horizontal nametable from t to v.
+	fn copy_horizontal_scroll(&mut self) {
+		self.current_vram_address = (self.current_vram_address & !0x041F) | (self.temp_vram_address & 0x041F);
+	}
+
+	// Fine Y, coarse Y and the vertical nametable from t to v.
+	fn copy_vertical_scroll(&mut self) {
+		self.current_vram_address = (self.current_vram_address & !0x7BE0) | (self.temp_vram_address & 0x7BE0);
+	}
+
 	fn tick_postrender_scanline(&mut self) {
+		if self.current_cycle == 0 {
+			// all visible lines are drawn
+			let number = self.frame.number + 1;
+			self.finished_frame = Some(mem::replace(&mut self.frame, Frame::new(number)));
+			if self.mapper_faults > 1 {
+				log!(Level::Error, Category::Mapper, "{} more PPU reads failed in frame {}.", self.mapper_faults - 1, number - 1);
+			}
+			self.mapper_faults = 0;
+		}
 		if self.current_cycle == 340 {
 			self.current_scanline += 1;
 			self.current_cycle = 0;
@@ -332,10 +1368,13 @@ impl Ppu {
 	}
 
 	fn tick_vblank_scanline(&mut self) {
-		if self.current_scanline == 241 && self.current_cycle == 1 {
+		if self.current_scanline == 241 && self.current_cycle == 1 && !self.suppress_vblank {
 			self.vblank = true;
+			if self.nmi_enable {
+				self.nmi_pending = true;
+			}
 		}
-		if self.current_cycle == 260 {
+		if self.current_cycle == 340 {
 			self.current_scanline += 1;
 			self.current_cycle = 0;
 		} else {
@@ -343,53 +1382,175 @@ impl Ppu {
 		}
 	}
 
-	fn draw_8x1(&self, x: usize, y: usize, output: &mut PpuOutput) {
-		// extract attribute table value
-		let attribute_value = 0b11 &
-			if x % 32 < 16 {
-				// left
-				if y % 32 < 16 {
-					// top
-					self.current_attributetable_byte >> 0
-				} else {
-					// bottom
-					self.current_attributetable_byte >> 4
+	fn finish_scanline(&mut self, y: usize) {
+		if let Some(ref mut output) = self.scanline_output {
+			output.scanline(y, self.frame.row(y));
+		}
+	}
+
+	// With rendering off the picture has the color of 3F00, unless the
+	// VRAM address points into the palette. Then it shows that entry, which
+	// some demos use to draw with all colors.
+	fn backdrop_color(&self) -> u8 {
+		if self.current_vram_address & 0x3F00 == 0x3F00 {
+			self.palette.get(self.current_vram_address)
+		} else {
+			self.palette.universal_background()
+		}
+	}
+
+	// Whether the background shows at x. PPUMASK can hide it in the left
+	// 8 pixels, e.g. to cover scroll seams.
+	fn background_visible(&self, x: usize) -> bool {
+		self.render_override.unwrap_or(self.background_enable) &&
+			(x >= 8 || self.background_left_column_enable)
+	}
+
+	fn sprites_visible(&self, x: usize) -> bool {
+		self.render_override.unwrap_or(self.sprite_enable) &&
+			(x >= 8 || self.sprite_left_column_enable)
+	}
+
+	// The first opaque sprite pixel at x as palette index, whether it is
+	// behind the background and whether it belongs to sprite 0. A sprite
+	// with a lower OAM index wins even if it is behind the background, so
+	// it hides the sprites after it where the background is opaque.
+	fn sprite_pixel(&self, x: usize) -> Option<(u8, bool, bool)> {
+		for (sprite, low, high, zero) in self.line_sprites() {
+			let offset = x.wrapping_sub(sprite[3] as usize);
+			if offset >= 8 {
+				continue;
+			}
+			if let Some((color_index, behind)) = sprite_color(sprite[2], low, high, offset) {
+				return Some((color_index, behind, zero));
+			}
+		}
+		None
+	}
+
+	// The sprites of the line in OAM order, each with its OAM bytes, its
+	// pattern bytes and whether it is sprite 0.
+	fn line_sprites<'a>(&'a self) -> impl Iterator<Item = (&'a [u8], u8, u8, bool)> + 'a {
+		let slots = (0..self.sprite_count).map(move |slot| (&self.secondary_oam[slot * 4..slot * 4 + 4],
+			self.sprite_pattern_low[slot], self.sprite_pattern_high[slot], slot == 0 && self.sprite_zero_in_line));
+		slots.chain(self.extra_sprites.iter().map(|&(ref sprite, low, high)| (&sprite[..], low, high, false)))
+	}
+
+	// Draws a visible line from the nametables and the sprites of the line
+	// at once, see set_scanline_renderer. v points to the first tile, as
+	// the tiles of the line are not fetched ahead.
+	fn render_line(&mut self, cartridge: &mut dyn Cartridge, y: usize) {
+		// the palette indexes of the 33 tiles covering the line
+		let mut background = [0u8; SCREEN_WIDTH + 8];
+		let base = if self.background_tile_select { 0x1000 } else { 0 };
+		for tile in 0..33 {
+			let v = self.current_vram_address;
+			let fine_y = (v >> 12) & 0b111;
+			let name = self.read_ppu(cartridge, 0x2000 | (v & 0x0FFF)) as u16;
+			let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+			let shift = ((v >> 4) & 0b100) | (v & 0b10);
+			let attribute = (self.read_ppu(cartridge, addr) >> shift) & 0b11;
+			let low = self.read_ppu(cartridge, base + name * 16 + fine_y);
+			let high = self.read_ppu(cartridge, base + name * 16 + fine_y + 8);
+			for bit in 0..8 {
+				background[tile * 8 + bit] = attribute << 2 | ((high >> (7 - bit)) & 1) << 1 | ((low >> (7 - bit)) & 1);
+			}
+			self.increment_coarse_x();
+		}
+
+		// the sprite pixels, those of later sprites first as the earlier win
+		let mut sprites = [None; SCREEN_WIDTH];
+		let line_sprites: Vec<_> = self.line_sprites().collect();
+		for &(sprite, low, high, zero) in line_sprites.iter().rev() {
+			for offset in 0..8 {
+				let x = sprite[3] as usize + offset;
+				if x >= SCREEN_WIDTH {
+					break;
 				}
-			} else {
-				// right
-				if y % 32 < 16 {
-					// top
-					self.current_attributetable_byte >> 2
-				} else {
-					// bottom
-					self.current_attributetable_byte >> 6
+				if let Some((color_index, behind)) = sprite_color(sprite[2], low, high, offset) {
+					sprites[x] = Some((color_index, behind, zero));
 				}
-			};
-
-		for i in 0..8 {
-			let color_index =
-				(((self.current_tilebitmap_high & (1 << (7 - i))) >> (7 - i)) << 1) |
-				((self.current_tilebitmap_low & (1 << (7 - i))) >> (7 - i)) |
-				(attribute_value << 2);
-			let color =
-				if color_index & 0b11 == 0 {
-					self.palette[0]
-				} else {
-					self.palette[color_index as usize]
-				};
-			let (r, g, b) = (
-				RGB_PALETTE[color as usize * 3],
-				RGB_PALETTE[color as usize * 3 + 1],
-				RGB_PALETTE[color as usize * 3 + 2]);
+			}
+		}
+
+		let fine_x = self.fine_x_scroll as usize;
+		for x in 0..SCREEN_WIDTH {
+			let sprite = if self.sprites_visible(x) { sprites[x] } else { None };
+			self.put_pixel(x, y, background[x + fine_x], sprite);
+		}
+	}
 
-			output.set_pixel(x + i, y, r, g, b);
+	// Outputs one pixel from the shift registers with the current PPUMASK.
+	fn draw_pixel(&mut self, x: usize, y: usize) {
+		let bit = 15 - self.fine_x_scroll;
+		let color_index =
+			((((self.pattern_shift_high >> bit) & 1) << 1) |
+			((self.pattern_shift_low >> bit) & 1) |
+			(((self.attribute_shift_high >> bit) & 1) << 3) |
+			(((self.attribute_shift_low >> bit) & 1) << 2)) as u8;
+		let sprite = if self.sprites_visible(x) { self.sprite_pixel(x) } else { None };
+		self.put_pixel(x, y, color_index, sprite);
+	}
+
+	// Outputs one pixel from the palette index of the background and the
+	// sprite pixel there, see sprite_pixel.
+	fn put_pixel(&mut self, x: usize, y: usize, color_index: u8, sprite: Option<(u8, bool, bool)>) {
+		let background_opaque = color_index & 0b11 != 0 && self.background_visible(x);
+		// not at x 255, where the hardware never reports a hit
+		if let Some((_, _, true)) = sprite {
+			if self.rendering_enabled() && background_opaque && x != 255 {
+				self.sprite_0_hit = true;
+			}
+		}
+		// a hidden background is drawn like a transparent one
+		let background_shown = background_opaque && self.show_background;
+		let sprite_shown = if self.show_sprites { sprite } else { None };
+		let mut color = match sprite_shown {
+			_ if !self.rendering_enabled() => self.backdrop_color(),
+			Some((sprite_index, behind, _)) if !(behind && background_shown) => self.palette.get(sprite_index as u16),
+			_ if background_shown => self.palette.get(color_index as u16),
+			_ => self.palette.universal_background(),
+		};
+		if self.greyscale {
+			color &= 0x30;
 		}
+		let (r, g, b) = self.emphasize(color);
+		self.frame.set_pixel(x, y, r, g, b);
 	}
+
+	// RGB of a palette color with the color emphasis of PPUMASK. Each
+	// emphasis bit darkens the other two channels to about 82%.
+	fn emphasize(&self, color: u8) -> (u8, u8, u8) {
+		let mut rgb = [
+			self.master_palette[color as usize * 3],
+			self.master_palette[color as usize * 3 + 1],
+			self.master_palette[color as usize * 3 + 2]];
+		let emphasis = [self.color_emph_r, self.color_emph_g, self.color_emph_b];
+		for (channel, value) in rgb.iter_mut().enumerate() {
+			let darkened = (0..3).any(|other| other != channel && emphasis[other]);
+			if darkened {
+				*value = (*value as u16 * 209 / 256) as u8;
+			}
+		}
+		(rgb[0], rgb[1], rgb[2])
+	}
+}
+
+// The palette index of a sprite pixel at an offset from its left edge and
+// whether it is behind the background, None if it is transparent. The
+// patterns are flipped already.
+fn sprite_color(attributes: u8, low: u8, high: u8, offset: usize) -> Option<(u8, bool)> {
+	let bit = 7 - offset;
+	let pixel = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+	if pixel == 0 {
+		return None;
+	}
+	Some((0x10 | ((attributes & 0b11) << 2) | pixel, attributes & 0x20 != 0))
 }
 
 // TODO real color?
 // Generated with http://bisqwit.iki.fi/utils/nespalette.php
-const RGB_PALETTE: [u8; 64 * 3] = [
+pub const RGB_PALETTE: [u8; 64 * 3] = [
 	0x52, 0x52, 0x52, 0x01, 0x1a, 0x51, 0x0f, 0x0f, 0x65, 0x23, 0x06, 0x63, 0x36, 0x03, 0x4b, 0x40,
 	0x04, 0x26, 0x3f, 0x09, 0x04, 0x32, 0x13, 0x00, 0x1f, 0x20, 0x00, 0x0b, 0x2a, 0x00, 0x00, 0x2f,
 	0x00, 0x00, 0x2e, 0x0a, 0x00, 0x26, 0x2d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
@@ -403,3 +1564,852 @@ const RGB_PALETTE: [u8; 64 * 3] = [
 	0xc1, 0xdf, 0xf1, 0xc7, 0xc2, 0xe8, 0xd0, 0xaa, 0xd9, 0xda, 0x9d, 0xc9, 0xe2, 0x9e, 0xbc, 0xe6,
 	0xae, 0xb4, 0xe5, 0xc7, 0xb5, 0xdf, 0xe4, 0xa9, 0xa9, 0xa9, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
 ];
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::MirrorMode;
+	use cartridge::nrom::NRom;
+	use cartridge::test_cartridge::TestCartridge;
+	use std::sync::{Arc, Mutex};
+
+	fn cartridge() -> NRom {
+		TestCartridge::builder().build()
+	}
+
+	#[test]
+	fn frame_handoff() {
+		fn assert_send<T: Send>() {}
+		assert_send::<Frame>();
+
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		assert!(ppu.take_frame().is_none());
+		for _ in 0..(341 * 241 + 1) {
+			ppu.tick(&mut cartridge);
+		}
+		let frame = ppu.take_frame().unwrap();
+		assert_eq!(0, frame.number);
+		assert_eq!((0x52, 0x52, 0x52), frame.pixel(0, 0));
+		assert!(ppu.take_frame().is_none());
+	}
+
+	#[test]
+	fn ppm() {
+		let mut frame = Frame::new(0);
+		frame.set_pixel(0, 0, 1, 2, 3);
+		frame.set_pixel(SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1, 4, 5, 6);
+		let mut ppm = Vec::new();
+		frame.write_ppm(&mut ppm).unwrap();
+		let header = b"P6\n256 240\n255\n";
+		assert_eq!(header.len() + SCREEN_WIDTH * SCREEN_HEIGHT * 3, ppm.len());
+		assert_eq!(&header[..], &ppm[..header.len()]);
+		assert_eq!(&[1, 2, 3, 0], &ppm[header.len()..header.len() + 4]);
+		assert_eq!(&[4, 5, 6], &ppm[ppm.len() - 3..]);
+
+		let read = Frame::read_ppm(&mut &ppm[..], 7).unwrap();
+		assert_eq!(7, read.number);
+		assert_eq!((1, 2, 3), read.pixel(0, 0));
+		assert_eq!((0, 0, 0), read.pixel(1, 0));
+		assert_eq!((4, 5, 6), read.pixel(SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1));
+		assert!(Frame::read_ppm(&mut &ppm[..ppm.len() - 1], 7).is_err());
+		assert!(Frame::read_ppm(&mut &b"P6\n128 120\n255\n"[..], 7).is_err());
+	}
+
+	struct ScanlineRecorder {
+		lines: Arc<Mutex<Vec<(usize, u8)>>>,
+	}
+
+	impl ScanlineOutput for ScanlineRecorder {
+		fn scanline(&mut self, y: usize, pixels: &[u8]) {
+			assert_eq!(SCREEN_WIDTH * 4, pixels.len());
+			self.lines.lock().unwrap().push((y, pixels[0]));
+		}
+	}
+
+	#[test]
+	fn scanline_output() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		let lines = Arc::new(Mutex::new(Vec::new()));
+		ppu.set_scanline_output(Some(Box::new(ScanlineRecorder { lines: lines.clone() })));
+		while ppu.take_frame().is_none() {
+			ppu.tick(&mut cartridge);
+		}
+		let lines = lines.lock().unwrap();
+		assert_eq!(SCREEN_HEIGHT, lines.len());
+		for (i, &(y, r)) in lines.iter().enumerate() {
+			assert_eq!(i, y);
+			assert_eq!(0x52, r);
+		}
+	}
+
+	#[derive(Debug, Clone)]
+	struct A12Counter {
+		nrom: NRom,
+		rises: usize,
+	}
+
+	impl Cartridge for A12Counter {
+		fn name(&self) -> &'static str { "A12Counter" }
+		fn capabilities(&self) -> u8 { self.nrom.capabilities() }
+		fn read_cpu(&mut self, addr: u16) -> u8 { self.nrom.read_cpu(addr) }
+		fn write_cpu(&mut self, addr: u16, value: u8) { self.nrom.write_cpu(addr, value) }
+		fn read_ppu(&mut self, addr: u16) -> u8 { self.nrom.read_ppu(addr) }
+		fn write_ppu(&mut self, addr: u16, value: u8) { self.nrom.write_ppu(addr, value) }
+		fn mirror_mode(&self) -> MirrorMode { self.nrom.mirror_mode() }
+		fn ppu_a12_rise(&mut self) { self.rises += 1; }
+		fn save_state(&self, out: &mut dyn Write) -> io::Result<()> { self.nrom.save_state(out) }
+		fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> { self.nrom.load_state(input) }
+	}
+
+	#[test]
+	fn a12_rises() {
+		let mut cartridge = A12Counter { nrom: cartridge(), rises: 0 };
+		let mut ppu = Ppu::new();
+
+		// background and sprites from the same table
+		ppu.write(&mut cartridge, 0x2001, 0b11000);
+		while ppu.take_frame().is_none() {
+			ppu.tick(&mut cartridge);
+		}
+		assert_eq!(0, cartridge.rises);
+
+		// sprites from the right table, once per visible line
+		ppu.write(&mut cartridge, 0x2000, 0b1000);
+		while ppu.take_frame().is_none() {
+			ppu.tick(&mut cartridge);
+		}
+		assert_eq!(240, cartridge.rises);
+	}
+
+	// Counts the reads through read_ppu, which peeks must not use.
+	#[derive(Debug, Clone)]
+	struct ReadCounter {
+		nrom: NRom,
+		reads: usize,
+	}
+
+	impl Cartridge for ReadCounter {
+		fn name(&self) -> &'static str { "ReadCounter" }
+		fn capabilities(&self) -> u8 { self.nrom.capabilities() }
+		fn read_cpu(&mut self, addr: u16) -> u8 { self.nrom.read_cpu(addr) }
+		fn write_cpu(&mut self, addr: u16, value: u8) { self.nrom.write_cpu(addr, value) }
+		fn read_ppu(&mut self, addr: u16) -> u8 {
+			self.reads += 1;
+			self.nrom.read_ppu(addr)
+		}
+		fn peek_ppu(&mut self, addr: u16) -> u8 { self.nrom.peek_ppu(addr) }
+		fn write_ppu(&mut self, addr: u16, value: u8) { self.nrom.write_ppu(addr, value) }
+		fn mirror_mode(&self) -> MirrorMode { self.nrom.mirror_mode() }
+		fn save_state(&self, out: &mut dyn Write) -> io::Result<()> { self.nrom.save_state(out) }
+		fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> { self.nrom.load_state(input) }
+	}
+
+	#[test]
+	fn peeks_use_peek_ppu() {
+		let mut cartridge = ReadCounter { nrom: cartridge(), reads: 0 };
+		let mut ppu = Ppu::new();
+		ppu.poke_vram(&mut cartridge, 0x2005, 0x42);
+		ppu.write(&mut cartridge, 0x2006, 0x20);
+		ppu.write(&mut cartridge, 0x2006, 0x05);
+		assert_eq!(0x42, ppu.peek_vram(&mut cartridge, 0x2005));
+		assert_eq!(0x42, ppu.peek(&mut cartridge, 0x2007));
+		assert_eq!(0, cartridge.reads);
+		ppu.read(&mut cartridge, 0x2007);
+		assert_eq!(1, cartridge.reads);
+	}
+
+	// Panics on reads of tile 1, like a mapper with a bad bank.
+	#[derive(Debug, Clone)]
+	struct FaultyChr {
+		nrom: NRom,
+	}
+
+	impl Cartridge for FaultyChr {
+		fn name(&self) -> &'static str { "FaultyChr" }
+		fn capabilities(&self) -> u8 { self.nrom.capabilities() }
+		fn read_cpu(&mut self, addr: u16) -> u8 { self.nrom.read_cpu(addr) }
+		fn write_cpu(&mut self, addr: u16, value: u8) { self.nrom.write_cpu(addr, value) }
+		fn read_ppu(&mut self, addr: u16) -> u8 {
+			assert!(addr & 0xFFF0 != 0x0010, "bad CHR bank");
+			self.nrom.read_ppu(addr)
+		}
+		fn write_ppu(&mut self, addr: u16, value: u8) { self.nrom.write_ppu(addr, value) }
+		fn mirror_mode(&self) -> MirrorMode { self.nrom.mirror_mode() }
+		fn save_state(&self, out: &mut dyn Write) -> io::Result<()> { self.nrom.save_state(out) }
+		fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> { self.nrom.load_state(input) }
+	}
+
+	#[test]
+	fn mapper_faults() {
+		let mut cartridge = FaultyChr { nrom: cartridge() };
+		let mut ppu = Ppu::new();
+		// tile 1 in the top left corner, tile 0 everywhere else
+		ppu.poke_vram(&mut cartridge, 0x2000, 1);
+		for color in 0..4 {
+			ppu.poke_palette(color, 0x0F + color);
+		}
+		ppu.write(&mut cartridge, 0x2001, 0b1010);
+		ppu.set_tolerate_mapper_faults(true);
+		next_frame(&mut ppu, &mut cartridge);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		// a checkerboard of colors 2 and 3
+		assert_eq!(rgb(0x11), frame.pixel(0, 0));
+		assert_eq!(rgb(0x12), frame.pixel(1, 0));
+		assert_eq!(rgb(0x12), frame.pixel(0, 1));
+		assert_eq!(rgb(0x0F), frame.pixel(8, 0));
+
+		ppu.set_tolerate_mapper_faults(false);
+		let result = panic::catch_unwind(AssertUnwindSafe(|| next_frame(&mut ppu, &mut cartridge)));
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn vram_increment() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.current_scanline = 241;
+		ppu.write(&mut cartridge, 0x2006, 0x20);
+		ppu.write(&mut cartridge, 0x2006, 0x1F);
+		ppu.write(&mut cartridge, 0x2007, 1);
+		assert_eq!(0x2020, ppu.current_vram_address);
+		ppu.write(&mut cartridge, 0x2000, 0b100);
+		ppu.read(&mut cartridge, 0x2007);
+		assert_eq!(0x2040, ppu.current_vram_address);
+	}
+
+	#[test]
+	fn vram_increment_while_rendering() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.write(&mut cartridge, 0x2006, 0x20);
+		ppu.write(&mut cartridge, 0x2006, 0x1F);
+		ppu.write(&mut cartridge, 0x2001, 0b1000);
+		ppu.current_scanline = 100;
+
+		// coarse X wraps into the next nametable, fine Y increments
+		ppu.write(&mut cartridge, 0x2007, 1);
+		assert_eq!(0x3400, ppu.current_vram_address);
+
+		// fine Y overflows into coarse Y
+		ppu.current_vram_address = 0x7000;
+		ppu.read(&mut cartridge, 0x2007);
+		assert_eq!(0x0021, ppu.current_vram_address);
+
+		// coarse Y 29 wraps to the next nametable
+		ppu.current_vram_address = 0x7000 | (29 << 5);
+		ppu.read(&mut cartridge, 0x2007);
+		assert_eq!(0x0801, ppu.current_vram_address);
+	}
+
+	#[test]
+	fn peek_poke() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.write(&mut cartridge, 0x2006, 0x21);
+		ppu.write(&mut cartridge, 0x2006, 0x00);
+		ppu.write(&mut cartridge, 0x2003, 0x10);
+
+		ppu.poke_vram(&mut cartridge, 0x2005, 7);
+		assert_eq!(7, ppu.peek_vram(&mut cartridge, 0x2005));
+		assert_eq!(7, ppu.peek_vram(&mut cartridge, 0x6005));
+		ppu.poke_vram(&mut cartridge, 0x3F11, 0xFF);
+		assert_eq!(0x3F, ppu.peek_vram(&mut cartridge, 0x3F11));
+		assert_eq!(0x3F, ppu.peek_palette(0x11));
+		ppu.poke_palette(0x10, 5);
+		assert_eq!(5, ppu.peek_vram(&mut cartridge, 0x3F00));
+		ppu.poke_oam(0x20, 9);
+		assert_eq!(9, ppu.peek_oam(0x20));
+
+		// nothing changed the registers
+		assert_eq!(0x2100, ppu.current_vram_address);
+		assert!(!ppu.write_toggle);
+		assert_eq!(0x10, ppu.oamaddr);
+		assert!(!ppu.a12_high);
+	}
+
+	// Ticks until the given dot is the next one.
+	fn run_to(ppu: &mut Ppu, cartridge: &mut dyn Cartridge, scanline: usize, cycle: usize) {
+		while ppu.current_scanline != scanline || ppu.current_cycle != cycle {
+			ppu.tick(cartridge);
+		}
+	}
+
+	#[test]
+	fn frame_length() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		while ppu.take_frame().is_none() {
+			ppu.tick(&mut cartridge);
+		}
+		let mut dots = 0;
+		while ppu.take_frame().is_none() {
+			ppu.tick(&mut cartridge);
+			dots += 1;
+		}
+		assert_eq!(341 * 262, dots);
+
+		// with rendering on, every other frame is a dot short
+		ppu.write(&mut cartridge, 0x2001, 0b1000);
+		let (scanline, dot) = (ppu.scanline(), ppu.dot() - 1);
+		let lengths: Vec<u64> = (0..4).map(|_| {
+			let predicted = ppu.dots_until(scanline, dot);
+			let mut dots = 0;
+			while ppu.take_frame().is_none() {
+				ppu.tick(&mut cartridge);
+				dots += 1;
+			}
+			assert_eq!(predicted, dots);
+			dots
+		}).collect();
+		assert_eq!(2 * 341 * 262 - 1, lengths[0] + lengths[1]);
+		assert_eq!(lengths[0..2], lengths[2..4]);
+	}
+
+	#[test]
+	fn vblank_nmi() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.write(&mut cartridge, 0x2000, 0x80);
+		run_to(&mut ppu, &mut cartridge, 241, 1);
+		assert!(!ppu.take_nmi());
+		ppu.tick(&mut cartridge);
+		assert!(ppu.take_nmi());
+		assert!(!ppu.take_nmi());
+
+		// flag cleared by reading
+		assert_eq!(0x80, ppu.read(&mut cartridge, 0x2002) & 0x80);
+		assert_eq!(0, ppu.read(&mut cartridge, 0x2002) & 0x80);
+
+		// cleared at the end of vblank
+		run_to(&mut ppu, &mut cartridge, 241, 2);
+		ppu.write(&mut cartridge, 0x2000, 0x00);
+		run_to(&mut ppu, &mut cartridge, 261, 2);
+		assert_eq!(0, ppu.read(&mut cartridge, 0x2002) & 0x80);
+
+		// enabling NMI during vblank raises it
+		run_to(&mut ppu, &mut cartridge, 250, 0);
+		assert!(!ppu.take_nmi());
+		ppu.write(&mut cartridge, 0x2000, 0x80);
+		assert!(ppu.take_nmi());
+	}
+
+	#[test]
+	fn status_race() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.write(&mut cartridge, 0x2000, 0x80);
+
+		// one dot before: flag not set in this frame, no NMI
+		run_to(&mut ppu, &mut cartridge, 241, 1);
+		assert_eq!(0, ppu.read(&mut cartridge, 0x2002) & 0x80);
+		run_to(&mut ppu, &mut cartridge, 250, 0);
+		assert_eq!(0, ppu.read(&mut cartridge, 0x2002) & 0x80);
+		assert!(!ppu.take_nmi());
+
+		// on the dot: flag set, no NMI
+		run_to(&mut ppu, &mut cartridge, 241, 2);
+		assert_eq!(0x80, ppu.read(&mut cartridge, 0x2002) & 0x80);
+		assert!(!ppu.take_nmi());
+
+		// one dot later: flag set, NMI
+		run_to(&mut ppu, &mut cartridge, 250, 0);
+		run_to(&mut ppu, &mut cartridge, 241, 3);
+		assert_eq!(0x80, ppu.read(&mut cartridge, 0x2002) & 0x80);
+		assert!(ppu.take_nmi());
+	}
+
+	fn run_frames(ppu: &mut Ppu, cartridge: &mut dyn Cartridge, frames: usize) {
+		for _ in 0..frames {
+			while ppu.take_frame().is_none() {
+				ppu.tick(cartridge);
+			}
+		}
+	}
+
+	#[test]
+	fn open_bus_decay() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.write(&mut cartridge, 0x2003, 0xFF);
+		run_frames(&mut ppu, &mut cartridge, 35);
+		assert_eq!(0xFF, ppu.read(&mut cartridge, 0x2000));
+
+		// PPUSTATUS refreshes only the upper three bits, here in vblank
+		run_to(&mut ppu, &mut cartridge, 241, 10);
+		assert_eq!(0x9F, ppu.read(&mut cartridge, 0x2002));
+		run_frames(&mut ppu, &mut cartridge, 1);
+		assert_eq!(0x80, ppu.read(&mut cartridge, 0x2000));
+		run_frames(&mut ppu, &mut cartridge, 36);
+		assert_eq!(0x00, ppu.read(&mut cartridge, 0x2000));
+
+		ppu.set_open_bus(false);
+		ppu.write(&mut cartridge, 0x2003, 0xFF);
+		assert_eq!(0x00, ppu.read(&mut cartridge, 0x2000));
+	}
+
+	#[test]
+	fn palette_read_open_bus() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.write(&mut cartridge, 0x2006, 0x3F);
+		ppu.write(&mut cartridge, 0x2006, 0x01);
+		ppu.write(&mut cartridge, 0x2007, 0x2A);
+		ppu.write(&mut cartridge, 0x2007, 0x15);
+		ppu.write(&mut cartridge, 0x2006, 0x3F);
+		ppu.write(&mut cartridge, 0x2006, 0x01);
+		// the upper 2 bits come from the latch, here a write to OAMADDR
+		ppu.write(&mut cartridge, 0x2003, 0x40);
+		assert_eq!(0x40 | 0x2A, ppu.peek(&mut cartridge, 0x2007));
+		assert_eq!(0x40 | 0x2A, ppu.read(&mut cartridge, 0x2007));
+		// the lower 6 bits are driven into the latch
+		assert_eq!(0x40 | 0x2A, ppu.read(&mut cartridge, 0x2000));
+		ppu.write(&mut cartridge, 0x2003, 0xFF);
+		assert_eq!(0xC0 | 0x15, ppu.read(&mut cartridge, 0x2007));
+
+		ppu.set_open_bus(false);
+		ppu.write(&mut cartridge, 0x2006, 0x3F);
+		ppu.write(&mut cartridge, 0x2006, 0x01);
+		assert_eq!(0x2A, ppu.read(&mut cartridge, 0x2007));
+	}
+
+	#[test]
+	fn palette_mirroring() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		fn write(ppu: &mut Ppu, cartridge: &mut dyn Cartridge, addr: u16, value: u8) {
+			ppu.write(cartridge, 0x2006, (addr >> 8) as u8);
+			ppu.write(cartridge, 0x2006, addr as u8);
+			ppu.write(cartridge, 0x2007, value);
+		}
+		for i in 0..0x20 {
+			write(&mut ppu, &mut cartridge, 0x3F00 + i, i as u8 | 0xC0);
+		}
+		for i in 0..0x20 {
+			// the last write to the shared background colors wins
+			let expected = if i & 0x13 == 0x00 { i | 0x10 } else { i };
+			assert_eq!(expected as u8, ppu.peek_palette(i as u8));
+			assert_eq!(expected as u8, ppu.peek_vram(&mut cartridge, 0x3FE0 + i));
+		}
+
+		write(&mut ppu, &mut cartridge, 0x3F44, 0x21);
+		assert_eq!(0x21, ppu.peek_palette(0x04));
+		assert_eq!(0x21, ppu.peek_palette(0x14));
+		assert_eq!(0x10, ppu.peek_palette(0x00));
+		write(&mut ppu, &mut cartridge, 0x3F00, 0x22);
+		assert_eq!(0x22, ppu.peek_palette(0x10));
+		assert_eq!(0x21, ppu.peek_palette(0x04));
+	}
+
+	#[test]
+	fn palette_ram() {
+		let mut palette = PaletteRam::new();
+		for i in 0..0x20 {
+			palette.set(0x3F00 + i, 0xC0 | i as u8);
+		}
+		assert_eq!(0x10, palette.universal_background());
+		assert_eq!(0x14, palette.get(0x04));
+		assert_eq!(0x1D, palette.get(0x3FFD));
+		assert_eq!([0x10, 0x05, 0x06, 0x07], palette.colors(1));
+		assert_eq!([0x10, 0x19, 0x1A, 0x1B], palette.colors(6));
+		let entries = palette.entries();
+		assert_eq!(entries[0x00], entries[0x10]);
+		assert_eq!(0x0F, entries[0x0F]);
+
+		let mut state = Vec::new();
+		palette.save_state(&mut state).unwrap();
+		let mut loaded = PaletteRam::new();
+		loaded.load_state(&mut &[0xFF; 32][..]).unwrap();
+		assert_eq!(0x3F, loaded.get(0x05));
+		loaded.load_state(&mut &state[..]).unwrap();
+		assert_eq!(palette, loaded);
+	}
+
+	#[test]
+	fn backdrop_color() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.poke_palette(0x00, 0x0F);
+		ppu.poke_palette(0x04, 0x16);
+		next_frame(&mut ppu, &mut cartridge);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x0F), frame.pixel(0, 0));
+
+		// rendering is off, so the palette entry at the VRAM address shows
+		ppu.write(&mut cartridge, 0x2006, 0x3F);
+		ppu.write(&mut cartridge, 0x2006, 0x04);
+		next_frame(&mut ppu, &mut cartridge);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x16), frame.pixel(100, 100));
+	}
+
+	#[test]
+	fn render_state() {
+		let nrom = TestCartridge::builder().chr(0x0000, &[0xFF]).build();
+		let mut cartridge = A12Counter { nrom, rises: 0 };
+		let mut ppu = Ppu::new();
+		ppu.poke_palette(0, 0x0F);
+		ppu.poke_palette(1, 0x30);
+		run_to(&mut ppu, &mut cartridge, 12, 34);
+		assert_eq!((12, 34), (ppu.scanline(), ppu.dot()));
+		assert!(!ppu.odd_frame());
+		assert!(!ppu.rendering_enabled());
+
+		// forced on, sprite fetches from the right table move A12
+		ppu.write(&mut cartridge, 0x2000, 0b1000);
+		ppu.set_render_enabled(Some(true));
+		assert!(ppu.rendering_enabled());
+		run_frames(&mut ppu, &mut cartridge, 1);
+		assert!(ppu.odd_frame());
+		assert!(cartridge.rises > 0);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x30), frame.pixel(8, 0));
+		// the left column is masked
+		assert_eq!(rgb(0x0F), frame.pixel(0, 0));
+
+		// forced off, only the backdrop is drawn and nothing is fetched
+		ppu.write(&mut cartridge, 0x2001, 0b11000);
+		ppu.set_render_enabled(Some(false));
+		assert!(!ppu.rendering_enabled());
+		run_frames(&mut ppu, &mut cartridge, 1);
+		let rises = cartridge.rises;
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x0F), frame.pixel(0, 0));
+		assert_eq!(rises, cartridge.rises);
+	}
+
+	#[test]
+	fn mid_scanline_mask() {
+		let mut cartridge = TestCartridge::builder().chr(0x0000, &[0xFF; 8]).build();
+		let mut ppu = Ppu::new();
+		ppu.poke_palette(0, 0x0F);
+		ppu.poke_palette(1, 0x16);
+		ppu.write(&mut cartridge, 0x2001, 0b1000);
+		next_frame(&mut ppu, &mut cartridge);
+
+		// greyscale and red emphasis from the pixel output at dot 101 on,
+		// emphasis off again from dot 193 on
+		run_to(&mut ppu, &mut cartridge, 10, 101);
+		ppu.write(&mut cartridge, 0x2001, 0b0010_1001);
+		run_to(&mut ppu, &mut cartridge, 10, 193);
+		ppu.write(&mut cartridge, 0x2001, 0b1001);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x16), frame.pixel(99, 10));
+		let (r, g, b) = rgb(0x10);
+		let darkened = |value: u8| (value as u16 * 209 / 256) as u8;
+		assert_eq!((r, darkened(g), darkened(b)), frame.pixel(100, 10));
+		assert_eq!((r, darkened(g), darkened(b)), frame.pixel(191, 10));
+		assert_eq!(rgb(0x10), frame.pixel(192, 10));
+		assert_eq!(rgb(0x10), frame.pixel(8, 11));
+	}
+
+	#[test]
+	fn left_column() {
+		let mut cartridge = TestCartridge::builder().chr(0x0000, &[0xFF]).build();
+		let mut ppu = Ppu::new();
+		ppu.poke_palette(0, 0x0F);
+		ppu.poke_palette(1, 0x30);
+		ppu.write(&mut cartridge, 0x2001, 0b1000);
+		next_frame(&mut ppu, &mut cartridge);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x0F), frame.pixel(7, 0));
+		assert_eq!(rgb(0x30), frame.pixel(8, 0));
+
+		ppu.write(&mut cartridge, 0x2001, 0b1010);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x30), frame.pixel(0, 0));
+
+		// sprites alone do not show the background
+		ppu.write(&mut cartridge, 0x2001, 0b10110);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x0F), frame.pixel(8, 0));
+	}
+
+	#[test]
+	fn attributes_and_fine_x() {
+		// tile 1 is solid color 1, the first attribute byte selects palette
+		// 0 to 3 for its quadrants
+		let mut cartridge = TestCartridge::builder().chr(0x0010, &[0xFF; 8]).build();
+		let mut ppu = Ppu::new();
+		for addr in 0x2000..0x23C0 {
+			ppu.poke_vram(&mut cartridge, addr, 1);
+		}
+		ppu.poke_vram(&mut cartridge, 0x23C0, 0b11_10_01_00);
+		ppu.poke_palette(0x00, 0x0F);
+		for palette in 0..4 {
+			ppu.poke_palette(palette * 4 + 1, 0x11 + palette * 4);
+		}
+		ppu.write(&mut cartridge, 0x2001, 0b1010);
+		next_frame(&mut ppu, &mut cartridge);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x11), frame.pixel(15, 15));
+		assert_eq!(rgb(0x15), frame.pixel(16, 0));
+		assert_eq!(rgb(0x19), frame.pixel(0, 16));
+		assert_eq!(rgb(0x1D), frame.pixel(31, 31));
+		assert_eq!(rgb(0x11), frame.pixel(32, 0));
+		assert_eq!(rgb(0x11), frame.pixel(0, 32));
+
+		// scrolled by 3 pixels to the right and 8 down
+		ppu.write(&mut cartridge, 0x2005, 3);
+		ppu.write(&mut cartridge, 0x2005, 8);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x11), frame.pixel(12, 0));
+		assert_eq!(rgb(0x15), frame.pixel(13, 0));
+		assert_eq!(rgb(0x19), frame.pixel(0, 8));
+		assert_eq!(rgb(0x1D), frame.pixel(13, 8));
+	}
+
+	fn next_frame(ppu: &mut Ppu, cartridge: &mut dyn Cartridge) -> Frame {
+		loop {
+			if let Some(frame) = ppu.take_frame() {
+				return frame;
+			}
+			ppu.tick(cartridge);
+		}
+	}
+
+	fn rgb(color: usize) -> (u8, u8, u8) {
+		(RGB_PALETTE[color * 3], RGB_PALETTE[color * 3 + 1], RGB_PALETTE[color * 3 + 2])
+	}
+
+	#[test]
+	fn sprite_priority() {
+		// tile 1 is solid color 1, tile 2 color 1 in its right half only
+		let mut cartridge = TestCartridge::builder()
+			.chr(0x0010, &[0xFF; 8])
+			.chr(0x0020, &[0x0F; 8])
+			.build();
+		let mut ppu = Ppu::new();
+		// opaque background in x 0-31 of the first tile row
+		for addr in 0x2000..0x2004 {
+			ppu.poke_vram(&mut cartridge, addr, 1);
+		}
+		ppu.poke_palette(0x00, 0x0F);
+		ppu.poke_palette(0x01, 0x11);
+		ppu.poke_palette(0x11, 0x21);
+		ppu.poke_palette(0x15, 0x25);
+		for index in 0..=255 {
+			ppu.poke_oam(index, 0xFF);
+		}
+		// Y, tile, attributes, X; the sprites cover the lines 1-8
+		let sprites = [
+			[0, 1, 0x20, 16],  // behind the background
+			[0, 1, 0x01, 20],  // in front, but hidden by sprite 0
+			[0, 1, 0x20, 40],  // behind, on transparent background
+			[0, 1, 0x01, 44],
+			[0, 2, 0x00, 60],  // transparent left half
+			[0, 1, 0x01, 60],
+			[0, 2, 0x40, 80],  // flipped horizontally
+		];
+		for (i, sprite) in sprites.iter().enumerate() {
+			for (j, &value) in sprite.iter().enumerate() {
+				ppu.poke_oam((i * 4 + j) as u8, value);
+			}
+		}
+		ppu.write(&mut cartridge, 0x2001, 0b11110);
+		next_frame(&mut ppu, &mut cartridge);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x11), frame.pixel(16, 4));
+		assert_eq!(rgb(0x11), frame.pixel(22, 4));
+		assert_eq!(rgb(0x25), frame.pixel(24, 4));
+		assert_eq!(rgb(0x21), frame.pixel(40, 4));
+		assert_eq!(rgb(0x21), frame.pixel(46, 4));
+		assert_eq!(rgb(0x25), frame.pixel(48, 4));
+		assert_eq!(rgb(0x25), frame.pixel(60, 4));
+		assert_eq!(rgb(0x21), frame.pixel(64, 4));
+		assert_eq!(rgb(0x21), frame.pixel(80, 4));
+		assert_eq!(rgb(0x0F), frame.pixel(84, 4));
+		assert_eq!(rgb(0x0F), frame.pixel(40, 0));
+		assert_eq!(rgb(0x0F), frame.pixel(40, 9));
+		// sprite 0 overlaps the opaque background
+		assert!(ppu.sprite_0_hit);
+		assert!(!ppu.sprite_overflow);
+
+		// hidden layers are not drawn, but sprite 0 still hits
+		ppu.set_layers(false, true);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x21), frame.pixel(16, 4));
+		assert_eq!(rgb(0x0F), frame.pixel(8, 4));
+		assert!(ppu.sprite_0_hit);
+		ppu.set_layers(true, false);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x11), frame.pixel(16, 4));
+		assert_eq!(rgb(0x0F), frame.pixel(46, 4));
+		assert!(ppu.sprite_0_hit);
+		ppu.set_layers(true, true);
+
+		// nine sprites on a line
+		for i in 8..17 {
+			ppu.poke_oam(i * 4, 100);
+		}
+		next_frame(&mut ppu, &mut cartridge);
+		assert!(ppu.sprite_overflow);
+	}
+
+	#[test]
+	fn sprite_limit() {
+		let mut cartridge = TestCartridge::builder().chr(0x0010, &[0xFF; 8]).build();
+		let mut ppu = Ppu::new();
+		ppu.poke_palette(0x00, 0x0F);
+		ppu.poke_palette(0x11, 0x21);
+		for index in 0..=255 {
+			ppu.poke_oam(index, 0xFF);
+		}
+		// nine solid sprites side by side on the lines 11-18
+		for i in 0..9 {
+			for (j, &value) in [10, 1, 0, i * 8].iter().enumerate() {
+				ppu.poke_oam(i * 4 + j as u8, value);
+			}
+		}
+		ppu.write(&mut cartridge, 0x2001, 0b10100);
+		next_frame(&mut ppu, &mut cartridge);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x21), frame.pixel(56, 12));
+		assert_eq!(rgb(0x0F), frame.pixel(64, 12));
+		assert!(ppu.sprite_overflow);
+
+		// the ninth is drawn, and the overflow still reported
+		ppu.set_sprite_limit(false);
+		let frame = next_frame(&mut ppu, &mut cartridge);
+		assert_eq!(rgb(0x21), frame.pixel(64, 12));
+		assert_eq!(rgb(0x0F), frame.pixel(72, 12));
+		assert!(ppu.sprite_overflow);
+	}
+
+	#[test]
+	fn scanline_renderer() {
+		// tile 1 is solid color 1, tile 2 has colors 1 to 3 in stripes
+		let mut cartridge = TestCartridge::builder()
+			.chr(0x0010, &[0xFF; 8])
+			.chr(0x0020, &[0x55; 8])
+			.chr(0x0028, &[0x33; 8])
+			.build();
+		let mut ppu = Ppu::new();
+		for addr in 0x2000..0x23C0 {
+			ppu.poke_vram(&mut cartridge, addr, (addr % 3) as u8);
+		}
+		for addr in 0x23C0..0x2400 {
+			ppu.poke_vram(&mut cartridge, addr, addr as u8);
+		}
+		for index in 0..0x20 {
+			ppu.poke_palette(index, index as u8 + 0x10);
+		}
+		for index in 0..=255 {
+			ppu.poke_oam(index, index.wrapping_mul(37));
+		}
+		ppu.write(&mut cartridge, 0x2005, 3);
+		ppu.write(&mut cartridge, 0x2005, 21);
+		ppu.write(&mut cartridge, 0x2001, 0b11110);
+		next_frame(&mut ppu, &mut cartridge);
+		let dots = next_frame(&mut ppu, &mut cartridge);
+		let sprite_0_hit = ppu.sprite_0_hit;
+
+		ppu.set_scanline_renderer(true);
+		next_frame(&mut ppu, &mut cartridge);
+		let lines = next_frame(&mut ppu, &mut cartridge);
+		for y in 0..SCREEN_HEIGHT {
+			for x in 0..SCREEN_WIDTH {
+				assert_eq!(dots.pixel(x, y), lines.pixel(x, y), "pixel {}, {}", x, y);
+			}
+		}
+		assert_eq!(sprite_0_hit, ppu.sprite_0_hit);
+	}
+
+	#[test]
+	fn oam_corruption() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		for i in 0..=255 {
+			ppu.poke_oam(i, i);
+		}
+		ppu.write(&mut cartridge, 0x2003, 2);
+		ppu.write(&mut cartridge, 0x2004, 0xFF);
+		ppu.write(&mut cartridge, 0x2003, 2);
+		assert_eq!(0xE3, ppu.read(&mut cartridge, 0x2004));
+
+		// writes while rendering only move OAMADDR
+		ppu.write(&mut cartridge, 0x2001, 0b11000);
+		ppu.current_scanline = 100;
+		ppu.current_cycle = 10;
+		ppu.write(&mut cartridge, 0x2003, 0x21);
+		ppu.write(&mut cartridge, 0x2004, 0);
+		assert_eq!(0x21, ppu.peek_oam(0x21));
+		assert_eq!(0x25, ppu.oamaddr);
+
+		// OAMADDR 8 or more when rendering starts
+		ppu.current_scanline = 261;
+		ppu.current_cycle = 0;
+		ppu.tick(&mut cartridge);
+		ppu.tick(&mut cartridge);
+		assert_eq!(0x20, ppu.peek_oam(0));
+		assert_eq!(0x27, ppu.peek_oam(7));
+		assert_eq!(0x08, ppu.peek_oam(8));
+
+		ppu.set_oam_corruption(false);
+		ppu.current_scanline = 100;
+		ppu.write(&mut cartridge, 0x2003, 0x21);
+		ppu.write(&mut cartridge, 0x2004, 0);
+		assert_eq!(0, ppu.peek_oam(0x21));
+	}
+
+	#[test]
+	fn oam_reads_while_rendering() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		for i in 0..=255 {
+			ppu.poke_oam(i, 0xF0);
+		}
+		// a sprite on lines 10 to 17
+		for (i, &value) in [9, 0x42, 0x01, 0x30].iter().enumerate() {
+			ppu.poke_oam(i as u8, value);
+		}
+		ppu.write(&mut cartridge, 0x2003, 1);
+		assert_eq!(0x42, ppu.read(&mut cartridge, 0x2004));
+		ppu.write(&mut cartridge, 0x2001, 0b11000);
+		run_to(&mut ppu, &mut cartridge, 10, 258);
+		assert_eq!(0x42, ppu.read(&mut cartridge, 0x2004));
+		run_to(&mut ppu, &mut cartridge, 10, 262);
+		assert_eq!(0x30, ppu.read(&mut cartridge, 0x2004));
+		// the second slot is empty
+		run_to(&mut ppu, &mut cartridge, 10, 265);
+		assert_eq!(0xFF, ppu.read(&mut cartridge, 0x2004));
+		run_to(&mut ppu, &mut cartridge, 10, 330);
+		assert_eq!(9, ppu.read(&mut cartridge, 0x2004));
+		// clearing the secondary OAM, then the evaluation at OAMADDR 0
+		run_to(&mut ppu, &mut cartridge, 11, 30);
+		assert_eq!(0xFF, ppu.read(&mut cartridge, 0x2004));
+		run_to(&mut ppu, &mut cartridge, 11, 100);
+		assert_eq!(9, ppu.read(&mut cartridge, 0x2004));
+	}
+
+	#[test]
+	fn oam_decay() {
+		let mut cartridge = cartridge();
+		let mut ppu = Ppu::new();
+		ppu.current_scanline = 241;
+		ppu.write(&mut cartridge, 0x2004, 1);
+		ppu.write(&mut cartridge, 0x2004, 2);
+		// a vblank is too short
+		for _ in 0..(20 * 341) {
+			ppu.tick(&mut cartridge);
+		}
+		ppu.write(&mut cartridge, 0x2003, 0);
+		assert_eq!(1, ppu.read(&mut cartridge, 0x2004));
+
+		// rendering stays off for a whole frame
+		for _ in 0..(262 * 341) {
+			ppu.tick(&mut cartridge);
+		}
+		ppu.set_oam_decay(false);
+		ppu.write(&mut cartridge, 0x2003, 1);
+		assert_eq!(2, ppu.read(&mut cartridge, 0x2004));
+		for _ in 0..(262 * 341) {
+			ppu.tick(&mut cartridge);
+		}
+		ppu.set_oam_decay(true);
+		assert_eq!(0xFF, ppu.read(&mut cartridge, 0x2004));
+		assert_eq!(0xFF, ppu.peek_oam(7));
+		assert_eq!(0, ppu.peek_oam(8));
+	}
+}
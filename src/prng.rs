@@ -0,0 +1,89 @@
+use std::io::{Read, Write};
+use std::io;
+use savestate;
+
+// Seeded pseudo random numbers for the features which need randomness,
+// like RAM contents at power on. The state is part of the save states, so
+// replays and run-ahead stay deterministic with these features on.
+// xorshift64*, see https://en.wikipedia.org/wiki/Xorshift
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prng {
+	// never 0
+	state: u64,
+}
+
+impl Prng {
+	pub fn new(seed: u64) -> Prng {
+		let mut prng = Prng { state: 1 };
+		prng.seed(seed);
+		prng
+	}
+
+	// Restarts the sequence. Every seed, including 0, gives a different one.
+	pub fn seed(&mut self, seed: u64) {
+		// one step of splitmix64, which maps 0 to a valid state
+		let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+		z ^= z >> 31;
+		self.state = if z == 0 { 1 } else { z };
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.state ^= self.state >> 12;
+		self.state ^= self.state << 25;
+		self.state ^= self.state >> 27;
+		self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+	}
+
+	pub fn fill(&mut self, bytes: &mut [u8]) {
+		for chunk in bytes.chunks_mut(8) {
+			let value = self.next_u64();
+			for (i, byte) in chunk.iter_mut().enumerate() {
+				*byte = (value >> (i * 8)) as u8;
+			}
+		}
+	}
+
+	pub fn save_state(&self, out: &mut dyn Write) -> io::Result<()> {
+		savestate::write_u64(out, self.state)
+	}
+
+	pub fn load_state(&mut self, input: &mut dyn Read) -> io::Result<()> {
+		let state = savestate::read_u64(input)?;
+		if state == 0 {
+			return savestate::invalid_state("Invalid random number state.");
+		}
+		self.state = state;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn sequence() {
+		let mut a = Prng::new(0);
+		let mut b = Prng::new(0);
+		let mut c = Prng::new(1);
+		let first = a.next_u64();
+		assert_eq!(first, b.next_u64());
+		assert!(first != c.next_u64());
+
+		// a state continues the sequence
+		let mut state = Vec::new();
+		a.save_state(&mut state).unwrap();
+		let mut bytes = [0; 12];
+		a.fill(&mut bytes);
+		let mut d = Prng::new(5);
+		d.load_state(&mut &state[..]).unwrap();
+		let mut other = [0; 12];
+		d.fill(&mut other);
+		assert_eq!(bytes, other);
+		assert!(bytes.iter().any(|&byte| byte != bytes[0]));
+
+		assert!(d.load_state(&mut &[0u8; 8][..]).is_err());
+	}
+}
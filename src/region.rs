@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+// TV system of the console, which decides the timing of CPU and PPU.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+	// 262 lines at 3 PPU dots per CPU cycle, about 60 frames per second.
+	Ntsc,
+	// 312 lines at 3.2 PPU dots per CPU cycle, 50 frames per second.
+	Pal,
+}
+
+impl Region {
+	pub fn from_name(name: &str) -> Option<Region> {
+		match name {
+			"ntsc" => Some(Region::Ntsc),
+			"pal" => Some(Region::Pal),
+			_ => None,
+		}
+	}
+
+	// The region from the first parenthesized tags of a No-Intro title,
+	// e.g. "Elite (Europe)" or "Tetris (Germany, Sweden) (Rev 1)". None if
+	// the game was released in regions of both systems, or in none known.
+	pub fn from_title(title: &str) -> Option<Region> {
+		const PAL_COUNTRIES: [&str; 12] = ["Europe", "Australia", "Germany", "France", "Spain",
+			"Italy", "Sweden", "Netherlands", "UK", "Scandinavia", "Denmark", "Finland"];
+		// Brazil used PAL-M, which has NTSC timing.
+		const NTSC_COUNTRIES: [&str; 6] = ["USA", "Japan", "Canada", "Korea", "Brazil", "Asia"];
+		let start = match title.find('(') {
+			Some(start) => start + 1,
+			None => return None,
+		};
+		let end = match title[start..].find(')') {
+			Some(end) => start + end,
+			None => return None,
+		};
+		let countries: Vec<&str> = title[start..end].split(',').map(|country| country.trim()).collect();
+		if countries.iter().all(|country| PAL_COUNTRIES.contains(country)) {
+			Some(Region::Pal)
+		} else if countries.iter().all(|country| NTSC_COUNTRIES.contains(country)) {
+			Some(Region::Ntsc)
+		} else {
+			None
+		}
+	}
+
+	// Index of the pre-render line, the last one of a frame.
+	pub fn prerender_line(&self) -> usize {
+		match *self {
+			Region::Ntsc => 261,
+			Region::Pal => 311,
+		}
+	}
+
+	// PPU dots of 5 CPU cycles, 3 or 3.2 per cycle.
+	pub fn dots_per_5_cycles(&self) -> u64 {
+		match *self {
+			Region::Ntsc => 15,
+			Region::Pal => 16,
+		}
+	}
+
+	// CPU cycles of whole scanlines of 341 dots, rounded down.
+	pub fn line_cycles(&self, lines: u32) -> u64 {
+		lines as u64 * 341 * 5 / self.dots_per_5_cycles()
+	}
+
+	// The CPU clock as a fraction in Hz: 236.25 MHz / 132 for NTSC,
+	// 26.6017125 MHz / 16 for PAL.
+	pub fn cpu_clock(&self) -> (u64, u64) {
+		match *self {
+			Region::Ntsc => (236_250_000, 132),
+			Region::Pal => (53_203_425, 32),
+		}
+	}
+
+	// Whether the PPU skips the last dot of every other frame while
+	// rendering.
+	pub fn skips_odd_frame_dot(&self) -> bool {
+		*self == Region::Ntsc
+	}
+
+	// Duration of a frame as the PPU runs it while rendering: 29780.5 CPU
+	// cycles for NTSC, whose odd frames are a dot short, 33247.5 for PAL.
+	pub fn frame_time(&self) -> Duration {
+		let two_frames = 2 * (self.prerender_line() as u64 + 1) * 341 - self.skips_odd_frame_dot() as u64;
+		let (numerator, denominator) = self.cpu_clock();
+		// from dots over cycles to nanoseconds, rounded
+		let divisor = 2 * self.dots_per_5_cycles() * numerator;
+		Duration::from_nanos((two_frames * 5 * denominator * 1_000_000_000 + divisor / 2) / divisor)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn title_region() {
+		assert_eq!(Some(Region::Pal), Region::from_title("Elite (Europe)"));
+		assert_eq!(Some(Region::Pal), Region::from_title("Tetris (Germany, Sweden) (Rev 1)"));
+		assert_eq!(Some(Region::Ntsc), Region::from_title("Zelda (USA) (Rev 1)"));
+		assert_eq!(None, Region::from_title("Tetris (USA, Europe)"));
+		assert_eq!(None, Region::from_title("Game (World)"));
+		assert_eq!(None, Region::from_title("Homebrew"));
+	}
+
+	#[test]
+	fn frame_time() {
+		assert_eq!(Duration::from_nanos(16_639_263), Region::Ntsc.frame_time());
+		assert_eq!(Duration::from_nanos(19_997_209), Region::Pal.frame_time());
+	}
+}
@@ -0,0 +1,244 @@
+use apu::{Channel, CHANNELS, SAMPLE_RATE};
+use cartridge::load_rom;
+use movie::{self, Movie, MovieSession};
+use nes::Nes;
+use wav::WavWriter;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+// Headless audio rendering:
+//   render-audio <rom> [--movie FILE] [--seconds N] [--out FILE.wav]
+//                [--solo CHANNEL] [--stems DIR]
+//
+// Runs the game as fast as it goes and writes its audio to a WAV file, to
+// archive soundtracks or compare the APU output of two versions. With a
+// movie its input drives the game, and the rendering ends with the movie
+// unless --seconds is given. Without a movie no buttons are pressed.
+// --solo leaves only one channel in the output, e.g. triangle. --stems
+// also writes every channel on its own before mixing to DIR, e.g.
+// DIR/pulse1.wav, for composers to rework the parts.
+pub fn run(args: &[String]) {
+	let mut rom_path = None;
+	let mut movie_path = None;
+	let mut seconds = None;
+	let mut out_path = String::from("audio.wav");
+	let mut solo = None;
+	let mut stems_dir = None;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_ref() {
+			"--movie" => {
+				movie_path = args.next().cloned();
+				if movie_path.is_none() {
+					println!("--movie expects a file name.");
+					return;
+				}
+			}
+			"--seconds" => {
+				seconds = match args.next().and_then(|seconds| seconds.parse::<f64>().ok()) {
+					Some(seconds) if seconds > 0.0 => Some(seconds),
+					_ => {
+						println!("--seconds expects a positive number.");
+						return;
+					}
+				};
+			}
+			"--out" => {
+				out_path = match args.next() {
+					Some(path) => path.clone(),
+					None => {
+						println!("--out expects a file name.");
+						return;
+					}
+				};
+			}
+			"--solo" => {
+				solo = match args.next().and_then(|name| Channel::from_name(name)) {
+					Some(channel) => Some(channel),
+					None => {
+						let names: Vec<&str> = CHANNELS.iter().map(|channel| channel.name()).collect();
+						println!("--solo expects one of: {}.", names.join(", "));
+						return;
+					}
+				};
+			}
+			"--stems" => {
+				stems_dir = args.next().cloned();
+				if stems_dir.is_none() {
+					println!("--stems expects a directory.");
+					return;
+				}
+			}
+			_ => rom_path = Some(arg.clone()),
+		}
+	}
+	let rom_path = match rom_path {
+		Some(path) => path,
+		None => {
+			println!("Usage: render-audio <rom> [--movie FILE] [--seconds N] [--out FILE.wav] [--solo CHANNEL] [--stems DIR]");
+			return;
+		}
+	};
+	if !out_path.ends_with(".wav") {
+		println!("Only WAV files can be written, the output has to end with .wav.");
+		return;
+	}
+	if is_nsf(&rom_path) {
+		println!("NSF files are not supported yet, only ROMs.");
+		return;
+	}
+	let seconds = match (seconds, movie_path.is_some()) {
+		(Some(seconds), _) => Some(seconds),
+		(None, true) => None,
+		(None, false) => Some(60.0),
+	};
+
+	let cartridge = match load_rom(&rom_path) {
+		Ok(rom) => rom,
+		Err(err) => {
+			println!("Could not load ROM: {}", err);
+			return;
+		}
+	};
+	let mut nes = Nes::new(cartridge);
+	let mut settings = nes.audio_settings().clone();
+	settings.solo = solo;
+	nes.set_audio_settings(settings);
+	let mut session = None;
+	if let Some(ref path) = movie_path {
+		match Movie::load(path) {
+			Ok(movie) => {
+				nes.set_region(movie.region);
+				session = Some(MovieSession::play(movie));
+			}
+			Err(err) => {
+				println!("Could not load movie: {}", err);
+				return;
+			}
+		}
+	}
+	let mut stems = match stems_dir {
+		Some(ref dir) => match create_stems(dir) {
+			Ok(stems) => stems,
+			Err(err) => {
+				println!("Could not create the stems in {}: {}", dir, err);
+				return;
+			}
+		},
+		None => Vec::new(),
+	};
+	nes.set_audio_stems(!stems.is_empty());
+	let result = WavWriter::create(&out_path, SAMPLE_RATE)
+		.and_then(|mut writer| {
+			let frames = render(&mut nes, session, seconds, &mut writer, &mut stems)?;
+			let samples = writer.samples();
+			writer.finish()?;
+			for stem in stems {
+				stem.finish()?;
+			}
+			Ok((frames, samples))
+		});
+	match result {
+		Ok((frames, samples)) => println!("Rendered {} frames, {:.1} seconds of audio to {}.",
+			frames, samples as f64 / SAMPLE_RATE as f64, out_path),
+		Err(err) => println!("Could not write {}: {}", out_path, err),
+	}
+}
+
+// A WAV file per channel in dir, in the order of CHANNELS.
+fn create_stems(dir: &str) -> io::Result<Vec<WavWriter<BufWriter<File>>>> {
+	fs::create_dir_all(dir)?;
+	CHANNELS.iter().map(|channel| {
+		let path = Path::new(dir).join(format!("{}.wav", channel.name()));
+		WavWriter::create(&path.to_string_lossy(), SAMPLE_RATE)
+	}).collect()
+}
+
+// Runs frames and writes their audio until the seconds are rendered, or
+// without seconds until the movie ends. Unless stems is empty, the stems
+// of the channels are written to it as well, see Nes::set_audio_stems.
+// Returns the number of frames.
+pub fn render<W: Write + Seek>(nes: &mut Nes, mut session: Option<MovieSession>, seconds: Option<f64>,
+		out: &mut WavWriter<W>, stems: &mut [WavWriter<W>]) -> io::Result<u64> {
+	let limit = seconds.map(|seconds| (seconds * SAMPLE_RATE as f64) as u32);
+	let mut frames = 0;
+	loop {
+		if limit.map(|limit| out.samples() >= limit).unwrap_or(false) {
+			break;
+		}
+		let playing = session.as_mut().map(|session| session.start_frame(nes, [0, 0])).unwrap_or(false);
+		if !playing && session.take().is_some() {
+			// the rest without buttons
+			nes.set_buttons(0, 0);
+			nes.set_buttons(1, 0);
+		}
+		if !playing && limit.is_none() {
+			break;
+		}
+		movie::run_frame(nes);
+		frames += 1;
+		if let Some(chunk) = nes.take_audio() {
+			let count = match limit {
+				Some(limit) => chunk.samples.len().min((limit - out.samples()) as usize),
+				None => chunk.samples.len(),
+			};
+			out.write_samples(&chunk.samples[..count])?;
+			if let Some(chunks) = nes.take_audio_stems() {
+				for (stem, (_, chunk)) in stems.iter_mut().zip(chunks) {
+					stem.write_samples(&chunk.samples[..count])?;
+				}
+			}
+		}
+		if let Some(ref mut session) = session {
+			if let Err(err) = session.end_frame(nes) {
+				println!("The movie diverged, the audio may differ from the recording: {}", err);
+			}
+		}
+	}
+	Ok(frames)
+}
+
+// NSF files start with "NESM" and an end of file character.
+fn is_nsf(path: &str) -> bool {
+	let mut magic = [0; 5];
+	File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && &magic == b"NESM\x1A"
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::test_cartridge::TestCartridge;
+	use region::Region;
+	use std::io::Cursor;
+
+	#[test]
+	fn render_seconds_and_movie() {
+		let nes = || Nes::new(Box::new(TestCartridge::builder().build()));
+		let mut writer = WavWriter::new(Cursor::new(Vec::new()), SAMPLE_RATE).unwrap();
+		let frames = render(&mut nes(), None, Some(1.5), &mut writer, &mut []).unwrap();
+		assert_eq!(66150, writer.samples());
+		assert_eq!(91, frames);
+
+		// until the end of the movie
+		let mut movie = Movie::new("00", Region::Ntsc);
+		movie.inputs = vec![[0, 0]; 30];
+		let mut writer = WavWriter::new(Cursor::new(Vec::new()), SAMPLE_RATE).unwrap();
+		let mut console = nes();
+		assert_eq!(30, render(&mut console, Some(MovieSession::play(movie.clone())), None, &mut writer, &mut []).unwrap());
+		assert!(writer.samples() > 29 * 733);
+
+		// and on without input
+		let mut writer = WavWriter::new(Cursor::new(Vec::new()), SAMPLE_RATE).unwrap();
+		render(&mut nes(), Some(MovieSession::play(movie)), Some(2.0), &mut writer, &mut []).unwrap();
+		assert_eq!(88200, writer.samples());
+
+		// with a stem per channel
+		let mut console = nes();
+		console.set_audio_stems(true);
+		let mut writer = WavWriter::new(Cursor::new(Vec::new()), SAMPLE_RATE).unwrap();
+		let mut stems: Vec<_> = CHANNELS.iter().map(|_| WavWriter::new(Cursor::new(Vec::new()), SAMPLE_RATE).unwrap()).collect();
+		render(&mut console, None, Some(0.5), &mut writer, &mut stems).unwrap();
+		assert!(stems.iter().all(|stem| stem.samples() == 22050));
+	}
+}
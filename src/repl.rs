@@ -0,0 +1,609 @@
+use apu::CHANNELS;
+use io_registers::{describe_register, REGISTERS};
+use cpu::{assemble, disassemble, Status, AccessSource, ACCESS_SOURCES, Watchpoint, WatchHit, Cpu, Hardware, TrapHandler};
+use nes::{Nes, ConsoleEvent};
+use std::collections::BTreeMap;
+
+const HELP: &str = "\
+Numbers are hexadecimal, with or without $.
+  r                    registers and PPU position
+  apu                  the last values written to the channel registers
+  io [ADDR]            what the I/O register at ADDR does, all by default
+  set REG VALUE        set a register: a, x, y, s, p or pc
+  m [SPACE] ADDR [LEN] memory dump, without side effects
+  w [SPACE] ADDR BYTE...
+                       write memory like the CPU, or the PPU memory of
+                       the space: cpu (default), vram, oam or pal
+  a ADDR INSTR;...     assemble and write the code like w
+  d [ADDR] [COUNT]     disassemble, from the PC by default
+  b ADDR / bd ADDR     set / delete a breakpoint
+  bl                   list breakpoints
+  wp ADDR[-END] [r|w|rw] [SOURCE...]
+                       stop after an access, by default a write by any of
+                       the sources fetch, data, stack, vector and dma
+  wd ADDR / wl         delete watchpoints starting at ADDR / list them
+  stub ADDR [A]        return from the subroutine at ADDR right away, with
+                       A set to the value if given, e.g. to skip a delay
+  sd ADDR / sl         delete the stub at ADDR / list stubs
+  p / c                pause / continue
+  s [COUNT]            step instructions, pauses
+  save NAME / load NAME / states
+                       save states kept in memory
+  reset / power        reset button / power cycle
+  q                    quit";
+
+// The memories which m and w access.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Space {
+	Cpu,
+	Vram,
+	Oam,
+	Palette,
+}
+
+impl Space {
+	fn from_name(name: &str) -> Option<Space> {
+		match name {
+			"cpu" => Some(Space::Cpu),
+			"vram" => Some(Space::Vram),
+			"oam" => Some(Space::Oam),
+			"pal" => Some(Space::Palette),
+			_ => None,
+		}
+	}
+
+	// Addresses wrap around at the size.
+	fn size(&self) -> u32 {
+		match *self {
+			Space::Cpu => 0x10000,
+			Space::Vram => 0x4000,
+			Space::Oam => 0x100,
+			Space::Palette => 0x20,
+		}
+	}
+
+	fn peek(&self, nes: &mut Nes, addr: u16) -> u8 {
+		let addr = (addr as u32 % self.size()) as u16;
+		match *self {
+			Space::Cpu => nes.peek_memory(addr),
+			Space::Vram => nes.peek_vram(addr),
+			Space::Oam => nes.peek_oam(addr as u8),
+			Space::Palette => nes.peek_palette(addr as u8),
+		}
+	}
+
+	fn poke(&self, nes: &mut Nes, addr: u16, value: u8) {
+		let addr = (addr as u32 % self.size()) as u16;
+		match *self {
+			Space::Cpu => nes.poke_memory(addr, value),
+			Space::Vram => nes.poke_vram(addr, value),
+			Space::Oam => nes.poke_oam(addr as u8, value),
+			Space::Palette => nes.poke_palette(addr as u8, value),
+		}
+	}
+}
+
+// What the frontend has to do after a command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunControl {
+	Pause,
+	Continue,
+	Quit,
+}
+
+// Terminal debugger: the frontend passes it the lines read from stdin
+// while the game runs or is paused, and asks it before every instruction
+// whether a breakpoint was hit.
+pub struct Repl {
+	breakpoints: Vec<u16>,
+	// the PC continued from, so its breakpoint does not hit again at once
+	resume_at: Option<u16>,
+	watchpoints: Vec<Watchpoint>,
+	// subroutines replaced by traps, with the value they return in A
+	stubs: BTreeMap<u16, Option<u8>>,
+	states: BTreeMap<String, Vec<u8>>,
+}
+
+impl Repl {
+	pub fn new() -> Repl {
+		Repl {
+			breakpoints: Vec::new(),
+			resume_at: None,
+			watchpoints: Vec::new(),
+			stubs: BTreeMap::new(),
+			states: BTreeMap::new(),
+		}
+	}
+
+	// Whether the instruction at pc is a breakpoint, to be called before
+	// executing it.
+	pub fn check_breakpoint(&mut self, pc: u16) -> bool {
+		if self.breakpoints.is_empty() {
+			return false;
+		}
+		if self.resume_at.take() == Some(pc) {
+			return false;
+		}
+		self.breakpoints.contains(&pc)
+	}
+
+	// Sets the watchpoints and the stubs in a console, e.g. after it was
+	// replaced by reloading the ROM.
+	pub fn apply_to(&self, nes: &mut Nes) {
+		nes.set_watchpoints(self.watchpoints.clone());
+		for (&addr, &value) in &self.stubs {
+			nes.set_trap(addr, stub(value));
+		}
+	}
+
+	// Runs a command and returns its output.
+	pub fn execute(&mut self, nes: &mut Nes, line: &str) -> (String, Option<RunControl>) {
+		let words: Vec<&str> = line.split_whitespace().collect();
+		let command = match words.first() {
+			Some(&command) => command,
+			None => return (String::new(), None),
+		};
+		// m and w may name a memory space before the address
+		let (space, args) = match words.get(1).and_then(|word| Space::from_name(word)) {
+			Some(space) if command == "m" || command == "w" => (space, &words[2..]),
+			_ => (Space::Cpu, &words[1..]),
+		};
+		let numbers: Result<Vec<u16>, String> = args.iter().map(|word| parse_hex(word)).collect();
+		let numbers = match numbers {
+			Ok(numbers) => numbers,
+			// only the save state commands take names
+			Err(_) if ["save", "load", "set", "wp", "a"].contains(&command) => Vec::new(),
+			Err(err) => return (err, None),
+		};
+		let arg = |i: usize| numbers.get(i).cloned();
+		match command {
+			"help" | "h" | "?" => (String::from(HELP), None),
+			"r" => (registers(nes), None),
+			"apu" => (channel_registers(nes), None),
+			"io" => match arg(0) {
+				Some(addr) => match describe_register(addr) {
+					Some(register) => (register.summary(), None),
+					None => (format!("No I/O register at ${:04X}.", addr), None),
+				},
+				None => {
+					let lines: Vec<String> = REGISTERS.iter().map(|register| register.summary()).collect();
+					(lines.join("\n"), None)
+				}
+			},
+			"set" => {
+				let value = match words.get(2).map(|word| parse_hex(word)) {
+					Some(Ok(value)) if words.len() == 3 => value,
+					Some(Err(err)) => return (err, None),
+					_ => return (String::from("Usage: set REG VALUE"), None),
+				};
+				let mut state = nes.cpu_state();
+				match words[1] {
+					"pc" => state.registers.pc = value,
+					_ if value > 0xFF => return (String::from("Not a byte."), None),
+					"a" => state.registers.a = value as u8,
+					"x" => state.registers.x = value as u8,
+					"y" => state.registers.y = value as u8,
+					"s" => state.registers.s = value as u8,
+					"p" => state.registers.p = Status::from(value as u8),
+					register => return (format!("Unknown register {}.", register), None),
+				}
+				nes.set_cpu_state(state);
+				(registers(nes), None)
+			}
+			"m" => match arg(0) {
+				Some(addr) => (dump(nes, space, addr, arg(1).unwrap_or(0x40).min(0x1000)), None),
+				None => (String::from("Usage: m [SPACE] ADDR [LEN]"), None),
+			},
+			"w" => match arg(0) {
+				Some(addr) if numbers.len() >= 2 && numbers[1..].iter().all(|&value| value <= 0xFF) => {
+					for (i, &value) in numbers[1..].iter().enumerate() {
+						space.poke(nes, addr.wrapping_add(i as u16), value as u8);
+					}
+					(format!("Wrote {} bytes.", numbers.len() - 1), None)
+				}
+				_ => (String::from("Usage: w [SPACE] ADDR BYTE..."), None),
+			},
+			"a" => match words.get(1).map(|word| parse_hex(word)) {
+				Some(Ok(addr)) if words.len() > 2 => match assemble(addr, &words[2..].join(" ")) {
+					Ok(code) => {
+						for (i, &value) in code.iter().enumerate() {
+							nes.poke_memory(addr.wrapping_add(i as u16), value);
+						}
+						(format!("Wrote {} bytes.", code.len()), None)
+					}
+					Err(err) => (err, None),
+				},
+				Some(Err(err)) => (err, None),
+				_ => (String::from("Usage: a ADDR INSTR;..."), None),
+			},
+			"d" => {
+				let addr = arg(0).unwrap_or_else(|| nes.pc());
+				(disassembly(nes, addr, arg(1).unwrap_or(10).min(100)), None)
+			}
+			"b" => match arg(0) {
+				Some(addr) => {
+					if !self.breakpoints.contains(&addr) {
+						self.breakpoints.push(addr);
+					}
+					(format!("Breakpoint at ${:04X}.", addr), None)
+				}
+				None => (String::from("Usage: b ADDR"), None),
+			},
+			"bd" => match arg(0) {
+				Some(addr) => {
+					self.breakpoints.retain(|&breakpoint| breakpoint != addr);
+					(format!("Deleted breakpoint at ${:04X}.", addr), None)
+				}
+				None => (String::from("Usage: bd ADDR"), None),
+			},
+			"bl" => {
+				let list: Vec<String> = self.breakpoints.iter().map(|addr| format!("${:04X}", addr)).collect();
+				(if list.is_empty() { String::from("No breakpoints.") } else { list.join(" ") }, None)
+			}
+			"wp" => match parse_watchpoint(&words[1..]) {
+				Some(watchpoint) => {
+					let text = format!("Watchpoint {}.", describe_watchpoint(&watchpoint));
+					self.watchpoints.push(watchpoint);
+					nes.set_watchpoints(self.watchpoints.clone());
+					(text, None)
+				}
+				None => (String::from("Usage: wp ADDR[-END] [r|w|rw] [SOURCE...]"), None),
+			},
+			"wd" => match arg(0) {
+				Some(addr) => {
+					self.watchpoints.retain(|watchpoint| watchpoint.start != addr);
+					nes.set_watchpoints(self.watchpoints.clone());
+					(format!("Deleted watchpoints at ${:04X}.", addr), None)
+				}
+				None => (String::from("Usage: wd ADDR"), None),
+			},
+			"wl" => {
+				let list: Vec<String> = self.watchpoints.iter().map(describe_watchpoint).collect();
+				(if list.is_empty() { String::from("No watchpoints.") } else { list.join("\n") }, None)
+			}
+			"stub" => match (arg(0), arg(1)) {
+				(Some(addr), value) if numbers.len() <= 2 && value.is_none_or(|value| value <= 0xFF) => {
+					let value = value.map(|value| value as u8);
+					self.stubs.insert(addr, value);
+					nes.set_trap(addr, stub(value));
+					(format!("Stub {}.", describe_stub(addr, value)), None)
+				}
+				_ => (String::from("Usage: stub ADDR [A]"), None),
+			},
+			"sd" => match arg(0) {
+				Some(addr) => {
+					self.stubs.remove(&addr);
+					nes.remove_trap(addr);
+					(format!("Deleted stub at ${:04X}.", addr), None)
+				}
+				None => (String::from("Usage: sd ADDR"), None),
+			},
+			"sl" => {
+				let list: Vec<String> = self.stubs.iter().map(|(&addr, &value)| describe_stub(addr, value)).collect();
+				(if list.is_empty() { String::from("No stubs.") } else { list.join("\n") }, None)
+			}
+			"p" => (registers(nes), Some(RunControl::Pause)),
+			"c" => {
+				self.resume_at = Some(nes.pc());
+				(String::new(), Some(RunControl::Continue))
+			}
+			"s" => {
+				let mut instr_log = None;
+				for _ in 0..arg(0).unwrap_or(1) {
+					nes.step(&mut instr_log);
+				}
+				let pc = nes.pc();
+				(format!("{}\n{}", registers(nes), disassembly(nes, pc, 1)), Some(RunControl::Pause))
+			}
+			"save" | "load" if words.len() != 2 => (format!("Usage: {} NAME", command), None),
+			"save" => {
+				let mut state = Vec::new();
+				match nes.save_state(&mut state) {
+					Ok(()) => {
+						self.states.insert(String::from(words[1]), state);
+						(format!("Saved state {}.", words[1]), None)
+					}
+					Err(err) => (format!("Could not save state: {}", err), None),
+				}
+			}
+			"load" => match self.states.get(words[1]) {
+				Some(state) => match nes.load_state(&mut &state[..]) {
+					Ok(()) => (format!("Loaded state {}.", words[1]), None),
+					Err(err) => (format!("Could not load state, the console is unchanged: {}", err), None),
+				},
+				None => (format!("No state {}.", words[1]), None),
+			},
+			"states" => {
+				let names: Vec<&str> = self.states.keys().map(|name| name.as_ref()).collect();
+				(if names.is_empty() { String::from("No states.") } else { names.join(" ") }, None)
+			}
+			"reset" => {
+				nes.handle_event(ConsoleEvent::SoftReset);
+				(String::from("Reset."), None)
+			}
+			"power" => {
+				nes.handle_event(ConsoleEvent::PowerCycle);
+				(String::from("Power cycled."), None)
+			}
+			"q" => (String::new(), Some(RunControl::Quit)),
+			_ => (format!("Unknown command {}, try help.", command), None),
+		}
+	}
+}
+
+fn parse_hex(word: &str) -> Result<u16, String> {
+	let digits = word.trim_start_matches('$').trim_start_matches("0x");
+	u16::from_str_radix(digits, 16).map_err(|_| format!("Not a hexadecimal number: {}", word))
+}
+
+// Parses the arguments of wp, e.g. "0300-03FF w data".
+fn parse_watchpoint(words: &[&str]) -> Option<Watchpoint> {
+	if words.is_empty() {
+		return None;
+	}
+	let (range, rest) = (words[0], &words[1..]);
+	let (start, end) = match range.find('-') {
+		Some(i) => (parse_hex(&range[..i]).ok(), parse_hex(&range[i + 1..]).ok()),
+		None => (parse_hex(range).ok(), parse_hex(range).ok()),
+	};
+	let (start, end) = match (start, end) {
+		(Some(start), Some(end)) if start <= end => (start, end),
+		_ => return None,
+	};
+	let (read, write, rest) = match rest.first() {
+		Some(&"r") => (true, false, &rest[1..]),
+		Some(&"w") => (false, true, &rest[1..]),
+		Some(&"rw") => (true, true, &rest[1..]),
+		_ => (false, true, rest),
+	};
+	let sources: Option<Vec<AccessSource>> = rest.iter().map(|name| AccessSource::from_name(name)).collect();
+	let sources = match sources {
+		Some(ref sources) if sources.is_empty() => ACCESS_SOURCES.to_vec(),
+		Some(sources) => sources,
+		None => return None,
+	};
+	Some(Watchpoint {
+		start,
+		end,
+		read,
+		write,
+		sources,
+	})
+}
+
+// A trap which does what RTS does, after setting A if there is a value.
+fn stub(value: Option<u8>) -> TrapHandler {
+	Box::new(move |cpu: &mut Cpu, hw: &mut Hardware| {
+		if let Some(value) = value {
+			cpu.registers_mut().a = value;
+		}
+		cpu.return_from_subroutine(hw);
+		// as long as the RTS
+		6
+	})
+}
+
+// e.g. "$C000 returning A=$01"
+fn describe_stub(addr: u16, value: Option<u8>) -> String {
+	match value {
+		Some(value) => format!("${:04X} returning A=${:02X}", addr, value),
+		None => format!("${:04X}", addr),
+	}
+}
+
+// e.g. "$0300-$03FF w by data"
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+	let range = if watchpoint.start == watchpoint.end {
+		format!("${:04X}", watchpoint.start)
+	} else {
+		format!("${:04X}-${:04X}", watchpoint.start, watchpoint.end)
+	};
+	let mode = match (watchpoint.read, watchpoint.write) {
+		(true, true) => "rw",
+		(true, false) => "r",
+		_ => "w",
+	};
+	let sources: Vec<&str> = watchpoint.sources.iter().map(|source| source.name()).collect();
+	format!("{} {} by {}", range, mode, sources.join(" "))
+}
+
+// e.g. "Watchpoint: data write of $05 to $0300 by the instruction at $8002."
+pub fn describe_watch_hit(hit: &WatchHit) -> String {
+	format!("Watchpoint: {} {} of ${:02X} {} ${:04X} by the instruction at ${:04X}.",
+		hit.source.name(), if hit.write { "write" } else { "read" }, hit.value,
+		if hit.write { "to" } else { "from" }, hit.addr, hit.pc)
+}
+
+// e.g. "PC:8005 A:01 X:00 Y:00 P:24 SP:FD  scanline 12, dot 30, frame 3"
+pub fn registers(nes: &Nes) -> String {
+	let registers = nes.cpu_state().registers;
+	format!("PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}  scanline {}, dot {}, frame {}",
+		registers.pc, registers.a, registers.x, registers.y, u8::from(registers.p), registers.s,
+		nes.ppu().scanline(), nes.ppu().dot(), nes.ppu().frame_number())
+}
+
+// A line per channel with its registers, e.g. "pulse1    4000  BF 08 A9 00".
+fn channel_registers(nes: &Nes) -> String {
+	let lines: Vec<String> = CHANNELS.iter()
+		.filter(|channel| !channel.registers().is_empty())
+		.map(|&channel| {
+			let values: Vec<String> = nes.channel_registers(channel).iter().map(|value| format!("{:02X}", value)).collect();
+			format!("{:9} {:04X}  {}", channel.name(), channel.registers().start, values.join(" "))
+		})
+		.collect();
+	lines.join("\n")
+}
+
+fn dump(nes: &mut Nes, space: Space, addr: u16, len: u16) -> String {
+	let mut lines = Vec::new();
+	for row in (0..len).step_by(16) {
+		let start = (addr.wrapping_add(row) as u32 % space.size()) as u16;
+		let bytes: Vec<String> = (0..16.min(len - row))
+			.map(|i| format!("{:02X}", space.peek(nes, start.wrapping_add(i))))
+			.collect();
+		lines.push(format!("{:04X}  {}", start, bytes.join(" ")));
+	}
+	lines.join("\n")
+}
+
+fn disassembly(nes: &mut Nes, mut addr: u16, count: u16) -> String {
+	let mut lines = Vec::new();
+	for _ in 0..count {
+		let bytes = [nes.peek_memory(addr), nes.peek_memory(addr.wrapping_add(1)), nes.peek_memory(addr.wrapping_add(2))];
+		let (text, size) = disassemble(addr, bytes);
+		let hex: Vec<String> = bytes[..size as usize].iter().map(|byte| format!("{:02X}", byte)).collect();
+		lines.push(format!("{:04X}  {:9} {}", addr, hex.join(" "), text));
+		addr = addr.wrapping_add(size as u16);
+	}
+	lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+	use cartridge::test_cartridge::TestCartridge;
+
+	#[test]
+	fn commands() {
+		let code = assemble(0x8000, "LDA #$05; STA $10; INC $11; JMP $8004").unwrap();
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+		let mut repl = Repl::new();
+
+		assert_eq!("8000  A9 05     LDA #$05\n8002  85 10     STA $10",
+			repl.execute(&mut nes, "d 8000 2").0);
+		let (text, control) = repl.execute(&mut nes, "s 2");
+		assert_eq!(Some(RunControl::Pause), control);
+		assert!(text.starts_with("PC:8004 A:05"), "{}", text);
+		assert!(text.ends_with("8004  E6 11     INC $11"), "{}", text);
+		assert_eq!("0010  05 00", repl.execute(&mut nes, "m $10 2").0);
+		repl.execute(&mut nes, "w 10 AA BB");
+		assert_eq!("0010  AA BB", repl.execute(&mut nes, "m 10 2").0);
+		assert!(repl.execute(&mut nes, "w 10 100").0.starts_with("Usage"));
+		assert_eq!("Wrote 4 bytes.", repl.execute(&mut nes, "a 300 LDA #$12; STA $10").0);
+		assert_eq!("0300  A9 12     LDA #$12\n0302  85 10     STA $10", repl.execute(&mut nes, "d 300 2").0);
+		assert_eq!("Operand out of range: LDA #$1234", repl.execute(&mut nes, "a 300 LDA #$1234").0);
+		assert!(repl.execute(&mut nes, "a 300").0.starts_with("Usage"));
+		assert!(repl.execute(&mut nes, "m xyz").0.starts_with("Not a hexadecimal"));
+		repl.execute(&mut nes, "set x 7");
+		repl.execute(&mut nes, "set p C3");
+		assert!(repl.execute(&mut nes, "r").0.starts_with("PC:8004 A:05 X:07 Y:00 P:E3"));
+		assert_eq!("Unknown register q.", repl.execute(&mut nes, "set q 1").0);
+		assert_eq!("Not a byte.", repl.execute(&mut nes, "set a 100").0);
+		repl.execute(&mut nes, "w 4008 81");
+		repl.execute(&mut nes, "w 4013 20");
+		assert_eq!("pulse1    4000  00 00 00 00\npulse2    4004  00 00 00 00\ntriangle  4008  81 00 00 00\n\
+			noise     400C  00 00 00 00\ndmc       4010  00 00 00 20", repl.execute(&mut nes, "apu").0);
+
+		// save states by name
+		repl.execute(&mut nes, "save before");
+		repl.execute(&mut nes, "s");
+		assert_eq!("0010  AA BC", repl.execute(&mut nes, "m 10 2").0);
+		assert_eq!("Loaded state before.", repl.execute(&mut nes, "load before").0);
+		assert_eq!("0010  AA BB", repl.execute(&mut nes, "m 10 2").0);
+		assert_eq!("No state other.", repl.execute(&mut nes, "load other").0);
+		assert_eq!("before", repl.execute(&mut nes, "states").0);
+
+		assert_eq!("$2000 PPUCTRL, write only. PPU control: nametable, increment, pattern tables, sprite size, NMI",
+			repl.execute(&mut nes, "io 2008").0);
+		assert_eq!("No I/O register at $4009.", repl.execute(&mut nes, "io 4009").0);
+		assert!(repl.execute(&mut nes, "io").0.ends_with("$4017 JOY2, read and write. APU frame counter, reads controller 2 serially"));
+	}
+
+	#[test]
+	fn ppu_memory() {
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().build()));
+		let mut repl = Repl::new();
+		assert_eq!("Wrote 2 bytes.", repl.execute(&mut nes, "w vram 2000 12 34").0);
+		assert_eq!("2000  12 34", repl.execute(&mut nes, "m vram 2000 2").0);
+		// mirrored like the PPU does
+		assert_eq!("2000  12 34", repl.execute(&mut nes, "m vram 6000 2").0);
+		repl.execute(&mut nes, "w oam FF 80");
+		assert_eq!("00FF  80", repl.execute(&mut nes, "m oam FF 1").0);
+		assert_eq!(0x80, nes.peek_oam(0xFF));
+		// the palette keeps six bits, 3F10 is 3F00
+		repl.execute(&mut nes, "w pal 10 FF");
+		assert_eq!("0000  3F", repl.execute(&mut nes, "m pal 0 1").0);
+		assert_eq!(0x3F, nes.peek_vram(0x3F00));
+		// the CPU space is the default
+		repl.execute(&mut nes, "w cpu 10 AA");
+		assert_eq!("0010  AA", repl.execute(&mut nes, "m 10 1").0);
+		assert!(repl.execute(&mut nes, "m oam").0.starts_with("Usage: m [SPACE]"));
+	}
+
+	#[test]
+	fn breakpoints() {
+		let code = assemble(0x8000, "JMP $8000").unwrap();
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+		let mut repl = Repl::new();
+		assert!(!repl.check_breakpoint(0x8000));
+		repl.execute(&mut nes, "b 8000");
+		repl.execute(&mut nes, "b $9000");
+		assert_eq!("$8000 $9000", repl.execute(&mut nes, "bl").0);
+		assert!(repl.check_breakpoint(0x8000));
+
+		// continuing steps over the breakpoint once
+		assert_eq!(Some(RunControl::Continue), repl.execute(&mut nes, "c").1);
+		assert!(!repl.check_breakpoint(0x8000));
+		assert!(repl.check_breakpoint(0x8000));
+
+		repl.execute(&mut nes, "bd 8000");
+		assert!(!repl.check_breakpoint(0x8000));
+		assert_eq!("Unknown command x, try help.", repl.execute(&mut nes, "x").0);
+	}
+
+	#[test]
+	fn watchpoints() {
+		// a program write and a push to the same address
+		let code = assemble(0x8000, "LDX #$FF; TXS; LDA #$05; STA $01FF; PHA; LDA $01FF; JMP $800C").unwrap();
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()));
+		let mut repl = Repl::new();
+		assert_eq!("Watchpoint $01FF w by data.", repl.execute(&mut nes, "wp 1FF w data").0);
+		let mut instr_log = None;
+		for _ in 0..3 {
+			nes.step(&mut instr_log);
+		}
+		assert_eq!(None, nes.take_watch_hit());
+		nes.step(&mut instr_log);
+		let hit = nes.take_watch_hit().unwrap();
+		assert_eq!("Watchpoint: data write of $05 to $01FF by the instruction at $8005.", describe_watch_hit(&hit));
+		// the push is not a data write
+		nes.step(&mut instr_log);
+		assert_eq!(None, nes.take_watch_hit());
+
+		repl.execute(&mut nes, "wd 1FF");
+		assert_eq!("Watchpoint $0100-$01FF r by stack data.", repl.execute(&mut nes, "wp 100-1FF r stack data").0);
+		nes.step(&mut instr_log);
+		assert_eq!(Some(AccessSource::Data), nes.take_watch_hit().map(|hit| hit.source));
+		assert_eq!("$0100-$01FF r by stack data", repl.execute(&mut nes, "wl").0);
+		assert!(repl.execute(&mut nes, "wp 1FF w refresh").0.starts_with("Usage"));
+		assert!(repl.execute(&mut nes, "wp 200-100").0.starts_with("Usage"));
+	}
+
+	#[test]
+	fn stubs() {
+		let code = assemble(0x8000, "LDX #$FF; TXS; JSR $8010; STA $10; JMP $8009").unwrap();
+		let subroutine = assemble(0x8010, "LDA #$05; RTS").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).prg(0x8010, &subroutine).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		let mut repl = Repl::new();
+		assert_eq!("Stub $8010 returning A=$42.", repl.execute(&mut nes, "stub 8010 42").0);
+		assert_eq!("$8010 returning A=$42", repl.execute(&mut nes, "sl").0);
+		repl.execute(&mut nes, "s 5");
+		assert_eq!("0010  42", repl.execute(&mut nes, "m 10 1").0);
+		assert!(repl.execute(&mut nes, "stub 8010 100").0.starts_with("Usage"));
+
+		// the stub is set again in a new console
+		let mut other = Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).prg(0x8010, &subroutine).build()));
+		repl.apply_to(&mut other);
+		repl.execute(&mut other, "s 5");
+		assert_eq!("0010  42", repl.execute(&mut other, "m 10 1").0);
+
+		// without the stub the subroutine runs again
+		assert_eq!("Deleted stub at $8010.", repl.execute(&mut nes, "sd 8010").0);
+		assert_eq!("No stubs.", repl.execute(&mut nes, "sl").0);
+		repl.execute(&mut nes, "power");
+		repl.execute(&mut nes, "s 6");
+		assert_eq!("0010  05", repl.execute(&mut nes, "m 10 1").0);
+	}
+}
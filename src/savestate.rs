@@ -0,0 +1,126 @@
+use std::io::{self, Read, Write};
+
+// Helpers for the binary save state format. Every component writes its
+// fields in a fixed order, multi-byte values are little endian.
+
+pub fn write_u8(out: &mut dyn Write, value: u8) -> io::Result<()> {
+	out.write_all(&[value])
+}
+
+pub fn write_bool(out: &mut dyn Write, value: bool) -> io::Result<()> {
+	write_u8(out, value as u8)
+}
+
+pub fn write_u16(out: &mut dyn Write, value: u16) -> io::Result<()> {
+	out.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+pub fn write_u64(out: &mut dyn Write, value: u64) -> io::Result<()> {
+	for i in 0..8 {
+		write_u8(out, (value >> (i * 8)) as u8)?;
+	}
+	Ok(())
+}
+
+// Writes a buffer whose size is known when loading.
+pub fn write_bytes(out: &mut dyn Write, value: &[u8]) -> io::Result<()> {
+	out.write_all(value)
+}
+
+pub fn read_u8(input: &mut dyn Read) -> io::Result<u8> {
+	let mut buffer = [0; 1];
+	input.read_exact(&mut buffer)?;
+	Ok(buffer[0])
+}
+
+pub fn read_bool(input: &mut dyn Read) -> io::Result<bool> {
+	Ok(read_u8(input)? != 0)
+}
+
+pub fn read_u16(input: &mut dyn Read) -> io::Result<u16> {
+	let lo = read_u8(input)? as u16;
+	let hi = read_u8(input)? as u16;
+	Ok((hi << 8) | lo)
+}
+
+pub fn read_u64(input: &mut dyn Read) -> io::Result<u64> {
+	let mut value = 0;
+	for i in 0..8 {
+		value |= (read_u8(input)? as u64) << (i * 8);
+	}
+	Ok(value)
+}
+
+// Fills the whole buffer.
+pub fn read_bytes(input: &mut dyn Read, value: &mut [u8]) -> io::Result<()> {
+	input.read_exact(value)
+}
+
+pub fn invalid_state<T>(message: &str) -> io::Result<T> {
+	Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+// How the components after the header of a save state are compressed. The
+// header says which one a state uses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+	None,
+	// LZ4 block format, fast enough for states of every frame; only with
+	// the lz4 feature
+	Lz4,
+}
+
+impl Compression {
+	// The best one this build has.
+	pub fn preferred() -> Compression {
+		if cfg!(feature = "lz4") { Compression::Lz4 } else { Compression::None }
+	}
+
+	pub fn id(&self) -> u8 {
+		match *self {
+			Compression::None => 0,
+			Compression::Lz4 => 1,
+		}
+	}
+
+	pub fn from_id(id: u8) -> Option<Compression> {
+		match id {
+			0 => Some(Compression::None),
+			1 => Some(Compression::Lz4),
+			_ => None,
+		}
+	}
+}
+
+#[cfg(feature = "lz4")]
+pub fn compress(compression: Compression, data: Vec<u8>) -> io::Result<Vec<u8>> {
+	match compression {
+		Compression::None => Ok(data),
+		Compression::Lz4 => Ok(::lz4_flex::compress_prepend_size(&data)),
+	}
+}
+
+#[cfg(not(feature = "lz4"))]
+pub fn compress(compression: Compression, data: Vec<u8>) -> io::Result<Vec<u8>> {
+	match compression {
+		Compression::None => Ok(data),
+		Compression::Lz4 => invalid_state("LZ4 compression needs the lz4 feature."),
+	}
+}
+
+#[cfg(feature = "lz4")]
+pub fn decompress(compression: Compression, data: Vec<u8>) -> io::Result<Vec<u8>> {
+	match compression {
+		Compression::None => Ok(data),
+		Compression::Lz4 => ::lz4_flex::decompress_size_prepended(&data)
+			.or_else(|_| invalid_state("Corrupt LZ4 data.")),
+	}
+}
+
+#[cfg(not(feature = "lz4"))]
+pub fn decompress(compression: Compression, data: Vec<u8>) -> io::Result<Vec<u8>> {
+	match compression {
+		Compression::None => Ok(data),
+		Compression::Lz4 => invalid_state("The state is compressed with LZ4, which needs the lz4 feature."),
+	}
+}
@@ -0,0 +1,69 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// Queue of timed events, ordered by their timestamp on the master clock.
+// Components register when their next event happens, so the main loop can
+// run the CPU until then instead of polling every component after every
+// instruction. Events with the same timestamp come out in the order of E.
+pub struct Scheduler<E: Ord + Copy> {
+	events: BinaryHeap<Reverse<(u64, E)>>,
+}
+
+impl<E: Ord + Copy> Scheduler<E> {
+	pub fn new() -> Scheduler<E> {
+		Scheduler {
+			events: BinaryHeap::new(),
+		}
+	}
+
+	pub fn schedule(&mut self, time: u64, event: E) {
+		self.events.push(Reverse((time, event)));
+	}
+
+	// Removes all pending occurrences of the event.
+	pub fn cancel(&mut self, event: E) {
+		self.events.retain(|&Reverse((_, pending))| pending != event);
+	}
+
+	pub fn clear(&mut self) {
+		self.events.clear();
+	}
+
+	// Timestamp of the next event.
+	pub fn next_time(&self) -> Option<u64> {
+		self.events.peek().map(|&Reverse((time, _))| time)
+	}
+
+	// Removes and returns the next event if it is due at the time now.
+	pub fn pop_due(&mut self, now: u64) -> Option<(u64, E)> {
+		match self.next_time() {
+			Some(time) if time <= now => self.events.pop().map(|Reverse(event)| event),
+			_ => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn scheduler() {
+		let mut scheduler = Scheduler::new();
+		assert_eq!(None, scheduler.next_time());
+		scheduler.schedule(30, 'c');
+		scheduler.schedule(10, 'b');
+		scheduler.schedule(10, 'a');
+		scheduler.schedule(20, 'x');
+		scheduler.cancel('x');
+		assert_eq!(Some(10), scheduler.next_time());
+		assert_eq!(None, scheduler.pop_due(9));
+		assert_eq!(Some((10, 'a')), scheduler.pop_due(15));
+		assert_eq!(Some((10, 'b')), scheduler.pop_due(15));
+		assert_eq!(None, scheduler.pop_due(15));
+		assert_eq!(Some((30, 'c')), scheduler.pop_due(100));
+		scheduler.schedule(5, 'd');
+		scheduler.clear();
+		assert_eq!(None, scheduler.pop_due(100));
+	}
+}
@@ -0,0 +1,185 @@
+use apu::AudioOutput;
+use sdl2::Sdl;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+// Samples the device asks for at once, 23 ms at 44.1 kHz.
+const DEVICE_BUFFER: u16 = 1024;
+
+// Samples which the emulation runs ahead of the device at most, 100 ms.
+// Beyond that, e.g. in fast forward, the oldest samples are dropped.
+const QUEUE_MILLIS: u32 = 100;
+
+// The samples between the emulation and the audio device, resampled from
+// the rate of the APU to the rate of the device by linear interpolation.
+pub struct SampleQueue {
+	samples: VecDeque<i16>,
+	capacity: usize,
+	output_rate: u32,
+	// Position of the next output sample after the previous input sample,
+	// in input samples.
+	position: f64,
+	previous: i16,
+	// The last sample played, repeated when the queue runs empty so it does
+	// not click.
+	last: i16,
+	// Whether the queue ran empty since the last call to take_underrun.
+	underrun: bool,
+}
+
+impl SampleQueue {
+	pub fn new(output_rate: u32) -> SampleQueue {
+		let capacity = (output_rate * QUEUE_MILLIS / 1000) as usize;
+		SampleQueue {
+			samples: VecDeque::with_capacity(capacity),
+			capacity,
+			output_rate,
+			position: 0.0,
+			previous: 0,
+			last: 0,
+			underrun: false,
+		}
+	}
+
+	pub fn push(&mut self, sample_rate: u32, samples: &[i16]) {
+		let step = sample_rate as f64 / self.output_rate as f64;
+		for &sample in samples {
+			while self.position < 1.0 {
+				let level = self.previous as f64 + (sample as f64 - self.previous as f64) * self.position;
+				self.samples.push_back(level.round() as i16);
+				self.position += step;
+			}
+			self.position -= 1.0;
+			self.previous = sample;
+		}
+		while self.samples.len() > self.capacity {
+			self.samples.pop_front();
+		}
+	}
+
+	pub fn pop(&mut self, out: &mut [i16]) {
+		for sample in out.iter_mut() {
+			match self.samples.pop_front() {
+				Some(next) => self.last = next,
+				None => self.underrun = true,
+			}
+			*sample = self.last;
+		}
+	}
+
+	// How full the queue is, from 0 to 1.
+	pub fn fill(&self) -> f64 {
+		self.samples.len() as f64 / self.capacity as f64
+	}
+
+	pub fn take_underrun(&mut self) -> bool {
+		let underrun = self.underrun;
+		self.underrun = false;
+		underrun
+	}
+}
+
+struct Playback {
+	queue: Arc<Mutex<SampleQueue>>,
+}
+
+impl AudioCallback for Playback {
+	type Channel = i16;
+
+	fn callback(&mut self, out: &mut [i16]) {
+		self.queue.lock().unwrap().pop(out);
+	}
+}
+
+struct QueueOutput {
+	queue: Arc<Mutex<SampleQueue>>,
+}
+
+impl AudioOutput for QueueOutput {
+	fn samples(&mut self, sample_rate: u32, samples: &[i16]) {
+		self.queue.lock().unwrap().push(sample_rate, samples);
+	}
+}
+
+// Plays the audio of the console on the default device of SDL. The device
+// may pick another sample rate than the one asked for.
+pub struct SdlAudio {
+	device: AudioDevice<Playback>,
+	queue: Arc<Mutex<SampleQueue>>,
+}
+
+impl SdlAudio {
+	pub fn open(sdl: &Sdl, sample_rate: u32) -> Result<SdlAudio, String> {
+		let audio = sdl.audio()?;
+		let desired = AudioSpecDesired {
+			freq: Some(sample_rate as i32),
+			channels: Some(1),
+			samples: Some(DEVICE_BUFFER),
+		};
+		let mut queue = None;
+		let device = audio.open_playback(None, &desired, |spec| {
+			let shared = Arc::new(Mutex::new(SampleQueue::new(spec.freq as u32)));
+			queue = Some(shared.clone());
+			Playback { queue: shared }
+		})?;
+		device.resume();
+		Ok(SdlAudio {
+			device,
+			queue: queue.unwrap(),
+		})
+	}
+
+	// The receiver for Nes::set_audio_output.
+	pub fn output(&self) -> Box<dyn AudioOutput + Send> {
+		Box::new(QueueOutput { queue: self.queue.clone() })
+	}
+
+	pub fn set_paused(&self, paused: bool) {
+		if paused {
+			self.device.pause();
+		} else {
+			self.device.resume();
+		}
+	}
+
+	pub fn fill(&self) -> f64 {
+		self.queue.lock().unwrap().fill()
+	}
+
+	// Whether the device ran out of samples since the last call.
+	pub fn take_underrun(&self) -> bool {
+		self.queue.lock().unwrap().take_underrun()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn resample() {
+		let mut queue = SampleQueue::new(48000);
+		queue.push(24000, &[0, 100, 200]);
+		let mut out = [0; 6];
+		queue.pop(&mut out);
+		// one input sample behind
+		assert_eq!([0, 0, 0, 50, 100, 150], out);
+		assert!(!queue.take_underrun());
+		// an empty queue repeats the last sample
+		queue.pop(&mut out[..2]);
+		assert_eq!([150, 150], out[..2]);
+		assert!(queue.take_underrun());
+		assert!(!queue.take_underrun());
+	}
+
+	#[test]
+	fn capacity() {
+		let mut queue = SampleQueue::new(44100);
+		queue.push(44100, &[7; 10000]);
+		assert_eq!(1.0, queue.fill());
+		let mut out = [0; 4410];
+		queue.pop(&mut out);
+		assert_eq!(0.0, queue.fill());
+	}
+}
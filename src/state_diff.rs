@@ -0,0 +1,113 @@
+use cartridge::load_rom;
+use nes::Nes;
+use std::fs::File;
+
+// Save state comparison: diff-states <rom> <state a> <state b>
+//
+// Loads both states of the ROM and reports for every component where they
+// first differ, with the position of each console, to find where two runs
+// which should be identical went apart.
+pub fn run(args: &[String]) {
+	if args.len() != 3 {
+		println!("Usage: diff-states <rom> <state a> <state b>");
+		return;
+	}
+	let mut consoles = Vec::new();
+	for path in args[1..].iter() {
+		let mut nes = match load_rom(&args[0]) {
+			Ok(cartridge) => Nes::new(cartridge),
+			Err(err) => {
+				println!("Could not load ROM: {}", err);
+				return;
+			}
+		};
+		if let Err(err) = File::open(path).and_then(|mut file| nes.load_state(&mut file)) {
+			println!("Could not load state {}: {}", path, err);
+			return;
+		}
+		println!("{}: {}", path, position(&nes));
+		consoles.push(nes);
+	}
+	match diff(&consoles[0], &consoles[1]) {
+		Ok(ref differences) if differences.is_empty() => println!("The states are identical."),
+		Ok(differences) => {
+			for difference in differences.iter() {
+				println!("{}", difference);
+			}
+		}
+		Err(err) => println!("Could not compare the states: {}", err),
+	}
+}
+
+fn position(nes: &Nes) -> String {
+	format!("PC {:04X}, frame {}, scanline {}, dot {}, clock {}",
+		nes.pc(), nes.ppu().frame_number(), nes.ppu().scanline(), nes.ppu().dot(), nes.clock())
+}
+
+// Describes the first differing byte of every component which differs.
+pub fn diff(a: &Nes, b: &Nes) -> Result<Vec<String>, String> {
+	let sections_a = a.state_sections().map_err(|err| err.to_string())?;
+	let sections_b = b.state_sections().map_err(|err| err.to_string())?;
+	let mut differences = Vec::new();
+	for (&(name, ref a), (_, b)) in sections_a.iter().zip(sections_b.iter()) {
+		if a.len() != b.len() {
+			differences.push(format!("{}: sizes differ, {} and {} bytes", name, a.len(), b.len()));
+			continue;
+		}
+		let count = a.iter().zip(b.iter()).filter(|&(x, y)| x != y).count();
+		if let Some(offset) = a.iter().zip(b.iter()).position(|(x, y)| x != y) {
+			differences.push(format!("{}: {} bytes differ, first at {} ({}): {:02X} vs {:02X}",
+				name, count, offset, field_name(name, offset), a[offset], b[offset]));
+		}
+	}
+	Ok(differences)
+}
+
+// Names the field at the offset of a section, as far as it is known.
+fn field_name(section: &str, offset: usize) -> String {
+	// layout of Cpu::save_state
+	const CPU_FIELDS: [&str; 12] = ["A", "X", "Y", "PC low", "PC high", "S", "P",
+		"opcode8", "opcode16 low", "opcode16 high", "RAM size low", "RAM size high"];
+	match section {
+		"cpu" if offset < CPU_FIELDS.len() => String::from(CPU_FIELDS[offset]),
+		"cpu" => format!("RAM ${:04X}", offset - CPU_FIELDS.len()),
+		_ => String::from("offset"),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+	use cartridge::test_cartridge::TestCartridge;
+	use std::io::Write;
+
+	fn nes() -> Nes {
+		let code = assemble(0x8000, "INC $10; JMP $8000").unwrap();
+		Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code).build()))
+	}
+
+	#[test]
+	fn state_diff() {
+		let mut a = nes();
+		let mut b = nes();
+		assert!(diff(&a, &b).unwrap().is_empty());
+
+		let mut instr_log: Option<&mut dyn Write> = None;
+		for _ in 0..2 {
+			a.step(&mut instr_log);
+			b.step(&mut instr_log);
+		}
+		b.step(&mut instr_log);
+		b.step(&mut instr_log);
+		let differences = diff(&a, &b).unwrap();
+		assert_eq!(4, differences.len());
+		assert!(differences[0].starts_with("clock: "));
+		// the loop is back at the same instruction, only the counter differs
+		assert_eq!("cpu: 1 bytes differ, first at 28 (RAM $0010): 01 vs 02", differences[1]);
+		assert!(differences[2].starts_with("ppu: "));
+		// the frame counter
+		assert!(differences[3].starts_with("apu: "));
+		assert_eq!("RAM $0010", field_name("cpu", 28));
+	}
+}
@@ -0,0 +1,488 @@
+use cartridge::load_rom;
+use checksum::crc32;
+use nes::{Nes, ConsoleEvent};
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+// Test ROM runner: testroms [manifest or directory]
+//
+// Runs all ROMs of the manifest (tests/testroms.toml by default) which exist
+// on disk and prints a pass/fail report. Given a directory, or without an
+// argument if NES_TEST_ROM_DIR is set, it runs every ROM found there
+// instead, see discover. The manifest is a list of TOML
+// tables, one per ROM:
+//
+//   [[rom]]
+//   path = "roms/01-basics.nes"  # relative to the working directory
+//   result_addr = 0x6000         # status byte, see below
+//   expected = 0                 # passing status, 0 by default
+//   timeout_frames = 1200        # fail if no result until then
+//   screen_crc = "1A2B3C4D"      # CRC32 of the RGBA pixels at the end
+//
+// The status byte follows the blargg convention, see BlarggResult. Without
+// result_addr the ROM runs for timeout_frames and only the screen is
+// checked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestRom {
+	pub path: String,
+	pub result_addr: Option<u16>,
+	pub expected: u8,
+	pub timeout_frames: u64,
+	pub screen_crc: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+	Passed,
+	Failed(String),
+	// The ROM is not on disk.
+	Missing,
+}
+
+const DEFAULT_MANIFEST: &str = "tests/testroms.toml";
+const DEFAULT_TIMEOUT_FRAMES: u64 = 60 * 60;
+// Status values of blargg's test ROMs.
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+// Follows the status byte once the ROM writes it, so that the RAM before
+// is not taken for a result.
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+// Where the ROMs report unless the manifest says otherwise.
+pub const BLARGG_STATUS_ADDR: u16 = 0x6000;
+
+// What a test ROM following the blargg convention reports: a status byte,
+// 0x80 while the test runs, 0x81 if it wants the reset button pressed and
+// the result code afterwards, then the signature and a zero terminated
+// text from status address + 4.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlarggResult {
+	Running,
+	ResetRequested,
+	Passed,
+	// The status and the text.
+	Failed(String),
+}
+
+impl BlarggResult {
+	// The result at the status address, usually BLARGG_STATUS_ADDR, where
+	// the expected status, usually 0, means passed.
+	pub fn poll(nes: &mut Nes, addr: u16, expected: u8) -> BlarggResult {
+		let signed = SIGNATURE.iter().enumerate()
+			.all(|(i, &byte)| nes.peek_memory(addr.wrapping_add(1 + i as u16)) == byte);
+		if !signed {
+			return BlarggResult::Running;
+		}
+		match nes.peek_memory(addr) {
+			STATUS_RUNNING => BlarggResult::Running,
+			STATUS_RESET => BlarggResult::ResetRequested,
+			status if status == expected => BlarggResult::Passed,
+			status => BlarggResult::Failed(format!("status {:02X}: {}", status, read_text(nes, addr.wrapping_add(4)))),
+		}
+	}
+}
+
+// The ROMs are not part of the repository. The tests which need them look in
+// this directory, roms by default, and skip themselves if a ROM is missing.
+pub const ROM_DIR_VARIABLE: &str = "NES_TEST_ROM_DIR";
+
+pub fn rom_dir() -> PathBuf {
+	env::var_os(ROM_DIR_VARIABLE).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("roms"))
+}
+
+// Path of a ROM of the test ROM directory, None with a note if it is missing.
+#[cfg(test)]
+pub fn test_rom_path(name: &str) -> Option<String> {
+	let path = rom_dir().join(name);
+	if path.exists() {
+		Some(path.to_string_lossy().into_owned())
+	} else {
+		println!("Skipped, {} not found. Set {} to the test ROM directory.", path.display(), ROM_DIR_VARIABLE);
+		None
+	}
+}
+
+// Finds the ROMs of a directory, sorted by name. All of them are expected to
+// follow the blargg convention with the status at 0x6000, except ROMs with a
+// log of the same name next to them, like nestest.nes and nestest.log, which
+// never report a status and are compared with their log instead.
+pub fn discover(dir: &Path) -> Result<Vec<TestRom>, String> {
+	let entries = fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+	let mut paths: Vec<PathBuf> = entries
+		.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+		.filter(|path| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("nes")))
+		.filter(|path| !path.with_extension("log").exists())
+		.collect();
+	paths.sort();
+	Ok(paths.iter().map(|path| TestRom {
+		path: path.to_string_lossy().into_owned(),
+		result_addr: Some(BLARGG_STATUS_ADDR),
+		expected: 0,
+		timeout_frames: DEFAULT_TIMEOUT_FRAMES,
+		screen_crc: None,
+	}).collect())
+}
+
+pub fn run(args: &[String]) {
+	let dir = match args.first() {
+		Some(path) if Path::new(path).is_dir() => Some(PathBuf::from(path)),
+		None if env::var_os(ROM_DIR_VARIABLE).is_some() => Some(rom_dir()),
+		_ => None,
+	};
+	if let Some(dir) = dir {
+		match discover(&dir) {
+			Ok(roms) => report(&roms),
+			Err(err) => println!("Could not read {}", err),
+		}
+		return;
+	}
+	let manifest_path = args.first().map(|path| path.as_ref()).unwrap_or(DEFAULT_MANIFEST);
+	let mut text = String::new();
+	if let Err(err) = File::open(manifest_path).and_then(|mut file| file.read_to_string(&mut text)) {
+		println!("Could not read {}: {}", manifest_path, err);
+		return;
+	}
+	let roms = match parse_manifest(&text) {
+		Ok(roms) => roms,
+		Err(err) => {
+			println!("Invalid manifest {}: {}", manifest_path, err);
+			return;
+		}
+	};
+	report(&roms);
+}
+
+fn report(roms: &[TestRom]) {
+	let (mut passed, mut failed, mut missing) = (0, 0, 0);
+	for rom in roms.iter() {
+		match run_test(rom) {
+			Outcome::Passed => {
+				passed += 1;
+				println!("PASS  {}", rom.path);
+			}
+			Outcome::Failed(reason) => {
+				failed += 1;
+				println!("FAIL  {}: {}", rom.path, reason);
+			}
+			Outcome::Missing => missing += 1,
+		}
+	}
+	println!("{} passed, {} failed, {} not found.", passed, failed, missing);
+}
+
+// Parses the subset of TOML used by the manifest: [[rom]] headers, and
+// keys with string or integer values.
+pub fn parse_manifest(text: &str) -> Result<Vec<TestRom>, String> {
+	let mut roms: Vec<TestRom> = Vec::new();
+	for (i, line) in text.lines().enumerate() {
+		let error = |message: &str| Err(format!("line {}: {}", i + 1, message));
+		let line = strip_comment(line).trim();
+		if line.is_empty() {
+			continue;
+		}
+		if line == "[[rom]]" {
+			roms.push(TestRom {
+				path: String::new(),
+				result_addr: None,
+				expected: 0,
+				timeout_frames: DEFAULT_TIMEOUT_FRAMES,
+				screen_crc: None,
+			});
+			continue;
+		}
+		let (key, value) = match line.find('=') {
+			Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+			None => return error("Expected key = value."),
+		};
+		let rom = match roms.last_mut() {
+			Some(rom) => rom,
+			None => return error("Expected [[rom]] first."),
+		};
+		match key {
+			"path" => match parse_string(value) {
+				Some(path) => rom.path = path,
+				None => return error("path must be a string."),
+			},
+			"result_addr" => match parse_integer(value) {
+				Some(addr) if addr <= 0xFFFF => rom.result_addr = Some(addr as u16),
+				_ => return error("result_addr must be an address."),
+			},
+			"expected" => match parse_integer(value) {
+				Some(expected) if expected <= 0xFF => rom.expected = expected as u8,
+				_ => return error("expected must be a byte."),
+			},
+			"timeout_frames" => match parse_integer(value) {
+				Some(frames) if frames > 0 => rom.timeout_frames = frames,
+				_ => return error("timeout_frames must be a positive number."),
+			},
+			"screen_crc" => match parse_string(value).and_then(|crc| u32::from_str_radix(&crc, 16).ok()) {
+				Some(crc) => rom.screen_crc = Some(crc),
+				None => return error("screen_crc must be a hexadecimal string."),
+			},
+			_ => return error(&format!("Unknown key {}.", key)),
+		}
+	}
+	match roms.iter().position(|rom| rom.path.is_empty()) {
+		Some(i) => Err(format!("entry {} has no path", i + 1)),
+		None => Ok(roms),
+	}
+}
+
+fn strip_comment(line: &str) -> &str {
+	// no '#' in the strings of the manifest
+	match line.find('#') {
+		Some(pos) => &line[..pos],
+		None => line,
+	}
+}
+
+fn parse_string(value: &str) -> Option<String> {
+	if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+		Some(String::from(&value[1..value.len() - 1]))
+	} else {
+		None
+	}
+}
+
+fn parse_integer(value: &str) -> Option<u64> {
+	let value = value.replace('_', "");
+	match value.strip_prefix("0x") {
+		Some(hex) => u64::from_str_radix(hex, 16).ok(),
+		None => value.parse().ok(),
+	}
+}
+
+pub fn run_test(rom: &TestRom) -> Outcome {
+	if !Path::new(&rom.path).exists() {
+		return Outcome::Missing;
+	}
+	let cartridge = match load_rom(&rom.path) {
+		Ok(cartridge) => cartridge,
+		Err(err) => return Outcome::Failed(String::from(err)),
+	};
+	// one crashing ROM should not end the whole report
+	let nes = Nes::new(cartridge);
+	match panic::catch_unwind(AssertUnwindSafe(|| run_test_on(nes, rom))) {
+		Ok(outcome) => outcome,
+		Err(_) => Outcome::Failed(String::from("emulator panicked")),
+	}
+}
+
+// Runs the console until the ROM reports a result or the time is up.
+pub fn run_test_on(mut nes: Nes, rom: &TestRom) -> Outcome {
+	let mut reset_in = None;
+	let mut frame = None;
+	let mut result = BlarggResult::Running;
+	for _ in 0..rom.timeout_frames {
+		frame = Some(nes.run_frame());
+		let addr = match rom.result_addr {
+			Some(addr) => addr,
+			None => continue,
+		};
+		// the reset has to wait a bit, as if pressed by hand
+		match reset_in {
+			Some(0) => {
+				nes.handle_event(ConsoleEvent::SoftReset);
+				reset_in = None;
+			}
+			Some(frames) => reset_in = Some(frames - 1),
+			None => {}
+		}
+		result = BlarggResult::poll(&mut nes, addr, rom.expected);
+		match result {
+			BlarggResult::Running => {}
+			BlarggResult::ResetRequested => {
+				if reset_in.is_none() {
+					reset_in = Some(6);
+				}
+			}
+			BlarggResult::Passed | BlarggResult::Failed(_) => break,
+		}
+	}
+
+	if rom.result_addr.is_some() {
+		match result {
+			BlarggResult::Passed => {}
+			BlarggResult::Failed(message) => return Outcome::Failed(message),
+			_ => return Outcome::Failed(String::from("timed out")),
+		}
+	}
+	if let Some(expected) = rom.screen_crc {
+		let crc = frame.map(|frame| crc32(&frame.pixels)).unwrap_or(0);
+		if crc != expected {
+			return Outcome::Failed(format!("screen CRC {:08X}, expected {:08X}", crc, expected));
+		}
+	}
+	Outcome::Passed
+}
+
+// Reads a zero terminated text, at most 1000 characters.
+fn read_text(nes: &mut Nes, mut addr: u16) -> String {
+	let mut text = Vec::new();
+	while text.len() < 1000 {
+		let byte = nes.peek_memory(addr);
+		if byte == 0 {
+			break;
+		}
+		text.push(byte);
+		addr = addr.wrapping_add(1);
+	}
+	String::from_utf8_lossy(&text).trim().replace('\n', " ")
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+	use cartridge::test_cartridge::TestCartridge;
+
+	#[test]
+	fn manifest() {
+		let roms = parse_manifest(r#"
+			# comment
+			[[rom]]
+			path = "roms/a.nes"  # trailing comment
+			result_addr = 0x6000
+			timeout_frames = 1_200
+
+			[[rom]]
+			path = "roms/b.nes"
+			screen_crc = "1a2B3c4D"
+		"#).unwrap();
+		assert_eq!(2, roms.len());
+		assert_eq!("roms/a.nes", roms[0].path);
+		assert_eq!(Some(0x6000), roms[0].result_addr);
+		assert_eq!(1200, roms[0].timeout_frames);
+		assert_eq!(None, roms[1].result_addr);
+		assert_eq!(Some(0x1A2B3C4D), roms[1].screen_crc);
+
+		assert!(parse_manifest("path = \"x\"").unwrap_err().contains("line 1"));
+		assert!(parse_manifest("[[rom]]\nexpected = 256").is_err());
+		assert!(parse_manifest("[[rom]]\nresult_addr = 1").is_err());
+		assert_eq!(Outcome::Missing, run_test(&roms[0]));
+	}
+
+	#[test]
+	fn result_byte() {
+		// reports "running", waits a bit, then the status in A
+		let code = |status: u8| assemble(0x8000, &format!(
+			"LDA #$80; STA $6000; LDA #$DE; STA $6001; LDA #$B0; STA $6002; LDA #$61; STA $6003; \
+			 LDA #$4F; STA $6004; LDA #$4B; STA $6005; \
+			 INC $10; BNE $8023; INC $11; LDX $11; CPX #$10; BNE $8023; \
+			 LDA #${:02X}; STA $6000; JMP $8037", status)).unwrap();
+		let rom = TestRom {
+			path: String::new(),
+			result_addr: Some(0x6000),
+			expected: 0,
+			timeout_frames: 60,
+			screen_crc: None,
+		};
+		let nes = |status| Nes::new(Box::new(TestCartridge::builder().prg(0x8000, &code(status)).build()));
+		// nothing to report before the signature is written
+		assert_eq!(BlarggResult::Running, BlarggResult::poll(&mut nes(0), BLARGG_STATUS_ADDR, 0));
+		assert_eq!(Outcome::Passed, run_test_on(nes(0), &rom));
+		assert_eq!(Outcome::Failed(String::from("status 03: OK")), run_test_on(nes(3), &rom));
+		let rom = TestRom { timeout_frames: 1, ..rom };
+		assert_eq!(Outcome::Failed(String::from("timed out")), run_test_on(nes(0), &rom));
+	}
+
+	#[test]
+	fn blargg_result() {
+		let mut nes = Nes::new(Box::new(TestCartridge::builder().build()));
+		let poll = |nes: &mut Nes| BlarggResult::poll(nes, BLARGG_STATUS_ADDR, 0);
+		// a status without the signature is left over RAM
+		nes.poke_memory(0x6000, 0x00);
+		assert_eq!(BlarggResult::Running, poll(&mut nes));
+		for (i, &byte) in SIGNATURE.iter().enumerate() {
+			nes.poke_memory(0x6001 + i as u16, byte);
+		}
+		assert_eq!(BlarggResult::Passed, poll(&mut nes));
+		nes.poke_memory(0x6000, STATUS_RUNNING);
+		assert_eq!(BlarggResult::Running, poll(&mut nes));
+		nes.poke_memory(0x6000, STATUS_RESET);
+		assert_eq!(BlarggResult::ResetRequested, poll(&mut nes));
+
+		// the text up to the zero, on one line
+		for (i, &byte) in b"\n  Flags wrong\nFailed\n\0".iter().enumerate() {
+			nes.poke_memory(0x6004 + i as u16, byte);
+		}
+		nes.poke_memory(0x6000, 0x02);
+		assert_eq!(BlarggResult::Failed(String::from("status 02: Flags wrong Failed")), poll(&mut nes));
+		// some ROMs pass with another status
+		assert_eq!(BlarggResult::Passed, BlarggResult::poll(&mut nes, BLARGG_STATUS_ADDR, 2));
+	}
+
+	#[test]
+	fn discovery() {
+		let dir = env::temp_dir().join(format!("nes-discover-{}", ::std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		for name in ["b.nes", "a.NES", "cpu.nes", "cpu.log", "notes.txt"].iter() {
+			File::create(dir.join(name)).unwrap();
+		}
+		let roms = discover(&dir).unwrap();
+		fs::remove_dir_all(&dir).unwrap();
+		let names: Vec<_> = roms.iter()
+			.map(|rom| Path::new(&rom.path).file_name().unwrap().to_string_lossy().into_owned())
+			.collect();
+		assert_eq!(vec!["a.NES", "b.nes"], names);
+		assert_eq!(Some(0x6000), roms[0].result_addr);
+		assert!(discover(&dir).is_err());
+	}
+
+	// Frames of the title demo of Super Mario Bros. which is played without
+	// any input, and a file with their hashes next to the ROM. A missing file
+	// is written on the first run, to be checked after looking at the demo.
+	const SMB_DEMO_FRAMES: [u32; 5] = [60, 300, 600, 900, 1200];
+
+	#[test]
+	fn smb_demo_rom() {
+		let rom_path = match test_rom_path("smb.nes") {
+			Some(rom_path) => rom_path,
+			None => return,
+		};
+		let mut nes = Nes::new(load_rom(&rom_path).unwrap());
+		let mut hashes = String::new();
+		for frame_number in 1..=SMB_DEMO_FRAMES[SMB_DEMO_FRAMES.len() - 1] {
+			let frame = nes.run_frame();
+			if SMB_DEMO_FRAMES.contains(&frame_number) {
+				hashes.push_str(&format!("{} {:08X}\n", frame_number, crc32(&frame.pixels)));
+			}
+		}
+
+		// the demo moves, so no two checkpoints show the same picture
+		let mut unique: Vec<_> = hashes.lines().map(|line| &line[line.len() - 8..]).collect();
+		unique.sort();
+		unique.dedup();
+		assert_eq!(SMB_DEMO_FRAMES.len(), unique.len(), "{}", hashes);
+
+		let hashes_path = Path::new(&rom_path).with_extension("hashes");
+		let mut expected = String::new();
+		match File::open(&hashes_path) {
+			Ok(mut file) => {
+				file.read_to_string(&mut expected).unwrap();
+				assert_eq!(expected, hashes);
+			},
+			Err(_) => {
+				fs::write(&hashes_path, &hashes).unwrap();
+				println!("Wrote {}.", hashes_path.display());
+			},
+		}
+	}
+
+	// Runs every ROM of the test ROM directory, only if it is set explicitly.
+	#[test]
+	fn rom_battery() {
+		if env::var_os(ROM_DIR_VARIABLE).is_none() {
+			return;
+		}
+		let failures: Vec<String> = discover(&rom_dir()).unwrap().iter()
+			.filter_map(|rom| match run_test(rom) {
+				Outcome::Failed(reason) => Some(format!("{}: {}", rom.path, reason)),
+				_ => None,
+			})
+			.collect();
+		assert!(failures.is_empty(), "{}", failures.join("\n"));
+	}
+}
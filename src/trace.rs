@@ -0,0 +1,208 @@
+use nes::Nes;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+// Condition to start or stop tracing, checked before every instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceTrigger {
+	// The CPU is about to execute the instruction at this address.
+	Pc(u16),
+	// The byte at this address in CPU RAM (0000-1FFF) has this value.
+	Ram(u16, u8),
+}
+
+impl TraceTrigger {
+	// Parses "C000" as Pc and "0010=05" as Ram, numbers are hexadecimal
+	// with an optional '$'.
+	pub fn parse(text: &str) -> Option<TraceTrigger> {
+		fn hex(text: &str) -> Option<u16> {
+			let text = if text.starts_with('$') { &text[1..] } else { text };
+			u16::from_str_radix(text, 16).ok()
+		}
+		match text.find('=') {
+			Some(i) => match (hex(&text[..i]), hex(&text[i + 1..])) {
+				(Some(addr), Some(value)) if addr <= 0x1FFF && value <= 0xFF =>
+					Some(TraceTrigger::Ram(addr, value as u8)),
+				_ => None,
+			},
+			None => hex(text).map(TraceTrigger::Pc),
+		}
+	}
+
+	fn matches(&self, nes: &Nes) -> bool {
+		match *self {
+			TraceTrigger::Pc(pc) => nes.pc() == pc,
+			TraceTrigger::Ram(addr, value) => nes.peek_ram(addr) == value,
+		}
+	}
+}
+
+// Receives the instruction log and keeps the last lines in memory, so the
+// interesting part can be dumped e.g. after a crash. Optionally forwards
+// everything to a file. Tracing can be switched on and off by hand or by
+// triggers.
+pub struct Tracer {
+	active: bool,
+	start: Option<TraceTrigger>,
+	stop: Option<TraceTrigger>,
+	ring: VecDeque<String>,
+	ring_size: usize,
+	file: Option<Box<dyn Write>>,
+	line: Vec<u8>,
+}
+
+impl Tracer {
+	// Keeps the last ring_size lines.
+	pub fn new(ring_size: usize) -> Tracer {
+		Tracer {
+			active: false,
+			start: None,
+			stop: None,
+			ring: VecDeque::new(),
+			ring_size,
+			file: None,
+			line: Vec::new(),
+		}
+	}
+
+	pub fn set_file(&mut self, file: Option<Box<dyn Write>>) {
+		self.file = file;
+	}
+
+	// Tracing starts when start matches and ends when stop matches. Either
+	// can still be switched by set_active.
+	pub fn set_triggers(&mut self, start: Option<TraceTrigger>, stop: Option<TraceTrigger>) {
+		self.start = start;
+		self.stop = stop;
+	}
+
+	pub fn is_active(&self) -> bool {
+		self.active
+	}
+
+	pub fn set_active(&mut self, active: bool) {
+		self.active = active;
+	}
+
+	// Checks the triggers, to be called before each instruction.
+	pub fn update(&mut self, nes: &Nes) {
+		if self.active {
+			if self.stop.map(|trigger| trigger.matches(nes)).unwrap_or(false) {
+				self.active = false;
+			}
+		} else if self.start.map(|trigger| trigger.matches(nes)).unwrap_or(false) {
+			self.active = true;
+		}
+	}
+
+	// The lines in the ring buffer, oldest first.
+	pub fn lines(&self) -> &VecDeque<String> {
+		&self.ring
+	}
+
+	// Writes the ring buffer.
+	pub fn dump(&self, out: &mut dyn Write) -> io::Result<()> {
+		for line in self.ring.iter() {
+			writeln!(out, "{}", line)?;
+		}
+		Ok(())
+	}
+
+	fn push_line(&mut self) {
+		let line = String::from_utf8_lossy(&self.line).into_owned();
+		self.line.clear();
+		if self.ring_size == 0 {
+			return;
+		}
+		if self.ring.len() == self.ring_size {
+			self.ring.pop_front();
+		}
+		self.ring.push_back(line);
+	}
+}
+
+impl Write for Tracer {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if let Some(ref mut file) = self.file {
+			file.write_all(buf)?;
+		}
+		for &byte in buf {
+			if byte == b'\n' {
+				self.push_line();
+			} else {
+				self.line.push(byte);
+			}
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self.file {
+			Some(ref mut file) => file.flush(),
+			None => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+	use cartridge::test_cartridge::TestCartridge;
+
+	#[test]
+	fn parse() {
+		assert_eq!(Some(TraceTrigger::Pc(0xC000)), TraceTrigger::parse("C000"));
+		assert_eq!(Some(TraceTrigger::Pc(0xC000)), TraceTrigger::parse("$c000"));
+		assert_eq!(Some(TraceTrigger::Ram(0x10, 5)), TraceTrigger::parse("$0010=$05"));
+		assert_eq!(None, TraceTrigger::parse("2002=00"));
+		assert_eq!(None, TraceTrigger::parse("0010=100"));
+		assert_eq!(None, TraceTrigger::parse("xyz"));
+	}
+
+	#[test]
+	fn ring_buffer() {
+		let mut tracer = Tracer::new(2);
+		write!(tracer, "a\nb\nc").unwrap();
+		assert_eq!(vec!["a", "b"], tracer.lines().iter().collect::<Vec<_>>());
+		tracer.write_all(b"d\n").unwrap();
+		assert_eq!(vec!["b", "cd"], tracer.lines().iter().collect::<Vec<_>>());
+		let mut dump = Vec::new();
+		tracer.dump(&mut dump).unwrap();
+		assert_eq!(b"b\ncd\n", &dump[..]);
+	}
+
+	#[test]
+	fn triggers() {
+		// traces the loop while the counter is 2 and 3
+		let code = assemble(0x8000, "INC $10; NOP; JMP $8000").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		let mut tracer = Tracer::new(100);
+		tracer.set_triggers(TraceTrigger::parse("10=2"), TraceTrigger::parse("10=4"));
+		for _ in 0..30 {
+			tracer.update(&nes);
+			let mut instr_log: Option<&mut dyn Write> = if tracer.is_active() { Some(&mut tracer) } else { None };
+			nes.step(&mut instr_log);
+		}
+		let pcs: Vec<&str> = tracer.lines().iter().map(|line| &line[..4]).collect();
+		assert_eq!(vec!["8002", "8003", "8000", "8002", "8003", "8000"], pcs);
+		assert!(!tracer.is_active());
+	}
+
+	#[test]
+	fn register_annotations() {
+		let code = assemble(0x8000, "LDA #$18; STA $2001; LDX #$00; LDA $2002,X; JMP $8000").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		nes.set_annotate_io(true);
+		let mut tracer = Tracer::new(100);
+		for _ in 0..5 {
+			nes.step(&mut Some(&mut tracer));
+		}
+		let lines = tracer.lines();
+		assert!(!lines[0].contains(';'));
+		assert!(lines[1].ends_with("; write $2001 PPUMASK: BG on, sprites on"), "{}", lines[1]);
+		assert!(lines[3].contains("; read $2002 PPUSTATUS: "), "{}", lines[3]);
+	}
+}
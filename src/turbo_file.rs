@@ -0,0 +1,132 @@
+use input::{ExpansionDevice, PORT_2};
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+// Size of the battery backed memory of the ASCII Turbo File.
+pub const TURBO_FILE_SIZE: usize = 8 * 1024;
+const BITS: usize = TURBO_FILE_SIZE * 8;
+
+// External save memory for the expansion port, accessed one bit at a time:
+//   $4016 write bit 0  data bit to store
+//   $4016 write bit 1  clear to move back to the first bit
+//   $4016 write bit 2  going from 1 to 0 stores bit 0 and moves on
+//   $4017 read bit 2   the current bit
+// Software reads the current bit and writes it back to get to the next one.
+pub struct TurboFile {
+	data: Vec<u8>,
+	position: usize,
+	last_write: u8,
+}
+
+impl TurboFile {
+	// Shorter data is padded with zeros, longer data is cut.
+	pub fn with_data(mut data: Vec<u8>) -> TurboFile {
+		data.resize(TURBO_FILE_SIZE, 0);
+		TurboFile {
+			data,
+			position: 0,
+			last_write: 0,
+		}
+	}
+
+	// Starts empty if the file does not exist yet.
+	pub fn load(path: &str) -> io::Result<TurboFile> {
+		let mut data = Vec::new();
+		match File::open(path) {
+			Ok(mut file) => {
+				file.read_to_end(&mut data)?;
+			}
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+			Err(err) => return Err(err),
+		}
+		Ok(TurboFile::with_data(data))
+	}
+
+}
+
+impl ExpansionDevice for TurboFile {
+	fn write(&mut self, value: u8) {
+		if value & 0x02 == 0 {
+			self.position = 0;
+		}
+		if value & 0x04 == 0 && self.last_write & 0x04 != 0 {
+			let mask = 1 << (self.position & 7);
+			let byte = &mut self.data[self.position >> 3];
+			if value & 0x01 != 0 {
+				*byte |= mask;
+			} else {
+				*byte &= !mask;
+			}
+			self.position = (self.position + 1) % BITS;
+		}
+		self.last_write = value;
+	}
+
+	fn read(&self, addr: u16) -> u8 {
+		match addr {
+			PORT_2 => ((self.data[self.position >> 3] >> (self.position & 7)) & 1) << 2,
+			_ => 0,
+		}
+	}
+
+	fn battery_data(&self) -> Option<&[u8]> {
+		Some(&self.data)
+	}
+}
+
+pub fn save(path: &str, data: &[u8]) -> io::Result<()> {
+	File::create(path).and_then(|mut file| file.write_all(data))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use input::{ExpansionDevice, PORT_1, PORT_2};
+	use std::env;
+
+	// Clocks one bit in, with bit 1 set to stay away from the first bit.
+	fn clock(device: &mut TurboFile, bit: u8) {
+		device.write(0x06 | bit);
+		device.write(0x02 | bit);
+	}
+
+	#[test]
+	fn turbo_file() {
+		let mut device = TurboFile::with_data(Vec::new());
+		for &bit in [1, 0, 1, 1].iter() {
+			clock(&mut device, bit);
+		}
+		assert_eq!(0x0D, device.battery_data().unwrap()[0]);
+
+		// back to the start and read it again, writing back what was read
+		device.write(0x00);
+		let mut bits = Vec::new();
+		for _ in 0..4 {
+			let bit = device.read(PORT_2) >> 2;
+			bits.push(bit);
+			clock(&mut device, bit);
+		}
+		assert_eq!(vec![1, 0, 1, 1], bits);
+		assert_eq!(0x0D, device.battery_data().unwrap()[0]);
+		assert_eq!(0, device.read(PORT_1));
+
+		let device = TurboFile::with_data(vec![0xAB]);
+		assert_eq!(TURBO_FILE_SIZE, device.battery_data().unwrap().len());
+		assert_eq!(0x04, device.read(PORT_2));
+	}
+
+	#[test]
+	fn file() {
+		let path = env::temp_dir().join(format!("nes-turbo-file-{}.sav", ::std::process::id()));
+		let path = path.to_str().unwrap();
+		let _ = ::std::fs::remove_file(path);
+		// a new Turbo File is empty
+		let mut device = TurboFile::load(path).unwrap();
+		assert_eq!(&[0; TURBO_FILE_SIZE][..], device.battery_data().unwrap());
+		clock(&mut device, 1);
+		save(path, device.battery_data().unwrap()).unwrap();
+		let device = TurboFile::load(path).unwrap();
+		assert_eq!(0x01, device.battery_data().unwrap()[0]);
+		::std::fs::remove_file(path).unwrap();
+	}
+}
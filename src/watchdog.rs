@@ -0,0 +1,82 @@
+use nes::Nes;
+
+// Detects a crashed game: every game reads or writes the PPU registers or
+// the controllers at least once per frame, so a CPU which runs for
+// millions of cycles without doing so has most likely crashed into a loop
+// or jammed on a KIL instruction, e.g. because of a bad dump.
+pub struct Watchdog {
+	// CPU cycles without accesses until the console counts as hung
+	limit: u64,
+	accesses: u64,
+	// CPU cycle of the last access seen
+	last_activity: u64,
+}
+
+impl Watchdog {
+	pub fn new(limit: u64) -> Watchdog {
+		Watchdog {
+			limit,
+			accesses: 0,
+			last_activity: 0,
+		}
+	}
+
+	// Returns whether the console looks hung, to be called regularly, e.g.
+	// once per frame. It recovers on its own once the game accesses the
+	// hardware again, e.g. after a reset.
+	pub fn check(&mut self, nes: &Nes) -> bool {
+		let cycles = nes.cpu_cycles();
+		if nes.io_accesses() != self.accesses || cycles < self.last_activity {
+			self.accesses = nes.io_accesses();
+			self.last_activity = cycles;
+		}
+		nes.cpu_jammed() || cycles - self.last_activity >= self.limit
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cpu::assemble;
+	use cartridge::test_cartridge::TestCartridge;
+	use nes::ConsoleEvent;
+	use std::io::Write;
+
+	fn run(nes: &mut Nes, watchdog: &mut Watchdog, instructions: usize) -> bool {
+		let mut instr_log: Option<&mut dyn Write> = None;
+		for _ in 0..instructions {
+			nes.step(&mut instr_log);
+		}
+		watchdog.check(nes)
+	}
+
+	#[test]
+	fn watchdog() {
+		// polls PPUSTATUS 100 times, then loops forever without it
+		let code = assemble(0x8000, "LDX #$64; LDA $2002; DEX; BNE $8002; JMP $8008").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		let mut watchdog = Watchdog::new(10000);
+		assert!(!run(&mut nes, &mut watchdog, 300));
+		assert!(!run(&mut nes, &mut watchdog, 3000));
+		assert!(run(&mut nes, &mut watchdog, 1000));
+
+		// activity after the reset ends the warning
+		nes.handle_event(ConsoleEvent::SoftReset);
+		assert!(!run(&mut nes, &mut watchdog, 10));
+	}
+
+	#[test]
+	fn jam() {
+		let code = assemble(0x8000, "LDA $2002; KIL").unwrap();
+		let cartridge = TestCartridge::builder().prg(0x8000, &code).build();
+		let mut nes = Nes::new(Box::new(cartridge));
+		let mut watchdog = Watchdog::new(1_000_000);
+		assert!(run(&mut nes, &mut watchdog, 10));
+		assert_eq!(0x8003, nes.pc());
+
+		nes.handle_event(ConsoleEvent::SoftReset);
+		assert!(!nes.cpu_jammed());
+		assert!(!run(&mut nes, &mut watchdog, 1));
+	}
+}
@@ -0,0 +1,155 @@
+use apu::AudioChunk;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+// Writes mono 16 bit PCM WAV files. The sizes in the header are only known
+// at the end, so they are filled in by finish.
+pub struct WavWriter<W: Write + Seek> {
+	out: W,
+	sample_rate: u32,
+	samples: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+	pub fn create(path: &str, sample_rate: u32) -> io::Result<WavWriter<BufWriter<File>>> {
+		WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+	}
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+	pub fn new(out: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+		let mut writer = WavWriter {
+			out,
+			sample_rate,
+			samples: 0,
+		};
+		writer.write_header()?;
+		Ok(writer)
+	}
+
+	pub fn sample_rate(&self) -> u32 {
+		self.sample_rate
+	}
+
+	// Number of samples written so far.
+	pub fn samples(&self) -> u32 {
+		self.samples
+	}
+
+	pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+		let mut bytes = Vec::with_capacity(samples.len() * 2);
+		for &sample in samples {
+			bytes.push(sample as u8);
+			bytes.push((sample >> 8) as u8);
+		}
+		self.out.write_all(&bytes)?;
+		self.samples += samples.len() as u32;
+		Ok(())
+	}
+
+	// Completes the header and returns the underlying writer.
+	pub fn finish(mut self) -> io::Result<W> {
+		self.out.seek(SeekFrom::Start(0))?;
+		self.write_header()?;
+		self.out.seek(SeekFrom::End(0))?;
+		self.out.flush()?;
+		Ok(self.out)
+	}
+
+	fn write_header(&mut self) -> io::Result<()> {
+		let data_size = self.samples * 2;
+		let mut header = Vec::with_capacity(44);
+		header.extend_from_slice(b"RIFF");
+		push_u32(&mut header, 36 + data_size);
+		header.extend_from_slice(b"WAVEfmt ");
+		push_u32(&mut header, 16);
+		// PCM, one channel
+		push_u16(&mut header, 1);
+		push_u16(&mut header, 1);
+		push_u32(&mut header, self.sample_rate);
+		// bytes per second and per sample, bits per sample
+		push_u32(&mut header, self.sample_rate * 2);
+		push_u16(&mut header, 2);
+		push_u16(&mut header, 16);
+		header.extend_from_slice(b"data");
+		push_u32(&mut header, data_size);
+		self.out.write_all(&header)
+	}
+}
+
+// Records the audio of the console to a file, which is created with the
+// sample rate of the first chunk.
+pub struct AudioRecorder {
+	path: String,
+	writer: Option<WavWriter<BufWriter<File>>>,
+}
+
+impl AudioRecorder {
+	pub fn new(path: String) -> AudioRecorder {
+		AudioRecorder {
+			path,
+			writer: None,
+		}
+	}
+
+	pub fn path(&self) -> &str {
+		&self.path
+	}
+
+	pub fn record(&mut self, chunk: &AudioChunk) -> io::Result<()> {
+		if self.writer.is_none() {
+			self.writer = Some(WavWriter::create(&self.path, chunk.sample_rate)?);
+		}
+		let writer = self.writer.as_mut().unwrap();
+		if writer.sample_rate() != chunk.sample_rate {
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, "The sample rate changed."));
+		}
+		writer.write_samples(&chunk.samples)
+	}
+
+	// Completes the file and returns the number of recorded samples.
+	pub fn finish(self) -> io::Result<u32> {
+		match self.writer {
+			Some(writer) => {
+				let samples = writer.samples();
+				writer.finish()?;
+				Ok(samples)
+			}
+			None => Ok(0),
+		}
+	}
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+	out.push(value as u8);
+	out.push((value >> 8) as u8);
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+	push_u16(out, value as u16);
+	push_u16(out, (value >> 16) as u16);
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::io::Cursor;
+
+	#[test]
+	fn wav() {
+		let mut writer = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+		writer.write_samples(&[1, -2]).unwrap();
+		writer.write_samples(&[0x1234]).unwrap();
+		assert_eq!(3, writer.samples());
+		let data = writer.finish().unwrap().into_inner();
+
+		assert_eq!(50, data.len());
+		assert_eq!(b"RIFF", &data[0..4]);
+		assert_eq!([42, 0, 0, 0], data[4..8]);
+		assert_eq!(b"WAVEfmt ", &data[8..16]);
+		assert_eq!([0x44, 0xAC, 0, 0], data[24..28]);
+		assert_eq!(b"data", &data[36..40]);
+		assert_eq!([6, 0, 0, 0], data[40..44]);
+		assert_eq!([1, 0, 0xFE, 0xFF, 0x34, 0x12], data[44..]);
+	}
+}
@@ -0,0 +1,141 @@
+use ppu::{Ppu, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+// Bits of $4017 with a Zapper in port 2. The light bit reads 0 while the
+// photodiode sees light.
+pub const LIGHT_BIT: u8 = 0x08;
+pub const TRIGGER_BIT: u8 = 0x10;
+
+const LINE_DOTS: usize = 341;
+// The photodiode circuit keeps its output active for a while after the beam
+// passed a bright spot, about 20 scanlines. Games check the light over a
+// few lines, so only looking at the pixel under the cursor at the moment
+// of the read makes them miss.
+const PULSE_LINES: usize = 20;
+// Luma from which a pixel is bright enough, between the light grey $10 and
+// the dark grey $00 of the palette.
+const BRIGHTNESS_THRESHOLD: u32 = 0x80;
+
+// The NES Zapper light gun in controller port 2. It senses the picture
+// the PPU draws: the light bit is set from the moment the beam draws a
+// bright pixel near where it aims until PULSE_LINES scanlines later.
+pub struct Zapper {
+	// pixel aimed at, None when pointing away from the screen
+	aim: Option<(usize, usize)>,
+	trigger: bool,
+	// pixels around the aim which the photodiode sees
+	radius: usize,
+	light: bool,
+}
+
+impl Zapper {
+	pub fn new(radius: usize) -> Zapper {
+		Zapper {
+			aim: None,
+			trigger: false,
+			radius,
+			light: false,
+		}
+	}
+
+	// Aims at a pixel of the picture. None or positions outside of it point
+	// away from the screen, which games like Duck Hunt never see as a hit.
+	pub fn set_aim(&mut self, aim: Option<(usize, usize)>) {
+		self.aim = aim.filter(|&(x, y)| x < SCREEN_WIDTH && y < SCREEN_HEIGHT);
+	}
+
+	pub fn set_trigger(&mut self, pulled: bool) {
+		self.trigger = pulled;
+	}
+
+	// Updates the photodiode from the picture and beam position of the PPU,
+	// to be called before reads of the port.
+	pub fn sense(&mut self, ppu: &Ppu) {
+		let (aim_x, aim_y) = match self.aim {
+			Some(aim) => aim,
+			None => {
+				self.light = false;
+				return;
+			}
+		};
+		let frame = ppu.frame_in_progress();
+		// Pixel x of line y is drawn in dot x + 1. Dots of the pre-render
+		// line are far past the last visible line, and pixels of the last
+		// frame which are not redrawn yet lie in the future.
+		let now = ppu.scanline() * LINE_DOTS + ppu.dot();
+		let first_y = aim_y.saturating_sub(self.radius);
+		let last_y = (aim_y + self.radius).min(SCREEN_HEIGHT - 1);
+		let first_x = aim_x.saturating_sub(self.radius);
+		let last_x = (aim_x + self.radius).min(SCREEN_WIDTH - 1);
+		self.light = (first_y..=last_y).any(|y| (first_x..=last_x).any(|x| {
+			let drawn = y * LINE_DOTS + x + 1;
+			drawn < now && now - drawn <= PULSE_LINES * LINE_DOTS && bright(frame.pixel(x, y))
+		}));
+	}
+
+	// The bits in $4017.
+	pub fn read(&self) -> u8 {
+		(if self.light { 0 } else { LIGHT_BIT }) | if self.trigger { TRIGGER_BIT } else { 0 }
+	}
+}
+
+fn bright((r, g, b): (u8, u8, u8)) -> bool {
+	(299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000 >= BRIGHTNESS_THRESHOLD
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cartridge::Cartridge;
+	use cartridge::test_cartridge::TestCartridge;
+
+	fn run_to(ppu: &mut Ppu, cartridge: &mut dyn Cartridge, scanline: usize, dot: usize) {
+		for _ in 0..ppu.dots_until(scanline, dot) {
+			ppu.tick(cartridge);
+		}
+	}
+
+	#[test]
+	fn light() {
+		let mut cartridge = TestCartridge::builder().build();
+		let mut ppu = Ppu::new();
+		// with rendering off the whole picture is the white backdrop
+		ppu.poke_palette(0, 0x30);
+		let mut zapper = Zapper::new(1);
+		zapper.set_aim(Some((100, 50)));
+
+		// the beam has not reached the aim yet
+		run_to(&mut ppu, &mut cartridge, 48, 300);
+		zapper.sense(&ppu);
+		assert_eq!(LIGHT_BIT, zapper.read());
+		// the line above, within the radius
+		run_to(&mut ppu, &mut cartridge, 49, 110);
+		zapper.sense(&ppu);
+		assert_eq!(0, zapper.read());
+		// the pulse lasts for a number of lines
+		run_to(&mut ppu, &mut cartridge, 60, 0);
+		zapper.sense(&ppu);
+		assert_eq!(0, zapper.read());
+		run_to(&mut ppu, &mut cartridge, 72, 0);
+		zapper.sense(&ppu);
+		assert_eq!(LIGHT_BIT, zapper.read());
+
+		// never light when pointing away from the screen
+		zapper.set_aim(Some((300, 50)));
+		zapper.set_trigger(true);
+		run_to(&mut ppu, &mut cartridge, 55, 0);
+		zapper.sense(&ppu);
+		assert_eq!(LIGHT_BIT | TRIGGER_BIT, zapper.read());
+	}
+
+	#[test]
+	fn dark() {
+		let mut cartridge = TestCartridge::builder().build();
+		let mut ppu = Ppu::new();
+		ppu.poke_palette(0, 0x00);
+		let mut zapper = Zapper::new(4);
+		zapper.set_aim(Some((10, 10)));
+		run_to(&mut ppu, &mut cartridge, 12, 0);
+		zapper.sense(&ppu);
+		assert_eq!(LIGHT_BIT, zapper.read());
+	}
+}